@@ -5,9 +5,11 @@
 use std::marker::PhantomData;
 use std::os::fd::{AsRawFd, OwnedFd, RawFd};
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use vmm_sys_util::eventfd::EventFd;
 
+use crate::dirty_bitmap::DirtyBitmap;
 use crate::{BatchRequest, DiskTopology, SECTOR_SIZE};
 
 #[derive(Error, Debug)]
@@ -24,6 +26,12 @@ pub enum DiskFileError {
     /// Resize failed
     #[error("Resize failed")]
     ResizeError(#[source] std::io::Error),
+    /// Snapshot operation failed
+    #[error("Snapshot operation failed")]
+    SnapshotError(#[source] std::io::Error),
+    /// Dirty bitmap operation failed
+    #[error("Dirty bitmap operation failed")]
+    DirtyBitmapError(#[source] std::io::Error),
 }
 
 pub type DiskFileResult<T> = std::result::Result<T, DiskFileError>;
@@ -56,6 +64,25 @@ impl AsRawFd for BorrowedDiskFd<'_> {
     }
 }
 
+/// An internal snapshot of a disk image.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskSnapshot {
+    /// Unique ID of the snapshot
+    pub id: String,
+    /// Name of the snapshot
+    pub name: String,
+    /// Time the snapshot was taken, in seconds since the Epoch
+    pub date_sec: u32,
+    /// Nanoseconds part of the time the snapshot was taken
+    pub date_nsec: u32,
+    /// Time the guest had been running when the snapshot was taken
+    pub vm_clock_nsec: u64,
+    /// Size of the VM state saved with the snapshot
+    pub vm_state_size: u64,
+    /// Virtual size of the disk when the snapshot was taken
+    pub disk_size: u64,
+}
+
 /// Abstraction over the effective [`File`] backing up a block device,
 /// with support for synchronous and asynchronous I/O.
 ///
@@ -78,6 +105,41 @@ pub trait DiskFile: Send {
         Err(DiskFileError::Unsupported)
     }
 
+    /// Returns the internal snapshots of the disk image.
+    fn snapshots(&mut self) -> DiskFileResult<Vec<DiskSnapshot>> {
+        Err(DiskFileError::Unsupported)
+    }
+
+    /// Takes an internal snapshot of the disk image, `vm_clock_nsec` being
+    /// the time the guest has been running.
+    fn create_snapshot(&mut self, _name: &str, _vm_clock_nsec: u64) -> DiskFileResult<()> {
+        Err(DiskFileError::Unsupported)
+    }
+
+    /// Reverts the disk image to the internal snapshot with the given ID or
+    /// name.
+    fn apply_snapshot(&mut self, _id_or_name: &str) -> DiskFileResult<()> {
+        Err(DiskFileError::Unsupported)
+    }
+
+    /// Deletes the internal snapshot with the given ID or name.
+    fn delete_snapshot(&mut self, _id_or_name: &str) -> DiskFileResult<()> {
+        Err(DiskFileError::Unsupported)
+    }
+
+    /// Loads the dirty bitmap with the given name stored in the disk image,
+    /// each bit covering `granularity` bytes. The whole disk is dirty if
+    /// there is no such bitmap to trust.
+    fn load_dirty_bitmap(&mut self, _name: &str, _granularity: u64) -> DiskFileResult<DirtyBitmap> {
+        Err(DiskFileError::Unsupported)
+    }
+
+    /// Stores a dirty bitmap with the given name in the disk image, once the
+    /// guest no longer writes to it.
+    fn store_dirty_bitmap(&mut self, _name: &str, _bitmap: &DirtyBitmap) -> DiskFileResult<()> {
+        Err(DiskFileError::Unsupported)
+    }
+
     /// Indicates support for sparse operations (punch hole, write zeroes, discard).
     /// Override to return true when supported.
     fn supports_sparse_operations(&self) -> bool {
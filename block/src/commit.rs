@@ -0,0 +1,289 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Merging of an overlay into its backing file while the guest keeps using
+//! the overlay.
+//!
+//! The chunks to merge are the ones set in a [`DirtyBitmap`]: first the
+//! clusters allocated in the overlay, then the ones the guest writes while
+//! they're being copied. They're read through the overlay, seeing the guest
+//! writes as the guest does, and written to the backing file. Each pass
+//! copies the chunks dirty so far, until few enough are left to copy them
+//! while the device is stopped and switch it to the backing file.
+
+use std::cmp::min;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::BlockBackend;
+use crate::async_io::AsyncIo;
+use crate::dirty_bitmap::DirtyBitmap;
+
+// The reads of the overlay are the only requests of its AsyncIo, one at a time.
+const READ_USER_DATA: u64 = 0;
+
+/// Copies the dirty chunks of an overlay to its backing file.
+pub struct DiskCommit {
+    overlay: Box<dyn AsyncIo>,
+    backing: Box<dyn BlockBackend>,
+    dirty_bitmap: Arc<DirtyBitmap>,
+    copied: Arc<AtomicU64>,
+    // Holds a chunk at `buf_offset`, aligned for the overlay.
+    buf: Vec<u8>,
+    buf_offset: usize,
+}
+
+impl DiskCommit {
+    /// Creates a commit reading the guest view of the disk through `overlay`
+    /// and writing it to `backing`, counting the bytes copied in `copied`.
+    pub fn new(
+        overlay: Box<dyn AsyncIo>,
+        backing: Box<dyn BlockBackend>,
+        dirty_bitmap: Arc<DirtyBitmap>,
+        copied: Arc<AtomicU64>,
+    ) -> Self {
+        let alignment = overlay.alignment() as usize;
+        let buf = vec![0u8; dirty_bitmap.granularity() as usize + alignment];
+        let buf_offset = buf.as_ptr().align_offset(alignment);
+        DiskCommit {
+            overlay,
+            backing,
+            dirty_bitmap,
+            copied,
+            buf,
+            buf_offset,
+        }
+    }
+
+    /// Copies the dirty chunks, cleaning them, and returns the number of
+    /// bytes copied. A chunk dirtied again behind the pass is left for the
+    /// next one, as are the chunks not copied yet once `stop` is set.
+    pub fn copy_dirty(&mut self, stop: &AtomicBool) -> io::Result<u64> {
+        let granularity = self.dirty_bitmap.granularity();
+        let mut copied = 0;
+        let mut next = 0;
+        while !stop.load(Ordering::Acquire)
+            && let Some((offset, len)) = self.copy_next(next)?
+        {
+            copied += len;
+            next = offset + granularity;
+        }
+        Ok(copied)
+    }
+
+    /// Copies the first dirty chunk starting at or after `offset`, cleaning
+    /// it, and returns its offset and length, or `None` if there is none.
+    pub fn copy_next(&mut self, offset: u64) -> io::Result<Option<(u64, u64)>> {
+        let Some(offset) = self.dirty_bitmap.take_next(offset) else {
+            return Ok(None);
+        };
+        Ok(Some((offset, self.copy_chunk(offset)?)))
+    }
+
+    /// Copies the dirty chunks overlapping `len` bytes at `offset`, cleaning
+    /// them, and returns the number of bytes copied.
+    pub fn copy_range(&mut self, offset: u64, len: u64) -> io::Result<u64> {
+        let granularity = self.dirty_bitmap.granularity();
+        let end = min(offset.saturating_add(len), self.dirty_bitmap.size());
+        let mut copied = 0;
+        let mut chunk = offset - offset % granularity;
+        while chunk < end {
+            if self.dirty_bitmap.take(chunk) {
+                copied += self.copy_chunk(chunk)?;
+            }
+            chunk += granularity;
+        }
+        Ok(copied)
+    }
+
+    /// Writes the data copied so far and the metadata of the backing file
+    /// to disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.backing.flush()
+    }
+
+    // Copies the chunk at `offset`, already cleaned, and returns its length.
+    fn copy_chunk(&mut self, offset: u64) -> io::Result<u64> {
+        let len = min(
+            self.dirty_bitmap.granularity(),
+            self.dirty_bitmap.size() - offset,
+        );
+        if let Err(e) = self.copy(offset, len as usize) {
+            // Keep the chunk for another attempt.
+            self.dirty_bitmap.set(offset, len);
+            return Err(e);
+        }
+        self.copied.fetch_add(len, Ordering::AcqRel);
+        Ok(len)
+    }
+
+    fn copy(&mut self, offset: u64, len: usize) -> io::Result<()> {
+        let buf = &mut self.buf[self.buf_offset..self.buf_offset + len];
+        let iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: len,
+        };
+        self.overlay
+            .read_vectored(offset as libc::off_t, &[iovec], READ_USER_DATA)
+            .map_err(io::Error::other)?;
+        let result = self.wait_for_read()?;
+        if result < 0 {
+            return Err(io::Error::from_raw_os_error(-result));
+        }
+        if result as usize != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        self.backing.seek(SeekFrom::Start(offset))?;
+        self.backing
+            .write_all(&self.buf[self.buf_offset..self.buf_offset + len])
+    }
+
+    // Waits for the read of the overlay to complete and returns its result.
+    fn wait_for_read(&mut self) -> io::Result<i32> {
+        loop {
+            if let Some((_, result)) = self.overlay.next_completed_request() {
+                return Ok(result);
+            }
+
+            let mut pollfd = libc::pollfd {
+                fd: self.overlay.notifier().as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: FFI call with a valid pollfd
+            if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+                continue;
+            }
+            // The completions are collected above, the count doesn't matter.
+            let _ = self.overlay.notifier().read();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+    use crate::async_io::DiskFile;
+    use crate::qcow::{BackingFileConfig, ImageType, QcowFile, RawFile};
+    use crate::qcow_sync::QcowDiskSync;
+
+    const CLUSTER_SIZE: u64 = 0x1_0000;
+
+    fn read_backing(backing_temp: &TempFile) -> Vec<u8> {
+        let mut data = Vec::new();
+        File::open(backing_temp.as_path())
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn disk_commit_copies_dirty_chunks() {
+        let size = 4 * CLUSTER_SIZE;
+        let backing_temp = TempFile::new().unwrap();
+        backing_temp
+            .as_file()
+            .write_all(&vec![0xab; size as usize])
+            .unwrap();
+
+        let overlay_temp = TempFile::new().unwrap();
+        let mut overlay = QcowFile::new_from_backing(
+            RawFile::new(overlay_temp.as_file().try_clone().unwrap(), false),
+            3,
+            size,
+            &BackingFileConfig {
+                path: backing_temp.as_path().to_str().unwrap().to_string(),
+                format: Some(ImageType::Raw),
+            },
+            true,
+        )
+        .unwrap();
+        overlay.seek(SeekFrom::Start(CLUSTER_SIZE)).unwrap();
+        overlay
+            .write_all(&vec![0xcd; CLUSTER_SIZE as usize])
+            .unwrap();
+        overlay.seek(SeekFrom::Start(3 * CLUSTER_SIZE)).unwrap();
+        overlay.write_all(&[0xef; 0x8000]).unwrap();
+        drop(overlay);
+
+        // The clusters allocated in the overlay are the first to copy.
+        let dirty_bitmap = Arc::new(DirtyBitmap::new(size, CLUSTER_SIZE));
+        QcowFile::from(RawFile::new(
+            File::open(overlay_temp.as_path()).unwrap(),
+            false,
+        ))
+        .unwrap()
+        .for_each_allocated_cluster(|address| dirty_bitmap.set(address, CLUSTER_SIZE))
+        .unwrap();
+        assert_eq!(dirty_bitmap.count(), 2);
+
+        let disk = QcowDiskSync::new(
+            overlay_temp.as_file().try_clone().unwrap(),
+            false,
+            true,
+            true,
+        )
+        .unwrap();
+        let backing = RawFile::new(backing_temp.as_file().try_clone().unwrap(), false);
+        let copied = Arc::new(AtomicU64::new(0));
+        let mut commit = DiskCommit::new(
+            disk.new_async_io(1).unwrap(),
+            Box::new(backing),
+            dirty_bitmap.clone(),
+            copied.clone(),
+        );
+        let stop = AtomicBool::new(false);
+        assert_eq!(commit.copy_dirty(&stop).unwrap(), 2 * CLUSTER_SIZE);
+        commit.flush().unwrap();
+        assert_eq!(dirty_bitmap.count(), 0);
+        assert_eq!(copied.load(Ordering::Acquire), 2 * CLUSTER_SIZE);
+
+        let data = read_backing(&backing_temp);
+        let cluster =
+            |index: u64| &data[(index * CLUSTER_SIZE) as usize..][..CLUSTER_SIZE as usize];
+        assert!(cluster(0).iter().all(|&b| b == 0xab));
+        assert!(cluster(1).iter().all(|&b| b == 0xcd));
+        assert!(cluster(2).iter().all(|&b| b == 0xab));
+        assert!(cluster(3)[..0x8000].iter().all(|&b| b == 0xef));
+        assert!(cluster(3)[0x8000..].iter().all(|&b| b == 0xab));
+
+        // A chunk written by the guest afterwards is copied by the next pass.
+        let mut async_io = disk.new_async_io(1).unwrap();
+        let mut buf = vec![0x12u8; 0x1000];
+        let iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        async_io.write_vectored(0x2000, &[iovec], 1).unwrap();
+        assert_eq!(async_io.next_completed_request(), Some((1, 0x1000)));
+        dirty_bitmap.set(0x2000, 0x1000);
+
+        stop.store(true, Ordering::Release);
+        assert_eq!(commit.copy_dirty(&stop).unwrap(), 0);
+        stop.store(false, Ordering::Release);
+        assert_eq!(commit.copy_dirty(&stop).unwrap(), CLUSTER_SIZE);
+        commit.flush().unwrap();
+        let data = read_backing(&backing_temp);
+        assert!(data[..0x2000].iter().all(|&b| b == 0xab));
+        assert!(data[0x2000..0x3000].iter().all(|&b| b == 0x12));
+        assert!(
+            data[0x3000..CLUSTER_SIZE as usize]
+                .iter()
+                .all(|&b| b == 0xab)
+        );
+    }
+}
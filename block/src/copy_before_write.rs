@@ -0,0 +1,251 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Point-in-time copy of a disk while the guest keeps writing to it.
+//!
+//! The chunks left to copy are the ones set in a [`DirtyBitmap`]. A thread
+//! copies them in the background, while the queues of the disk, going
+//! through [`CopyBeforeWriteIo`], first copy the chunks the guest is about
+//! to write. The copy ends up with the content the disk had when it started.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::error;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::async_io::{AsyncIo, AsyncIoResult};
+use crate::commit::DiskCommit;
+use crate::dirty_bitmap::DirtyBitmap;
+use crate::{BatchRequest, BlockBackend, RequestType};
+
+/// Copies the chunks of a disk before the guest writes to them.
+pub struct CopyBeforeWrite {
+    // A chunk isn't written by the guest while it's being copied.
+    copy: Mutex<DiskCommit>,
+    pending: Arc<DirtyBitmap>,
+    failed: AtomicBool,
+}
+
+impl CopyBeforeWrite {
+    /// Creates a copy of the chunks set in `pending`, read from the disk
+    /// through `source` and written to `target`, counting the bytes copied
+    /// in `copied`.
+    pub fn new(
+        source: Box<dyn AsyncIo>,
+        target: Box<dyn BlockBackend>,
+        pending: Arc<DirtyBitmap>,
+        copied: Arc<AtomicU64>,
+    ) -> Self {
+        CopyBeforeWrite {
+            copy: Mutex::new(DiskCommit::new(source, target, pending.clone(), copied)),
+            pending,
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Copies the chunks overlapping `len` bytes at `offset` that weren't
+    /// yet, before the guest writes to them. The guest write goes on if this
+    /// fails, the copy being given up instead.
+    pub fn before_write(&self, offset: u64, len: u64) {
+        if self.failed.load(Ordering::Acquire) {
+            return;
+        }
+        if let Err(e) = self.copy.lock().unwrap().copy_range(offset, len) {
+            error!("Failed to copy the chunks at {offset:#x} before they're written: {e}");
+            self.failed.store(true, Ordering::Release);
+            self.pending.clear();
+        }
+    }
+
+    /// Copies the chunks left one at a time, so that a guest write waits
+    /// for one chunk at most, and returns the number of bytes copied. The
+    /// chunks not copied yet once `stop` is set are left.
+    pub fn copy_pending(&self, stop: &AtomicBool) -> io::Result<u64> {
+        let granularity = self.pending.granularity();
+        let mut copied = 0;
+        let mut next = 0;
+        while !stop.load(Ordering::Acquire)
+            && let Some((offset, len)) = self.copy.lock().unwrap().copy_next(next)?
+        {
+            copied += len;
+            next = offset + granularity;
+        }
+        if self.failed.load(Ordering::Acquire) {
+            return Err(io::Error::other("a copy before a guest write failed"));
+        }
+        Ok(copied)
+    }
+
+    /// Returns the number of bytes left to copy.
+    pub fn remaining_bytes(&self) -> u64 {
+        self.pending.dirty_bytes()
+    }
+
+    /// Writes the data copied so far and the metadata of the target to disk.
+    pub fn flush(&self) -> io::Result<()> {
+        self.copy.lock().unwrap().flush()
+    }
+}
+
+/// Asynchronous I/O of a queue copying the chunks the guest writes first.
+pub struct CopyBeforeWriteIo {
+    async_io: Box<dyn AsyncIo>,
+    copy_before_write: Arc<CopyBeforeWrite>,
+}
+
+impl CopyBeforeWriteIo {
+    pub fn new(async_io: Box<dyn AsyncIo>, copy_before_write: Arc<CopyBeforeWrite>) -> Self {
+        CopyBeforeWriteIo {
+            async_io,
+            copy_before_write,
+        }
+    }
+}
+
+fn iovecs_len(iovecs: &[libc::iovec]) -> u64 {
+    iovecs.iter().map(|iovec| iovec.iov_len as u64).sum()
+}
+
+impl AsyncIo for CopyBeforeWriteIo {
+    fn notifier(&self) -> &EventFd {
+        self.async_io.notifier()
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.async_io.read_vectored(offset, iovecs, user_data)
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.copy_before_write
+            .before_write(offset as u64, iovecs_len(iovecs));
+        self.async_io.write_vectored(offset, iovecs, user_data)
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.async_io.fsync(user_data)
+    }
+
+    fn punch_hole(&mut self, offset: u64, length: u64, user_data: u64) -> AsyncIoResult<()> {
+        self.copy_before_write.before_write(offset, length);
+        self.async_io.punch_hole(offset, length, user_data)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, length: u64, user_data: u64) -> AsyncIoResult<()> {
+        self.copy_before_write.before_write(offset, length);
+        self.async_io.write_zeroes(offset, length, user_data)
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.async_io.next_completed_request()
+    }
+
+    fn batch_requests_enabled(&self) -> bool {
+        self.async_io.batch_requests_enabled()
+    }
+
+    fn submit_batch_requests(&mut self, batch_request: &[BatchRequest]) -> AsyncIoResult<()> {
+        for request in batch_request {
+            if request.request_type == RequestType::Out {
+                self.copy_before_write
+                    .before_write(request.offset as u64, iovecs_len(&request.iovecs));
+            }
+        }
+        self.async_io.submit_batch_requests(batch_request)
+    }
+
+    fn alignment(&self) -> u64 {
+        self.async_io.alignment()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::{Read, Write};
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+    use crate::async_io::DiskFile;
+    use crate::qcow::RawFile;
+    use crate::raw_sync::RawFileDiskSync;
+
+    const CHUNK_SIZE: u64 = 0x1_0000;
+
+    fn write_disk(async_io: &mut dyn AsyncIo, offset: u64, byte: u8) {
+        let mut buf = vec![byte; 0x1000];
+        let iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        async_io
+            .write_vectored(offset as libc::off_t, &[iovec], 1)
+            .unwrap();
+        assert_eq!(async_io.next_completed_request(), Some((1, 0x1000)));
+    }
+
+    #[test]
+    fn copy_before_write_keeps_the_initial_content() {
+        let size = 4 * CHUNK_SIZE;
+        let disk_temp = TempFile::new().unwrap();
+        disk_temp
+            .as_file()
+            .write_all(&vec![0xab; size as usize])
+            .unwrap();
+        let target_temp = TempFile::new().unwrap();
+        target_temp.as_file().set_len(size).unwrap();
+
+        let disk = RawFileDiskSync::new(disk_temp.as_file().try_clone().unwrap());
+        let pending = Arc::new(DirtyBitmap::new(size, CHUNK_SIZE));
+        pending.set_all();
+        let copied = Arc::new(AtomicU64::new(0));
+        let copy_before_write = Arc::new(CopyBeforeWrite::new(
+            disk.new_async_io(1).unwrap(),
+            Box::new(RawFile::new(
+                target_temp.as_file().try_clone().unwrap(),
+                false,
+            )),
+            pending,
+            copied.clone(),
+        ));
+        let mut async_io =
+            CopyBeforeWriteIo::new(disk.new_async_io(1).unwrap(), copy_before_write.clone());
+
+        // The chunk written by the guest is copied first, once.
+        write_disk(&mut async_io, CHUNK_SIZE + 0x1000, 0xcd);
+        write_disk(&mut async_io, CHUNK_SIZE, 0xef);
+        assert_eq!(copied.load(Ordering::Acquire), CHUNK_SIZE);
+        assert_eq!(copy_before_write.remaining_bytes(), 3 * CHUNK_SIZE);
+
+        let stop = AtomicBool::new(false);
+        assert_eq!(
+            copy_before_write.copy_pending(&stop).unwrap(),
+            3 * CHUNK_SIZE
+        );
+        copy_before_write.flush().unwrap();
+        assert_eq!(copy_before_write.remaining_bytes(), 0);
+
+        // The writes once the chunks are copied don't reach the target.
+        write_disk(&mut async_io, 3 * CHUNK_SIZE, 0x12);
+
+        let mut data = Vec::new();
+        File::open(target_temp.as_path())
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert!(data.iter().all(|&b| b == 0xab));
+    }
+}
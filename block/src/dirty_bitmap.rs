@@ -0,0 +1,443 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tracking of the parts of a disk written by the guest.
+//!
+//! The queues of a virtio-blk device mark the chunks their write, discard
+//! and write zeroes requests touched once they complete, while another
+//! thread collects them, for instance to copy them elsewhere.
+//!
+//! A bitmap kept across runs is stored in the image when its format can hold
+//! one, as qcow2 does, or else in a [`DirtyBitmapFile`] next to it.
+
+use std::cmp::min;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::warn;
+
+const BITS_PER_WORD: u64 = u64::BITS as u64;
+const BYTES_PER_WORD: usize = size_of::<u64>();
+
+/// Bitmap of the chunks of a disk written since they were last taken, each
+/// bit covering `granularity` bytes.
+#[derive(Debug)]
+pub struct DirtyBitmap {
+    size: u64,
+    granularity: u64,
+    words: Vec<AtomicU64>,
+}
+
+impl DirtyBitmap {
+    /// Creates a clean bitmap for a disk of `size` bytes. `granularity` must
+    /// be a power of two.
+    pub fn new(size: u64, granularity: u64) -> Self {
+        assert!(granularity.is_power_of_two());
+        let chunks = size.div_ceil(granularity);
+        DirtyBitmap {
+            size,
+            granularity,
+            words: (0..chunks.div_ceil(BITS_PER_WORD))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+
+    /// Creates a bitmap for a disk of `size` bytes from its bits, as returned
+    /// by [`Self::to_bytes`]. Missing bits are clean, the ones past the end of
+    /// the disk are ignored.
+    pub fn from_bytes(size: u64, granularity: u64, bytes: &[u8]) -> Self {
+        let bitmap = Self::new(size, granularity);
+        for (word, bytes) in bitmap.words.iter().zip(bytes.chunks(BYTES_PER_WORD)) {
+            let mut le_bytes = [0u8; BYTES_PER_WORD];
+            le_bytes[..bytes.len()].copy_from_slice(bytes);
+            word.store(u64::from_le_bytes(le_bytes), Ordering::Release);
+        }
+        let chunks = size.div_ceil(granularity);
+        if !chunks.is_multiple_of(BITS_PER_WORD)
+            && let Some(last) = bitmap.words.last()
+        {
+            last.fetch_and((1 << (chunks % BITS_PER_WORD)) - 1, Ordering::AcqRel);
+        }
+        bitmap
+    }
+
+    /// Returns the bits of the bitmap, the first chunk being the least
+    /// significant bit of the first byte.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = self
+            .words
+            .iter()
+            .flat_map(|word| word.load(Ordering::Acquire).to_le_bytes())
+            .collect();
+        bytes.truncate(self.size.div_ceil(self.granularity).div_ceil(8) as usize);
+        bytes
+    }
+
+    /// Returns a copy of the bitmap for a disk grown or shrunk to `size`
+    /// bytes, the chunks added being dirty.
+    pub fn resized(&self, size: u64) -> Self {
+        let bitmap = Self::from_bytes(size, self.granularity, &self.to_bytes());
+        if size > self.size {
+            bitmap.set(self.size, size - self.size);
+        }
+        bitmap
+    }
+
+    /// Returns the size of the disk the bitmap covers.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the number of bytes covered by each bit.
+    pub fn granularity(&self) -> u64 {
+        self.granularity
+    }
+
+    /// Marks the chunks overlapping `len` bytes at `offset` as dirty. The part
+    /// of the range beyond the end of the disk is ignored.
+    pub fn set(&self, offset: u64, len: u64) {
+        if len == 0 || offset >= self.size {
+            return;
+        }
+        let end = min(offset.saturating_add(len), self.size);
+        let last = (end - 1) / self.granularity;
+        let mut chunk = offset / self.granularity;
+        while chunk <= last {
+            let bit = chunk % BITS_PER_WORD;
+            let count = min(BITS_PER_WORD - bit, last - chunk + 1);
+            let mask = if count == BITS_PER_WORD {
+                u64::MAX
+            } else {
+                ((1 << count) - 1) << bit
+            };
+            self.words[(chunk / BITS_PER_WORD) as usize].fetch_or(mask, Ordering::AcqRel);
+            chunk += count;
+        }
+    }
+
+    /// Returns true if the chunk holding `offset` is dirty.
+    pub fn is_set(&self, offset: u64) -> bool {
+        if offset >= self.size {
+            return false;
+        }
+        let chunk = offset / self.granularity;
+        self.words[(chunk / BITS_PER_WORD) as usize].load(Ordering::Acquire)
+            & (1 << (chunk % BITS_PER_WORD))
+            != 0
+    }
+
+    /// Returns the number of dirty chunks.
+    pub fn count(&self) -> u64 {
+        self.words
+            .iter()
+            .map(|word| u64::from(word.load(Ordering::Acquire).count_ones()))
+            .sum()
+    }
+
+    /// Returns the number of dirty bytes, the last chunk possibly being
+    /// partial.
+    pub fn dirty_bytes(&self) -> u64 {
+        let mut bytes = self.count() * self.granularity;
+        if !self.size.is_multiple_of(self.granularity) && self.is_set(self.size - 1) {
+            bytes -= self.granularity - self.size % self.granularity;
+        }
+        bytes
+    }
+
+    /// Cleans the first dirty chunk starting at or after `offset` and returns
+    /// its offset, or `None` if there is none.
+    pub fn take_next(&self, offset: u64) -> Option<u64> {
+        let mut chunk = offset.div_ceil(self.granularity);
+        while chunk < self.size.div_ceil(self.granularity) {
+            let word = &self.words[(chunk / BITS_PER_WORD) as usize];
+            let bits = word.load(Ordering::Acquire) & (u64::MAX << (chunk % BITS_PER_WORD));
+            if bits == 0 {
+                chunk = (chunk / BITS_PER_WORD + 1) * BITS_PER_WORD;
+                continue;
+            }
+            let bit = u64::from(bits.trailing_zeros());
+            word.fetch_and(!(1 << bit), Ordering::AcqRel);
+            return Some(((chunk / BITS_PER_WORD) * BITS_PER_WORD + bit) * self.granularity);
+        }
+        None
+    }
+
+    /// Cleans the chunk holding `offset` and returns true if it was dirty.
+    pub fn take(&self, offset: u64) -> bool {
+        if offset >= self.size {
+            return false;
+        }
+        let chunk = offset / self.granularity;
+        let mask = 1 << (chunk % BITS_PER_WORD);
+        self.words[(chunk / BITS_PER_WORD) as usize].fetch_and(!mask, Ordering::AcqRel) & mask != 0
+    }
+
+    /// Marks the chunks dirty in `other`, a bitmap of the same size and
+    /// granularity, as dirty.
+    pub fn merge(&self, other: &DirtyBitmap) {
+        for (word, other) in self.words.iter().zip(&other.words) {
+            word.fetch_or(other.load(Ordering::Acquire), Ordering::AcqRel);
+        }
+    }
+
+    /// Marks the whole disk as dirty.
+    pub fn set_all(&self) {
+        self.set(0, self.size);
+    }
+
+    /// Marks the whole disk as clean.
+    pub fn clear(&self) {
+        for word in &self.words {
+            word.store(0, Ordering::Release);
+        }
+    }
+}
+
+impl Clone for DirtyBitmap {
+    fn clone(&self) -> Self {
+        DirtyBitmap {
+            size: self.size,
+            granularity: self.granularity,
+            words: self
+                .words
+                .iter()
+                .map(|word| AtomicU64::new(word.load(Ordering::Acquire)))
+                .collect(),
+        }
+    }
+}
+
+const DIRTY_BITMAP_FILE_MAGIC: &[u8; 8] = b"CHDIRTY\0";
+const DIRTY_BITMAP_FILE_VERSION: u32 = 1;
+// The bitmap was loaded and may have been written to since.
+const DIRTY_BITMAP_FILE_IN_USE: u32 = 1 << 0;
+// Magic, version, flags, disk size and granularity.
+const DIRTY_BITMAP_FILE_HEADER_SIZE: usize = 32;
+
+/// A dirty bitmap stored in its own file, for the disk images that can't hold
+/// one.
+///
+/// The file is flagged in use while the bitmap is loaded, so a bitmap left
+/// behind by a crash is not trusted: the whole disk is dirty instead.
+#[derive(Debug)]
+pub struct DirtyBitmapFile {
+    path: PathBuf,
+}
+
+impl DirtyBitmapFile {
+    /// Creates the bitmap file at `path`. Nothing is written until the
+    /// bitmap is loaded.
+    pub fn new(path: &Path) -> Self {
+        DirtyBitmapFile {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Returns the path of the bitmap file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the bitmap of a disk of `size` bytes and flags the file in use.
+    /// The whole disk is dirty if the file is missing, still in use or for
+    /// another disk size or granularity.
+    pub fn load(&self, size: u64, granularity: u64) -> io::Result<DirtyBitmap> {
+        let bitmap = self.read(size, granularity)?.unwrap_or_else(|| {
+            let bitmap = DirtyBitmap::new(size, granularity);
+            bitmap.set_all();
+            bitmap
+        });
+        self.store(&bitmap, true)?;
+        Ok(bitmap)
+    }
+
+    /// Stores `bitmap`, replacing the previous file at once. A bitmap stored
+    /// `in_use` is only trusted for the disk size and granularity checks, its
+    /// chunks are all dirty on the next load.
+    pub fn store(&self, bitmap: &DirtyBitmap, in_use: bool) -> io::Result<()> {
+        let mut data = Vec::with_capacity(DIRTY_BITMAP_FILE_HEADER_SIZE);
+        data.extend_from_slice(DIRTY_BITMAP_FILE_MAGIC);
+        data.extend_from_slice(&DIRTY_BITMAP_FILE_VERSION.to_le_bytes());
+        let flags = if in_use { DIRTY_BITMAP_FILE_IN_USE } else { 0 };
+        data.extend_from_slice(&flags.to_le_bytes());
+        data.extend_from_slice(&bitmap.size().to_le_bytes());
+        data.extend_from_slice(&bitmap.granularity().to_le_bytes());
+        data.extend_from_slice(&bitmap.to_bytes());
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // Returns the bitmap stored in the file, or `None` if it can't be trusted.
+    fn read(&self, size: u64, granularity: u64) -> io::Result<Option<DirtyBitmap>> {
+        let mut data = Vec::new();
+        match File::open(&self.path) {
+            Ok(mut file) => file.read_to_end(&mut data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if data.len() < DIRTY_BITMAP_FILE_HEADER_SIZE || &data[..8] != DIRTY_BITMAP_FILE_MAGIC {
+            warn!("Invalid dirty bitmap file {}", self.path.display());
+            return Ok(None);
+        }
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        if u32_at(8) != DIRTY_BITMAP_FILE_VERSION {
+            warn!("Unsupported dirty bitmap file {}", self.path.display());
+            return Ok(None);
+        }
+        if u32_at(12) & DIRTY_BITMAP_FILE_IN_USE != 0 {
+            warn!(
+                "Dirty bitmap file {} was not stored, the disk is all dirty",
+                self.path.display()
+            );
+            return Ok(None);
+        }
+        if u64_at(16) != size || u64_at(24) != granularity {
+            return Ok(None);
+        }
+        Ok(Some(DirtyBitmap::from_bytes(
+            size,
+            granularity,
+            &data[DIRTY_BITMAP_FILE_HEADER_SIZE..],
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vmm_sys_util::tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn dirty_bitmap_set_and_take() {
+        let bitmap = DirtyBitmap::new(0x100_0000, 0x1_0000);
+        assert_eq!(bitmap.count(), 0);
+        assert_eq!(bitmap.take_next(0), None);
+
+        bitmap.set(0x1_8000, 0x1_0000);
+        bitmap.set(0x40_0000, 1);
+        bitmap.set(0x20_0000, 0);
+        assert_eq!(bitmap.count(), 3);
+        assert!(bitmap.is_set(0x1_0000));
+        assert!(bitmap.is_set(0x2_ffff));
+        assert!(!bitmap.is_set(0x3_0000));
+
+        assert_eq!(bitmap.take_next(0x1_0001), Some(0x2_0000));
+        assert_eq!(bitmap.take_next(0), Some(0x1_0000));
+        assert_eq!(bitmap.take_next(0), Some(0x40_0000));
+        assert_eq!(bitmap.take_next(0), None);
+        assert_eq!(bitmap.count(), 0);
+
+        bitmap.set(0x1_0000, 1);
+        assert!(bitmap.take(0x1_ffff));
+        assert!(!bitmap.take(0x1_0000));
+        assert!(!bitmap.take(0x100_0000));
+
+        let other = DirtyBitmap::new(0x100_0000, 0x1_0000);
+        other.set(0x30_0000, 0x2_0000);
+        bitmap.set(0x30_0000, 1);
+        bitmap.set(0x60_0000, 1);
+        bitmap.merge(&other);
+        assert_eq!(bitmap.count(), 3);
+        assert!(bitmap.is_set(0x31_0000));
+        assert!(bitmap.is_set(0x60_0000));
+    }
+
+    #[test]
+    fn dirty_bitmap_ranges() {
+        // Ranges spanning several words, up to the partial last chunk.
+        let bitmap = DirtyBitmap::new(200 * 0x1000 + 0x200, 0x1000);
+        bitmap.set(0x3_f000, 0x4_1000);
+        assert_eq!(bitmap.count(), 65);
+        assert!(bitmap.is_set(0x3_f000));
+        assert!(bitmap.is_set(0x7_f000));
+        assert!(!bitmap.is_set(0x8_0000));
+
+        bitmap.set(199 * 0x1000, u64::MAX);
+        assert_eq!(bitmap.count(), 67);
+        assert_eq!(bitmap.dirty_bytes(), 66 * 0x1000 + 0x200);
+        assert_eq!(bitmap.take_next(0x8_0000), Some(199 * 0x1000));
+        assert_eq!(bitmap.take_next(0x8_0000), Some(200 * 0x1000));
+
+        bitmap.clear();
+        assert_eq!(bitmap.count(), 0);
+        bitmap.set(200 * 0x1000 + 0x200, 0x1000);
+        assert_eq!(bitmap.count(), 0);
+    }
+
+    #[test]
+    fn dirty_bitmap_bytes_and_resize() {
+        let bitmap = DirtyBitmap::new(70 * 0x1000, 0x1000);
+        bitmap.set(0, 1);
+        bitmap.set(9 * 0x1000, 1);
+        bitmap.set(69 * 0x1000, 1);
+        let bytes = bitmap.to_bytes();
+        assert_eq!(bytes.len(), 9);
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(bytes[1], 0x02);
+        assert_eq!(bytes[8], 0x20);
+
+        // Bits past the end of the disk are dropped.
+        let mut bytes = bytes;
+        bytes[8] |= 0xc0;
+        let loaded = DirtyBitmap::from_bytes(70 * 0x1000, 0x1000, &bytes);
+        assert_eq!(loaded.count(), 3);
+        assert_eq!(loaded.clone().to_bytes(), bitmap.to_bytes());
+
+        let grown = bitmap.resized(72 * 0x1000);
+        assert_eq!(grown.count(), 5);
+        assert!(grown.is_set(71 * 0x1000));
+        let shrunk = bitmap.resized(10 * 0x1000);
+        assert_eq!(shrunk.count(), 2);
+
+        bitmap.set_all();
+        assert_eq!(bitmap.count(), 70);
+    }
+
+    #[test]
+    fn dirty_bitmap_file() {
+        let dir = TempDir::new().unwrap();
+        let file = DirtyBitmapFile::new(&dir.as_path().join("disk.dirty-bitmap"));
+
+        // A new bitmap is all dirty.
+        let bitmap = file.load(0x10_0000, 0x1_0000).unwrap();
+        assert_eq!(bitmap.count(), 16);
+        bitmap.clear();
+        bitmap.set(0x3_0000, 1);
+        file.store(&bitmap, false).unwrap();
+        let bitmap = file.load(0x10_0000, 0x1_0000).unwrap();
+        assert_eq!(bitmap.count(), 1);
+        assert!(bitmap.is_set(0x3_0000));
+
+        // The file is in use until stored again, as after a crash.
+        assert_eq!(file.load(0x10_0000, 0x1_0000).unwrap().count(), 16);
+        file.store(&bitmap, false).unwrap();
+        // Another granularity makes the disk all dirty.
+        assert_eq!(file.load(0x10_0000, 0x1000).unwrap().count(), 256);
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use remain::sorted;
+use thiserror::Error;
+
+use crate::vhd::checksum;
+
+/// "cxsparse"
+const DYNAMIC_HEADER_COOKIE: u64 = 0x6378_7370_6172_7365;
+const DYNAMIC_HEADER_VERSION: u32 = 0x0001_0000;
+pub const DYNAMIC_HEADER_SIZE: usize = 1024;
+
+// Offsets of the fields of the dynamic disk header
+const DATA_OFFSET_OFFSET: usize = 8;
+const TABLE_OFFSET_OFFSET: usize = 16;
+const VERSION_OFFSET: usize = 24;
+const MAX_TABLE_ENTRIES_OFFSET: usize = 28;
+const BLOCK_SIZE_OFFSET: usize = 32;
+const CHECKSUM_OFFSET: usize = 36;
+const PARENT_UNIQUE_ID_OFFSET: usize = 40;
+const PARENT_TIME_STAMP_OFFSET: usize = 56;
+const PARENT_NAME_OFFSET: usize = 64;
+const PARENT_NAME_SIZE: usize = 512;
+const PARENT_LOCATORS_OFFSET: usize = 576;
+const PARENT_LOCATOR_SIZE: usize = 24;
+pub const PARENT_LOCATOR_COUNT: usize = 8;
+
+// Platform codes of the parent locators
+/// Relative Windows path, in UTF-16LE
+pub const PLATFORM_CODE_W2RU: u32 = 0x5732_7275;
+/// Absolute Windows path, in UTF-16LE
+pub const PLATFORM_CODE_W2KU: u32 = 0x5732_6b75;
+/// File URL, in UTF-8
+pub const PLATFORM_CODE_MACX: u32 = 0x4d61_6358;
+
+// Parent locators larger than this are not paths.
+const MAX_PARENT_LOCATOR_SIZE: u32 = 64 << 10;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum DynamicVhdHeaderError {
+    #[error("Invalid block size {0}")]
+    InvalidBlockSize(u32),
+    #[error("Invalid dynamic disk header checksum")]
+    InvalidChecksum,
+    #[error("Invalid dynamic disk header cookie")]
+    InvalidCookie,
+    #[error("Invalid parent locator")]
+    InvalidParentLocator,
+    #[error("Failed to read the dynamic disk header")]
+    ReadHeader(#[source] io::Error),
+    #[error("Failed to read a parent locator")]
+    ReadParentLocator(#[source] io::Error),
+    #[error("Unsupported dynamic disk header version {0:#x}")]
+    UnsupportedVersion(u32),
+}
+
+pub type Result<T> = std::result::Result<T, DynamicVhdHeaderError>;
+
+/// Entry of the dynamic disk header telling where the parent of a
+/// differencing disk may be found
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParentLocator {
+    pub platform_code: u32,
+    pub data_space: u32,
+    pub data_length: u32,
+    pub data_offset: u64,
+}
+
+impl ParentLocator {
+    /// Reads the path stored by the locator, None if its platform code isn't
+    /// one of a path.
+    pub fn read_path(&self, f: &File) -> Result<Option<String>> {
+        if !matches!(
+            self.platform_code,
+            PLATFORM_CODE_W2RU | PLATFORM_CODE_W2KU | PLATFORM_CODE_MACX
+        ) {
+            return Ok(None);
+        }
+        if self.data_length > MAX_PARENT_LOCATOR_SIZE {
+            return Err(DynamicVhdHeaderError::InvalidParentLocator);
+        }
+
+        let mut data = vec![0u8; self.data_length as usize];
+        f.read_exact_at(&mut data, self.data_offset)
+            .map_err(DynamicVhdHeaderError::ReadParentLocator)?;
+        let path = if self.platform_code == PLATFORM_CODE_MACX {
+            let url =
+                String::from_utf8(data).map_err(|_| DynamicVhdHeaderError::InvalidParentLocator)?;
+            let path = url
+                .strip_prefix("file://")
+                .ok_or(DynamicVhdHeaderError::InvalidParentLocator)?;
+            path.strip_prefix("localhost").unwrap_or(path).to_string()
+        } else {
+            let data: Vec<u16> = data.chunks_exact(2).map(LittleEndian::read_u16).collect();
+            String::from_utf16(&data).map_err(|_| DynamicVhdHeaderError::InvalidParentLocator)?
+        };
+        Ok(Some(path.trim_end_matches('\0').to_string()))
+    }
+}
+
+/// Header of the dynamic and differencing disks, following the copy of the
+/// footer at the start of the file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DynamicHeader {
+    pub table_offset: u64,
+    pub max_table_entries: u32,
+    pub block_size: u32,
+    pub parent_unique_id: u128,
+    pub parent_time_stamp: u32,
+    pub parent_name: String,
+    pub parent_locators: [ParentLocator; PARENT_LOCATOR_COUNT],
+}
+
+impl DynamicHeader {
+    /// Reads the header found at `offset` in `f`.
+    pub fn read_from(f: &File, offset: u64) -> Result<DynamicHeader> {
+        let mut data = [0u8; DYNAMIC_HEADER_SIZE];
+        f.read_exact_at(&mut data, offset)
+            .map_err(DynamicVhdHeaderError::ReadHeader)?;
+
+        if BigEndian::read_u64(&data) != DYNAMIC_HEADER_COOKIE {
+            return Err(DynamicVhdHeaderError::InvalidCookie);
+        }
+        let version = BigEndian::read_u32(&data[VERSION_OFFSET..]);
+        if version != DYNAMIC_HEADER_VERSION {
+            return Err(DynamicVhdHeaderError::UnsupportedVersion(version));
+        }
+        if BigEndian::read_u32(&data[CHECKSUM_OFFSET..]) != checksum(&data, CHECKSUM_OFFSET) {
+            return Err(DynamicVhdHeaderError::InvalidChecksum);
+        }
+        let block_size = BigEndian::read_u32(&data[BLOCK_SIZE_OFFSET..]);
+        if !block_size.is_power_of_two() || block_size < 512 {
+            return Err(DynamicVhdHeaderError::InvalidBlockSize(block_size));
+        }
+
+        let name: Vec<u16> = data[PARENT_NAME_OFFSET..][..PARENT_NAME_SIZE]
+            .chunks_exact(2)
+            .map(BigEndian::read_u16)
+            .take_while(|&c| c != 0)
+            .collect();
+        let mut parent_locators = [ParentLocator::default(); PARENT_LOCATOR_COUNT];
+        for (i, locator) in parent_locators.iter_mut().enumerate() {
+            let entry = &data[PARENT_LOCATORS_OFFSET + i * PARENT_LOCATOR_SIZE..];
+            *locator = ParentLocator {
+                platform_code: BigEndian::read_u32(entry),
+                data_space: BigEndian::read_u32(&entry[4..]),
+                data_length: BigEndian::read_u32(&entry[8..]),
+                data_offset: BigEndian::read_u64(&entry[16..]),
+            };
+        }
+
+        Ok(DynamicHeader {
+            table_offset: BigEndian::read_u64(&data[TABLE_OFFSET_OFFSET..]),
+            max_table_entries: BigEndian::read_u32(&data[MAX_TABLE_ENTRIES_OFFSET..]),
+            block_size,
+            parent_unique_id: u128::from_be_bytes(
+                data[PARENT_UNIQUE_ID_OFFSET..][..16].try_into().unwrap(),
+            ),
+            parent_time_stamp: BigEndian::read_u32(&data[PARENT_TIME_STAMP_OFFSET..]),
+            parent_name: String::from_utf16_lossy(&name),
+            parent_locators,
+        })
+    }
+
+    /// Returns the header as stored on disk, with its checksum.
+    pub fn to_bytes(&self) -> [u8; DYNAMIC_HEADER_SIZE] {
+        let mut data = [0u8; DYNAMIC_HEADER_SIZE];
+        BigEndian::write_u64(&mut data, DYNAMIC_HEADER_COOKIE);
+        BigEndian::write_u64(&mut data[DATA_OFFSET_OFFSET..], u64::MAX);
+        BigEndian::write_u64(&mut data[TABLE_OFFSET_OFFSET..], self.table_offset);
+        BigEndian::write_u32(&mut data[VERSION_OFFSET..], DYNAMIC_HEADER_VERSION);
+        BigEndian::write_u32(
+            &mut data[MAX_TABLE_ENTRIES_OFFSET..],
+            self.max_table_entries,
+        );
+        BigEndian::write_u32(&mut data[BLOCK_SIZE_OFFSET..], self.block_size);
+        data[PARENT_UNIQUE_ID_OFFSET..][..16].copy_from_slice(&self.parent_unique_id.to_be_bytes());
+        BigEndian::write_u32(
+            &mut data[PARENT_TIME_STAMP_OFFSET..],
+            self.parent_time_stamp,
+        );
+        // The name is truncated to fit, keeping a terminating NUL.
+        for (i, c) in self
+            .parent_name
+            .encode_utf16()
+            .take(PARENT_NAME_SIZE / 2 - 1)
+            .enumerate()
+        {
+            BigEndian::write_u16(&mut data[PARENT_NAME_OFFSET + i * 2..], c);
+        }
+        for (i, locator) in self.parent_locators.iter().enumerate() {
+            let entry = &mut data[PARENT_LOCATORS_OFFSET + i * PARENT_LOCATOR_SIZE..];
+            BigEndian::write_u32(entry, locator.platform_code);
+            BigEndian::write_u32(&mut entry[4..], locator.data_space);
+            BigEndian::write_u32(&mut entry[8..], locator.data_length);
+            BigEndian::write_u64(&mut entry[16..], locator.data_offset);
+        }
+        let checksum = checksum(&data, CHECKSUM_OFFSET);
+        BigEndian::write_u32(&mut data[CHECKSUM_OFFSET..], checksum);
+        data
+    }
+}
@@ -0,0 +1,882 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Dynamic and differencing VHD images.
+//!
+//! Both are made of a copy of the footer, a dynamic disk header and a Block
+//! Allocation Table (BAT) mapping each block of the virtual disk to its
+//! location in the file. Blocks are allocated on the first write to them, at
+//! the end of the file, each one starting with a bitmap of the sectors it
+//! holds. Sectors which aren't held by a differencing disk are read from its
+//! parent, found through the locators of the dynamic disk header.
+
+use std::cmp::min;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+use remain::sorted;
+use thiserror::Error;
+
+use crate::BlockBackend;
+use crate::dynamic_vhd::dynamic_vhd_header::{
+    DYNAMIC_HEADER_SIZE, DynamicHeader, DynamicVhdHeaderError, PLATFORM_CODE_W2KU,
+    PLATFORM_CODE_W2RU, ParentLocator,
+};
+use crate::fixed_vhd::FixedVhd;
+use crate::vhd::{
+    DISK_TYPE_DIFFERENCING, DISK_TYPE_DYNAMIC, FOOTER_CHECKSUM_OFFSET, VHD_COOKIE, VHD_FOOTER_SIZE,
+    VHD_VERSION, VhdFooter, checksum, is_dynamic_vhd, is_fixed_vhd, set_footer_checksum,
+};
+
+mod dynamic_vhd_header;
+
+const SECTOR_SIZE: u64 = 512;
+/// BAT entry of a block which isn't allocated
+const BAT_ENTRY_UNUSED: u32 = 0xffff_ffff;
+/// Block size used by Hyper-V and by default when creating images
+pub const DEFAULT_BLOCK_SIZE: u32 = 2 << 20;
+/// Maximum length of a chain of differencing disks
+pub const MAX_NESTING_DEPTH: u32 = 64;
+/// Number of seconds between the Unix epoch and the VHD one, 2000-01-01
+const VHD_EPOCH_OFFSET: u64 = 946_684_800;
+const CREATOR_APPLICATION: &[u8; 4] = b"chv ";
+const CREATOR_VERSION: u32 = 0x0001_0000;
+const CREATOR_HOST_OS_WINDOWS: &[u8; 4] = b"Wi2k";
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum DynamicVhdError {
+    #[error("Backing file support is disabled")]
+    BackingFilesDisabled,
+    #[error("Invalid Block Allocation Table")]
+    InvalidBat,
+    #[error("Invalid block size {0}")]
+    InvalidBlockSize(u32),
+    #[error("Invalid disk size {0}")]
+    InvalidDiskSize(u64),
+    #[error("Invalid VHD footer")]
+    InvalidFooter,
+    #[error("Maximum disk nesting depth exceeded")]
+    MaxNestingDepthExceeded,
+    #[error("Failed to find the parent of the differencing disk")]
+    MissingParent,
+    #[error("Failed to open the parent disk {0:?}")]
+    OpenParent(PathBuf, #[source] io::Error),
+    #[error("The unique id of the parent disk doesn't match the differencing disk")]
+    ParentMismatch,
+    #[error("Failed to parse the dynamic disk header")]
+    ParseHeader(#[source] DynamicVhdHeaderError),
+    #[error("Failed to read the Block Allocation Table")]
+    ReadBat(#[source] io::Error),
+    #[error("Failed to read the VHD footer")]
+    ReadFooter(#[source] io::Error),
+    #[error("Failed to read a parent locator")]
+    ReadParentLocator(#[source] DynamicVhdHeaderError),
+    #[error("The parent disk {0:?} isn't a VHD")]
+    UnsupportedParent(PathBuf),
+    #[error("Failed to write the image metadata")]
+    WriteMetadata(#[source] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, DynamicVhdError>;
+
+#[derive(Debug)]
+pub struct DynamicVhd {
+    file: File,
+    // Footer as stored on disk, moved to the end of the file as it grows
+    footer: [u8; VHD_FOOTER_SIZE],
+    size: u64,
+    block_size: u64,
+    bitmap_size: u64,
+    table_offset: u64,
+    bat: Vec<u32>,
+    // Offset of the next allocated block, where the footer currently is
+    next_block_offset: u64,
+    parent: Option<Box<dyn BlockBackend>>,
+    position: u64,
+}
+
+impl DynamicVhd {
+    /// Opens a dynamic or a differencing VHD, along with the chain of its
+    /// parents.
+    pub fn new(file: File) -> Result<DynamicVhd> {
+        Self::from_with_nesting_depth(file, MAX_NESTING_DEPTH)
+    }
+
+    /// Opens a dynamic or a differencing VHD, with at most
+    /// `max_nesting_depth` parents.
+    pub fn from_with_nesting_depth(file: File, max_nesting_depth: u32) -> Result<DynamicVhd> {
+        let footer_data = read_footer(&file)?;
+        let footer = VhdFooter::from_sector(&footer_data);
+        let header = DynamicHeader::read_from(&file, footer.data_offset())
+            .map_err(DynamicVhdError::ParseHeader)?;
+
+        let size = footer.current_size();
+        let block_size = u64::from(header.block_size);
+        let entries = size.div_ceil(block_size);
+        let file_size = file.metadata().map_err(DynamicVhdError::ReadFooter)?.len();
+        if entries > u64::from(header.max_table_entries)
+            || header.table_offset + entries * 4 > file_size
+        {
+            return Err(DynamicVhdError::InvalidBat);
+        }
+        let mut table = vec![0u8; entries as usize * 4];
+        file.read_exact_at(&mut table, header.table_offset)
+            .map_err(DynamicVhdError::ReadBat)?;
+        let bat: Vec<u32> = table.chunks_exact(4).map(BigEndian::read_u32).collect();
+
+        let bitmap_size = bitmap_size(block_size);
+        // New blocks go after all the metadata and the allocated blocks, and
+        // never before the footer so that nothing is truncated.
+        let metadata_end = header
+            .parent_locators
+            .iter()
+            .map(|l| l.data_offset + u64::from(l.data_length))
+            .chain([
+                footer.data_offset() + DYNAMIC_HEADER_SIZE as u64,
+                header.table_offset + u64::from(header.max_table_entries) * 4,
+                file_size - VHD_FOOTER_SIZE as u64,
+            ])
+            .chain(
+                bat.iter()
+                    .filter(|&&e| e != BAT_ENTRY_UNUSED)
+                    .map(|&e| u64::from(e) * SECTOR_SIZE + bitmap_size + block_size),
+            )
+            .max()
+            .unwrap();
+
+        let parent = if footer.disk_type() == DISK_TYPE_DIFFERENCING {
+            Some(open_parent(&file, &header, max_nesting_depth)?)
+        } else {
+            None
+        };
+
+        Ok(DynamicVhd {
+            file,
+            footer: footer_data,
+            size,
+            block_size,
+            bitmap_size,
+            table_offset: header.table_offset,
+            bat,
+            next_block_offset: metadata_end.next_multiple_of(SECTOR_SIZE),
+            parent,
+            position: 0,
+        })
+    }
+
+    /// Creates an empty dynamic VHD of `size` bytes in `file`.
+    pub fn create(file: File, size: u64, block_size: u32) -> Result<DynamicVhd> {
+        if size == 0 || !size.is_multiple_of(SECTOR_SIZE) {
+            return Err(DynamicVhdError::InvalidDiskSize(size));
+        }
+        let header = DynamicHeader {
+            block_size,
+            ..Default::default()
+        };
+        Self::create_image(&file, size, DISK_TYPE_DYNAMIC, header, None)?;
+        Self::new(file)
+    }
+
+    /// Creates in `file` a differencing VHD on top of the VHD found at
+    /// `parent_path`. The path is stored as given in the parent locators, a
+    /// relative one being relative to the directory of `file`.
+    pub fn create_differencing(file: File, parent_path: &Path) -> Result<DynamicVhd> {
+        let resolved = match child_dir(&file) {
+            Some(dir) if parent_path.is_relative() => dir.join(parent_path),
+            _ => parent_path.to_path_buf(),
+        };
+        let open_error = |e| DynamicVhdError::OpenParent(resolved.clone(), e);
+        let mut parent_file = File::open(&resolved).map_err(open_error)?;
+        let parent_footer = VhdFooter::new(&mut parent_file).map_err(open_error)?;
+        let block_size = if is_dynamic_vhd(&mut parent_file).map_err(open_error)? {
+            DynamicHeader::read_from(&parent_file, parent_footer.data_offset())
+                .map_err(DynamicVhdError::ParseHeader)?
+                .block_size
+        } else if is_fixed_vhd(&mut parent_file).map_err(open_error)? {
+            DEFAULT_BLOCK_SIZE
+        } else {
+            return Err(DynamicVhdError::UnsupportedParent(resolved));
+        };
+
+        let path = parent_path.to_string_lossy();
+        let header = DynamicHeader {
+            block_size,
+            parent_unique_id: parent_footer.unique_id(),
+            parent_time_stamp: parent_footer.time_stamp(),
+            parent_name: path.to_string(),
+            ..Default::default()
+        };
+        let platform_code = if parent_path.is_relative() {
+            PLATFORM_CODE_W2RU
+        } else {
+            PLATFORM_CODE_W2KU
+        };
+        // Windows paths, as read by Hyper-V.
+        let locator: Vec<u8> = path
+            .replace('/', "\\")
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        Self::create_image(
+            &file,
+            parent_footer.current_size(),
+            DISK_TYPE_DIFFERENCING,
+            header,
+            Some((platform_code, &locator)),
+        )?;
+        Self::new(file)
+    }
+
+    /// Writes the metadata of an image without any allocated block: the copy
+    /// of the footer, the dynamic disk header, the BAT, the parent locator
+    /// and the footer.
+    fn create_image(
+        file: &File,
+        size: u64,
+        disk_type: u32,
+        mut header: DynamicHeader,
+        locator: Option<(u32, &[u8])>,
+    ) -> Result<()> {
+        if !header.block_size.is_power_of_two() || u64::from(header.block_size) < SECTOR_SIZE {
+            return Err(DynamicVhdError::InvalidBlockSize(header.block_size));
+        }
+        let entries = u32::try_from(size.div_ceil(u64::from(header.block_size)))
+            .map_err(|_| DynamicVhdError::InvalidDiskSize(size))?;
+        let header_offset = VHD_FOOTER_SIZE as u64;
+        header.table_offset = header_offset + DYNAMIC_HEADER_SIZE as u64;
+        header.max_table_entries = entries;
+        let table_size = (u64::from(entries) * 4).next_multiple_of(SECTOR_SIZE);
+        let mut end = header.table_offset + table_size;
+
+        if let Some((platform_code, data)) = locator {
+            let data_space = (data.len() as u64).next_multiple_of(SECTOR_SIZE);
+            header.parent_locators[0] = ParentLocator {
+                platform_code,
+                data_space: data_space as u32,
+                data_length: data.len() as u32,
+                data_offset: end,
+            };
+            end += data_space;
+        }
+
+        let footer = new_footer(size, disk_type, header_offset);
+        file.set_len(0).map_err(DynamicVhdError::WriteMetadata)?;
+        file.set_len(end + VHD_FOOTER_SIZE as u64)
+            .map_err(DynamicVhdError::WriteMetadata)?;
+        file.write_all_at(&footer, 0)
+            .map_err(DynamicVhdError::WriteMetadata)?;
+        file.write_all_at(&header.to_bytes(), header_offset)
+            .map_err(DynamicVhdError::WriteMetadata)?;
+        file.write_all_at(&vec![0xff; table_size as usize], header.table_offset)
+            .map_err(DynamicVhdError::WriteMetadata)?;
+        if let Some((_, data)) = locator {
+            file.write_all_at(data, header.parent_locators[0].data_offset)
+                .map_err(DynamicVhdError::WriteMetadata)?;
+        }
+        file.write_all_at(&footer, end)
+            .map_err(DynamicVhdError::WriteMetadata)?;
+        file.sync_all().map_err(DynamicVhdError::WriteMetadata)
+    }
+
+    pub fn virtual_disk_size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns true for a differencing disk.
+    pub fn has_parent(&self) -> bool {
+        self.parent.is_some()
+    }
+
+    fn read_bitmap(&self, entry: u32) -> io::Result<Vec<u8>> {
+        let mut bitmap = vec![0u8; self.bitmap_size as usize];
+        self.file
+            .read_exact_at(&mut bitmap, u64::from(entry) * SECTOR_SIZE)?;
+        Ok(bitmap)
+    }
+
+    /// Reads from the parent of a differencing disk, zeros for a dynamic one.
+    fn read_parent(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        buf.fill(0);
+        if let Some(parent) = self.parent.as_mut() {
+            let parent_size = parent.logical_size().map_err(io::Error::other)?;
+            let count = min(buf.len() as u64, parent_size.saturating_sub(offset)) as usize;
+            if count > 0 {
+                parent.seek(SeekFrom::Start(offset))?;
+                parent.read_exact(&mut buf[..count])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads `buf` at `offset` in `block`, `buf` not crossing the end of
+    /// the block.
+    fn read_block(&mut self, block: u64, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let block_offset = block * self.block_size;
+        let entry = self.bat[block as usize];
+        if entry == BAT_ENTRY_UNUSED {
+            return self.read_parent(block_offset + offset, buf);
+        }
+        let data_offset = u64::from(entry) * SECTOR_SIZE + self.bitmap_size;
+        if self.parent.is_none() {
+            return self.file.read_exact_at(buf, data_offset + offset);
+        }
+
+        // Take each run of sectors from the differencing disk or from its
+        // parent, according to the sector bitmap.
+        let bitmap = self.read_bitmap(entry)?;
+        let end = offset + buf.len() as u64;
+        let mut start = offset;
+        while start < end {
+            let present = sector_present(&bitmap, start / SECTOR_SIZE);
+            let mut run_end = (start / SECTOR_SIZE + 1) * SECTOR_SIZE;
+            while run_end < end && sector_present(&bitmap, run_end / SECTOR_SIZE) == present {
+                run_end += SECTOR_SIZE;
+            }
+            let run_end = min(run_end, end);
+            let data = &mut buf[(start - offset) as usize..(run_end - offset) as usize];
+            if present {
+                self.file.read_exact_at(data, data_offset + start)?;
+            } else {
+                self.read_parent(block_offset + start, data)?;
+            }
+            start = run_end;
+        }
+        Ok(())
+    }
+
+    /// Allocates `block` at the end of the file, returning its BAT entry.
+    fn allocate_block(&mut self, block: u64) -> io::Result<u32> {
+        let offset = self.next_block_offset;
+        let entry = u32::try_from(offset / SECTOR_SIZE)
+            .ok()
+            .filter(|&e| e != BAT_ENTRY_UNUSED)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOSPC))?;
+        let end = offset + self.bitmap_size + self.block_size;
+
+        // Move the footer first, so that the file stays valid if the
+        // allocation is interrupted.
+        self.file.set_len(end + VHD_FOOTER_SIZE as u64)?;
+        self.file.write_all_at(&self.footer, end)?;
+        // All the sectors of a block of a dynamic disk are present, while
+        // those of a differencing disk are still read from its parent.
+        let fill = if self.parent.is_some() { 0 } else { 0xff };
+        self.file
+            .write_all_at(&vec![fill; self.bitmap_size as usize], offset)?;
+        self.file
+            .write_all_at(&entry.to_be_bytes(), self.table_offset + block * 4)?;
+
+        self.bat[block as usize] = entry;
+        self.next_block_offset = end;
+        Ok(entry)
+    }
+
+    /// Writes `buf` at `offset` in `block`, `buf` not crossing the end of
+    /// the block.
+    fn write_block(&mut self, block: u64, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let entry = match self.bat[block as usize] {
+            BAT_ENTRY_UNUSED => self.allocate_block(block)?,
+            entry => entry,
+        };
+        let bitmap_offset = u64::from(entry) * SECTOR_SIZE;
+        let data_offset = bitmap_offset + self.bitmap_size;
+        if self.parent.is_none() {
+            return self.file.write_all_at(buf, data_offset + offset);
+        }
+
+        let mut bitmap = self.read_bitmap(entry)?;
+        let end = offset + buf.len() as u64;
+        let first = offset / SECTOR_SIZE;
+        let last = (end - 1) / SECTOR_SIZE;
+        // Sectors partially written which aren't in the differencing disk
+        // yet are first copied from the parent.
+        let mut changed = false;
+        for sector in [first, last] {
+            let start = sector * SECTOR_SIZE;
+            if !sector_present(&bitmap, sector) && (start < offset || start + SECTOR_SIZE > end) {
+                let mut data = [0u8; SECTOR_SIZE as usize];
+                self.read_parent(block * self.block_size + start, &mut data)?;
+                self.file.write_all_at(&data, data_offset + start)?;
+                changed |= set_sector_present(&mut bitmap, sector);
+            }
+        }
+        self.file.write_all_at(buf, data_offset + offset)?;
+
+        for sector in first..=last {
+            changed |= set_sector_present(&mut bitmap, sector);
+        }
+        if changed {
+            self.file.write_all_at(&bitmap, bitmap_offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for DynamicVhd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Read for DynamicVhd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = min(buf.len() as u64, self.size.saturating_sub(self.position)) as usize;
+        let mut done = 0;
+        while done < len {
+            let offset = self.position + done as u64;
+            let block = offset / self.block_size;
+            let block_offset = offset % self.block_size;
+            let count = min((len - done) as u64, self.block_size - block_offset) as usize;
+            self.read_block(block, block_offset, &mut buf[done..done + count])?;
+            done += count;
+        }
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for DynamicVhd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = min(buf.len() as u64, self.size.saturating_sub(self.position)) as usize;
+        if len == 0 && !buf.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+        let mut done = 0;
+        while done < len {
+            let offset = self.position + done as u64;
+            let block = offset / self.block_size;
+            let block_offset = offset % self.block_size;
+            let count = min((len - done) as u64, self.block_size - block_offset) as usize;
+            self.write_block(block, block_offset, &buf[done..done + count])?;
+            done += count;
+        }
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+impl Seek for DynamicVhd {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => self.size.checked_add_signed(off),
+            SeekFrom::Current(off) => self.position.checked_add_signed(off),
+        };
+
+        if let Some(p) = new_position
+            && p <= self.size
+        {
+            self.position = p;
+            return Ok(p);
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Failed seek operation",
+        ))
+    }
+}
+
+impl BlockBackend for DynamicVhd {
+    fn logical_size(&self) -> std::result::Result<u64, crate::Error> {
+        Ok(self.size)
+    }
+
+    fn physical_size(&self) -> std::result::Result<u64, crate::Error> {
+        self.file
+            .metadata()
+            .map(|m| m.len())
+            .map_err(crate::Error::GetFileMetadata)
+    }
+}
+
+fn bitmap_size(block_size: u64) -> u64 {
+    (block_size / SECTOR_SIZE)
+        .div_ceil(8)
+        .next_multiple_of(SECTOR_SIZE)
+}
+
+fn sector_present(bitmap: &[u8], sector: u64) -> bool {
+    bitmap[(sector / 8) as usize] & (0x80 >> (sector % 8)) != 0
+}
+
+/// Marks `sector` as present, returning true if it wasn't.
+fn set_sector_present(bitmap: &mut [u8], sector: u64) -> bool {
+    let byte = &mut bitmap[(sector / 8) as usize];
+    let bit = 0x80 >> (sector % 8);
+    let changed = *byte & bit == 0;
+    *byte |= bit;
+    changed
+}
+
+fn footer_is_valid(data: &[u8]) -> bool {
+    let footer = VhdFooter::from_sector(data);
+    footer.cookie() == VHD_COOKIE
+        && footer.file_format_version() == VHD_VERSION
+        && matches!(
+            footer.disk_type(),
+            DISK_TYPE_DYNAMIC | DISK_TYPE_DIFFERENCING
+        )
+        && footer.data_offset() != u64::MAX
+        && footer.checksum() == checksum(data, FOOTER_CHECKSUM_OFFSET)
+}
+
+/// Reads the footer at the end of the file, or its copy at the start of the
+/// file if it is damaged.
+fn read_footer(file: &File) -> Result<[u8; VHD_FOOTER_SIZE]> {
+    let file_size = file.metadata().map_err(DynamicVhdError::ReadFooter)?.len();
+    let mut footer = [0u8; VHD_FOOTER_SIZE];
+    for offset in [file_size.checked_sub(VHD_FOOTER_SIZE as u64), Some(0)]
+        .into_iter()
+        .flatten()
+    {
+        file.read_exact_at(&mut footer, offset)
+            .map_err(DynamicVhdError::ReadFooter)?;
+        if footer_is_valid(&footer) {
+            return Ok(footer);
+        }
+    }
+    Err(DynamicVhdError::InvalidFooter)
+}
+
+/// Builds the footer of a new image whose dynamic disk header is at
+/// `header_offset`.
+fn new_footer(size: u64, disk_type: u32, header_offset: u64) -> [u8; VHD_FOOTER_SIZE] {
+    let time_stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs().saturating_sub(VHD_EPOCH_OFFSET));
+    let mut footer = [0u8; VHD_FOOTER_SIZE];
+    BigEndian::write_u64(&mut footer[0..8], VHD_COOKIE);
+    // Reserved feature bit, always set
+    BigEndian::write_u32(&mut footer[8..12], 2);
+    BigEndian::write_u32(&mut footer[12..16], VHD_VERSION);
+    BigEndian::write_u64(&mut footer[16..24], header_offset);
+    BigEndian::write_u32(&mut footer[24..28], time_stamp as u32);
+    footer[28..32].copy_from_slice(CREATOR_APPLICATION);
+    BigEndian::write_u32(&mut footer[32..36], CREATOR_VERSION);
+    footer[36..40].copy_from_slice(CREATOR_HOST_OS_WINDOWS);
+    BigEndian::write_u64(&mut footer[40..48], size);
+    BigEndian::write_u64(&mut footer[48..56], size);
+    BigEndian::write_u32(&mut footer[56..60], disk_geometry(size));
+    BigEndian::write_u32(&mut footer[60..64], disk_type);
+    footer[68..84].copy_from_slice(&uuid::Uuid::new_v4().as_u128().to_be_bytes());
+    set_footer_checksum(&mut footer);
+    footer
+}
+
+/// CHS geometry of a disk of `size` bytes, as computed by the VHD
+/// specification.
+fn disk_geometry(size: u64) -> u32 {
+    let total_sectors = min(size / SECTOR_SIZE, 65535 * 16 * 255);
+    let (cylinders_times_heads, heads, sectors_per_track) = if total_sectors >= 65535 * 16 * 63 {
+        (total_sectors / 255, 16, 255)
+    } else {
+        let mut sectors_per_track = 17;
+        let mut cylinders_times_heads = total_sectors / sectors_per_track;
+        let mut heads = cylinders_times_heads.div_ceil(1024).max(4);
+        if cylinders_times_heads >= heads * 1024 || heads > 16 {
+            sectors_per_track = 31;
+            heads = 16;
+            cylinders_times_heads = total_sectors / sectors_per_track;
+        }
+        if cylinders_times_heads >= heads * 1024 {
+            sectors_per_track = 63;
+            heads = 16;
+            cylinders_times_heads = total_sectors / sectors_per_track;
+        }
+        (cylinders_times_heads, heads, sectors_per_track)
+    };
+    let cylinders = cylinders_times_heads / heads;
+    ((cylinders as u32) << 16) | ((heads as u32) << 8) | sectors_per_track as u32
+}
+
+/// Directory of the image opened as `file`, used to resolve the relative
+/// paths of its parent.
+fn child_dir(file: &File) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+}
+
+/// Candidate locations of a parent stored as `path`, Windows paths being
+/// converted. Absolute Windows paths can't be found as such, the parent
+/// being then looked up next to its child.
+fn parent_candidates(path: &str, dir: Option<&Path>) -> Vec<PathBuf> {
+    let path = PathBuf::from(path.replace('\\', "/"));
+    let mut candidates = Vec::new();
+    match dir {
+        Some(dir) => candidates.push(dir.join(&path)),
+        None => candidates.push(path.clone()),
+    }
+    if let (Some(dir), Some(name)) = (dir, path.file_name()) {
+        candidates.push(dir.join(name));
+    }
+    candidates
+}
+
+/// Opens the parent of the differencing disk `file`, looking it up through
+/// the parent locators and then the parent name.
+fn open_parent(
+    file: &File,
+    header: &DynamicHeader,
+    max_nesting_depth: u32,
+) -> Result<Box<dyn BlockBackend>> {
+    if max_nesting_depth == 0 {
+        return Err(DynamicVhdError::MaxNestingDepthExceeded);
+    }
+
+    let dir = child_dir(file);
+    let mut candidates = Vec::new();
+    for locator in &header.parent_locators {
+        if let Some(path) = locator
+            .read_path(file)
+            .map_err(DynamicVhdError::ReadParentLocator)?
+        {
+            candidates.extend(parent_candidates(&path, dir.as_deref()));
+        }
+    }
+    if !header.parent_name.is_empty() {
+        candidates.extend(parent_candidates(&header.parent_name, dir.as_deref()));
+    }
+    let path = candidates
+        .into_iter()
+        .find(|p| p.is_file())
+        .ok_or(DynamicVhdError::MissingParent)?;
+
+    let mut parent_file = OpenOptions::new()
+        .read(true)
+        .open(&path)
+        .map_err(|e| DynamicVhdError::OpenParent(path.clone(), e))?;
+    let footer = VhdFooter::new(&mut parent_file)
+        .map_err(|e| DynamicVhdError::OpenParent(path.clone(), e))?;
+    if footer.unique_id() != header.parent_unique_id {
+        return Err(DynamicVhdError::ParentMismatch);
+    }
+
+    if is_fixed_vhd(&mut parent_file).map_err(|e| DynamicVhdError::OpenParent(path.clone(), e))? {
+        let parent =
+            FixedVhd::new(parent_file).map_err(|e| DynamicVhdError::OpenParent(path, e))?;
+        Ok(Box::new(parent))
+    } else if is_dynamic_vhd(&mut parent_file)
+        .map_err(|e| DynamicVhdError::OpenParent(path.clone(), e))?
+    {
+        Ok(Box::new(DynamicVhd::from_with_nesting_depth(
+            parent_file,
+            max_nesting_depth - 1,
+        )?))
+    } else {
+        Err(DynamicVhdError::UnsupportedParent(path))
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    const BLOCK_SIZE: u32 = 64 << 10;
+    const DISK_SIZE: u64 = 1 << 20;
+
+    fn new_disk() -> DynamicVhd {
+        let file = TempFile::new().unwrap().into_file();
+        DynamicVhd::create(file, DISK_SIZE, BLOCK_SIZE).unwrap()
+    }
+
+    fn read_at(disk: &mut DynamicVhd, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        disk.seek(SeekFrom::Start(offset)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn write_at(disk: &mut DynamicVhd, offset: u64, data: &[u8]) {
+        disk.seek(SeekFrom::Start(offset)).unwrap();
+        disk.write_all(data).unwrap();
+    }
+
+    fn reopen(disk: &DynamicVhd) -> DynamicVhd {
+        DynamicVhd::new(disk.file.try_clone().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_create_is_dynamic_vhd() {
+        let mut disk = new_disk();
+        assert!(is_dynamic_vhd(&mut disk.file).unwrap());
+        assert!(!is_fixed_vhd(&mut disk.file).unwrap());
+        assert_eq!(disk.logical_size().unwrap(), DISK_SIZE);
+        assert!(!disk.has_parent());
+        assert_eq!(read_at(&mut disk, 0, 4096), vec![0u8; 4096]);
+    }
+
+    #[test]
+    fn test_write_read_reopen() {
+        let mut disk = new_disk();
+        let initial_size = disk.physical_size().unwrap();
+
+        // Crosses the boundary between the first two blocks.
+        let data: Vec<u8> = (0..8192).map(|i| i as u8).collect();
+        let offset = u64::from(BLOCK_SIZE) - 1000;
+        write_at(&mut disk, offset, &data);
+        assert_eq!(read_at(&mut disk, offset, data.len()), data);
+        assert_eq!(
+            disk.physical_size().unwrap(),
+            initial_size + 2 * (u64::from(BLOCK_SIZE) + 512)
+        );
+
+        // Rewriting allocated blocks doesn't grow the file.
+        write_at(&mut disk, offset, &data);
+        assert_eq!(
+            disk.physical_size().unwrap(),
+            initial_size + 2 * (u64::from(BLOCK_SIZE) + 512)
+        );
+
+        let mut disk = reopen(&disk);
+        assert_eq!(read_at(&mut disk, offset, data.len()), data);
+        assert_eq!(read_at(&mut disk, 0, 512), vec![0u8; 512]);
+        // Unallocated blocks read as zeros.
+        assert_eq!(
+            read_at(&mut disk, 3 * u64::from(BLOCK_SIZE), 512),
+            vec![0u8; 512]
+        );
+    }
+
+    #[test]
+    fn test_write_past_end() {
+        let mut disk = new_disk();
+        disk.seek(SeekFrom::Start(DISK_SIZE - 512)).unwrap();
+        assert_eq!(disk.write(&[1u8; 1024]).unwrap(), 512);
+        disk.write(&[1u8; 512]).unwrap_err();
+        assert_eq!(disk.read(&mut [0u8; 512]).unwrap(), 0);
+        disk.seek(SeekFrom::Start(DISK_SIZE + 1)).unwrap_err();
+    }
+
+    #[test]
+    fn test_damaged_footer() {
+        let mut disk = new_disk();
+        write_at(&mut disk, 0, &[3u8; 512]);
+        let end = disk.file.metadata().unwrap().len();
+        disk.file.write_all_at(&[0u8; 512], end - 512).unwrap();
+
+        // The copy of the footer at the start of the file is used.
+        let mut disk = reopen(&disk);
+        assert_eq!(read_at(&mut disk, 0, 512), vec![3u8; 512]);
+    }
+
+    #[test]
+    fn test_bad_header_checksum() {
+        let disk = new_disk();
+        let mut sector = [0u8; 512];
+        disk.file.read_exact_at(&mut sector, 512).unwrap();
+        sector[100] ^= 1;
+        disk.file.write_all_at(&sector, 512).unwrap();
+        assert!(matches!(
+            DynamicVhd::new(disk.file.try_clone().unwrap()),
+            Err(DynamicVhdError::ParseHeader(
+                DynamicVhdHeaderError::InvalidChecksum
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_disk_geometry() {
+        // 127 MiB, 8 GiB and the largest disk of the specification
+        assert_eq!(disk_geometry(127 << 20), (1019 << 16) | (15 << 8) | 17);
+        assert_eq!(disk_geometry(8 << 30), (16644 << 16) | (16 << 8) | 63);
+        assert_eq!(disk_geometry(u64::MAX), (65535 << 16) | (16 << 8) | 255);
+    }
+
+    #[test]
+    fn test_differencing() {
+        let dir = TempDir::new_with_prefix("/tmp/dynamic_vhd").unwrap();
+        let parent_path = dir.as_path().join("parent.vhd");
+        let parent_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&parent_path)
+            .unwrap();
+        let mut parent = DynamicVhd::create(parent_file, DISK_SIZE, BLOCK_SIZE).unwrap();
+        write_at(&mut parent, 0, &[1u8; 4096]);
+        write_at(&mut parent, u64::from(BLOCK_SIZE), &[2u8; 512]);
+        let parent_size = parent.physical_size().unwrap();
+
+        let child_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(dir.as_path().join("child.vhd"))
+            .unwrap();
+        let mut child =
+            DynamicVhd::create_differencing(child_file, Path::new("parent.vhd")).unwrap();
+        assert!(child.has_parent());
+        assert_eq!(child.logical_size().unwrap(), DISK_SIZE);
+        assert_eq!(read_at(&mut child, 0, 4096), vec![1u8; 4096]);
+
+        // Partial sectors keep the data of the parent around them.
+        write_at(&mut child, 1000, &[9u8; 100]);
+        write_at(&mut child, u64::from(BLOCK_SIZE) + 256, &[8u8; 512]);
+        let mut expected = vec![1u8; 4096];
+        expected[1000..1100].fill(9);
+        assert_eq!(read_at(&mut child, 0, 4096), expected);
+        let mut expected = vec![2u8; 256];
+        expected.extend([8u8; 512]);
+        expected.extend([0u8; 256]);
+        assert_eq!(read_at(&mut child, u64::from(BLOCK_SIZE), 1024), expected);
+
+        // The parent is left untouched.
+        assert_eq!(read_at(&mut parent, 0, 4096), vec![1u8; 4096]);
+        assert_eq!(parent.physical_size().unwrap(), parent_size);
+
+        // The parent is found again through the relative locator.
+        let mut child = reopen(&child);
+        let mut expected = vec![1u8; 4096];
+        expected[1000..1100].fill(9);
+        assert_eq!(read_at(&mut child, 0, 4096), expected);
+    }
+
+    #[test]
+    fn test_differencing_parent_mismatch() {
+        let dir = TempDir::new_with_prefix("/tmp/dynamic_vhd").unwrap();
+        let parent_path = dir.as_path().join("parent.vhd");
+        let new_file = |path: &Path| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .unwrap()
+        };
+        DynamicVhd::create(new_file(&parent_path), DISK_SIZE, BLOCK_SIZE).unwrap();
+        let child = DynamicVhd::create_differencing(
+            new_file(&dir.as_path().join("child.vhd")),
+            &parent_path,
+        )
+        .unwrap();
+
+        // Recreating the parent changes its unique id.
+        DynamicVhd::create(new_file(&parent_path), DISK_SIZE, BLOCK_SIZE).unwrap();
+        assert!(matches!(
+            DynamicVhd::new(child.file.try_clone().unwrap()),
+            Err(DynamicVhdError::ParentMismatch)
+        ));
+
+        std::fs::remove_file(&parent_path).unwrap();
+        assert!(matches!(
+            DynamicVhd::new(child.file.try_clone().unwrap()),
+            Err(DynamicVhdError::MissingParent)
+        ));
+    }
+}
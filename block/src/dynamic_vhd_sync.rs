@@ -0,0 +1,212 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, Mutex};
+
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, BorrowedDiskFd, DiskFile, DiskFileError, DiskFileResult,
+};
+use crate::dynamic_vhd::{
+    DynamicVhd, DynamicVhdError, MAX_NESTING_DEPTH, Result as DynamicVhdResult,
+};
+use crate::{AsyncAdaptor, BlockBackend, Error};
+
+pub struct DynamicVhdDiskSync {
+    // The Mutex serializes I/O across queues, as blocks are allocated at the
+    // end of the file and the parent chain is shared.
+    dynamic_vhd_file: Arc<Mutex<DynamicVhd>>,
+}
+
+impl DynamicVhdDiskSync {
+    /// Opens a dynamic or a differencing VHD, the parents of the latter
+    /// being only opened if `backing_files` is set.
+    pub fn new(f: File, backing_files: bool) -> DynamicVhdResult<Self> {
+        let max_nesting_depth = if backing_files { MAX_NESTING_DEPTH } else { 0 };
+        let dynamic_vhd =
+            DynamicVhd::from_with_nesting_depth(f, max_nesting_depth).map_err(|e| match e {
+                DynamicVhdError::MaxNestingDepthExceeded if !backing_files => {
+                    DynamicVhdError::BackingFilesDisabled
+                }
+                other => other,
+            })?;
+        Ok(DynamicVhdDiskSync {
+            dynamic_vhd_file: Arc::new(Mutex::new(dynamic_vhd)),
+        })
+    }
+}
+
+impl DiskFile for DynamicVhdDiskSync {
+    fn logical_size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.dynamic_vhd_file.lock().unwrap().virtual_disk_size())
+    }
+
+    fn physical_size(&mut self) -> DiskFileResult<u64> {
+        self.dynamic_vhd_file
+            .lock()
+            .unwrap()
+            .physical_size()
+            .map_err(|e| {
+                let io_inner = match e {
+                    Error::GetFileMetadata(e) => e,
+                    _ => unreachable!(),
+                };
+                DiskFileError::Size(io_inner)
+            })
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(DynamicVhdSync::new(Arc::clone(&self.dynamic_vhd_file))) as Box<dyn AsyncIo>)
+    }
+
+    fn fd(&mut self) -> BorrowedDiskFd<'_> {
+        BorrowedDiskFd::new(self.dynamic_vhd_file.lock().unwrap().as_raw_fd())
+    }
+}
+
+pub struct DynamicVhdSync {
+    dynamic_vhd_file: Arc<Mutex<DynamicVhd>>,
+    eventfd: EventFd,
+    completion_list: VecDeque<(u64, i32)>,
+}
+
+impl DynamicVhdSync {
+    pub fn new(dynamic_vhd_file: Arc<Mutex<DynamicVhd>>) -> Self {
+        DynamicVhdSync {
+            dynamic_vhd_file,
+            eventfd: EventFd::new(libc::EFD_NONBLOCK)
+                .expect("Failed creating EventFd for DynamicVhdSync"),
+            completion_list: VecDeque::new(),
+        }
+    }
+}
+
+impl AsyncAdaptor for DynamicVhd {}
+
+impl AsyncIo for DynamicVhdSync {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.dynamic_vhd_file.lock().unwrap().read_vectored_sync(
+            offset,
+            iovecs,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.dynamic_vhd_file.lock().unwrap().write_vectored_sync(
+            offset,
+            iovecs,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.dynamic_vhd_file.lock().unwrap().fsync_sync(
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.completion_list.pop_front()
+    }
+
+    fn punch_hole(&mut self, _offset: u64, _length: u64, _user_data: u64) -> AsyncIoResult<()> {
+        Err(AsyncIoError::PunchHole(std::io::Error::other(
+            "punch_hole not supported for dynamic VHD",
+        )))
+    }
+
+    fn write_zeroes(&mut self, _offset: u64, _length: u64, _user_data: u64) -> AsyncIoResult<()> {
+        Err(AsyncIoError::WriteZeroes(std::io::Error::other(
+            "write_zeroes not supported for dynamic VHD",
+        )))
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::fs::OpenOptions;
+    use std::path::Path;
+
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    #[test]
+    fn test_dynamic_vhd_sync_write_read() {
+        let file = TempFile::new().unwrap().into_file();
+        DynamicVhd::create(file.try_clone().unwrap(), 4 << 20, 1 << 20).unwrap();
+        let disk = DynamicVhdDiskSync::new(file, false).unwrap();
+        let mut async_io = disk.new_async_io(1).unwrap();
+
+        let mut data = vec![0xa5u8; 8192];
+        let iovec = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        async_io
+            .write_vectored((1 << 20) - 4096, &[iovec], 1)
+            .unwrap();
+        assert_eq!(async_io.next_completed_request(), Some((1, 8192)));
+
+        let mut buf = vec![0u8; 8192];
+        let iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        async_io
+            .read_vectored((1 << 20) - 4096, &[iovec], 2)
+            .unwrap();
+        assert_eq!(async_io.next_completed_request(), Some((2, 8192)));
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn backing_files_disabled_error() {
+        let dir = TempDir::new_with_prefix("/tmp/dynamic_vhd_sync").unwrap();
+        let new_file = |name: &str| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(dir.as_path().join(name))
+                .unwrap()
+        };
+        DynamicVhd::create(new_file("parent.vhd"), 1 << 20, 1 << 20).unwrap();
+        let child = new_file("child.vhd");
+        DynamicVhd::create_differencing(child.try_clone().unwrap(), Path::new("parent.vhd"))
+            .unwrap();
+
+        assert!(matches!(
+            DynamicVhdDiskSync::new(child.try_clone().unwrap(), false),
+            Err(DynamicVhdError::BackingFilesDisabled)
+        ));
+        DynamicVhdDiskSync::new(child, true).unwrap();
+    }
+}
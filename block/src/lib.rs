@@ -9,13 +9,23 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 pub mod async_io;
+pub mod commit;
+pub mod copy_before_write;
+pub mod dirty_bitmap;
+pub mod dynamic_vhd;
+pub mod dynamic_vhd_sync;
 pub mod fcntl;
 pub mod fixed_vhd;
 #[cfg(feature = "io_uring")]
 /// Enabled with the `"io_uring"` feature
 pub mod fixed_vhd_async;
 pub mod fixed_vhd_sync;
+pub mod luks;
+pub mod luks_sync;
 pub mod qcow;
+#[cfg(feature = "io_uring")]
+/// Enabled with the `"io_uring"` feature
+pub mod qcow_async;
 pub mod qcow_sync;
 #[cfg(feature = "io_uring")]
 /// Async primitives based on `io-uring`
@@ -263,6 +273,8 @@ pub struct Request {
     pub writeback: bool,
     pub aligned_operations: SmallVec<[AlignedOperation; DEFAULT_DESCRIPTOR_VEC_SIZE]>,
     pub start: Instant,
+    /// Offset and length in bytes of the range a discard or write zeroes request clears.
+    pub zeroed_range: Option<(u64, u64)>,
 }
 
 impl Request {
@@ -294,6 +306,7 @@ impl Request {
             writeback: true,
             aligned_operations: SmallVec::with_capacity(DEFAULT_DESCRIPTOR_VEC_SIZE),
             start: Instant::now(),
+            zeroed_range: None,
         };
 
         let status_desc;
@@ -602,6 +615,7 @@ impl Request {
                 disk_image
                     .punch_hole(discard_offset, discard_length, user_data)
                     .map_err(ExecuteError::AsyncPunchHole)?;
+                self.zeroed_range = Some((discard_offset, discard_length));
             }
             RequestType::WriteZeroes => {
                 let (data_addr, data_len) = if self.data_descriptors.len() == 1 {
@@ -634,6 +648,7 @@ impl Request {
                 disk_image
                     .write_zeroes(wz_offset, wz_length, user_data)
                     .map_err(ExecuteError::AsyncWriteZeroes)?;
+                self.zeroed_range = Some((wz_offset, wz_length));
             }
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         }
@@ -674,6 +689,22 @@ impl Request {
     pub fn set_writeback(&mut self, writeback: bool) {
         self.writeback = writeback;
     }
+
+    /// Returns the offset and length in bytes of the range of the disk the
+    /// request modifies, if any.
+    pub fn written_range(&self) -> Option<(u64, u64)> {
+        match self.request_type {
+            RequestType::Out => Some((
+                self.sector << SECTOR_SHIFT,
+                self.data_descriptors
+                    .iter()
+                    .map(|&(_, len)| u64::from(len))
+                    .sum(),
+            )),
+            RequestType::Discard | RequestType::WriteZeroes => self.zeroed_range,
+            _ => None,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
@@ -1005,7 +1036,9 @@ pub trait AsyncAdaptor {
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
 pub enum ImageType {
+    DynamicVhd,
     FixedVhd,
+    Luks,
     Qcow2,
     Raw,
     Vhdx,
@@ -1016,7 +1049,9 @@ pub enum ImageType {
 impl fmt::Display for ImageType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            ImageType::DynamicVhd => write!(f, "dynamic-vhd"),
             ImageType::FixedVhd => write!(f, "vhd"),
+            ImageType::Luks => write!(f, "luks"),
             ImageType::Qcow2 => write!(f, "qcow2"),
             ImageType::Raw => write!(f, "raw"),
             ImageType::Vhdx => write!(f, "vhdx"),
@@ -1034,7 +1069,9 @@ impl FromStr for ImageType {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
+            "dynamic-vhd" => Ok(ImageType::DynamicVhd),
             "vhd" => Ok(ImageType::FixedVhd),
+            "luks" => Ok(ImageType::Luks),
             "qcow2" => Ok(ImageType::Qcow2),
             "raw" => Ok(ImageType::Raw),
             "vhdx" => Ok(ImageType::Vhdx),
@@ -1072,8 +1109,12 @@ pub fn detect_image_type(f: &mut File) -> std::io::Result<ImageType> {
         ImageType::Qcow2
     } else if vhd::is_fixed_vhd(f)? {
         ImageType::FixedVhd
+    } else if vhd::is_dynamic_vhd(f)? {
+        ImageType::DynamicVhd
     } else if u64::from_le_bytes(block[0..8].try_into().unwrap()) == VHDX_SIGN {
         ImageType::Vhdx
+    } else if luks::LuksHeader::is_luks(&block) {
+        ImageType::Luks
     } else {
         ImageType::Raw
     };
@@ -0,0 +1,315 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cryptographic primitives of LUKS2: AES-XTS sector encryption, the
+//! anti-forensic splitter protecting the key slots and the key derivation
+//! functions.
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::{Aes128, Aes256};
+use argon2::{Algorithm, Argon2, Params, Version};
+use remain::sorted;
+use sha2::{Digest, Sha256, Sha512};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+const AES_BLOCK_SIZE: usize = 16;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum LuksCryptoError {
+    #[error("Invalid argon2 parameters")]
+    Argon2(#[source] argon2::Error),
+    #[error("Invalid AES-XTS key size {0}")]
+    InvalidKeySize(usize),
+    #[error("Failed to generate random bytes")]
+    Random(#[source] getrandom::Error),
+    #[error("Unsupported hash {0}")]
+    UnsupportedHash(String),
+}
+
+pub type Result<T> = std::result::Result<T, LuksCryptoError>;
+
+/// Hash functions used by the key derivation and the anti-forensic splitter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hash {
+    Sha256,
+    Sha512,
+}
+
+impl Hash {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "sha256" => Ok(Hash::Sha256),
+            "sha512" => Ok(Hash::Sha512),
+            _ => Err(LuksCryptoError::UnsupportedHash(name.to_string())),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Hash::Sha256 => "sha256",
+            Hash::Sha512 => "sha512",
+        }
+    }
+
+    fn digest(self, parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            Hash::Sha256 => {
+                let mut hasher = Sha256::new();
+                parts.iter().for_each(|part| hasher.update(part));
+                hasher.finalize().to_vec()
+            }
+            Hash::Sha512 => {
+                let mut hasher = Sha512::new();
+                parts.iter().for_each(|part| hasher.update(part));
+                hasher.finalize().to_vec()
+            }
+        }
+    }
+
+    fn output_size(self) -> usize {
+        match self {
+            Hash::Sha256 => 32,
+            Hash::Sha512 => 64,
+        }
+    }
+}
+
+/// Fill `buf` with random bytes from the OS.
+pub fn random_bytes(buf: &mut [u8]) -> Result<()> {
+    getrandom::fill(buf).map_err(LuksCryptoError::Random)
+}
+
+/// PBKDF2 with HMAC over `hash`.
+pub fn pbkdf2(hash: Hash, password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    match hash {
+        Hash::Sha256 => pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, out),
+        Hash::Sha512 => pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, iterations, out),
+    }
+}
+
+/// Argon2 version 1.3, `memory` being in KiB.
+pub fn argon2(
+    algorithm: Algorithm,
+    password: &[u8],
+    salt: &[u8],
+    time: u32,
+    memory: u32,
+    cpus: u32,
+    out: &mut [u8],
+) -> Result<()> {
+    let params =
+        Params::new(memory, time, cpus, Some(out.len())).map_err(LuksCryptoError::Argon2)?;
+    Argon2::new(algorithm, Version::V0x13, params)
+        .hash_password_into(password, salt, out)
+        .map_err(LuksCryptoError::Argon2)
+}
+
+/// Diffuse `data` by hashing each of its digest sized blocks with its index,
+/// as done between the stripes of the anti-forensic splitter.
+fn diffuse(hash: Hash, data: &mut [u8]) {
+    for (i, block) in data.chunks_mut(hash.output_size()).enumerate() {
+        let digest = hash.digest(&[&(i as u32).to_be_bytes(), block]);
+        block.copy_from_slice(&digest[..block.len()]);
+    }
+}
+
+/// Split `key` into `stripes` random looking stripes, all of them being
+/// needed to recover it, so that wiping any part of a key slot destroys it.
+pub fn af_split(key: &[u8], stripes: usize, hash: Hash) -> Result<Zeroizing<Vec<u8>>> {
+    let mut material = Zeroizing::new(vec![0u8; key.len() * stripes]);
+    let mut d = Zeroizing::new(vec![0u8; key.len()]);
+    let (random, last) = material.split_at_mut(key.len() * (stripes - 1));
+    random_bytes(random)?;
+    for stripe in random.chunks(key.len()) {
+        d.iter_mut().zip(stripe).for_each(|(d, s)| *d ^= s);
+        diffuse(hash, &mut d);
+    }
+    for ((out, d), k) in last.iter_mut().zip(d.iter()).zip(key) {
+        *out = d ^ k;
+    }
+    Ok(material)
+}
+
+/// Recover a key of `key_size` bytes from its anti-forensic stripes.
+pub fn af_merge(
+    material: &[u8],
+    key_size: usize,
+    stripes: usize,
+    hash: Hash,
+) -> Zeroizing<Vec<u8>> {
+    let mut d = Zeroizing::new(vec![0u8; key_size]);
+    let mut stripes = material[..key_size * stripes].chunks(key_size);
+    let last = stripes.next_back().unwrap();
+    for stripe in stripes {
+        d.iter_mut().zip(stripe).for_each(|(d, s)| *d ^= s);
+        diffuse(hash, &mut d);
+    }
+    d.iter_mut().zip(last).for_each(|(d, s)| *d ^= s);
+    d
+}
+
+enum Aes {
+    Aes128(Box<Aes128>),
+    Aes256(Box<Aes256>),
+}
+
+impl Aes {
+    fn new(key: &[u8]) -> Self {
+        match key.len() {
+            16 => Aes::Aes128(Box::new(Aes128::new(GenericArray::from_slice(key)))),
+            _ => Aes::Aes256(Box::new(Aes256::new(GenericArray::from_slice(key)))),
+        }
+    }
+
+    fn encrypt(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            Aes::Aes128(aes) => aes.encrypt_block(block),
+            Aes::Aes256(aes) => aes.encrypt_block(block),
+        }
+    }
+
+    fn decrypt(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            Aes::Aes128(aes) => aes.decrypt_block(block),
+            Aes::Aes256(aes) => aes.decrypt_block(block),
+        }
+    }
+}
+
+/// AES in XTS mode with the plain64 IV, the sector number in little endian.
+pub struct XtsCipher {
+    data: Aes,
+    tweak: Aes,
+}
+
+impl XtsCipher {
+    /// `key` is the data key followed by the tweak key, both of 128 or 256
+    /// bits.
+    pub fn new(key: &[u8]) -> Result<Self> {
+        if key.len() != 32 && key.len() != 64 {
+            return Err(LuksCryptoError::InvalidKeySize(key.len()));
+        }
+        let (data, tweak) = key.split_at(key.len() / 2);
+        Ok(XtsCipher {
+            data: Aes::new(data),
+            tweak: Aes::new(tweak),
+        })
+    }
+
+    /// Encrypt `buf` in place, a whole number of sectors of `sector_size`
+    /// bytes, the first one being `sector`.
+    pub fn encrypt(&self, sector: u64, sector_size: usize, buf: &mut [u8]) {
+        for (i, data) in buf.chunks_mut(sector_size).enumerate() {
+            self.xts(sector + i as u64, data, true);
+        }
+    }
+
+    /// Decrypt `buf` in place, a whole number of sectors of `sector_size`
+    /// bytes, the first one being `sector`.
+    pub fn decrypt(&self, sector: u64, sector_size: usize, buf: &mut [u8]) {
+        for (i, data) in buf.chunks_mut(sector_size).enumerate() {
+            self.xts(sector + i as u64, data, false);
+        }
+    }
+
+    fn xts(&self, sector: u64, data: &mut [u8], encrypt: bool) {
+        let mut tweak = u128::from(sector).to_le_bytes();
+        self.tweak.encrypt(&mut tweak);
+        let mut tweak = u128::from_le_bytes(tweak);
+        for block in data.chunks_exact_mut(AES_BLOCK_SIZE) {
+            let t = tweak.to_le_bytes();
+            block.iter_mut().zip(t).for_each(|(b, t)| *b ^= t);
+            if encrypt {
+                self.data.encrypt(block);
+            } else {
+                self.data.decrypt(block);
+            }
+            block.iter_mut().zip(t).for_each(|(b, t)| *b ^= t);
+            // Multiply the tweak by x in GF(2^128).
+            tweak = (tweak << 1) ^ ((tweak >> 127) * 0x87);
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_xts_ieee1619_vectors() {
+        // Vector 1
+        let cipher = XtsCipher::new(&[0u8; 32]).unwrap();
+        let mut data = [0u8; 32];
+        cipher.encrypt(0, 32, &mut data);
+        assert_eq!(
+            data.to_vec(),
+            hex("917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e")
+        );
+        cipher.decrypt(0, 32, &mut data);
+        assert_eq!(data, [0u8; 32]);
+
+        // Vector 2
+        let mut key = vec![0x11u8; 16];
+        key.extend([0x22u8; 16]);
+        let cipher = XtsCipher::new(&key).unwrap();
+        let mut data = [0x44u8; 32];
+        cipher.encrypt(0x33_3333_3333, 32, &mut data);
+        assert_eq!(
+            data.to_vec(),
+            hex("c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0")
+        );
+    }
+
+    #[test]
+    fn test_xts_sectors() {
+        let cipher = XtsCipher::new(&[7u8; 64]).unwrap();
+        let plain: Vec<u8> = (0..2048).map(|i| i as u8).collect();
+        let mut whole = plain.clone();
+        cipher.encrypt(10, 512, &mut whole);
+        assert_ne!(whole, plain);
+
+        // Each sector only depends on its own number.
+        let mut third = plain[1024..1536].to_vec();
+        cipher.encrypt(12, 512, &mut third);
+        assert_eq!(third, whole[1024..1536]);
+
+        cipher.decrypt(10, 512, &mut whole);
+        assert_eq!(whole, plain);
+        assert!(XtsCipher::new(&[0u8; 48]).is_err());
+    }
+
+    #[test]
+    fn test_af_split_merge() {
+        let key: Vec<u8> = (0..64).collect();
+        for hash in [Hash::Sha256, Hash::Sha512] {
+            let material = af_split(&key, 4000, hash).unwrap();
+            assert_eq!(material.len(), 64 * 4000);
+            assert_eq!(*af_merge(&material, 64, 4000, hash), key);
+
+            let mut damaged = material.clone();
+            damaged[1000] ^= 1;
+            assert_ne!(*af_merge(&damaged, 64, 4000, hash), key);
+        }
+    }
+
+    #[test]
+    fn test_pbkdf2_sha256() {
+        // RFC 7914 test vector
+        let mut out = [0u8; 64];
+        pbkdf2(Hash::Sha256, b"passwd", b"salt", 1, &mut out);
+        assert_eq!(out[..16].to_vec(), hex("55ac046e56e3089fec1691c22544b605"));
+    }
+}
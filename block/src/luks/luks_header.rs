@@ -0,0 +1,227 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Binary headers of LUKS2. The header is stored twice, the secondary copy
+//! following the primary one, each made of a 4KiB binary header followed by
+//! the JSON metadata and protected by a checksum over both.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use byteorder::{BigEndian, ByteOrder};
+use remain::sorted;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::luks::luks_crypto::{LuksCryptoError, random_bytes};
+use crate::luks::luks_metadata::Metadata;
+
+pub const LUKS_MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+const LUKS_SECONDARY_MAGIC: &[u8; 6] = b"SKUL\xba\xbe";
+const LUKS_VERSION: u16 = 2;
+
+/// Size of the binary part of each header
+pub const BINARY_HEADER_SIZE: u64 = 4096;
+
+/// Size of each header, binary part and JSON area, used when creating images
+pub const DEFAULT_HEADER_SIZE: u64 = 16 << 10;
+
+/// Valid sizes of a header, the secondary header being found right after the
+/// primary one
+const HEADER_SIZES: [u64; 9] = [
+    16 << 10,
+    32 << 10,
+    64 << 10,
+    128 << 10,
+    256 << 10,
+    512 << 10,
+    1 << 20,
+    2 << 20,
+    4 << 20,
+];
+
+const CHECKSUM_ALGORITHM: &str = "sha256";
+
+// Offsets of the fields of the binary header
+const VERSION_OFFSET: usize = 6;
+const HDR_SIZE_OFFSET: usize = 8;
+const SEQID_OFFSET: usize = 16;
+const LABEL_OFFSET: usize = 24;
+const LABEL_SIZE: usize = 48;
+const CHECKSUM_ALG_OFFSET: usize = 72;
+const CHECKSUM_ALG_SIZE: usize = 32;
+const SALT_OFFSET: usize = 104;
+const SALT_SIZE: usize = 64;
+const UUID_OFFSET: usize = 168;
+const UUID_SIZE: usize = 40;
+const HDR_OFFSET_OFFSET: usize = 256;
+const CHECKSUM_OFFSET: usize = 448;
+const CHECKSUM_SIZE: usize = 64;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum LuksHeaderError {
+    #[error("Invalid JSON metadata")]
+    InvalidMetadata(#[source] serde_json::Error),
+    #[error("Not a LUKS image")]
+    NotLuks,
+    #[error("No valid LUKS2 header found")]
+    NoValidHeader,
+    #[error("Failed to read the LUKS header")]
+    ReadHeader(#[source] io::Error),
+    #[error("Failed to generate the header salt")]
+    Salt(#[source] LuksCryptoError),
+    #[error("The JSON metadata doesn't fit in the header")]
+    TooLargeMetadata,
+    #[error("Unsupported LUKS version {0}")]
+    UnsupportedVersion(u16),
+    #[error("Failed to write the LUKS header")]
+    WriteHeader(#[source] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, LuksHeaderError>;
+
+/// Header of a LUKS2 image, as read from its most recent valid copy.
+#[derive(Clone, Debug)]
+pub struct LuksHeader {
+    pub hdr_size: u64,
+    pub seqid: u64,
+    pub label: String,
+    pub uuid: String,
+    pub metadata: Metadata,
+}
+
+/// Returns the string stored in a NUL padded field.
+fn read_string(field: &[u8]) -> String {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+fn checksum(area: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(&area[..CHECKSUM_OFFSET]);
+    hasher.update([0u8; CHECKSUM_SIZE]);
+    hasher.update(&area[CHECKSUM_OFFSET + CHECKSUM_SIZE..]);
+    hasher.finalize().into()
+}
+
+impl LuksHeader {
+    /// Returns true if `block`, the start of an image, holds a LUKS2 header.
+    pub fn is_luks(block: &[u8]) -> bool {
+        block.len() >= 8
+            && block[..6] == LUKS_MAGIC[..]
+            && BigEndian::read_u16(&block[VERSION_OFFSET..]) == LUKS_VERSION
+    }
+
+    /// Read the header of the image `f`, using the secondary copy if it's
+    /// more recent than the primary one or if the primary one is damaged.
+    pub fn read_from<F: Read + Seek>(f: &mut F) -> Result<LuksHeader> {
+        let mut magic = [0u8; 8];
+        f.seek(SeekFrom::Start(0))
+            .map_err(LuksHeaderError::ReadHeader)?;
+        f.read_exact(&mut magic)
+            .map_err(LuksHeaderError::ReadHeader)?;
+        if magic[..6] != LUKS_MAGIC[..] {
+            return Err(LuksHeaderError::NotLuks);
+        }
+        let version = BigEndian::read_u16(&magic[VERSION_OFFSET..]);
+        if version != LUKS_VERSION {
+            return Err(LuksHeaderError::UnsupportedVersion(version));
+        }
+
+        let mut best: Option<LuksHeader> = None;
+        for offset in std::iter::once(0).chain(HEADER_SIZES) {
+            if let Some(header) = Self::read_copy(f, offset)?
+                && best.as_ref().is_none_or(|best| header.seqid > best.seqid)
+            {
+                best = Some(header);
+            }
+        }
+        best.ok_or(LuksHeaderError::NoValidHeader)
+    }
+
+    /// Read the copy of the header at `offset`, returning None if there is
+    /// no valid one.
+    fn read_copy<F: Read + Seek>(f: &mut F, offset: u64) -> Result<Option<LuksHeader>> {
+        let mut binary = vec![0u8; BINARY_HEADER_SIZE as usize];
+        f.seek(SeekFrom::Start(offset))
+            .map_err(LuksHeaderError::ReadHeader)?;
+        match f.read_exact(&mut binary) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(LuksHeaderError::ReadHeader(e)),
+        }
+
+        let magic = if offset == 0 {
+            LUKS_MAGIC
+        } else {
+            LUKS_SECONDARY_MAGIC
+        };
+        let hdr_size = BigEndian::read_u64(&binary[HDR_SIZE_OFFSET..]);
+        if binary[..6] != magic[..]
+            || BigEndian::read_u16(&binary[VERSION_OFFSET..]) != LUKS_VERSION
+            || !HEADER_SIZES.contains(&hdr_size)
+            || BigEndian::read_u64(&binary[HDR_OFFSET_OFFSET..]) != offset
+            || read_string(&binary[CHECKSUM_ALG_OFFSET..][..CHECKSUM_ALG_SIZE])
+                != CHECKSUM_ALGORITHM
+        {
+            return Ok(None);
+        }
+
+        let mut area = binary;
+        area.resize(hdr_size as usize, 0);
+        match f.read_exact(&mut area[BINARY_HEADER_SIZE as usize..]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(LuksHeaderError::ReadHeader(e)),
+        }
+        if checksum(&area)[..] != area[CHECKSUM_OFFSET..][..32] {
+            return Ok(None);
+        }
+
+        let json = &area[BINARY_HEADER_SIZE as usize..];
+        let len = json.iter().position(|&b| b == 0).unwrap_or(json.len());
+        let metadata =
+            serde_json::from_slice(&json[..len]).map_err(LuksHeaderError::InvalidMetadata)?;
+        Ok(Some(LuksHeader {
+            hdr_size,
+            seqid: BigEndian::read_u64(&area[SEQID_OFFSET..]),
+            label: read_string(&area[LABEL_OFFSET..][..LABEL_SIZE]),
+            uuid: read_string(&area[UUID_OFFSET..][..UUID_SIZE]),
+            metadata,
+        }))
+    }
+
+    /// Write both copies of the header to `f`.
+    pub fn write_to<F: Write + Seek>(&self, f: &mut F) -> Result<()> {
+        let json = serde_json::to_vec(&self.metadata).map_err(LuksHeaderError::InvalidMetadata)?;
+        // The JSON area must keep a terminating NUL.
+        if json.len() >= (self.hdr_size - BINARY_HEADER_SIZE) as usize {
+            return Err(LuksHeaderError::TooLargeMetadata);
+        }
+
+        for (offset, magic) in [(0, LUKS_MAGIC), (self.hdr_size, LUKS_SECONDARY_MAGIC)] {
+            let mut area = vec![0u8; self.hdr_size as usize];
+            area[..6].copy_from_slice(magic);
+            BigEndian::write_u16(&mut area[VERSION_OFFSET..], LUKS_VERSION);
+            BigEndian::write_u64(&mut area[HDR_SIZE_OFFSET..], self.hdr_size);
+            BigEndian::write_u64(&mut area[SEQID_OFFSET..], self.seqid);
+            let label = &self.label.as_bytes()[..self.label.len().min(LABEL_SIZE - 1)];
+            area[LABEL_OFFSET..][..label.len()].copy_from_slice(label);
+            area[CHECKSUM_ALG_OFFSET..][..CHECKSUM_ALGORITHM.len()]
+                .copy_from_slice(CHECKSUM_ALGORITHM.as_bytes());
+            random_bytes(&mut area[SALT_OFFSET..][..SALT_SIZE]).map_err(LuksHeaderError::Salt)?;
+            let uuid = &self.uuid.as_bytes()[..self.uuid.len().min(UUID_SIZE - 1)];
+            area[UUID_OFFSET..][..uuid.len()].copy_from_slice(uuid);
+            BigEndian::write_u64(&mut area[HDR_OFFSET_OFFSET..], offset);
+            area[BINARY_HEADER_SIZE as usize..][..json.len()].copy_from_slice(&json);
+            let checksum = checksum(&area);
+            area[CHECKSUM_OFFSET..][..checksum.len()].copy_from_slice(&checksum);
+
+            f.seek(SeekFrom::Start(offset))
+                .map_err(LuksHeaderError::WriteHeader)?;
+            f.write_all(&area).map_err(LuksHeaderError::WriteHeader)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! JSON metadata area of a LUKS2 header, describing the key slots, the
+//! encrypted segments and the digests of the volume key.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// LUKS2 stores 64 bit values as decimal strings, JSON numbers not being
+/// able to represent them reliably.
+mod u64_string {
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Metadata {
+    pub keyslots: BTreeMap<String, Keyslot>,
+    #[serde(default)]
+    pub tokens: BTreeMap<String, serde_json::Value>,
+    pub segments: BTreeMap<String, Segment>,
+    pub digests: BTreeMap<String, Digest>,
+    pub config: Config,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Keyslot {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub key_size: usize,
+    pub area: KeyslotArea,
+    pub kdf: Kdf,
+    pub af: AntiForensic,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyslotArea {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(with = "u64_string")]
+    pub offset: u64,
+    #[serde(with = "u64_string")]
+    pub size: u64,
+    pub encryption: String,
+    pub key_size: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Kdf {
+    #[serde(rename = "pbkdf2")]
+    Pbkdf2 {
+        hash: String,
+        iterations: u32,
+        salt: String,
+    },
+    #[serde(rename = "argon2i")]
+    Argon2i {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: String,
+    },
+    #[serde(rename = "argon2id")]
+    Argon2id {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AntiForensic {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub stripes: usize,
+    pub hash: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Segment {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(with = "u64_string")]
+    pub offset: u64,
+    /// Size in bytes, or "dynamic" when the segment ends with the device
+    pub size: String,
+    #[serde(with = "u64_string")]
+    pub iv_tweak: u64,
+    pub encryption: String,
+    pub sector_size: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Digest {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub keyslots: Vec<String>,
+    pub segments: Vec<String>,
+    pub hash: String,
+    pub iterations: u32,
+    pub salt: String,
+    pub digest: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(with = "u64_string")]
+    pub json_size: u64,
+    #[serde(with = "u64_string")]
+    pub keyslots_size: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requirements: Option<Requirements>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Requirements {
+    #[serde(default)]
+    pub mandatory: Vec<String>,
+}
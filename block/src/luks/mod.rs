@@ -0,0 +1,913 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LUKS2 encrypted images
+//!
+//! The image starts with a LUKS2 header, describing key slots each holding
+//! the volume key encrypted with a key derived from a passphrase, followed by
+//! the guest data encrypted with AES-XTS, sector by sector, using the volume
+//! key. The same header format protects the data clusters of qcow2 images
+//! whose encryption format is LUKS.
+
+mod luks_crypto;
+mod luks_header;
+mod luks_metadata;
+
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use argon2::Algorithm;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use libc::ENOSPC;
+use remain::sorted;
+use thiserror::Error;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::BlockBackend;
+use crate::luks::luks_crypto::{Hash, LuksCryptoError, XtsCipher, af_merge, af_split};
+pub use crate::luks::luks_header::LuksHeader;
+use crate::luks::luks_header::{BINARY_HEADER_SIZE, DEFAULT_HEADER_SIZE, LuksHeaderError};
+use crate::luks::luks_metadata::{
+    AntiForensic, Config, Digest, Kdf, Keyslot, KeyslotArea, Metadata, Segment,
+};
+use crate::qcow::RawFile;
+
+/// The only cipher supported for the data and the key slots
+const CIPHER: &str = "aes-xts-plain64";
+
+/// Number of stripes the key slots are split into
+const AF_STRIPES: usize = 4000;
+
+/// Alignment of the key slot areas
+const KEYSLOT_ALIGNMENT: u64 = 4096;
+
+/// Alignment of the encrypted data of the images created
+const DATA_ALIGNMENT: u64 = 1 << 20;
+
+/// Maximum number of key slots of a LUKS2 header
+const MAX_KEYSLOTS: usize = 32;
+
+const SALT_SIZE: usize = 32;
+
+/// Sector size the key slot areas are encrypted with
+const KEYSLOT_SECTOR_SIZE: usize = 512;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum LuksError {
+    #[error("Failed to derive the key of key slot {0}")]
+    DeriveKey(String, #[source] LuksCryptoError),
+    #[error("Failed to generate the volume key")]
+    GenerateKey(#[source] LuksCryptoError),
+    #[error("Invalid LUKS header")]
+    Header(#[source] LuksHeaderError),
+    #[error("Invalid base64 data in the LUKS metadata")]
+    InvalidBase64(#[source] base64::DecodeError),
+    #[error("Invalid number of keys {0}")]
+    InvalidKeyCount(usize),
+    #[error("Invalid key slot {0}")]
+    InvalidKeyslot(String),
+    #[error("Invalid segment size {0}")]
+    InvalidSegmentSize(String),
+    #[error("Invalid volume key")]
+    InvalidVolumeKey(#[source] LuksCryptoError),
+    #[error("Failed to access the encrypted data")]
+    Io(#[source] io::Error),
+    #[error("Failed to read the key file {0:?}")]
+    ReadKeyFile(PathBuf, #[source] io::Error),
+    #[error("Failed to read key slot {0}")]
+    ReadKeyslot(String, #[source] io::Error),
+    #[error("Unsupported encryption {0}")]
+    UnsupportedEncryption(String),
+    #[error("Unsupported LUKS requirements {0:?}")]
+    UnsupportedRequirements(Vec<String>),
+    #[error("Unsupported sector size {0}")]
+    UnsupportedSectorSize(u32),
+    #[error("Unsupported segment layout")]
+    UnsupportedSegments,
+    #[error("No key slot can be unlocked with the given key")]
+    WrongKey,
+}
+
+pub type Result<T> = std::result::Result<T, LuksError>;
+
+/// Read a key file, its whole content being the passphrase.
+pub fn read_key_file(path: &Path) -> Result<Zeroizing<Vec<u8>>> {
+    fs::read(path)
+        .map(Zeroizing::new)
+        .map_err(|e| LuksError::ReadKeyFile(path.to_path_buf(), e))
+}
+
+/// Key derivation function protecting the key slots of a new image
+#[derive(Clone, Copy, Debug)]
+pub enum LuksKdf {
+    Pbkdf2 {
+        iterations: u32,
+    },
+    /// `memory` is in KiB.
+    Argon2id {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+    },
+}
+
+/// Parameters of a new LUKS2 header
+#[derive(Clone, Debug)]
+pub struct LuksCreateOptions {
+    /// Size of the AES-XTS volume key in bytes, 32 or 64
+    pub key_size: usize,
+    /// Size of the encrypted sectors, a power of two from 512 to 4096
+    pub sector_size: u32,
+    pub kdf: LuksKdf,
+    /// PBKDF2-SHA256 iterations of the digest checking the volume key
+    pub digest_iterations: u32,
+}
+
+impl Default for LuksCreateOptions {
+    fn default() -> Self {
+        LuksCreateOptions {
+            key_size: 64,
+            sector_size: 512,
+            kdf: LuksKdf::Argon2id {
+                time: 4,
+                memory: 256 << 10,
+                cpus: 4,
+            },
+            digest_iterations: 100_000,
+        }
+    }
+}
+
+/// Encrypted segment of an image unlocked with its volume key
+pub struct LuksVolume {
+    cipher: XtsCipher,
+    offset: u64,
+    size: Option<u64>,
+    sector_size: u64,
+    iv_tweak: u64,
+}
+
+impl Debug for LuksVolume {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LuksVolume")
+            .field("offset", &self.offset)
+            .field("size", &self.size)
+            .field("sector_size", &self.sector_size)
+            .field("iv_tweak", &self.iv_tweak)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LuksVolume {
+    /// Offset of the encrypted data in the image
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Size of the encrypted data, None if it extends to the end of the
+    /// image.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    pub fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    /// Encrypt `buf` in place, whole sectors found at `offset` in the
+    /// encrypted data.
+    pub fn encrypt(&self, offset: u64, buf: &mut [u8]) {
+        self.cipher.encrypt(
+            self.iv_tweak + offset / self.sector_size,
+            self.sector_size as usize,
+            buf,
+        );
+    }
+
+    /// Decrypt `buf` in place, whole sectors found at `offset` in the
+    /// encrypted data.
+    pub fn decrypt(&self, offset: u64, buf: &mut [u8]) {
+        self.cipher.decrypt(
+            self.iv_tweak + offset / self.sector_size,
+            self.sector_size as usize,
+            buf,
+        );
+    }
+
+    /// Create a LUKS2 header with one key slot per key in `keys`, and
+    /// return it along with the volume it protects. The encrypted data
+    /// starts right after the header, which is as large as that offset.
+    pub fn format(keys: &[&[u8]], options: &LuksCreateOptions) -> Result<(Vec<u8>, LuksVolume)> {
+        if keys.is_empty() || keys.len() > MAX_KEYSLOTS {
+            return Err(LuksError::InvalidKeyCount(keys.len()));
+        }
+        if !options.sector_size.is_power_of_two() || !(512..=4096).contains(&options.sector_size) {
+            return Err(LuksError::UnsupportedSectorSize(options.sector_size));
+        }
+
+        let mut volume_key = Zeroizing::new(vec![0u8; options.key_size]);
+        luks_crypto::random_bytes(&mut volume_key).map_err(LuksError::GenerateKey)?;
+        let cipher = XtsCipher::new(&volume_key).map_err(LuksError::InvalidVolumeKey)?;
+
+        let area_size =
+            (options.key_size * AF_STRIPES).next_multiple_of(KEYSLOT_ALIGNMENT as usize);
+        let keyslots_offset = 2 * DEFAULT_HEADER_SIZE;
+        let data_offset =
+            (keyslots_offset + (area_size * keys.len()) as u64).next_multiple_of(DATA_ALIGNMENT);
+
+        let mut image = Cursor::new(vec![0u8; data_offset as usize]);
+        let mut keyslots = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            let id = i.to_string();
+            let salt = random_salt()?;
+            let kdf = match options.kdf {
+                LuksKdf::Pbkdf2 { iterations } => Kdf::Pbkdf2 {
+                    hash: Hash::Sha256.name().to_string(),
+                    iterations,
+                    salt: BASE64.encode(salt),
+                },
+                LuksKdf::Argon2id { time, memory, cpus } => Kdf::Argon2id {
+                    time,
+                    memory,
+                    cpus,
+                    salt: BASE64.encode(salt),
+                },
+            };
+            let keyslot = Keyslot {
+                kind: "luks2".to_string(),
+                key_size: options.key_size,
+                area: KeyslotArea {
+                    kind: "raw".to_string(),
+                    offset: keyslots_offset + (area_size * i) as u64,
+                    size: area_size as u64,
+                    encryption: CIPHER.to_string(),
+                    key_size: options.key_size,
+                },
+                kdf,
+                af: AntiForensic {
+                    kind: "luks1".to_string(),
+                    stripes: AF_STRIPES,
+                    hash: Hash::Sha256.name().to_string(),
+                },
+                priority: None,
+            };
+
+            let area_key = derive_key(&id, &keyslot, key)?;
+            let area_cipher = XtsCipher::new(&area_key).map_err(LuksError::InvalidVolumeKey)?;
+            let mut material =
+                af_split(&volume_key, AF_STRIPES, Hash::Sha256).map_err(LuksError::GenerateKey)?;
+            area_cipher.encrypt(0, KEYSLOT_SECTOR_SIZE, &mut material);
+            let area = &mut image.get_mut()[keyslot.area.offset as usize..];
+            area[..material.len()].copy_from_slice(&material);
+            keyslots.insert(id, keyslot);
+        }
+
+        let digest_salt = random_salt()?;
+        let mut digest = [0u8; 32];
+        luks_crypto::pbkdf2(
+            Hash::Sha256,
+            &volume_key,
+            &digest_salt,
+            options.digest_iterations,
+            &mut digest,
+        );
+
+        let metadata = Metadata {
+            keyslots: keyslots.clone(),
+            tokens: BTreeMap::new(),
+            segments: BTreeMap::from([(
+                "0".to_string(),
+                Segment {
+                    kind: "crypt".to_string(),
+                    offset: data_offset,
+                    size: "dynamic".to_string(),
+                    iv_tweak: 0,
+                    encryption: CIPHER.to_string(),
+                    sector_size: options.sector_size,
+                    flags: Vec::new(),
+                },
+            )]),
+            digests: BTreeMap::from([(
+                "0".to_string(),
+                Digest {
+                    kind: "pbkdf2".to_string(),
+                    keyslots: keyslots.into_keys().collect(),
+                    segments: vec!["0".to_string()],
+                    hash: Hash::Sha256.name().to_string(),
+                    iterations: options.digest_iterations,
+                    salt: BASE64.encode(digest_salt),
+                    digest: BASE64.encode(digest),
+                },
+            )]),
+            config: Config {
+                json_size: DEFAULT_HEADER_SIZE - BINARY_HEADER_SIZE,
+                keyslots_size: data_offset - keyslots_offset,
+                flags: Vec::new(),
+                requirements: None,
+            },
+        };
+        LuksHeader {
+            hdr_size: DEFAULT_HEADER_SIZE,
+            seqid: 1,
+            label: String::new(),
+            uuid: Uuid::new_v4().to_string(),
+            metadata,
+        }
+        .write_to(&mut image)
+        .map_err(LuksError::Header)?;
+
+        Ok((
+            image.into_inner(),
+            LuksVolume {
+                cipher,
+                offset: data_offset,
+                size: None,
+                sector_size: u64::from(options.sector_size),
+                iv_tweak: 0,
+            },
+        ))
+    }
+}
+
+fn random_salt() -> Result<[u8; SALT_SIZE]> {
+    let mut salt = [0u8; SALT_SIZE];
+    luks_crypto::random_bytes(&mut salt).map_err(LuksError::GenerateKey)?;
+    Ok(salt)
+}
+
+fn decode_base64(data: &str) -> Result<Vec<u8>> {
+    BASE64.decode(data).map_err(LuksError::InvalidBase64)
+}
+
+/// Derive the key encrypting the area of key slot `id` from `key`.
+fn derive_key(id: &str, keyslot: &Keyslot, key: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let mut area_key = Zeroizing::new(vec![0u8; keyslot.area.key_size]);
+    match &keyslot.kdf {
+        Kdf::Pbkdf2 {
+            hash,
+            iterations,
+            salt,
+        } => {
+            let hash =
+                Hash::from_name(hash).map_err(|e| LuksError::DeriveKey(id.to_string(), e))?;
+            luks_crypto::pbkdf2(hash, key, &decode_base64(salt)?, *iterations, &mut area_key);
+        }
+        Kdf::Argon2i {
+            time,
+            memory,
+            cpus,
+            salt,
+        }
+        | Kdf::Argon2id {
+            time,
+            memory,
+            cpus,
+            salt,
+        } => {
+            let algorithm = if matches!(keyslot.kdf, Kdf::Argon2i { .. }) {
+                Algorithm::Argon2i
+            } else {
+                Algorithm::Argon2id
+            };
+            luks_crypto::argon2(
+                algorithm,
+                key,
+                &decode_base64(salt)?,
+                *time,
+                *memory,
+                *cpus,
+                &mut area_key,
+            )
+            .map_err(|e| LuksError::DeriveKey(id.to_string(), e))?;
+        }
+    }
+    Ok(area_key)
+}
+
+impl LuksHeader {
+    /// Returns the only segment of the image, images with several ones
+    /// being in the middle of a reencryption.
+    fn segment(&self) -> Result<(&String, &Segment)> {
+        let mut segments = self.metadata.segments.iter();
+        match (segments.next(), segments.next()) {
+            (Some((id, segment)), None) if segment.kind == "crypt" => Ok((id, segment)),
+            _ => Err(LuksError::UnsupportedSegments),
+        }
+    }
+
+    /// Unlock the volume key from one of the key slots with `key`, the key
+    /// slot areas being read from `f`, the image starting with this header.
+    pub fn unlock<F: Read + Seek>(&self, f: &mut F, key: &[u8]) -> Result<LuksVolume> {
+        if let Some(requirements) = &self.metadata.config.requirements
+            && !requirements.mandatory.is_empty()
+        {
+            return Err(LuksError::UnsupportedRequirements(
+                requirements.mandatory.clone(),
+            ));
+        }
+
+        let (segment_id, segment) = self.segment()?;
+        if segment.encryption != CIPHER {
+            return Err(LuksError::UnsupportedEncryption(segment.encryption.clone()));
+        }
+        if !segment.sector_size.is_power_of_two() || !(512..=4096).contains(&segment.sector_size) {
+            return Err(LuksError::UnsupportedSectorSize(segment.sector_size));
+        }
+        let size = match segment.size.as_str() {
+            "dynamic" => None,
+            size => Some(
+                size.parse()
+                    .map_err(|_| LuksError::InvalidSegmentSize(size.to_string()))?,
+            ),
+        };
+
+        for digest in self
+            .metadata
+            .digests
+            .values()
+            .filter(|digest| digest.segments.contains(segment_id))
+        {
+            let mut keyslots: Vec<(&String, &Keyslot)> = digest
+                .keyslots
+                .iter()
+                .filter_map(|id| self.metadata.keyslots.get_key_value(id))
+                .collect();
+            // Key slots with a higher priority are tried first.
+            keyslots.sort_by_key(|(_, keyslot)| std::cmp::Reverse(keyslot.priority.unwrap_or(1)));
+
+            for (id, keyslot) in keyslots {
+                let volume_key = self.unlock_keyslot(f, id, keyslot, key)?;
+                if check_digest(digest, &volume_key)? {
+                    return Ok(LuksVolume {
+                        cipher: XtsCipher::new(&volume_key).map_err(LuksError::InvalidVolumeKey)?,
+                        offset: segment.offset,
+                        size,
+                        sector_size: u64::from(segment.sector_size),
+                        iv_tweak: segment.iv_tweak,
+                    });
+                }
+            }
+        }
+
+        Err(LuksError::WrongKey)
+    }
+
+    /// Decrypt the volume key stored in key slot `id` with `key`, the
+    /// result being garbage if `key` is the wrong one.
+    fn unlock_keyslot<F: Read + Seek>(
+        &self,
+        f: &mut F,
+        id: &str,
+        keyslot: &Keyslot,
+        key: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        let invalid = || LuksError::InvalidKeyslot(id.to_string());
+        if keyslot.kind != "luks2" || keyslot.area.kind != "raw" || keyslot.af.kind != "luks1" {
+            return Err(invalid());
+        }
+        if keyslot.area.encryption != CIPHER {
+            return Err(LuksError::UnsupportedEncryption(
+                keyslot.area.encryption.clone(),
+            ));
+        }
+        let af_hash = Hash::from_name(&keyslot.af.hash)
+            .map_err(|e| LuksError::DeriveKey(id.to_string(), e))?;
+        let material_size =
+            (keyslot.key_size * keyslot.af.stripes).next_multiple_of(KEYSLOT_SECTOR_SIZE);
+        if keyslot.key_size == 0
+            || keyslot.af.stripes == 0
+            || material_size as u64 > keyslot.area.size
+        {
+            return Err(invalid());
+        }
+
+        let area_key = derive_key(id, keyslot, key)?;
+        let area_cipher = XtsCipher::new(&area_key).map_err(LuksError::InvalidVolumeKey)?;
+        let mut material = Zeroizing::new(vec![0u8; material_size]);
+        f.seek(SeekFrom::Start(keyslot.area.offset))
+            .and_then(|_| f.read_exact(&mut material))
+            .map_err(|e| LuksError::ReadKeyslot(id.to_string(), e))?;
+        area_cipher.decrypt(0, KEYSLOT_SECTOR_SIZE, &mut material);
+        Ok(af_merge(
+            &material,
+            keyslot.key_size,
+            keyslot.af.stripes,
+            af_hash,
+        ))
+    }
+}
+
+/// Returns true if `volume_key` matches `digest`.
+fn check_digest(digest: &Digest, volume_key: &[u8]) -> Result<bool> {
+    if digest.kind != "pbkdf2" {
+        return Err(LuksError::UnsupportedEncryption(digest.kind.clone()));
+    }
+    let hash = Hash::from_name(&digest.hash).map_err(LuksError::InvalidVolumeKey)?;
+    let expected = decode_base64(&digest.digest)?;
+    let mut computed = vec![0u8; expected.len()];
+    luks_crypto::pbkdf2(
+        hash,
+        volume_key,
+        &decode_base64(&digest.salt)?,
+        digest.iterations,
+        &mut computed,
+    );
+    // Compare in constant time.
+    Ok(!expected.is_empty()
+        && computed
+            .iter()
+            .zip(&expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0)
+}
+
+/// Image whose data is encrypted with LUKS2, stored in `B`
+#[derive(Debug)]
+pub struct LuksFile<B: BlockBackend> {
+    file: B,
+    volume: LuksVolume,
+    size: u64,
+    position: u64,
+}
+
+impl<B: BlockBackend> LuksFile<B> {
+    /// Open the LUKS2 image `file`, unlocking it with `key`.
+    pub fn new(mut file: B, key: &[u8]) -> Result<Self> {
+        let header = LuksHeader::read_from(&mut file).map_err(LuksError::Header)?;
+        let volume = header.unlock(&mut file, key)?;
+        let available = file
+            .logical_size()
+            .map_err(|e| LuksError::Io(io::Error::other(e)))?
+            .saturating_sub(volume.offset);
+        let size = min(volume.size.unwrap_or(available), available);
+        Ok(LuksFile {
+            file,
+            size: size - size % volume.sector_size,
+            volume,
+            position: 0,
+        })
+    }
+
+    /// Size of the sectors the data is encrypted by
+    pub fn sector_size(&self) -> u64 {
+        self.volume.sector_size
+    }
+
+    /// Read and decrypt whole sectors at `offset` in the encrypted data.
+    fn read_sectors(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file
+            .seek(SeekFrom::Start(self.volume.offset + offset))?;
+        self.file.read_exact(buf)?;
+        self.volume.decrypt(offset, buf);
+        Ok(())
+    }
+
+    /// Encrypt `buf` in place and write it as whole sectors at `offset` in
+    /// the encrypted data.
+    fn write_sectors(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.volume.encrypt(offset, buf);
+        self.file
+            .seek(SeekFrom::Start(self.volume.offset + offset))?;
+        self.file.write_all(buf)
+    }
+
+    /// Returns the range of whole sectors covering `len` bytes from the
+    /// current position.
+    fn sectors(&self, len: usize) -> (u64, u64) {
+        let sector_size = self.volume.sector_size;
+        let start = self.position - self.position % sector_size;
+        let end = (self.position + len as u64).next_multiple_of(sector_size);
+        (start, end)
+    }
+}
+
+impl LuksFile<RawFile> {
+    /// Create a LUKS2 image of `size` bytes in `file`, with one key slot per
+    /// key in `keys`. The encrypted data is left uninitialized.
+    pub fn create(
+        mut file: RawFile,
+        size: u64,
+        keys: &[&[u8]],
+        options: &LuksCreateOptions,
+    ) -> Result<Self> {
+        let (header, volume) = LuksVolume::format(keys, options)?;
+        file.rewind()
+            .and_then(|_| file.write_all(&header))
+            .and_then(|_| file.set_len(volume.offset + size))
+            .and_then(|_| file.sync_all())
+            .map_err(LuksError::Io)?;
+        Ok(LuksFile {
+            file,
+            size: size - size % volume.sector_size,
+            volume,
+            position: 0,
+        })
+    }
+
+    /// Change the size of the encrypted data, which must extend to the end
+    /// of the image.
+    pub fn resize(&mut self, size: u64) -> io::Result<()> {
+        if self.volume.size.is_some() || !size.is_multiple_of(self.volume.sector_size) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        self.file.set_len(self.volume.offset + size)?;
+        self.size = size;
+        Ok(())
+    }
+}
+
+impl<B: BlockBackend + AsRawFd> AsRawFd for LuksFile<B> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl<B: BlockBackend> Read for LuksFile<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = min(buf.len() as u64, self.size.saturating_sub(self.position)) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let (start, end) = self.sectors(len);
+        let mut data = Zeroizing::new(vec![0u8; (end - start) as usize]);
+        self.read_sectors(start, &mut data)?;
+        let skip = (self.position - start) as usize;
+        buf[..len].copy_from_slice(&data[skip..skip + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<B: BlockBackend> Write for LuksFile<B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = min(buf.len() as u64, self.size.saturating_sub(self.position)) as usize;
+        if len == 0 {
+            return if buf.is_empty() {
+                Ok(0)
+            } else {
+                Err(io::Error::from_raw_os_error(ENOSPC))
+            };
+        }
+
+        let (start, end) = self.sectors(len);
+        let sector_size = self.volume.sector_size as usize;
+        let mut data = Zeroizing::new(vec![0u8; (end - start) as usize]);
+        // The sectors only partly written keep the rest of their data.
+        let partial_head = self.position != start;
+        let partial_tail = self.position + len as u64 != end;
+        if partial_head {
+            self.read_sectors(start, &mut data[..sector_size])?;
+        }
+        if partial_tail && !(partial_head && data.len() == sector_size) {
+            let last = data.len() - sector_size;
+            self.read_sectors(end - sector_size as u64, &mut data[last..])?;
+        }
+        let skip = (self.position - start) as usize;
+        data[skip..skip + len].copy_from_slice(&buf[..len]);
+        self.write_sectors(start, &mut data)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<B: BlockBackend> Seek for LuksFile<B> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => self.size.checked_add_signed(off),
+            SeekFrom::Current(off) => self.position.checked_add_signed(off),
+        };
+        let new_pos = new_pos.ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        self.position = new_pos;
+        Ok(new_pos)
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for LuksFile<B> {
+    fn logical_size(&self) -> std::result::Result<u64, crate::Error> {
+        Ok(self.size)
+    }
+
+    fn physical_size(&self) -> std::result::Result<u64, crate::Error> {
+        self.file.physical_size()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    /// Cheap key derivation, to keep the tests fast
+    fn test_options() -> LuksCreateOptions {
+        LuksCreateOptions {
+            kdf: LuksKdf::Pbkdf2 { iterations: 1000 },
+            digest_iterations: 1000,
+            ..Default::default()
+        }
+    }
+
+    fn create_image(size: u64, keys: &[&[u8]], options: &LuksCreateOptions) -> (TempFile, u64) {
+        let temp_file = TempFile::new().unwrap();
+        let file = RawFile::new(temp_file.as_file().try_clone().unwrap(), false);
+        let luks = LuksFile::create(file, size, keys, options).unwrap();
+        let offset = luks.volume.offset();
+        (temp_file, offset)
+    }
+
+    fn open_image(temp_file: &TempFile, key: &[u8]) -> Result<LuksFile<RawFile>> {
+        LuksFile::new(
+            RawFile::new(temp_file.as_file().try_clone().unwrap(), false),
+            key,
+        )
+    }
+
+    #[test]
+    fn test_luks_round_trip() {
+        let (temp_file, offset) = create_image(1 << 20, &[b"secret"], &test_options());
+        assert!(offset.is_multiple_of(DATA_ALIGNMENT));
+        assert_eq!(
+            temp_file.as_file().metadata().unwrap().len(),
+            offset + (1 << 20)
+        );
+
+        let mut luks = open_image(&temp_file, b"secret").unwrap();
+        assert_eq!(luks.logical_size().unwrap(), 1 << 20);
+        // Unaligned writes spanning several sectors keep the data around them.
+        let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        luks.seek(SeekFrom::Start(0)).unwrap();
+        luks.write_all(&[0xaa; 8192]).unwrap();
+        luks.seek(SeekFrom::Start(700)).unwrap();
+        luks.write_all(&data).unwrap();
+        luks.seek(SeekFrom::Start(100)).unwrap();
+        luks.write_all(&[0x55; 10]).unwrap();
+        luks.flush().unwrap();
+
+        let mut luks = open_image(&temp_file, b"secret").unwrap();
+        let mut read = vec![0u8; 8192];
+        luks.seek(SeekFrom::Start(0)).unwrap();
+        luks.read_exact(&mut read).unwrap();
+        assert_eq!(read[..100], [0xaa; 100]);
+        assert_eq!(read[100..110], [0x55; 10]);
+        assert_eq!(read[110..700], [0xaa; 590]);
+        assert_eq!(read[700..3700], data[..]);
+        assert_eq!(read[3700..], [0xaa; 4492]);
+
+        // The data is only stored encrypted.
+        let mut raw = vec![0u8; 8192];
+        let mut file = temp_file.as_file();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.read_exact(&mut raw).unwrap();
+        assert_ne!(raw, read);
+        assert!(!raw.windows(16).any(|w| w == [0xaa; 16]));
+
+        // Reads and writes stop at the end of the disk.
+        luks.seek(SeekFrom::End(-4)).unwrap();
+        assert_eq!(luks.read(&mut read).unwrap(), 4);
+        luks.seek(SeekFrom::End(0)).unwrap();
+        luks.write(&[1]).unwrap_err();
+    }
+
+    #[test]
+    fn test_luks_keys() {
+        let (temp_file, _) = create_image(1 << 20, &[b"first", b"second"], &test_options());
+        let mut luks = open_image(&temp_file, b"second").unwrap();
+        luks.write_all(b"hello").unwrap();
+        luks.flush().unwrap();
+
+        let mut luks = open_image(&temp_file, b"first").unwrap();
+        let mut read = [0u8; 5];
+        luks.read_exact(&mut read).unwrap();
+        assert_eq!(&read, b"hello");
+
+        assert!(matches!(
+            open_image(&temp_file, b"third"),
+            Err(LuksError::WrongKey)
+        ));
+        assert!(matches!(
+            LuksVolume::format(&[], &test_options()),
+            Err(LuksError::InvalidKeyCount(0))
+        ));
+    }
+
+    #[test]
+    fn test_luks_key_file() {
+        let (temp_file, _) = create_image(1 << 20, &[b"key\nwith newline\n"], &test_options());
+        let key_file = TempFile::new().unwrap();
+        key_file
+            .as_file()
+            .write_all(b"key\nwith newline\n")
+            .unwrap();
+        let key = read_key_file(key_file.as_path()).unwrap();
+        open_image(&temp_file, &key).unwrap();
+        read_key_file(Path::new("/nonexistent/key")).unwrap_err();
+    }
+
+    #[test]
+    fn test_luks_argon2id() {
+        let options = LuksCreateOptions {
+            key_size: 32,
+            kdf: LuksKdf::Argon2id {
+                time: 1,
+                memory: 64,
+                cpus: 1,
+            },
+            ..test_options()
+        };
+        let (temp_file, _) = create_image(1 << 20, &[b"secret"], &options);
+        open_image(&temp_file, b"secret").unwrap();
+        open_image(&temp_file, b"wrong").unwrap_err();
+    }
+
+    #[test]
+    fn test_luks_4k_sectors() {
+        let options = LuksCreateOptions {
+            sector_size: 4096,
+            ..test_options()
+        };
+        let (temp_file, _) = create_image(1 << 20, &[b"secret"], &options);
+        let mut luks = open_image(&temp_file, b"secret").unwrap();
+        assert_eq!(luks.volume.sector_size(), 4096);
+        luks.seek(SeekFrom::Start(4000)).unwrap();
+        luks.write_all(&[7; 200]).unwrap();
+
+        let mut luks = open_image(&temp_file, b"secret").unwrap();
+        let mut read = [0u8; 200];
+        luks.seek(SeekFrom::Start(4000)).unwrap();
+        luks.read_exact(&mut read).unwrap();
+        assert_eq!(read, [7; 200]);
+    }
+
+    #[test]
+    fn test_luks_secondary_header() {
+        let (temp_file, _) = create_image(1 << 20, &[b"secret"], &test_options());
+        let mut file = temp_file.as_file();
+        let header = LuksHeader::read_from(&mut file).unwrap();
+        assert_eq!(header.seqid, 1);
+
+        // Damage the JSON metadata of the primary header.
+        file.seek(SeekFrom::Start(BINARY_HEADER_SIZE + 10)).unwrap();
+        file.write_all(b"garbage").unwrap();
+        let secondary = LuksHeader::read_from(&mut file).unwrap();
+        assert_eq!(secondary.uuid, header.uuid);
+        open_image(&temp_file, b"secret").unwrap();
+
+        // Without any valid copy the image can't be opened.
+        file.seek(SeekFrom::Start(
+            DEFAULT_HEADER_SIZE + BINARY_HEADER_SIZE + 10,
+        ))
+        .unwrap();
+        file.write_all(b"garbage").unwrap();
+        assert!(matches!(
+            open_image(&temp_file, b"secret"),
+            Err(LuksError::Header(LuksHeaderError::NoValidHeader))
+        ));
+    }
+
+    #[test]
+    fn test_luks_resize() {
+        let (temp_file, offset) = create_image(1 << 20, &[b"secret"], &test_options());
+        let mut luks = open_image(&temp_file, b"secret").unwrap();
+        luks.seek(SeekFrom::Start(1000)).unwrap();
+        luks.write_all(b"kept").unwrap();
+        luks.resize(4 << 20).unwrap();
+        assert!(luks.resize(1000).is_err());
+        assert_eq!(
+            temp_file.as_file().metadata().unwrap().len(),
+            offset + (4 << 20)
+        );
+
+        let mut luks = open_image(&temp_file, b"secret").unwrap();
+        assert_eq!(luks.logical_size().unwrap(), 4 << 20);
+        let mut read = [0u8; 4];
+        luks.seek(SeekFrom::Start(1000)).unwrap();
+        luks.read_exact(&mut read).unwrap();
+        assert_eq!(&read, b"kept");
+    }
+
+    #[test]
+    fn test_luks_not_luks() {
+        let temp_file = TempFile::new().unwrap();
+        temp_file.as_file().set_len(1 << 20).unwrap();
+        assert!(matches!(
+            open_image(&temp_file, b"secret"),
+            Err(LuksError::Header(LuksHeaderError::NotLuks))
+        ));
+        assert!(!LuksHeader::is_luks(&[0u8; 512]));
+    }
+}
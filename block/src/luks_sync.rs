@@ -0,0 +1,218 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, Mutex};
+
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, BorrowedDiskFd, DiskFile, DiskFileError, DiskFileResult,
+};
+use crate::luks::{LuksFile, Result as LuksResult};
+use crate::qcow::RawFile;
+use crate::{AsyncAdaptor, BlockBackend, DiskTopology};
+
+pub struct LuksDiskSync {
+    // The Mutex serializes the I/O operations across queues, LuksFile going
+    // through a single file position.
+    luks_file: Arc<Mutex<LuksFile<RawFile>>>,
+}
+
+impl LuksDiskSync {
+    /// Open the LUKS2 image `file`, unlocking it with `key`.
+    pub fn new(file: File, direct_io: bool, key: &[u8]) -> LuksResult<Self> {
+        Ok(LuksDiskSync {
+            luks_file: Arc::new(Mutex::new(LuksFile::new(
+                RawFile::new(file, direct_io),
+                key,
+            )?)),
+        })
+    }
+}
+
+impl DiskFile for LuksDiskSync {
+    fn logical_size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.luks_file.lock().unwrap().logical_size().unwrap())
+    }
+
+    fn physical_size(&mut self) -> DiskFileResult<u64> {
+        self.luks_file.lock().unwrap().physical_size().map_err(|e| {
+            let io_inner = match e {
+                crate::Error::GetFileMetadata(e) => e,
+                _ => unreachable!(),
+            };
+            DiskFileError::Size(io_inner)
+        })
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(LuksSync::new(Arc::clone(&self.luks_file))) as Box<dyn AsyncIo>)
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        // The guest must not write less than an encrypted sector.
+        let sector_size = self.luks_file.lock().unwrap().sector_size();
+        DiskTopology {
+            logical_block_size: sector_size,
+            physical_block_size: sector_size,
+            minimum_io_size: sector_size,
+            optimal_io_size: 0,
+        }
+    }
+
+    fn resize(&mut self, size: u64) -> DiskFileResult<()> {
+        self.luks_file
+            .lock()
+            .unwrap()
+            .resize(size)
+            .map_err(DiskFileError::ResizeError)
+    }
+
+    fn fd(&mut self) -> BorrowedDiskFd<'_> {
+        BorrowedDiskFd::new(self.luks_file.lock().unwrap().as_raw_fd())
+    }
+}
+
+pub struct LuksSync {
+    luks_file: Arc<Mutex<LuksFile<RawFile>>>,
+    eventfd: EventFd,
+    completion_list: VecDeque<(u64, i32)>,
+}
+
+impl LuksSync {
+    pub fn new(luks_file: Arc<Mutex<LuksFile<RawFile>>>) -> Self {
+        LuksSync {
+            luks_file,
+            eventfd: EventFd::new(libc::EFD_NONBLOCK)
+                .expect("Failed creating EventFd for LuksSync"),
+            completion_list: VecDeque::new(),
+        }
+    }
+}
+
+impl AsyncAdaptor for LuksFile<RawFile> {}
+
+impl AsyncIo for LuksSync {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.luks_file.lock().unwrap().read_vectored_sync(
+            offset,
+            iovecs,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.luks_file.lock().unwrap().write_vectored_sync(
+            offset,
+            iovecs,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.luks_file.lock().unwrap().fsync_sync(
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.completion_list.pop_front()
+    }
+
+    // Discarding would reveal which parts of the disk are in use, and the
+    // zeroes written in clear would read back as garbage.
+    fn punch_hole(&mut self, _offset: u64, _length: u64, _user_data: u64) -> AsyncIoResult<()> {
+        Err(AsyncIoError::PunchHole(io::Error::other(
+            "punch_hole not supported for LUKS",
+        )))
+    }
+
+    fn write_zeroes(&mut self, _offset: u64, _length: u64, _user_data: u64) -> AsyncIoResult<()> {
+        Err(AsyncIoError::WriteZeroes(io::Error::other(
+            "write_zeroes not supported for LUKS",
+        )))
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::io::{Read, Seek, SeekFrom};
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+    use crate::luks::{LuksCreateOptions, LuksKdf};
+
+    #[test]
+    fn test_luks_sync_round_trip() {
+        let temp_file = TempFile::new().unwrap();
+        let options = LuksCreateOptions {
+            kdf: LuksKdf::Pbkdf2 { iterations: 1000 },
+            digest_iterations: 1000,
+            ..Default::default()
+        };
+        let raw_file = RawFile::new(temp_file.as_file().try_clone().unwrap(), false);
+        LuksFile::create(raw_file, 1 << 20, &[b"secret"], &options).unwrap();
+
+        let mut disk =
+            LuksDiskSync::new(temp_file.as_file().try_clone().unwrap(), false, b"secret").unwrap();
+        assert_eq!(disk.logical_size().unwrap(), 1 << 20);
+        let mut async_io = disk.new_async_io(1).unwrap();
+
+        let mut data = vec![0x42u8; 4096];
+        let iovec = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        async_io.write_vectored(8192, &[iovec], 1).unwrap();
+        assert_eq!(async_io.next_completed_request(), Some((1, 4096)));
+        async_io.fsync(Some(2)).unwrap();
+        assert_eq!(async_io.next_completed_request(), Some((2, 0)));
+
+        let mut read = vec![0u8; 4096];
+        let iovec = libc::iovec {
+            iov_base: read.as_mut_ptr() as *mut libc::c_void,
+            iov_len: read.len(),
+        };
+        async_io.read_vectored(8192, &[iovec], 3).unwrap();
+        assert_eq!(async_io.next_completed_request(), Some((3, 4096)));
+        assert_eq!(read, data);
+
+        // The image only holds the encrypted data.
+        let mut raw = vec![0u8; 4096];
+        let mut file = temp_file.as_file();
+        file.seek(SeekFrom::Start((1 << 20) + 8192)).unwrap();
+        file.read_exact(&mut raw).unwrap();
+        assert_ne!(raw, data);
+
+        assert!(async_io.punch_hole(0, 4096, 4).is_err());
+        assert!(
+            LuksDiskSync::new(temp_file.as_file().try_clone().unwrap(), false, b"wrong").is_err()
+        );
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{self, Read};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// Header extension holding the location of the bitmap directory.
+pub const HEADER_EXT_BITMAPS: u32 = 0x2385_2875;
+/// Autoclear feature bit telling the bitmaps extension is consistent.
+pub const AUTOCLEAR_BITMAPS: u64 = 1 << 0;
+/// Size of the data of the bitmaps header extension.
+pub const BITMAPS_EXTENSION_SIZE: u32 = 24;
+/// Maximum number of bitmaps of an image, from the specification.
+pub const MAX_BITMAPS: u32 = 65535;
+/// Maximum length of the name of a bitmap, from the specification.
+pub const MAX_BITMAP_NAME_SIZE: usize = 1023;
+/// Maximum size of the bitmap directory, from the specification.
+pub const MAX_BITMAP_DIRECTORY_SIZE: u64 = 64 << 20;
+
+/// The bitmap may not reflect all the writes to the image.
+pub const BITMAP_FLAG_IN_USE: u32 = 1 << 0;
+/// The bitmap must reflect all the writes to the image.
+pub const BITMAP_FLAG_AUTO: u32 = 1 << 1;
+/// Bitmap tracking the clusters written to.
+pub const BITMAP_TYPE_DIRTY_TRACKING: u8 = 1;
+
+/// Entry of a bitmap table for a cluster of set bits, when it has no offset.
+pub const BITMAP_TABLE_ENTRY_ALL_ONES: u64 = 1;
+/// Mask of the offset of a cluster of bitmap data in a bitmap table entry.
+pub const BITMAP_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+
+// Size of the fixed part of a bitmap directory entry.
+const BITMAP_ENTRY_HEADER_SIZE: usize = 24;
+// Larger extra data is rejected, as QEMU does.
+const MAX_BITMAP_EXTRA_DATA_SIZE: usize = 1024;
+// Range of granularities allowed by the specification.
+const MIN_BITMAP_GRANULARITY_BITS: u8 = 9;
+const MAX_BITMAP_GRANULARITY_BITS: u8 = 31;
+
+/// Contents of the bitmaps header extension.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BitmapsExtension {
+    /// Number of bitmaps in the directory.
+    pub nb_bitmaps: u32,
+    /// Size of the bitmap directory in bytes.
+    pub directory_size: u64,
+    /// Offset of the bitmap directory.
+    pub directory_offset: u64,
+}
+
+impl BitmapsExtension {
+    /// Parses the data of the extension.
+    pub fn read_from<R: Read>(f: &mut R) -> io::Result<Self> {
+        let nb_bitmaps = f.read_u32::<BigEndian>()?;
+        // Reserved
+        f.read_u32::<BigEndian>()?;
+        Ok(BitmapsExtension {
+            nb_bitmaps,
+            directory_size: f.read_u64::<BigEndian>()?,
+            directory_offset: f.read_u64::<BigEndian>()?,
+        })
+    }
+
+    /// Returns the data of the extension, as stored in the image.
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(BITMAPS_EXTENSION_SIZE as usize);
+        // Writing to a Vec can't fail.
+        data.write_u32::<BigEndian>(self.nb_bitmaps).unwrap();
+        data.write_u32::<BigEndian>(0).unwrap();
+        data.write_u64::<BigEndian>(self.directory_size).unwrap();
+        data.write_u64::<BigEndian>(self.directory_offset).unwrap();
+        data
+    }
+}
+
+/// A persistent bitmap, as stored in the bitmap directory of a qcow2 image.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QcowBitmap {
+    /// Offset of the bitmap table.
+    pub table_offset: u64,
+    /// Number of entries of the bitmap table.
+    pub table_size: u32,
+    /// `BITMAP_FLAG_*` flags of the bitmap.
+    pub flags: u32,
+    /// Type of the bitmap.
+    pub bitmap_type: u8,
+    /// Each bit covers 2^granularity_bits bytes of the disk.
+    pub granularity_bits: u8,
+    /// Name of the bitmap.
+    pub name: String,
+    /// Extra data, which must be kept as is.
+    pub extra_data: Vec<u8>,
+}
+
+impl QcowBitmap {
+    /// Reads `count` bitmap directory entries from `f`.
+    pub fn read_directory<R: Read>(f: &mut R, count: u32) -> io::Result<Vec<Self>> {
+        (0..count).map(|_| Self::read_from(f)).collect()
+    }
+
+    fn read_from<R: Read>(f: &mut R) -> io::Result<Self> {
+        let table_offset = f.read_u64::<BigEndian>()?;
+        let table_size = f.read_u32::<BigEndian>()?;
+        let flags = f.read_u32::<BigEndian>()?;
+        let bitmap_type = f.read_u8()?;
+        let granularity_bits = f.read_u8()?;
+        let name_size = f.read_u16::<BigEndian>()? as usize;
+        let extra_data_size = f.read_u32::<BigEndian>()? as usize;
+        if extra_data_size > MAX_BITMAP_EXTRA_DATA_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bitmap extra data too large: {extra_data_size}"),
+            ));
+        }
+        if !(MIN_BITMAP_GRANULARITY_BITS..=MAX_BITMAP_GRANULARITY_BITS).contains(&granularity_bits)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid bitmap granularity: {granularity_bits} bits"),
+            ));
+        }
+        if name_size > MAX_BITMAP_NAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bitmap name too long: {name_size}"),
+            ));
+        }
+
+        let mut extra_data = vec![0u8; extra_data_size];
+        f.read_exact(&mut extra_data)?;
+        let mut name = vec![0u8; name_size];
+        f.read_exact(&mut name)?;
+
+        let entry_size = BITMAP_ENTRY_HEADER_SIZE + extra_data_size + name_size;
+        let mut padding = vec![0u8; entry_size.next_multiple_of(8) - entry_size];
+        f.read_exact(&mut padding)?;
+
+        Ok(QcowBitmap {
+            table_offset,
+            table_size,
+            flags,
+            bitmap_type,
+            granularity_bits,
+            name: String::from_utf8_lossy(&name).into_owned(),
+            extra_data,
+        })
+    }
+
+    /// Returns the bitmap directory holding `bitmaps`, as stored in the image.
+    pub fn write_directory(bitmaps: &[Self]) -> Vec<u8> {
+        let mut directory = Vec::with_capacity(Self::directory_size(bitmaps) as usize);
+        for bitmap in bitmaps {
+            bitmap.write_to(&mut directory);
+        }
+        directory
+    }
+
+    /// Returns the size of the bitmap directory holding `bitmaps`.
+    pub fn directory_size(bitmaps: &[Self]) -> u64 {
+        bitmaps.iter().map(|b| b.entry_size() as u64).sum()
+    }
+
+    /// Returns the number of bytes of the disk covered by each bit.
+    pub fn granularity(&self) -> u64 {
+        1 << self.granularity_bits
+    }
+
+    /// Returns true if the bitmap may not reflect all the writes to the image.
+    pub fn in_use(&self) -> bool {
+        self.flags & BITMAP_FLAG_IN_USE != 0
+    }
+
+    /// Returns true if the bitmap must reflect all the writes to the image.
+    pub fn auto(&self) -> bool {
+        self.flags & BITMAP_FLAG_AUTO != 0
+    }
+
+    /// Returns the number of bitmap table entries, each pointing at a cluster of bitmap data, for
+    /// a disk of `disk_size` bytes.
+    pub fn table_entries(disk_size: u64, granularity: u64, cluster_size: u64) -> u64 {
+        disk_size.div_ceil(granularity).div_ceil(cluster_size * 8)
+    }
+
+    // Size of the directory entry of this bitmap, padding included.
+    fn entry_size(&self) -> usize {
+        (BITMAP_ENTRY_HEADER_SIZE + self.extra_data.len() + self.name.len()).next_multiple_of(8)
+    }
+
+    fn write_to(&self, directory: &mut Vec<u8>) {
+        let start = directory.len();
+        // Writing to a Vec can't fail.
+        directory.write_u64::<BigEndian>(self.table_offset).unwrap();
+        directory.write_u32::<BigEndian>(self.table_size).unwrap();
+        directory.write_u32::<BigEndian>(self.flags).unwrap();
+        directory.write_u8(self.bitmap_type).unwrap();
+        directory.write_u8(self.granularity_bits).unwrap();
+        directory
+            .write_u16::<BigEndian>(self.name.len() as u16)
+            .unwrap();
+        directory
+            .write_u32::<BigEndian>(self.extra_data.len() as u32)
+            .unwrap();
+        directory.extend_from_slice(&self.extra_data);
+        directory.extend_from_slice(self.name.as_bytes());
+        directory.resize(start + self.entry_size(), 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn bitmap_directory_round_trip() {
+        let bitmaps = vec![
+            QcowBitmap {
+                table_offset: 0x3_0000,
+                table_size: 1,
+                flags: BITMAP_FLAG_AUTO,
+                bitmap_type: BITMAP_TYPE_DIRTY_TRACKING,
+                granularity_bits: 16,
+                name: "backup".to_string(),
+                ..Default::default()
+            },
+            QcowBitmap {
+                table_offset: 0x5_0000,
+                table_size: 2,
+                flags: BITMAP_FLAG_IN_USE | BITMAP_FLAG_AUTO,
+                bitmap_type: BITMAP_TYPE_DIRTY_TRACKING,
+                granularity_bits: 20,
+                name: "other bitmap".to_string(),
+                extra_data: vec![1, 2, 3],
+            },
+        ];
+
+        let directory = QcowBitmap::write_directory(&bitmaps);
+        assert_eq!(directory.len() as u64, QcowBitmap::directory_size(&bitmaps));
+        assert_eq!(directory.len() % 8, 0);
+
+        let read = QcowBitmap::read_directory(&mut Cursor::new(&directory), 2).unwrap();
+        assert_eq!(read, bitmaps);
+        assert!(!read[0].in_use());
+        assert!(read[1].in_use());
+        assert_eq!(read[1].granularity(), 0x10_0000);
+
+        let extension = BitmapsExtension {
+            nb_bitmaps: 2,
+            directory_size: directory.len() as u64,
+            directory_offset: 0x4_0000,
+        };
+        let data = extension.to_bytes();
+        assert_eq!(data.len(), BITMAPS_EXTENSION_SIZE as usize);
+        assert_eq!(
+            BitmapsExtension::read_from(&mut Cursor::new(&data)).unwrap(),
+            extension
+        );
+    }
+
+    #[test]
+    fn bitmap_table_entries() {
+        // One cluster of bitmap data covers cluster_size * 8 chunks.
+        assert_eq!(QcowBitmap::table_entries(0, 0x1_0000, 0x1_0000), 0);
+        assert_eq!(QcowBitmap::table_entries(1, 0x1_0000, 0x1_0000), 1);
+        assert_eq!(
+            QcowBitmap::table_entries(0x1_0000 * 0x8_0000, 0x1_0000, 0x1_0000),
+            1
+        );
+        assert_eq!(
+            QcowBitmap::table_entries(0x1_0000 * 0x8_0000 + 1, 0x1_0000, 0x1_0000),
+            2
+        );
+    }
+}
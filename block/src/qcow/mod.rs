@@ -4,19 +4,23 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
+mod bitmap;
 mod decoder;
 mod qcow_raw_file;
 mod raw_file;
 mod refcount;
+mod snapshot;
 mod vec_cache;
 
 use std::cmp::{max, min};
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 use std::fs::{OpenOptions, read_link};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
 use std::os::fd::{AsRawFd, RawFd};
 use std::str::{self, FromStr};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use bitflags::bitflags;
 use libc::{EINVAL, EIO, ENOSPC};
@@ -28,10 +32,20 @@ use vmm_sys_util::seek_hole::SeekHole;
 use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
 
 use crate::BlockBackend;
+use crate::dirty_bitmap::DirtyBitmap;
+use crate::luks::{LuksCreateOptions, LuksError, LuksHeader, LuksVolume};
+pub use crate::qcow::bitmap::QcowBitmap;
+use crate::qcow::bitmap::{
+    AUTOCLEAR_BITMAPS, BITMAP_FLAG_AUTO, BITMAP_FLAG_IN_USE, BITMAP_TABLE_ENTRY_ALL_ONES,
+    BITMAP_TABLE_OFFSET_MASK, BITMAP_TYPE_DIRTY_TRACKING, BITMAPS_EXTENSION_SIZE, BitmapsExtension,
+    HEADER_EXT_BITMAPS, MAX_BITMAP_DIRECTORY_SIZE, MAX_BITMAP_NAME_SIZE, MAX_BITMAPS,
+};
 use crate::qcow::decoder::{Decoder, ZlibDecoder, ZstdDecoder};
 use crate::qcow::qcow_raw_file::{BeUint, QcowRawFile};
 pub use crate::qcow::raw_file::RawFile;
 use crate::qcow::refcount::RefCount;
+use crate::qcow::snapshot::MAX_SNAPSHOTS;
+pub use crate::qcow::snapshot::QcowSnapshot;
 use crate::qcow::vec_cache::{CacheMap, Cacheable, VecCache};
 
 /// Nesting depth limit for disk formats that can open other disk files.
@@ -48,8 +62,16 @@ pub enum Error {
     BackingFilesDisabled,
     #[error("Backing file name is too long: {0} bytes over")]
     BackingFileTooLong(usize),
+    #[error("Bitmap name is too long: {0} bytes")]
+    BitmapNameTooLong(usize),
+    #[error("Persistent bitmaps need a version 3 image")]
+    BitmapsNeedVersion3,
     #[error("Image is marked corrupt and cannot be opened for writing")]
     CorruptImage,
+    #[error("Encrypted images don't support asynchronous I/O")]
+    EncryptedAsyncIo,
+    #[error("Encrypted backing files are not supported")]
+    EncryptedBackingFile,
     #[error("Failed to evict cache")]
     EvictingCache(#[source] io::Error),
     #[error("File larger than max of {MAX_QCOW_FILE_SIZE}: {0}")]
@@ -58,12 +80,16 @@ pub enum Error {
     GettingFileSize(#[source] io::Error),
     #[error("Failed to get refcount")]
     GettingRefcount(#[source] refcount::Error),
+    #[error("Header extensions don't fit in the first cluster")]
+    HeaderTooLarge,
     #[error("Failed to parse filename")]
     InvalidBackingFileName(#[source] str::Utf8Error),
     #[error("Invalid cluster index")]
     InvalidClusterIndex,
     #[error("Invalid cluster size")]
     InvalidClusterSize,
+    #[error("Invalid location of the LUKS header")]
+    InvalidCryptoHeader,
     #[error("Invalid index")]
     InvalidIndex,
     #[error("Invalid L1 table offset")]
@@ -78,6 +104,8 @@ pub enum Error {
     InvalidRefcountTableOffset,
     #[error("Invalid refcount table size: {0}")]
     InvalidRefcountTableSize(u64),
+    #[error("Failed to unlock the LUKS encrypted data")]
+    Luks(#[source] LuksError),
     #[error("Maximum disk nesting depth exceeded")]
     MaxNestingDepthExceeded,
     #[error("No free clusters")]
@@ -88,6 +116,8 @@ pub enum Error {
     NotEnoughSpaceForRefcounts,
     #[error("Failed to open file {0}")]
     OpeningFile(#[source] io::Error),
+    #[error("Failed to read the bitmaps")]
+    ReadingBitmaps(#[source] io::Error),
     #[error("Failed to read data")]
     ReadingData(#[source] io::Error),
     #[error("Failed to read header")]
@@ -98,6 +128,8 @@ pub enum Error {
     ReadingRefCountBlock(#[source] refcount::Error),
     #[error("Failed to read ref counts")]
     ReadingRefCounts(#[source] io::Error),
+    #[error("Failed to read the snapshot table")]
+    ReadingSnapshots(#[source] io::Error),
     #[error("Failed to rebuild ref counts")]
     RebuildingRefCounts(#[source] io::Error),
     #[error("Refcount overflow")]
@@ -120,26 +152,44 @@ pub enum Error {
     ShrinkNotSupported,
     #[error("Size too small for number of clusters")]
     SizeTooSmallForNumberOfClusters,
+    #[error("Snapshot {0} already exists")]
+    SnapshotExists(String),
+    #[error("Snapshot name is too long: {0} bytes")]
+    SnapshotNameTooLong(usize),
+    #[error("Snapshot {0} not found")]
+    SnapshotNotFound(String),
+    #[error("Snapshot was taken with a disk size of {0} bytes")]
+    SnapshotSizeMismatch(u64),
     #[error("Failed to sync header")]
     SyncingHeader(#[source] io::Error),
+    #[error("Too many bitmaps: {0}")]
+    TooManyBitmaps(u32),
     #[error("L1 entry table too large: {0}")]
     TooManyL1Entries(u64),
     #[error("Ref count table too large: {0}")]
     TooManyRefcounts(u64),
+    #[error("Too many snapshots: {0}")]
+    TooManySnapshots(u32),
     #[error("Unsupported backing file format: {0}")]
     UnsupportedBackingFileFormat(String),
     #[error("Unsupported compression type")]
     UnsupportedCompressionType,
+    #[error("Unsupported encryption method: {0}")]
+    UnsupportedEncryption(u32),
     #[error("Unsupported qcow2 feature(s)")]
     UnsupportedFeature(#[source] MissingFeatureError),
     #[error("Unsupported refcount order")]
     UnsupportedRefcountOrder,
     #[error("Unsupported version: {0}")]
     UnsupportedVersion(u32),
+    #[error("Failed to write the bitmaps")]
+    WritingBitmaps(#[source] io::Error),
     #[error("Failed to write data")]
     WritingData(#[source] io::Error),
     #[error("Failed to write header")]
     WritingHeader(#[source] io::Error),
+    #[error("Failed to write snapshots")]
+    WritingSnapshots(#[source] io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -177,6 +227,13 @@ pub enum CompressionType {
     Zstd,
 }
 
+/// Location of the LUKS header of an image whose data clusters are encrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CryptoHeader {
+    pub offset: u64,
+    pub length: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct BackingFileConfig {
     pub path: String,
@@ -199,12 +256,15 @@ const MAX_CLUSTER_BITS: u32 = 21;
 // This easily covers 1 TB files. When support for bigger files is needed the assumptions made to
 // keep these tables in RAM needs to be thrown out.
 const MAX_RAM_POINTER_TABLE_SIZE: u64 = 35_000_000;
+// Number of L2 tables kept in memory.
+const L2_CACHE_SIZE: usize = 100;
 // 16-bit refcounts.
 const DEFAULT_REFCOUNT_ORDER: u32 = 4;
 
 const V2_BARE_HEADER_SIZE: u32 = 72;
 const V3_BARE_HEADER_SIZE: u32 = 104;
 const AUTOCLEAR_FEATURES_OFFSET: u64 = 88;
+const NB_SNAPSHOTS_OFFSET: u64 = 60;
 
 // bits 0-8 and 56-63 are reserved.
 const L1_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
@@ -226,6 +286,15 @@ const HEADER_EXT_END: u32 = 0x00000000;
 const HEADER_EXT_BACKING_FORMAT: u32 = 0xe2792aca;
 // Feature name table
 const HEADER_EXT_FEATURE_NAME_TABLE: u32 = 0x6803f857;
+// Full disk encryption header pointer
+const HEADER_EXT_CRYPTO: u32 = 0x0537be77;
+const CRYPTO_HEADER_EXTENSION_SIZE: u32 = 16;
+
+// Encryption methods of the data clusters
+const CRYPT_NONE: u32 = 0;
+const CRYPT_LUKS: u32 = 2;
+// Limit of the size of the LUKS header, as qemu
+const MAX_CRYPTO_HEADER_SIZE: u64 = 64 << 20;
 
 // Feature name table entry type incompatible
 const FEAT_TYPE_INCOMPATIBLE: u8 = 0;
@@ -380,6 +449,8 @@ pub struct QcowHeader {
 
     // Post-header entries
     pub backing_file: Option<BackingFileConfig>,
+    pub bitmaps: Option<BitmapsExtension>,
+    pub crypto_header: Option<CryptoHeader>,
 }
 
 impl QcowHeader {
@@ -412,6 +483,20 @@ impl QcowHeader {
                         backing_file.format = Some(format_str.parse()?);
                     }
                 }
+                // Bitmaps are only consistent with the image while their autoclear bit is set.
+                HEADER_EXT_BITMAPS
+                    if header.autoclear_features & AUTOCLEAR_BITMAPS != 0
+                        && ext_length == BITMAPS_EXTENSION_SIZE =>
+                {
+                    header.bitmaps =
+                        Some(BitmapsExtension::read_from(f).map_err(Error::ReadingHeader)?);
+                }
+                HEADER_EXT_CRYPTO if ext_length == CRYPTO_HEADER_EXTENSION_SIZE => {
+                    header.crypto_header = Some(CryptoHeader {
+                        offset: u64::read_be(f).map_err(Error::ReadingHeader)?,
+                        length: u64::read_be(f).map_err(Error::ReadingHeader)?,
+                    });
+                }
                 HEADER_EXT_FEATURE_NAME_TABLE if feature_table.is_some() => {
                     const FEATURE_NAME_ENTRY_SIZE: usize = 1 + 1 + 46; // type + bit + name
                     let mut data = vec![0u8; ext_length as usize];
@@ -492,6 +577,8 @@ impl QcowHeader {
             },
             compression_type: CompressionType::Zlib,
             backing_file: None,
+            bitmaps: None,
+            crypto_header: None,
         };
         if version == 3 && header.header_size > V3_BARE_HEADER_SIZE {
             let raw_compression_type = read_u64_be(f)? >> (64 - 8);
@@ -620,6 +707,8 @@ impl QcowHeader {
                 path: String::from(path),
                 format: None,
             }),
+            bitmaps: None,
+            crypto_header: None,
         })
     }
 
@@ -660,6 +749,20 @@ impl QcowHeader {
                 write_u64_be(file, 0)?; // no compression
             }
 
+            if let Some(crypto_header) = self.crypto_header {
+                write_u32_be(file, HEADER_EXT_CRYPTO)?;
+                write_u32_be(file, CRYPTO_HEADER_EXTENSION_SIZE)?;
+                write_u64_be(file, crypto_header.offset)?;
+                write_u64_be(file, crypto_header.length)?;
+            }
+
+            if let Some(bitmaps) = self.bitmaps {
+                write_u32_be(file, HEADER_EXT_BITMAPS)?;
+                write_u32_be(file, BITMAPS_EXTENSION_SIZE)?;
+                file.write_all(&bitmaps.to_bytes())
+                    .map_err(Error::WritingHeader)?;
+            }
+
             write_u32_be(file, 0)?; // header extension type: end of header extension area
             write_u32_be(file, 0)?; // length of header extension data: 0
         }
@@ -697,6 +800,18 @@ impl QcowHeader {
         Ok(())
     }
 
+    /// Write only the nb_snapshots and snapshots_offset fields to the file at their fixed offset.
+    fn write_snapshot_table_location<F: Seek + Write>(&self, file: &mut F) -> Result<()> {
+        // Both fields go in a single write so the header never points at a mismatched table.
+        let mut fields = [0u8; 12];
+        fields[..4].copy_from_slice(&self.nb_snapshots.to_be_bytes());
+        fields[4..].copy_from_slice(&self.snapshots_offset.to_be_bytes());
+        file.seek(SeekFrom::Start(NB_SNAPSHOTS_OFFSET))
+            .map_err(Error::WritingHeader)?;
+        file.write_all(&fields).map_err(Error::WritingHeader)?;
+        Ok(())
+    }
+
     /// Set or clear the dirty bit for QCOW2 v3 images.
     ///
     /// When `dirty` is true, sets the bit to indicate the image is in use.
@@ -736,19 +851,24 @@ impl QcowHeader {
             .contains(IncompatFeatures::CORRUPT)
     }
 
-    /// Clear all autoclear feature bits for QCOW2 v3 images.
+    /// Clear the autoclear feature bits of the features not supported for QCOW2 v3 images.
     ///
     /// These bits indicate features that can be safely disabled when modified
-    /// by software that doesn't understand them.
+    /// by software that doesn't understand them. Only the bitmaps are kept.
     pub fn clear_autoclear_features<F: Seek + Write + FileSync>(
         &mut self,
         file: &mut F,
     ) -> Result<()> {
-        if self.version == 3 && self.autoclear_features != 0 {
-            self.autoclear_features = 0;
+        let supported = if self.bitmaps.is_some() {
+            AUTOCLEAR_BITMAPS
+        } else {
+            0
+        };
+        if self.version == 3 && self.autoclear_features & !supported != 0 {
+            self.autoclear_features &= supported;
             file.seek(SeekFrom::Start(AUTOCLEAR_FEATURES_OFFSET))
                 .map_err(Error::WritingHeader)?;
-            u64::write_be(file, 0).map_err(Error::WritingHeader)?;
+            u64::write_be(file, self.autoclear_features).map_err(Error::WritingHeader)?;
             file.fsync().map_err(Error::SyncingHeader)?;
         }
         Ok(())
@@ -764,21 +884,120 @@ fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u
     for_data + for_refcounts
 }
 
+/// Where a range of guest data is read from, as mapped by [`QcowFile::map_read`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReadMapping {
+    /// `len` bytes at `offset` in `fd`, the image file or one of its backing files.
+    Host { fd: RawFd, offset: u64, len: usize },
+    /// `len` bytes reading as zeros.
+    Zero { len: usize },
+    /// Bytes of a compressed cluster, already read and decompressed.
+    Data(Vec<u8>),
+}
+
+impl ReadMapping {
+    /// Returns the number of guest bytes this mapping covers.
+    pub fn len(&self) -> usize {
+        match self {
+            ReadMapping::Host { len, .. } | ReadMapping::Zero { len } => *len,
+            ReadMapping::Data(data) => data.len(),
+        }
+    }
+
+    /// Returns true if the mapping covers no bytes.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Appends `mapping` to `mappings`, merging it with the last one when they are contiguous.
+fn push_read_mapping(mappings: &mut Vec<ReadMapping>, mapping: ReadMapping) {
+    match (mappings.last_mut(), &mapping) {
+        (
+            Some(ReadMapping::Host {
+                fd: last_fd,
+                offset: last_offset,
+                len: last_len,
+            }),
+            ReadMapping::Host { fd, offset, len },
+        ) if last_fd == fd && *last_offset + *last_len as u64 == *offset => *last_len += len,
+        (Some(ReadMapping::Zero { len: last_len }), ReadMapping::Zero { len }) => *last_len += len,
+        _ => mappings.push(mapping),
+    }
+}
+
+/// Where a write to a cluster goes, as mapped by [`QcowFile::map_write`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteMapping {
+    /// Offset of the guest address in the image file.
+    pub offset: u64,
+    /// The cluster was just allocated, the metadata pointing at it must
+    /// not be synced before its data is written.
+    pub allocated: bool,
+    /// The cluster was just allocated and the rest of it, outside of the
+    /// written range, has to be copied from the backing file.
+    pub copy_backing: bool,
+}
+
+/// Metadata written back by [`QcowFile::write_back_metadata`], not synced yet.
+#[derive(Debug)]
+pub struct MetadataWriteBack {
+    /// Offset, value and entries as stored in the file of the L1 table.
+    l1_table: Option<(u64, Vec<u64>, Vec<u64>)>,
+    refcount_table: Option<Vec<u64>>,
+    /// Clusters freed before the write back, reused once it is synced.
+    freed_clusters: Vec<u64>,
+    /// Pointer tables written by the file when written back.
+    tables_written: u64,
+    /// The L1 or refcount table was written from this write back.
+    tables_pending_sync: bool,
+}
+
 trait BackingFileOps: Send + Seek + Read {
     fn read_at(&mut self, address: u64, buf: &mut [u8]) -> std::io::Result<()> {
         self.seek(SeekFrom::Start(address))?;
         self.read_exact(buf)
     }
+    fn map_read(
+        &mut self,
+        address: u64,
+        count: usize,
+        mappings: &mut Vec<ReadMapping>,
+    ) -> std::io::Result<()>;
     fn clone_box(&self) -> Box<dyn BackingFileOps>;
 }
 
 impl BackingFileOps for QcowFile {
+    fn map_read(
+        &mut self,
+        address: u64,
+        count: usize,
+        mappings: &mut Vec<ReadMapping>,
+    ) -> std::io::Result<()> {
+        QcowFile::map_read(self, address, count, mappings)
+    }
     fn clone_box(&self) -> Box<dyn BackingFileOps> {
         Box::new(self.clone())
     }
 }
 
 impl BackingFileOps for RawFile {
+    fn map_read(
+        &mut self,
+        address: u64,
+        count: usize,
+        mappings: &mut Vec<ReadMapping>,
+    ) -> std::io::Result<()> {
+        push_read_mapping(
+            mappings,
+            ReadMapping::Host {
+                fd: self.as_raw_fd(),
+                offset: address,
+                len: count,
+            },
+        );
+        Ok(())
+    }
     fn clone_box(&self) -> Box<dyn BackingFileOps> {
         Box::new(self.clone())
     }
@@ -831,6 +1050,9 @@ impl BackingFile {
                 let backing_qcow =
                     QcowFile::from_with_nesting_depth(raw_file, max_nesting_depth - 1, sparse)
                         .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
+                if backing_qcow.is_encrypted() {
+                    return Err(Error::EncryptedBackingFile);
+                }
                 let size = backing_qcow.virtual_size();
                 (Box::new(backing_qcow), size)
             }
@@ -862,6 +1084,28 @@ impl BackingFile {
             Ok(())
         }
     }
+
+    /// Map a range of the backing file, the portion beyond its size reading as zeros.
+    fn map_read(
+        &mut self,
+        address: u64,
+        count: usize,
+        mappings: &mut Vec<ReadMapping>,
+    ) -> std::io::Result<()> {
+        let available = self.virtual_size.saturating_sub(address).min(count as u64) as usize;
+        if available > 0 {
+            self.inner.map_read(address, available, mappings)?;
+        }
+        if available < count {
+            push_read_mapping(
+                mappings,
+                ReadMapping::Zero {
+                    len: count - available,
+                },
+            );
+        }
+        Ok(())
+    }
 }
 
 impl Clone for BackingFile {
@@ -911,6 +1155,15 @@ pub struct QcowFile {
     avail_clusters: Vec<u64>,
     backing_file: Option<BackingFile>,
     sparse: bool,
+    snapshots: Vec<QcowSnapshot>,
+    bitmaps: Vec<QcowBitmap>,
+    // Names of the bitmaps already in use when the image was opened, which miss some writes.
+    stale_bitmaps: Vec<String>,
+    // Cipher of the data clusters once an encrypted image is unlocked.
+    crypto: Option<Arc<LuksVolume>>,
+    // Number of times the L1 or refcount table was written, telling if the tables of a
+    // `MetadataWriteBack` are stale.
+    tables_written: u64,
 }
 
 impl QcowFile {
@@ -935,6 +1188,18 @@ impl QcowFile {
             return Err(Error::UnsupportedVersion(header.version));
         }
 
+        // The legacy AES encryption is broken, only LUKS is supported.
+        match header.crypt_method {
+            CRYPT_NONE => {}
+            CRYPT_LUKS => match header.crypto_header {
+                Some(crypto_header)
+                    if crypto_header.length > 0
+                        && crypto_header.length <= MAX_CRYPTO_HEADER_SIZE => {}
+                _ => return Err(Error::InvalidCryptoHeader),
+            },
+            method => return Err(Error::UnsupportedEncryption(method)),
+        }
+
         // Make sure that the L1 table fits in RAM.
         if u64::from(header.l1_size) > MAX_RAM_POINTER_TABLE_SIZE {
             return Err(Error::InvalidL1TableSize(header.l1_size));
@@ -1058,10 +1323,23 @@ impl QcowFile {
             return Err(Error::TooManyRefcounts(refcount_clusters));
         }
         let refcount_block_entries = cluster_size * 8 / refcount_bits;
+        // Snapshots keep clusters the image no longer uses, so the file can outgrow the estimate
+        // above. Cover the whole file plus a rewrite of the disk, as far as the table goes.
+        let file_clusters = div_round_up_u64(file_size, cluster_size);
+        let refcount_table_entries = max(
+            refcount_clusters,
+            min(
+                div_round_up_u64(
+                    file_clusters + num_clusters + num_l2_clusters,
+                    refcount_block_entries,
+                ),
+                u64::from(header.refcount_table_clusters) * cluster_size / size_of::<u64>() as u64,
+            ),
+        );
         let refcounts = RefCount::new(
             &mut raw_file,
             header.refcount_table_offset,
-            refcount_clusters,
+            refcount_table_entries,
             refcount_block_entries,
             cluster_size,
             refcount_bits,
@@ -1069,19 +1347,31 @@ impl QcowFile {
         .map_err(Error::ReadingRefCounts)?;
 
         let l2_entries = cluster_size / size_of::<u64>() as u64;
+        let snapshots = QcowFile::read_snapshots(&mut raw_file, &header)?;
+        let bitmaps = QcowFile::read_bitmaps(&mut raw_file, &header)?;
+        let stale_bitmaps = bitmaps
+            .iter()
+            .filter(|b| b.in_use())
+            .map(|b| b.name.clone())
+            .collect();
 
         let mut qcow = QcowFile {
             raw_file,
             header,
             l1_table,
             l2_entries,
-            l2_cache: CacheMap::new(100),
+            l2_cache: CacheMap::new(L2_CACHE_SIZE),
             refcounts,
             current_offset: 0,
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
             backing_file,
             sparse,
+            snapshots,
+            bitmaps,
+            stale_bitmaps,
+            crypto: None,
+            tables_written: 0,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -1105,6 +1395,7 @@ impl QcowFile {
 
             qcow.header
                 .clear_autoclear_features(qcow.raw_file.file_mut())?;
+            qcow.mark_bitmaps_in_use()?;
         }
 
         Ok(qcow)
@@ -1136,6 +1427,49 @@ impl QcowFile {
         // backing_file is loaded by new_from_header -> Self::from() based on the header
     }
 
+    /// Creates a new QcowFile whose data clusters are encrypted with LUKS, with one key slot
+    /// per key in `keys`. The returned file is already unlocked.
+    pub fn new_encrypted(
+        file: RawFile,
+        virtual_size: u64,
+        sparse: bool,
+        keys: &[&[u8]],
+        options: &LuksCreateOptions,
+    ) -> Result<QcowFile> {
+        let header = QcowHeader::create_for_size_and_path(3, virtual_size, None)?;
+        let mut qcow = QcowFile::new_from_header(file, &header, sparse)?;
+        let (luks_header, volume) = LuksVolume::format(keys, options).map_err(Error::Luks)?;
+        if volume.sector_size() > qcow.cluster_size() {
+            return Err(Error::Luks(LuksError::UnsupportedSectorSize(
+                volume.sector_size() as u32,
+            )));
+        }
+
+        let cluster_size = qcow.cluster_size();
+        let length = luks_header.len() as u64;
+        let offset = qcow
+            .alloc_contiguous_clusters(div_round_up_u64(length, cluster_size))
+            .map_err(Error::WritingHeader)?;
+        let raw_file = qcow.raw_file.file_mut();
+        raw_file
+            .seek(SeekFrom::Start(offset))
+            .map_err(Error::WritingHeader)?;
+        raw_file
+            .write_all(&luks_header)
+            .map_err(Error::WritingHeader)?;
+
+        qcow.header.crypt_method = CRYPT_LUKS;
+        qcow.header.crypto_header = Some(CryptoHeader { offset, length });
+        qcow.raw_file
+            .file_mut()
+            .rewind()
+            .map_err(Error::SeekingFile)?;
+        qcow.header.write_to(qcow.raw_file.file_mut())?;
+        qcow.crypto = Some(Arc::new(volume));
+        qcow.flush().map_err(Error::SyncingHeader)?;
+        Ok(qcow)
+    }
+
     fn new_from_header(mut file: RawFile, header: &QcowHeader, sparse: bool) -> Result<QcowFile> {
         file.rewind().map_err(Error::SeekingFile)?;
         header.write_to(&mut file)?;
@@ -1175,6 +1509,104 @@ impl QcowFile {
         &self.header
     }
 
+    /// Returns the size of the clusters of this file.
+    pub fn cluster_size(&self) -> u64 {
+        self.raw_file.cluster_size()
+    }
+
+    /// Returns true if the data clusters of this file are encrypted, in which case it must be
+    /// unlocked before its data is accessed.
+    pub fn is_encrypted(&self) -> bool {
+        self.header.crypt_method != CRYPT_NONE
+    }
+
+    /// Unlocks the data of an encrypted file with `key`, matched against the key slots of its
+    /// LUKS header.
+    pub fn unlock(&mut self, key: &[u8]) -> Result<()> {
+        let crypto_header = self
+            .header
+            .crypto_header
+            .ok_or(Error::InvalidCryptoHeader)?;
+        let mut luks_header = vec![0u8; crypto_header.length as usize];
+        let raw_file = self.raw_file.file_mut();
+        raw_file
+            .seek(SeekFrom::Start(crypto_header.offset))
+            .map_err(Error::ReadingHeader)?;
+        raw_file
+            .read_exact(&mut luks_header)
+            .map_err(Error::ReadingHeader)?;
+
+        // The LUKS header is stored as if it was an image on its own, the qcow2 clusters holding
+        // the encrypted data instead of its payload.
+        let mut luks_header = Cursor::new(luks_header);
+        let volume = LuksHeader::read_from(&mut luks_header)
+            .map_err(|e| Error::Luks(LuksError::Header(e)))?
+            .unlock(&mut luks_header, key)
+            .map_err(Error::Luks)?;
+        if volume.sector_size() > self.cluster_size() {
+            return Err(Error::Luks(LuksError::UnsupportedSectorSize(
+                volume.sector_size() as u32,
+            )));
+        }
+        self.crypto = Some(Arc::new(volume));
+        Ok(())
+    }
+
+    // Returns the cipher of the data clusters, None if they aren't encrypted, or an error if the
+    // file is still locked.
+    fn crypto(&self) -> std::io::Result<Option<Arc<LuksVolume>>> {
+        match &self.crypto {
+            Some(crypto) => Ok(Some(Arc::clone(crypto))),
+            None if self.is_encrypted() => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the encrypted image is locked",
+            )),
+            None => Ok(None),
+        }
+    }
+
+    // Reads `buf` from the data cluster at the host offset `offset`, decrypting it. The IV of
+    // each encrypted sector is its host offset, as done by QEMU.
+    fn read_data(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let Some(crypto) = self.crypto()? else {
+            let raw_file = self.raw_file.file_mut();
+            raw_file.seek(SeekFrom::Start(offset))?;
+            return raw_file.read_exact(buf);
+        };
+        let sector_size = crypto.sector_size();
+        let start = offset - offset % sector_size;
+        let end = (offset + buf.len() as u64).next_multiple_of(sector_size);
+        let mut sectors = vec![0u8; (end - start) as usize];
+        let raw_file = self.raw_file.file_mut();
+        raw_file.seek(SeekFrom::Start(start))?;
+        raw_file.read_exact(&mut sectors)?;
+        crypto.decrypt(start, &mut sectors);
+        buf.copy_from_slice(&sectors[(offset - start) as usize..][..buf.len()]);
+        Ok(())
+    }
+
+    // Writes `data` to the data cluster at the host offset `offset`, encrypting it. The sectors
+    // only partially written are read back first.
+    fn write_data(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        let Some(crypto) = self.crypto()? else {
+            let raw_file = self.raw_file.file_mut();
+            raw_file.seek(SeekFrom::Start(offset))?;
+            return raw_file.write_all(data);
+        };
+        let sector_size = crypto.sector_size();
+        let start = offset - offset % sector_size;
+        let end = (offset + data.len() as u64).next_multiple_of(sector_size);
+        let mut sectors = vec![0u8; (end - start) as usize];
+        if start != offset || end != offset + data.len() as u64 {
+            self.read_data(start, &mut sectors)?;
+        }
+        sectors[(offset - start) as usize..][..data.len()].copy_from_slice(data);
+        crypto.encrypt(start, &mut sectors);
+        let raw_file = self.raw_file.file_mut();
+        raw_file.seek(SeekFrom::Start(start))?;
+        raw_file.write_all(&sectors)
+    }
+
     /// Returns the L1 lookup table for this file. This is only useful for debugging.
     pub fn l1_table(&self) -> &[u64] {
         self.l1_table.get_values()
@@ -1382,6 +1814,554 @@ impl QcowFile {
         Ok(())
     }
 
+    /// Returns the internal snapshots of the image.
+    pub fn snapshots(&self) -> &[QcowSnapshot] {
+        &self.snapshots
+    }
+
+    /// Takes an internal snapshot of the image named `name`. `vm_clock_nsec` is how long the guest
+    /// has been running, recorded with the snapshot.
+    ///
+    /// The snapshot shares all clusters with the image, they are copied when the image next
+    /// writes to them.
+    pub fn create_snapshot(&mut self, name: &str, vm_clock_nsec: u64) -> Result<()> {
+        if name.len() > u16::MAX as usize {
+            return Err(Error::SnapshotNameTooLong(name.len()));
+        }
+        if self.snapshots.iter().any(|s| s.name == name) {
+            return Err(Error::SnapshotExists(name.to_string()));
+        }
+        if self.snapshots.len() >= MAX_SNAPSHOTS as usize {
+            return Err(Error::TooManySnapshots(self.header.nb_snapshots));
+        }
+
+        self.begin_snapshot_update()?;
+
+        let l1_table = self.l1_table.get_values().to_vec();
+        self.update_tree_refcounts(&l1_table, 1)
+            .map_err(Error::WritingSnapshots)?;
+
+        // The snapshot gets its own copy of the L1 table.
+        let l1_clusters = div_round_up_u64(
+            l1_table.len() as u64 * size_of::<u64>() as u64,
+            self.raw_file.cluster_size(),
+        );
+        let l1_table_offset = self
+            .alloc_contiguous_clusters(l1_clusters)
+            .map_err(Error::WritingSnapshots)?;
+        self.raw_file
+            .write_pointer_table_direct(l1_table_offset, l1_table.iter())
+            .map_err(Error::WritingSnapshots)?;
+
+        let id = self
+            .snapshots
+            .iter()
+            .filter_map(|s| s.id.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut snapshots = self.snapshots.clone();
+        snapshots.push(QcowSnapshot {
+            l1_table_offset,
+            l1_size: l1_table.len() as u32,
+            id: id.to_string(),
+            name: name.to_string(),
+            date_sec: date.as_secs() as u32,
+            date_nsec: date.subsec_nanos(),
+            vm_clock_nsec,
+            disk_size: self.virtual_size(),
+            ..Default::default()
+        });
+        self.write_snapshots(snapshots)?;
+
+        self.end_snapshot_update()
+    }
+
+    /// Reverts the image to the snapshot with the ID, or else the name, `id_or_name`.
+    pub fn apply_snapshot(&mut self, id_or_name: &str) -> Result<()> {
+        let snapshot = self.snapshots[self.find_snapshot(id_or_name)?].clone();
+        if snapshot.disk_size != self.virtual_size() {
+            return Err(Error::SnapshotSizeMismatch(snapshot.disk_size));
+        }
+
+        self.begin_snapshot_update()?;
+
+        // The L1 table of the image keeps its size, the snapshot can't address past it.
+        let l1_size = self.l1_table.len();
+        let mut l1_table = self
+            .raw_file
+            .read_pointer_table(
+                snapshot.l1_table_offset,
+                min(snapshot.l1_size as usize, l1_size) as u64,
+                Some(L1_TABLE_OFFSET_MASK),
+            )
+            .map_err(Error::ReadingSnapshots)?;
+        l1_table.resize(l1_size, 0);
+
+        // Take references to the snapshot clusters before the image points at them, then drop
+        // the ones to the clusters the image used.
+        self.update_tree_refcounts(&l1_table, 1)
+            .map_err(Error::WritingSnapshots)?;
+        self.sync_caches().map_err(Error::WritingSnapshots)?;
+        let old_l1_table = std::mem::replace(&mut self.l1_table, VecCache::from_vec(l1_table));
+        self.write_l1_table().map_err(Error::WritingSnapshots)?;
+        self.raw_file
+            .file_mut()
+            .sync_data()
+            .map_err(Error::WritingSnapshots)?;
+        self.update_tree_refcounts(old_l1_table.get_values(), -1)
+            .map_err(Error::WritingSnapshots)?;
+
+        self.end_snapshot_update()
+    }
+
+    /// Deletes the snapshot with the ID, or else the name, `id_or_name`, releasing the clusters
+    /// only it used.
+    pub fn delete_snapshot(&mut self, id_or_name: &str) -> Result<()> {
+        let index = self.find_snapshot(id_or_name)?;
+
+        self.begin_snapshot_update()?;
+
+        let mut snapshots = self.snapshots.clone();
+        let snapshot = snapshots.remove(index);
+        let l1_table = self
+            .raw_file
+            .read_pointer_table(
+                snapshot.l1_table_offset,
+                u64::from(snapshot.l1_size),
+                Some(L1_TABLE_OFFSET_MASK),
+            )
+            .map_err(Error::ReadingSnapshots)?;
+        self.write_snapshots(snapshots)?;
+
+        self.update_tree_refcounts(&l1_table, -1)
+            .map_err(Error::WritingSnapshots)?;
+        let l1_clusters = div_round_up_u64(
+            u64::from(snapshot.l1_size) * size_of::<u64>() as u64,
+            self.raw_file.cluster_size(),
+        );
+        self.free_clusters(snapshot.l1_table_offset, l1_clusters)
+            .map_err(Error::WritingSnapshots)?;
+
+        self.end_snapshot_update()
+    }
+
+    // Returns the index of the snapshot with the ID `id_or_name`, or else the name.
+    fn find_snapshot(&self, id_or_name: &str) -> Result<usize> {
+        self.snapshots
+            .iter()
+            .position(|s| s.id == id_or_name)
+            .or_else(|| self.snapshots.iter().position(|s| s.name == id_or_name))
+            .ok_or_else(|| Error::SnapshotNotFound(id_or_name.to_string()))
+    }
+
+    // Gets the image ready for its clusters to be shared or released. The cached L2 tables are
+    // dropped as their COPIED flags are about to change on disk.
+    fn begin_snapshot_update(&mut self) -> Result<()> {
+        self.sync_caches().map_err(Error::WritingSnapshots)?;
+        self.l2_cache = CacheMap::new(L2_CACHE_SIZE);
+        Ok(())
+    }
+
+    // Updates the COPIED flags of the image to the refcounts left by a snapshot operation, and
+    // commits everything to disk.
+    fn end_snapshot_update(&mut self) -> Result<()> {
+        let l1_table = self.l1_table.get_values().to_vec();
+        self.update_tree_refcounts(&l1_table, 0)
+            .map_err(Error::WritingSnapshots)?;
+        self.sync_caches().map_err(Error::WritingSnapshots)?;
+        self.write_l1_table().map_err(Error::WritingSnapshots)?;
+        self.flush().map_err(Error::WritingSnapshots)
+    }
+
+    // Replaces the snapshot table of the image with one holding `snapshots`.
+    fn write_snapshots(&mut self, snapshots: Vec<QcowSnapshot>) -> Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let old_offset = self.header.snapshots_offset;
+        let old_clusters =
+            div_round_up_u64(QcowSnapshot::table_size(&self.snapshots), cluster_size);
+
+        let mut table = QcowSnapshot::write_table(&snapshots);
+        let clusters = div_round_up_u64(table.len() as u64, cluster_size);
+        let offset = self
+            .alloc_contiguous_clusters(clusters)
+            .map_err(Error::WritingSnapshots)?;
+        if !table.is_empty() {
+            table.resize((clusters * cluster_size) as usize, 0);
+            self.raw_file
+                .file_mut()
+                .seek(SeekFrom::Start(offset))
+                .map_err(Error::WritingSnapshots)?;
+            self.raw_file
+                .file_mut()
+                .write_all(&table)
+                .map_err(Error::WritingSnapshots)?;
+        }
+        // The table and the refcounts of its clusters must be on disk before the header points
+        // at it.
+        self.sync_caches().map_err(Error::WritingSnapshots)?;
+
+        self.header.nb_snapshots = snapshots.len() as u32;
+        self.header.snapshots_offset = offset;
+        self.header
+            .write_snapshot_table_location(self.raw_file.file_mut())?;
+        self.raw_file
+            .file_mut()
+            .fsync()
+            .map_err(Error::SyncingHeader)?;
+        self.snapshots = snapshots;
+
+        self.free_clusters(old_offset, old_clusters)
+            .map_err(Error::WritingSnapshots)
+    }
+
+    // Reads the snapshot table of the image described by `header`.
+    fn read_snapshots(
+        raw_file: &mut QcowRawFile,
+        header: &QcowHeader,
+    ) -> Result<Vec<QcowSnapshot>> {
+        if header.nb_snapshots > MAX_SNAPSHOTS {
+            return Err(Error::TooManySnapshots(header.nb_snapshots));
+        }
+        if header.nb_snapshots == 0 {
+            return Ok(Vec::new());
+        }
+
+        let file = raw_file.file_mut();
+        file.seek(SeekFrom::Start(header.snapshots_offset))
+            .map_err(Error::ReadingSnapshots)?;
+        let snapshots =
+            QcowSnapshot::read_table(&mut BufReader::new(file), header.nb_snapshots, header.size)
+                .map_err(Error::ReadingSnapshots)?;
+        for snapshot in &snapshots {
+            offset_is_cluster_boundary(snapshot.l1_table_offset, header.cluster_bits)?;
+            if u64::from(snapshot.l1_size) > MAX_RAM_POINTER_TABLE_SIZE {
+                return Err(Error::InvalidL1TableSize(snapshot.l1_size));
+            }
+        }
+        Ok(snapshots)
+    }
+
+    /// Returns the persistent bitmaps of the image.
+    pub fn bitmaps(&self) -> &[QcowBitmap] {
+        &self.bitmaps
+    }
+
+    /// Loads the dirty tracking bitmap named `name`, each bit covering `granularity` bytes. The
+    /// whole disk is dirty if the image has no such bitmap, or one that has another granularity
+    /// or wasn't stored after the last writes to the image.
+    pub fn load_dirty_bitmap(&mut self, name: &str, granularity: u64) -> Result<DirtyBitmap> {
+        let size = self.virtual_size();
+        let cluster_size = self.raw_file.cluster_size();
+        let stored = self
+            .bitmaps
+            .iter()
+            .find(|b| {
+                b.name == name
+                    && b.bitmap_type == BITMAP_TYPE_DIRTY_TRACKING
+                    && b.granularity() == granularity
+                    && u64::from(b.table_size)
+                        == QcowBitmap::table_entries(size, granularity, cluster_size)
+                    && !self.stale_bitmaps.contains(&b.name)
+            })
+            .cloned();
+        let Some(stored) = stored else {
+            let bitmap = DirtyBitmap::new(size, granularity);
+            bitmap.set_all();
+            return Ok(bitmap);
+        };
+
+        let table = self
+            .raw_file
+            .read_pointer_table(stored.table_offset, u64::from(stored.table_size), None)
+            .map_err(Error::ReadingBitmaps)?;
+        let mut bytes = Vec::with_capacity(table.len() * cluster_size as usize);
+        let mut cluster = vec![0u8; cluster_size as usize];
+        for entry in table {
+            let offset = entry & BITMAP_TABLE_OFFSET_MASK;
+            if offset != 0 {
+                let file = self.raw_file.file_mut();
+                file.seek(SeekFrom::Start(offset))
+                    .map_err(Error::ReadingBitmaps)?;
+                file.read_exact(&mut cluster)
+                    .map_err(Error::ReadingBitmaps)?;
+            } else if entry & BITMAP_TABLE_ENTRY_ALL_ONES != 0 {
+                cluster.fill(0xff);
+            } else {
+                cluster.fill(0);
+            }
+            bytes.extend_from_slice(&cluster);
+        }
+        Ok(DirtyBitmap::from_bytes(size, granularity, &bytes))
+    }
+
+    /// Stores `bitmap` as the dirty tracking bitmap named `name`, replacing any bitmap of that
+    /// name. The bitmap is expected to track the writes to the image until it is stored, as the
+    /// image is closed.
+    pub fn store_dirty_bitmap(&mut self, name: &str, bitmap: &DirtyBitmap) -> Result<()> {
+        if self.header.version != 3 {
+            return Err(Error::BitmapsNeedVersion3);
+        }
+        if name.len() > MAX_BITMAP_NAME_SIZE {
+            return Err(Error::BitmapNameTooLong(name.len()));
+        }
+        let index = self.bitmaps.iter().position(|b| b.name == name);
+        if index.is_none() && self.bitmaps.len() >= MAX_BITMAPS as usize {
+            return Err(Error::TooManyBitmaps(self.bitmaps.len() as u32));
+        }
+
+        // Clusters of clean chunks only are left out.
+        let cluster_size = self.raw_file.cluster_size();
+        let mut table = Vec::new();
+        for data in bitmap.to_bytes().chunks(cluster_size as usize) {
+            if data.iter().all(|&b| b == 0) {
+                table.push(0);
+                continue;
+            }
+            let offset = self
+                .alloc_contiguous_clusters(1)
+                .map_err(Error::WritingBitmaps)?;
+            let mut cluster = data.to_vec();
+            cluster.resize(cluster_size as usize, 0);
+            self.raw_file
+                .write_cluster(offset, &cluster)
+                .map_err(Error::WritingBitmaps)?;
+            table.push(offset);
+        }
+        let table_clusters =
+            div_round_up_u64(table.len() as u64 * size_of::<u64>() as u64, cluster_size);
+        let table_offset = self
+            .alloc_contiguous_clusters(table_clusters)
+            .map_err(Error::WritingBitmaps)?;
+        if !table.is_empty() {
+            self.raw_file
+                .write_pointer_table_direct(table_offset, table.iter())
+                .map_err(Error::WritingBitmaps)?;
+        }
+
+        let stored = QcowBitmap {
+            table_offset,
+            table_size: table.len() as u32,
+            flags: BITMAP_FLAG_AUTO,
+            bitmap_type: BITMAP_TYPE_DIRTY_TRACKING,
+            granularity_bits: bitmap.granularity().trailing_zeros() as u8,
+            name: name.to_string(),
+            extra_data: Vec::new(),
+        };
+        let mut bitmaps = self.bitmaps.clone();
+        let replaced = match index {
+            Some(index) => Some(std::mem::replace(&mut bitmaps[index], stored)),
+            None => {
+                bitmaps.push(stored);
+                None
+            }
+        };
+        self.write_bitmaps(bitmaps)?;
+        self.stale_bitmaps.retain(|n| n != name);
+
+        if let Some(replaced) = replaced {
+            self.free_bitmap_clusters(&replaced)
+                .map_err(Error::WritingBitmaps)?;
+        }
+        Ok(())
+    }
+
+    // Flags the bitmaps to update with the image in use, as they won't be up to date on disk until
+    // stored again.
+    fn mark_bitmaps_in_use(&mut self) -> Result<()> {
+        let Some(extension) = self.header.bitmaps else {
+            return Ok(());
+        };
+        if !self.bitmaps.iter().any(|b| b.auto() && !b.in_use()) {
+            return Ok(());
+        }
+        for bitmap in self.bitmaps.iter_mut().filter(|b| b.auto()) {
+            bitmap.flags |= BITMAP_FLAG_IN_USE;
+        }
+        // Only the flags change, the directory is updated in place.
+        let directory = QcowBitmap::write_directory(&self.bitmaps);
+        let file = self.raw_file.file_mut();
+        file.seek(SeekFrom::Start(extension.directory_offset))
+            .map_err(Error::WritingBitmaps)?;
+        file.write_all(&directory).map_err(Error::WritingBitmaps)?;
+        file.fsync().map_err(Error::WritingBitmaps)
+    }
+
+    // Replaces the bitmap directory of the image with one holding `bitmaps`.
+    fn write_bitmaps(&mut self, bitmaps: Vec<QcowBitmap>) -> Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let old_extension = self.header.bitmaps;
+
+        let mut directory = QcowBitmap::write_directory(&bitmaps);
+        let directory_size = directory.len() as u64;
+        let clusters = div_round_up_u64(directory_size, cluster_size);
+        let offset = self
+            .alloc_contiguous_clusters(clusters)
+            .map_err(Error::WritingBitmaps)?;
+        if !directory.is_empty() {
+            directory.resize((clusters * cluster_size) as usize, 0);
+            self.raw_file
+                .file_mut()
+                .seek(SeekFrom::Start(offset))
+                .map_err(Error::WritingBitmaps)?;
+            self.raw_file
+                .file_mut()
+                .write_all(&directory)
+                .map_err(Error::WritingBitmaps)?;
+        }
+        // The directory, the tables and the refcounts of their clusters must be on disk before
+        // the header points at them.
+        self.sync_caches().map_err(Error::WritingBitmaps)?;
+
+        // An image without bitmaps has no bitmaps extension.
+        let extension = (!bitmaps.is_empty()).then_some(BitmapsExtension {
+            nb_bitmaps: bitmaps.len() as u32,
+            directory_size,
+            directory_offset: offset,
+        });
+        self.write_bitmaps_extension(extension)?;
+        self.bitmaps = bitmaps;
+
+        if let Some(old_extension) = old_extension {
+            self.free_clusters(
+                old_extension.directory_offset,
+                div_round_up_u64(old_extension.directory_size, cluster_size),
+            )
+            .map_err(Error::WritingBitmaps)?;
+        }
+        Ok(())
+    }
+
+    // Rewrites the header extensions with `extension` as the bitmaps extension, keeping the other
+    // ones, followed by the backing file name. The first cluster is rewritten with a single write
+    // so the header never points at a mismatched directory.
+    fn write_bitmaps_extension(&mut self, extension: Option<BitmapsExtension>) -> Result<()> {
+        let cluster_size = self.raw_file.cluster_size() as usize;
+        let header_size = self.header.header_size as usize;
+        let mut cluster = vec![0u8; cluster_size];
+        let file = self.raw_file.file_mut();
+        file.rewind().map_err(Error::ReadingHeader)?;
+        file.read_exact(&mut cluster)
+            .map_err(Error::ReadingHeader)?;
+
+        let be_u32 =
+            |offset: usize| u32::from_be_bytes(cluster[offset..offset + 4].try_into().unwrap());
+        let mut extensions = Vec::new();
+        let mut offset = header_size;
+        loop {
+            if offset + QCOW_EMPTY_HEADER_EXTENSION_SIZE as usize > cluster_size {
+                return Err(Error::HeaderTooLarge);
+            }
+            let ext_type = be_u32(offset);
+            if ext_type == HEADER_EXT_END {
+                break;
+            }
+            let ext_end = offset
+                + QCOW_EMPTY_HEADER_EXTENSION_SIZE as usize
+                + (be_u32(offset + 4) as usize).next_multiple_of(8);
+            if ext_end > cluster_size {
+                return Err(Error::HeaderTooLarge);
+            }
+            if ext_type != HEADER_EXT_BITMAPS {
+                extensions.extend_from_slice(&cluster[offset..ext_end]);
+            }
+            offset = ext_end;
+        }
+        if let Some(extension) = extension {
+            extensions.extend_from_slice(&HEADER_EXT_BITMAPS.to_be_bytes());
+            extensions.extend_from_slice(&BITMAPS_EXTENSION_SIZE.to_be_bytes());
+            extensions.extend_from_slice(&extension.to_bytes());
+        }
+        extensions.extend_from_slice(&[0u8; QCOW_EMPTY_HEADER_EXTENSION_SIZE as usize]);
+
+        let backing_file_offset = (header_size + extensions.len()) as u64;
+        let backing_file_path = self.header.backing_file.as_ref().map(|b| b.path.as_bytes());
+        let header_end = backing_file_offset as usize + backing_file_path.map_or(0, |p| p.len());
+        if header_end > cluster_size {
+            return Err(Error::HeaderTooLarge);
+        }
+        let autoclear_features = if extension.is_some() {
+            self.header.autoclear_features | AUTOCLEAR_BITMAPS
+        } else {
+            self.header.autoclear_features & !AUTOCLEAR_BITMAPS
+        };
+
+        let mut prefix = cluster[..header_size].to_vec();
+        if backing_file_path.is_some() {
+            prefix[8..16].copy_from_slice(&backing_file_offset.to_be_bytes());
+        }
+        let autoclear_range =
+            AUTOCLEAR_FEATURES_OFFSET as usize..AUTOCLEAR_FEATURES_OFFSET as usize + 8;
+        prefix[autoclear_range].copy_from_slice(&autoclear_features.to_be_bytes());
+        prefix.extend_from_slice(&extensions);
+        if let Some(path) = backing_file_path {
+            prefix.extend_from_slice(path);
+        }
+
+        let file = self.raw_file.file_mut();
+        file.rewind().map_err(Error::WritingHeader)?;
+        file.write_all(&prefix).map_err(Error::WritingHeader)?;
+        file.fsync().map_err(Error::SyncingHeader)?;
+
+        if self.header.backing_file.is_some() {
+            self.header.backing_file_offset = backing_file_offset;
+        }
+        self.header.autoclear_features = autoclear_features;
+        self.header.bitmaps = extension;
+        Ok(())
+    }
+
+    // Drops the references of a bitmap no longer in the directory to its table and data.
+    fn free_bitmap_clusters(&mut self, bitmap: &QcowBitmap) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let table = self.raw_file.read_pointer_table(
+            bitmap.table_offset,
+            u64::from(bitmap.table_size),
+            Some(BITMAP_TABLE_OFFSET_MASK),
+        )?;
+        for offset in table.into_iter().filter(|&offset| offset != 0) {
+            self.free_clusters(offset, 1)?;
+        }
+        let table_clusters = div_round_up_u64(
+            u64::from(bitmap.table_size) * size_of::<u64>() as u64,
+            cluster_size,
+        );
+        self.free_clusters(bitmap.table_offset, table_clusters)
+    }
+
+    // Reads the bitmap directory of the image described by `header`.
+    fn read_bitmaps(raw_file: &mut QcowRawFile, header: &QcowHeader) -> Result<Vec<QcowBitmap>> {
+        let Some(extension) = header.bitmaps else {
+            return Ok(Vec::new());
+        };
+        if extension.nb_bitmaps > MAX_BITMAPS {
+            return Err(Error::TooManyBitmaps(extension.nb_bitmaps));
+        }
+        if extension.directory_size > MAX_BITMAP_DIRECTORY_SIZE {
+            return Err(Error::ReadingBitmaps(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bitmap directory too large: {}", extension.directory_size),
+            )));
+        }
+        offset_is_cluster_boundary(extension.directory_offset, header.cluster_bits)?;
+
+        let mut directory = vec![0u8; extension.directory_size as usize];
+        let file = raw_file.file_mut();
+        file.seek(SeekFrom::Start(extension.directory_offset))
+            .map_err(Error::ReadingBitmaps)?;
+        file.read_exact(&mut directory)
+            .map_err(Error::ReadingBitmaps)?;
+        let bitmaps = QcowBitmap::read_directory(&mut directory.as_slice(), extension.nb_bitmaps)
+            .map_err(Error::ReadingBitmaps)?;
+        for bitmap in &bitmaps {
+            offset_is_cluster_boundary(bitmap.table_offset, header.cluster_bits)?;
+        }
+        Ok(bitmaps)
+    }
+
     fn find_avail_clusters(&mut self) -> Result<()> {
         let cluster_size = self.raw_file.cluster_size();
 
@@ -1439,17 +2419,17 @@ impl QcowFile {
             add_ref(refcounts, cluster_size, 0, max_refcount, refcount_bits)
         }
 
-        // Add references to the L1 table clusters.
+        // Add references to the clusters of the L1 table at `l1_table_offset`.
         fn set_l1_refcounts(
             refcounts: &mut [u64],
-            header: &QcowHeader,
+            l1_table_offset: u64,
+            l1_size: u32,
             cluster_size: u64,
             max_refcount: u64,
             refcount_bits: u64,
         ) -> Result<()> {
             let entries_per_cluster = cluster_size / size_of::<u64>() as u64;
-            let l1_clusters = div_round_up_u64(u64::from(header.l1_size), entries_per_cluster);
-            let l1_table_offset = header.l1_table_offset;
+            let l1_clusters = div_round_up_u64(u64::from(l1_size), entries_per_cluster);
             for i in 0..l1_clusters {
                 add_ref(
                     refcounts,
@@ -1462,10 +2442,12 @@ impl QcowFile {
             Ok(())
         }
 
-        // Traverse the L1 and L2 tables to find all reachable data clusters.
+        // Traverse the L1 table at `l1_table_offset` and its L2 tables to find all reachable data
+        // clusters.
         fn set_data_refcounts(
             refcounts: &mut [u64],
-            header: &QcowHeader,
+            l1_table_offset: u64,
+            l1_size: u32,
             cluster_size: u64,
             raw_file: &mut QcowRawFile,
             max_refcount: u64,
@@ -1473,12 +2455,12 @@ impl QcowFile {
         ) -> Result<()> {
             let l1_table = raw_file
                 .read_pointer_table(
-                    header.l1_table_offset,
-                    u64::from(header.l1_size),
+                    l1_table_offset,
+                    u64::from(l1_size),
                     Some(L1_TABLE_OFFSET_MASK),
                 )
                 .map_err(Error::ReadingPointers)?;
-            for l1_index in 0..header.l1_size as usize {
+            for l1_index in 0..l1_size as usize {
                 let l2_addr_disk = *l1_table.get(l1_index).ok_or(Error::InvalidIndex)?;
                 if l2_addr_disk != 0 {
                     // Add a reference to the L2 table cluster itself.
@@ -1515,6 +2497,109 @@ impl QcowFile {
             Ok(())
         }
 
+        // Add references to the snapshot table clusters.
+        fn set_snapshot_table_refcounts(
+            refcounts: &mut [u64],
+            header: &QcowHeader,
+            snapshots: &[QcowSnapshot],
+            cluster_size: u64,
+            max_refcount: u64,
+            refcount_bits: u64,
+        ) -> Result<()> {
+            let table_clusters =
+                div_round_up_u64(QcowSnapshot::table_size(snapshots), cluster_size);
+            for i in 0..table_clusters {
+                add_ref(
+                    refcounts,
+                    cluster_size,
+                    header.snapshots_offset + i * cluster_size,
+                    max_refcount,
+                    refcount_bits,
+                )?;
+            }
+            Ok(())
+        }
+
+        // Add references to the clusters of the LUKS header.
+        fn set_crypto_header_refcounts(
+            refcounts: &mut [u64],
+            header: &QcowHeader,
+            cluster_size: u64,
+            max_refcount: u64,
+            refcount_bits: u64,
+        ) -> Result<()> {
+            let Some(crypto_header) = header.crypto_header else {
+                return Ok(());
+            };
+            for i in 0..div_round_up_u64(crypto_header.length, cluster_size) {
+                add_ref(
+                    refcounts,
+                    cluster_size,
+                    crypto_header.offset + i * cluster_size,
+                    max_refcount,
+                    refcount_bits,
+                )?;
+            }
+            Ok(())
+        }
+
+        // Add references to the bitmap directory clusters and to the tables and data of the
+        // bitmaps.
+        fn set_bitmap_refcounts(
+            refcounts: &mut [u64],
+            header: &QcowHeader,
+            raw_file: &mut QcowRawFile,
+            cluster_size: u64,
+            max_refcount: u64,
+            refcount_bits: u64,
+        ) -> Result<()> {
+            let Some(extension) = header.bitmaps else {
+                return Ok(());
+            };
+            let directory_clusters = div_round_up_u64(extension.directory_size, cluster_size);
+            for i in 0..directory_clusters {
+                add_ref(
+                    refcounts,
+                    cluster_size,
+                    extension.directory_offset + i * cluster_size,
+                    max_refcount,
+                    refcount_bits,
+                )?;
+            }
+            for bitmap in QcowFile::read_bitmaps(raw_file, header)? {
+                let table_clusters = div_round_up_u64(
+                    u64::from(bitmap.table_size) * size_of::<u64>() as u64,
+                    cluster_size,
+                );
+                for i in 0..table_clusters {
+                    add_ref(
+                        refcounts,
+                        cluster_size,
+                        bitmap.table_offset + i * cluster_size,
+                        max_refcount,
+                        refcount_bits,
+                    )?;
+                }
+                let table = raw_file
+                    .read_pointer_table(
+                        bitmap.table_offset,
+                        u64::from(bitmap.table_size),
+                        Some(BITMAP_TABLE_OFFSET_MASK),
+                    )
+                    .map_err(Error::ReadingBitmaps)?;
+                for data_cluster_addr in table.into_iter().filter(|&addr| addr != 0) {
+                    add_ref(
+                        refcounts,
+                        cluster_size,
+                        data_cluster_addr,
+                        max_refcount,
+                        refcount_bits,
+                    )?;
+                }
+            }
+            Ok(())
+        }
+
         // Add references to the top-level refcount table clusters.
         fn set_refcount_table_refcounts(
             refcounts: &mut [u64],
@@ -1644,9 +2729,16 @@ impl QcowFile {
         let l2_clusters = div_round_up_u64(data_clusters, pointers_per_cluster);
         let l1_clusters = div_round_up_u64(l2_clusters, pointers_per_cluster);
         let header_clusters = div_round_up_u64(size_of::<QcowHeader>() as u64, cluster_size);
-        let max_clusters = data_clusters + l2_clusters + l1_clusters + header_clusters;
+        // Clusters kept for snapshots can make the file larger than the disk.
+        let max_clusters = max(
+            data_clusters + l2_clusters + l1_clusters + header_clusters,
+            div_round_up_u64(file_size, cluster_size),
+        );
         let mut max_valid_cluster_index = max_clusters;
         let refblock_clusters = div_round_up_u64(max_valid_cluster_index, refcount_block_entries);
+        if refblock_clusters > u64::from(header.refcount_table_clusters) * pointers_per_cluster {
+            return Err(Error::NotEnoughSpaceForRefcounts);
+        }
         let reftable_clusters = div_round_up_u64(refblock_clusters, pointers_per_cluster);
         // Account for refblocks and the ref table size needed to address them.
         let refblocks_for_refs = div_round_up_u64(
@@ -1668,21 +2760,66 @@ impl QcowFile {
         }
 
         let mut refcounts = vec![0; max_valid_cluster_index as usize];
+        let snapshots = QcowFile::read_snapshots(raw_file, &header)?;
 
         // Find all references clusters and rebuild refcounts.
         set_header_refcount(&mut refcounts, cluster_size, max_refcount, refcount_bits)?;
         set_l1_refcounts(
             &mut refcounts,
-            &header,
+            header.l1_table_offset,
+            header.l1_size,
             cluster_size,
             max_refcount,
             refcount_bits,
         )?;
         set_data_refcounts(
+            &mut refcounts,
+            header.l1_table_offset,
+            header.l1_size,
+            cluster_size,
+            raw_file,
+            max_refcount,
+            refcount_bits,
+        )?;
+        for snapshot in &snapshots {
+            set_l1_refcounts(
+                &mut refcounts,
+                snapshot.l1_table_offset,
+                snapshot.l1_size,
+                cluster_size,
+                max_refcount,
+                refcount_bits,
+            )?;
+            set_data_refcounts(
+                &mut refcounts,
+                snapshot.l1_table_offset,
+                snapshot.l1_size,
+                cluster_size,
+                raw_file,
+                max_refcount,
+                refcount_bits,
+            )?;
+        }
+        set_snapshot_table_refcounts(
+            &mut refcounts,
+            &header,
+            &snapshots,
+            cluster_size,
+            max_refcount,
+            refcount_bits,
+        )?;
+        set_crypto_header_refcounts(
             &mut refcounts,
             &header,
             cluster_size,
+            max_refcount,
+            refcount_bits,
+        )?;
+        set_bitmap_refcounts(
+            &mut refcounts,
+            &header,
             raw_file,
+            cluster_size,
             max_refcount,
             refcount_bits,
         )?;
@@ -1764,6 +2901,11 @@ impl QcowFile {
 
     // Decompress the cluster, return EIO on failure
     fn decompress_l2_cluster(&mut self, l2_entry: u64) -> std::io::Result<Vec<u8>> {
+        // Compressed clusters are never encrypted, QEMU not writing any in encrypted images.
+        if self.is_encrypted() {
+            self.set_corrupt_bit_best_effort();
+            return Err(io::Error::from_raw_os_error(EIO));
+        }
         let (compressed_cluster_addr, compressed_cluster_size) =
             l2_entry_compressed_cluster_layout(l2_entry, self.header.cluster_bits);
         // Read compressed cluster from raw file
@@ -1802,6 +2944,38 @@ impl QcowFile {
             return Err(err_inval);
         }
 
+        let l2_entry = self.l2_entry(address)?;
+        if l2_entry_is_empty(l2_entry) {
+            // Reading from an unallocated cluster will return zeros.
+            return Ok(None);
+        } else if l2_entry_is_compressed(l2_entry) {
+            // Compressed cluster.
+            // Read it, decompress, then return slice from decompressed data.
+            let mut decompressed_cluster = self.decompress_l2_cluster(l2_entry)?;
+            decompressed_cluster.resize(self.raw_file.cluster_size() as usize, 0);
+            let start = self.raw_file.cluster_offset(address) as usize;
+            let end = start.checked_add(count);
+            if end.is_none() || end.unwrap() > decompressed_cluster.len() {
+                return Err(err_inval);
+            }
+            buf[..count].copy_from_slice(&decompressed_cluster[start..end.unwrap()]);
+        } else if l2_entry_is_zero(l2_entry) {
+            // Cluster with zero flag reads as zeros without accessing disk.
+            return Ok(None);
+        } else {
+            let cluster_addr = l2_entry_std_cluster_addr(l2_entry);
+            if cluster_addr & (self.raw_file.cluster_size() - 1) != 0 {
+                self.set_corrupt_bit_best_effort();
+                return Err(io::Error::from_raw_os_error(EIO));
+            }
+            let start = cluster_addr + self.raw_file.cluster_offset(address);
+            self.read_data(start, buf)?;
+        }
+        Ok(Some(()))
+    }
+
+    // Gets the L2 entry of the given guest address, empty when its L2 table isn't allocated.
+    fn l2_entry(&mut self, address: u64) -> std::io::Result<u64> {
         let l1_index = self.l1_table_index(address) as usize;
         let l2_addr_disk = *self
             .l1_table
@@ -1809,52 +2983,157 @@ impl QcowFile {
             .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
 
         if l2_addr_disk == 0 {
-            // Reading from an unallocated cluster will return zeros.
-            return Ok(None);
+            return Ok(0);
         }
 
         let l2_index = self.l2_table_index(address) as usize;
 
         self.cache_l2_cluster(l1_index, l2_addr_disk, false)?;
 
-        let l2_entry = self.l2_cache.get(l1_index).unwrap()[l2_index];
-        if l2_entry_is_empty(l2_entry) {
-            // Reading from an unallocated cluster will return zeros.
-            return Ok(None);
-        } else if l2_entry_is_compressed(l2_entry) {
-            // Compressed cluster.
-            // Read it, decompress, then return slice from decompressed data.
-            let mut decompressed_cluster = self.decompress_l2_cluster(l2_entry)?;
-            decompressed_cluster.resize(self.raw_file.cluster_size() as usize, 0);
-            let start = self.raw_file.cluster_offset(address) as usize;
-            let end = start.checked_add(count);
-            if end.is_none() || end.unwrap() > decompressed_cluster.len() {
-                return Err(err_inval);
+        Ok(self.l2_cache.get(l1_index).unwrap()[l2_index])
+    }
+
+    /// Maps `count` bytes of guest data at `address` to where they are read from, in this file
+    /// or in its backing files, and appends the mappings to `mappings`.
+    ///
+    /// Only compressed clusters are read, to be decompressed. The range is limited to the
+    /// virtual size of the file, beyond which it reads as zeros. Encrypted files can't be
+    /// mapped, their data having to be decrypted.
+    pub fn map_read(
+        &mut self,
+        address: u64,
+        count: usize,
+        mappings: &mut Vec<ReadMapping>,
+    ) -> std::io::Result<()> {
+        if self.is_encrypted() {
+            return Err(io::Error::from_raw_os_error(EINVAL));
+        }
+        let read_count = self.limit_range_file(address, count);
+
+        let mut nread: usize = 0;
+        while nread < read_count {
+            let curr_addr = address + nread as u64;
+            let count = self.limit_range_cluster(curr_addr, read_count - nread);
+
+            let l2_entry = self.l2_entry(curr_addr)?;
+            if l2_entry_is_compressed(l2_entry) {
+                let decompressed_cluster = self.decompress_l2_cluster(l2_entry)?;
+                let start = self.raw_file.cluster_offset(curr_addr) as usize;
+                mappings.push(ReadMapping::Data(
+                    decompressed_cluster[start..start + count].to_vec(),
+                ));
+            } else if l2_entry_is_empty(l2_entry) || l2_entry_is_zero(l2_entry) {
+                self.map_backing_read(curr_addr, count, mappings)?;
+            } else {
+                let cluster_addr = l2_entry_std_cluster_addr(l2_entry);
+                if cluster_addr & (self.raw_file.cluster_size() - 1) != 0 {
+                    self.set_corrupt_bit_best_effort();
+                    return Err(io::Error::from_raw_os_error(EIO));
+                }
+                push_read_mapping(
+                    mappings,
+                    ReadMapping::Host {
+                        fd: self.raw_file.as_raw_fd(),
+                        offset: cluster_addr + self.raw_file.cluster_offset(curr_addr),
+                        len: count,
+                    },
+                );
+            }
+
+            nread += count;
+        }
+
+        if read_count < count {
+            push_read_mapping(
+                mappings,
+                ReadMapping::Zero {
+                    len: count - read_count,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Maps `count` bytes of the backing file at `address` like [`Self::map_read`], reading as
+    /// zeros when there is no backing file.
+    pub fn map_backing_read(
+        &mut self,
+        address: u64,
+        count: usize,
+        mappings: &mut Vec<ReadMapping>,
+    ) -> std::io::Result<()> {
+        match self.backing_file.as_mut() {
+            Some(backing) => backing.map_read(address, count, mappings),
+            None => {
+                push_read_mapping(mappings, ReadMapping::Zero { len: count });
+                Ok(())
             }
-            buf[..count].copy_from_slice(&decompressed_cluster[start..end.unwrap()]);
-        } else if l2_entry_is_zero(l2_entry) {
-            // Cluster with zero flag reads as zeros without accessing disk.
-            return Ok(None);
-        } else {
-            let cluster_addr = l2_entry_std_cluster_addr(l2_entry);
-            if cluster_addr & (self.raw_file.cluster_size() - 1) != 0 {
-                self.set_corrupt_bit_best_effort();
-                return Err(io::Error::from_raw_os_error(EIO));
+        }
+    }
+
+    /// Maps the guest address `address` to where it is written in this file, allocating the L2
+    /// table and the data cluster if needed.
+    ///
+    /// Unlike writes through [`Write`], a newly allocated cluster isn't filled from the backing
+    /// file. [`WriteMapping::copy_backing`] tells the caller to copy the part of the cluster it
+    /// doesn't write itself.
+    pub fn map_write(&mut self, address: u64) -> std::io::Result<WriteMapping> {
+        if self.is_encrypted() {
+            return Err(io::Error::from_raw_os_error(EINVAL));
+        }
+        let (offset, allocated) = self.file_offset_alloc(address, false)?;
+        Ok(WriteMapping {
+            offset,
+            allocated,
+            copy_backing: allocated && self.backing_file.is_some(),
+        })
+    }
+
+    /// Calls `f` with the guest address of each cluster allocated in this file, as opposed to
+    /// the ones read from the backing file. Zero and compressed clusters count as allocated.
+    pub fn for_each_allocated_cluster(&mut self, mut f: impl FnMut(u64)) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let size = self.virtual_size();
+        for l1_index in 0..self.l1_table.len() {
+            let l2_addr_disk = self.l1_table[l1_index];
+            if l2_addr_disk == 0 {
+                continue;
+            }
+            self.cache_l2_cluster(l1_index, l2_addr_disk, false)?;
+            let l2_table = self.l2_cache.get(l1_index).unwrap();
+            for (l2_index, &l2_entry) in l2_table.iter().enumerate() {
+                let address = (l1_index as u64 * self.l2_entries + l2_index as u64) * cluster_size;
+                if address >= size {
+                    return Ok(());
+                }
+                if !l2_entry_is_empty(l2_entry) {
+                    f(address);
+                }
             }
-            let start = cluster_addr + self.raw_file.cluster_offset(address);
-            let raw_file = self.raw_file.file_mut();
-            raw_file.seek(SeekFrom::Start(start))?;
-            raw_file.read_exact(buf)?;
         }
-        Ok(Some(()))
+        Ok(())
     }
 
     // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters need
     // to be allocated, they will be.
     fn file_offset_write(&mut self, address: u64) -> std::io::Result<u64> {
+        self.file_offset_alloc(address, true)
+            .map(|(offset, _allocated)| offset)
+    }
+
+    // Gets the offset of the given guest address in the host file, allocating L1, L2, or data
+    // clusters if needed, and whether the data cluster was allocated. A new data cluster is only
+    // filled from the backing file if `copy_backing` is set.
+    fn file_offset_alloc(
+        &mut self,
+        address: u64,
+        copy_backing: bool,
+    ) -> std::io::Result<(u64, bool)> {
         if address >= self.virtual_size() {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
+        // Fail before allocating anything if the data can't be written.
+        self.crypto()?;
 
         let l1_index = self.l1_table_index(address) as usize;
         let l2_addr_disk = *self
@@ -1871,29 +3150,22 @@ impl QcowFile {
         }
 
         let l2_entry = self.l2_cache.get(l1_index).unwrap()[l2_index];
+        let mut allocated = false;
         let cluster_addr = if l2_entry_is_compressed(l2_entry) {
             // Writing to compressed cluster.
 
             // Allocate new cluster, decompress into new cluster, then use
             // offset of new cluster.
             let decompressed_cluster = self.decompress_l2_cluster(l2_entry)?;
-            let cluster_addr = self.append_data_cluster(None)?;
+            let cluster_addr = self.append_data_cluster(Some(decompressed_cluster))?;
             self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
-            self.raw_file
-                .file_mut()
-                .seek(SeekFrom::Start(cluster_addr))?;
-            let nwritten = self.raw_file.file_mut().write(&decompressed_cluster)?;
-            if nwritten != decompressed_cluster.len() {
-                self.set_corrupt_bit_best_effort();
-                return Err(std::io::Error::from_raw_os_error(EIO));
-            }
 
             // Decrement refcount for each cluster spanned by the old compressed data
             self.deallocate_compressed_cluster(l2_entry)?;
 
             cluster_addr
         } else if l2_entry_is_empty(l2_entry) || l2_entry_is_zero(l2_entry) {
-            let initial_data = if let Some(backing) = self.backing_file.as_mut() {
+            let initial_data = if copy_backing && let Some(backing) = self.backing_file.as_mut() {
                 let cluster_size = self.raw_file.cluster_size();
                 let cluster_begin = address - (address % cluster_size);
                 let mut cluster_data = vec![0u8; cluster_size as usize];
@@ -1905,21 +3177,36 @@ impl QcowFile {
             // Need to allocate a data cluster
             let cluster_addr = self.append_data_cluster(initial_data)?;
             self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+            allocated = true;
             cluster_addr
         } else {
             let cluster_addr = l2_entry_std_cluster_addr(l2_entry);
-            if cluster_addr & (self.raw_file.cluster_size() - 1) != 0 {
+            let cluster_size = self.raw_file.cluster_size();
+            if cluster_addr & (cluster_size - 1) != 0 {
                 self.set_corrupt_bit_best_effort();
                 return Err(io::Error::from_raw_os_error(EIO));
             }
-            cluster_addr
+            if l2_entry & CLUSTER_USED_FLAG == 0 && self.cluster_refcount(cluster_addr)? > 1 {
+                // The cluster is shared with a snapshot, write to a copy of it.
+                let mut cluster_data = vec![0u8; cluster_size as usize];
+                self.read_data(cluster_addr, &mut cluster_data)?;
+                let new_addr = self.append_data_cluster(Some(cluster_data))?;
+                self.update_cluster_addr(l1_index, l2_index, new_addr, &mut set_refcounts)?;
+                self.update_cluster_refcount(cluster_addr, -1)?;
+                new_addr
+            } else {
+                cluster_addr
+            }
         };
 
         for (addr, count) in set_refcounts {
             self.set_cluster_refcount_track_freed(addr, count)?;
         }
 
-        Ok(cluster_addr + self.raw_file.cluster_offset(address))
+        Ok((
+            cluster_addr + self.raw_file.cluster_offset(address),
+            allocated,
+        ))
     }
 
     // Updates the l1 and l2 tables to point to the new `cluster_addr`.
@@ -1929,6 +3216,19 @@ impl QcowFile {
         l2_index: usize,
         cluster_addr: u64,
         set_refcounts: &mut Vec<(u64, u64)>,
+    ) -> io::Result<()> {
+        self.relocate_l2_table(l1_index, set_refcounts)?;
+        // 'unwrap' is OK because it was just added.
+        self.l2_cache.get_mut(l1_index).unwrap()[l2_index] = l2_entry_make_std(cluster_addr);
+        Ok(())
+    }
+
+    // Moves the cached L2 table at `l1_index` to a new cluster, unless it was already moved since
+    // it was last written.
+    fn relocate_l2_table(
+        &mut self,
+        l1_index: usize,
+        set_refcounts: &mut Vec<(u64, u64)>,
     ) -> io::Result<()> {
         if !self.l2_cache.get(l1_index).unwrap().dirty() {
             // Free the previously used cluster if one exists. Modified tables are always
@@ -1937,8 +3237,14 @@ impl QcowFile {
             // The index must be valid from when it was inserted.
             let addr = self.l1_table[l1_index];
             if addr != 0 {
-                self.unref_clusters.push(addr);
-                set_refcounts.push((addr, 0));
+                // Snapshots sharing the table keep it.
+                let refcount = self.cluster_refcount(addr)?;
+                if refcount > 1 {
+                    set_refcounts.push((addr, refcount - 1));
+                } else {
+                    self.unref_clusters.push(addr);
+                    set_refcounts.push((addr, 0));
+                }
             }
 
             // Allocate a new cluster to store the L2 table and update the L1 table to point
@@ -1950,8 +3256,6 @@ impl QcowFile {
             set_refcounts.push((new_addr, 1));
             self.l1_table[l1_index] = new_addr;
         }
-        // 'unwrap' is OK because it was just added.
-        self.l2_cache.get_mut(l1_index).unwrap()[l2_index] = l2_entry_make_std(cluster_addr);
         Ok(())
     }
 
@@ -1990,6 +3294,15 @@ impl QcowFile {
     // Allocate and initialize a new data cluster. Returns the offset of the
     // cluster into the file on success.
     fn append_data_cluster(&mut self, initial_data: Option<Vec<u8>>) -> std::io::Result<u64> {
+        if self.crypto()?.is_some() {
+            // The data is encrypted once its location is known, zeros included.
+            let cluster_size = self.raw_file.cluster_size() as usize;
+            let data = initial_data.unwrap_or_else(|| vec![0u8; cluster_size]);
+            let new_addr: u64 = self.get_new_cluster(None)?;
+            self.set_cluster_refcount_track_freed(new_addr, 1)?;
+            self.write_data(new_addr, &data)?;
+            return Ok(new_addr);
+        }
         let new_addr: u64 = self.get_new_cluster(initial_data)?;
         // The cluster refcount starts at one indicating it is used but doesn't need COW.
         self.set_cluster_refcount_track_freed(new_addr, 1)?;
@@ -2070,18 +3383,7 @@ impl QcowFile {
         // Decrement refcount for each cluster spanned by the compressed data
         let mut addr = self.raw_file.cluster_address(compressed_cluster_addr);
         while addr < compressed_clusters_end {
-            let refcount = self
-                .refcounts
-                .get_cluster_refcount(&mut self.raw_file, addr)
-                .map_err(|e| {
-                    if matches!(e, refcount::Error::RefblockUnaligned(_)) {
-                        self.set_corrupt_bit_best_effort();
-                    }
-                    io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("failed to get cluster refcount: {e}"),
-                    )
-                })?;
+            let refcount = self.cluster_refcount(addr)?;
             if refcount > 0 {
                 self.set_cluster_refcount_track_freed(addr, refcount - 1)?;
             }
@@ -2119,6 +3421,15 @@ impl QcowFile {
             return Ok(());
         }
 
+        // A table shared with snapshots can't be modified in place.
+        if self.cluster_refcount(l2_addr_disk)? > 1 {
+            let mut set_refcounts = Vec::new();
+            self.relocate_l2_table(l1_index, &mut set_refcounts)?;
+            for (addr, refcount) in set_refcounts {
+                self.set_cluster_refcount_track_freed(addr, refcount)?;
+            }
+        }
+
         // Compressed clusters cannot use the zero flag optimization, thus fully deallocate instead.
         if l2_entry_is_compressed(l2_entry) {
             self.deallocate_compressed_cluster(l2_entry)?;
@@ -2129,18 +3440,7 @@ impl QcowFile {
         let cluster_addr = l2_entry_std_cluster_addr(l2_entry);
 
         // Decrement the refcount.
-        let refcount = self
-            .refcounts
-            .get_cluster_refcount(&mut self.raw_file, cluster_addr)
-            .map_err(|e| {
-                if matches!(e, refcount::Error::RefblockUnaligned(_)) {
-                    self.set_corrupt_bit_best_effort();
-                }
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("failed to get cluster refcount: {e}"),
-                )
-            })?;
+        let refcount = self.cluster_refcount(cluster_addr)?;
         if refcount == 0 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -2200,7 +3500,11 @@ impl QcowFile {
                 // unallocated clusters already read back as zeroes.
                 let offset = self.file_offset_write(curr_addr)?;
                 // Partial cluster - zero it out.
-                self.raw_file.file_mut().write_zeroes_at(offset, count)?;
+                if self.crypto()?.is_some() {
+                    self.write_data(offset, &vec![0u8; count])?;
+                } else {
+                    self.raw_file.file_mut().write_zeroes_at(offset, count)?;
+                }
             }
 
             nwritten += count;
@@ -2329,7 +3633,153 @@ impl QcowFile {
         Ok(unref_clusters)
     }
 
-    fn sync_caches(&mut self) -> std::io::Result<()> {
+    // Gets the refcount of the cluster at `address`.
+    fn cluster_refcount(&mut self, address: u64) -> std::io::Result<u64> {
+        self.refcounts
+            .get_cluster_refcount(&mut self.raw_file, address)
+            .map_err(|e| {
+                if matches!(e, refcount::Error::RefblockUnaligned(_)) {
+                    self.set_corrupt_bit_best_effort();
+                }
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("failed to get cluster refcount: {e}"),
+                )
+            })
+    }
+
+    // Adds `delta` to the refcount of the cluster at `address` and returns the new refcount.
+    // Clusters no longer referenced are added to the unref list.
+    fn update_cluster_refcount(&mut self, address: u64, delta: i64) -> std::io::Result<u64> {
+        let refcount = self.cluster_refcount(address)?;
+        if delta == 0 {
+            return Ok(refcount);
+        }
+        let refcount = refcount
+            .checked_add_signed(delta)
+            .ok_or_else(|| io::Error::from_raw_os_error(EINVAL))?;
+        self.set_cluster_refcount_track_freed(address, refcount)?;
+        if refcount == 0 {
+            self.unref_clusters.push(address);
+        }
+        Ok(refcount)
+    }
+
+    // Drops the refcounts of `count` clusters starting at `address` to zero.
+    fn free_clusters(&mut self, address: u64, count: u64) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        for i in 0..count {
+            self.set_cluster_refcount_track_freed(address + i * cluster_size, 0)?;
+            self.unref_clusters.push(address + i * cluster_size);
+        }
+        Ok(())
+    }
+
+    // Allocates `count` contiguous clusters at the end of the file and returns the offset of the
+    // first one.
+    fn alloc_contiguous_clusters(&mut self, count: u64) -> std::io::Result<u64> {
+        if count == 0 {
+            return Ok(0);
+        }
+        let cluster_size = self.raw_file.cluster_size();
+        let file_end = self.raw_file.file_mut().seek(SeekFrom::End(0))?;
+        let offset = self.raw_file.cluster_address(file_end + cluster_size - 1);
+        let last_cluster = offset + (count - 1) * cluster_size;
+        if last_cluster > self.refcounts.max_valid_cluster_offset() {
+            error!("No free clusters in alloc_contiguous_clusters()");
+            return Err(std::io::Error::from_raw_os_error(ENOSPC));
+        }
+        self.raw_file
+            .file_mut()
+            .set_len(last_cluster + cluster_size)?;
+        for i in 0..count {
+            self.set_cluster_refcount_track_freed(offset + i * cluster_size, 1)?;
+        }
+        Ok(offset)
+    }
+
+    // Adds `delta` to the refcounts of the L2 tables and data clusters reachable from `l1_table`,
+    // as a snapshot or the image takes or drops its references to them. The COPIED flags of the
+    // L2 entries are updated to match, which is all a `delta` of zero does.
+    //
+    // The L2 tables are read and written directly, the L2 cache must not hold any of them.
+    fn update_tree_refcounts(&mut self, l1_table: &[u64], delta: i64) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        for &l2_addr in l1_table.iter().filter(|&&addr| addr != 0) {
+            if l2_addr & (cluster_size - 1) != 0 {
+                self.set_corrupt_bit_best_effort();
+                return Err(io::Error::from_raw_os_error(EIO));
+            }
+            let mut l2_table = self.raw_file.read_pointer_cluster(l2_addr, None)?;
+            let mut l2_changed = false;
+            for l2_entry in l2_table.iter_mut().filter(|e| !l2_entry_is_empty(**e)) {
+                if l2_entry_is_compressed(*l2_entry) {
+                    // Compressed clusters are never written in place, they have no COPIED flag.
+                    if delta != 0 {
+                        let (addr, size) =
+                            l2_entry_compressed_cluster_layout(*l2_entry, self.header.cluster_bits);
+                        let mut cluster_addr = self.raw_file.cluster_address(addr);
+                        while cluster_addr < addr + size as u64 {
+                            self.update_cluster_refcount(cluster_addr, delta)?;
+                            cluster_addr += cluster_size;
+                        }
+                    }
+                    continue;
+                }
+
+                let cluster_addr = l2_entry_std_cluster_addr(*l2_entry);
+                if cluster_addr == 0 {
+                    continue;
+                }
+                let refcount = self.update_cluster_refcount(cluster_addr, delta)?;
+                let new_entry = if refcount == 1 {
+                    *l2_entry | CLUSTER_USED_FLAG
+                } else {
+                    *l2_entry & !CLUSTER_USED_FLAG
+                };
+                l2_changed |= new_entry != *l2_entry;
+                *l2_entry = new_entry;
+            }
+
+            if self.update_cluster_refcount(l2_addr, delta)? > 0 && l2_changed {
+                self.raw_file
+                    .write_pointer_table_direct(l2_addr, l2_table.iter())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the entries of the L1 table as stored in the file, with the COPIED flags of the L2
+    // tables it points to.
+    fn l1_table_entries(&mut self) -> std::io::Result<Vec<u64>> {
+        let mut entries = Vec::with_capacity(self.l1_table.len());
+        for &l2_addr in self.l1_table.iter() {
+            if l2_addr == 0 {
+                entries.push(0);
+            } else {
+                let refcount = self
+                    .refcounts
+                    .get_cluster_refcount(&mut self.raw_file, l2_addr)
+                    .map_err(|e| std::io::Error::other(Error::GettingRefcount(e)))?;
+                entries.push(l1_entry_make(l2_addr, refcount == 1));
+            }
+        }
+        Ok(entries)
+    }
+
+    // Writes the L1 table with the COPIED flags of the L2 tables it points to.
+    fn write_l1_table(&mut self) -> std::io::Result<()> {
+        let entries = self.l1_table_entries()?;
+        self.raw_file
+            .write_pointer_table_direct(self.header.l1_table_offset, entries.iter())?;
+        self.l1_table.mark_clean();
+        self.tables_written = self.tables_written.wrapping_add(1);
+        Ok(())
+    }
+
+    // Writes out the dirty L2 tables and refcount blocks, the clusters the L1 and refcount tables
+    // point to.
+    fn write_back_clusters(&mut self) -> std::io::Result<()> {
         // Write out all dirty L2 tables.
         for (l1_index, l2_table) in self.l2_cache.iter_mut().filter(|(_k, v)| v.dirty()) {
             // The index must be valid from when we inserted it.
@@ -2344,41 +3794,103 @@ impl QcowFile {
             l2_table.mark_clean();
         }
         // Write the modified refcount blocks.
-        self.refcounts.flush_blocks(&mut self.raw_file)?;
+        self.refcounts.flush_blocks(&mut self.raw_file)
+    }
+
+    fn sync_caches(&mut self) -> std::io::Result<()> {
+        self.write_back_clusters()?;
         // Make sure metadata(file len) and all data clusters are written.
         self.raw_file.file_mut().sync_all()?;
 
         // Push L1 table and refcount table last as all the clusters they point to are now
         // guaranteed to be valid.
         let mut sync_required = if self.l1_table.dirty() {
-            // Write L1 table with OFLAG_COPIED bits
-            let refcounts = &mut self.refcounts;
-            self.raw_file.write_pointer_table(
-                self.header.l1_table_offset,
-                self.l1_table.iter(),
-                |raw_file, l2_addr| {
-                    if l2_addr == 0 {
-                        Ok(0)
-                    } else {
-                        let refcount = refcounts
-                            .get_cluster_refcount(raw_file, l2_addr)
-                            .map_err(|e| std::io::Error::other(Error::GettingRefcount(e)))?;
-                        Ok(l1_entry_make(l2_addr, refcount == 1))
-                    }
-                },
-            )?;
-            self.l1_table.mark_clean();
+            self.write_l1_table()?;
             true
         } else {
             false
         };
-        sync_required |= self.refcounts.flush_table(&mut self.raw_file)?;
+        if self.refcounts.flush_table(&mut self.raw_file)? {
+            self.tables_written = self.tables_written.wrapping_add(1);
+            sync_required = true;
+        }
         if sync_required {
             self.raw_file.file_mut().sync_data()?;
         }
 
         Ok(())
     }
+
+    /// Writes the dirty metadata back to the file like [`Write::flush`], without syncing it.
+    ///
+    /// Only the clusters the L1 and refcount tables point to are written, the tables being
+    /// written by [`Self::write_back_tables`] once the file is synced. The clusters freed so far
+    /// are reused after [`Self::complete_write_back`].
+    pub fn write_back_metadata(&mut self) -> std::io::Result<MetadataWriteBack> {
+        self.write_back_clusters()?;
+        let l1_table = if self.l1_table.dirty() {
+            Some((
+                self.header.l1_table_offset,
+                self.l1_table.to_vec(),
+                self.l1_table_entries()?,
+            ))
+        } else {
+            None
+        };
+        Ok(MetadataWriteBack {
+            l1_table,
+            refcount_table: self.refcounts.dirty_table(),
+            freed_clusters: std::mem::take(&mut self.unref_clusters),
+            tables_written: self.tables_written,
+            tables_pending_sync: false,
+        })
+    }
+
+    /// Writes the L1 and refcount tables of `write_back` once the clusters they point to are
+    /// synced, unless newer tables were written since. Returns whether the file has to be synced
+    /// again.
+    pub fn write_back_tables(
+        &mut self,
+        write_back: &mut MetadataWriteBack,
+    ) -> std::io::Result<bool> {
+        let l1_table = write_back.l1_table.take();
+        let refcount_table = write_back.refcount_table.take();
+        if write_back.tables_written != self.tables_written {
+            return Ok(false);
+        }
+
+        if let Some((offset, table, entries)) = l1_table {
+            self.raw_file
+                .write_pointer_table_direct(offset, entries.iter())?;
+            if offset == self.header.l1_table_offset && *self.l1_table == *table {
+                self.l1_table.mark_clean();
+            }
+            write_back.tables_pending_sync = true;
+        }
+        if let Some(table) = refcount_table {
+            self.refcounts.write_table(&mut self.raw_file, &table)?;
+            write_back.tables_pending_sync = true;
+        }
+        if write_back.tables_pending_sync {
+            self.tables_written = self.tables_written.wrapping_add(1);
+        }
+        Ok(write_back.tables_pending_sync)
+    }
+
+    /// Completes `write_back`, `synced` telling if the file was synced after its tables were
+    /// written. The clusters freed before it can be reused once it is synced, otherwise the
+    /// tables it wrote are written again by the next flush.
+    pub fn complete_write_back(&mut self, mut write_back: MetadataWriteBack, synced: bool) {
+        if synced {
+            self.avail_clusters.append(&mut write_back.freed_clusters);
+        } else {
+            self.unref_clusters.append(&mut write_back.freed_clusters);
+            if write_back.tables_pending_sync {
+                self.l1_table.mark_dirty();
+                self.refcounts.mark_table_dirty();
+            }
+        }
+    }
 }
 
 impl AsRawFd for QcowFile {
@@ -2465,11 +3977,7 @@ impl Write for QcowFile {
             let offset = self.file_offset_write(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, write_count - nwritten);
 
-            self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
-            let count = self
-                .raw_file
-                .file_mut()
-                .write(&buf[nwritten..(nwritten + count)])?;
+            self.write_data(offset, &buf[nwritten..(nwritten + count)])?;
 
             nwritten += count;
         }
@@ -2715,6 +4223,7 @@ mod unit_tests {
     use vmm_sys_util::write_zeroes::WriteZeroes;
 
     use super::*;
+    use crate::luks::LuksKdf;
 
     fn valid_header_v3() -> Vec<u8> {
         vec![
@@ -4926,4 +6435,399 @@ mod unit_tests {
             assert_eq!(qcow.header.version, 2);
         });
     }
+
+    fn read_at(qcow: &mut QcowFile, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        qcow.seek(SeekFrom::Start(offset)).unwrap();
+        qcow.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn write_at(qcow: &mut QcowFile, offset: u64, data: &[u8]) {
+        qcow.seek(SeekFrom::Start(offset)).unwrap();
+        qcow.write_all(data).unwrap();
+    }
+
+    #[test]
+    fn snapshot_create_apply_delete() {
+        with_default_file(0x10_0000, false, |mut qcow| {
+            write_at(&mut qcow, 0, b"before");
+            qcow.create_snapshot("first", 1_000).unwrap();
+            assert_eq!(qcow.snapshots().len(), 1);
+            assert_eq!(qcow.snapshots()[0].id, "1");
+            assert_eq!(qcow.snapshots()[0].name, "first");
+            assert_eq!(qcow.snapshots()[0].vm_clock_nsec, 1_000);
+            assert_eq!(qcow.snapshots()[0].disk_size, 0x10_0000);
+
+            write_at(&mut qcow, 0, b"after!");
+            write_at(&mut qcow, 0x2_0000, b"new");
+            assert_eq!(read_at(&mut qcow, 0, 6), b"after!");
+
+            qcow.apply_snapshot("first").unwrap();
+            assert_eq!(read_at(&mut qcow, 0, 6), b"before");
+            assert_eq!(read_at(&mut qcow, 0x2_0000, 3), [0u8; 3]);
+
+            // Writing after reverting leaves the snapshot untouched.
+            write_at(&mut qcow, 0, b"again!");
+            qcow.apply_snapshot("1").unwrap();
+            assert_eq!(read_at(&mut qcow, 0, 6), b"before");
+
+            qcow.delete_snapshot("first").unwrap();
+            assert!(qcow.snapshots().is_empty());
+            assert_eq!(read_at(&mut qcow, 0, 6), b"before");
+        });
+    }
+
+    #[test]
+    fn snapshot_errors() {
+        with_default_file(0x10_0000, false, |mut qcow| {
+            qcow.create_snapshot("first", 0).unwrap();
+            assert!(matches!(
+                qcow.create_snapshot("first", 0),
+                Err(Error::SnapshotExists(_))
+            ));
+            assert!(matches!(
+                qcow.apply_snapshot("second"),
+                Err(Error::SnapshotNotFound(_))
+            ));
+            assert!(matches!(
+                qcow.delete_snapshot("2"),
+                Err(Error::SnapshotNotFound(_))
+            ));
+
+            qcow.resize(0x20_0000).unwrap();
+            assert!(matches!(
+                qcow.apply_snapshot("first"),
+                Err(Error::SnapshotSizeMismatch(0x10_0000))
+            ));
+        });
+    }
+
+    #[test]
+    fn snapshot_persists_across_reopen() {
+        let file = TempFile::new().unwrap().into_file();
+        {
+            let raw = RawFile::new(file.try_clone().unwrap(), false);
+            let mut qcow = QcowFile::new(raw, 3, 0x10_0000, true).unwrap();
+            write_at(&mut qcow, 0x1_0000, b"snapshot data");
+            qcow.create_snapshot("first", 0).unwrap();
+            qcow.create_snapshot("second", 0).unwrap();
+            write_at(&mut qcow, 0x1_0000, b"current data!");
+        }
+
+        let raw = RawFile::new(file.try_clone().unwrap(), false);
+        let mut qcow = QcowFile::from(raw).unwrap();
+        let names: Vec<&str> = qcow.snapshots().iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["first", "second"]);
+        assert_eq!(read_at(&mut qcow, 0x1_0000, 13), b"current data!");
+
+        qcow.delete_snapshot("first").unwrap();
+        qcow.apply_snapshot("second").unwrap();
+        assert_eq!(read_at(&mut qcow, 0x1_0000, 13), b"snapshot data");
+        drop(qcow);
+
+        let raw = RawFile::new(file, false);
+        let mut qcow = QcowFile::from(raw).unwrap();
+        assert_eq!(qcow.snapshots().len(), 1);
+        assert_eq!(qcow.snapshots()[0].id, "2");
+        assert_eq!(read_at(&mut qcow, 0x1_0000, 13), b"snapshot data");
+    }
+
+    #[test]
+    fn snapshot_refcounts() {
+        with_default_file(0x10_0000, false, |mut qcow| {
+            write_at(&mut qcow, 0, b"data");
+            qcow.flush().unwrap();
+            let l2_entry = qcow.l2_table(0).unwrap().unwrap()[0];
+            let cluster_addr = l2_entry_std_cluster_addr(l2_entry);
+            assert_ne!(l2_entry & CLUSTER_USED_FLAG, 0);
+
+            // The snapshot shares the cluster, which loses its COPIED flag.
+            qcow.create_snapshot("first", 0).unwrap();
+            assert_eq!(qcow.cluster_refcount(cluster_addr).unwrap(), 2);
+            assert_eq!(qcow.l2_table(0).unwrap().unwrap()[0] & CLUSTER_USED_FLAG, 0);
+
+            // Writing copies the cluster, leaving the original to the snapshot.
+            write_at(&mut qcow, 0, b"more");
+            qcow.flush().unwrap();
+            let l2_entry = qcow.l2_table(0).unwrap().unwrap()[0];
+            assert_ne!(l2_entry_std_cluster_addr(l2_entry), cluster_addr);
+            assert_ne!(l2_entry & CLUSTER_USED_FLAG, 0);
+            assert_eq!(qcow.cluster_refcount(cluster_addr).unwrap(), 1);
+
+            // Deleting the snapshot releases it.
+            qcow.delete_snapshot("first").unwrap();
+            assert_eq!(qcow.cluster_refcount(cluster_addr).unwrap(), 0);
+            assert!(qcow.avail_clusters.contains(&cluster_addr));
+        });
+    }
+
+    #[test]
+    fn dirty_bitmap_store_and_load() {
+        let file = TempFile::new().unwrap().into_file();
+        {
+            let raw = RawFile::new(file.try_clone().unwrap(), false);
+            let mut qcow = QcowFile::new(raw, 3, 0x100_0000, true).unwrap();
+            // A missing bitmap is all dirty.
+            let bitmap = qcow.load_dirty_bitmap("backup", 0x1_0000).unwrap();
+            assert_eq!(bitmap.count(), 256);
+            bitmap.clear();
+            bitmap.set(0x2_0000, 1);
+            bitmap.set(0xff_0000, 1);
+            qcow.store_dirty_bitmap("backup", &bitmap).unwrap();
+        }
+
+        let raw = RawFile::new(file.try_clone().unwrap(), false);
+        let mut qcow = QcowFile::from(raw).unwrap();
+        assert_ne!(qcow.header.autoclear_features & AUTOCLEAR_BITMAPS, 0);
+        assert_eq!(qcow.bitmaps().len(), 1);
+        // The bitmap is in use while the image is open for writing.
+        assert!(qcow.bitmaps()[0].in_use());
+        let bitmap = qcow.load_dirty_bitmap("backup", 0x1_0000).unwrap();
+        assert_eq!(bitmap.count(), 2);
+        assert!(bitmap.is_set(0x2_0000));
+        assert!(bitmap.is_set(0xff_0000));
+        // Another granularity doesn't match.
+        assert_eq!(
+            qcow.load_dirty_bitmap("backup", 0x2_0000).unwrap().count(),
+            128
+        );
+        drop(qcow);
+
+        // Not stored after the image was written to, the bitmap can't be trusted.
+        let raw = RawFile::new(file, false);
+        let mut qcow = QcowFile::from(raw).unwrap();
+        assert_eq!(
+            qcow.load_dirty_bitmap("backup", 0x1_0000).unwrap().count(),
+            256
+        );
+    }
+
+    #[test]
+    fn dirty_bitmap_keeps_backing_file() {
+        let backing = TempFile::new().unwrap();
+        backing.as_file().set_len(0x10_0000).unwrap();
+        let file = TempFile::new().unwrap().into_file();
+        let backing_config = BackingFileConfig {
+            path: backing.as_path().to_str().unwrap().to_string(),
+            format: Some(ImageType::Raw),
+        };
+        {
+            let raw = RawFile::new(file.try_clone().unwrap(), false);
+            let mut qcow =
+                QcowFile::new_from_backing(raw, 3, 0x10_0000, &backing_config, true).unwrap();
+            let bitmap = DirtyBitmap::new(0x10_0000, 0x1_0000);
+            bitmap.set(0, 1);
+            qcow.store_dirty_bitmap("backup", &bitmap).unwrap();
+            assert_eq!(
+                qcow.header.backing_file_offset,
+                u64::from(qcow.header.header_size)
+                    + u64::from(QCOW_EMPTY_HEADER_EXTENSION_SIZE + BITMAPS_EXTENSION_SIZE)
+                    + u64::from(QCOW_EMPTY_HEADER_EXTENSION_SIZE)
+            );
+        }
+
+        let mut raw = RawFile::new(file.try_clone().unwrap(), false);
+        let header = QcowHeader::new(&mut raw).unwrap();
+        assert_eq!(header.backing_file.unwrap().path, backing_config.path);
+        assert!(header.bitmaps.is_some());
+
+        // Adding a bitmap rewrites the extensions again.
+        let mut qcow = QcowFile::from(raw).unwrap();
+        let bitmap = qcow.load_dirty_bitmap("backup", 0x1_0000).unwrap();
+        assert_eq!(bitmap.count(), 1);
+        qcow.store_dirty_bitmap("other", &bitmap).unwrap();
+        assert_eq!(qcow.bitmaps().len(), 2);
+        drop(qcow);
+
+        let raw = RawFile::new(file, false);
+        let mut qcow = QcowFile::from(raw).unwrap();
+        assert_eq!(
+            qcow.header.backing_file.as_ref().unwrap().path,
+            backing_config.path
+        );
+        assert_eq!(read_at(&mut qcow, 0, 4), [0u8; 4]);
+        assert_eq!(
+            qcow.load_dirty_bitmap("other", 0x1_0000).unwrap().count(),
+            1
+        );
+    }
+
+    #[test]
+    fn dirty_bitmap_refcounts() {
+        let file = TempFile::new().unwrap().into_file();
+        let raw = RawFile::new(file.try_clone().unwrap(), false);
+        let mut qcow = QcowFile::new(raw, 3, 0x10_0000, true).unwrap();
+        let bitmap = DirtyBitmap::new(0x10_0000, 0x1_0000);
+        bitmap.set(0, 1);
+        qcow.store_dirty_bitmap("backup", &bitmap).unwrap();
+        let old_table_offset = qcow.bitmaps()[0].table_offset;
+        let old_data_offset = qcow
+            .raw_file
+            .read_pointer_table(old_table_offset, 1, None)
+            .unwrap()[0];
+        assert_eq!(qcow.cluster_refcount(old_table_offset).unwrap(), 1);
+        assert_eq!(qcow.cluster_refcount(old_data_offset).unwrap(), 1);
+
+        // Replacing the bitmap releases the clusters of the previous one.
+        qcow.store_dirty_bitmap("backup", &bitmap).unwrap();
+        assert_eq!(qcow.cluster_refcount(old_table_offset).unwrap(), 0);
+        assert_eq!(qcow.cluster_refcount(old_data_offset).unwrap(), 0);
+        let table_offset = qcow.bitmaps()[0].table_offset;
+        let directory_offset = qcow.header.bitmaps.unwrap().directory_offset;
+        drop(qcow);
+
+        // The refcounts rebuilt after a crash keep the bitmap clusters.
+        let raw = RawFile::new(file.try_clone().unwrap(), false);
+        std::mem::forget(QcowFile::from(raw).unwrap());
+        let raw = RawFile::new(file, false);
+        let mut qcow = QcowFile::from(raw).unwrap();
+        assert_eq!(qcow.cluster_refcount(table_offset).unwrap(), 1);
+        assert_eq!(qcow.cluster_refcount(directory_offset).unwrap(), 1);
+        assert_eq!(
+            qcow.load_dirty_bitmap("backup", 0x1_0000).unwrap().count(),
+            16
+        );
+    }
+
+    fn luks_options() -> LuksCreateOptions {
+        LuksCreateOptions {
+            kdf: LuksKdf::Pbkdf2 { iterations: 1000 },
+            digest_iterations: 1000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn luks_write_read_reopen() {
+        let file = TempFile::new().unwrap().into_file();
+        let raw = RawFile::new(file.try_clone().unwrap(), false);
+        let mut qcow = QcowFile::new_encrypted(
+            raw,
+            0x10_0000,
+            true,
+            &[b"first", b"second"],
+            &luks_options(),
+        )
+        .unwrap();
+        assert!(qcow.is_encrypted());
+        write_at(&mut qcow, 0x1_0100, b"secret data");
+        write_at(&mut qcow, 0x2_0000, &[0x55u8; 0x1_0000]);
+        assert_eq!(read_at(&mut qcow, 0x1_0100, 11), b"secret data");
+        assert_eq!(read_at(&mut qcow, 0x1_0000, 0x100), [0u8; 0x100]);
+        qcow.write_zeroes_at(0x2_0010, 0x20).unwrap();
+        assert_eq!(read_at(&mut qcow, 0x2_0000, 0x10), [0x55u8; 0x10]);
+        assert_eq!(read_at(&mut qcow, 0x2_0010, 0x20), [0u8; 0x20]);
+        assert_eq!(read_at(&mut qcow, 0x2_0030, 0x10), [0x55u8; 0x10]);
+        drop(qcow);
+
+        // Only the encrypted data reaches the disk.
+        let mut content = Vec::new();
+        (&file).rewind().unwrap();
+        (&file).read_to_end(&mut content).unwrap();
+        assert!(!content.windows(11).any(|w| w == b"secret data"));
+
+        let raw = RawFile::new(file.try_clone().unwrap(), false);
+        let mut qcow = QcowFile::from(raw).unwrap();
+        assert!(qcow.is_encrypted());
+        assert!(matches!(
+            qcow.unlock(b"wrong"),
+            Err(Error::Luks(LuksError::WrongKey))
+        ));
+        qcow.unlock(b"second").unwrap();
+        assert_eq!(read_at(&mut qcow, 0x1_0100, 11), b"secret data");
+        assert_eq!(read_at(&mut qcow, 0x2_0030, 0x10), [0x55u8; 0x10]);
+    }
+
+    #[test]
+    fn luks_locked() {
+        let file = TempFile::new().unwrap().into_file();
+        let raw = RawFile::new(file.try_clone().unwrap(), false);
+        let mut qcow =
+            QcowFile::new_encrypted(raw, 0x10_0000, true, &[b"key"], &luks_options()).unwrap();
+        write_at(&mut qcow, 0, b"data");
+        drop(qcow);
+
+        let raw = RawFile::new(file, false);
+        let mut qcow = QcowFile::from(raw).unwrap();
+        let mut buf = [0u8; 4];
+        qcow.rewind().unwrap();
+        assert_eq!(
+            qcow.read_exact(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        qcow.seek(SeekFrom::Start(0x2_0000)).unwrap();
+        assert_eq!(
+            qcow.write(b"data").unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        qcow.map_write(0).unwrap_err();
+        // Unallocated clusters don't need the key to read as zeros.
+        assert_eq!(read_at(&mut qcow, 0x3_0000, 4), [0u8; 4]);
+    }
+
+    #[test]
+    fn luks_snapshot_copy_on_write() {
+        let file = TempFile::new().unwrap().into_file();
+        let raw = RawFile::new(file, false);
+        let mut qcow =
+            QcowFile::new_encrypted(raw, 0x10_0000, true, &[b"key"], &luks_options()).unwrap();
+        write_at(&mut qcow, 0x1_0000, b"before");
+        qcow.create_snapshot("first", 0).unwrap();
+        write_at(&mut qcow, 0x1_0003, b"ore!!");
+        assert_eq!(read_at(&mut qcow, 0x1_0000, 8), b"before!!");
+        qcow.apply_snapshot("first").unwrap();
+        assert_eq!(read_at(&mut qcow, 0x1_0000, 8), b"before\0\0");
+    }
+
+    #[test]
+    fn luks_refcounts() {
+        let file = TempFile::new().unwrap().into_file();
+        let raw = RawFile::new(file.try_clone().unwrap(), false);
+        let qcow =
+            QcowFile::new_encrypted(raw, 0x10_0000, true, &[b"key"], &luks_options()).unwrap();
+        let crypto_header = qcow.header.crypto_header.unwrap();
+        drop(qcow);
+
+        // The refcounts rebuilt after a crash keep the LUKS header clusters.
+        let raw = RawFile::new(file.try_clone().unwrap(), false);
+        std::mem::forget(QcowFile::from(raw).unwrap());
+        let raw = RawFile::new(file, false);
+        let mut qcow = QcowFile::from(raw).unwrap();
+        let cluster_size = qcow.cluster_size();
+        for i in 0..div_round_up_u64(crypto_header.length, cluster_size) {
+            let addr = crypto_header.offset + i * cluster_size;
+            assert_eq!(qcow.cluster_refcount(addr).unwrap(), 1);
+        }
+        qcow.unlock(b"key").unwrap();
+    }
+
+    #[test]
+    fn luks_unsupported_encryption() {
+        let file = TempFile::new().unwrap().into_file();
+        let raw = RawFile::new(file.try_clone().unwrap(), false);
+        let qcow = QcowFile::new(raw, 3, 0x10_0000, true).unwrap();
+        let mut header = qcow.header.clone();
+        drop(qcow);
+
+        // The legacy AES encryption is rejected.
+        let mut raw = RawFile::new(file.try_clone().unwrap(), false);
+        header.crypt_method = 1;
+        raw.rewind().unwrap();
+        header.write_to(&mut raw).unwrap();
+        assert!(matches!(
+            QcowFile::from(raw),
+            Err(Error::UnsupportedEncryption(1))
+        ));
+
+        // LUKS needs its header.
+        let mut raw = RawFile::new(file, false);
+        header.crypt_method = CRYPT_LUKS;
+        raw.rewind().unwrap();
+        header.write_to(&mut raw).unwrap();
+        assert!(matches!(
+            QcowFile::from(raw),
+            Err(Error::InvalidCryptoHeader)
+        ));
+    }
 }
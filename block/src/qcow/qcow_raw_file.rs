@@ -234,24 +234,6 @@ impl QcowRawFile {
         Ok(BufWriter::with_capacity(capacity, my_file))
     }
 
-    /// Writes a pointer table to `offset` in the file.
-    /// Entries are computed on-the-fly by the callback.
-    pub fn write_pointer_table<'a, T: Copy + 'a>(
-        &mut self,
-        offset: u64,
-        entries: impl Iterator<Item = &'a T>,
-        mut f: impl FnMut(&mut QcowRawFile, T) -> io::Result<u64>,
-    ) -> io::Result<()> {
-        let mut buffer = self.setup_pointer_table_writer(offset, &entries)?;
-
-        for addr in entries {
-            let entry = f(self, *addr)?;
-            u64::write_be(&mut buffer, entry)?;
-        }
-        buffer.flush()?;
-        Ok(())
-    }
-
     /// Writes a pointer table directly without transforming values.
     pub fn write_pointer_table_direct<'a>(
         &mut self,
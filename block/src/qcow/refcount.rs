@@ -194,6 +194,28 @@ impl RefCount {
         }
     }
 
+    /// Returns a copy of the refcount table if it changed since it was last written.
+    pub fn dirty_table(&self) -> Option<Vec<u64>> {
+        self.ref_table
+            .dirty()
+            .then(|| self.ref_table.get_values().to_vec())
+    }
+
+    /// Writes `table`, a copy of the refcount table returned by `dirty_table()`. The refcount
+    /// table stays dirty if it changed since.
+    pub fn write_table(&mut self, raw_file: &mut QcowRawFile, table: &[u64]) -> io::Result<()> {
+        raw_file.write_pointer_table_direct(self.refcount_table_offset, table.iter())?;
+        if self.ref_table.get_values() == table {
+            self.ref_table.mark_clean();
+        }
+        Ok(())
+    }
+
+    /// Marks the refcount table as dirty, to be written again by the next `flush_table()`.
+    pub fn mark_table_dirty(&mut self) {
+        self.ref_table.mark_dirty();
+    }
+
     /// Gets the refcount for a cluster with the given address.
     pub fn get_cluster_refcount(
         &mut self,
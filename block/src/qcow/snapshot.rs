@@ -0,0 +1,240 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use std::io::{self, Read};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::async_io::DiskSnapshot;
+
+/// Maximum number of snapshots of an image, from the specification.
+pub const MAX_SNAPSHOTS: u32 = 65536;
+
+// Size of the fixed part of a snapshot table entry.
+const SNAPSHOT_HEADER_SIZE: usize = 40;
+// Size of the extra data fields known here: the 64 bit VM state size and the disk size.
+const SNAPSHOT_EXTRA_DATA_SIZE: usize = 16;
+// Larger extra data is rejected, as QEMU does.
+const MAX_SNAPSHOT_EXTRA_DATA_SIZE: usize = 1024;
+
+/// An internal snapshot, as stored in the snapshot table of a qcow2 image.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QcowSnapshot {
+    /// Offset of the L1 table of the snapshot.
+    pub l1_table_offset: u64,
+    /// Number of entries of the L1 table of the snapshot.
+    pub l1_size: u32,
+    /// Unique ID of the snapshot.
+    pub id: String,
+    /// Name of the snapshot.
+    pub name: String,
+    /// Time the snapshot was taken, seconds part.
+    pub date_sec: u32,
+    /// Time the snapshot was taken, nanoseconds part.
+    pub date_nsec: u32,
+    /// Time the guest had been running when the snapshot was taken.
+    pub vm_clock_nsec: u64,
+    /// Size of the VM state saved with the snapshot.
+    pub vm_state_size: u64,
+    /// Virtual size of the disk when the snapshot was taken.
+    pub disk_size: u64,
+    /// Extra data following the fields known here, which must be kept as is.
+    pub extra_data: Vec<u8>,
+}
+
+impl QcowSnapshot {
+    /// Reads `count` snapshot table entries from `f`. Entries without a disk size, from images
+    /// written by older software, get `disk_size`.
+    pub fn read_table<R: Read>(f: &mut R, count: u32, disk_size: u64) -> io::Result<Vec<Self>> {
+        (0..count).map(|_| Self::read_from(f, disk_size)).collect()
+    }
+
+    fn read_from<R: Read>(f: &mut R, disk_size: u64) -> io::Result<Self> {
+        let l1_table_offset = f.read_u64::<BigEndian>()?;
+        let l1_size = f.read_u32::<BigEndian>()?;
+        let id_size = f.read_u16::<BigEndian>()? as usize;
+        let name_size = f.read_u16::<BigEndian>()? as usize;
+        let date_sec = f.read_u32::<BigEndian>()?;
+        let date_nsec = f.read_u32::<BigEndian>()?;
+        let vm_clock_nsec = f.read_u64::<BigEndian>()?;
+        let mut vm_state_size = u64::from(f.read_u32::<BigEndian>()?);
+        let extra_data_size = f.read_u32::<BigEndian>()? as usize;
+        if extra_data_size > MAX_SNAPSHOT_EXTRA_DATA_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("snapshot extra data too large: {extra_data_size}"),
+            ));
+        }
+
+        let mut extra_data = vec![0u8; extra_data_size];
+        f.read_exact(&mut extra_data)?;
+        let mut disk_size = disk_size;
+        if extra_data.len() >= 8 {
+            vm_state_size = u64::from_be_bytes(extra_data[0..8].try_into().unwrap());
+        }
+        if extra_data.len() >= SNAPSHOT_EXTRA_DATA_SIZE {
+            disk_size = u64::from_be_bytes(extra_data[8..16].try_into().unwrap());
+        }
+        extra_data.drain(..extra_data.len().min(SNAPSHOT_EXTRA_DATA_SIZE));
+
+        let mut id = vec![0u8; id_size];
+        f.read_exact(&mut id)?;
+        let mut name = vec![0u8; name_size];
+        f.read_exact(&mut name)?;
+
+        let entry_size = SNAPSHOT_HEADER_SIZE + extra_data_size + id_size + name_size;
+        let mut padding = vec![0u8; entry_size.next_multiple_of(8) - entry_size];
+        f.read_exact(&mut padding)?;
+
+        Ok(QcowSnapshot {
+            l1_table_offset,
+            l1_size,
+            id: String::from_utf8_lossy(&id).into_owned(),
+            name: String::from_utf8_lossy(&name).into_owned(),
+            date_sec,
+            date_nsec,
+            vm_clock_nsec,
+            vm_state_size,
+            disk_size,
+            extra_data,
+        })
+    }
+
+    /// Returns the snapshot table holding `snapshots`, as stored in the image.
+    pub fn write_table(snapshots: &[Self]) -> Vec<u8> {
+        let mut table = Vec::with_capacity(Self::table_size(snapshots) as usize);
+        for snapshot in snapshots {
+            snapshot.write_to(&mut table);
+        }
+        table
+    }
+
+    /// Returns the size of the snapshot table holding `snapshots`.
+    pub fn table_size(snapshots: &[Self]) -> u64 {
+        snapshots.iter().map(|s| s.entry_size() as u64).sum()
+    }
+
+    // Size of the table entry of this snapshot, padding included.
+    fn entry_size(&self) -> usize {
+        (SNAPSHOT_HEADER_SIZE
+            + SNAPSHOT_EXTRA_DATA_SIZE
+            + self.extra_data.len()
+            + self.id.len()
+            + self.name.len())
+        .next_multiple_of(8)
+    }
+
+    fn write_to(&self, table: &mut Vec<u8>) {
+        let start = table.len();
+        // Writing to a Vec can't fail.
+        table.write_u64::<BigEndian>(self.l1_table_offset).unwrap();
+        table.write_u32::<BigEndian>(self.l1_size).unwrap();
+        table.write_u16::<BigEndian>(self.id.len() as u16).unwrap();
+        table
+            .write_u16::<BigEndian>(self.name.len() as u16)
+            .unwrap();
+        table.write_u32::<BigEndian>(self.date_sec).unwrap();
+        table.write_u32::<BigEndian>(self.date_nsec).unwrap();
+        table.write_u64::<BigEndian>(self.vm_clock_nsec).unwrap();
+        // The 64 bit size in the extra data supersedes this one.
+        table
+            .write_u32::<BigEndian>(u32::try_from(self.vm_state_size).unwrap_or(0))
+            .unwrap();
+        table
+            .write_u32::<BigEndian>((SNAPSHOT_EXTRA_DATA_SIZE + self.extra_data.len()) as u32)
+            .unwrap();
+        table.write_u64::<BigEndian>(self.vm_state_size).unwrap();
+        table.write_u64::<BigEndian>(self.disk_size).unwrap();
+        table.extend_from_slice(&self.extra_data);
+        table.extend_from_slice(self.id.as_bytes());
+        table.extend_from_slice(self.name.as_bytes());
+        table.resize(start + self.entry_size(), 0);
+    }
+}
+
+impl From<&QcowSnapshot> for DiskSnapshot {
+    fn from(snapshot: &QcowSnapshot) -> Self {
+        DiskSnapshot {
+            id: snapshot.id.clone(),
+            name: snapshot.name.clone(),
+            date_sec: snapshot.date_sec,
+            date_nsec: snapshot.date_nsec,
+            vm_clock_nsec: snapshot.vm_clock_nsec,
+            vm_state_size: snapshot.vm_state_size,
+            disk_size: snapshot.disk_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn snapshot_table_round_trip() {
+        let snapshots = vec![
+            QcowSnapshot {
+                l1_table_offset: 0x3_0000,
+                l1_size: 1,
+                id: "1".to_string(),
+                name: "first".to_string(),
+                date_sec: 1_700_000_000,
+                date_nsec: 42,
+                vm_clock_nsec: 1_000_000,
+                disk_size: 0x10_0000,
+                ..Default::default()
+            },
+            QcowSnapshot {
+                l1_table_offset: 0x5_0000,
+                l1_size: 2,
+                id: "2".to_string(),
+                name: "second snapshot".to_string(),
+                date_sec: 1_700_000_100,
+                vm_clock_nsec: 2_000_000,
+                vm_state_size: 0x1_0000_0000,
+                disk_size: 0x20_0000,
+                extra_data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                ..Default::default()
+            },
+        ];
+
+        let table = QcowSnapshot::write_table(&snapshots);
+        assert_eq!(table.len() as u64, QcowSnapshot::table_size(&snapshots));
+        assert_eq!(table.len() % 8, 0);
+
+        let read = QcowSnapshot::read_table(&mut Cursor::new(&table), 2, 0).unwrap();
+        assert_eq!(read, snapshots);
+    }
+
+    #[test]
+    fn snapshot_table_extra_data() {
+        // A v2 entry without extra data takes the current disk size.
+        let mut entry = Vec::new();
+        entry.write_u64::<BigEndian>(0x3_0000).unwrap();
+        entry.write_u32::<BigEndian>(1).unwrap();
+        entry.write_u16::<BigEndian>(1).unwrap();
+        entry.write_u16::<BigEndian>(3).unwrap();
+        entry.extend_from_slice(&[0u8; 16]);
+        entry.write_u32::<BigEndian>(512).unwrap();
+        entry.write_u32::<BigEndian>(0).unwrap();
+        entry.extend_from_slice(b"1abc");
+        entry.resize(48, 0);
+
+        let read = QcowSnapshot::read_table(&mut Cursor::new(&entry), 1, 0x1000).unwrap();
+        assert_eq!(read[0].id, "1");
+        assert_eq!(read[0].name, "abc");
+        assert_eq!(read[0].vm_state_size, 512);
+        assert_eq!(read[0].disk_size, 0x1000);
+
+        // Unknown extra data is written back.
+        let mut snapshot = read[0].clone();
+        snapshot.extra_data = vec![0xaa; 8];
+        let table = QcowSnapshot::write_table(std::slice::from_ref(&snapshot));
+        let read = QcowSnapshot::read_table(&mut Cursor::new(&table), 1, 0).unwrap();
+        assert_eq!(read[0], snapshot);
+        assert_eq!(read[0].disk_size, 0x1000);
+    }
+}
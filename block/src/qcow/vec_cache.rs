@@ -58,6 +58,11 @@ impl<T: 'static + Copy + Default> VecCache<T> {
         self.dirty = false;
     }
 
+    /// Mark this cache element as dirty.
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
     /// Returns the number of elements in the vector.
     pub fn len(&self) -> usize {
         self.vec.len()
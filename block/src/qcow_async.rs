@@ -0,0 +1,1119 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Asynchronous QCOW2 disk based on `io-uring`.
+//!
+//! The L1, L2 and refcount tables stay in the caches of a [`QcowFile`] shared
+//! by all queues, which is only locked while a request is mapped to the
+//! clusters it touches. The guest data is then read and written through the
+//! io_uring of each queue, directly from and to the clusters of the image and
+//! of its backing files.
+//!
+//! When a write allocates a cluster over a backing file, the part of the
+//! cluster the guest doesn't write is copied from the backing file through
+//! io_uring as well. Until the copy completes, the requests touching that
+//! cluster wait while the other requests of the queue carry on.
+//!
+//! A flush writes the metadata back under the lock, then syncs the image
+//! through io_uring. It waits for the writes allocating clusters, whose
+//! metadata must not be synced before their data, and for the flushes of the
+//! other queues.
+
+use std::alloc::{Layout, alloc_zeroed, dealloc};
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use io_uring::{IoUring, opcode, squeue, types};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::write_zeroes::PunchHole;
+
+use crate::async_io::{
+    AsyncIo, AsyncIoResult, BorrowedDiskFd, DiskFile, DiskFileError, DiskFileResult, DiskSnapshot,
+};
+use crate::dirty_bitmap::DirtyBitmap;
+use crate::qcow::{
+    Error as QcowError, MAX_NESTING_DEPTH, MetadataWriteBack, QcowFile, RawFile, ReadMapping,
+    Result as QcowResult,
+};
+use crate::{BlockBackend, DiskTopology, SECTOR_SIZE};
+
+/// Alignment of the buffers backing data is copied through, suitable for
+/// direct I/O.
+const BOUNCE_BUFFER_ALIGNMENT: usize = 4096;
+
+/// Metadata of the image, shared by all queues.
+struct QcowState {
+    file: QcowFile,
+    /// Clusters being copied from the backing file, by guest address, with
+    /// the notifiers of the queues waiting for them.
+    copying: HashMap<u64, Vec<Arc<EventFd>>>,
+    /// Clusters allocated by the writes in flight, by guest address, with the
+    /// notifiers of the queues flushing the image.
+    allocating: HashMap<u64, Vec<Arc<EventFd>>>,
+    /// Notifiers of the queues waiting for the flush in flight, if any.
+    syncing: Option<Vec<Arc<EventFd>>>,
+}
+
+/// What a request waits for before it can go on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Blocker {
+    /// A cluster being copied or allocated, by guest address.
+    Cluster(u64),
+    /// The flush of another queue.
+    Sync,
+}
+
+impl QcowState {
+    /// Returns a cluster being copied that the guest range overlaps.
+    fn copying_cluster(&self, offset: u64, length: u64) -> Option<u64> {
+        let cluster_size = self.file.cluster_size();
+        self.copying
+            .keys()
+            .copied()
+            .find(|&cluster| cluster < offset + length && offset < cluster + cluster_size)
+    }
+
+    /// Have `notifier` signaled once `blocker` is gone.
+    fn wait_for(&mut self, blocker: Blocker, notifier: &Arc<EventFd>) {
+        let waiters = match blocker {
+            Blocker::Cluster(cluster) => self
+                .copying
+                .get_mut(&cluster)
+                .or_else(|| self.allocating.get_mut(&cluster)),
+            Blocker::Sync => self.syncing.as_mut(),
+        };
+        if let Some(waiters) = waiters
+            && !waiters.iter().any(|waiter| Arc::ptr_eq(waiter, notifier))
+        {
+            waiters.push(Arc::clone(notifier));
+        }
+    }
+
+    /// Marks `clusters` as copied, waking up the queues waiting for them.
+    fn copied(&mut self, clusters: &[u64]) {
+        for cluster in clusters {
+            for waiter in self.copying.remove(cluster).into_iter().flatten() {
+                waiter.write(1).unwrap();
+            }
+        }
+    }
+
+    /// Marks the data of `clusters` as written, waking up the queues waiting
+    /// for them.
+    fn allocated(&mut self, clusters: &[u64]) {
+        for cluster in clusters {
+            for waiter in self.allocating.remove(cluster).into_iter().flatten() {
+                waiter.write(1).unwrap();
+            }
+        }
+    }
+
+    /// Marks the flush in flight as done, waking up the queues waiting for it.
+    fn synced(&mut self) {
+        for waiter in self.syncing.take().into_iter().flatten() {
+            waiter.write(1).unwrap();
+        }
+    }
+}
+
+pub struct QcowDiskAsync {
+    state: Arc<Mutex<QcowState>>,
+    alignment: u64,
+}
+
+impl QcowDiskAsync {
+    pub fn new(file: File, direct_io: bool, backing_files: bool, sparse: bool) -> QcowResult<Self> {
+        let alignment = DiskTopology::probe(&file).map_or(SECTOR_SIZE, |t| t.logical_block_size);
+        let max_nesting_depth = if backing_files { MAX_NESTING_DEPTH } else { 0 };
+        let qcow_file = QcowFile::from_with_nesting_depth(
+            RawFile::new(file, direct_io),
+            max_nesting_depth,
+            sparse,
+        )
+        .map_err(|e| match e {
+            QcowError::MaxNestingDepthExceeded if !backing_files => QcowError::BackingFilesDisabled,
+            other => other,
+        })?;
+        // The I/O goes straight to the host file, where the data would have to be decrypted.
+        if qcow_file.is_encrypted() {
+            return Err(QcowError::EncryptedAsyncIo);
+        }
+        Ok(QcowDiskAsync {
+            state: Arc::new(Mutex::new(QcowState {
+                file: qcow_file,
+                copying: HashMap::new(),
+                allocating: HashMap::new(),
+                syncing: None,
+            })),
+            alignment,
+        })
+    }
+}
+
+impl DiskFile for QcowDiskAsync {
+    fn logical_size(&mut self) -> DiskFileResult<u64> {
+        self.state
+            .lock()
+            .unwrap()
+            .file
+            .seek(SeekFrom::End(0))
+            .map_err(DiskFileError::Size)
+    }
+
+    fn physical_size(&mut self) -> DiskFileResult<u64> {
+        self.state
+            .lock()
+            .unwrap()
+            .file
+            .physical_size()
+            .map_err(|e| {
+                let io_inner = match e {
+                    crate::Error::GetFileMetadata(e) => e,
+                    _ => unreachable!(),
+                };
+                DiskFileError::Size(io_inner)
+            })
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        let mut qcow = QcowAsync::new(Arc::clone(&self.state), ring_depth)
+            .map_err(DiskFileError::NewAsyncIo)?;
+        qcow.alignment = self.alignment;
+        Ok(Box::new(qcow) as Box<dyn AsyncIo>)
+    }
+
+    fn resize(&mut self, size: u64) -> DiskFileResult<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .file
+            .resize(size)
+            .map_err(|e| DiskFileError::ResizeError(io::Error::other(e)))
+    }
+
+    fn snapshots(&mut self) -> DiskFileResult<Vec<DiskSnapshot>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .file
+            .snapshots()
+            .iter()
+            .map(DiskSnapshot::from)
+            .collect())
+    }
+
+    fn create_snapshot(&mut self, name: &str, vm_clock_nsec: u64) -> DiskFileResult<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .file
+            .create_snapshot(name, vm_clock_nsec)
+            .map_err(|e| DiskFileError::SnapshotError(io::Error::other(e)))
+    }
+
+    fn apply_snapshot(&mut self, id_or_name: &str) -> DiskFileResult<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .file
+            .apply_snapshot(id_or_name)
+            .map_err(|e| DiskFileError::SnapshotError(io::Error::other(e)))
+    }
+
+    fn delete_snapshot(&mut self, id_or_name: &str) -> DiskFileResult<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .file
+            .delete_snapshot(id_or_name)
+            .map_err(|e| DiskFileError::SnapshotError(io::Error::other(e)))
+    }
+
+    fn load_dirty_bitmap(&mut self, name: &str, granularity: u64) -> DiskFileResult<DirtyBitmap> {
+        self.state
+            .lock()
+            .unwrap()
+            .file
+            .load_dirty_bitmap(name, granularity)
+            .map_err(|e| DiskFileError::DirtyBitmapError(io::Error::other(e)))
+    }
+
+    fn store_dirty_bitmap(&mut self, name: &str, bitmap: &DirtyBitmap) -> DiskFileResult<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .file
+            .store_dirty_bitmap(name, bitmap)
+            .map_err(|e| DiskFileError::DirtyBitmapError(io::Error::other(e)))
+    }
+
+    fn supports_sparse_operations(&self) -> bool {
+        true
+    }
+
+    fn supports_zero_flag(&self) -> bool {
+        true
+    }
+
+    fn fd(&mut self) -> BorrowedDiskFd<'_> {
+        BorrowedDiskFd::new(self.state.lock().unwrap().file.as_raw_fd())
+    }
+}
+
+/// Buffers of a request, as handed to io_uring.
+struct Iovecs(Vec<libc::iovec>);
+
+// SAFETY: the buffers are guest memory or bounce buffers of the request,
+// which outlive it and are only accessed by the kernel while it is in flight.
+unsafe impl Send for Iovecs {}
+
+impl Iovecs {
+    fn len(&self) -> usize {
+        self.0.iter().map(|iovec| iovec.iov_len).sum()
+    }
+}
+
+/// Aligned buffer the backing data of a cluster is copied through.
+struct BounceBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+// SAFETY: the buffer is owned by its request and only accessed by the kernel
+// while the request is in flight.
+unsafe impl Send for BounceBuffer {}
+
+impl BounceBuffer {
+    fn new(len: usize) -> Self {
+        let layout = Layout::from_size_align(len, BOUNCE_BUFFER_ALIGNMENT).unwrap();
+        // SAFETY: layout has non-zero size, the copied ranges never being empty.
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        BounceBuffer { ptr, layout }
+    }
+
+    fn iovecs(&self) -> Iovecs {
+        Iovecs(vec![libc::iovec {
+            iov_base: self.ptr as *mut libc::c_void,
+            iov_len: self.layout.size(),
+        }])
+    }
+}
+
+impl Drop for BounceBuffer {
+    fn drop(&mut self) {
+        // SAFETY: ptr was allocated by alloc_zeroed with the same layout.
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+/// Guest request waiting for a cluster to be copied.
+enum Operation {
+    Read { offset: u64, iovecs: Iovecs },
+    Write { offset: u64, iovecs: Iovecs },
+    Fsync,
+    PunchHole { offset: u64, length: u64 },
+}
+
+impl Operation {
+    /// Returns what must be waited for before the operation can go on.
+    fn blocked_by(&self, state: &QcowState) -> Option<Blocker> {
+        match self {
+            Operation::Read { offset, iovecs } | Operation::Write { offset, iovecs } => state
+                .copying_cluster(*offset, iovecs.len() as u64)
+                .map(Blocker::Cluster),
+            // The metadata pointing at the clusters being copied or written
+            // must not be synced before their data. The flushes of the queues
+            // don't overlap, the tables a flush writes once the image is
+            // synced being the ones it wrote back.
+            Operation::Fsync => state
+                .copying
+                .keys()
+                .chain(state.allocating.keys())
+                .next()
+                .copied()
+                .map(Blocker::Cluster)
+                .or(state.syncing.is_some().then_some(Blocker::Sync)),
+            Operation::PunchHole { offset, length } => state
+                .copying_cluster(*offset, *length)
+                .map(Blocker::Cluster),
+        }
+    }
+}
+
+/// Guest request in flight, split in io_uring operations.
+struct Request {
+    user_data: u64,
+    /// io_uring operations not completed yet.
+    pending: usize,
+    /// Length of the request, or the error of the first failed operation.
+    result: i32,
+    /// Clusters this request copies from the backing file.
+    copying: Vec<u64>,
+    /// Clusters this request allocates without copying them.
+    allocating: Vec<u64>,
+    /// Metadata this request flushes, until the image is synced.
+    write_back: Option<MetadataWriteBack>,
+    /// Buffers of the io_uring operations, kept until they complete.
+    iovecs: Vec<Iovecs>,
+    buffers: Vec<BounceBuffer>,
+}
+
+impl Request {
+    fn new(user_data: u64, len: usize) -> Self {
+        Request {
+            user_data,
+            pending: 0,
+            result: len as i32,
+            copying: Vec::new(),
+            allocating: Vec::new(),
+            write_back: None,
+            iovecs: Vec::new(),
+            buffers: Vec::new(),
+        }
+    }
+
+    fn fail(&mut self, e: &io::Error) {
+        if self.result >= 0 {
+            self.result = -e.raw_os_error().unwrap_or(libc::EIO);
+        }
+    }
+
+    /// Queues a vectored read or write of `iovecs` at `offset` in `fd`.
+    fn rw(&mut self, write: bool, fd: RawFd, offset: u64, iovecs: Iovecs) -> squeue::Entry {
+        let (ptr, len) = (iovecs.0.as_ptr(), iovecs.0.len() as u32);
+        // The entries point at the iovecs, which are moved here without
+        // moving the memory they live in.
+        self.iovecs.push(iovecs);
+        if write {
+            opcode::Writev::new(types::Fd(fd), ptr, len)
+                .offset(offset)
+                .build()
+        } else {
+            opcode::Readv::new(types::Fd(fd), ptr, len)
+                .offset(offset)
+                .build()
+        }
+    }
+}
+
+pub struct QcowAsync {
+    state: Arc<Mutex<QcowState>>,
+    fd: RawFd,
+    io_uring: IoUring,
+    eventfd: Arc<EventFd>,
+    alignment: u64,
+    requests: HashMap<u64, Request>,
+    next_id: u64,
+    blocked: VecDeque<(u64, Operation)>,
+    completion_list: VecDeque<(u64, i32)>,
+}
+
+impl QcowAsync {
+    fn new(state: Arc<Mutex<QcowState>>, ring_depth: u32) -> io::Result<Self> {
+        let fd = state.lock().unwrap().file.as_raw_fd();
+        let io_uring = IoUring::new(ring_depth)?;
+        let eventfd = EventFd::new(libc::EFD_NONBLOCK)?;
+
+        // Register the io_uring eventfd that will notify when something in
+        // the completion queue is ready.
+        io_uring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+
+        Ok(QcowAsync {
+            state,
+            fd,
+            io_uring,
+            eventfd: Arc::new(eventfd),
+            alignment: SECTOR_SIZE,
+            requests: HashMap::new(),
+            next_id: 0,
+            blocked: VecDeque::new(),
+            completion_list: VecDeque::new(),
+        })
+    }
+
+    /// Starts `operation`, or queues it until the cluster it touches is
+    /// copied. The guest request always completes, with an error if the
+    /// operation fails.
+    fn submit(&mut self, user_data: u64, operation: Operation) {
+        let len = match &operation {
+            Operation::Read { iovecs, .. } | Operation::Write { iovecs, .. } => iovecs.len(),
+            Operation::Fsync | Operation::PunchHole { .. } => 0,
+        };
+        let mut request = Request::new(user_data, len);
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let chains = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(blocker) = operation.blocked_by(&state) {
+                state.wait_for(blocker, &self.eventfd);
+                drop(state);
+                self.blocked.push_back((user_data, operation));
+                return;
+            }
+
+            Self::prepare(self.fd, &mut state, &operation, &mut request).unwrap_or_else(|e| {
+                state.copied(&request.copying);
+                request.copying.clear();
+                state.allocated(&request.allocating);
+                request.allocating.clear();
+                request.fail(&e);
+                Vec::new()
+            })
+        };
+
+        self.requests.insert(id, request);
+        if let Err(e) = self.push(id, chains) {
+            self.requests.get_mut(&id).unwrap().fail(&e);
+        }
+        if self.requests[&id].pending == 0 {
+            let request = self.requests.remove(&id).unwrap();
+            self.finish(request);
+            self.eventfd.write(1).unwrap();
+        }
+    }
+
+    /// Maps `operation` to the clusters it touches and returns the chains of
+    /// io_uring operations performing it. The parts not needing any I/O are
+    /// performed right away.
+    fn prepare(
+        fd: RawFd,
+        state: &mut QcowState,
+        operation: &Operation,
+        request: &mut Request,
+    ) -> io::Result<Vec<Vec<squeue::Entry>>> {
+        let mut chains = Vec::new();
+        match operation {
+            Operation::Read { offset, iovecs } => {
+                let mut mappings = Vec::new();
+                state.file.map_read(*offset, iovecs.len(), &mut mappings)?;
+
+                let mut pos = 0;
+                for mapping in mappings {
+                    let len = mapping.len();
+                    match mapping {
+                        ReadMapping::Host { fd, offset, .. } => {
+                            let slice = Iovecs(slice_iovecs(&iovecs.0, pos, len));
+                            chains.push(vec![request.rw(false, fd, offset, slice)]);
+                        }
+                        ReadMapping::Zero { .. } => fill_iovecs(&iovecs.0, pos, len, None),
+                        ReadMapping::Data(data) => fill_iovecs(&iovecs.0, pos, len, Some(&data)),
+                    }
+                    pos += len;
+                }
+            }
+            Operation::Write { offset, iovecs } => {
+                let cluster_size = state.file.cluster_size();
+                let len = iovecs.len() as u64;
+                // Contiguous writes in the image, as guest position, host
+                // offset and length.
+                let mut writes: Vec<(u64, u64, u64)> = Vec::new();
+
+                let mut pos = 0;
+                while pos < len {
+                    let address = offset + pos;
+                    let in_cluster = address % cluster_size;
+                    let count = min(cluster_size - in_cluster, len - pos);
+                    let mapping = state.file.map_write(address)?;
+
+                    match writes.last_mut() {
+                        Some((_, host, write_len)) if *host + *write_len == mapping.offset => {
+                            *write_len += count;
+                        }
+                        _ => writes.push((pos, mapping.offset, count)),
+                    }
+
+                    if mapping.allocated && !mapping.copy_backing {
+                        let cluster = address - in_cluster;
+                        state.allocating.insert(cluster, Vec::new());
+                        request.allocating.push(cluster);
+                    }
+                    if mapping.copy_backing {
+                        let cluster = address - in_cluster;
+                        let host_cluster = mapping.offset - in_cluster;
+                        state.copying.insert(cluster, Vec::new());
+                        request.copying.push(cluster);
+
+                        // The new cluster is zeroed, only the parts of the
+                        // backing file holding data are copied over.
+                        for (start, end) in [(0, in_cluster), (in_cluster + count, cluster_size)] {
+                            if start == end {
+                                continue;
+                            }
+                            let mut mappings = Vec::new();
+                            state.file.map_backing_read(
+                                cluster + start,
+                                (end - start) as usize,
+                                &mut mappings,
+                            )?;
+
+                            let mut copy_pos = host_cluster + start;
+                            for mapping in mappings {
+                                let len = mapping.len();
+                                match mapping {
+                                    ReadMapping::Host {
+                                        fd: src, offset, ..
+                                    } => {
+                                        let buffer = BounceBuffer::new(len);
+                                        let read = request
+                                            .rw(false, src, offset, buffer.iovecs())
+                                            .flags(squeue::Flags::IO_LINK);
+                                        let write = request.rw(true, fd, copy_pos, buffer.iovecs());
+                                        request.buffers.push(buffer);
+                                        chains.push(vec![read, write]);
+                                    }
+                                    ReadMapping::Zero { .. } => {}
+                                    ReadMapping::Data(data) => {
+                                        let buffer = BounceBuffer::new(len);
+                                        // SAFETY: the buffer was just allocated
+                                        // with the length of the data.
+                                        unsafe {
+                                            std::ptr::copy_nonoverlapping(
+                                                data.as_ptr(),
+                                                buffer.ptr,
+                                                len,
+                                            );
+                                        };
+                                        let write = request.rw(true, fd, copy_pos, buffer.iovecs());
+                                        request.buffers.push(buffer);
+                                        chains.push(vec![write]);
+                                    }
+                                }
+                                copy_pos += len as u64;
+                            }
+                        }
+                    }
+
+                    pos += count;
+                }
+
+                for (pos, host, len) in writes {
+                    let slice = Iovecs(slice_iovecs(&iovecs.0, pos as usize, len as usize));
+                    chains.push(vec![request.rw(true, fd, host, slice)]);
+                }
+            }
+            Operation::Fsync => {
+                request.write_back = Some(state.file.write_back_metadata()?);
+                state.syncing = Some(Vec::new());
+                chains.push(vec![opcode::Fsync::new(types::Fd(fd)).build()]);
+            }
+            Operation::PunchHole { offset, length } => state.file.punch_hole(*offset, *length)?,
+        }
+        Ok(chains)
+    }
+
+    /// Pushes the chains of io_uring operations of request `id` and submits
+    /// them.
+    fn push(&mut self, id: u64, chains: Vec<Vec<squeue::Entry>>) -> io::Result<()> {
+        if chains.is_empty() {
+            return Ok(());
+        }
+
+        let (submitter, mut sq, _) = self.io_uring.split();
+        let request = self.requests.get_mut(&id).unwrap();
+        for chain in chains {
+            let chain: Vec<squeue::Entry> =
+                chain.into_iter().map(|entry| entry.user_data(id)).collect();
+
+            // A chain must be submitted as a whole, make room for it.
+            if sq.capacity() - sq.len() < chain.len() {
+                sq.sync();
+                submitter.submit()?;
+                sq.sync();
+            }
+
+            // SAFETY: we know the file descriptors are valid, the guest
+            // buffers are provided by vm-memory and the other buffers belong
+            // to the request, which lives until its operations complete.
+            unsafe { sq.push_multiple(&chain) }
+                .map_err(|_| io::Error::other("Submission queue is full"))?;
+            request.pending += chain.len();
+        }
+
+        // Update the submission queue and submit new operations to the
+        // io_uring instance.
+        sq.sync();
+        submitter.submit()?;
+
+        Ok(())
+    }
+
+    /// Completes the guest request, releasing the clusters it copied or
+    /// allocated and the metadata it flushed.
+    fn finish(&mut self, request: Request) {
+        let Request {
+            user_data,
+            result,
+            copying,
+            allocating,
+            write_back,
+            ..
+        } = request;
+        if !copying.is_empty() || !allocating.is_empty() || write_back.is_some() {
+            let mut state = self.state.lock().unwrap();
+            state.copied(&copying);
+            state.allocated(&allocating);
+            if let Some(write_back) = write_back {
+                state.file.complete_write_back(write_back, result >= 0);
+                state.synced();
+            }
+        }
+        self.completion_list.push_back((user_data, result));
+    }
+
+    /// Writes the tables of the metadata flushed by request `id` once the
+    /// clusters they point to are synced, and syncs them in turn. Returns
+    /// whether the request has operations in flight again.
+    fn write_back_tables(&mut self, id: u64) -> bool {
+        let request = self.requests.get_mut(&id).unwrap();
+        let Some(write_back) = request.write_back.as_mut() else {
+            return false;
+        };
+        if request.result < 0 {
+            return false;
+        }
+
+        let sync = self
+            .state
+            .lock()
+            .unwrap()
+            .file
+            .write_back_tables(write_back);
+        match sync {
+            Ok(false) => false,
+            Ok(true) => {
+                let fsync = opcode::Fsync::new(types::Fd(self.fd))
+                    .flags(types::FsyncFlags::DATASYNC)
+                    .build();
+                if let Err(e) = self.push(id, vec![vec![fsync]]) {
+                    self.requests.get_mut(&id).unwrap().fail(&e);
+                }
+                self.requests[&id].pending > 0
+            }
+            Err(e) => {
+                request.fail(&e);
+                false
+            }
+        }
+    }
+
+    /// Collects the completed io_uring operations and the guest requests
+    /// they complete.
+    fn reap(&mut self) {
+        let mut finished = Vec::new();
+        for entry in self.io_uring.completion() {
+            let id = entry.user_data();
+            let Some(request) = self.requests.get_mut(&id) else {
+                continue;
+            };
+            request.pending -= 1;
+            if entry.result() < 0 && request.result >= 0 {
+                request.result = entry.result();
+            }
+            if request.pending == 0 {
+                finished.push(id);
+            }
+        }
+
+        for id in finished {
+            if self.write_back_tables(id) {
+                continue;
+            }
+            let request = self.requests.remove(&id).unwrap();
+            self.finish(request);
+        }
+    }
+}
+
+impl AsyncIo for QcowAsync {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn alignment(&self) -> u64 {
+        self.alignment
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.submit(
+            user_data,
+            Operation::Read {
+                offset: offset as u64,
+                iovecs: Iovecs(iovecs.to_vec()),
+            },
+        );
+        Ok(())
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.submit(
+            user_data,
+            Operation::Write {
+                offset: offset as u64,
+                iovecs: Iovecs(iovecs.to_vec()),
+            },
+        );
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        if let Some(user_data) = user_data {
+            self.submit(user_data, Operation::Fsync);
+        } else {
+            // Ignore the result, there is no request to report it to.
+            let _ = self.state.lock().unwrap().file.flush();
+        }
+        Ok(())
+    }
+
+    fn punch_hole(&mut self, offset: u64, length: u64, user_data: u64) -> AsyncIoResult<()> {
+        // For QCOW2, punch_hole calls deallocate_cluster
+        self.submit(user_data, Operation::PunchHole { offset, length });
+        Ok(())
+    }
+
+    fn write_zeroes(&mut self, offset: u64, length: u64, user_data: u64) -> AsyncIoResult<()> {
+        // Unallocated clusters read as zero, so write_zeroes deallocates
+        // them like punch_hole.
+        self.submit(user_data, Operation::PunchHole { offset, length });
+        Ok(())
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.reap();
+        if self.completion_list.is_empty() && !self.blocked.is_empty() {
+            // Retry the requests that waited for a cluster, they queue
+            // themselves again if it's still being copied.
+            for (user_data, operation) in std::mem::take(&mut self.blocked) {
+                self.submit(user_data, operation);
+            }
+        }
+        self.completion_list.pop_front()
+    }
+}
+
+/// Returns the iovecs of `len` bytes of `iovecs` from `start`.
+fn slice_iovecs(iovecs: &[libc::iovec], mut start: usize, mut len: usize) -> Vec<libc::iovec> {
+    let mut slice = Vec::new();
+    for iovec in iovecs {
+        if len == 0 {
+            break;
+        }
+        if start >= iovec.iov_len {
+            start -= iovec.iov_len;
+            continue;
+        }
+        let count = min(iovec.iov_len - start, len);
+        slice.push(libc::iovec {
+            iov_base: (iovec.iov_base as *mut u8).wrapping_add(start) as *mut libc::c_void,
+            iov_len: count,
+        });
+        len -= count;
+        start = 0;
+    }
+    slice
+}
+
+/// Fills `len` bytes of `iovecs` from `start` with `data`, or with zeros.
+fn fill_iovecs(iovecs: &[libc::iovec], start: usize, len: usize, data: Option<&[u8]>) {
+    let mut filled = 0;
+    for iovec in slice_iovecs(iovecs, start, len) {
+        let dst = iovec.iov_base as *mut u8;
+        // SAFETY: the iovec is a part of a guest buffer provided by vm-memory
+        // and, for data, the mapping holds exactly `len` bytes.
+        unsafe {
+            match data {
+                Some(data) => {
+                    std::ptr::copy_nonoverlapping(data[filled..].as_ptr(), dst, iovec.iov_len);
+                }
+                None => std::ptr::write_bytes(dst, 0, iovec.iov_len),
+            }
+        };
+        filled += iovec.iov_len;
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::io::{Read, Write};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+    use crate::block_io_uring_is_supported;
+    use crate::qcow::{BackingFileConfig, ImageType};
+
+    const CLUSTER_SIZE: usize = 64 * 1024;
+
+    fn qcow_disk(qcow_file: QcowFile) -> QcowDiskAsync {
+        QcowDiskAsync {
+            state: Arc::new(Mutex::new(QcowState {
+                file: qcow_file,
+                copying: HashMap::new(),
+                allocating: HashMap::new(),
+                syncing: None,
+            })),
+            alignment: SECTOR_SIZE,
+        }
+    }
+
+    fn iovec(buf: &mut [u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }
+
+    fn wait_for_completion(async_io: &mut dyn AsyncIo, user_data: u64) -> i32 {
+        for _ in 0..5000 {
+            if let Some((completed, result)) = async_io.next_completed_request() {
+                assert_eq!(completed, user_data);
+                return result;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        panic!("Request {user_data} did not complete");
+    }
+
+    fn write(async_io: &mut dyn AsyncIo, offset: u64, buf: &mut [u8], user_data: u64) {
+        let len = buf.len() as i32;
+        async_io
+            .write_vectored(offset as libc::off_t, &[iovec(buf)], user_data)
+            .unwrap();
+        assert_eq!(wait_for_completion(async_io, user_data), len);
+    }
+
+    fn read(async_io: &mut dyn AsyncIo, offset: u64, buf: &mut [u8], user_data: u64) {
+        let len = buf.len() as i32;
+        async_io
+            .read_vectored(offset as libc::off_t, &[iovec(buf)], user_data)
+            .unwrap();
+        assert_eq!(wait_for_completion(async_io, user_data), len);
+    }
+
+    #[test]
+    fn test_qcow_async_read_write() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let temp_file = TempFile::new().unwrap();
+        let raw_file = RawFile::new(temp_file.into_file(), false);
+        let qcow_file = QcowFile::new(raw_file, 3, 1024 * 1024 * 100, true).unwrap();
+        let disk = qcow_disk(qcow_file);
+        let mut async_io = disk.new_async_io(16).unwrap();
+
+        // Unallocated clusters read as zeros
+        let mut buf = vec![0xFF; CLUSTER_SIZE];
+        read(async_io.as_mut(), 0, &mut buf, 1);
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // Write across a cluster boundary, split in two iovecs
+        let offset = CLUSTER_SIZE as u64 - 4096;
+        let mut first = vec![0x11; 4096];
+        let mut second = vec![0x22; 8192];
+        async_io
+            .write_vectored(
+                offset as libc::off_t,
+                &[iovec(&mut first), iovec(&mut second)],
+                2,
+            )
+            .unwrap();
+        assert_eq!(wait_for_completion(async_io.as_mut(), 2), 12288);
+
+        let mut buf = vec![0; 3 * 4096];
+        read(async_io.as_mut(), offset, &mut buf, 3);
+        assert!(buf[..4096].iter().all(|&b| b == 0x11));
+        assert!(buf[4096..].iter().all(|&b| b == 0x22));
+
+        // Another queue sees the same data
+        let mut other_io = disk.new_async_io(16).unwrap();
+        let mut buf = vec![0; 4096];
+        read(other_io.as_mut(), CLUSTER_SIZE as u64, &mut buf, 4);
+        assert!(buf.iter().all(|&b| b == 0x22));
+
+        // Flush and discard
+        async_io.fsync(Some(5)).unwrap();
+        assert_eq!(wait_for_completion(async_io.as_mut(), 5), 0);
+        async_io.punch_hole(0, 2 * CLUSTER_SIZE as u64, 6).unwrap();
+        assert_eq!(wait_for_completion(async_io.as_mut(), 6), 0);
+        let mut buf = vec![0xFF; 3 * 4096];
+        read(async_io.as_mut(), offset, &mut buf, 7);
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_qcow_async_backing_copy() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let file_size = 4 * CLUSTER_SIZE as u64;
+        let backing_temp = TempFile::new().unwrap();
+        let mut backing_file = backing_temp.as_file().try_clone().unwrap();
+        backing_file
+            .write_all(&vec![0xAB; file_size as usize])
+            .unwrap();
+
+        let overlay_temp = TempFile::new().unwrap();
+        let overlay_file = overlay_temp.as_file().try_clone().unwrap();
+        let qcow_file = QcowFile::new_from_backing(
+            RawFile::new(overlay_file, false),
+            3,
+            file_size,
+            &BackingFileConfig {
+                path: backing_temp.as_path().to_str().unwrap().to_string(),
+                format: Some(ImageType::Raw),
+            },
+            true,
+        )
+        .unwrap();
+        let disk = qcow_disk(qcow_file);
+        let mut async_io = disk.new_async_io(16).unwrap();
+
+        // Reads go to the backing file
+        let mut buf = vec![0; 4096];
+        read(async_io.as_mut(), CLUSTER_SIZE as u64, &mut buf, 1);
+        assert!(buf.iter().all(|&b| b == 0xAB));
+
+        // Write the middle of a cluster, the rest of it comes from the
+        // backing file
+        let mut data = vec![0xCD; 8192];
+        write(async_io.as_mut(), CLUSTER_SIZE as u64 + 4096, &mut data, 2);
+        let mut buf = vec![0; CLUSTER_SIZE];
+        read(async_io.as_mut(), CLUSTER_SIZE as u64, &mut buf, 3);
+        assert!(buf[..4096].iter().all(|&b| b == 0xAB));
+        assert!(buf[4096..12288].iter().all(|&b| b == 0xCD));
+        assert!(buf[12288..].iter().all(|&b| b == 0xAB));
+
+        async_io.fsync(Some(4)).unwrap();
+        assert_eq!(wait_for_completion(async_io.as_mut(), 4), 0);
+        drop(async_io);
+        drop(disk);
+
+        // The copy is in the image itself
+        let mut qcow_file = QcowFile::from(RawFile::new(
+            overlay_temp.as_file().try_clone().unwrap(),
+            false,
+        ))
+        .unwrap();
+        let mut buf = vec![0; CLUSTER_SIZE];
+        qcow_file
+            .seek(SeekFrom::Start(CLUSTER_SIZE as u64))
+            .unwrap();
+        qcow_file.read_exact(&mut buf).unwrap();
+        assert!(buf[..4096].iter().all(|&b| b == 0xAB));
+        assert!(buf[4096..12288].iter().all(|&b| b == 0xCD));
+        assert!(buf[12288..].iter().all(|&b| b == 0xAB));
+    }
+
+    #[test]
+    fn test_qcow_async_wait_for_copy() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let temp_file = TempFile::new().unwrap();
+        let raw_file = RawFile::new(temp_file.into_file(), false);
+        let qcow_file = QcowFile::new(raw_file, 3, 1024 * 1024, true).unwrap();
+        let disk = qcow_disk(qcow_file);
+        let mut async_io = disk.new_async_io(16).unwrap();
+
+        // Requests touching a cluster being copied wait for it
+        disk.state.lock().unwrap().copying.insert(0, Vec::new());
+        let mut buf = vec![0xFF; 4096];
+        async_io.read_vectored(512, &[iovec(&mut buf)], 1).unwrap();
+        async_io.fsync(Some(2)).unwrap();
+        async_io.punch_hole(CLUSTER_SIZE as u64, 4096, 3).unwrap();
+        assert_eq!(wait_for_completion(async_io.as_mut(), 3), 0);
+        assert!(async_io.next_completed_request().is_none());
+
+        // And complete once it is copied, the flush going through io_uring
+        disk.state.lock().unwrap().copied(&[0]);
+        async_io.notifier().read().unwrap();
+        let mut completed = Vec::new();
+        for _ in 0..5000 {
+            if completed.len() == 2 {
+                break;
+            }
+            match async_io.next_completed_request() {
+                Some(request) => completed.push(request),
+                None => sleep(Duration::from_millis(1)),
+            }
+        }
+        completed.sort_unstable();
+        assert_eq!(completed, vec![(1, 4096), (2, 0)]);
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_qcow_async_fsync() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let temp_file = TempFile::new().unwrap();
+        let raw_file = RawFile::new(temp_file.as_file().try_clone().unwrap(), false);
+        let qcow_file = QcowFile::new(raw_file, 3, 1024 * 1024, true).unwrap();
+        let disk = qcow_disk(qcow_file);
+        let mut async_io = disk.new_async_io(16).unwrap();
+        let mut other_io = disk.new_async_io(16).unwrap();
+
+        // A flush waits for the writes allocating clusters
+        disk.state.lock().unwrap().allocating.insert(0, Vec::new());
+        async_io.fsync(Some(1)).unwrap();
+        assert!(async_io.next_completed_request().is_none());
+        disk.state.lock().unwrap().allocated(&[0]);
+        async_io.notifier().read().unwrap();
+        assert_eq!(wait_for_completion(async_io.as_mut(), 1), 0);
+
+        // And for the flush of another queue
+        disk.state.lock().unwrap().syncing = Some(Vec::new());
+        other_io.fsync(Some(2)).unwrap();
+        assert!(other_io.next_completed_request().is_none());
+        disk.state.lock().unwrap().synced();
+        other_io.notifier().read().unwrap();
+        assert_eq!(wait_for_completion(other_io.as_mut(), 2), 0);
+
+        // The allocated clusters are in the image once flushed
+        let mut data = vec![0xCD; 4096];
+        write(async_io.as_mut(), CLUSTER_SIZE as u64, &mut data, 3);
+        assert!(disk.state.lock().unwrap().allocating.is_empty());
+        async_io.fsync(Some(4)).unwrap();
+        assert_eq!(wait_for_completion(async_io.as_mut(), 4), 0);
+        assert!(disk.state.lock().unwrap().syncing.is_none());
+
+        let mut qcow_file = QcowFile::from(RawFile::new(
+            temp_file.as_file().try_clone().unwrap(),
+            false,
+        ))
+        .unwrap();
+        let mut buf = vec![0; 4096];
+        qcow_file
+            .seek(SeekFrom::Start(CLUSTER_SIZE as u64))
+            .unwrap();
+        qcow_file.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xCD));
+    }
+}
@@ -13,7 +13,9 @@ use vmm_sys_util::write_zeroes::PunchHole;
 
 use crate::async_io::{
     AsyncIo, AsyncIoError, AsyncIoResult, BorrowedDiskFd, DiskFile, DiskFileError, DiskFileResult,
+    DiskSnapshot,
 };
+use crate::dirty_bitmap::DirtyBitmap;
 use crate::qcow::{Error as QcowError, MAX_NESTING_DEPTH, QcowFile, RawFile, Result as QcowResult};
 use crate::{AsyncAdaptor, BlockBackend};
 
@@ -45,6 +47,16 @@ impl QcowDiskSync {
             qcow_file: Arc::new(Mutex::new(qcow_file)),
         })
     }
+
+    /// Returns true if the data of the image is encrypted, needing [`Self::unlock`].
+    pub fn is_encrypted(&self) -> bool {
+        self.qcow_file.lock().unwrap().is_encrypted()
+    }
+
+    /// Unlocks the data of an encrypted image with `key`.
+    pub fn unlock(&mut self, key: &[u8]) -> QcowResult<()> {
+        self.qcow_file.lock().unwrap().unlock(key)
+    }
 }
 
 impl DiskFile for QcowDiskSync {
@@ -78,6 +90,57 @@ impl DiskFile for QcowDiskSync {
             .map_err(|e| DiskFileError::ResizeError(io::Error::other(e)))
     }
 
+    fn snapshots(&mut self) -> DiskFileResult<Vec<DiskSnapshot>> {
+        Ok(self
+            .qcow_file
+            .lock()
+            .unwrap()
+            .snapshots()
+            .iter()
+            .map(DiskSnapshot::from)
+            .collect())
+    }
+
+    fn create_snapshot(&mut self, name: &str, vm_clock_nsec: u64) -> DiskFileResult<()> {
+        self.qcow_file
+            .lock()
+            .unwrap()
+            .create_snapshot(name, vm_clock_nsec)
+            .map_err(|e| DiskFileError::SnapshotError(io::Error::other(e)))
+    }
+
+    fn apply_snapshot(&mut self, id_or_name: &str) -> DiskFileResult<()> {
+        self.qcow_file
+            .lock()
+            .unwrap()
+            .apply_snapshot(id_or_name)
+            .map_err(|e| DiskFileError::SnapshotError(io::Error::other(e)))
+    }
+
+    fn delete_snapshot(&mut self, id_or_name: &str) -> DiskFileResult<()> {
+        self.qcow_file
+            .lock()
+            .unwrap()
+            .delete_snapshot(id_or_name)
+            .map_err(|e| DiskFileError::SnapshotError(io::Error::other(e)))
+    }
+
+    fn load_dirty_bitmap(&mut self, name: &str, granularity: u64) -> DiskFileResult<DirtyBitmap> {
+        self.qcow_file
+            .lock()
+            .unwrap()
+            .load_dirty_bitmap(name, granularity)
+            .map_err(|e| DiskFileError::DirtyBitmapError(io::Error::other(e)))
+    }
+
+    fn store_dirty_bitmap(&mut self, name: &str, bitmap: &DirtyBitmap) -> DiskFileResult<()> {
+        self.qcow_file
+            .lock()
+            .unwrap()
+            .store_dirty_bitmap(name, bitmap)
+            .map_err(|e| DiskFileError::DirtyBitmapError(io::Error::other(e)))
+    }
+
     fn supports_sparse_operations(&self) -> bool {
         true
     }
@@ -501,4 +564,42 @@ mod unit_tests {
             Ok(_) => panic!("Expected BackingFilesDisabled error, but succeeded"),
         }
     }
+
+    #[test]
+    fn encrypted_disk_unlock() {
+        use crate::luks::{LuksCreateOptions, LuksKdf};
+
+        let temp_file = TempFile::new().unwrap();
+        let options = LuksCreateOptions {
+            kdf: LuksKdf::Pbkdf2 { iterations: 1000 },
+            digest_iterations: 1000,
+            ..Default::default()
+        };
+        let raw_file = RawFile::new(temp_file.as_file().try_clone().unwrap(), false);
+        QcowFile::new_encrypted(raw_file, 0x10_0000, true, &[b"key"], &options).unwrap();
+
+        let mut disk =
+            QcowDiskSync::new(temp_file.as_file().try_clone().unwrap(), false, true, true).unwrap();
+        assert!(disk.is_encrypted());
+        assert!(disk.unlock(b"wrong").is_err());
+        disk.unlock(b"key").unwrap();
+
+        let mut async_io = disk.new_async_io(1).unwrap();
+        let mut data = vec![0xABu8; 4096];
+        let iovec = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        async_io.write_vectored(0x1000, &[iovec], 1).unwrap();
+        assert_eq!(async_io.next_completed_request(), Some((1, 4096)));
+
+        let mut read_buf = vec![0u8; 4096];
+        let iovec = libc::iovec {
+            iov_base: read_buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: read_buf.len(),
+        };
+        async_io.read_vectored(0x1000, &[iovec], 2).unwrap();
+        assert_eq!(async_io.next_completed_request(), Some((2, 4096)));
+        assert_eq!(read_buf, data);
+    }
 }
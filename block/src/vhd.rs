@@ -7,6 +7,17 @@ use std::io::{Seek, SeekFrom};
 
 use crate::{DiskTopology, read_aligned_block_size};
 
+/// "conectix"
+pub const VHD_COOKIE: u64 = 0x636f_6e65_6374_6978;
+pub const VHD_VERSION: u32 = 0x0001_0000;
+pub const VHD_FOOTER_SIZE: usize = 512;
+pub const FOOTER_CHECKSUM_OFFSET: usize = 64;
+
+// Disk types
+pub const DISK_TYPE_FIXED: u32 = 2;
+pub const DISK_TYPE_DYNAMIC: u32 = 3;
+pub const DISK_TYPE_DIFFERENCING: u32 = 4;
+
 #[derive(Clone, Copy)]
 pub struct VhdFooter {
     cookie: u64,
@@ -37,9 +48,12 @@ impl VhdFooter {
 
         // We only care about the last sector
         let offset = blocksize - 512;
-        let sector = &data[offset..];
+        Ok(VhdFooter::from_sector(&data[offset..]))
+    }
 
-        Ok(VhdFooter {
+    /// Parse the footer found in `sector`.
+    pub fn from_sector(sector: &[u8]) -> VhdFooter {
+        VhdFooter {
             cookie: u64::from_be_bytes(sector[0..8].try_into().unwrap()),
             features: u32::from_be_bytes(sector[8..12].try_into().unwrap()),
             file_format_version: u32::from_be_bytes(sector[12..16].try_into().unwrap()),
@@ -55,7 +69,14 @@ impl VhdFooter {
             checksum: u32::from_be_bytes(sector[64..68].try_into().unwrap()),
             unique_id: u128::from_be_bytes(sector[68..84].try_into().unwrap()),
             saved_state: u8::from_be_bytes(sector[84..85].try_into().unwrap()),
-        })
+        }
+    }
+
+    /// Returns true if the footer is valid for a VHD of type `disk_type`.
+    fn is_valid(&self, disk_type: u32) -> bool {
+        self.cookie == VHD_COOKIE
+            && self.file_format_version == VHD_VERSION
+            && self.disk_type == disk_type
     }
 
     pub fn cookie(&self) -> u64 {
@@ -105,15 +126,41 @@ impl VhdFooter {
     }
 }
 
+/// Computes the checksum of a footer or of a dynamic disk header, the one's
+/// complement of the sum of its bytes but the checksum ones at
+/// `checksum_offset`.
+pub fn checksum(data: &[u8], checksum_offset: usize) -> u32 {
+    let sum = data
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(checksum_offset..checksum_offset + 4).contains(i))
+        .fold(0u32, |sum, (_, &b)| sum.wrapping_add(u32::from(b)));
+    !sum
+}
+
+/// Computes and stores the checksum of the footer in `sector`.
+pub fn set_footer_checksum(sector: &mut [u8]) {
+    let checksum = checksum(sector, FOOTER_CHECKSUM_OFFSET);
+    sector[FOOTER_CHECKSUM_OFFSET..FOOTER_CHECKSUM_OFFSET + 4]
+        .copy_from_slice(&checksum.to_be_bytes());
+}
+
 /// Determine image type through file parsing.
 pub fn is_fixed_vhd(f: &mut File) -> std::io::Result<bool> {
     let footer = VhdFooter::new(f)?;
 
-    // "conectix" => 0x636f6e6563746978
-    Ok(footer.cookie() == 0x636f6e6563746978
-        && footer.file_format_version() == 0x0001_0000
-        && footer.data_offset() == 0xffff_ffff_ffff_ffff
-        && footer.disk_type() == 0x2)
+    Ok(footer.is_valid(DISK_TYPE_FIXED) && footer.data_offset() == 0xffff_ffff_ffff_ffff)
+}
+
+/// Returns true if `f` is a dynamic or a differencing VHD, whose blocks are
+/// allocated on write.
+pub fn is_dynamic_vhd(f: &mut File) -> std::io::Result<bool> {
+    let footer = VhdFooter::new(f)?;
+
+    Ok(
+        (footer.is_valid(DISK_TYPE_DYNAMIC) || footer.is_valid(DISK_TYPE_DIFFERENCING))
+            && footer.data_offset() != 0xffff_ffff_ffff_ffff,
+    )
 }
 
 #[cfg(test)]
@@ -123,7 +170,7 @@ mod unit_tests {
 
     use vmm_sys_util::tempfile::TempFile;
 
-    use super::{VhdFooter, is_fixed_vhd};
+    use super::{VhdFooter, checksum, is_dynamic_vhd, is_fixed_vhd};
 
     fn valid_fixed_vhd_footer() -> Vec<u8> {
         vec![
@@ -217,4 +264,26 @@ mod unit_tests {
             assert!(!(is_fixed_vhd(&mut file).unwrap()));
         });
     }
+
+    #[test]
+    fn test_is_dynamic_vhd() {
+        with_file(&valid_dynamic_vhd_footer(), |mut file: File| {
+            assert!(is_dynamic_vhd(&mut file).unwrap());
+        });
+        with_file(&valid_fixed_vhd_footer(), |mut file: File| {
+            assert!(!(is_dynamic_vhd(&mut file).unwrap()));
+        });
+    }
+
+    #[test]
+    fn test_checksum() {
+        let mut footer = valid_fixed_vhd_footer();
+        footer.resize(512, 0);
+        let sum = checksum(&footer, 64);
+        footer[64..68].copy_from_slice(&sum.to_be_bytes());
+        // The checksum doesn't cover itself.
+        assert_eq!(checksum(&footer, 64), sum);
+        footer[0] ^= 1;
+        assert_ne!(checksum(&footer, 64), sum);
+    }
 }
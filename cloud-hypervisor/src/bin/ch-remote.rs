@@ -14,7 +14,7 @@ use std::process;
 
 use api_client::{
     Error as ApiClientError, simple_api_command, simple_api_command_with_fds,
-    simple_api_full_command,
+    simple_api_full_command, simple_api_full_command_and_response,
 };
 use clap::{Arg, ArgAction, ArgMatches, Command};
 use log::error;
@@ -22,8 +22,8 @@ use option_parser::{ByteSized, ByteSizedParseError};
 use thiserror::Error;
 use vmm::config::RestoreConfig;
 use vmm::vm_config::{
-    DeviceConfig, DiskConfig, FsConfig, GenericVhostUserConfig, NetConfig, PmemConfig,
-    UserDeviceConfig, VdpaConfig, VsockConfig,
+    DeviceConfig, DiskConfig, FsConfig, GenericVhostUserConfig, GpuConfig, NetConfig, PmemConfig,
+    UserDeviceConfig, VdpaConfig, VirtioInputConfig, VsockConfig,
 };
 #[cfg(feature = "dbus_api")]
 use zbus::{proxy, zvariant::Optional};
@@ -61,6 +61,10 @@ enum Error {
     AddVdpaConfig(#[source] vmm::config::Error),
     #[error("Error parsing vsock syntax")]
     AddVsockConfig(#[source] vmm::config::Error),
+    #[error("Error parsing gpu syntax")]
+    AddGpuConfig(#[source] vmm::config::Error),
+    #[error("Error parsing input syntax")]
+    AddInputConfig(#[source] vmm::config::Error),
     #[error("Error parsing restore syntax")]
     Restore(#[source] vmm::config::Error),
     #[error("Error reading from stdin")]
@@ -69,6 +73,28 @@ enum Error {
     ReadingFile(#[source] std::io::Error),
     #[error("Invalid disk size")]
     InvalidDiskSize(#[source] ByteSizedParseError),
+    #[error("Error parsing scanout")]
+    InvalidScanout(#[source] std::num::ParseIntError),
+    #[error("Invalid display resolution: {0}")]
+    InvalidDisplayResolution(String),
+    #[error("Invalid frame encoding: {0}")]
+    InvalidFrameEncoding(String),
+    #[error("Error parsing the frame snapshot")]
+    FrameSnapshotResponse(#[source] serde_json::Error),
+    #[error("Error decoding the frame snapshot")]
+    FrameSnapshotDecode(#[source] vmm::frame_export::FrameExportError),
+    #[error("Error writing the frame snapshot")]
+    FrameSnapshotWrite(#[source] std::io::Error),
+    #[error("Error parsing frame rate")]
+    InvalidFrameRate(#[source] std::num::ParseIntError),
+    #[error("Invalid video container: {0}")]
+    InvalidVideoContainer(String),
+    #[error("Invalid keyboard layout: {0}")]
+    InvalidKeyboardLayout(String),
+    #[error("Error parsing key delay")]
+    InvalidKeyDelay(#[source] std::num::ParseIntError),
+    #[error("Error parsing replay speed")]
+    InvalidReplaySpeed(#[source] std::num::ParseFloatError),
 }
 
 enum TargetApi<'a> {
@@ -89,6 +115,8 @@ trait DBusApi1 {
         &self,
         generic_vhost_user_config: &str,
     ) -> zbus::Result<Optional<String>>;
+    fn vm_add_gpu(&self, gpu_config: &str) -> zbus::Result<Optional<String>>;
+    fn vm_add_input(&self, input_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_net(&self, net_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_pmem(&self, pmem_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_user_device(&self, vm_add_user_device: &str) -> zbus::Result<Optional<String>>;
@@ -169,6 +197,14 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
         self.print_response(self.vm_add_net(net_config))
     }
 
+    fn api_vm_add_gpu(&self, gpu_config: &str) -> ApiResult {
+        self.print_response(self.vm_add_gpu(gpu_config))
+    }
+
+    fn api_vm_add_input(&self, input_config: &str) -> ApiResult {
+        self.print_response(self.vm_add_input(input_config))
+    }
+
     fn api_vm_add_pmem(&self, pmem_config: &str) -> ApiResult {
         self.print_response(self.vm_add_pmem(pmem_config))
     }
@@ -348,6 +384,133 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
             simple_api_command(socket, "PUT", "resize-disk", Some(&resize_disk))
                 .map_err(Error::HttpApiClient)
         }
+        Some("disk-internal-snapshot-list") => {
+            let snapshot_list = disk_internal_snapshot_list_data(
+                matches
+                    .subcommand_matches("disk-internal-snapshot-list")
+                    .unwrap()
+                    .get_one::<String>("disk")
+                    .unwrap(),
+            );
+            simple_api_command(
+                socket,
+                "PUT",
+                "disk-internal-snapshot.list",
+                Some(&snapshot_list),
+            )
+            .map_err(Error::HttpApiClient)
+        }
+        Some("disk-internal-snapshot-create") => {
+            let subcommand = matches
+                .subcommand_matches("disk-internal-snapshot-create")
+                .unwrap();
+            let snapshot = disk_internal_snapshot_data(
+                subcommand.get_one::<String>("disk").unwrap(),
+                subcommand.get_one::<String>("name").unwrap(),
+            );
+            simple_api_command(
+                socket,
+                "PUT",
+                "disk-internal-snapshot.create",
+                Some(&snapshot),
+            )
+            .map_err(Error::HttpApiClient)
+        }
+        Some("disk-internal-snapshot-apply") => {
+            let subcommand = matches
+                .subcommand_matches("disk-internal-snapshot-apply")
+                .unwrap();
+            let snapshot = disk_internal_snapshot_data(
+                subcommand.get_one::<String>("disk").unwrap(),
+                subcommand.get_one::<String>("name").unwrap(),
+            );
+            simple_api_command(
+                socket,
+                "PUT",
+                "disk-internal-snapshot.apply",
+                Some(&snapshot),
+            )
+            .map_err(Error::HttpApiClient)
+        }
+        Some("disk-internal-snapshot-delete") => {
+            let subcommand = matches
+                .subcommand_matches("disk-internal-snapshot-delete")
+                .unwrap();
+            let snapshot = disk_internal_snapshot_data(
+                subcommand.get_one::<String>("disk").unwrap(),
+                subcommand.get_one::<String>("name").unwrap(),
+            );
+            simple_api_command(
+                socket,
+                "PUT",
+                "disk-internal-snapshot.delete",
+                Some(&snapshot),
+            )
+            .map_err(Error::HttpApiClient)
+        }
+        Some("disk-snapshot") => {
+            let subcommand = matches.subcommand_matches("disk-snapshot").unwrap();
+            let snapshot = disk_snapshot_data(
+                subcommand.get_one::<String>("disk").unwrap(),
+                subcommand.get_one::<String>("overlay").unwrap(),
+            );
+            simple_api_command(socket, "PUT", "disk-snapshot", Some(&snapshot))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("disk-backup") => {
+            let subcommand = matches.subcommand_matches("disk-backup").unwrap();
+            let backup = disk_backup_data(
+                subcommand.get_one::<String>("disk").unwrap(),
+                subcommand.get_one::<String>("target").unwrap(),
+                subcommand.get_flag("incremental"),
+            );
+            simple_api_command(socket, "PUT", "disk-backup", Some(&backup))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("disk-backup-cancel") => {
+            let backup = disk_backup_job_data(
+                matches
+                    .subcommand_matches("disk-backup-cancel")
+                    .unwrap()
+                    .get_one::<String>("disk")
+                    .unwrap(),
+            );
+            simple_api_command(socket, "PUT", "disk-backup.cancel", Some(&backup))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("disk-backup-status") => {
+            let backup = disk_backup_job_data(
+                matches
+                    .subcommand_matches("disk-backup-status")
+                    .unwrap()
+                    .get_one::<String>("disk")
+                    .unwrap(),
+            );
+            simple_api_command(socket, "PUT", "disk-backup.status", Some(&backup))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("disk-commit") => {
+            let commit = disk_commit_data(
+                matches
+                    .subcommand_matches("disk-commit")
+                    .unwrap()
+                    .get_one::<String>("disk")
+                    .unwrap(),
+            );
+            simple_api_command(socket, "PUT", "disk-commit", Some(&commit))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("disk-commit-status") => {
+            let commit = disk_commit_data(
+                matches
+                    .subcommand_matches("disk-commit-status")
+                    .unwrap()
+                    .get_one::<String>("disk")
+                    .unwrap(),
+            );
+            simple_api_command(socket, "PUT", "disk-commit.status", Some(&commit))
+                .map_err(Error::HttpApiClient)
+        }
         Some("resize-zone") => {
             let resize_zone = resize_zone_config(
                 matches
@@ -364,6 +527,140 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
             simple_api_command(socket, "PUT", "resize-zone", Some(&resize_zone))
                 .map_err(Error::HttpApiClient)
         }
+        Some("display-change") => {
+            let display_change = display_change_config(
+                matches
+                    .subcommand_matches("display-change")
+                    .unwrap()
+                    .get_one::<String>("scanout")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("display-change")
+                    .unwrap()
+                    .get_one::<String>("resolution")
+                    .map(|x| x as &str),
+            )?;
+            simple_api_command(socket, "PUT", "display-change", Some(&display_change))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("frame-snapshot") => {
+            let frame_snapshot = frame_snapshot_data(
+                matches
+                    .subcommand_matches("frame-snapshot")
+                    .unwrap()
+                    .get_one::<String>("scanout")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("frame-snapshot")
+                    .unwrap()
+                    .get_one::<String>("encoding")
+                    .unwrap(),
+            )?;
+            let response = simple_api_full_command_and_response(
+                socket,
+                "GET",
+                "vm.frame-capture.snapshot",
+                Some(&frame_snapshot),
+            )
+            .map_err(Error::HttpApiClient)?;
+            save_frame_snapshot(
+                response.as_deref(),
+                matches
+                    .subcommand_matches("frame-snapshot")
+                    .unwrap()
+                    .get_one::<String>("output")
+                    .unwrap(),
+            )
+        }
+        Some("frame-record") => {
+            let frame_record = frame_record_data(
+                matches
+                    .subcommand_matches("frame-record")
+                    .unwrap()
+                    .get_one::<String>("output")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("frame-record")
+                    .unwrap()
+                    .get_one::<String>("fps")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("frame-record")
+                    .unwrap()
+                    .get_one::<String>("container")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "frame-capture.record", Some(&frame_record))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("frame-capture-stop") => simple_api_command(socket, "PUT", "frame-capture.stop", None)
+            .map_err(Error::HttpApiClient),
+        Some("input-backend") => {
+            let input_backend = input_backend_data(
+                matches
+                    .subcommand_matches("input-backend")
+                    .unwrap()
+                    .get_one::<String>("backend")
+                    .unwrap(),
+            );
+            simple_api_command(socket, "PUT", "input.switch-backend", Some(&input_backend))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("input-record-start") => simple_api_command(socket, "PUT", "input.record.start", None)
+            .map_err(Error::HttpApiClient),
+        Some("input-record-stop") => {
+            let record_stop = input_record_stop_data(
+                matches
+                    .subcommand_matches("input-record-stop")
+                    .unwrap()
+                    .get_one::<String>("path")
+                    .map(|x| x as &str),
+            );
+            simple_api_command(socket, "PUT", "input.record.stop", Some(&record_stop))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("input-replay") => {
+            let replay = input_replay_data(
+                matches
+                    .subcommand_matches("input-replay")
+                    .unwrap()
+                    .get_one::<String>("path")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("input-replay")
+                    .unwrap()
+                    .get_one::<String>("speed")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "input.replay.start", Some(&replay))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("input-replay-stop") => simple_api_command(socket, "PUT", "input.replay.stop", None)
+            .map_err(Error::HttpApiClient),
+        Some("input-state") => {
+            simple_api_command(socket, "GET", "input-state", None).map_err(Error::HttpApiClient)
+        }
+        Some("type-text") => {
+            let type_text = type_text_data(
+                matches
+                    .subcommand_matches("type-text")
+                    .unwrap()
+                    .get_one::<String>("text")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("type-text")
+                    .unwrap()
+                    .get_one::<String>("layout")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("type-text")
+                    .unwrap()
+                    .get_one::<String>("delay")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "type-text", Some(&type_text))
+                .map_err(Error::HttpApiClient)
+        }
         Some("add-device") => {
             let device_config = add_device_config(
                 matches
@@ -435,6 +732,28 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
             simple_api_command(socket, "PUT", "add-pmem", Some(&pmem_config))
                 .map_err(Error::HttpApiClient)
         }
+        Some("add-gpu") => {
+            let gpu_config = add_gpu_config(
+                matches
+                    .subcommand_matches("add-gpu")
+                    .unwrap()
+                    .get_one::<String>("gpu_config")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "add-gpu", Some(&gpu_config))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("add-input") => {
+            let input_config = add_input_config(
+                matches
+                    .subcommand_matches("add-input")
+                    .unwrap()
+                    .get_one::<String>("input_config")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "add-input", Some(&input_config))
+                .map_err(Error::HttpApiClient)
+        }
         Some("add-net") => {
             let (net_config, fds) = add_net_config(
                 matches
@@ -666,6 +985,26 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             )?;
             proxy.api_vm_add_pmem(&pmem_config)
         }
+        Some("add-gpu") => {
+            let gpu_config = add_gpu_config(
+                matches
+                    .subcommand_matches("add-gpu")
+                    .unwrap()
+                    .get_one::<String>("gpu_config")
+                    .unwrap(),
+            )?;
+            proxy.api_vm_add_gpu(&gpu_config)
+        }
+        Some("add-input") => {
+            let input_config = add_input_config(
+                matches
+                    .subcommand_matches("add-input")
+                    .unwrap()
+                    .get_one::<String>("input_config")
+                    .unwrap(),
+            )?;
+            proxy.api_vm_add_input(&input_config)
+        }
         Some("add-net") => {
             let (net_config, _fds) = add_net_config(
                 matches
@@ -825,6 +1164,52 @@ fn resize_disk_config(id: &str, size: &str) -> Result<String, Error> {
     Ok(serde_json::to_string(&resize_disk).unwrap())
 }
 
+fn disk_internal_snapshot_list_data(id: &str) -> String {
+    let snapshot_list = vmm::api::VmDiskInternalSnapshotListData { id: id.to_owned() };
+
+    serde_json::to_string(&snapshot_list).unwrap()
+}
+
+fn disk_internal_snapshot_data(id: &str, name: &str) -> String {
+    let snapshot = vmm::api::VmDiskInternalSnapshotData {
+        id: id.to_owned(),
+        name: name.to_owned(),
+    };
+
+    serde_json::to_string(&snapshot).unwrap()
+}
+
+fn disk_snapshot_data(id: &str, overlay: &str) -> String {
+    let snapshot = vmm::api::VmDiskSnapshotData {
+        id: id.to_owned(),
+        overlay: overlay.into(),
+    };
+
+    serde_json::to_string(&snapshot).unwrap()
+}
+
+fn disk_backup_data(id: &str, target: &str, incremental: bool) -> String {
+    let backup = vmm::api::VmDiskBackupData {
+        id: id.to_owned(),
+        target: target.into(),
+        incremental,
+    };
+
+    serde_json::to_string(&backup).unwrap()
+}
+
+fn disk_backup_job_data(id: &str) -> String {
+    let backup = vmm::api::VmDiskBackupJobData { id: id.to_owned() };
+
+    serde_json::to_string(&backup).unwrap()
+}
+
+fn disk_commit_data(id: &str) -> String {
+    let commit = vmm::api::VmDiskCommitData { id: id.to_owned() };
+
+    serde_json::to_string(&commit).unwrap()
+}
+
 fn resize_zone_config(id: &str, size: &str) -> Result<String, Error> {
     let resize_zone = vmm::api::VmResizeZoneData {
         id: id.to_owned(),
@@ -837,6 +1222,26 @@ fn resize_zone_config(id: &str, size: &str) -> Result<String, Error> {
     Ok(serde_json::to_string(&resize_zone).unwrap())
 }
 
+fn display_change_config(scanout: &str, resolution: Option<&str>) -> Result<String, Error> {
+    let mode = resolution
+        .map(|resolution| {
+            resolution
+                .split_once('x')
+                .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                .ok_or_else(|| Error::InvalidDisplayResolution(resolution.to_owned()))
+        })
+        .transpose()?;
+
+    let display_change = vmm::api::VmDisplayChangeData {
+        scanout_id: scanout.parse().map_err(Error::InvalidScanout)?,
+        connected: mode.is_some(),
+        width: mode.map(|(width, _)| width),
+        height: mode.map(|(_, height)| height),
+    };
+
+    Ok(serde_json::to_string(&display_change).unwrap())
+}
+
 fn add_device_config(config: &str) -> Result<String, Error> {
     let device_config = DeviceConfig::parse(config).map_err(Error::AddDeviceConfig)?;
     let device_config = serde_json::to_string(&device_config).unwrap();
@@ -913,6 +1318,20 @@ fn add_vsock_config(config: &str) -> Result<String, Error> {
     Ok(vsock_config)
 }
 
+fn add_gpu_config(config: &str) -> Result<String, Error> {
+    let gpu_config = GpuConfig::parse(config).map_err(Error::AddGpuConfig)?;
+    let gpu_config = serde_json::to_string(&gpu_config).unwrap();
+
+    Ok(gpu_config)
+}
+
+fn add_input_config(config: &str) -> Result<String, Error> {
+    let input_config = VirtioInputConfig::parse(config).map_err(Error::AddInputConfig)?;
+    let input_config = serde_json::to_string(&input_config).unwrap();
+
+    Ok(input_config)
+}
+
 fn snapshot_config(url: &str) -> String {
     let snapshot_config = vmm::api::VmSnapshotConfig {
         destination_url: String::from(url),
@@ -937,6 +1356,90 @@ fn restore_config(config: &str) -> Result<(String, Vec<i32>), Error> {
     Ok((restore_config, fds))
 }
 
+fn frame_snapshot_data(scanout: &str, encoding: &str) -> Result<String, Error> {
+    let frame_snapshot = vmm::api::VmFrameSnapshotData {
+        scanout_id: scanout.parse().map_err(Error::InvalidScanout)?,
+        encoding: match encoding {
+            "png" => vmm::api::FrameEncoding::Png,
+            "raw" => vmm::api::FrameEncoding::Raw,
+            _ => return Err(Error::InvalidFrameEncoding(encoding.to_owned())),
+        },
+    };
+
+    Ok(serde_json::to_string(&frame_snapshot).unwrap())
+}
+
+fn save_frame_snapshot(response: Option<&str>, path: &str) -> ApiResult {
+    let snapshot: vmm::api::VmFrameSnapshotResponse =
+        serde_json::from_str(response.unwrap_or_default()).map_err(Error::FrameSnapshotResponse)?;
+    let image =
+        vmm::frame_export::base64_decode(&snapshot.data).map_err(Error::FrameSnapshotDecode)?;
+    std::fs::write(path, image).map_err(Error::FrameSnapshotWrite)?;
+
+    println!(
+        "Frame {} ({}x{} {}, timestamp {} ns) written to {path}",
+        snapshot.frame_number,
+        snapshot.width,
+        snapshot.height,
+        snapshot.format,
+        snapshot.timestamp_ns
+    );
+    Ok(())
+}
+
+fn frame_record_data(path: &str, fps: &str, container: &str) -> Result<String, Error> {
+    let frame_record = vmm::api::VmFrameCaptureRecordData {
+        path: path.into(),
+        fps: fps.parse().map_err(Error::InvalidFrameRate)?,
+        container: match container {
+            "y4m" => vmm::frame_export::VideoContainer::Y4m,
+            "raw" => vmm::frame_export::VideoContainer::Raw,
+            _ => return Err(Error::InvalidVideoContainer(container.to_owned())),
+        },
+    };
+
+    Ok(serde_json::to_string(&frame_record).unwrap())
+}
+
+fn input_backend_data(backend: &str) -> String {
+    let input_backend = vmm::api::VmInputSwitchBackendData {
+        backend: backend.to_owned(),
+    };
+
+    serde_json::to_string(&input_backend).unwrap()
+}
+
+fn input_record_stop_data(path: Option<&str>) -> String {
+    let record_stop = vmm::api::VmInputRecordStopData {
+        path: path.map(Into::into),
+    };
+
+    serde_json::to_string(&record_stop).unwrap()
+}
+
+fn input_replay_data(path: &str, speed: &str) -> Result<String, Error> {
+    let replay = vmm::api::VmInputReplayData {
+        script: None,
+        path: Some(path.into()),
+        speed: speed.parse().map_err(Error::InvalidReplaySpeed)?,
+        backend: None,
+    };
+
+    Ok(serde_json::to_string(&replay).unwrap())
+}
+
+fn type_text_data(text: &str, layout: &str, delay_ms: &str) -> Result<String, Error> {
+    let type_text = vmm::api::VmTypeTextData {
+        text: text.to_owned(),
+        layout: vmm::input::KeyboardLayout::from_name(layout)
+            .ok_or_else(|| Error::InvalidKeyboardLayout(layout.to_owned()))?,
+        delay_ms: delay_ms.parse().map_err(Error::InvalidKeyDelay)?,
+        backend: None,
+    };
+
+    Ok(serde_json::to_string(&type_text).unwrap())
+}
+
 fn coredump_config(destination_url: &str) -> String {
     let coredump_config = vmm::api::VmCoredumpData {
         destination_url: String::from(destination_url),
@@ -1032,6 +1535,16 @@ fn get_cli_commands_sorted() -> Box<[Command]> {
                     .index(1)
                     .help(vmm::vm_config::GenericVhostUserConfig::SYNTAX),
             ),
+        Command::new("add-gpu")
+            .about("Add virtio-gpu device")
+            .arg(Arg::new("gpu_config").index(1).help(GpuConfig::SYNTAX)),
+        Command::new("add-input")
+            .about("Add virtio-input device")
+            .arg(
+                Arg::new("input_config")
+                    .index(1)
+                    .help(VirtioInputConfig::SYNTAX),
+            ),
         Command::new("add-net")
             .about("Add network device")
             .arg(Arg::new("net_config").index(1).help(NetConfig::SYNTAX)),
@@ -1064,7 +1577,232 @@ fn get_cli_commands_sorted() -> Box<[Command]> {
             .about("Create VM from a JSON configuration")
             .arg(Arg::new("path").index(1).default_value("-")),
         Command::new("delete").about("Delete a VM"),
+        Command::new("disk-backup")
+            .about("Copy a disk to a new qcow2 image in the background")
+            .arg(
+                Arg::new("disk")
+                    .long("disk")
+                    .help("Disk identifier")
+                    .num_args(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("incremental")
+                    .long("incremental")
+                    .help("Only copy the chunks written since the previous backup")
+                    .num_args(0)
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("target")
+                    .long("target")
+                    .help("Path of the backup to create")
+                    .num_args(1)
+                    .required(true),
+            ),
+        Command::new("disk-backup-cancel")
+            .about("Stop the backup of a disk")
+            .arg(
+                Arg::new("disk")
+                    .long("disk")
+                    .help("Disk identifier")
+                    .num_args(1)
+                    .required(true),
+            ),
+        Command::new("disk-backup-status")
+            .about("Report the progress of the backup of a disk")
+            .arg(
+                Arg::new("disk")
+                    .long("disk")
+                    .help("Disk identifier")
+                    .num_args(1)
+                    .required(true),
+            ),
+        Command::new("disk-commit")
+            .about("Merge the overlay of a disk into its backing file in the background")
+            .arg(
+                Arg::new("disk")
+                    .long("disk")
+                    .help("Disk identifier")
+                    .num_args(1)
+                    .required(true),
+            ),
+        Command::new("disk-commit-status")
+            .about("Report the progress of the commit of a disk")
+            .arg(
+                Arg::new("disk")
+                    .long("disk")
+                    .help("Disk identifier")
+                    .num_args(1)
+                    .required(true),
+            ),
+        Command::new("disk-internal-snapshot-apply")
+            .about("Revert a disk to one of its internal snapshots, the VM must be paused")
+            .arg(
+                Arg::new("disk")
+                    .long("disk")
+                    .help("Disk identifier")
+                    .num_args(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("name")
+                    .long("name")
+                    .help("Snapshot ID or name")
+                    .num_args(1)
+                    .required(true),
+            ),
+        Command::new("disk-internal-snapshot-create")
+            .about("Take an internal snapshot of a qcow2 disk, the VM must be paused")
+            .arg(
+                Arg::new("disk")
+                    .long("disk")
+                    .help("Disk identifier")
+                    .num_args(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("name")
+                    .long("name")
+                    .help("Snapshot name")
+                    .num_args(1)
+                    .required(true),
+            ),
+        Command::new("disk-internal-snapshot-delete")
+            .about("Delete an internal snapshot of a disk, the VM must be paused")
+            .arg(
+                Arg::new("disk")
+                    .long("disk")
+                    .help("Disk identifier")
+                    .num_args(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("name")
+                    .long("name")
+                    .help("Snapshot ID or name")
+                    .num_args(1)
+                    .required(true),
+            ),
+        Command::new("disk-internal-snapshot-list")
+            .about("List the internal snapshots of a disk")
+            .arg(
+                Arg::new("disk")
+                    .long("disk")
+                    .help("Disk identifier")
+                    .num_args(1)
+                    .required(true),
+            ),
+        Command::new("disk-snapshot")
+            .about("Redirect the writes to a disk to a new qcow2 overlay backed by its image")
+            .arg(
+                Arg::new("disk")
+                    .long("disk")
+                    .help("Disk identifier")
+                    .num_args(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("overlay")
+                    .long("overlay")
+                    .help("Path of the overlay to create")
+                    .num_args(1)
+                    .required(true),
+            ),
+        Command::new("display-change")
+            .about("Connect or disconnect a virtio-gpu display")
+            .arg(
+                Arg::new("resolution")
+                    .long("resolution")
+                    .help("<width>x<height> of the connected display, disconnects it if omitted")
+                    .num_args(1),
+            )
+            .arg(
+                Arg::new("scanout")
+                    .long("scanout")
+                    .help("Scanout the display is connected to")
+                    .num_args(1)
+                    .required(true),
+            ),
+        Command::new("frame-capture-stop").about("Stop the frame capture and recording"),
+        Command::new("frame-record")
+            .about("Record the frames published by the guest, and its audio")
+            .arg(
+                Arg::new("container")
+                    .long("container")
+                    .help("y4m, or raw frames in the frame format")
+                    .num_args(1)
+                    .default_value("y4m"),
+            )
+            .arg(
+                Arg::new("fps")
+                    .long("fps")
+                    .help("Frame rate of the video")
+                    .num_args(1)
+                    .default_value("30"),
+            )
+            .arg(
+                Arg::new("output")
+                    .index(1)
+                    .help("<file_path>, the audio goes to the same path with a .wav extension")
+                    .required(true),
+            ),
+        Command::new("frame-snapshot")
+            .about("Save the last frame published by the guest")
+            .arg(
+                Arg::new("encoding")
+                    .long("encoding")
+                    .help("png, or raw pixel data in the frame format")
+                    .num_args(1)
+                    .default_value("png"),
+            )
+            .arg(
+                Arg::new("output")
+                    .index(1)
+                    .help("<file_path>")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("scanout")
+                    .long("scanout")
+                    .help("Scanout to capture")
+                    .num_args(1)
+                    .default_value("0"),
+            ),
         Command::new("info").about("Info on the VM"),
+        Command::new("input-backend")
+            .about("Select the backend receiving the injected input")
+            .arg(
+                Arg::new("backend")
+                    .index(1)
+                    .help("ps2, virtio or usb")
+                    .required(true),
+            ),
+        Command::new("input-record-start").about("Start recording the injected input"),
+        Command::new("input-record-stop")
+            .about("Stop recording the injected input and print the script")
+            .arg(
+                Arg::new("path")
+                    .index(1)
+                    .help("File to save the script to, on the VMM host"),
+            ),
+        Command::new("input-replay")
+            .about("Replay a recorded input script")
+            .arg(
+                Arg::new("path")
+                    .index(1)
+                    .help("Script file, on the VMM host")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("speed")
+                    .long("speed")
+                    .help("Replay speed, 2 replays twice as fast as recorded")
+                    .num_args(1)
+                    .default_value("1"),
+            ),
+        Command::new("input-replay-stop").about("Stop replaying input"),
+        Command::new("input-state").about("Keyboard LEDs and input devices the guest has set up"),
         Command::new("nmi").about("Trigger NMI"),
         Command::new("pause").about("Pause the VM"),
         Command::new("ping").about("Ping the VMM to check for API server availability"),
@@ -1158,6 +1896,28 @@ fn get_cli_commands_sorted() -> Box<[Command]> {
                     .index(1)
                     .help("<destination_url>"),
             ),
+        Command::new("type-text")
+            .about("Type text on the VM keyboard")
+            .arg(
+                Arg::new("delay")
+                    .long("delay")
+                    .help("Delay between two characters, in milliseconds")
+                    .num_args(1)
+                    .default_value("10"),
+            )
+            .arg(
+                Arg::new("layout")
+                    .long("layout")
+                    .help("Keyboard layout of the guest: us, de, fr or jp")
+                    .num_args(1)
+                    .default_value("us"),
+            )
+            .arg(
+                Arg::new("text")
+                    .index(1)
+                    .help("UTF-8 text to type")
+                    .required(true),
+            ),
     ]
     .to_vec()
     .into_boxed_slice()
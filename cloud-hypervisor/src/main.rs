@@ -31,10 +31,12 @@ use vmm::vm_config;
 use vmm::vm_config::FwCfgConfig;
 #[cfg(feature = "ivshmem")]
 use vmm::vm_config::IvshmemConfig;
+#[cfg(feature = "vnc")]
+use vmm::vm_config::VncConfig;
 use vmm::vm_config::{
-    BalloonConfig, DeviceConfig, DiskConfig, FsConfig, GenericVhostUserConfig, LandlockConfig,
-    NetConfig, NumaConfig, PciSegmentConfig, PmemConfig, RateLimiterGroupConfig, TpmConfig,
-    UserDeviceConfig, VdpaConfig, VmConfig, VsockConfig,
+    BalloonConfig, DeviceConfig, DiskConfig, FsConfig, GenericVhostUserConfig, GpuConfig,
+    LandlockConfig, NetConfig, NumaConfig, PciSegmentConfig, PmemConfig, RateLimiterGroupConfig,
+    TpmConfig, UsbConfig, UserDeviceConfig, VdpaConfig, VirtioInputConfig, VmConfig, VsockConfig,
 };
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::signal::block_signal;
@@ -285,6 +287,11 @@ fn get_cli_options_sorted(
             .help(GenericVhostUserConfig::SYNTAX)
             .num_args(1..)
             .group("vm-config"),
+        Arg::new("gpu")
+            .long("gpu")
+            .help(GpuConfig::SYNTAX)
+            .num_args(1)
+            .group("vm-config"),
         #[cfg(feature = "igvm")]
         Arg::new("igvm")
             .long("igvm")
@@ -302,6 +309,11 @@ fn get_cli_options_sorted(
             .help("Path to initramfs image")
             .num_args(1)
             .group("vm-config"),
+        Arg::new("input")
+            .long("input")
+            .help(VirtioInputConfig::SYNTAX)
+            .num_args(1)
+            .group("vm-config"),
         #[cfg(feature = "ivshmem")]
         Arg::new("ivshmem")
             .long("ivshmem")
@@ -434,6 +446,11 @@ fn get_cli_options_sorted(
             .num_args(1)
             .help(TpmConfig::SYNTAX)
             .group("vm-config"),
+        Arg::new("usb")
+            .long("usb")
+            .help(UsbConfig::SYNTAX)
+            .num_args(1)
+            .group("vm-config"),
         Arg::new("user-device")
             .long("user-device")
             .help(UserDeviceConfig::SYNTAX)
@@ -455,6 +472,12 @@ fn get_cli_options_sorted(
             .action(ArgAction::SetTrue)
             .help("Print version")
             .num_args(0),
+        #[cfg(feature = "vnc")]
+        Arg::new("vnc")
+            .long("vnc")
+            .help(VncConfig::SYNTAX)
+            .num_args(1)
+            .group("vm-config"),
         Arg::new("vsock")
             .long("vsock")
             .help(VsockConfig::SYNTAX)
@@ -873,6 +896,14 @@ fn main() {
     // SAFETY: trivially safe
     let _ = unsafe { libc::umask(0o077) };
 
+    // Mesa defaults to its software rasterizer so that virtio-gpu 3D does not
+    // need a host GPU. The environment can't be changed once threads exist.
+    #[cfg(feature = "virgl")]
+    if std::env::var_os("LIBGL_ALWAYS_SOFTWARE").is_none() {
+        // SAFETY: No other thread has been spawned yet.
+        unsafe { std::env::set_var("LIBGL_ALWAYS_SOFTWARE", "1") };
+    }
+
     let (default_vcpus, default_memory, default_rng) = prepare_default_values();
     let cmd_arguments = create_app(default_vcpus, default_memory, default_rng).get_matches();
 
@@ -1040,6 +1071,11 @@ mod unit_tests {
             landlock_rules: None,
             #[cfg(feature = "ivshmem")]
             ivshmem: None,
+            usb: None,
+            gpu: None,
+            input: None,
+            #[cfg(feature = "vnc")]
+            vnc: None,
         };
 
         assert_eq!(expected_vm_config, result_vm_config);
@@ -734,6 +734,43 @@ fn resize_disk_command(api_socket: &str, id: &str, desired_size: &str) -> bool {
     cmd.status().expect("Failed to launch ch-remote").success()
 }
 
+fn disk_backup_command(api_socket: &str, id: &str, target: &str, incremental: bool) -> bool {
+    let mut cmd = Command::new(clh_command("ch-remote"));
+    cmd.args([
+        &format!("--api-socket={api_socket}"),
+        "disk-backup",
+        &format!("--disk={id}"),
+        &format!("--target={target}"),
+    ]);
+    if incremental {
+        cmd.arg("--incremental");
+    }
+
+    cmd.status().expect("Failed to launch ch-remote").success()
+}
+
+fn wait_disk_backup(api_socket: &str, id: &str) -> String {
+    for _ in 0..60 {
+        let mut cmd = Command::new(clh_command("ch-remote"));
+        cmd.args([
+            &format!("--api-socket={api_socket}"),
+            "disk-backup-status",
+            &format!("--disk={id}"),
+        ]);
+        let output = cmd.output().expect("Failed to launch ch-remote");
+        assert!(output.status.success());
+
+        let status: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        let state = status["state"].as_str().unwrap().to_owned();
+        if state != "running" {
+            return state;
+        }
+        thread::sleep(std::time::Duration::new(1, 0));
+    }
+
+    panic!("Backup of disk {id} didn't finish");
+}
+
 // setup OVS-DPDK bridge and ports
 fn setup_ovs_dpdk() {
     // setup OVS-DPDK
@@ -7036,6 +7073,102 @@ mod common_parallel {
         handle_child_output(r, &output);
     }
 
+    #[test]
+    fn test_disk_backup_with_seccomp() {
+        let disk_config = UbuntuDiskConfig::new(JAMMY_IMAGE_NAME.to_string());
+        let guest = Guest::new(Box::new(disk_config));
+
+        #[cfg(target_arch = "x86_64")]
+        let kernel_path = direct_kernel_boot_path();
+        #[cfg(target_arch = "aarch64")]
+        let kernel_path = edk2_path();
+
+        let api_socket = temp_api_path(&guest.tmp_dir);
+
+        let test_disk_path = guest.tmp_dir.as_path().join("backup-test.img");
+        File::create(&test_disk_path)
+            .unwrap()
+            .set_len(16 << 20)
+            .unwrap();
+        let full_backup_path = guest.tmp_dir.as_path().join("backup-full.qcow2");
+        let incremental_backup_path = guest.tmp_dir.as_path().join("backup-incremental.qcow2");
+        let mut dirty_bitmap_path = test_disk_path.clone().into_os_string();
+        dirty_bitmap_path.push(".dirty-bitmap");
+
+        let mut cmd = GuestCommand::new(&guest);
+
+        cmd.args(["--api-socket", &api_socket])
+            .args(["--seccomp", "true"])
+            .default_cpus()
+            .default_memory()
+            .args(["--kernel", kernel_path.to_str().unwrap()])
+            .args(["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+            .default_disks()
+            .default_net()
+            .capture_output();
+
+        let mut child = cmd.spawn().unwrap();
+
+        let r = std::panic::catch_unwind(|| {
+            guest.wait_vm_boot().unwrap();
+
+            // Add the raw disk, its dirty bitmap living in a file next to it
+            let (cmd_success, cmd_output) = remote_command_w_output(
+                &api_socket,
+                "add-disk",
+                Some(&format!(
+                    "path={},id=test0,dirty_bitmap=on",
+                    test_disk_path.to_str().unwrap()
+                )),
+            );
+            assert!(cmd_success);
+            assert!(String::from_utf8_lossy(&cmd_output).contains("\"id\":\"test0\""));
+
+            guest
+                .ssh_command("sudo dd if=/dev/urandom of=/dev/vdc bs=1M count=8 oflag=direct")
+                .unwrap();
+
+            // The backup is renamed into place once copied
+            assert!(disk_backup_command(
+                &api_socket,
+                "test0",
+                full_backup_path.to_str().unwrap(),
+                false
+            ));
+            assert_eq!(wait_disk_backup(&api_socket, "test0"), "completed");
+            assert!(full_backup_path.exists());
+
+            guest
+                .ssh_command("sudo dd if=/dev/urandom of=/dev/vdc bs=1M count=1 oflag=direct")
+                .unwrap();
+
+            assert!(disk_backup_command(
+                &api_socket,
+                "test0",
+                incremental_backup_path.to_str().unwrap(),
+                true
+            ));
+            assert_eq!(wait_disk_backup(&api_socket, "test0"), "completed");
+            assert!(incremental_backup_path.exists());
+
+            // Removing the disk stores its dirty bitmap, renamed into place too
+            assert!(remote_command(&api_socket, "remove-device", Some("test0")));
+            thread::sleep(std::time::Duration::new(5, 0));
+            assert!(Path::new(&dirty_bitmap_path).exists());
+
+            // The VMM survived the renames
+            assert!(remote_command(&api_socket, "info", None));
+        });
+
+        kill_child(&mut child);
+        let output = child.wait_with_output().unwrap();
+
+        disk_check_consistency(&full_backup_path, None);
+        disk_check_consistency(&incremental_backup_path, None);
+
+        handle_child_output(r, &output);
+    }
+
     fn create_loop_device(backing_file_path: &str, block_size: u32, num_retries: usize) -> String {
         const LOOP_CONFIGURE: u64 = 0x4c0a;
         const LOOP_CTL_GET_FREE: u64 = 0x4c82;
@@ -11,9 +11,10 @@ use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 /// Guest Agent 命令（Host -> Guest）
 /// 由 Host 写入，Guest Agent 读取并执行
 #[repr(u32)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum GuestCommand {
     /// 无命令/空闲状态
+    #[default]
     None = 0,
     /// 开始捕获帧数据
     StartCapture = 1,
@@ -23,12 +24,6 @@ pub enum GuestCommand {
     SetFormat = 3,
 }
 
-impl Default for GuestCommand {
-    fn default() -> Self {
-        GuestCommand::None
-    }
-}
-
 impl TryFrom<u32> for GuestCommand {
     type Error = &'static str;
 
@@ -46,9 +41,10 @@ impl TryFrom<u32> for GuestCommand {
 /// Guest Agent 状态（Guest -> Host）
 /// 由 Guest Agent 写入，Host 读取
 #[repr(u32)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum GuestState {
     /// 空闲状态，未捕获
+    #[default]
     Idle = 0,
     /// 正在捕获帧数据
     Capturing = 1,
@@ -58,12 +54,6 @@ pub enum GuestState {
     Initializing = 3,
 }
 
-impl Default for GuestState {
-    fn default() -> Self {
-        GuestState::Idle
-    }
-}
-
 impl TryFrom<u32> for GuestState {
     type Error = &'static str;
 
@@ -89,9 +79,10 @@ pub const DEFAULT_BUFFER_COUNT: u32 = 3;
 
 /// Frame format enumeration
 #[repr(u32)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum FrameFormat {
     /// 32-bit BGRA format (Blue, Green, Red, Alpha)
+    #[default]
     Bgra32 = 0,
     /// 32-bit RGBA format (Red, Green, Blue, Alpha)
     Rgba32 = 1,
@@ -99,12 +90,6 @@ pub enum FrameFormat {
     Nv12 = 2,
 }
 
-impl Default for FrameFormat {
-    fn default() -> Self {
-        FrameFormat::Bgra32
-    }
-}
-
 impl TryFrom<u32> for FrameFormat {
     type Error = &'static str;
 
@@ -357,8 +342,7 @@ impl FrameBufferHeader {
     pub fn begin_write_frame(&self) -> u32 {
         // 获取下一个缓冲区（跳过当前活跃的）
         let current = self.active_index();
-        let next = (current + 1) % self.buffer_count;
-        next
+        (current + 1) % self.buffer_count
     }
 
     /// 完成帧写入（Guest Agent 调用）
@@ -436,12 +420,11 @@ impl FrameBufferHeader {
         hot_y: i16,
         data_size: u32,
     ) {
-        // SAFETY: These are plain fields, not accessed via atomics
         // We use a pointer to write multiple fields atomically from the guest side
         // In practice, the guest should ensure it's not racing with itself
         let header = self as *const FrameBufferHeader as *mut FrameBufferHeader;
 
-        // Write fields
+        // SAFETY: These are plain fields, not accessed via atomics
         unsafe {
             (*header).cursor_width = width;
             (*header).cursor_height = height;
@@ -456,8 +439,8 @@ impl FrameBufferHeader {
 
     /// Sets the cursor data offset (called during layout initialization)
     pub fn set_cursor_offset(&self, offset: u64) {
-        // SAFETY: This is called once during initialization
         let header = self as *const FrameBufferHeader as *mut FrameBufferHeader;
+        // SAFETY: This is called once during initialization
         unsafe {
             (*header).cursor_offset = offset;
         }
@@ -483,7 +466,7 @@ impl FrameBufferHeader {
 
 /// Frame metadata (one per buffer)
 #[repr(C)]
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct FrameMetadata {
     /// Frame sequence number
     pub frame_number: u64,
@@ -520,6 +503,59 @@ impl FrameMetadata {
     }
 }
 
+/// Frame ready notification, streamed to the frame notification socket
+/// clients each time the guest publishes a frame
+///
+/// Wire format (48 bytes, little endian):
+/// ```text
+/// +--------------+----------+---------------------------------+
+/// | buffer_index | reserved | FrameMetadata                   |
+/// | u32          | u32      | (40 bytes, same layout as shm)  |
+/// +--------------+----------+---------------------------------+
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameReadyEvent {
+    /// Buffer holding the frame
+    pub buffer_index: u32,
+    /// Metadata of the frame
+    pub metadata: FrameMetadata,
+}
+
+impl FrameReadyEvent {
+    /// Size of an encoded event in bytes
+    pub const SIZE: usize = 8 + FrameBufferLayout::METADATA_SIZE;
+
+    /// Encodes the event in its wire format
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.buffer_index.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.metadata.frame_number.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.metadata.timestamp_ns.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.metadata.flags.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.metadata.data_size.to_le_bytes());
+        bytes[32..48].copy_from_slice(&self.metadata.reserved);
+        bytes
+    }
+
+    /// Decodes an event from its wire format
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        FrameReadyEvent {
+            buffer_index: u32_at(0),
+            metadata: FrameMetadata {
+                frame_number: u64_at(8),
+                timestamp_ns: u64_at(16),
+                flags: u32_at(24),
+                data_size: u32_at(28),
+                reserved: bytes[32..48].try_into().unwrap(),
+            },
+        }
+    }
+}
+
 /// Frame buffer layout calculator
 #[derive(Debug, Clone)]
 pub struct FrameBufferLayout {
@@ -535,6 +571,8 @@ pub struct FrameBufferLayout {
     pub cursor_shape_offset: usize,
     /// Offset to the cursor data
     pub cursor_data_offset: usize,
+    /// Offset to the optional audio ring, right after the frame buffer
+    pub audio_offset: usize,
     /// Total size of the frame buffer region
     pub total_size: usize,
     /// Number of buffers
@@ -583,6 +621,9 @@ impl FrameBufferLayout {
         // Total size includes cursor data region
         let total_size = cursor_data_offset + Self::CURSOR_DATA_SIZE;
 
+        // The audio ring is only present in regions large enough for it
+        let audio_offset = (total_size + CACHE_LINE_SIZE - 1) & !(CACHE_LINE_SIZE - 1);
+
         FrameBufferLayout {
             header_offset,
             metadata_offset,
@@ -590,6 +631,7 @@ impl FrameBufferLayout {
             cursor_metadata_offset,
             cursor_shape_offset,
             cursor_data_offset,
+            audio_offset,
             total_size,
             buffer_count,
             buffer_size,
@@ -637,6 +679,11 @@ impl FrameBufferLayout {
     pub fn cursor_data_offset_for(&self) -> usize {
         self.cursor_data_offset
     }
+
+    /// Gets the audio ring header offset
+    pub fn audio_offset_for(&self) -> usize {
+        self.audio_offset
+    }
 }
 
 // ============================================================================
@@ -645,9 +692,10 @@ impl FrameBufferLayout {
 
 /// Audio format enumeration
 #[repr(u32)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum AudioFormat {
     /// 16-bit signed PCM, little-endian
+    #[default]
     PcmS16Le = 0,
     /// 24-bit signed PCM, little-endian
     PcmS24Le = 1,
@@ -657,12 +705,6 @@ pub enum AudioFormat {
     FloatLe = 3,
 }
 
-impl Default for AudioFormat {
-    fn default() -> Self {
-        AudioFormat::PcmS16Le
-    }
-}
-
 impl TryFrom<u32> for AudioFormat {
     type Error = &'static str;
 
@@ -815,7 +857,7 @@ mod tests {
         assert_eq!(FrameFormat::try_from(0).unwrap(), FrameFormat::Bgra32);
         assert_eq!(FrameFormat::try_from(1).unwrap(), FrameFormat::Rgba32);
         assert_eq!(FrameFormat::try_from(2).unwrap(), FrameFormat::Nv12);
-        assert!(FrameFormat::try_from(3).is_err());
+        FrameFormat::try_from(3).unwrap_err();
     }
 
     #[test]
@@ -876,6 +918,10 @@ mod tests {
         let data2 = layout.data_offset_for(2);
         assert_eq!(data1 - data0, 1024);
         assert_eq!(data2 - data1, 1024);
+
+        // Audio ring after the cursor data
+        assert!(layout.audio_offset_for() >= layout.total_size);
+        assert_eq!(layout.audio_offset_for() % 64, 0);
     }
 
     #[test]
@@ -907,6 +953,20 @@ mod tests {
         assert!(meta.flags().contains(FrameFlags::KEYFRAME));
     }
 
+    #[test]
+    fn test_frame_ready_event_encoding() {
+        let event = FrameReadyEvent {
+            buffer_index: 2,
+            metadata: FrameMetadata::new(42, 12345678, 8294400, FrameFlags::KEYFRAME),
+        };
+
+        let bytes = event.to_bytes();
+        assert_eq!(bytes.len(), 48);
+        assert_eq!(&bytes[0..4], &2u32.to_le_bytes());
+        assert_eq!(&bytes[8..16], &42u64.to_le_bytes());
+        assert_eq!(FrameReadyEvent::from_bytes(&bytes), event);
+    }
+
     #[test]
     fn test_frame_flags() {
         let flags = FrameFlags::KEYFRAME | FrameFlags::PROCESSED;
@@ -969,7 +1029,7 @@ mod tests {
         assert_eq!(GuestCommand::try_from(1).unwrap(), GuestCommand::StartCapture);
         assert_eq!(GuestCommand::try_from(2).unwrap(), GuestCommand::StopCapture);
         assert_eq!(GuestCommand::try_from(3).unwrap(), GuestCommand::SetFormat);
-        assert!(GuestCommand::try_from(4).is_err());
+        GuestCommand::try_from(4).unwrap_err();
     }
 
     #[test]
@@ -978,7 +1038,7 @@ mod tests {
         assert_eq!(GuestState::try_from(1).unwrap(), GuestState::Capturing);
         assert_eq!(GuestState::try_from(2).unwrap(), GuestState::Error);
         assert_eq!(GuestState::try_from(3).unwrap(), GuestState::Initializing);
-        assert!(GuestState::try_from(4).is_err());
+        GuestState::try_from(4).unwrap_err();
     }
 
     #[test]
@@ -1063,7 +1123,7 @@ mod tests {
         assert_eq!(AudioFormat::try_from(1).unwrap(), AudioFormat::PcmS24Le);
         assert_eq!(AudioFormat::try_from(2).unwrap(), AudioFormat::PcmS32Le);
         assert_eq!(AudioFormat::try_from(3).unwrap(), AudioFormat::FloatLe);
-        assert!(AudioFormat::try_from(4).is_err());
+        AudioFormat::try_from(4).unwrap_err();
     }
 
     #[test]
@@ -1104,7 +1164,7 @@ mod tests {
 
     #[test]
     fn test_audio_buffer_available() {
-        let mut header = AudioBufferHeader::default();
+        let header = AudioBufferHeader::default();
 
         // Initially empty
         assert_eq!(header.available_to_read(), 0);
@@ -1120,7 +1180,7 @@ mod tests {
 
     #[test]
     fn test_audio_buffer_wrap_around() {
-        let mut header = AudioBufferHeader::new(
+        let header = AudioBufferHeader::new(
             AudioFormat::PcmS16Le,
             48000,
             2,
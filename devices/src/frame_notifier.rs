@@ -0,0 +1,334 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Frame ready notifications
+//!
+//! Watches the frame buffer header in the ivshmem shared memory and streams a
+//! [`FrameReadyEvent`] to every client connected to a UNIX socket each time
+//! the guest publishes a new frame, so that host readers don't have to poll
+//! `frame_count` through `/vm.frame-info`.
+//!
+//! The guest agent is expected to write the ivshmem Doorbell register right
+//! after `end_write_frame()`, in which case the clients are notified within
+//! the same frame. Agents which don't ring the doorbell are still picked up
+//! by re-reading the header every poll interval.
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use thiserror::Error;
+use vm_memory::bitmap::AtomicBitmap;
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::frame_buffer::{FrameBufferHeader, FrameBufferLayout, FrameMetadata, FrameReadyEvent};
+
+type MmapRegion = vm_memory::MmapRegion<AtomicBitmap>;
+
+/// Header poll interval used when the guest agent doesn't ring the doorbell
+pub const FRAME_NOTIFIER_POLL_INTERVAL: Duration = Duration::from_millis(4);
+
+const KILL_EVENT: u64 = 0;
+const LISTENER_EVENT: u64 = 1;
+const DOORBELL_EVENT: u64 = 2;
+
+#[derive(Debug, Error)]
+pub enum FrameNotifierError {
+    #[error("Failed to bind the frame notification socket")]
+    Bind(#[source] io::Error),
+    #[error("Failed to create the frame notifier kill event")]
+    CreateKillEvent(#[source] io::Error),
+    #[error("Failed to spawn the frame notifier thread")]
+    SpawnThread(#[source] io::Error),
+}
+
+/// Streams frame ready notifications from the frame buffer to the clients of
+/// a UNIX socket
+pub struct FrameNotifier {
+    path: PathBuf,
+    kill_evt: EventFd,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl FrameNotifier {
+    /// Listen on `path` and notify its clients of the frames published in
+    /// the frame buffer starting at the beginning of `region`. The header is
+    /// re-read whenever `doorbell_evt` is signaled, or at the latest every
+    /// `poll_interval`.
+    pub fn new(
+        path: &Path,
+        region: Arc<MmapRegion>,
+        doorbell_evt: EventFd,
+        poll_interval: Duration,
+    ) -> Result<Self, FrameNotifierError> {
+        let listener = UnixListener::bind(path).map_err(FrameNotifierError::Bind)?;
+        listener.set_nonblocking(true).map_err(FrameNotifierError::Bind)?;
+        let kill_evt =
+            EventFd::new(libc::EFD_NONBLOCK).map_err(FrameNotifierError::CreateKillEvent)?;
+
+        let mut worker = FrameNotifierWorker {
+            listener,
+            clients: Vec::new(),
+            region,
+            doorbell_evt,
+            kill_evt: kill_evt.try_clone().map_err(FrameNotifierError::CreateKillEvent)?,
+            poll_interval,
+            last_frame: 0,
+        };
+        // Only the frames published from now on are notified
+        worker.last_frame = worker.header().map_or(0, FrameBufferHeader::frame_count);
+        let worker = thread::Builder::new()
+            .name("frame-notifier".to_string())
+            .spawn(move || {
+                if let Err(e) = worker.run() {
+                    error!("Frame notifier thread failed: {e}");
+                }
+            })
+            .map_err(FrameNotifierError::SpawnThread)?;
+
+        info!("Streaming frame ready notifications on {path:?}");
+
+        Ok(FrameNotifier {
+            path: path.to_path_buf(),
+            kill_evt,
+            worker: Some(worker),
+        })
+    }
+}
+
+impl Drop for FrameNotifier {
+    fn drop(&mut self) {
+        // Ignore the results because there is nothing we can do about it.
+        let _ = self.kill_evt.write(1);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+struct FrameNotifierWorker {
+    listener: UnixListener,
+    clients: Vec<UnixStream>,
+    region: Arc<MmapRegion>,
+    doorbell_evt: EventFd,
+    kill_evt: EventFd,
+    poll_interval: Duration,
+    last_frame: u64,
+}
+
+impl FrameNotifierWorker {
+    fn run(&mut self) -> io::Result<()> {
+        let epoll = Epoll::new()?;
+        epoll.ctl(
+            ControlOperation::Add,
+            self.kill_evt.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, KILL_EVENT),
+        )?;
+        epoll.ctl(
+            ControlOperation::Add,
+            self.listener.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, LISTENER_EVENT),
+        )?;
+        epoll.ctl(
+            ControlOperation::Add,
+            self.doorbell_evt.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, DOORBELL_EVENT),
+        )?;
+
+        let timeout = self.poll_interval.as_millis().try_into().unwrap_or(i32::MAX);
+        let mut events = [EpollEvent::default(); 3];
+        loop {
+            let num_events = match epoll.wait(timeout, &mut events) {
+                Ok(num_events) => num_events,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for event in events.iter().take(num_events) {
+                match event.data() {
+                    KILL_EVENT => return Ok(()),
+                    LISTENER_EVENT => self.accept_clients(),
+                    DOORBELL_EVENT => {
+                        let _ = self.doorbell_evt.read();
+                    }
+                    data => warn!("Unexpected frame notifier event {data}"),
+                }
+            }
+
+            self.check_frame();
+        }
+    }
+
+    fn accept_clients(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        warn!("Dropping frame notification client: {e}");
+                        continue;
+                    }
+                    debug!("New frame notification client");
+                    self.clients.push(stream);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("Failed to accept a frame notification client: {e}");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Header written by the guest agent, if it looks sane
+    fn header(&self) -> Option<&FrameBufferHeader> {
+        if self.region.size() < FrameBufferLayout::HEADER_SIZE {
+            return None;
+        }
+        // SAFETY: the region is mapped as long as we hold it and is large
+        // enough for the header, which the VMM wrote at its start.
+        let header = unsafe { &*(self.region.as_ptr() as *const FrameBufferHeader) };
+        header.validate().then_some(header)
+    }
+
+    /// Notify the clients if the guest published a frame since last time
+    fn check_frame(&mut self) {
+        let Some(header) = self.header() else {
+            return;
+        };
+        let (buffer_index, frame_count) = header.read_frame_info();
+        if frame_count == self.last_frame {
+            return;
+        }
+
+        let layout = FrameBufferLayout::from_header(header);
+        if buffer_index >= layout.buffer_count
+            || !layout.validate_region_size(self.region.size())
+        {
+            warn!("Ignoring frame {frame_count} in invalid buffer {buffer_index}");
+            self.last_frame = frame_count;
+            return;
+        }
+
+        // SAFETY: the metadata entry lies within the region, as checked
+        // against the layout above.
+        let mut metadata = unsafe {
+            std::ptr::read_volatile(
+                self.region.as_ptr().add(layout.metadata_offset_for(buffer_index))
+                    as *const FrameMetadata,
+            )
+        };
+        // The header is authoritative, the metadata is filled in on a best
+        // effort basis by the guest agent.
+        metadata.frame_number = frame_count;
+        if metadata.timestamp_ns == 0 {
+            metadata.timestamp_ns = monotonic_ns();
+        }
+        if metadata.data_size == 0 {
+            metadata.data_size = header.expected_data_size() as u32;
+        }
+        self.last_frame = frame_count;
+
+        let event = FrameReadyEvent {
+            buffer_index,
+            metadata,
+        }
+        .to_bytes();
+        // Slow clients miss frames rather than stalling the others, only a
+        // partial write corrupts the stream and drops the client.
+        self.clients.retain_mut(|client| match client.write(&event) {
+            Ok(len) if len == event.len() => true,
+            Ok(_) => {
+                warn!("Dropping frame notification client after a partial write");
+                false
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => true,
+            Err(e) => {
+                debug!("Frame notification client went away: {e}");
+                false
+            }
+        });
+    }
+}
+
+fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: FFI call with a valid timespec
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    use super::*;
+    use crate::frame_buffer::{FrameFlags, FrameFormat};
+
+    #[test]
+    fn test_frame_notifier() {
+        let layout = FrameBufferLayout::new(3, 64 * 64 * 4);
+        let region = Arc::new(MmapRegion::new(layout.total_size).unwrap());
+        let header_ptr = region.as_ptr() as *mut FrameBufferHeader;
+        // SAFETY: the region is large enough for the frame buffer
+        let header = unsafe {
+            header_ptr.write(FrameBufferHeader::new(
+                3,
+                64 * 64 * 4,
+                64,
+                64,
+                FrameFormat::Bgra32,
+            ));
+            &*header_ptr
+        };
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("frames.sock");
+        let doorbell_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        // Make sure the doorbell, not the polling, triggers the notification
+        let notifier = FrameNotifier::new(
+            &path,
+            region.clone(),
+            doorbell_evt.try_clone().unwrap(),
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // Guest agent side
+        let index = header.begin_write_frame();
+        // SAFETY: the metadata entry lies within the region
+        unsafe {
+            (region.as_ptr().add(layout.metadata_offset_for(index)) as *mut FrameMetadata)
+                .write(FrameMetadata::new(0, 1000, 1234, FrameFlags::KEYFRAME));
+        }
+        header.end_write_frame(index);
+        doorbell_evt.write(1).unwrap();
+
+        let mut buf = [0u8; FrameReadyEvent::SIZE];
+        client.read_exact(&mut buf).unwrap();
+        let event = FrameReadyEvent::from_bytes(&buf);
+        assert_eq!(event.buffer_index, 1);
+        assert_eq!(event.metadata.frame_number, 1);
+        assert_eq!(event.metadata.timestamp_ns, 1000);
+        assert_eq!(event.metadata.data_size, 1234);
+        assert!(event.metadata.flags().contains(FrameFlags::KEYFRAME));
+
+        drop(notifier);
+        assert!(!path.exists());
+    }
+}
@@ -4,26 +4,34 @@
 //
 
 use std::any::Any;
+use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
-use std::result;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
+use std::{io, result, thread};
 
 use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use log::{debug, error, warn};
 use pci::{
-    BarReprogrammingParams, PCI_CONFIGURATION_ID, PciBarConfiguration, PciBarPrefetchable,
-    PciBarRegionType, PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType,
-    PciSubclass,
+    BarReprogrammingParams, MsixCap, MsixConfig, PCI_CONFIGURATION_ID, PciBarConfiguration,
+    PciBarPrefetchable, PciBarRegionType, PciClassCode, PciConfiguration, PciDevice,
+    PciDeviceError, PciHeaderType, PciSubclass,
 };
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use vm_allocator::{AddressAllocator, SystemAllocator};
+use vm_device::interrupt::{
+    InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
+};
 use vm_device::{BusDevice, Resource, UserspaceMapping};
 use vm_memory::bitmap::AtomicBitmap;
 use vm_memory::{Address, GuestAddress};
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::ivshmem_client::{IvshmemClient, IvshmemPeers};
 
 const IVSHMEM_BAR0_IDX: usize = 0;
 const IVSHMEM_BAR1_IDX: usize = 1;
@@ -34,7 +42,28 @@ const IVSHMEM_DEVICE_ID: u16 = 0x1110;
 
 const IVSHMEM_REG_BAR_SIZE: u64 = 0x100;
 
+// BAR0 registers
+const IVSHMEM_REG_INTR_MASK: u64 = 0x0;
+const IVSHMEM_REG_INTR_STATUS: u64 = 0x4;
+const IVSHMEM_REG_IV_POSITION: u64 = 0x8;
+const IVSHMEM_REG_DOORBELL: u64 = 0xc;
+
+// BAR1 holds the MSI-X table and pending bit array
+const IVSHMEM_MSIX_BAR_SIZE: u64 = 0x1000;
+const IVSHMEM_MSIX_TABLE_OFFSET: u64 = 0x0;
+const IVSHMEM_MSIX_PBA_OFFSET: u64 = 0x800;
+
+/// Maximum number of interrupt vectors in doorbell mode
+pub const IVSHMEM_MAX_VECTORS: u16 = 64;
+
+// Doorbell worker events, vector `n` is reported as IVSHMEM_VECTOR_EVENT + n
+const IVSHMEM_KILL_EVENT: u64 = 0;
+const IVSHMEM_SERVER_EVENT: u64 = 1;
+const IVSHMEM_VECTOR_EVENT: u64 = 2;
+
 type MmapRegion = vm_memory::MmapRegion<AtomicBitmap>;
+/// MSI-X table of the doorbell, with the interrupts it drives
+type DoorbellMsix = (Arc<Mutex<MsixConfig>>, Arc<dyn InterruptSourceGroup>);
 
 #[derive(Debug, Error)]
 pub enum IvshmemError {
@@ -50,6 +79,18 @@ pub enum IvshmemError {
     CreateUserspaceMapping,
     #[error("Failed to remove old userspace mapping.")]
     RemoveUserspaceMapping,
+    #[error("Failed to retrieve MsixConfigState: {0}")]
+    RetrieveMsixConfigState(#[source] anyhow::Error),
+    #[error("Failed creating MSI interrupt group: {0}")]
+    CreateInterruptGroup(#[source] io::Error),
+    #[error("Failed creating MSI-X configuration: {0}")]
+    CreateMsixConfig(#[source] anyhow::Error),
+    #[error("Failed to set up MSI-X capability: {0}")]
+    CapabilitiesSetup(#[source] PciDeviceError),
+    #[error("Failed to create the doorbell kill event: {0}")]
+    CreateKillEvent(#[source] io::Error),
+    #[error("Failed to spawn the doorbell thread: {0}")]
+    SpawnDoorbellThread(#[source] io::Error),
 }
 
 #[derive(Copy, Clone)]
@@ -74,20 +115,46 @@ pub trait IvshmemOps: Send + Sync {
     fn unmap_ram_region(&mut self, mapping: UserspaceMapping) -> Result<(), IvshmemError>;
 }
 
+/// ivshmem-doorbell parameters
+pub struct IvshmemDoorbellConfig<'a> {
+    /// Connection to the ivshmem server the peers are registered with
+    pub client: IvshmemClient,
+    /// Number of MSI-X vectors
+    pub vectors: u16,
+    pub pci_device_bdf: u32,
+    pub interrupt_manager: &'a dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>,
+}
+
+/// ivshmem-doorbell state
+struct Doorbell {
+    msix_config: Arc<Mutex<MsixConfig>>,
+    peers: Arc<Mutex<IvshmemPeers>>,
+    kill_evt: EventFd,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
 /// Inner-Vm Shared Memory Device (Ivshmem device)
 ///
 /// This device can share memory between host and guest(ivshmem-plain)
 /// and share memory between guests(ivshmem-doorbell).
-/// But only ivshmem-plain support now, ivshmem-doorbell doesn't support yet.
+///
+/// In doorbell mode, the peers and their interrupt vectors are handed out by
+/// an ivshmem server. Writing the Doorbell register signals a vector of
+/// another peer, and the vectors of this peer are delivered to the guest as
+/// MSI-X interrupts. Legacy interrupts are not supported, without MSI-X the
+/// guest can only poll IntrStatus.
 pub struct IvshmemDevice {
     id: String,
 
-    // ivshmem device registers
-    // (only used for ivshmem-doorbell, ivshmem-doorbell don't support yet)
-    _interrupt_mask: u32,
-    _interrupt_status: Arc<AtomicU32>,
-    _iv_position: u32,
-    _doorbell: u32,
+    // ivshmem device registers (only used for ivshmem-doorbell)
+    interrupt_mask: u32,
+    interrupt_status: Arc<AtomicU32>,
+    iv_position: u32,
+    doorbell_reg: u32,
+
+    doorbell: Option<Doorbell>,
+    // Signaled on every Doorbell write, lets the VMM itself be rung
+    doorbell_evt: Option<EventFd>,
 
     // PCI configuration registers.
     configuration: PciConfiguration,
@@ -114,6 +181,7 @@ impl IvshmemDevice {
         region_size: u64,
         backend_file: Option<PathBuf>,
         ivshmem_ops: Arc<Mutex<dyn IvshmemOps>>,
+        doorbell: Option<IvshmemDoorbellConfig>,
         snapshot: Option<&Snapshot>,
     ) -> Result<Self, IvshmemError> {
         let pci_configuration_state = vm_migration::state_from_id(snapshot, PCI_CONFIGURATION_ID)
@@ -133,7 +201,23 @@ impl IvshmemDevice {
                 ))
             })?;
 
-        let configuration = PciConfiguration::new(
+        let (msix, msix_cap) = match doorbell.as_ref() {
+            Some(doorbell) => {
+                let (msix_config, interrupt_source_group) =
+                    Self::create_msix(doorbell, snapshot)?;
+                let msix_cap = MsixCap::new(
+                    IVSHMEM_BAR1_IDX as u8,
+                    doorbell.vectors,
+                    IVSHMEM_MSIX_TABLE_OFFSET as u32,
+                    IVSHMEM_BAR1_IDX as u8,
+                    IVSHMEM_MSIX_PBA_OFFSET as u32,
+                );
+                (Some((msix_config, interrupt_source_group)), Some(msix_cap))
+            }
+            None => (None, None),
+        };
+
+        let mut configuration = PciConfiguration::new(
             IVSHMEM_VENDOR_ID,
             IVSHMEM_DEVICE_ID,
             0x1,
@@ -143,19 +227,26 @@ impl IvshmemDevice {
             PciHeaderType::Device,
             0,
             0,
-            None,
+            msix.as_ref().map(|(msix_config, _)| msix_config.clone()),
             pci_configuration_state,
         );
+        if let Some(msix_cap) = msix_cap {
+            configuration.add_capability(&msix_cap).map_err(|e| {
+                IvshmemError::CapabilitiesSetup(PciDeviceError::CapabilitiesSetup(e))
+            })?;
+        }
 
-        let device = if let Some(s) = state {
+        let mut device = if let Some(s) = state {
             IvshmemDevice {
                 id,
                 configuration,
                 bar_regions: vec![],
-                _interrupt_mask: s.interrupt_mask,
-                _interrupt_status: Arc::new(AtomicU32::new(s.interrupt_status)),
-                _iv_position: s.iv_position,
-                _doorbell: s.doorbell,
+                interrupt_mask: s.interrupt_mask,
+                interrupt_status: Arc::new(AtomicU32::new(s.interrupt_status)),
+                iv_position: s.iv_position,
+                doorbell_reg: s.doorbell,
+                doorbell: None,
+                doorbell_evt: None,
                 region_size,
                 ivshmem_ops,
                 region: None,
@@ -167,10 +258,12 @@ impl IvshmemDevice {
                 id,
                 configuration,
                 bar_regions: vec![],
-                _interrupt_mask: 0,
-                _interrupt_status: Arc::new(AtomicU32::new(0)),
-                _iv_position: 0,
-                _doorbell: 0,
+                interrupt_mask: 0,
+                interrupt_status: Arc::new(AtomicU32::new(0)),
+                iv_position: 0,
+                doorbell_reg: 0,
+                doorbell: None,
+                doorbell_evt: None,
                 region_size,
                 ivshmem_ops,
                 region: None,
@@ -178,9 +271,88 @@ impl IvshmemDevice {
                 backend_file,
             }
         };
+
+        if let (Some(doorbell), Some((msix_config, interrupt_source_group))) = (doorbell, msix) {
+            device.start_doorbell(doorbell.client, msix_config, interrupt_source_group)?;
+        }
+
         Ok(device)
     }
 
+    fn create_msix(
+        doorbell: &IvshmemDoorbellConfig,
+        snapshot: Option<&Snapshot>,
+    ) -> Result<DoorbellMsix, IvshmemError> {
+        let msix_state = vm_migration::state_from_id(snapshot, pci::MSIX_CONFIG_ID).map_err(|e| {
+            IvshmemError::RetrieveMsixConfigState(anyhow!(
+                "Failed to get MsixConfigState from Snapshot: {e}"
+            ))
+        })?;
+
+        let interrupt_source_group = doorbell
+            .interrupt_manager
+            .create_group(MsiIrqGroupConfig {
+                base: 0,
+                count: doorbell.vectors as InterruptIndex,
+            })
+            .map_err(IvshmemError::CreateInterruptGroup)?;
+
+        let msix_config = MsixConfig::new(
+            doorbell.vectors,
+            interrupt_source_group.clone(),
+            doorbell.pci_device_bdf,
+            msix_state,
+        )
+        .map_err(|e| IvshmemError::CreateMsixConfig(anyhow!("{e}")))?;
+
+        Ok((Arc::new(Mutex::new(msix_config)), interrupt_source_group))
+    }
+
+    /// Spawn the thread tracking the peers and delivering the interrupts
+    /// of this peer
+    fn start_doorbell(
+        &mut self,
+        client: IvshmemClient,
+        msix_config: Arc<Mutex<MsixConfig>>,
+        interrupt_source_group: Arc<dyn InterruptSourceGroup>,
+    ) -> Result<(), IvshmemError> {
+        self.iv_position = client.id().into();
+
+        let peers = Arc::new(Mutex::new(IvshmemPeers::default()));
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(IvshmemError::CreateKillEvent)?;
+        let worker = DoorbellWorker {
+            client,
+            peers: peers.clone(),
+            msix_config: msix_config.clone(),
+            interrupt_source_group,
+            interrupt_status: self.interrupt_status.clone(),
+            kill_evt: kill_evt.try_clone().map_err(IvshmemError::CreateKillEvent)?,
+        };
+        let worker = thread::Builder::new()
+            .name("ivshmem-doorbell".to_string())
+            .spawn(move || {
+                if let Err(e) = worker.run() {
+                    error!("ivshmem doorbell thread failed: {e}");
+                }
+            })
+            .map_err(IvshmemError::SpawnDoorbellThread)?;
+
+        self.doorbell = Some(Doorbell {
+            msix_config,
+            peers,
+            kill_evt,
+            worker: Some(worker),
+        });
+
+        Ok(())
+    }
+
+    /// Signal `evt` whenever the guest writes the Doorbell register,
+    /// whatever the peer and vector it targets
+    pub fn set_doorbell_evt(&mut self, evt: EventFd) {
+        self.doorbell_evt = Some(evt);
+    }
+
     pub fn set_region(&mut self, region: Arc<MmapRegion>, userspace_mapping: UserspaceMapping) {
         self.region = Some(region);
         self.userspace_mapping = Some(userspace_mapping);
@@ -194,12 +366,177 @@ impl IvshmemDevice {
         self.configuration.get_bar_addr(IVSHMEM_BAR2_IDX)
     }
 
+    fn msix_bar_addr(&self) -> u64 {
+        self.configuration.get_bar_addr(IVSHMEM_BAR1_IDX)
+    }
+
     fn state(&self) -> IvshmemDeviceState {
         IvshmemDeviceState {
-            interrupt_mask: self._interrupt_mask,
-            interrupt_status: self._interrupt_status.load(Ordering::SeqCst),
-            iv_position: self._iv_position,
-            doorbell: self._doorbell,
+            interrupt_mask: self.interrupt_mask,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+            iv_position: self.iv_position,
+            doorbell: self.doorbell_reg,
+        }
+    }
+
+    fn read_reg(&mut self, offset: u64) -> u32 {
+        match offset {
+            IVSHMEM_REG_INTR_MASK => self.interrupt_mask,
+            // Reading the status acknowledges the interrupts
+            IVSHMEM_REG_INTR_STATUS => self.interrupt_status.swap(0, Ordering::SeqCst),
+            IVSHMEM_REG_IV_POSITION => self.iv_position,
+            // The doorbell is write-only
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, value: u32) {
+        match offset {
+            IVSHMEM_REG_INTR_MASK => self.interrupt_mask = value,
+            IVSHMEM_REG_INTR_STATUS => {
+                self.interrupt_status.store(value, Ordering::SeqCst);
+            }
+            IVSHMEM_REG_DOORBELL => {
+                self.doorbell_reg = value;
+                if let Some(evt) = self.doorbell_evt.as_ref()
+                    && let Err(e) = evt.write(1)
+                {
+                    error!("Failed to signal the ivshmem doorbell event: {e}");
+                }
+
+                let Some(doorbell) = self.doorbell.as_ref() else {
+                    debug!("No ivshmem peer to ring in plain mode");
+                    return;
+                };
+
+                let peer_id = (value >> 16) as u16;
+                let vector = value as u16;
+                if let Err(e) = doorbell.peers.lock().unwrap().notify(peer_id, vector) {
+                    error!("Failed to ring ivshmem peer {peer_id} vector {vector}: {e}");
+                }
+            }
+            _ => warn!("Ignoring write to ivshmem register 0x{offset:x}"),
+        }
+    }
+}
+
+impl Drop for IvshmemDevice {
+    fn drop(&mut self) {
+        if let Some(doorbell) = self.doorbell.as_mut() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = doorbell.kill_evt.write(1);
+            if let Some(worker) = doorbell.worker.take() {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+/// Thread following the peer updates of the ivshmem server and turning the
+/// vectors of this peer into MSI-X interrupts
+struct DoorbellWorker {
+    client: IvshmemClient,
+    peers: Arc<Mutex<IvshmemPeers>>,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    interrupt_source_group: Arc<dyn InterruptSourceGroup>,
+    interrupt_status: Arc<AtomicU32>,
+    kill_evt: EventFd,
+}
+
+impl DoorbellWorker {
+    fn run(&self) -> io::Result<()> {
+        let epoll = Epoll::new()?;
+        epoll.ctl(
+            ControlOperation::Add,
+            self.kill_evt.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, IVSHMEM_KILL_EVENT),
+        )?;
+        epoll.ctl(
+            ControlOperation::Add,
+            self.client.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, IVSHMEM_SERVER_EVENT),
+        )?;
+
+        let id = self.client.id();
+        let mut watched_vectors = 0;
+        let mut events = [EpollEvent::default(); 16];
+        loop {
+            let num_events = match epoll.wait(-1, &mut events) {
+                Ok(num_events) => num_events,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for event in events.iter().take(num_events) {
+                match event.data() {
+                    IVSHMEM_KILL_EVENT => return Ok(()),
+                    IVSHMEM_SERVER_EVENT => match self.client.recv() {
+                        Ok(message) => {
+                            let mut peers = self.peers.lock().unwrap();
+                            peers.update(message);
+
+                            // The vectors of this peer are announced one at a time
+                            for (vector, eventfd) in
+                                peers.vectors(id).iter().enumerate().skip(watched_vectors)
+                            {
+                                epoll.ctl(
+                                    ControlOperation::Add,
+                                    eventfd.as_raw_fd(),
+                                    EpollEvent::new(
+                                        EventSet::IN,
+                                        IVSHMEM_VECTOR_EVENT + vector as u64,
+                                    ),
+                                )?;
+                                watched_vectors = vector + 1;
+                            }
+                        }
+                        Err(e) => {
+                            // The peers known so far can still be signalled
+                            error!("Lost the ivshmem server connection: {e}");
+                            epoll.ctl(
+                                ControlOperation::Delete,
+                                self.client.as_raw_fd(),
+                                EpollEvent::default(),
+                            )?;
+                        }
+                    },
+                    data => {
+                        let vector = (data - IVSHMEM_VECTOR_EVENT) as u16;
+                        let peers = self.peers.lock().unwrap();
+                        if let Some(eventfd) = peers.vectors(id).get(vector as usize) {
+                            let _ = eventfd.read();
+                        }
+                        drop(peers);
+                        self.signal_vector(vector);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Deliver `vector` to the guest
+    fn signal_vector(&self, vector: u16) {
+        let config = &mut self.msix_config.lock().unwrap();
+        if !config.enabled() {
+            self.interrupt_status.fetch_or(1, Ordering::SeqCst);
+            return;
+        }
+        let Some(entry) = config.table_entries.get(vector as usize) else {
+            warn!("Ignoring ivshmem vector {vector} beyond the MSI-X table");
+            return;
+        };
+        // Masked vectors only latch the pending bit, the guest picks the
+        // interrupt up once it unmasks the entry.
+        if config.masked() || entry.masked() {
+            config.set_pba_bit(vector, false);
+            return;
+        }
+
+        if let Err(e) = self
+            .interrupt_source_group
+            .trigger(vector as InterruptIndex)
+        {
+            error!("Failed to trigger ivshmem vector {vector}: {e:?}");
         }
     }
 }
@@ -224,6 +561,7 @@ impl PciDevice for IvshmemDevice {
     ) -> std::result::Result<Vec<PciBarConfiguration>, PciDeviceError> {
         let mut bars = Vec::new();
         let mut bar0_addr = None;
+        let mut bar1_addr = None;
         let mut bar2_addr = None;
 
         let restoring = resources.is_some();
@@ -234,7 +572,9 @@ impl PciDevice for IvshmemDevice {
                         IVSHMEM_BAR0_IDX => {
                             bar0_addr = Some(GuestAddress(base));
                         }
-                        IVSHMEM_BAR1_IDX => {}
+                        IVSHMEM_BAR1_IDX => {
+                            bar1_addr = Some(GuestAddress(base));
+                        }
                         IVSHMEM_BAR2_IDX => {
                             bar2_addr = Some(GuestAddress(base));
                         }
@@ -247,7 +587,10 @@ impl PciDevice for IvshmemDevice {
                     }
                 }
             }
-            if bar0_addr.is_none() || bar2_addr.is_none() {
+            if bar0_addr.is_none()
+                || bar2_addr.is_none()
+                || (self.doorbell.is_some() && bar1_addr.is_none())
+            {
                 return Err(PciDeviceError::MissingResource);
             }
         }
@@ -266,6 +609,23 @@ impl PciDevice for IvshmemDevice {
             .set_prefetchable(PciBarPrefetchable::NotPrefetchable);
 
         // BAR1 holds MSI-X table and PBA (only ivshmem-doorbell).
+        let bar1 = if self.doorbell.is_some() {
+            let bar1_addr = mmio32_allocator
+                .allocate(bar1_addr, IVSHMEM_MSIX_BAR_SIZE, Some(IVSHMEM_MSIX_BAR_SIZE))
+                .ok_or(PciDeviceError::IoAllocationFailed(IVSHMEM_MSIX_BAR_SIZE))?;
+            debug!("ivshmem bar1 address 0x{:x}", bar1_addr.0);
+
+            Some(
+                PciBarConfiguration::default()
+                    .set_index(IVSHMEM_BAR1_IDX)
+                    .set_address(bar1_addr.raw_value())
+                    .set_size(IVSHMEM_MSIX_BAR_SIZE)
+                    .set_region_type(PciBarRegionType::Memory32BitRegion)
+                    .set_prefetchable(PciBarPrefetchable::NotPrefetchable),
+            )
+        } else {
+            None
+        };
 
         // BAR2 maps the shared memory object
         let bar2_size = self.region_size;
@@ -285,12 +645,18 @@ impl PciDevice for IvshmemDevice {
             self.configuration
                 .add_pci_bar(&bar0)
                 .map_err(|e| PciDeviceError::IoRegistrationFailed(bar0_addr.raw_value(), e))?;
+            if let Some(bar1) = bar1.as_ref() {
+                self.configuration
+                    .add_pci_bar(bar1)
+                    .map_err(|e| PciDeviceError::IoRegistrationFailed(bar1.addr(), e))?;
+            }
             self.configuration
                 .add_pci_bar(&bar2)
                 .map_err(|e| PciDeviceError::IoRegistrationFailed(bar2_addr.raw_value(), e))?;
         }
 
         bars.push(bar0);
+        bars.extend(bar1);
         bars.push(bar2);
         self.bar_regions = bars.clone();
 
@@ -326,29 +692,58 @@ impl PciDevice for IvshmemDevice {
     fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
         debug!("read base {base:x} offset {offset}");
 
-        let mut bar_idx = 0;
-        for (idx, bar) in self.bar_regions.iter().enumerate() {
-            if bar.addr() == base {
-                bar_idx = idx;
+        if base == self.config_bar_addr() {
+            if data.len() == 4 {
+                let value = self.read_reg(offset);
+                LittleEndian::write_u32(data, value);
+            } else {
+                warn!("Unsupported {} byte read of ivshmem register {offset}", data.len());
+                data.fill(0);
             }
-        }
-        match bar_idx {
-            // bar 0
-            0 => {
-                // ivshmem don't use interrupt, we return zero now.
-                LittleEndian::write_u32(data, 0);
-            }
-            // bar 2
-            1 => warn!("Unexpected read ivshmem memory idx: {offset}"),
-            _ => {
-                warn!("Invalid bar_idx: {bar_idx}");
+        } else if self.doorbell.is_some() && base == self.msix_bar_addr() {
+            let msix_config = &self.doorbell.as_ref().unwrap().msix_config;
+            if offset < IVSHMEM_MSIX_PBA_OFFSET {
+                msix_config
+                    .lock()
+                    .unwrap()
+                    .read_table(offset - IVSHMEM_MSIX_TABLE_OFFSET, data);
+            } else {
+                msix_config
+                    .lock()
+                    .unwrap()
+                    .read_pba(offset - IVSHMEM_MSIX_PBA_OFFSET, data);
             }
+        } else {
+            warn!("Unexpected read ivshmem memory idx: {offset}");
         }
     }
 
-    fn write_bar(&mut self, base: u64, offset: u64, _data: &[u8]) -> Option<Arc<Barrier>> {
+    fn write_bar(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
         debug!("write base {base:x} offset {offset}");
-        warn!("Unexpected write ivshmem memory idx: {offset}");
+
+        if base == self.config_bar_addr() {
+            if data.len() == 4 {
+                self.write_reg(offset, LittleEndian::read_u32(data));
+            } else {
+                warn!("Unsupported {} byte write to ivshmem register {offset}", data.len());
+            }
+        } else if self.doorbell.is_some() && base == self.msix_bar_addr() {
+            let msix_config = &self.doorbell.as_ref().unwrap().msix_config;
+            if offset < IVSHMEM_MSIX_PBA_OFFSET {
+                msix_config
+                    .lock()
+                    .unwrap()
+                    .write_table(offset - IVSHMEM_MSIX_TABLE_OFFSET, data);
+            } else {
+                msix_config
+                    .lock()
+                    .unwrap()
+                    .write_pba(offset - IVSHMEM_MSIX_PBA_OFFSET, data);
+            }
+        } else {
+            warn!("Unexpected write ivshmem memory idx: {offset}");
+        }
+
         None
     }
 
@@ -398,14 +793,20 @@ impl Snapshottable for IvshmemDevice {
         self.id.clone()
     }
 
-    // The snapshot/restore (also live migration) support only work for ivshmem-plain mode.
-    // Additional work is needed for supporting ivshmem-doorbell.
+    // In doorbell mode, the peers are not part of the snapshot. The restored
+    // device registers again with the ivshmem server, which may assign it
+    // another IVPosition.
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
         let mut snapshot = Snapshot::new_from_state(&self.state())?;
 
         // Snapshot PciConfiguration
         snapshot.add_snapshot(self.configuration.id(), self.configuration.snapshot()?);
 
+        if let Some(doorbell) = self.doorbell.as_ref() {
+            let mut msix_config = doorbell.msix_config.lock().unwrap();
+            snapshot.add_snapshot(msix_config.id(), msix_config.snapshot()?);
+        }
+
         Ok(snapshot)
     }
 }
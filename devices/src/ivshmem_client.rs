@@ -0,0 +1,267 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! ivshmem-server client
+//!
+//! Peer side of the UNIX socket protocol spoken by QEMU's ivshmem-server.
+//! Every message is a little endian 64-bit integer, optionally carrying a
+//! file descriptor:
+//!
+//! 1. the protocol version, without file descriptor
+//! 2. the id assigned to this peer, without file descriptor
+//! 3. -1 with the shared memory file descriptor
+//! 4. for every peer, this one included, one message per interrupt vector
+//!    carrying the peer id and the eventfd of the vector
+//!
+//! Afterwards, a peer id with a file descriptor announces a vector of a new
+//! peer, and a peer id without file descriptor announces the peer left.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use thiserror::Error;
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+/// Version of the protocol implemented by ivshmem-server
+pub const IVSHMEM_PROTOCOL_VERSION: i64 = 0;
+
+#[derive(Debug, Error)]
+pub enum IvshmemClientError {
+    #[error("Failed to connect to the ivshmem server")]
+    Connect(#[source] io::Error),
+    #[error("Failed to receive a message from the ivshmem server")]
+    Receive(#[source] io::Error),
+    #[error("The ivshmem server closed the connection")]
+    Disconnected,
+    #[error("Unsupported ivshmem protocol version: {0}")]
+    UnsupportedVersion(i64),
+    #[error("Unexpected message from the ivshmem server: {0}")]
+    UnexpectedMessage(i64),
+    #[error("The ivshmem server did not send the shared memory")]
+    MissingSharedMemory,
+}
+
+type Result<T> = std::result::Result<T, IvshmemClientError>;
+
+/// Peer update sent by the ivshmem server once connected
+#[derive(Debug)]
+pub enum ServerMessage {
+    /// Eventfd of the next interrupt vector of `peer_id`
+    PeerVector { peer_id: u16, eventfd: EventFd },
+    /// `peer_id` left
+    PeerGone(u16),
+}
+
+/// Interrupt vectors of every peer known to the server, this one included
+#[derive(Default)]
+pub struct IvshmemPeers {
+    peers: HashMap<u16, Vec<EventFd>>,
+}
+
+impl IvshmemPeers {
+    /// Apply a peer update received from the server
+    pub fn update(&mut self, message: ServerMessage) {
+        match message {
+            ServerMessage::PeerVector { peer_id, eventfd } => {
+                self.peers.entry(peer_id).or_default().push(eventfd);
+            }
+            ServerMessage::PeerGone(peer_id) => {
+                self.peers.remove(&peer_id);
+            }
+        }
+    }
+
+    /// Interrupt vectors of `peer_id`
+    pub fn vectors(&self, peer_id: u16) -> &[EventFd] {
+        self.peers.get(&peer_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Ring `vector` of `peer_id`
+    pub fn notify(&self, peer_id: u16, vector: u16) -> io::Result<()> {
+        let Some(eventfd) = self.vectors(peer_id).get(vector as usize) else {
+            warn!("Ignoring doorbell for unknown ivshmem peer {peer_id} vector {vector}");
+            return Ok(());
+        };
+        eventfd.write(1)
+    }
+}
+
+/// Connection to an ivshmem server
+pub struct IvshmemClient {
+    socket: UnixStream,
+    id: u16,
+    shm: File,
+}
+
+impl IvshmemClient {
+    /// Connect to the server listening on `path` and retrieve the peer id
+    /// and the shared memory
+    pub fn connect(path: &Path) -> Result<Self> {
+        let socket = UnixStream::connect(path).map_err(IvshmemClientError::Connect)?;
+        Self::handshake(socket)
+    }
+
+    fn handshake(socket: UnixStream) -> Result<Self> {
+        let (version, _) = recv_message(&socket)?;
+        if version != IVSHMEM_PROTOCOL_VERSION {
+            return Err(IvshmemClientError::UnsupportedVersion(version));
+        }
+
+        let (id, fd) = recv_message(&socket)?;
+        if fd.is_some() || !(0..=i64::from(u16::MAX)).contains(&id) {
+            return Err(IvshmemClientError::UnexpectedMessage(id));
+        }
+
+        match recv_message(&socket)? {
+            (-1, Some(shm)) => Ok(IvshmemClient {
+                socket,
+                id: id as u16,
+                shm,
+            }),
+            _ => Err(IvshmemClientError::MissingSharedMemory),
+        }
+    }
+
+    /// Id the server assigned to this peer
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Shared memory handed out by the server
+    pub fn shm(&self) -> &File {
+        &self.shm
+    }
+
+    /// Path the shared memory can be opened from, as long as the client
+    /// is alive
+    pub fn shm_path(&self) -> PathBuf {
+        PathBuf::from(format!("/proc/self/fd/{}", self.shm.as_raw_fd()))
+    }
+
+    /// Wait for the next peer update
+    pub fn recv(&self) -> Result<ServerMessage> {
+        let (peer_id, fd) = recv_message(&self.socket)?;
+        if !(0..=i64::from(u16::MAX)).contains(&peer_id) {
+            return Err(IvshmemClientError::UnexpectedMessage(peer_id));
+        }
+
+        let peer_id = peer_id as u16;
+        Ok(match fd {
+            Some(file) => ServerMessage::PeerVector {
+                peer_id,
+                // SAFETY: the server hands out eventfds, the file is owned
+                // by the EventFd from now on.
+                eventfd: unsafe { EventFd::from_raw_fd(file.into_raw_fd()) },
+            },
+            None => ServerMessage::PeerGone(peer_id),
+        })
+    }
+}
+
+impl AsRawFd for IvshmemClient {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+/// Receive one 64-bit message and its optional file descriptor
+fn recv_message(socket: &UnixStream) -> Result<(i64, Option<File>)> {
+    let mut buf = [0u8; 8];
+    let (len, file) = socket
+        .recv_with_fd(&mut buf)
+        .map_err(|e| IvshmemClientError::Receive(io::Error::from_raw_os_error(e.errno())))?;
+    match len {
+        0 => Err(IvshmemClientError::Disconnected),
+        8 => Ok((i64::from_le_bytes(buf), file)),
+        _ => Err(IvshmemClientError::Receive(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Truncated ivshmem server message: {len} bytes"),
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    fn send(server: &mut UnixStream, value: i64, fd: Option<RawFd>) {
+        match fd {
+            Some(fd) => {
+                server.send_with_fd(&value.to_le_bytes()[..], fd).unwrap();
+            }
+            None => server.write_all(&value.to_le_bytes()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_ivshmem_client_protocol() {
+        let (mut server, socket) = UnixStream::pair().unwrap();
+        let shm = TempFile::new().unwrap();
+        shm.as_file().set_len(0x1000).unwrap();
+
+        send(&mut server, IVSHMEM_PROTOCOL_VERSION, None);
+        send(&mut server, 3, None);
+        send(&mut server, -1, Some(shm.as_file().as_raw_fd()));
+        let client = IvshmemClient::handshake(socket).unwrap();
+        assert_eq!(client.id(), 3);
+        assert_eq!(client.shm().metadata().unwrap().len(), 0x1000);
+
+        // Vectors of another peer, then of this one
+        let peer_vectors = [
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+        ];
+        let own_vector = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        for vector in peer_vectors.iter() {
+            send(&mut server, 1, Some(vector.as_raw_fd()));
+        }
+        send(&mut server, 3, Some(own_vector.as_raw_fd()));
+
+        let mut peers = IvshmemPeers::default();
+        for _ in 0..3 {
+            peers.update(client.recv().unwrap());
+        }
+        assert_eq!(peers.vectors(1).len(), 2);
+        assert_eq!(peers.vectors(3).len(), 1);
+
+        // Ringing a peer signals the eventfd handed out by the server
+        peers.notify(1, 1).unwrap();
+        assert_eq!(peer_vectors[1].read().unwrap(), 1);
+        peer_vectors[0].read().unwrap_err();
+        peers.notify(3, 0).unwrap();
+        assert_eq!(own_vector.read().unwrap(), 1);
+        // Unknown vectors are ignored
+        peers.notify(1, 2).unwrap();
+        peers.notify(2, 0).unwrap();
+
+        send(&mut server, 1, None);
+        peers.update(client.recv().unwrap());
+        assert!(peers.vectors(1).is_empty());
+
+        drop(server);
+        assert!(matches!(
+            client.recv(),
+            Err(IvshmemClientError::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn test_ivshmem_client_bad_version() {
+        let (mut server, socket) = UnixStream::pair().unwrap();
+        send(&mut server, 1, None);
+        assert!(matches!(
+            IvshmemClient::handshake(socket),
+            Err(IvshmemClientError::UnsupportedVersion(1))
+        ));
+    }
+}
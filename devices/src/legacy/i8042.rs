@@ -35,7 +35,9 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
 
+use event_monitor::event;
 use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use vm_device::interrupt::InterruptSourceGroup;
 use vm_device::BusDevice;
 use vmm_sys_util::eventfd::EventFd;
@@ -59,6 +61,14 @@ const SCANCODE_RELEASE_PREFIX: u8 = 0xF0;
 /// PS/2 mouse packet size (Intellimouse: 4 bytes)
 const MOUSE_PACKET_SIZE: usize = 4;
 
+/// Sample rate of the PS/2 mouse after a reset
+const MOUSE_DEFAULT_SAMPLE_RATE: u8 = 100;
+
+/// Sample rates the guest sets in a row to unlock the Intellimouse and
+/// Intellimouse Explorer protocols
+const INTELLIMOUSE_KNOCK: [u8; 3] = [200, 100, 80];
+const INTELLIMOUSE_EXPLORER_KNOCK: [u8; 3] = [200, 200, 80];
+
 // ============================================================================
 // PS/2 Commands
 // ============================================================================
@@ -77,6 +87,45 @@ mod cmd {
     pub const WRITE_TO_MOUSE: u8 = 0xD4;
 }
 
+/// PS/2 keyboard commands
+mod kbd_cmd {
+    pub const SET_LEDS: u8 = 0xED;
+    pub const ECHO: u8 = 0xEE;
+    pub const SCANCODE_SET: u8 = 0xF0;
+    pub const IDENTIFY: u8 = 0xF2;
+    pub const SET_TYPEMATIC: u8 = 0xF3;
+    pub const ENABLE_SCANNING: u8 = 0xF4;
+    pub const DISABLE_SCANNING: u8 = 0xF5;
+    pub const SET_DEFAULTS: u8 = 0xF6;
+    pub const RESET: u8 = 0xFF;
+}
+
+/// PS/2 keyboard responses
+mod kbd_resp {
+    pub const ACK: u8 = 0xFA;
+    pub const RESEND: u8 = 0xFE;
+    pub const SELF_TEST_PASSED: u8 = 0xAA;
+}
+
+/// PS/2 mouse commands
+mod mouse_cmd {
+    pub const SET_RESOLUTION: u8 = 0xE8;
+    pub const STATUS_REQUEST: u8 = 0xE9;
+    pub const GET_ID: u8 = 0xF2;
+    pub const SET_SAMPLE_RATE: u8 = 0xF3;
+    pub const ENABLE_REPORTING: u8 = 0xF4;
+    pub const DISABLE_REPORTING: u8 = 0xF5;
+    pub const SET_DEFAULTS: u8 = 0xF6;
+    pub const RESET: u8 = 0xFF;
+}
+
+/// PS/2 keyboard LED bits, as set with the 0xED command
+mod leds {
+    pub const SCROLL_LOCK: u8 = 0x01;
+    pub const NUM_LOCK: u8 = 0x02;
+    pub const CAPS_LOCK: u8 = 0x04;
+}
+
 /// Controller Command Byte bits
 mod ccb {
     pub const KBD_INT: u8 = 0x01; // Enable keyboard interrupt
@@ -115,6 +164,14 @@ mod mouse_btn {
     pub const Y_OVERFLOW: u8 = 0x40;
 }
 
+/// Intellimouse Explorer button bits for byte 4 of the packet, above the
+/// 4-bit wheel movement
+mod explorer_btn {
+    pub const WHEEL_MASK: u8 = 0x0F;
+    pub const SIDE: u8 = 0x10; // Button 4
+    pub const EXTRA: u8 = 0x20; // Button 5
+}
+
 // ============================================================================
 // Input Event Types
 // ============================================================================
@@ -128,6 +185,74 @@ pub struct KeyboardEvent {
     pub release: bool,
 }
 
+/// Scancode set of the bytes read by the guest from the data port
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// IBM PC XT scancodes, also what the controller hands out when it
+    /// translates
+    Set1,
+    /// IBM PC AT scancodes, the default of a PS/2 keyboard
+    #[default]
+    Set2,
+}
+
+/// Protocol of the PS/2 mouse, which the guest switches to by setting a
+/// magic sequence of sample rates
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MouseProtocol {
+    /// 3-byte packets without a wheel, device ID 0x00
+    Standard,
+    /// 4-byte packets with a wheel, device ID 0x03
+    #[default]
+    Intellimouse,
+    /// 4-byte packets with a wheel and two more buttons, device ID 0x04
+    IntellimouseExplorer,
+}
+
+impl MouseProtocol {
+    /// Device ID the mouse answers with
+    fn id(self) -> u8 {
+        match self {
+            MouseProtocol::Standard => 0x00,
+            MouseProtocol::Intellimouse => 0x03,
+            MouseProtocol::IntellimouseExplorer => 0x04,
+        }
+    }
+
+    /// Size of a movement packet
+    fn packet_size(self) -> usize {
+        match self {
+            MouseProtocol::Standard => 3,
+            _ => MOUSE_PACKET_SIZE,
+        }
+    }
+
+    /// Name used in logs and events
+    pub fn name(self) -> &'static str {
+        match self {
+            MouseProtocol::Standard => "standard",
+            MouseProtocol::Intellimouse => "intellimouse",
+            MouseProtocol::IntellimouseExplorer => "intellimouse-explorer",
+        }
+    }
+}
+
+/// Keyboard and mouse state set up by the guest
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Ps2State {
+    /// Keyboard LEDs
+    pub num_lock: bool,
+    pub caps_lock: bool,
+    pub scroll_lock: bool,
+    /// The keyboard port is enabled and the keyboard scanning
+    pub keyboard_enabled: bool,
+    /// The mouse port is enabled and the mouse reporting
+    pub mouse_enabled: bool,
+    /// Protocol the guest switched the mouse to
+    pub mouse_protocol: MouseProtocol,
+}
+
 /// Mouse input event
 #[derive(Clone, Debug, Default)]
 pub struct MouseEvent {
@@ -147,6 +272,10 @@ pub struct MouseButtons {
     pub left: bool,
     pub right: bool,
     pub middle: bool,
+    /// Only reported with the Intellimouse Explorer protocol
+    pub side: bool,
+    /// Only reported with the Intellimouse Explorer protocol
+    pub extra: bool,
 }
 
 // ============================================================================
@@ -170,14 +299,23 @@ pub struct I8042Device {
     // Keyboard state
     kbd_buffer: VecDeque<u8>,
     kbd_interrupt: Option<Arc<dyn InterruptSourceGroup>>,
+    kbd_pending_command: Option<u8>,
+    kbd_scancode_set: ScancodeSet,
+    kbd_leds: u8,
+    kbd_scanning: bool,
 
     // Mouse state
     mouse_buffer: VecDeque<u8>,
     mouse_interrupt: Option<Arc<dyn InterruptSourceGroup>>,
     mouse_buttons: MouseButtons,
+    mouse_pending_command: Option<u8>,
+    mouse_reporting: bool,
+    mouse_resolution: u8,
+    // Last sample rates set, to recognize the protocol knocks
+    mouse_sample_rates: [u8; 3],
 
     // Device identification
-    mouse_id: u8, // 0x00: standard, 0x03: Intellimouse, 0x04: Intellimouse Explorer
+    mouse_protocol: MouseProtocol,
 }
 
 impl I8042Device {
@@ -191,10 +329,34 @@ impl I8042Device {
             pending_command: None,
             kbd_buffer: VecDeque::with_capacity(MAX_BUFFER_SIZE),
             kbd_interrupt: None,
+            kbd_pending_command: None,
+            kbd_scancode_set: ScancodeSet::default(),
+            kbd_leds: 0,
+            kbd_scanning: true,
             mouse_buffer: VecDeque::with_capacity(MAX_BUFFER_SIZE),
             mouse_interrupt: None,
             mouse_buttons: MouseButtons::default(),
-            mouse_id: 0x03, // Intellimouse (supports scroll wheel)
+            mouse_pending_command: None,
+            mouse_reporting: false,
+            mouse_resolution: 2,
+            mouse_sample_rates: [MOUSE_DEFAULT_SAMPLE_RATE; 3],
+            // Intellimouse (supports scroll wheel) until the guest resets it
+            mouse_protocol: MouseProtocol::Intellimouse,
+        }
+    }
+
+    /// Keyboard and mouse state set up by the guest
+    ///
+    /// Input is injected whatever this state, the guest simply ignores the
+    /// devices it didn't enable.
+    pub fn state(&self) -> Ps2State {
+        Ps2State {
+            num_lock: self.kbd_leds & leds::NUM_LOCK != 0,
+            caps_lock: self.kbd_leds & leds::CAPS_LOCK != 0,
+            scroll_lock: self.kbd_leds & leds::SCROLL_LOCK != 0,
+            keyboard_enabled: self.kbd_scanning && self.command_byte & ccb::KBD_DISABLE == 0,
+            mouse_enabled: self.mouse_reporting && self.command_byte & ccb::MOUSE_DISABLE == 0,
+            mouse_protocol: self.mouse_protocol,
         }
     }
 
@@ -255,20 +417,118 @@ impl I8042Device {
     }
 
     /// Inject raw scancode bytes directly
+    ///
+    /// The bytes are handed to the guest as they are, so they must be in
+    /// the [`scancode_set`](Self::scancode_set) the guest reads. A sequence
+    /// which doesn't fit in the buffer is dropped whole, so that the guest
+    /// never sees half of a multi-byte key.
     pub fn inject_keyboard_bytes(&mut self, bytes: &[u8]) {
         if self.command_byte & ccb::KBD_DISABLE != 0 {
             return;
         }
 
-        for &byte in bytes {
-            if self.kbd_buffer.len() < MAX_BUFFER_SIZE {
-                self.kbd_buffer.push_back(byte);
-            }
+        if self.kbd_buffer.len() + bytes.len() > MAX_BUFFER_SIZE {
+            warn!("Keyboard buffer overflow, dropping {} bytes", bytes.len());
+            return;
         }
+        self.kbd_buffer.extend(bytes);
 
         self.trigger_keyboard_interrupt();
     }
 
+    /// Scancode set the guest reads from the data port
+    ///
+    /// This is Set 1 when the guest selected it on the keyboard, or when
+    /// the controller translates Set 2 to Set 1 for it.
+    pub fn scancode_set(&self) -> ScancodeSet {
+        if self.command_byte & ccb::KBD_TRANSLATE != 0 {
+            ScancodeSet::Set1
+        } else {
+            self.kbd_scancode_set
+        }
+    }
+
+    /// Bytes the keyboard buffer can still take before the guest reads it
+    pub fn keyboard_space(&self) -> usize {
+        MAX_BUFFER_SIZE.saturating_sub(self.kbd_buffer.len())
+    }
+
+    /// Packets the mouse buffer can still take before the guest reads it,
+    /// in the size of the protocol the guest negotiated
+    pub fn mouse_space(&self) -> usize {
+        MAX_BUFFER_SIZE.saturating_sub(self.mouse_buffer.len()) / self.mouse_protocol.packet_size()
+    }
+
+    /// Handle a byte written by the guest to the keyboard
+    fn handle_keyboard_data(&mut self, data: u8) {
+        let response: &[u8] = match self.kbd_pending_command.take() {
+            Some(kbd_cmd::SCANCODE_SET) => match data {
+                // Report the current set, translated like any other byte
+                0 => match (
+                    self.kbd_scancode_set,
+                    self.command_byte & ccb::KBD_TRANSLATE != 0,
+                ) {
+                    (ScancodeSet::Set1, false) => &[kbd_resp::ACK, 0x01],
+                    (ScancodeSet::Set2, false) => &[kbd_resp::ACK, 0x02],
+                    (ScancodeSet::Set1, true) => &[kbd_resp::ACK, 0x43],
+                    (ScancodeSet::Set2, true) => &[kbd_resp::ACK, 0x41],
+                },
+                1 => {
+                    self.kbd_scancode_set = ScancodeSet::Set1;
+                    &[kbd_resp::ACK]
+                }
+                2 => {
+                    self.kbd_scancode_set = ScancodeSet::Set2;
+                    &[kbd_resp::ACK]
+                }
+                _ => {
+                    warn!("Unsupported keyboard scancode set {data}");
+                    &[kbd_resp::RESEND]
+                }
+            },
+            Some(kbd_cmd::SET_LEDS) => {
+                self.kbd_leds = data & (leds::SCROLL_LOCK | leds::NUM_LOCK | leds::CAPS_LOCK);
+                &[kbd_resp::ACK]
+            }
+            Some(kbd_cmd::SET_TYPEMATIC) => &[kbd_resp::ACK],
+            _ => match data {
+                kbd_cmd::SCANCODE_SET | kbd_cmd::SET_LEDS | kbd_cmd::SET_TYPEMATIC => {
+                    self.kbd_pending_command = Some(data);
+                    &[kbd_resp::ACK]
+                }
+                kbd_cmd::ECHO => &[kbd_cmd::ECHO],
+                // MF2 keyboard, which translation turns into 0x41
+                kbd_cmd::IDENTIFY => &[kbd_resp::ACK, 0xAB, 0x83],
+                kbd_cmd::ENABLE_SCANNING => {
+                    self.kbd_scanning = true;
+                    &[kbd_resp::ACK]
+                }
+                kbd_cmd::DISABLE_SCANNING => {
+                    self.kbd_scanning = false;
+                    self.kbd_scancode_set = ScancodeSet::default();
+                    &[kbd_resp::ACK]
+                }
+                kbd_cmd::SET_DEFAULTS => {
+                    self.kbd_scancode_set = ScancodeSet::default();
+                    &[kbd_resp::ACK]
+                }
+                kbd_cmd::RESET => {
+                    self.kbd_scanning = true;
+                    self.kbd_scancode_set = ScancodeSet::default();
+                    self.kbd_leds = 0;
+                    &[kbd_resp::ACK, kbd_resp::SELF_TEST_PASSED]
+                }
+                _ => {
+                    debug!("Keyboard data: 0x{:02X}", data);
+                    return;
+                }
+            },
+        };
+
+        self.kbd_buffer.extend(response);
+        self.trigger_keyboard_interrupt();
+    }
+
     /// Trigger keyboard interrupt (IRQ1)
     fn trigger_keyboard_interrupt(&mut self) {
         // Set output buffer full flag
@@ -319,24 +579,25 @@ impl I8042Device {
         // Update button state
         self.mouse_buttons = event.buttons.clone();
 
-        // Build PS/2 Intellimouse packet (4 bytes)
+        // Build PS/2 Intellimouse packet (4 bytes), without the wheel byte
+        // unless the guest switched the mouse to a protocol having one
         let packet = self.build_mouse_packet(&event);
+        let packet = &packet[..self.mouse_protocol.packet_size()];
 
         // Check buffer space
-        if self.mouse_buffer.len() + MOUSE_PACKET_SIZE > MAX_BUFFER_SIZE {
+        if self.mouse_buffer.len() + packet.len() > MAX_BUFFER_SIZE {
             warn!("Mouse buffer overflow, dropping event");
             return;
         }
 
         // Add packet to buffer
-        for byte in packet {
-            self.mouse_buffer.push_back(byte);
-        }
+        self.mouse_buffer.extend(packet);
 
         debug!(
-            "Injected mouse event: dx={}, dy={}, dz={}, buttons=({},{},{})",
+            "Injected mouse event: dx={}, dy={}, dz={}, buttons=({},{},{},{},{})",
             event.dx, event.dy, event.dz,
-            event.buttons.left, event.buttons.right, event.buttons.middle
+            event.buttons.left, event.buttons.right, event.buttons.middle,
+            event.buttons.side, event.buttons.extra
         );
 
         // Trigger mouse interrupt
@@ -373,10 +634,139 @@ impl I8042Device {
         // Y axis is inverted in PS/2 protocol
         let dy_inverted = -dy as u8;
 
-        // Scroll wheel (4-bit signed)
-        let dz = (event.dz.clamp(-8, 7) as i8) as u8;
+        // Scroll wheel (4-bit signed), sharing the byte with buttons 4 and 5
+        // in the Explorer protocol
+        let dz = event.dz.clamp(-8, 7) as u8;
+        let byte4 = if self.mouse_protocol == MouseProtocol::IntellimouseExplorer {
+            let mut byte4 = dz & explorer_btn::WHEEL_MASK;
+            if event.buttons.side {
+                byte4 |= explorer_btn::SIDE;
+            }
+            if event.buttons.extra {
+                byte4 |= explorer_btn::EXTRA;
+            }
+            byte4
+        } else {
+            dz
+        };
+
+        [byte1, dx as u8, dy_inverted, byte4]
+    }
+
+    /// Handle a byte written by the guest to the mouse
+    fn handle_mouse_data(&mut self, data: u8) {
+        // Responses replace whatever packets the guest didn't read
+        self.mouse_buffer.clear();
+        self.mouse_buffer.push_back(kbd_resp::ACK);
+
+        match self.mouse_pending_command.take() {
+            Some(mouse_cmd::SET_SAMPLE_RATE) => {
+                self.mouse_sample_rates.rotate_left(1);
+                self.mouse_sample_rates[2] = data;
+                // The Explorer knock only works once in Intellimouse mode
+                match (self.mouse_protocol, self.mouse_sample_rates) {
+                    (MouseProtocol::Standard, INTELLIMOUSE_KNOCK) => {
+                        self.mouse_protocol = MouseProtocol::Intellimouse;
+                    }
+                    (MouseProtocol::Intellimouse, INTELLIMOUSE_EXPLORER_KNOCK) => {
+                        self.mouse_protocol = MouseProtocol::IntellimouseExplorer;
+                    }
+                    _ => {}
+                }
+            }
+            Some(mouse_cmd::SET_RESOLUTION) => self.mouse_resolution = data & 0x03,
+            _ => match data {
+                mouse_cmd::SET_SAMPLE_RATE | mouse_cmd::SET_RESOLUTION => {
+                    self.mouse_pending_command = Some(data);
+                }
+                mouse_cmd::GET_ID => self.mouse_buffer.push_back(self.mouse_protocol.id()),
+                mouse_cmd::STATUS_REQUEST => {
+                    let mut status = 0;
+                    if self.mouse_reporting {
+                        status |= 0x20;
+                    }
+                    if self.mouse_buttons.left {
+                        status |= 0x04;
+                    }
+                    if self.mouse_buttons.middle {
+                        status |= 0x02;
+                    }
+                    if self.mouse_buttons.right {
+                        status |= 0x01;
+                    }
+                    self.mouse_buffer.extend([
+                        status,
+                        self.mouse_resolution,
+                        self.mouse_sample_rates[2],
+                    ]);
+                }
+                mouse_cmd::ENABLE_REPORTING => self.mouse_reporting = true,
+                mouse_cmd::DISABLE_REPORTING => self.mouse_reporting = false,
+                mouse_cmd::SET_DEFAULTS => self.reset_mouse(),
+                mouse_cmd::RESET => {
+                    self.reset_mouse();
+                    self.mouse_protocol = MouseProtocol::Standard;
+                    self.mouse_buffer
+                        .extend([kbd_resp::SELF_TEST_PASSED, MouseProtocol::Standard.id()]);
+                }
+                _ => debug!("Mouse data: 0x{data:02X}"),
+            },
+        }
+
+        self.trigger_mouse_interrupt();
+    }
+
+    /// Restore the defaults of the mouse, which keeps its protocol
+    fn reset_mouse(&mut self) {
+        self.mouse_reporting = false;
+        self.mouse_resolution = 2;
+        self.mouse_sample_rates = [MOUSE_DEFAULT_SAMPLE_RATE; 3];
+    }
+
+    /// Report the changes the guest made to the state of the devices
+    fn notify_state(&self, previous: Ps2State) {
+        let state = self.state();
+        if state == previous {
+            return;
+        }
 
-        [byte1, dx as u8, dy_inverted, dz]
+        debug!("PS/2 state changed to {state:?}");
+        event!(
+            "input",
+            "state-changed",
+            "backend",
+            "ps2",
+            "num-lock",
+            state.num_lock.to_string(),
+            "caps-lock",
+            state.caps_lock.to_string(),
+            "scroll-lock",
+            state.scroll_lock.to_string(),
+            "keyboard-ready",
+            state.keyboard_enabled.to_string(),
+            "pointer-ready",
+            state.mouse_enabled.to_string(),
+            "mouse-protocol",
+            state.mouse_protocol.name()
+        );
+    }
+
+    /// Point the status at the buffer the next data read comes from
+    ///
+    /// The mouse keeps the output until its packet is fully read, then the
+    /// keyboard gets it, so that neither buffer is left pending unnoticed.
+    fn update_output_status(&mut self) {
+        if self.status & status::MOUSE_OUT != 0 && !self.mouse_buffer.is_empty() {
+            return;
+        }
+        if !self.kbd_buffer.is_empty() {
+            self.status |= status::OUT_FULL;
+            self.status &= !status::MOUSE_OUT;
+        } else if !self.mouse_buffer.is_empty() {
+            self.status |= status::OUT_FULL | status::MOUSE_OUT;
+        } else {
+            self.status &= !(status::OUT_FULL | status::MOUSE_OUT);
+        }
     }
 
     /// Trigger mouse interrupt (IRQ12)
@@ -472,19 +862,11 @@ impl I8042Device {
                     self.command_byte = data;
                     debug!("Command byte set to: 0x{:02X}", data);
                 }
-                cmd::WRITE_TO_MOUSE => {
-                    // Mouse command/response
-                    debug!("Mouse data: 0x{:02X}", data);
-                    // Acknowledge
-                    self.mouse_buffer.clear();
-                    self.mouse_buffer.push_back(0xFA); // ACK
-                    self.trigger_mouse_interrupt();
-                }
+                cmd::WRITE_TO_MOUSE => self.handle_mouse_data(data),
                 _ => {}
             }
         } else {
-            // Keyboard data
-            debug!("Keyboard data: 0x{:02X}", data);
+            self.handle_keyboard_data(data);
         }
     }
 }
@@ -505,17 +887,12 @@ impl BusDevice for I8042Device {
                 // Check if mouse data is available
                 if self.status & status::MOUSE_OUT != 0 && !self.mouse_buffer.is_empty() {
                     data[0] = self.mouse_buffer.pop_front().unwrap_or(0);
-                    if self.mouse_buffer.is_empty() {
-                        self.status &= !(status::OUT_FULL | status::MOUSE_OUT);
-                    }
                 } else if !self.kbd_buffer.is_empty() {
                     data[0] = self.kbd_buffer.pop_front().unwrap_or(0);
-                    if self.kbd_buffer.is_empty() {
-                        self.status &= !status::OUT_FULL;
-                    }
                 } else {
                     data[0] = 0;
                 }
+                self.update_output_status();
                 debug!("i8042 data read: 0x{:02X}", data[0]);
             }
 
@@ -537,6 +914,7 @@ impl BusDevice for I8042Device {
             return None;
         }
 
+        let previous = self.state();
         match offset {
             // Data Port (0x60)
             I8042_DATA_REG => {
@@ -550,6 +928,7 @@ impl BusDevice for I8042Device {
 
             _ => {}
         }
+        self.notify_state(previous);
 
         None
     }
@@ -701,6 +1080,49 @@ mod tests {
         assert_ne!(data[0] & status::OUT_FULL, 0);
     }
 
+    #[test]
+    fn test_interleaved_output_status() {
+        let mut dev = create_test_device();
+        let mut data = [0u8; 1];
+        let mut read_status = |dev: &mut I8042Device| {
+            dev.read(0, I8042_COMMAND_REG, &mut data);
+            data[0] & (status::OUT_FULL | status::MOUSE_OUT)
+        };
+
+        // A mouse packet injected behind keyboard data is read first
+        dev.inject_keyboard(KeyboardEvent {
+            scancode: 0x1C,
+            release: true,
+        });
+        dev.inject_mouse(MouseEvent {
+            dx: 1,
+            dy: 1,
+            dz: 0,
+            buttons: MouseButtons::default(),
+        });
+        for _ in 0..MOUSE_PACKET_SIZE {
+            assert_eq!(read_status(&mut dev), status::OUT_FULL | status::MOUSE_OUT);
+            dev.read(0, I8042_DATA_REG, &mut [0u8]);
+        }
+
+        // Then the keyboard data, while a new mouse packet waits for it
+        assert_eq!(read_status(&mut dev), status::OUT_FULL);
+        let mut byte = [0u8];
+        dev.read(0, I8042_DATA_REG, &mut byte);
+        assert_eq!(byte[0], 0xF0);
+        dev.inject_mouse(MouseEvent::default());
+        for _ in 0..MOUSE_PACKET_SIZE {
+            assert_eq!(read_status(&mut dev), status::OUT_FULL | status::MOUSE_OUT);
+            dev.read(0, I8042_DATA_REG, &mut [0u8]);
+        }
+        assert_eq!(read_status(&mut dev), status::OUT_FULL);
+        dev.read(0, I8042_DATA_REG, &mut byte);
+        assert_eq!(byte[0], 0x1C);
+
+        // Both buffers are empty
+        assert_eq!(read_status(&mut dev), 0);
+    }
+
     #[test]
     fn test_buffer_overflow() {
         let mut dev = create_test_device();
@@ -719,5 +1141,189 @@ mod tests {
 
         // Buffer should be limited to MAX_BUFFER_SIZE
         assert!(dev.kbd_buffer.len() <= MAX_BUFFER_SIZE);
+
+        // A sequence which doesn't fit is dropped whole
+        dev.kbd_buffer.clear();
+        dev.inject_keyboard_bytes(&[0; MAX_BUFFER_SIZE - 2]);
+        dev.inject_keyboard_bytes(&[0xE0, 0xF0, 0x75]);
+        assert_eq!(dev.kbd_buffer.len(), MAX_BUFFER_SIZE - 2);
+        assert_eq!(dev.keyboard_space(), 2);
+
+        // Room for four Intellimouse packets
+        assert_eq!(dev.mouse_space(), 4);
+        dev.inject_mouse(MouseEvent {
+            dx: 1,
+            dy: 0,
+            dz: 0,
+            buttons: MouseButtons::default(),
+        });
+        assert_eq!(dev.mouse_space(), 3);
+    }
+
+    #[test]
+    fn test_scancode_set() {
+        // Write bytes to the keyboard and read back its responses
+        fn read(dev: &mut I8042Device, bytes: &[u8]) -> Vec<u8> {
+            let mut output = Vec::new();
+            for &byte in bytes {
+                dev.write(0, I8042_DATA_REG, &[byte]);
+            }
+            while !dev.kbd_buffer.is_empty() {
+                let mut data = [0u8];
+                dev.read(0, I8042_DATA_REG, &mut data);
+                output.push(data[0]);
+            }
+            output
+        }
+
+        let mut dev = create_test_device();
+
+        assert_eq!(dev.scancode_set(), ScancodeSet::Set2);
+        assert_eq!(read(&mut dev, &[0xF0, 0x00]), [0xFA, 0xFA, 0x02]);
+        assert_eq!(read(&mut dev, &[0xF0, 0x01]), [0xFA, 0xFA]);
+        assert_eq!(dev.scancode_set(), ScancodeSet::Set1);
+        assert_eq!(read(&mut dev, &[0xF0, 0x03]), [0xFA, 0xFE]);
+        assert_eq!(dev.scancode_set(), ScancodeSet::Set1);
+
+        // The controller translates Set 2 for the guest
+        assert_eq!(read(&mut dev, &[0xF0, 0x02]), [0xFA, 0xFA]);
+        assert_eq!(dev.scancode_set(), ScancodeSet::Set2);
+        dev.write(0, I8042_COMMAND_REG, &[cmd::WRITE_CMD_BYTE]);
+        dev.write(0, I8042_DATA_REG, &[ccb::KBD_TRANSLATE]);
+        assert_eq!(dev.scancode_set(), ScancodeSet::Set1);
+        assert_eq!(read(&mut dev, &[0xF0, 0x00]), [0xFA, 0xFA, 0x41]);
+    }
+
+    #[test]
+    fn test_keyboard_state() {
+        let mut dev = create_test_device();
+        assert!(dev.state().keyboard_enabled);
+
+        dev.write(0, I8042_DATA_REG, &[kbd_cmd::SET_LEDS]);
+        dev.write(0, I8042_DATA_REG, &[leds::CAPS_LOCK | leds::NUM_LOCK]);
+        assert_eq!(dev.kbd_buffer, [0xFA, 0xFA]);
+        let state = dev.state();
+        assert!(state.caps_lock && state.num_lock && !state.scroll_lock);
+
+        dev.write(0, I8042_DATA_REG, &[kbd_cmd::DISABLE_SCANNING]);
+        assert!(!dev.state().keyboard_enabled);
+        dev.write(0, I8042_DATA_REG, &[kbd_cmd::ENABLE_SCANNING]);
+        assert!(dev.state().keyboard_enabled);
+        dev.write(0, I8042_COMMAND_REG, &[cmd::DISABLE_KBD]);
+        assert!(!dev.state().keyboard_enabled);
+        dev.write(0, I8042_COMMAND_REG, &[cmd::ENABLE_KBD]);
+
+        // A reset turns the LEDs off
+        dev.kbd_buffer.clear();
+        dev.write(0, I8042_DATA_REG, &[kbd_cmd::RESET]);
+        assert_eq!(dev.kbd_buffer, [0xFA, 0xAA]);
+        assert_eq!(
+            dev.state(),
+            Ps2State {
+                keyboard_enabled: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_mouse_protocol() {
+        // Write bytes to the mouse and return its last response
+        fn command(dev: &mut I8042Device, bytes: &[u8]) -> Vec<u8> {
+            for &byte in bytes {
+                dev.write(0, I8042_COMMAND_REG, &[cmd::WRITE_TO_MOUSE]);
+                dev.write(0, I8042_DATA_REG, &[byte]);
+            }
+            dev.mouse_buffer.drain(..).collect()
+        }
+        fn packet_size(dev: &mut I8042Device) -> usize {
+            dev.inject_mouse(MouseEvent {
+                dx: 1,
+                ..Default::default()
+            });
+            dev.mouse_buffer.drain(..).count()
+        }
+
+        let mut dev = create_test_device();
+        assert_eq!(dev.state().mouse_protocol, MouseProtocol::Intellimouse);
+        assert!(!dev.state().mouse_enabled);
+
+        // A reset falls back to the standard protocol
+        assert_eq!(command(&mut dev, &[mouse_cmd::RESET]), [0xFA, 0xAA, 0x00]);
+        assert_eq!(dev.state().mouse_protocol, MouseProtocol::Standard);
+        assert_eq!(packet_size(&mut dev), 3);
+
+        // The Explorer knock needs the Intellimouse one first
+        assert_eq!(command(&mut dev, &[0xF3, 200, 0xF3, 200, 0xF3, 80]), [0xFA]);
+        assert_eq!(command(&mut dev, &[mouse_cmd::GET_ID]), [0xFA, 0x00]);
+        command(&mut dev, &[0xF3, 200, 0xF3, 100, 0xF3, 80]);
+        assert_eq!(command(&mut dev, &[mouse_cmd::GET_ID]), [0xFA, 0x03]);
+        assert_eq!(packet_size(&mut dev), 4);
+        command(&mut dev, &[0xF3, 200, 0xF3, 200, 0xF3, 80]);
+        assert_eq!(command(&mut dev, &[mouse_cmd::GET_ID]), [0xFA, 0x04]);
+        assert_eq!(
+            dev.state().mouse_protocol,
+            MouseProtocol::IntellimouseExplorer
+        );
+
+        assert_eq!(command(&mut dev, &[mouse_cmd::ENABLE_REPORTING]), [0xFA]);
+        assert!(dev.state().mouse_enabled);
+        assert_eq!(
+            command(&mut dev, &[mouse_cmd::STATUS_REQUEST]),
+            [0xFA, 0x20, 0x02, 80]
+        );
+        dev.write(0, I8042_COMMAND_REG, &[cmd::DISABLE_MOUSE]);
+        assert!(!dev.state().mouse_enabled);
+        dev.write(0, I8042_COMMAND_REG, &[cmd::ENABLE_MOUSE]);
+        command(&mut dev, &[mouse_cmd::SET_DEFAULTS]);
+        assert!(!dev.state().mouse_enabled);
+    }
+
+    #[test]
+    fn test_explorer_packet() {
+        let mut dev = create_test_device();
+        let event = MouseEvent {
+            dz: -1,
+            buttons: MouseButtons {
+                side: true,
+                extra: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // The wheel takes the whole byte with the Intellimouse protocol
+        dev.inject_mouse(event.clone());
+        let packet: Vec<u8> = dev.mouse_buffer.drain(..).collect();
+        assert_eq!(packet, [0x08, 0, 0, 0xFF]);
+
+        for knock in [INTELLIMOUSE_KNOCK, INTELLIMOUSE_EXPLORER_KNOCK] {
+            for rate in knock {
+                dev.write(0, I8042_COMMAND_REG, &[cmd::WRITE_TO_MOUSE]);
+                dev.write(0, I8042_DATA_REG, &[0xF3]);
+                dev.write(0, I8042_COMMAND_REG, &[cmd::WRITE_TO_MOUSE]);
+                dev.write(0, I8042_DATA_REG, &[rate]);
+            }
+        }
+        dev.mouse_buffer.clear();
+        assert_eq!(
+            dev.state().mouse_protocol,
+            MouseProtocol::IntellimouseExplorer
+        );
+
+        // Buttons 4 and 5 above the 4-bit wheel movement
+        dev.inject_mouse(event);
+        let packet: Vec<u8> = dev.mouse_buffer.drain(..).collect();
+        assert_eq!(packet, [0x08, 0, 0, 0x3F]);
+        dev.inject_mouse(MouseEvent {
+            dz: 7,
+            buttons: MouseButtons {
+                side: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let packet: Vec<u8> = dev.mouse_buffer.drain(..).collect();
+        assert_eq!(packet, [0x08, 0, 0, 0x17]);
     }
 }
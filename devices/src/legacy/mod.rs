@@ -32,7 +32,9 @@ pub use self::fwdebug::FwDebugDevice;
 pub use self::gpio_pl061::Error as GpioDeviceError;
 #[cfg(target_arch = "aarch64")]
 pub use self::gpio_pl061::Gpio;
-pub use self::i8042::{I8042Device, KeyboardEvent, MouseEvent, MouseButtons};
+pub use self::i8042::{
+    I8042Device, KeyboardEvent, MouseButtons, MouseEvent, MouseProtocol, Ps2State, ScancodeSet,
+};
 #[cfg(target_arch = "aarch64")]
 pub use self::rtc_pl031::Rtc;
 pub use self::serial::Serial;
@@ -19,10 +19,13 @@ pub mod interrupt_controller;
 pub mod ioapic;
 #[cfg(feature = "ivshmem")]
 pub mod buffer_manager;
-#[cfg(feature = "ivshmem")]
 pub mod frame_buffer;
 #[cfg(feature = "ivshmem")]
+pub mod frame_notifier;
+#[cfg(feature = "ivshmem")]
 pub mod ivshmem;
+#[cfg(feature = "ivshmem")]
+pub mod ivshmem_client;
 pub mod legacy;
 #[cfg(feature = "pvmemcontrol")]
 pub mod pvmemcontrol;
@@ -43,9 +46,11 @@ pub use self::buffer_manager::{
 #[cfg(feature = "ivshmem")]
 pub use self::frame_buffer::{
     FrameBufferHeader, FrameBufferLayout, FrameFlags, FrameFormat, FrameMetadata,
-    DEFAULT_BUFFER_COUNT, FRAME_BUFFER_MAGIC, FRAME_BUFFER_VERSION,
+    FrameReadyEvent, DEFAULT_BUFFER_COUNT, FRAME_BUFFER_MAGIC, FRAME_BUFFER_VERSION,
 };
 #[cfg(feature = "ivshmem")]
+pub use self::frame_notifier::{FrameNotifier, FrameNotifierError};
+#[cfg(feature = "ivshmem")]
 pub use self::ivshmem::IvshmemDevice;
 pub use self::pvpanic::{PVPANIC_DEVICE_MMIO_SIZE, PvPanicDevice};
 
@@ -11,6 +11,8 @@ use std::collections::VecDeque;
 use std::io;
 use std::sync::{Arc, Mutex};
 
+use event_monitor::event;
+use log::debug;
 use vm_memory::ByteValued;
 
 // ============================================================================
@@ -29,6 +31,14 @@ pub const HID_PROTOCOL_KEYBOARD: u8 = 0x01;
 /// HID protocol for mouse
 pub const HID_PROTOCOL_MOUSE: u8 = 0x02;
 
+/// Keyboard LED bits of the boot keyboard output report
+pub const HID_LED_NUM_LOCK: u8 = 0x01;
+pub const HID_LED_CAPS_LOCK: u8 = 0x02;
+pub const HID_LED_SCROLL_LOCK: u8 = 0x04;
+
+/// Largest value of the tablet position axes
+pub const TABLET_ABS_MAX: u16 = 0x7FFF;
+
 /// USB endpoint direction: IN (device to host)
 pub const USB_DIR_IN: u8 = 0x80;
 
@@ -101,6 +111,26 @@ impl UsbDeviceDescriptor {
             b_num_configurations: 1,
         }
     }
+
+    /// Create a HID tablet device descriptor
+    pub fn tablet() -> Self {
+        Self {
+            b_length: std::mem::size_of::<Self>() as u8,
+            b_descriptor_type: 0x01,
+            bcd_usb: 0x0200,
+            b_device_class: 0x00,
+            b_device_sub_class: 0x00,
+            b_device_protocol: 0x00,
+            b_max_packet_size0: 8,
+            id_vendor: 0x1D6B,
+            id_product: 0x0106,      // Virtual HID Tablet
+            bcd_device: 0x0100,
+            i_manufacturer: 1,
+            i_product: 4,
+            i_serial_number: 0,
+            b_num_configurations: 1,
+        }
+    }
 }
 
 /// USB configuration descriptor
@@ -237,6 +267,51 @@ pub fn mouse_report_descriptor() -> Vec<u8> {
     ]
 }
 
+/// USB HID tablet report descriptor
+///
+/// Same buttons and wheel as the mouse, with 16-bit absolute X and Y
+/// ranging from 0 to [`TABLET_ABS_MAX`].
+pub fn tablet_report_descriptor() -> Vec<u8> {
+    vec![
+        0x05, 0x01,        // Usage Page (Generic Desktop)
+        0x09, 0x02,        // Usage (Mouse)
+        0xA1, 0x01,        // Collection (Application)
+        0x09, 0x01,        //   Usage (Pointer)
+        0xA1, 0x00,        //   Collection (Physical)
+        0x05, 0x09,        //     Usage Page (Button)
+        0x19, 0x01,        //     Usage Minimum (1)
+        0x29, 0x03,        //     Usage Maximum (3)
+        0x15, 0x00,        //     Logical Minimum (0)
+        0x25, 0x01,        //     Logical Maximum (1)
+        0x95, 0x03,        //     Report Count (3)
+        0x75, 0x01,        //     Report Size (1)
+        0x81, 0x02,        //     Input (Data, Var, Abs)
+        0x95, 0x01,        //     Report Count (1)
+        0x75, 0x05,        //     Report Size (5)
+        0x81, 0x01,        //     Input (Const) - Reserved
+        0x05, 0x01,        //     Usage Page (Generic Desktop)
+        0x09, 0x30,        //     Usage (X)
+        0x09, 0x31,        //     Usage (Y)
+        0x15, 0x00,        //     Logical Minimum (0)
+        0x26, 0xFF, 0x7F,  //     Logical Maximum (32767)
+        0x35, 0x00,        //     Physical Minimum (0)
+        0x46, 0xFF, 0x7F,  //     Physical Maximum (32767)
+        0x75, 0x10,        //     Report Size (16)
+        0x95, 0x02,        //     Report Count (2)
+        0x81, 0x02,        //     Input (Data, Var, Abs)
+        0x09, 0x38,        //     Usage (Wheel)
+        0x15, 0x81,        //     Logical Minimum (-127)
+        0x25, 0x7F,        //     Logical Maximum (127)
+        0x35, 0x00,        //     Physical Minimum (0)
+        0x45, 0x00,        //     Physical Maximum (0)
+        0x75, 0x08,        //     Report Size (8)
+        0x95, 0x01,        //     Report Count (1)
+        0x81, 0x06,        //     Input (Data, Var, Rel)
+        0xC0,              //   End Collection
+        0xC0,              // End Collection
+    ]
+}
+
 // ============================================================================
 // HID Device Types
 // ============================================================================
@@ -246,6 +321,18 @@ pub fn mouse_report_descriptor() -> Vec<u8> {
 pub enum HidType {
     Keyboard,
     Mouse,
+    Tablet,
+}
+
+impl HidType {
+    /// Name used in logs and events
+    pub fn name(self) -> &'static str {
+        match self {
+            HidType::Keyboard => "keyboard",
+            HidType::Mouse => "mouse",
+            HidType::Tablet => "tablet",
+        }
+    }
 }
 
 /// HID device state
@@ -277,6 +364,10 @@ pub struct UsbHidDevice {
     report_queue: VecDeque<Vec<u8>>,
     /// Maximum queue depth
     max_queue_depth: usize,
+    /// Keyboard LEDs set by the guest through the output report
+    leds: u8,
+    /// Called once a report is queued
+    report_notifier: Option<ReportNotifier>,
 }
 
 impl UsbHidDevice {
@@ -290,6 +381,8 @@ impl UsbHidDevice {
             device_descriptor: UsbDeviceDescriptor::keyboard(),
             report_queue: VecDeque::new(),
             max_queue_depth: 16,
+            leds: 0,
+            report_notifier: None,
         }
     }
 
@@ -303,6 +396,23 @@ impl UsbHidDevice {
             device_descriptor: UsbDeviceDescriptor::mouse(),
             report_queue: VecDeque::new(),
             max_queue_depth: 16,
+            leds: 0,
+            report_notifier: None,
+        }
+    }
+
+    /// Create a new USB HID tablet device
+    pub fn new_tablet() -> Self {
+        Self {
+            hid_type: HidType::Tablet,
+            state: HidState::Default,
+            address: 0,
+            configuration: 0,
+            device_descriptor: UsbDeviceDescriptor::tablet(),
+            report_queue: VecDeque::new(),
+            max_queue_depth: 16,
+            leds: 0,
+            report_notifier: None,
         }
     }
 
@@ -321,6 +431,66 @@ impl UsbHidDevice {
         self.address
     }
 
+    /// Keyboard LEDs set by the guest, made of the `HID_LED_*` bits
+    pub fn leds(&self) -> u8 {
+        self.leds
+    }
+
+    /// Set the callback notified once a report is queued, for the
+    /// controller to hand it to the guest
+    pub fn set_report_notifier(&mut self, notifier: ReportNotifier) {
+        self.report_notifier = Some(notifier);
+    }
+
+    /// Set the LEDs from the output report of a boot keyboard
+    fn set_leds(&mut self, report: &[u8]) {
+        if self.hid_type != HidType::Keyboard {
+            return;
+        }
+        if let Some(&leds) = report.first() {
+            let leds = leds & (HID_LED_NUM_LOCK | HID_LED_CAPS_LOCK | HID_LED_SCROLL_LOCK);
+            if leds != self.leds {
+                debug!("USB HID keyboard LEDs set to {leds:#x}");
+                self.leds = leds;
+                self.notify_state();
+            }
+        }
+    }
+
+    /// Report the state the guest set up on the device
+    fn notify_state(&self) {
+        let ready = (self.state == HidState::Configured).to_string();
+        if self.hid_type == HidType::Keyboard {
+            event!(
+                "input",
+                "state-changed",
+                "backend",
+                "usb",
+                "device",
+                self.hid_type.name(),
+                "ready",
+                ready,
+                "num-lock",
+                (self.leds & HID_LED_NUM_LOCK != 0).to_string(),
+                "caps-lock",
+                (self.leds & HID_LED_CAPS_LOCK != 0).to_string(),
+                "scroll-lock",
+                (self.leds & HID_LED_SCROLL_LOCK != 0).to_string()
+            );
+        } else {
+            event!(
+                "input",
+                "state-changed",
+                "backend",
+                "usb",
+                "device",
+                self.hid_type.name(),
+                "ready",
+                ready
+            );
+        }
+    }
+
     /// Get device descriptor
     pub fn device_descriptor(&self) -> &UsbDeviceDescriptor {
         &self.device_descriptor
@@ -331,6 +501,7 @@ impl UsbHidDevice {
         match self.hid_type {
             HidType::Keyboard => keyboard_report_descriptor(),
             HidType::Mouse => mouse_report_descriptor(),
+            HidType::Tablet => tablet_report_descriptor(),
         }
     }
 
@@ -349,6 +520,13 @@ impl UsbHidDevice {
         let _index = u16::from(request[4]) | (u16::from(request[5]) << 8);
         let _length = u16::from(request[6]) | (u16::from(request[7]) << 8);
 
+        // HID class requests (GET_REPORT, SET_REPORT, SET_IDLE, ...) have
+        // nothing to answer, the output report of SET_REPORT comes with the
+        // data stage
+        if request_type & 0x60 == 0x20 {
+            return Ok(vec![]);
+        }
+
         // Standard device requests
         match request_code {
             0x05 => {
@@ -360,8 +538,9 @@ impl UsbHidDevice {
             0x09 => {
                 // SET_CONFIGURATION
                 self.configuration = value as u8;
-                if self.configuration != 0 {
+                if self.configuration != 0 && self.state != HidState::Configured {
                     self.state = HidState::Configured;
+                    self.notify_state();
                 }
                 Ok(vec![])
             }
@@ -395,14 +574,6 @@ impl UsbHidDevice {
                 // GET_INTERFACE
                 Ok(vec![0])
             }
-            0x21 => {
-                // HID SET_REPORT
-                Ok(vec![])
-            }
-            0x01 => {
-                // HID SET_IDLE
-                Ok(vec![])
-            }
             0x0B => {
                 // SET_INTERFACE
                 Ok(vec![])
@@ -435,10 +606,15 @@ impl UsbHidDevice {
             b_alternate_setting: 0,
             b_num_endpoints: 1,
             b_interface_class: USB_CLASS_HID,
-            b_interface_sub_class: HID_SUBCLASS_BOOT,
+            // There is no boot protocol for absolute pointers
+            b_interface_sub_class: match self.hid_type {
+                HidType::Keyboard | HidType::Mouse => HID_SUBCLASS_BOOT,
+                HidType::Tablet => 0,
+            },
             b_interface_protocol: match self.hid_type {
                 HidType::Keyboard => HID_PROTOCOL_KEYBOARD,
                 HidType::Mouse => HID_PROTOCOL_MOUSE,
+                HidType::Tablet => 0,
             },
             i_interface: 0,
         };
@@ -460,6 +636,7 @@ impl UsbHidDevice {
             w_max_packet_size: match self.hid_type {
                 HidType::Keyboard => 8,
                 HidType::Mouse => 4,
+                HidType::Tablet => 6,
             },
             b_interval: 10, // 10ms polling interval
         };
@@ -485,7 +662,7 @@ impl UsbHidDevice {
 
     /// Get string descriptor
     fn get_string_descriptor(&self, index: u8) -> Vec<u8> {
-        let strings = ["Cloud Hypervisor", "HID Device", "HID Mouse"];
+        let strings = ["Cloud Hypervisor", "HID Device", "HID Mouse", "HID Tablet"];
 
         if index == 0 {
             // Language ID descriptor
@@ -519,6 +696,11 @@ impl UsbHidDevice {
         true
     }
 
+    /// Reports the queue can still take before it drops the oldest ones
+    pub fn queue_space(&self) -> usize {
+        self.max_queue_depth.saturating_sub(self.report_queue.len())
+    }
+
     /// Get next queued report
     pub fn get_report(&mut self) -> Option<Vec<u8>> {
         self.report_queue.pop_front()
@@ -537,6 +719,25 @@ impl UsbHidDevice {
 /// Thread-safe USB HID device wrapper
 pub type SharedUsbHidDevice = Arc<Mutex<UsbHidDevice>>;
 
+/// Callback notified once a report is queued on a device
+pub type ReportNotifier = Arc<dyn Fn() + Send + Sync>;
+
+/// Queue a HID report on a shared device and notify the controller. The
+/// notifier runs once the device lock is released, as the controller takes
+/// it to collect the report.
+pub fn send_report(device: &SharedUsbHidDevice, report: Vec<u8>) {
+    let notifier = match device.lock() {
+        Ok(mut dev) => {
+            dev.queue_report(report);
+            dev.report_notifier.clone()
+        }
+        Err(_) => return,
+    };
+    if let Some(notifier) = notifier {
+        notifier();
+    }
+}
+
 // ============================================================================
 // UsbDevice Trait Implementation
 // ============================================================================
@@ -581,9 +782,21 @@ impl UsbDevice for UsbHidDevice {
             0x07, 0x05, 0x81, 0x03, 0x04, 0x00, 0x0A,
         ];
 
+        static TABLET_CONFIG: &[u8] = &[
+            // Configuration descriptor (9 bytes)
+            0x09, 0x02, 0x22, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+            // Interface descriptor (9 bytes), no boot protocol
+            0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00,
+            // HID descriptor (9 bytes)
+            0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x48, 0x00,
+            // Endpoint descriptor (7 bytes)
+            0x07, 0x05, 0x81, 0x03, 0x06, 0x00, 0x0A,
+        ];
+
         match self.hid_type {
             HidType::Keyboard => KEYBOARD_CONFIG,
             HidType::Mouse => MOUSE_CONFIG,
+            HidType::Tablet => TABLET_CONFIG,
         }
     }
 
@@ -591,7 +804,16 @@ impl UsbDevice for UsbHidDevice {
         self.handle_control(request)
     }
 
-    fn handle_transfer(&mut self, ep: u8, _data: &[u8]) -> io::Result<Vec<u8>> {
+    fn handle_control_out(&mut self, request: &[u8], data: &[u8]) -> io::Result<()> {
+        self.handle_control(request)?;
+        // SET_REPORT carries the output report in the data stage
+        if request[0] == 0x21 && request[1] == 0x09 {
+            self.set_leds(data);
+        }
+        Ok(())
+    }
+
+    fn handle_transfer(&mut self, ep: u8, data: &[u8]) -> io::Result<Vec<u8>> {
         // Handle endpoint transfers
         match ep {
             0x81 => {
@@ -601,8 +823,9 @@ impl UsbDevice for UsbHidDevice {
                 })
             }
             0x01 => {
-                // EP1 OUT - Receive HID report (for SET_REPORT)
-                // Usually not needed for simple input devices
+                // EP1 OUT - Receive HID report (for SET_REPORT), which is
+                // only the LEDs of a boot keyboard
+                self.set_leds(data);
                 Ok(vec![])
             }
             _ => {
@@ -621,10 +844,15 @@ impl UsbDevice for UsbHidDevice {
     }
 
     fn reset(&mut self) {
+        let configured = self.state == HidState::Configured;
         self.state = HidState::Default;
         self.address = 0;
         self.configuration = 0;
         self.report_queue.clear();
+        self.leds = 0;
+        if configured {
+            self.notify_state();
+        }
     }
 }
 
@@ -656,13 +884,64 @@ mod tests {
         assert!(!mouse.report_descriptor().is_empty());
     }
 
+    #[test]
+    fn test_tablet_device() {
+        let device = UsbHidDevice::new_tablet();
+        assert_eq!(device.hid_type(), HidType::Tablet);
+
+        // The HID descriptor announces the length of the report descriptor
+        let config = UsbDevice::configuration_descriptor(&device);
+        let report_len = device.report_descriptor().len();
+        assert_eq!(
+            u16::from_le_bytes([config[25], config[26]]) as usize,
+            report_len
+        );
+        // Interface without boot protocol
+        assert_eq!(config[9 + 6..9 + 8], [0, 0]);
+
+        let string = device.get_string_descriptor(device.device_descriptor().i_product);
+        let name: Vec<u16> = string[2..]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        assert_eq!(String::from_utf16(&name).unwrap(), "HID Tablet");
+    }
+
+    #[test]
+    fn test_keyboard_leds() {
+        let mut device = UsbHidDevice::new_keyboard();
+
+        // SET_CONFIGURATION 1
+        device
+            .handle_control(&[0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00])
+            .unwrap();
+        assert_eq!(device.state(), HidState::Configured);
+
+        // SET_REPORT of the output report, which must not be taken for
+        // SET_CONFIGURATION, then its data stage
+        device
+            .handle_control(&[0x21, 0x09, 0x00, 0x02, 0x00, 0x00, 0x01, 0x00])
+            .unwrap();
+        assert_eq!(device.configuration, 1);
+        device
+            .handle_transfer(0x01, &[HID_LED_CAPS_LOCK | HID_LED_NUM_LOCK])
+            .unwrap();
+        assert_eq!(device.leds(), HID_LED_CAPS_LOCK | HID_LED_NUM_LOCK);
+
+        UsbDevice::reset(&mut device);
+        assert_eq!(device.state(), HidState::Default);
+        assert_eq!(device.leds(), 0);
+    }
+
     #[test]
     fn test_queue_report() {
         let mut device = UsbHidDevice::new_keyboard();
         let report = vec![0, 0, 0x04, 0, 0, 0, 0, 0]; // 'A' key
 
+        assert_eq!(device.queue_space(), 16);
         assert!(device.queue_report(report.clone()));
         assert!(device.has_pending_reports());
+        assert_eq!(device.queue_space(), 15);
         assert_eq!(device.get_report(), Some(report));
         assert!(!device.has_pending_reports());
     }
@@ -9,17 +9,23 @@
 pub mod hid;
 pub mod xhci;
 
-pub use hid::{HidType, HidState, UsbHidDevice, SharedUsbHidDevice};
+pub use hid::{HidType, HidState, UsbHidDevice, SharedUsbHidDevice, ReportNotifier, send_report};
 pub use hid::{
     USB_CLASS_HID,
     HID_SUBCLASS_BOOT,
     HID_PROTOCOL_KEYBOARD,
     HID_PROTOCOL_MOUSE,
+    HID_LED_NUM_LOCK,
+    HID_LED_CAPS_LOCK,
+    HID_LED_SCROLL_LOCK,
+    TABLET_ABS_MAX,
     keyboard_report_descriptor,
     mouse_report_descriptor,
+    tablet_report_descriptor,
 };
 
 // Re-export commonly used xHCI types
-pub use xhci::{XhciController, XhciState, XhciError, XHCI_VERSION, XHCI_MAX_SLOTS, XHCI_MAX_PORTS};
+pub use xhci::{XhciController, XhciInterrupt, XhciState, XhciError, XHCI_VERSION, XHCI_MAX_SLOTS, XHCI_MAX_PORTS};
+pub use xhci::{XhciPciDevice, XhciPciError, XHCI_KEYBOARD_PORT, XHCI_MOUSE_PORT, XHCI_TABLET_PORT};
 pub use xhci::rings::{Trb, TrbType, CompletionCode, CommandRing, EventRing, TransferRing};
 pub use xhci::device::{UsbDevice, UsbSpeed, DeviceContext, SlotContext, SlotState, XhciDeviceSlot};
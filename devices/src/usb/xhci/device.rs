@@ -9,14 +9,19 @@ use std::io;
 use std::sync::{Arc, Mutex};
 
 use super::rings::{Trb, TransferRing, CompletionCode, TrbType};
-use vm_memory::{GuestMemoryMmap, Bytes, GuestAddress};
+use log::warn;
+use vm_memory::bitmap::AtomicBitmap;
+use vm_memory::{Bytes, GuestAddress};
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 
 // ============================================================================
 // Helper Types and Functions
 // ============================================================================
 
-/// TRB size in bytes
-const TRB_SIZE: usize = 16;
+/// Size of the slot and endpoint contexts in guest memory, HCCPARAMS1.CSZ
+/// being clear
+const CONTEXT_SIZE: u64 = 32;
 
 /// Endpoint states
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -40,13 +45,6 @@ impl From<u8> for EndpointState {
     }
 }
 
-/// Read a TRB from guest memory
-fn read_trb_from_memory(mem: &GuestMemoryMmap, addr: u64) -> Option<Trb> {
-    let mut buf = [0u8; TRB_SIZE];
-    mem.read(&mut buf, GuestAddress(addr)).ok()?;
-    Some(Trb::from_bytes(&buf))
-}
-
 /// Read data from guest memory
 fn read_mem(mem: &GuestMemoryMmap, addr: u64, len: usize) -> Option<Vec<u8>> {
     let mut buf = vec![0u8; len];
@@ -59,24 +57,33 @@ fn write_mem(mem: &GuestMemoryMmap, addr: u64, data: &[u8]) -> bool {
     mem.write(data, GuestAddress(addr)).is_ok()
 }
 
-/// Extended TRB structure with helper methods
-trait TrbExt {
-    fn cycle_bit(&self) -> bool;
-    fn transfer_length(&self) -> u32;
-    fn parameter(&self) -> u64;
-}
-
-impl TrbExt for Trb {
-    fn cycle_bit(&self) -> bool {
-        self.control & 1 != 0
+/// Read a slot or endpoint context from guest memory
+fn read_context(mem: &GuestMemoryMmap, addr: u64) -> Option<[u32; 8]> {
+    let buf = read_mem(mem, addr, CONTEXT_SIZE as usize)?;
+    let mut context = [0u32; 8];
+    for (dword, bytes) in context.iter_mut().zip(buf.chunks_exact(4)) {
+        *dword = u32::from_le_bytes(bytes.try_into().expect("chunk has correct length"));
     }
+    Some(context)
+}
 
-    fn transfer_length(&self) -> u32 {
-        self.status & 0x1FFFF
-    }
+/// Write a slot or endpoint context to guest memory
+fn write_context(mem: &GuestMemoryMmap, addr: u64, context: &[u32; 8]) -> bool {
+    let buf: Vec<u8> = context
+        .iter()
+        .flat_map(|dword| dword.to_le_bytes())
+        .collect();
+    write_mem(mem, addr, &buf)
+}
 
-    fn parameter(&self) -> u64 {
-        self.parameter
+/// Completion of a transfer of `transferred` bytes out of `length`, with
+/// the residual length
+fn transfer_completion(length: u32, transferred: usize) -> (CompletionCode, u32) {
+    let residual = length - transferred as u32;
+    if residual > 0 {
+        (CompletionCode::ShortPacket, residual)
+    } else {
+        (CompletionCode::Success, 0)
     }
 }
 
@@ -95,6 +102,11 @@ pub trait UsbDevice: Send {
     /// Handle control transfer
     fn handle_control(&mut self, request: &[u8]) -> io::Result<Vec<u8>>;
 
+    /// Handle control transfer with data from the host
+    fn handle_control_out(&mut self, request: &[u8], _data: &[u8]) -> io::Result<()> {
+        self.handle_control(request).map(|_| ())
+    }
+
     /// Handle data transfer (IN/OUT)
     fn handle_transfer(&mut self, ep: u8, data: &[u8]) -> io::Result<Vec<u8>>;
 
@@ -381,31 +393,60 @@ pub struct XhciDeviceSlot {
     slot_id: u8,
     /// Device context
     context: DeviceContext,
+    /// Root hub port of the device (1-based), 0 until addressed
+    port: u8,
+    /// Output device context in guest memory
+    output_context: u64,
     /// USB device
     device: Option<Arc<Mutex<dyn UsbDevice>>>,
     /// Transfer rings for each endpoint
     transfer_rings: Vec<Option<TransferRing>>,
+    /// Setup packet of the control transfer in progress
+    setup: [u8; 8],
+    /// Data returned by the device for the control transfer in progress
+    control_data: Vec<u8>,
 }
 
 impl XhciDeviceSlot {
-    /// Create a new device slot
-    pub fn new(slot_id: u8, device: Arc<Mutex<dyn UsbDevice>>) -> Self {
+    /// Create a new device slot, enabled but not bound to any device yet
+    pub fn new(slot_id: u8) -> Self {
         // Create context with 31 endpoints (EP0 + 30)
         let context = DeviceContext::new(31);
 
         Self {
             slot_id,
             context,
-            device: Some(device),
+            port: 0,
+            output_context: 0,
+            device: None,
             transfer_rings: (0..32).map(|_| None).collect(), // EP0-31
+            setup: [0; 8],
+            control_data: Vec::new(),
         }
     }
 
+    /// Get the root hub port (1-based) an input context names for the
+    /// device of a slot
+    pub fn input_root_hub_port(mem: &GuestMemoryMmap, input: u64) -> Option<u8> {
+        let slot = read_context(mem, input + CONTEXT_SIZE)?;
+        Some((slot[1] >> 16) as u8)
+    }
+
     /// Get slot ID
     pub fn slot_id(&self) -> u8 {
         self.slot_id
     }
 
+    /// Get root hub port (1-based)
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+
+    /// Get the interrupter the events of the slot go to
+    pub fn interrupter_target(&self) -> usize {
+        self.context.slot().interrupter_target as usize
+    }
+
     /// Get device context
     pub fn context(&self) -> &DeviceContext {
         &self.context
@@ -416,101 +457,165 @@ impl XhciDeviceSlot {
         &mut self.context
     }
 
-    /// Ring endpoint (process transfers)
-    pub fn ring_ep(&mut self, ep_id: u8) {
+    /// DCIs of the IN endpoints with a transfer ring
+    pub fn in_endpoints(&self) -> Vec<u8> {
+        (3..32u8)
+            .step_by(2)
+            .filter(|&dci| self.transfer_rings[dci as usize].is_some())
+            .collect()
+    }
+
+    /// Ring endpoint, restarting it if stopped, and process its transfers.
+    /// Returns the transfer events.
+    pub fn ring_ep(&mut self, ep_id: u8, mem: &GuestMemoryMmap) -> Vec<Trb> {
         if ep_id as usize >= self.transfer_rings.len() {
-            return;
+            return Vec::new();
         }
 
-        if let Some(ref mut ring) = self.transfer_rings[ep_id as usize] {
-            // Process pending transfers
-            while let Some(_trb) = ring.next() {
-                // Handle transfer
-            }
+        if self.transfer_rings[ep_id as usize].is_some()
+            && self
+                .context
+                .endpoint(ep_id as usize)
+                .is_some_and(|ep| ep.state() == EndpointState::Stopped)
+        {
+            self.update_endpoint_context(mem, ep_id, EndpointState::Running);
         }
+        self.process_transfer(ep_id, mem)
     }
 
-    /// Process transfer for an endpoint
+    /// Process transfers for an endpoint
     ///
     /// This method reads TRBs from the transfer ring and processes them.
     /// For IN endpoints, it queues data from the device.
     /// For OUT endpoints, it sends data to the device.
-    pub fn process_transfer(&mut self, ep_id: u8, mem: &Option<GuestMemoryMmap>) -> Option<(CompletionCode, u32)> {
+    /// An IN transfer the device has no data for yet stays on the ring.
+    /// Returns the transfer events.
+    pub fn process_transfer(&mut self, ep_id: u8, mem: &GuestMemoryMmap) -> Vec<Trb> {
+        let mut events = Vec::new();
         let ep_idx = ep_id as usize;
-        let ring = self.transfer_rings.get(ep_idx)?.as_ref()?;
-        let ep_ctx = self.context.endpoint(ep_idx)?;
 
         // Check if endpoint is running
-        if ep_ctx.state() != EndpointState::Running {
-            return None;
+        if self.context.endpoint(ep_idx).map(|ep| ep.state()) != Some(EndpointState::Running) {
+            return events;
         }
 
-        // Get the dequeue pointer
-        let dequeue_ptr = ep_ctx.tr_dequeue_ptr();
-        let dcs = ep_ctx.dcs();
-
-        // Read TRB from guest memory
-        let mem = mem.as_ref()?;
-        let trb = read_trb_from_memory(mem, dequeue_ptr)?;
+        while let Some((addr, trb)) = self
+            .transfer_rings
+            .get_mut(ep_idx)
+            .and_then(Option::as_mut)
+            .and_then(|ring| ring.peek(mem))
+        {
+            // Process based on TRB type
+            let (code, residual) = match trb.trb_type() {
+                Some(TrbType::Normal) => match self.handle_normal_transfer(&trb, ep_id, mem) {
+                    Some(result) => result,
+                    None => break,
+                },
+                Some(TrbType::SetupStage) => self.handle_setup_stage(&trb),
+                Some(TrbType::DataStage) => self.handle_data_stage(&trb, mem),
+                Some(TrbType::StatusStage | TrbType::EventData | TrbType::NoopTransfer) => {
+                    (CompletionCode::Success, 0)
+                }
+                _ => (CompletionCode::TrbError, 0),
+            };
 
-        // Check cycle bit
-        if trb.cycle_bit() != dcs {
-            // Ring is empty
-            return None;
+            let Some(ring) = self.transfer_rings[ep_idx].as_mut() else {
+                break;
+            };
+            ring.advance();
+
+            // Errors are always reported
+            let failed = !matches!(code, CompletionCode::Success | CompletionCode::ShortPacket);
+            if failed
+                || trb.is_interrupt_on_completion()
+                || (code == CompletionCode::ShortPacket && trb.is_interrupt_on_short_packet())
+            {
+                events.push(ring.create_transfer_event(addr, self.slot_id, code, residual));
+            }
+            if failed {
+                // The endpoint halts until the guest resets it
+                self.update_endpoint_context(mem, ep_id, EndpointState::Halted);
+                break;
+            }
         }
 
-        // Process based on TRB type
-        let result = match trb.trb_type() {
-            Some(TrbType::Normal) => self.handle_normal_transfer(&trb, ep_id, mem),
-            Some(TrbType::SetupStage) => self.handle_setup_stage(&trb),
-            Some(TrbType::DataStage) => self.handle_data_stage(&trb, ep_id, mem),
-            Some(TrbType::StatusStage) => self.handle_status_stage(&trb),
-            _ => (CompletionCode::TrbError, 0),
+        events
+    }
+
+    /// Handle normal transfer TRB, returns `None` while the device has no
+    /// data for an IN transfer
+    fn handle_normal_transfer(
+        &mut self,
+        trb: &Trb,
+        ep_id: u8,
+        mem: &GuestMemoryMmap,
+    ) -> Option<(CompletionCode, u32)> {
+        let data_ptr = trb.parameter;
+        let length = trb.transfer_length();
+
+        let Some(ref device) = self.device else {
+            return Some((CompletionCode::SlotNotEnabled, length));
+        };
+        let Ok(mut dev) = device.lock() else {
+            return Some((CompletionCode::Error, length));
         };
 
-        // Update dequeue pointer (simplified - should handle link TRBs)
-        let new_dequeue = dequeue_ptr + 16; // TRB_SIZE
-        self.context.endpoint_mut(ep_idx)?.set_tr_dequeue_ptr(new_dequeue);
+        // Odd DCIs are the IN endpoints
+        let is_in = ep_id % 2 == 1;
+        let ep = (ep_id / 2) | if is_in { 0x80 } else { 0 };
 
-        Some(result)
+        if is_in {
+            // IN transfer - get data from device
+            let data = match dev.handle_transfer(ep, &[]) {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(_) => return Some((CompletionCode::Stalled, length)),
+            };
+            // Write data to guest memory
+            let write_len = std::cmp::min(data.len(), length as usize);
+            if !write_mem(mem, data_ptr, &data[..write_len]) {
+                return Some((CompletionCode::Error, length));
+            }
+            Some(transfer_completion(length, write_len))
+        } else {
+            // OUT transfer - read data from guest memory, or from the TRB
+            // itself for immediate data
+            let data = if trb.is_immediate_data() {
+                let len = std::cmp::min(length as usize, 8);
+                trb.parameter.to_le_bytes()[..len].to_vec()
+            } else {
+                match read_mem(mem, data_ptr, length as usize) {
+                    Some(data) => data,
+                    None => return Some((CompletionCode::Error, length)),
+                }
+            };
+            match dev.handle_transfer(ep, &data) {
+                Ok(_) => Some((CompletionCode::Success, 0)),
+                Err(_) => Some((CompletionCode::Stalled, length)),
+            }
+        }
     }
 
-    /// Handle normal transfer TRB
-    fn handle_normal_transfer(&mut self, trb: &Trb, ep_id: u8, mem: &GuestMemoryMmap) -> (CompletionCode, u32) {
-        let data_ptr = trb.parameter;
-        let length = trb.transfer_length();
+    /// Handle setup stage TRB (for control endpoints)
+    fn handle_setup_stage(&mut self, trb: &Trb) -> (CompletionCode, u32) {
+        // Setup packet is in the parameter field (8 bytes)
+        self.setup = trb.parameter.to_le_bytes();
+        self.control_data.clear();
+
+        // Requests with data for the device go to it with the data stage
+        let length = u16::from_le_bytes([self.setup[6], self.setup[7]]);
+        if self.setup[0] & 0x80 == 0 && length > 0 {
+            return (CompletionCode::Success, 0);
+        }
 
         if let Some(ref device) = self.device {
             if let Ok(mut dev) = device.lock() {
-                // Determine direction from endpoint number
-                let is_in = ep_id >= 16; // EP 16-31 are IN endpoints
-
-                if is_in {
-                    // IN transfer - get data from device
-                    let ep = 0x80 | (ep_id - 16);
-                    match dev.handle_transfer(ep, &[]) {
-                        Ok(data) => {
-                            // Write data to guest memory
-                            let write_len = std::cmp::min(data.len(), length as usize);
-                            if write_mem(mem, data_ptr, &data[..write_len]) {
-                                (CompletionCode::Success, write_len as u32)
-                            } else {
-                                (CompletionCode::Error, 0)
-                            }
-                        }
-                        Err(_) => (CompletionCode::Stalled, 0),
-                    }
-                } else {
-                    // OUT transfer - read data from guest memory
-                    let read_len = length as usize;
-                    if let Some(data) = read_mem(mem, data_ptr, read_len) {
-                        match dev.handle_transfer(ep_id, &data) {
-                            Ok(_) => (CompletionCode::Success, length),
-                            Err(_) => (CompletionCode::Stalled, 0),
-                        }
-                    } else {
-                        (CompletionCode::Error, 0)
+                match dev.handle_control(&self.setup) {
+                    Ok(data) => {
+                        self.control_data = data;
+                        (CompletionCode::Success, 0)
                     }
+                    Err(_) => (CompletionCode::Stalled, 0),
                 }
             } else {
                 (CompletionCode::Error, 0)
@@ -520,46 +625,114 @@ impl XhciDeviceSlot {
         }
     }
 
-    /// Handle setup stage TRB (for control endpoints)
-    fn handle_setup_stage(&mut self, trb: &Trb) -> (CompletionCode, u32) {
-        // Setup packet is in the parameter field (8 bytes)
-        let setup_packet = trb.parameter.to_le_bytes();
+    /// Handle data stage TRB
+    fn handle_data_stage(&mut self, trb: &Trb, mem: &GuestMemoryMmap) -> (CompletionCode, u32) {
+        let length = trb.transfer_length();
+
+        if trb.is_direction_in() {
+            // Hand the guest what the device answered to the setup stage
+            let write_len = std::cmp::min(self.control_data.len(), length as usize);
+            if !write_mem(mem, trb.parameter, &self.control_data[..write_len]) {
+                return (CompletionCode::Error, length);
+            }
+            return transfer_completion(length, write_len);
+        }
 
+        let Some(data) = read_mem(mem, trb.parameter, length as usize) else {
+            return (CompletionCode::Error, length);
+        };
         if let Some(ref device) = self.device {
             if let Ok(mut dev) = device.lock() {
-                match dev.handle_control(&setup_packet) {
-                    Ok(_) => (CompletionCode::Success, 0),
-                    Err(_) => (CompletionCode::Stalled, 0),
+                match dev.handle_control_out(&self.setup, &data) {
+                    Ok(()) => (CompletionCode::Success, 0),
+                    Err(_) => (CompletionCode::Stalled, length),
                 }
             } else {
-                (CompletionCode::Error, 0)
+                (CompletionCode::Error, length)
             }
         } else {
-            (CompletionCode::SlotNotEnabled, 0)
+            (CompletionCode::SlotNotEnabled, length)
         }
     }
 
-    /// Handle data stage TRB
-    fn handle_data_stage(&mut self, trb: &Trb, ep_id: u8, mem: &GuestMemoryMmap) -> (CompletionCode, u32) {
-        // Similar to normal transfer but for control endpoint data phase
-        self.handle_normal_transfer(trb, ep_id, mem)
+    /// Initialize endpoint ring from the dequeue pointer and cycle state
+    /// of its endpoint context
+    pub fn init_ep_ring(&mut self, ep_id: u8, dequeue: u64, dcs: bool) {
+        if ep_id as usize >= self.transfer_rings.len() {
+            return;
+        }
+
+        self.transfer_rings[ep_id as usize] = Some(TransferRing::new(ep_id, dequeue, dcs));
+        if let Some(ep) = self.context.endpoint_mut(ep_id as usize) {
+            ep.set_state(EndpointState::Running);
+            ep.set_tr_dequeue_ptr(dequeue & !0xF);
+            ep.set_dcs(dcs);
+        }
     }
 
-    /// Handle status stage TRB
-    fn handle_status_stage(&mut self, _trb: &Trb) -> (CompletionCode, u32) {
-        // Status stage is zero-length
-        (CompletionCode::Success, 0)
+    /// Set up an endpoint from its input endpoint context, and write it as
+    /// running to the output device context
+    fn enable_endpoint(&mut self, mem: &GuestMemoryMmap, ep_id: u8, mut ctx: [u32; 8]) -> bool {
+        let dequeue = (ctx[2] as u64 | ((ctx[3] as u64) << 32)) & !0xF;
+        self.init_ep_ring(ep_id, dequeue, ctx[2] & 1 != 0);
+        if let Some(ep) = self.context.endpoint_mut(ep_id as usize) {
+            ep.ep_type = ((ctx[1] >> 3) & 0x7) as u8;
+        }
+
+        ctx[0] = (ctx[0] & !0x7) | EndpointState::Running as u32;
+        write_context(mem, self.output_context + ep_id as u64 * CONTEXT_SIZE, &ctx)
     }
 
-    /// Initialize endpoint ring
-    pub fn init_ep_ring(&mut self, ep_id: u8, base: u64, size: usize) {
-        if ep_id as usize >= self.transfer_rings.len() {
+    /// Drop the transfer ring of an endpoint, written as disabled to the
+    /// output device context
+    fn disable_endpoint(&mut self, mem: &GuestMemoryMmap, ep_id: u8) {
+        if self.transfer_rings[ep_id as usize].is_some() {
+            self.update_endpoint_context(mem, ep_id, EndpointState::Disabled);
+            self.transfer_rings[ep_id as usize] = None;
+        }
+    }
+
+    /// Write the state and dequeue pointer of an endpoint to the output
+    /// device context
+    fn update_endpoint_context(&mut self, mem: &GuestMemoryMmap, ep_id: u8, state: EndpointState) {
+        let dequeue = self.transfer_rings[ep_id as usize]
+            .as_ref()
+            .map(|ring| (ring.dequeue_ptr(), ring.dcs()));
+        if let Some(ep) = self.context.endpoint_mut(ep_id as usize) {
+            ep.set_state(state);
+            if let Some((ptr, dcs)) = dequeue {
+                ep.set_tr_dequeue_ptr(ptr);
+                ep.set_dcs(dcs);
+            }
+        }
+
+        let addr = self.output_context + ep_id as u64 * CONTEXT_SIZE;
+        let Some(mut ctx) = read_context(mem, addr) else {
             return;
+        };
+        ctx[0] = (ctx[0] & !0x7) | state as u32;
+        if let Some((ptr, dcs)) = dequeue {
+            ctx[2] = ptr as u32 | dcs as u32;
+            ctx[3] = (ptr >> 32) as u32;
+        }
+        if !write_context(mem, addr, &ctx) {
+            warn!(
+                "Failed to update the context of slot {} endpoint {ep_id}",
+                self.slot_id
+            );
         }
+    }
 
-        let mut ring = TransferRing::new(ep_id);
-        ring.init(base, size);
-        self.transfer_rings[ep_id as usize] = Some(ring);
+    /// Write the state and address of the slot to the output device
+    /// context
+    fn update_slot_context(&self, mem: &GuestMemoryMmap) {
+        let Some(mut ctx) = read_context(mem, self.output_context) else {
+            return;
+        };
+        ctx[3] = self.address() as u32 | ((self.state() as u32) << 27);
+        if !write_context(mem, self.output_context, &ctx) {
+            warn!("Failed to update the context of slot {}", self.slot_id);
+        }
     }
 
     /// Set slot state
@@ -582,55 +755,183 @@ impl XhciDeviceSlot {
         self.context.slot().device_address()
     }
 
-    /// Handle command TRB
-    /// `address` is the USB device address to assign (caller should allocate it)
-    pub fn handle_command(&mut self, trb: &Trb, address: Option<u8>) -> (CompletionCode, u32) {
+    /// Handle the Address Device command, binding `device` on the root hub
+    /// `port` to the slot. An `address` of 0 (BSR set) only moves the slot
+    /// to the Default state.
+    pub fn address_device(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        input: u64,
+        output: u64,
+        port: u8,
+        device: Arc<Mutex<dyn UsbDevice>>,
+        address: u8,
+    ) -> CompletionCode {
+        let (Some(icc), Some(mut slot_ctx), Some(ep0_ctx)) = (
+            read_context(mem, input),
+            read_context(mem, input + CONTEXT_SIZE),
+            read_context(mem, input + 2 * CONTEXT_SIZE),
+        ) else {
+            return CompletionCode::TrbError;
+        };
+        // The slot and EP0 contexts must both be added
+        if icc[1] & 0x3 != 0x3 {
+            return CompletionCode::ParameterError;
+        }
+
+        let state = if address == 0 {
+            SlotState::Default
+        } else {
+            // The controller sends SET_ADDRESS on behalf of the guest
+            if let Ok(mut dev) = device.lock()
+                && let Err(e) = dev.handle_control(&[0x00, 0x05, address, 0, 0, 0, 0, 0])
+            {
+                warn!("Failed to set the address of the device on port {port}: {e}");
+            }
+            SlotState::Addressed
+        };
+
+        slot_ctx[3] = address as u32 | ((state as u32) << 27);
+        if !write_context(mem, output, &slot_ctx) {
+            return CompletionCode::TrbError;
+        }
+
+        self.port = port;
+        self.output_context = output;
+        self.device = Some(device);
+        self.context.slot_mut().interrupter_target = (slot_ctx[2] >> 22) as u16;
+        self.set_state(state);
+        self.set_address(address);
+
+        if !self.enable_endpoint(mem, 1, ep0_ctx) {
+            return CompletionCode::TrbError;
+        }
+        CompletionCode::Success
+    }
+
+    /// Handle command TRB, with the contexts in guest memory
+    pub fn handle_command(&mut self, trb: &Trb, mem: &GuestMemoryMmap) -> CompletionCode {
+        let input = trb.parameter & !0xF;
+        let ep_id = trb.endpoint_id();
+
         match trb.trb_type() {
-            Some(TrbType::AddressDevice) => {
-                // Address device command
-                if let Some(addr) = address {
-                    self.set_state(SlotState::Addressed);
-                    self.set_address(addr);
-                    (CompletionCode::Success, 0)
+            Some(TrbType::ConfigureEndpoint) => {
+                if !matches!(self.state(), SlotState::Addressed | SlotState::Configured) {
+                    return CompletionCode::ContextStateError;
+                }
+
+                // Deconfigure drops every endpoint but EP0
+                let (drop, add) = if trb.control & (1 << 9) != 0 {
+                    (!0, 0)
                 } else {
-                    (CompletionCode::ResourceError, 0)
+                    match read_context(mem, input) {
+                        Some(icc) => (icc[0], icc[1]),
+                        None => return CompletionCode::TrbError,
+                    }
+                };
+
+                for ep_id in 2..32u8 {
+                    if drop & (1 << ep_id) != 0 {
+                        self.disable_endpoint(mem, ep_id);
+                    }
                 }
-            }
-            Some(TrbType::ConfigureEndpoint) => {
-                // Configure endpoint command
-                (CompletionCode::Success, 0)
+                for ep_id in 2..32u8 {
+                    if add & (1 << ep_id) == 0 {
+                        continue;
+                    }
+                    let Some(ctx) = read_context(mem, input + (ep_id as u64 + 1) * CONTEXT_SIZE)
+                    else {
+                        return CompletionCode::TrbError;
+                    };
+                    if !self.enable_endpoint(mem, ep_id, ctx) {
+                        return CompletionCode::TrbError;
+                    }
+                }
+
+                let configured = self.transfer_rings[2..].iter().any(Option::is_some);
+                self.set_state(if configured {
+                    SlotState::Configured
+                } else {
+                    SlotState::Addressed
+                });
+                self.update_slot_context(mem);
+                CompletionCode::Success
             }
             Some(TrbType::EvaluateContext) => {
-                // Evaluate context command
-                (CompletionCode::Success, 0)
+                if self.state() == SlotState::Disabled {
+                    return CompletionCode::ContextStateError;
+                }
+                let (Some(icc), Some(slot_in), Some(ep0_in)) = (
+                    read_context(mem, input),
+                    read_context(mem, input + CONTEXT_SIZE),
+                    read_context(mem, input + 2 * CONTEXT_SIZE),
+                ) else {
+                    return CompletionCode::TrbError;
+                };
+
+                // Only the max exit latency, the interrupter target and the
+                // EP0 max packet size are evaluated
+                if icc[1] & 0x1 != 0
+                    && let Some(mut slot_out) = read_context(mem, self.output_context)
+                {
+                    slot_out[1] = (slot_out[1] & !0xFFFF) | (slot_in[1] & 0xFFFF);
+                    slot_out[2] = (slot_out[2] & 0x3F_FFFF) | (slot_in[2] & !0x3F_FFFF);
+                    self.context.slot_mut().interrupter_target = (slot_in[2] >> 22) as u16;
+                    write_context(mem, self.output_context, &slot_out);
+                }
+                if icc[1] & 0x2 != 0
+                    && let Some(mut ep0_out) = read_context(mem, self.output_context + CONTEXT_SIZE)
+                {
+                    ep0_out[1] = (ep0_out[1] & 0xFFFF) | (ep0_in[1] & !0xFFFF);
+                    write_context(mem, self.output_context + CONTEXT_SIZE, &ep0_out);
+                }
+                CompletionCode::Success
             }
             Some(TrbType::ResetEndpoint) => {
-                // Reset endpoint command
-                (CompletionCode::Success, 0)
+                if self.context.endpoint(ep_id as usize).map(|ep| ep.state())
+                    != Some(EndpointState::Halted)
+                {
+                    return CompletionCode::ContextStateError;
+                }
+                self.update_endpoint_context(mem, ep_id, EndpointState::Stopped);
+                CompletionCode::Success
             }
             Some(TrbType::StopEndpoint) => {
-                // Stop endpoint command
-                (CompletionCode::Success, 0)
+                if self.context.endpoint(ep_id as usize).map(|ep| ep.state())
+                    != Some(EndpointState::Running)
+                {
+                    return CompletionCode::ContextStateError;
+                }
+                self.update_endpoint_context(mem, ep_id, EndpointState::Stopped);
+                CompletionCode::Success
             }
             Some(TrbType::SetTrDequeue) => {
-                // Set TR dequeue pointer command
-                let ep_id = (trb.control >> 16) & 0x1F;
-                if let Some(ref mut ring) = self.transfer_rings[ep_id as usize] {
-                    ring.set_dequeue_ptr(trb.parameter & !0xF, trb.parameter & 1 != 0);
+                let state = self.context.endpoint(ep_id as usize).map(|ep| ep.state());
+                if !matches!(state, Some(EndpointState::Stopped | EndpointState::Error)) {
+                    return CompletionCode::ContextStateError;
                 }
-                (CompletionCode::Success, 0)
+                let Some(ref mut ring) = self.transfer_rings[ep_id as usize] else {
+                    return CompletionCode::EndpointNotEnabled;
+                };
+                ring.set_dequeue_ptr(trb.parameter & !0xF, trb.parameter & 1 != 0);
+                self.update_endpoint_context(mem, ep_id, EndpointState::Stopped);
+                CompletionCode::Success
             }
             Some(TrbType::ResetDevice) => {
-                // Reset device command
-                if let Some(ref device) = self.device {
-                    if let Ok(mut dev) = device.lock() {
-                        dev.reset();
-                    }
+                if let Some(ref device) = self.device
+                    && let Ok(mut dev) = device.lock()
+                {
+                    dev.reset();
+                }
+                for ep_id in 2..32u8 {
+                    self.disable_endpoint(mem, ep_id);
                 }
                 self.set_state(SlotState::Default);
-                (CompletionCode::Success, 0)
+                self.set_address(0);
+                self.update_slot_context(mem);
+                CompletionCode::Success
             }
-            _ => (CompletionCode::TrbError, 0),
+            _ => CompletionCode::TrbError,
         }
     }
 }
@@ -9,14 +9,19 @@
 pub mod regs;
 pub mod rings;
 pub mod device;
+pub mod pci_device;
 
 pub use regs::*;
 pub use rings::*;
 pub use device::*;
+pub use pci_device::{XhciPciDevice, XhciPciError, XHCI_KEYBOARD_PORT, XHCI_MOUSE_PORT, XHCI_TABLET_PORT};
 
 use std::sync::{Arc, Mutex};
 
-use vm_memory::GuestMemoryMmap;
+use vm_memory::bitmap::AtomicBitmap;
+use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryLoadGuard};
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 
 /// xHCI controller version
 pub const XHCI_VERSION: u16 = 0x0100; // xHCI 1.0
@@ -30,6 +35,9 @@ pub const XHCI_MAX_INTRS: u8 = 8;
 /// Maximum number of ports
 pub const XHCI_MAX_PORTS: u8 = 8;
 
+/// Maximum number of event ring segments per interrupter (HCSPARAMS2.ERST Max)
+const XHCI_MAX_ERST_SIZE: u32 = 1;
+
 /// xHCI operational states
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum XhciState {
@@ -43,6 +51,12 @@ pub enum XhciState {
     Reset,
 }
 
+/// Interrupt signalling of the controller
+pub trait XhciInterrupt: Send + Sync {
+    /// Signal an interrupter to the guest
+    fn trigger(&self, interrupter: usize);
+}
+
 /// xHCI Host Controller
 pub struct XhciController {
     /// Controller state
@@ -57,12 +71,16 @@ pub struct XhciController {
     doorbells: Vec<regs::DoorbellRegister>,
     /// Device slots
     slots: Vec<Option<Arc<Mutex<device::XhciDeviceSlot>>>>,
+    /// Devices attached to the root hub ports
+    ports: Vec<Option<Arc<Mutex<dyn device::UsbDevice>>>>,
     /// Command ring
     cmd_ring: rings::CommandRing,
     /// Event rings
     event_rings: Vec<rings::EventRing>,
     /// Guest memory for DMA
-    mem: Option<GuestMemoryMmap>,
+    mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+    /// Interrupt signalling
+    interrupt: Option<Arc<dyn XhciInterrupt>>,
     /// USB device address allocator (bit 0 unused, 1-127 valid)
     address_bitmap: u128,
 }
@@ -72,7 +90,7 @@ impl XhciController {
     pub fn new() -> Self {
         let cap_regs = regs::CapabilityRegisters::new(XHCI_MAX_SLOTS, XHCI_MAX_INTRS, XHCI_MAX_PORTS);
         let op_regs = regs::OperationalRegisters::default();
-        let rt_regs = regs::RuntimeRegisters::default();
+        let rt_regs = regs::RuntimeRegisters::new();
 
         let doorbells = vec![regs::DoorbellRegister::default(); XHCI_MAX_SLOTS as usize + 1];
         let slots = vec![None; XHCI_MAX_SLOTS as usize + 1];
@@ -87,18 +105,30 @@ impl XhciController {
             rt_regs,
             doorbells,
             slots,
+            ports: vec![None; XHCI_MAX_PORTS as usize],
             cmd_ring: rings::CommandRing::new(),
             event_rings,
             mem: None,
+            interrupt: None,
             address_bitmap: 0,
         }
     }
 
     /// Set guest memory for DMA operations
-    pub fn set_memory(&mut self, mem: GuestMemoryMmap) {
+    pub fn set_memory(&mut self, mem: GuestMemoryAtomic<GuestMemoryMmap>) {
         self.mem = Some(mem);
     }
 
+    /// Set interrupt signalling
+    pub fn set_interrupt(&mut self, interrupt: Arc<dyn XhciInterrupt>) {
+        self.interrupt = Some(interrupt);
+    }
+
+    /// Get guest memory
+    fn memory(&self) -> Option<GuestMemoryLoadGuard<GuestMemoryMmap>> {
+        self.mem.as_ref().map(|mem| mem.memory())
+    }
+
     /// Get capability registers
     pub fn capability_registers(&self) -> &regs::CapabilityRegisters {
         &self.cap_regs
@@ -106,12 +136,31 @@ impl XhciController {
 
     /// Read operational register
     pub fn read_operational(&self, offset: u64) -> u32 {
-        self.op_regs.read(offset)
+        let value = self.op_regs.read(offset);
+        // CRCR only reads back whether the command ring is running
+        if offset == 0x18 && self.cmd_ring.is_running() {
+            value | regs::CRCR_CRR
+        } else {
+            value
+        }
     }
 
     /// Write operational register
     pub fn write_operational(&mut self, offset: u64, value: u32) {
-        self.op_regs.write(offset, value, &mut self.state);
+        match self.op_regs.write(offset, value, &mut self.state) {
+            Some(regs::OperationalWrite::Reset) => self.reset(),
+            Some(regs::OperationalWrite::Halted) => self.cmd_ring.stop(),
+            Some(regs::OperationalWrite::CommandRing) => {
+                let crcr = self.op_regs.crcr();
+                self.cmd_ring
+                    .set_dequeue_ptr(crcr & regs::CRCR_PTR_MASK, crcr & regs::CRCR_RCS != 0);
+            }
+            Some(regs::OperationalWrite::CommandRingStop) => self.stop_command_ring(),
+            Some(regs::OperationalWrite::PortReset(port_id)) => {
+                self.send_port_status_change(port_id);
+            }
+            None => {}
+        }
     }
 
     /// Read runtime register
@@ -121,7 +170,94 @@ impl XhciController {
 
     /// Write runtime register
     pub fn write_runtime(&mut self, offset: u64, value: u32) {
-        self.rt_regs.write(offset, value);
+        match self.rt_regs.write(offset, value) {
+            Some(regs::InterrupterWrite::SegmentTable(intr)) => self.load_segment_table(intr),
+            Some(regs::InterrupterWrite::DequeuePointer(intr)) => {
+                if let Some(ptr) = self.rt_regs.dequeue_pointer(intr) {
+                    self.event_rings[intr].set_dequeue_ptr(ptr);
+                }
+                // Room was made for the events left over, and the ones not
+                // consumed yet are signalled again
+                self.flush_events(intr);
+            }
+            None => {}
+        }
+    }
+
+    /// Reset the controller on USBCMD.HCRST
+    fn reset(&mut self) {
+        self.rt_regs = regs::RuntimeRegisters::new();
+        self.doorbells = vec![regs::DoorbellRegister::default(); XHCI_MAX_SLOTS as usize + 1];
+        self.slots = vec![None; XHCI_MAX_SLOTS as usize + 1];
+        self.cmd_ring = rings::CommandRing::new();
+        self.event_rings = (0..XHCI_MAX_INTRS)
+            .map(|_| rings::EventRing::new())
+            .collect();
+        self.address_bitmap = 0;
+
+        for device in self.ports.iter().flatten() {
+            if let Ok(mut dev) = device.lock() {
+                dev.reset();
+            }
+        }
+    }
+
+    /// Load the event ring segment table of an interrupter
+    fn load_segment_table(&mut self, intr: usize) {
+        let Some(mem) = self.memory() else {
+            return;
+        };
+        let Some((size, base)) = self.rt_regs.segment_table(intr) else {
+            return;
+        };
+
+        let segments = (0..size.min(XHCI_MAX_ERST_SIZE) as u64)
+            .filter_map(|i| rings::EventRingSegment::read_from(&mem, base, i))
+            .collect();
+        self.event_rings[intr].set_segments(segments);
+        self.flush_events(intr);
+    }
+
+    /// Queue an event on the event ring of an interrupter
+    fn send_event(&mut self, intr: usize, event: rings::Trb) {
+        let intr = if intr < self.event_rings.len() {
+            intr
+        } else {
+            0
+        };
+        self.event_rings[intr].queue(event);
+        self.flush_events(intr);
+    }
+
+    /// Write the queued events of an interrupter to its event ring, and
+    /// signal the events the guest has yet to handle
+    fn flush_events(&mut self, intr: usize) {
+        let Some(mem) = self.memory() else {
+            return;
+        };
+        let Some(ring) = self.event_rings.get_mut(intr) else {
+            return;
+        };
+        ring.flush(&mem);
+        if ring.is_empty() || !self.rt_regs.assert_interrupt(intr) {
+            return;
+        }
+
+        self.op_regs.set_event_interrupt();
+        if self.op_regs.usbcmd() & regs::USBCMD_INTE != 0
+            && self.rt_regs.interrupt_enabled(intr)
+            && let Some(ref interrupt) = self.interrupt
+        {
+            interrupt.trigger(intr);
+        }
+    }
+
+    /// Report a port status change (1-based port ID) to the guest
+    fn send_port_status_change(&mut self, port_id: u8) {
+        let mut event = rings::Trb::new((port_id as u64) << 24, 0, 0);
+        event.set_trb_type(rings::TrbType::PortStatusChange);
+        event.set_completion_code(rings::CompletionCode::Success);
+        self.send_event(0, event);
     }
 
     /// Ring doorbell
@@ -133,94 +269,170 @@ impl XhciController {
         // Store doorbell value
         self.doorbells[slot_id as usize].target = target;
 
+        if self.state != XhciState::Running {
+            return;
+        }
+
         if slot_id == 0 {
             // Command ring doorbell
+            self.cmd_ring.start();
             self.process_command_ring();
-        } else if let Some(ref slot) = self.slots[slot_id as usize] {
+        } else {
             // Transfer ring doorbell for a device slot
-            if let Ok(mut slot) = slot.lock() {
-                slot.ring_ep(target);
-            }
+            self.process_transfer(slot_id, target);
         }
     }
 
+    /// Stop the command ring on CRCR.CS or CRCR.CA, which the guest learns
+    /// about through a Command Ring Stopped completion
+    fn stop_command_ring(&mut self) {
+        if !self.cmd_ring.is_running() {
+            return;
+        }
+
+        self.cmd_ring.stop();
+        let event = self.cmd_ring.create_completion_event(
+            self.cmd_ring.dequeue_ptr(),
+            0,
+            rings::CompletionCode::CommandRingStopped,
+        );
+        self.send_event(0, event);
+    }
+
     /// Process command ring
     fn process_command_ring(&mut self) {
-        if self.state != XhciState::Running {
+        let Some(mem) = self.memory() else {
             return;
-        }
+        };
 
         // Process command TRBs from the command ring
-        while let Some(trb) = self.cmd_ring.next() {
-            let (code, slot_id) = self.handle_command(&trb);
+        while let Some((addr, trb)) = self.cmd_ring.next(&mem) {
+            let (code, slot_id) = self.handle_command(&trb, &mem);
 
-            // Create completion event
-            let event = self.cmd_ring.create_completion_event(&trb, slot_id, code);
+            // Create completion event, for the primary interrupter
+            let event = self.cmd_ring.create_completion_event(addr, slot_id, code);
+            self.send_event(0, event);
+        }
+    }
 
-            // Queue event to primary interrupter
-            if let Some(event_ring) = self.event_rings.first_mut() {
-                event_ring.queue(event);
-            }
+    /// Get the device slot of an enabled slot ID
+    fn slot(&self, slot_id: u8) -> Option<Arc<Mutex<device::XhciDeviceSlot>>> {
+        if slot_id == 0 {
+            return None;
         }
+        self.slots.get(slot_id as usize).cloned().flatten()
     }
 
-    /// Handle a command TRB
-    fn handle_command(&mut self, trb: &rings::Trb) -> (rings::CompletionCode, u8) {
-        use rings::TrbType;
+    /// Get the output device context of a slot from the DCBAA
+    fn output_context(&self, mem: &GuestMemoryMmap, slot_id: u8) -> u64 {
+        let entry = self.op_regs.dcbaap() + slot_id as u64 * 8;
+        mem.read_obj::<u64>(GuestAddress(entry))
+            .map_or(0, |ptr| ptr & !0x3F)
+    }
 
+    /// Handle a command TRB
+    fn handle_command(
+        &mut self,
+        trb: &rings::Trb,
+        mem: &GuestMemoryMmap,
+    ) -> (rings::CompletionCode, u8) {
+        use rings::{CompletionCode, TrbType};
+
+        let slot_id = trb.slot_id();
         match trb.trb_type() {
             Some(TrbType::EnableSlot) => {
                 // Find free slot
                 match self.find_free_slot() {
-                    Ok(slot_id) => (rings::CompletionCode::Success, slot_id),
-                    Err(_) => (rings::CompletionCode::NoSlotsAvailable, 0),
+                    Ok(slot_id) => {
+                        let slot = device::XhciDeviceSlot::new(slot_id);
+                        self.slots[slot_id as usize] = Some(Arc::new(Mutex::new(slot)));
+                        (CompletionCode::Success, slot_id)
+                    }
+                    Err(_) => (CompletionCode::NoSlotsAvailable, 0),
                 }
             }
             Some(TrbType::DisableSlot) => {
-                let slot_id = (trb.control >> 24) as u8;
-                if (slot_id as usize) < self.slots.len() {
-                    self.slots[slot_id as usize] = None;
-                    (rings::CompletionCode::Success, slot_id)
+                if self.slot(slot_id).is_some() && self.detach_device(slot_id).is_ok() {
+                    (CompletionCode::Success, slot_id)
                 } else {
-                    (rings::CompletionCode::SlotNotEnabled, 0)
+                    (CompletionCode::SlotNotEnabled, slot_id)
                 }
             }
             Some(TrbType::AddressDevice) => {
-                let slot_id = (trb.control >> 24) as u8;
-                if let Some(ref slot) = self.slots.get(slot_id as usize).and_then(|s| s.clone()) {
-                    if let Ok(mut s) = slot.lock() {
-                        let addr = self.allocate_address();
-                        let (code, _) = s.handle_command(trb, addr);
-                        (code, slot_id)
-                    } else {
-                        (rings::CompletionCode::SlotNotEnabled, slot_id)
-                    }
+                let Some(slot) = self.slot(slot_id) else {
+                    return (CompletionCode::SlotNotEnabled, slot_id);
+                };
+                // The input context names the root hub port of the device
+                let input = trb.parameter & !0xF;
+                let Some(port_id) = device::XhciDeviceSlot::input_root_hub_port(mem, input) else {
+                    return (CompletionCode::TrbError, slot_id);
+                };
+                let Some(device) = port_id
+                    .checked_sub(1)
+                    .and_then(|port| self.ports.get(port as usize).cloned().flatten())
+                else {
+                    return (CompletionCode::TrbError, slot_id);
+                };
+                let Ok(mut slot) = slot.lock() else {
+                    return (CompletionCode::SlotNotEnabled, slot_id);
+                };
+
+                // A slot is addressed again after a device reset
+                self.free_address(slot.address());
+                // BSR only moves the slot to the Default state
+                let address = if trb.control & (1 << 9) != 0 {
+                    0
                 } else {
-                    (rings::CompletionCode::SlotNotEnabled, 0)
+                    match self.allocate_address() {
+                        Some(address) => address,
+                        None => return (CompletionCode::ResourceError, slot_id),
+                    }
+                };
+
+                let output = self.output_context(mem, slot_id);
+                let code = slot.address_device(mem, input, output, port_id, device, address);
+                if code != CompletionCode::Success {
+                    self.free_address(address);
+                    slot.set_address(0);
                 }
+                (code, slot_id)
             }
-            Some(TrbType::ConfigureEndpoint) => {
-                let slot_id = (trb.control >> 24) as u8;
-                if let Some(ref slot) = self.slots.get(slot_id as usize).and_then(|s| s.clone()) {
-                    if let Ok(mut s) = slot.lock() {
-                        let (code, _) = s.handle_command(trb, None);
-                        (code, slot_id)
-                    } else {
-                        (rings::CompletionCode::SlotNotEnabled, slot_id)
-                    }
-                } else {
-                    (rings::CompletionCode::SlotNotEnabled, 0)
+            Some(
+                TrbType::ConfigureEndpoint
+                | TrbType::EvaluateContext
+                | TrbType::ResetEndpoint
+                | TrbType::StopEndpoint
+                | TrbType::SetTrDequeue
+                | TrbType::ResetDevice,
+            ) => {
+                let Some(slot) = self.slot(slot_id) else {
+                    return (CompletionCode::SlotNotEnabled, slot_id);
+                };
+                let Ok(mut slot) = slot.lock() else {
+                    return (CompletionCode::SlotNotEnabled, slot_id);
+                };
+                if trb.trb_type() == Some(TrbType::ResetDevice) {
+                    self.free_address(slot.address());
                 }
+                (slot.handle_command(trb, mem), slot_id)
             }
             Some(TrbType::Noop) => {
-                (rings::CompletionCode::Success, 0)
+                (CompletionCode::Success, 0)
             }
             _ => {
-                (rings::CompletionCode::TrbError, 0)
+                (CompletionCode::TrbError, 0)
             }
         }
     }
 
+    /// Check if an interrupter has events waiting for room in its event
+    /// ring
+    pub fn has_pending_events(&self, interrupter: usize) -> bool {
+        self.event_rings
+            .get(interrupter)
+            .is_some_and(|ring| ring.has_pending())
+    }
+
     /// Get controller state
     pub fn state(&self) -> XhciState {
         self.state
@@ -231,26 +443,25 @@ impl XhciController {
         XHCI_MAX_PORTS
     }
 
-    /// Attach a device to a port
-    pub fn attach_device(&mut self, port: u8, device: Arc<Mutex<dyn device::UsbDevice>>) -> Result<u8, XhciError> {
+    /// Attach a device to a port, the guest enumerates it from there
+    pub fn attach_device(
+        &mut self,
+        port: u8,
+        device: Arc<Mutex<dyn device::UsbDevice>>,
+    ) -> Result<(), XhciError> {
         if port >= XHCI_MAX_PORTS {
             return Err(XhciError::InvalidPort);
         }
 
-        // Find free slot
-        let slot_id = self.find_free_slot()?;
-        if slot_id == 0 {
-            return Err(XhciError::NoFreeSlots);
-        }
-
-        // Create device slot
-        let slot = device::XhciDeviceSlot::new(slot_id, device);
-        self.slots[slot_id as usize] = Some(Arc::new(Mutex::new(slot)));
+        self.ports[port as usize] = Some(device);
 
-        // Enable slot in port status
+        // Report the connection in port status
         self.op_regs.set_port_connected(port, true);
+        if self.state == XhciState::Running {
+            self.send_port_status_change(port + 1);
+        }
 
-        Ok(slot_id)
+        Ok(())
     }
 
     /// Detach device from a port
@@ -294,11 +505,53 @@ impl XhciController {
             return;
         }
 
-        if let Some(ref slot) = self.slots[slot_id as usize] {
-            if let Ok(mut slot_guard) = slot.lock() {
-                // Process transfers from the endpoint's transfer ring
-                slot_guard.process_transfer(ep_id, &self.mem);
+        let Some(mem) = self.memory() else {
+            return;
+        };
+        let Some(slot) = self.slot(slot_id) else {
+            return;
+        };
+        let (intr, events) = match slot.lock() {
+            Ok(mut slot) => (slot.interrupter_target(), slot.ring_ep(ep_id, &mem)),
+            Err(_) => return,
+        };
+        for event in events {
+            self.send_event(intr, event);
+        }
+    }
+
+    /// Hand the data the device on a port got ready to the transfers the
+    /// guest queued on its IN endpoints
+    pub fn port_data_ready(&mut self, port: u8) {
+        if self.state != XhciState::Running {
+            return;
+        }
+        let Some(mem) = self.memory() else {
+            return;
+        };
+        let Some(slot) = self
+            .slots
+            .iter()
+            .flatten()
+            .find(|slot| slot.lock().is_ok_and(|slot| slot.port() == port + 1))
+            .cloned()
+        else {
+            return;
+        };
+
+        let (intr, events) = match slot.lock() {
+            Ok(mut slot) => {
+                let events: Vec<_> = slot
+                    .in_endpoints()
+                    .into_iter()
+                    .flat_map(|ep_id| slot.process_transfer(ep_id, &mem))
+                    .collect();
+                (slot.interrupter_target(), events)
             }
+            Err(_) => return,
+        };
+        for event in events {
+            self.send_event(intr, event);
         }
     }
 
@@ -0,0 +1,853 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! xHCI PCI Device
+//!
+//! This module exposes the [`XhciController`] to the guest as a PCI
+//! function (class 0x0C, subclass 0x03, prog-if 0x30). All controller
+//! registers live in a single 32-bit MMIO BAR:
+//!
+//! ```text
+//! 0x0000 - 0x003F  Capability registers
+//! 0x0040 - 0x0FFF  Operational registers (incl. port registers at 0x440)
+//! 0x1000 - 0x1FFF  Doorbell array (DBOFF)
+//! 0x2000 - 0x2FFF  Runtime registers (RTSOFF)
+//! 0x3000 - 0x37FF  MSI-X table
+//! 0x3800 - 0x3FFF  MSI-X pending bit array
+//! ```
+//!
+//! Each interrupter is backed by one MSI-X vector, signalled as the
+//! controller writes events to its event ring.
+
+use std::any::Any;
+use std::result;
+use std::sync::{Arc, Barrier, Mutex};
+
+use anyhow::anyhow;
+use byteorder::{ByteOrder, LittleEndian};
+use log::{debug, error, warn};
+use pci::{
+    BarReprogrammingParams, MsixCap, MsixConfig, PCI_CONFIGURATION_ID, PciBarConfiguration,
+    PciBarPrefetchable, PciBarRegionType, PciClassCode, PciConfiguration, PciDevice,
+    PciDeviceError, PciHeaderType, PciProgrammingInterface, PciSerialBusSubClass,
+};
+use thiserror::Error;
+use vm_allocator::{AddressAllocator, SystemAllocator};
+use vm_device::interrupt::{
+    InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
+};
+use vm_device::{BusDevice, Resource};
+use vm_memory::{Address, GuestAddress, GuestMemoryAtomic};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+
+use super::{GuestMemoryMmap, XhciController, XhciInterrupt, XHCI_MAX_INTRS};
+use crate::usb::hid::SharedUsbHidDevice;
+
+const XHCI_BAR0_IDX: usize = 0;
+
+// Red Hat / QEMU xHCI (qemu-xhci), recognised by all mainstream xHCI drivers.
+const XHCI_VENDOR_ID: u16 = 0x1b36;
+const XHCI_DEVICE_ID: u16 = 0x000d;
+const XHCI_REVISION_ID: u8 = 0x01;
+
+/// Size of the register BAR
+const XHCI_BAR0_SIZE: u64 = 0x4000;
+
+const XHCI_CAP_LENGTH: u64 = super::regs::XHCI_CAP_LENGTH as u64;
+const XHCI_OP_REGS_END: u64 = 0x1000;
+const XHCI_DOORBELL_OFFSET: u64 = 0x1000;
+const XHCI_DOORBELL_END: u64 = 0x2000;
+const XHCI_RUNTIME_OFFSET: u64 = 0x2000;
+const XHCI_RUNTIME_END: u64 = 0x3000;
+const XHCI_MSIX_TABLE_OFFSET: u64 = 0x3000;
+const XHCI_MSIX_TABLE_END: u64 = 0x3800;
+const XHCI_MSIX_PBA_OFFSET: u64 = 0x3800;
+const XHCI_MSIX_PBA_END: u64 = 0x4000;
+
+/// Port the emulated HID keyboard is attached to
+pub const XHCI_KEYBOARD_PORT: u8 = 0;
+/// Port the emulated HID mouse is attached to
+pub const XHCI_MOUSE_PORT: u8 = 1;
+/// Port the emulated HID tablet is attached to
+pub const XHCI_TABLET_PORT: u8 = 2;
+
+#[derive(Debug, Error)]
+pub enum XhciPciError {
+    #[error("Failed to retrieve PciConfigurationState: {0}")]
+    RetrievePciConfigurationState(#[source] anyhow::Error),
+    #[error("Failed to retrieve MsixConfigState: {0}")]
+    RetrieveMsixConfigState(#[source] anyhow::Error),
+    #[error("Failed creating MSI interrupt group: {0}")]
+    CreateInterruptGroup(#[source] std::io::Error),
+    #[error("Failed creating MSI-X configuration: {0}")]
+    CreateMsixConfig(#[source] anyhow::Error),
+    #[error("Failed to set up MSI-X capability: {0}")]
+    CapabilitiesSetup(#[source] PciDeviceError),
+    #[error("Failed to attach USB device: {0}")]
+    AttachDevice(#[source] super::XhciError),
+}
+
+/// xHCI programming interface
+#[derive(Copy, Clone)]
+pub struct XhciProgrammingInterface;
+
+impl PciProgrammingInterface for XhciProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        0x30
+    }
+}
+
+/// MSI-X signalling of the interrupters
+struct XhciMsixInterrupt {
+    msix_config: Arc<Mutex<MsixConfig>>,
+    interrupt_source_group: Arc<dyn InterruptSourceGroup>,
+}
+
+impl XhciInterrupt for XhciMsixInterrupt {
+    fn trigger(&self, interrupter: usize) {
+        let vector = interrupter as u16;
+        let config = &mut self.msix_config.lock().unwrap();
+        if !config.enabled() {
+            return;
+        }
+        let entry = &config.table_entries[vector as usize];
+        // Masked vectors only latch the pending bit, the guest picks the
+        // interrupt up once it unmasks the entry.
+        if config.masked() || entry.masked() {
+            config.set_pba_bit(vector, false);
+            return;
+        }
+
+        if let Err(e) = self
+            .interrupt_source_group
+            .trigger(vector as InterruptIndex)
+        {
+            error!("Failed to trigger xHCI interrupter {interrupter}: {e:?}");
+        }
+    }
+}
+
+/// xHCI host controller exposed as a PCI device
+pub struct XhciPciDevice {
+    id: String,
+
+    // PCI configuration registers.
+    configuration: PciConfiguration,
+    bar_regions: Vec<PciBarConfiguration>,
+
+    // MSI-X vectors, one per interrupter.
+    msix_config: Arc<Mutex<MsixConfig>>,
+
+    // Shared with the HID devices, which notify it of their reports.
+    controller: Arc<Mutex<XhciController>>,
+    keyboard: Option<SharedUsbHidDevice>,
+    mouse: Option<SharedUsbHidDevice>,
+    tablet: Option<SharedUsbHidDevice>,
+}
+
+impl XhciPciDevice {
+    pub fn new(
+        id: String,
+        pci_device_bdf: u32,
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_manager: &dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>,
+        snapshot: Option<&Snapshot>,
+    ) -> Result<Self, XhciPciError> {
+        let pci_configuration_state = vm_migration::state_from_id(snapshot, PCI_CONFIGURATION_ID)
+            .map_err(|e| {
+                XhciPciError::RetrievePciConfigurationState(anyhow!(
+                    "Failed to get PciConfigurationState from Snapshot: {e}",
+                ))
+            })?;
+
+        let msix_state = vm_migration::state_from_id(snapshot, pci::MSIX_CONFIG_ID).map_err(|e| {
+            XhciPciError::RetrieveMsixConfigState(anyhow!(
+                "Failed to get MsixConfigState from Snapshot: {e}"
+            ))
+        })?;
+
+        let msix_num = XHCI_MAX_INTRS as u16;
+        let interrupt_source_group = interrupt_manager
+            .create_group(MsiIrqGroupConfig {
+                base: 0,
+                count: msix_num as InterruptIndex,
+            })
+            .map_err(XhciPciError::CreateInterruptGroup)?;
+
+        let msix_config = Arc::new(Mutex::new(
+            MsixConfig::new(
+                msix_num,
+                interrupt_source_group.clone(),
+                pci_device_bdf,
+                msix_state,
+            )
+            .map_err(|e| XhciPciError::CreateMsixConfig(anyhow!("{e}")))?,
+        ));
+
+        let mut configuration = PciConfiguration::new(
+            XHCI_VENDOR_ID,
+            XHCI_DEVICE_ID,
+            XHCI_REVISION_ID,
+            PciClassCode::SerialBusController,
+            &PciSerialBusSubClass::Usb,
+            Some(&XhciProgrammingInterface),
+            PciHeaderType::Device,
+            0,
+            0,
+            Some(msix_config.clone()),
+            pci_configuration_state,
+        );
+
+        let msix_cap = MsixCap::new(
+            XHCI_BAR0_IDX as u8,
+            msix_num,
+            XHCI_MSIX_TABLE_OFFSET as u32,
+            XHCI_BAR0_IDX as u8,
+            XHCI_MSIX_PBA_OFFSET as u32,
+        );
+        configuration
+            .add_capability(&msix_cap)
+            .map_err(|e| XhciPciError::CapabilitiesSetup(PciDeviceError::CapabilitiesSetup(e)))?;
+
+        let mut controller = XhciController::new();
+        controller.set_memory(memory);
+        controller.set_interrupt(Arc::new(XhciMsixInterrupt {
+            msix_config: msix_config.clone(),
+            interrupt_source_group,
+        }));
+
+        Ok(XhciPciDevice {
+            id,
+            configuration,
+            bar_regions: vec![],
+            msix_config,
+            controller: Arc::new(Mutex::new(controller)),
+            keyboard: None,
+            mouse: None,
+            tablet: None,
+        })
+    }
+
+    /// Attach the HID keyboard, mouse and tablet handed out to the input
+    /// manager
+    pub fn attach_hid_devices(
+        &mut self,
+        keyboard: Option<SharedUsbHidDevice>,
+        mouse: Option<SharedUsbHidDevice>,
+        tablet: Option<SharedUsbHidDevice>,
+    ) -> Result<(), XhciPciError> {
+        if let Some(keyboard) = keyboard {
+            self.attach_hid_device(XHCI_KEYBOARD_PORT, &keyboard)?;
+            self.keyboard = Some(keyboard);
+        }
+        if let Some(mouse) = mouse {
+            self.attach_hid_device(XHCI_MOUSE_PORT, &mouse)?;
+            self.mouse = Some(mouse);
+        }
+        if let Some(tablet) = tablet {
+            self.attach_hid_device(XHCI_TABLET_PORT, &tablet)?;
+            self.tablet = Some(tablet);
+        }
+        Ok(())
+    }
+
+    /// Attach a HID device to a port, the controller hands its reports to
+    /// the guest as they get queued
+    fn attach_hid_device(&self, port: u8, device: &SharedUsbHidDevice) -> Result<(), XhciPciError> {
+        self.controller
+            .lock()
+            .unwrap()
+            .attach_device(port, device.clone())
+            .map_err(XhciPciError::AttachDevice)?;
+
+        // The device outlives the controller it is attached to
+        let controller = Arc::downgrade(&self.controller);
+        device
+            .lock()
+            .unwrap()
+            .set_report_notifier(Arc::new(move || {
+                if let Some(controller) = controller.upgrade() {
+                    controller.lock().unwrap().port_data_ready(port);
+                }
+            }));
+        Ok(())
+    }
+
+    /// Shared HID keyboard handle, if one is attached
+    pub fn keyboard(&self) -> Option<SharedUsbHidDevice> {
+        self.keyboard.clone()
+    }
+
+    /// Shared HID mouse handle, if one is attached
+    pub fn mouse(&self) -> Option<SharedUsbHidDevice> {
+        self.mouse.clone()
+    }
+
+    /// Shared HID tablet handle, if one is attached
+    pub fn tablet(&self) -> Option<SharedUsbHidDevice> {
+        self.tablet.clone()
+    }
+
+    /// Get the underlying controller
+    pub fn controller(&self) -> Arc<Mutex<XhciController>> {
+        self.controller.clone()
+    }
+
+    fn reg_bar_addr(&self) -> u64 {
+        self.configuration.get_bar_addr(XHCI_BAR0_IDX)
+    }
+
+    fn read_reg(&self, offset: u64) -> u32 {
+        let controller = self.controller.lock().unwrap();
+        match offset {
+            0..XHCI_CAP_LENGTH => controller.capability_registers().read(offset),
+            XHCI_CAP_LENGTH..XHCI_OP_REGS_END => {
+                controller.read_operational(offset - XHCI_CAP_LENGTH)
+            }
+            // Doorbells always read as zero
+            XHCI_DOORBELL_OFFSET..XHCI_DOORBELL_END => 0,
+            XHCI_RUNTIME_OFFSET..XHCI_RUNTIME_END => {
+                controller.read_runtime(offset - XHCI_RUNTIME_OFFSET)
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, value: u32) {
+        let mut controller = self.controller.lock().unwrap();
+        match offset {
+            0..XHCI_CAP_LENGTH => warn!("Ignoring write to xHCI capability register 0x{offset:x}"),
+            XHCI_CAP_LENGTH..XHCI_OP_REGS_END => {
+                controller.write_operational(offset - XHCI_CAP_LENGTH, value);
+            }
+            XHCI_DOORBELL_OFFSET..XHCI_DOORBELL_END => {
+                let slot_id = ((offset - XHCI_DOORBELL_OFFSET) / 4) as u8;
+                controller.ring_doorbell(slot_id, (value & 0xff) as u8);
+            }
+            XHCI_RUNTIME_OFFSET..XHCI_RUNTIME_END => {
+                controller.write_runtime(offset - XHCI_RUNTIME_OFFSET, value);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl BusDevice for XhciPciDevice {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data);
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl PciDevice for XhciPciDevice {
+    fn allocate_bars(
+        &mut self,
+        _allocator: &Arc<Mutex<SystemAllocator>>,
+        mmio32_allocator: &mut AddressAllocator,
+        _mmio64_allocator: &mut AddressAllocator,
+        resources: Option<Vec<Resource>>,
+    ) -> std::result::Result<Vec<PciBarConfiguration>, PciDeviceError> {
+        let mut bar0_addr = None;
+
+        let restoring = resources.is_some();
+        if let Some(resources) = resources {
+            for resource in resources {
+                match resource {
+                    Resource::PciBar { index, base, .. } => match index {
+                        XHCI_BAR0_IDX => {
+                            bar0_addr = Some(GuestAddress(base));
+                        }
+                        _ => {
+                            error!("Unexpected pci bar index {index}");
+                        }
+                    },
+                    _ => {
+                        error!("Unexpected resource {resource:?}");
+                    }
+                }
+            }
+            if bar0_addr.is_none() {
+                return Err(PciDeviceError::MissingResource);
+            }
+        }
+
+        // BAR0 holds the controller registers and the MSI-X structures
+        let bar0_addr = mmio32_allocator
+            .allocate(bar0_addr, XHCI_BAR0_SIZE, Some(XHCI_BAR0_SIZE))
+            .ok_or(PciDeviceError::IoAllocationFailed(XHCI_BAR0_SIZE))?;
+        debug!("xhci bar0 address 0x{:x}", bar0_addr.0);
+
+        let bar0 = PciBarConfiguration::default()
+            .set_index(XHCI_BAR0_IDX)
+            .set_address(bar0_addr.raw_value())
+            .set_size(XHCI_BAR0_SIZE)
+            .set_region_type(PciBarRegionType::Memory32BitRegion)
+            .set_prefetchable(PciBarPrefetchable::NotPrefetchable);
+
+        if !restoring {
+            self.configuration
+                .add_pci_bar(&bar0)
+                .map_err(|e| PciDeviceError::IoRegistrationFailed(bar0_addr.raw_value(), e))?;
+        }
+
+        self.bar_regions = vec![bar0];
+
+        Ok(vec![bar0])
+    }
+
+    fn free_bars(
+        &mut self,
+        _allocator: &mut SystemAllocator,
+        mmio32_allocator: &mut AddressAllocator,
+        _mmio64_allocator: &mut AddressAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        for bar in self.bar_regions.drain(..) {
+            mmio32_allocator.free(GuestAddress(bar.addr()), bar.size());
+        }
+        Ok(())
+    }
+
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> (Vec<BarReprogrammingParams>, Option<Arc<Barrier>>) {
+        (
+            self.configuration
+                .write_config_register(reg_idx, offset, data),
+            None,
+        )
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        if base != self.reg_bar_addr() {
+            warn!("Invalid xhci bar base: 0x{base:x}");
+            return;
+        }
+
+        match offset {
+            XHCI_MSIX_TABLE_OFFSET..XHCI_MSIX_TABLE_END => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .read_table(offset - XHCI_MSIX_TABLE_OFFSET, data);
+            }
+            XHCI_MSIX_PBA_OFFSET..XHCI_MSIX_PBA_END => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .read_pba(offset - XHCI_MSIX_PBA_OFFSET, data);
+            }
+            _ => match data.len() {
+                8 => {
+                    LittleEndian::write_u32(&mut data[..4], self.read_reg(offset));
+                    LittleEndian::write_u32(&mut data[4..], self.read_reg(offset + 4));
+                }
+                4 => LittleEndian::write_u32(data, self.read_reg(offset)),
+                len => {
+                    // Sub-dword access: pull the bytes out of the
+                    // containing dword.
+                    let aligned = offset & !0x3;
+                    let shift = (offset - aligned) * 8;
+                    let value = self.read_reg(aligned) >> shift;
+                    for (i, byte) in data.iter_mut().enumerate().take(len) {
+                        *byte = (value >> (i * 8)) as u8;
+                    }
+                }
+            },
+        }
+    }
+
+    fn write_bar(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if base != self.reg_bar_addr() {
+            warn!("Invalid xhci bar base: 0x{base:x}");
+            return None;
+        }
+
+        match offset {
+            XHCI_MSIX_TABLE_OFFSET..XHCI_MSIX_TABLE_END => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .write_table(offset - XHCI_MSIX_TABLE_OFFSET, data);
+            }
+            XHCI_MSIX_PBA_OFFSET..XHCI_MSIX_PBA_END => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .write_pba(offset - XHCI_MSIX_PBA_OFFSET, data);
+            }
+            _ => match data.len() {
+                8 => {
+                    self.write_reg(offset, LittleEndian::read_u32(&data[..4]));
+                    self.write_reg(offset + 4, LittleEndian::read_u32(&data[4..]));
+                }
+                4 => self.write_reg(offset, LittleEndian::read_u32(data)),
+                len => {
+                    warn!("Unsupported {len} byte write to xhci register 0x{offset:x}");
+                }
+            },
+        }
+
+        None
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> result::Result<(), std::io::Error> {
+        for bar in self.bar_regions.iter_mut() {
+            if bar.addr() == old_base {
+                *bar = bar.set_address(new_base);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(self.id.clone())
+    }
+}
+
+impl Pausable for XhciPciDevice {}
+
+impl Snapshottable for XhciPciDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    // Only the PCI and MSI-X configuration are preserved, the guest driver
+    // re-enumerates the bus after restore.
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::default();
+
+        snapshot.add_snapshot(self.configuration.id(), self.configuration.snapshot()?);
+
+        let mut msix_config = self.msix_config.lock().unwrap();
+        snapshot.add_snapshot(msix_config.id(), msix_config.snapshot()?);
+
+        Ok(snapshot)
+    }
+}
+
+impl Transportable for XhciPciDevice {}
+
+impl Migratable for XhciPciDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::hid::{UsbHidDevice, send_report};
+    use crate::usb::xhci::rings::{CompletionCode, Trb, TrbType};
+    use vm_device::interrupt::InterruptSourceConfig;
+    use vm_memory::Bytes;
+    use vmm_sys_util::eventfd::{EFD_NONBLOCK, EventFd};
+
+    // Guest memory layout of the rings and contexts
+    const ERST: u64 = 0x1000;
+    const EVENT_RING: u64 = 0x2000;
+    const COMMAND_RING: u64 = 0x3000;
+    const DCBAA: u64 = 0x4000;
+    const OUTPUT_CONTEXT: u64 = 0x5000;
+    const INPUT_CONTEXT: u64 = 0x6000;
+    const TRANSFER_RING: u64 = 0x7000;
+    const REPORT_BUFFER: u64 = 0x8000;
+
+    // Interrupter 0 registers in BAR0
+    const IMAN: u64 = 0x2020;
+    const ERSTSZ: u64 = 0x2028;
+    const ERSTBA: u64 = 0x2030;
+    const ERDP: u64 = 0x2038;
+
+    struct TestInterrupt {
+        event_fd: EventFd,
+    }
+
+    impl InterruptSourceGroup for TestInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> std::result::Result<(), std::io::Error> {
+            self.event_fd.write(1)
+        }
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+            _masked: bool,
+            _set_gsi: bool,
+        ) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+        fn set_gsi(&self) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            Some(self.event_fd.try_clone().unwrap())
+        }
+    }
+
+    struct TestInterruptManager {
+        event_fd: EventFd,
+    }
+
+    impl InterruptManager for TestInterruptManager {
+        type GroupConfig = MsiIrqGroupConfig;
+
+        fn create_group(
+            &self,
+            _config: Self::GroupConfig,
+        ) -> std::result::Result<Arc<dyn InterruptSourceGroup>, std::io::Error> {
+            Ok(Arc::new(TestInterrupt {
+                event_fd: self.event_fd.try_clone().unwrap(),
+            }))
+        }
+        fn destroy_group(
+            &self,
+            _group: Arc<dyn InterruptSourceGroup>,
+        ) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    struct TestController {
+        device: XhciPciDevice,
+        mem: GuestMemoryMmap,
+        event_fd: EventFd,
+    }
+
+    impl TestController {
+        /// Bring the controller up the way a guest driver does, with an
+        /// event ring of `event_trbs` TRBs and MSI-X vector 0 unmasked
+        fn new(event_trbs: u32) -> Self {
+            let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+            let event_fd = EventFd::new(EFD_NONBLOCK).unwrap();
+            let interrupt_manager = TestInterruptManager {
+                event_fd: event_fd.try_clone().unwrap(),
+            };
+            let mut device = XhciPciDevice::new(
+                "_xhci0".to_string(),
+                0,
+                GuestMemoryAtomic::new(mem.clone()),
+                &interrupt_manager,
+                None,
+            )
+            .unwrap();
+
+            device.msix_config.lock().unwrap().set_msg_ctl(1 << 15);
+            device.write_bar(0, XHCI_MSIX_TABLE_OFFSET + 0xc, &0u32.to_le_bytes());
+
+            let mut controller = TestController {
+                device,
+                mem,
+                event_fd,
+            };
+            controller.write_mem(ERST, &EVENT_RING.to_le_bytes());
+            controller.write_mem(ERST + 8, &event_trbs.to_le_bytes());
+            controller.write_reg(IMAN, 0x2);
+            controller.write_reg(ERSTSZ, 1);
+            controller.write_reg(ERDP, EVENT_RING as u32);
+            controller.write_reg(ERSTBA, ERST as u32);
+            controller.write_reg(ERSTBA + 4, 0);
+
+            // CRCR with RCS set, DCBAAP, then USBCMD.RS | USBCMD.INTE
+            controller.write_reg(0x58, COMMAND_RING as u32 | 1);
+            controller.write_reg(0x5C, 0);
+            controller.write_reg(0x70, DCBAA as u32);
+            controller.write_reg(0x74, 0);
+            controller.write_reg(0x40, 0x5);
+            controller
+        }
+
+        fn write_reg(&mut self, offset: u64, value: u32) {
+            self.device.write_bar(0, offset, &value.to_le_bytes());
+        }
+
+        fn read_reg(&mut self, offset: u64) -> u32 {
+            let mut data = [0u8; 4];
+            self.device.read_bar(0, offset, &mut data);
+            u32::from_le_bytes(data)
+        }
+
+        fn write_mem(&self, addr: u64, data: &[u8]) {
+            self.mem.write_slice(data, GuestAddress(addr)).unwrap();
+        }
+
+        fn write_trb(
+            &self,
+            addr: u64,
+            trb_type: TrbType,
+            parameter: u64,
+            control: u32,
+            cycle: bool,
+        ) {
+            let mut trb = Trb::new(parameter, 0, control);
+            trb.set_trb_type(trb_type);
+            trb.set_cycle_bit(cycle);
+            assert!(trb.write_to(&self.mem, addr));
+        }
+
+        fn event(&self, index: u64) -> Trb {
+            Trb::read_from(&self.mem, EVENT_RING + index * 16).unwrap()
+        }
+
+        fn interrupted(&self) -> bool {
+            self.event_fd.read().is_ok()
+        }
+
+        /// Move ERDP past the events handled, clearing IMAN.IP and ERDP.EHB
+        /// like the interrupt handler of a guest driver
+        fn ack_events(&mut self, dequeue: u64) {
+            self.write_reg(IMAN, 0x3);
+            self.write_reg(ERDP, dequeue as u32 | 0x8);
+            self.write_reg(ERDP + 4, 0);
+        }
+    }
+
+    fn assert_command_completion(event: &Trb, command: u64, slot_id: u8, cycle: bool) {
+        assert_eq!(event.trb_type(), Some(TrbType::CommandCompletion));
+        assert_eq!(event.parameter, command);
+        assert_eq!(event.completion_code(), CompletionCode::Success as u8);
+        assert_eq!(event.slot_id(), slot_id);
+        assert_eq!(event.cycle_bit(), cycle);
+    }
+
+    #[test]
+    fn test_command_ring_events() {
+        let mut xhci = TestController::new(4);
+
+        // A link back to the start of the ring toggles the cycle state
+        xhci.write_trb(COMMAND_RING, TrbType::Noop, 0, 0, true);
+        xhci.write_trb(COMMAND_RING + 0x10, TrbType::EnableSlot, 0, 0, true);
+        xhci.write_trb(
+            COMMAND_RING + 0x40,
+            TrbType::Link,
+            COMMAND_RING,
+            1 << 1,
+            true,
+        );
+        xhci.write_reg(0x1000, 0);
+
+        assert_command_completion(&xhci.event(0), COMMAND_RING, 0, true);
+        assert_command_completion(&xhci.event(1), COMMAND_RING + 0x10, 1, true);
+        assert_eq!(xhci.event(2).control, 0);
+        // One interrupt for both events, with IMAN.IP, USBSTS.EINT and
+        // CRCR.CRR set
+        assert!(xhci.interrupted());
+        assert!(!xhci.interrupted());
+        assert_eq!(xhci.read_reg(IMAN) & 0x1, 0x1);
+        assert_eq!(xhci.read_reg(0x44) & (1 << 3), 1 << 3);
+        assert_eq!(xhci.read_reg(0x58) & (1 << 3), 1 << 3);
+
+        // Consuming every event leaves nothing to signal
+        xhci.ack_events(EVENT_RING + 0x20);
+        assert_eq!(xhci.read_reg(IMAN) & 0x1, 0);
+        assert!(!xhci.interrupted());
+
+        // The third event fills the ring, and wraps around with the cycle
+        // bit toggled
+        xhci.write_trb(COMMAND_RING + 0x20, TrbType::Noop, 0, 0, true);
+        xhci.write_trb(COMMAND_RING + 0x30, TrbType::Noop, 0, 0, true);
+        xhci.write_trb(COMMAND_RING, TrbType::Noop, 0, 0, false);
+        xhci.write_reg(0x1000, 0);
+
+        assert_command_completion(&xhci.event(2), COMMAND_RING + 0x20, 0, true);
+        assert_command_completion(&xhci.event(3), COMMAND_RING + 0x30, 0, true);
+        assert_command_completion(&xhci.event(0), COMMAND_RING, 0, false);
+        assert!(xhci.interrupted());
+    }
+
+    #[test]
+    fn test_hid_report_transfer_event() {
+        let mut xhci = TestController::new(16);
+        let keyboard = Arc::new(Mutex::new(UsbHidDevice::new_keyboard()));
+        xhci.device
+            .attach_hid_devices(Some(keyboard.clone()), None, None)
+            .unwrap();
+        xhci.write_mem(DCBAA + 8, &OUTPUT_CONTEXT.to_le_bytes());
+
+        // Attaching to the running controller reports a connection on port 1
+        let event = xhci.event(0);
+        assert_eq!(event.trb_type(), Some(TrbType::PortStatusChange));
+        assert_eq!(event.parameter >> 24, 1);
+
+        // Address the keyboard on root hub port 1, with the slot and EP0
+        // contexts added
+        xhci.write_mem(INPUT_CONTEXT + 4, &0x3u32.to_le_bytes());
+        xhci.write_mem(INPUT_CONTEXT + 0x20, &(1u32 << 27).to_le_bytes());
+        xhci.write_mem(INPUT_CONTEXT + 0x24, &(1u32 << 16).to_le_bytes());
+        xhci.write_mem(
+            INPUT_CONTEXT + 0x44,
+            &((8u32 << 16) | (4 << 3)).to_le_bytes(),
+        );
+        xhci.write_mem(INPUT_CONTEXT + 0x48, &0x7801u32.to_le_bytes());
+        xhci.write_trb(COMMAND_RING, TrbType::EnableSlot, 0, 0, true);
+        xhci.write_trb(
+            COMMAND_RING + 0x10,
+            TrbType::AddressDevice,
+            INPUT_CONTEXT,
+            1 << 24,
+            true,
+        );
+        xhci.write_reg(0x1000, 0);
+        assert_command_completion(&xhci.event(1), COMMAND_RING, 1, true);
+        assert_command_completion(&xhci.event(2), COMMAND_RING + 0x10, 1, true);
+
+        // Add the interrupt IN endpoint (DCI 3)
+        xhci.write_mem(INPUT_CONTEXT + 4, &0x9u32.to_le_bytes());
+        xhci.write_mem(INPUT_CONTEXT + 0x20, &(3u32 << 27).to_le_bytes());
+        xhci.write_mem(
+            INPUT_CONTEXT + 0x84,
+            &((8u32 << 16) | (7 << 3)).to_le_bytes(),
+        );
+        xhci.write_mem(
+            INPUT_CONTEXT + 0x88,
+            &(TRANSFER_RING as u32 | 1).to_le_bytes(),
+        );
+        xhci.write_trb(
+            COMMAND_RING + 0x20,
+            TrbType::ConfigureEndpoint,
+            INPUT_CONTEXT,
+            1 << 24,
+            true,
+        );
+        xhci.write_reg(0x1000, 0);
+        assert_command_completion(&xhci.event(3), COMMAND_RING + 0x20, 1, true);
+        assert!(xhci.interrupted());
+        xhci.ack_events(EVENT_RING + 0x40);
+
+        // With no report queued the transfer waits on the ring
+        let mut normal = Trb::new(REPORT_BUFFER, 8, 1 << 5);
+        normal.set_trb_type(TrbType::Normal);
+        normal.set_cycle_bit(true);
+        assert!(normal.write_to(&xhci.mem, TRANSFER_RING));
+        xhci.write_reg(0x1000 + 4, 3);
+        assert_eq!(xhci.event(4).control, 0);
+        assert!(!xhci.interrupted());
+
+        // Queueing a report completes it and interrupts the guest
+        let report = vec![0, 0, 0x04, 0, 0, 0, 0, 0];
+        send_report(&keyboard, report.clone());
+        let event = xhci.event(4);
+        assert_eq!(event.trb_type(), Some(TrbType::TransferEvent));
+        assert_eq!(event.parameter, TRANSFER_RING);
+        assert_eq!(event.completion_code(), CompletionCode::Success as u8);
+        assert_eq!(event.slot_id(), 1);
+        assert_eq!(event.endpoint_id(), 3);
+        assert!(event.cycle_bit());
+        assert!(xhci.interrupted());
+
+        let mut data = [0u8; 8];
+        xhci.mem
+            .read_slice(&mut data, GuestAddress(REPORT_BUFFER))
+            .unwrap();
+        assert_eq!(data.to_vec(), report);
+    }
+}
@@ -11,12 +11,15 @@
 //! - Doorbell Registers
 
 use super::XhciState;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
 // ============================================================================
 // Capability Registers (Offset 0x00 - 0x1F)
 // ============================================================================
 
+/// CAPLENGTH: offset of the operational registers from the register base
+pub const XHCI_CAP_LENGTH: u8 = 0x40;
+
 /// xHCI Capability Registers
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -47,7 +50,7 @@ impl CapabilityRegisters {
             hcsparams1: HcsParams1::new(max_slots, max_intrs, max_ports),
             hcsparams2: HcsParams2::default(),
             hcsparams3: HcsParams3::default(),
-            hccparams1: HccParams1::default(),
+            hccparams1: HccParams1(HCCPARAMS1_AC64),
             dboff: 0x1000, // Doorbell registers start at 4KB offset
             rtsoff: 0x2000, // Runtime registers start at 8KB offset
             hccparams2: 0,
@@ -92,7 +95,7 @@ impl CapabilityRegisters {
     /// Read register at offset
     pub fn read(&self, offset: u64) -> u32 {
         match offset {
-            0x00 => self.hciversion as u32,
+            0x00 => XHCI_CAP_LENGTH as u32 | ((self.hciversion as u32) << 16),
             0x02 => (self.hciversion >> 8) as u32 | ((self.hciversion as u32) << 16),
             0x04 => self.hcsparams1(),
             0x08 => self.hcsparams2(),
@@ -183,6 +186,9 @@ impl From<HcsParams3> for u32 {
     }
 }
 
+/// HCCPARAMS1 AC64 bit: the controller takes 64-bit addresses
+const HCCPARAMS1_AC64: u32 = 1 << 0;
+
 /// HCCPARAMS1: Capability Parameters 1
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, Default)]
@@ -249,12 +255,12 @@ pub struct OperationalRegisters {
     usbsts: AtomicU32,
     /// PGSZ: Page Size Register (0x08)
     pgsz: AtomicU32,
-    /// DNCTRL: Device Notification Control (0x0C)
+    /// DNCTRL: Device Notification Control (0x14)
     dnctrl: AtomicU32,
-    /// CRCR: Command Ring Control Register (0x10)
-    crcr: AtomicU32,
+    /// CRCR: Command Ring Control Register (0x18), dequeue pointer and RCS
+    crcr: AtomicU64,
     /// DCBAAP: Device Context Base Address Array Pointer (0x30)
-    dcbaap: AtomicU32,
+    dcbaap: AtomicU64,
     /// CONFIG: Configure Register (0x38)
     config: AtomicU32,
     /// Port Status and Control Registers (0x400+)
@@ -266,10 +272,10 @@ impl Default for OperationalRegisters {
         Self {
             usbcmd: AtomicU32::new(0),
             usbsts: AtomicU32::new(USBSTS_HCH), // Controller halted
-            pgsz: AtomicU32::new(0x1000),       // 4KB page size
+            pgsz: AtomicU32::new(0x1),          // 4KB page size
             dnctrl: AtomicU32::new(0),
-            crcr: AtomicU32::new(0),
-            dcbaap: AtomicU32::new(0),
+            crcr: AtomicU64::new(0),
+            dcbaap: AtomicU64::new(0),
             config: AtomicU32::new(0),
             ports: (0..super::XHCI_MAX_PORTS).map(|_| PortRegister::default()).collect(),
         }
@@ -297,6 +303,28 @@ pub const USBSTS_SRE: u32 = 1 << 10;     // Save/Restore Error
 pub const USBSTS_CNR: u32 = 1 << 11;     // Controller Not Ready
 pub const USBSTS_HCE: u32 = 1 << 12;     // Host Controller Error
 
+/// CRCR register bits
+pub const CRCR_RCS: u64 = 1 << 0;        // Ring Cycle State
+pub const CRCR_CS: u32 = 1 << 1;         // Command Stop
+pub const CRCR_CA: u32 = 1 << 2;         // Command Abort
+pub const CRCR_CRR: u32 = 1 << 3;        // Command Ring Running
+pub const CRCR_PTR_MASK: u64 = !0x3F;    // Command Ring Pointer
+
+/// Operational register write the controller has to act upon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationalWrite {
+    /// USBCMD.HCRST, the controller goes back to its initial state
+    Reset,
+    /// USBCMD.RS cleared, the controller halted
+    Halted,
+    /// CRCR written, with the new dequeue pointer of the command ring
+    CommandRing,
+    /// CRCR.CS or CRCR.CA set, the command ring has to stop
+    CommandRingStop,
+    /// PORTSC reset of a port, by its 1-based port ID
+    PortReset(u8),
+}
+
 impl OperationalRegisters {
     /// Read register at offset
     pub fn read(&self, offset: u64) -> u32 {
@@ -304,9 +332,12 @@ impl OperationalRegisters {
             0x00 => self.usbcmd.load(Ordering::Acquire),
             0x04 => self.usbsts.load(Ordering::Acquire),
             0x08 => self.pgsz.load(Ordering::Acquire),
-            0x0C => self.dnctrl.load(Ordering::Acquire),
-            0x10..=0x17 => self.crcr.load(Ordering::Acquire),
-            0x30..=0x37 => self.dcbaap.load(Ordering::Acquire),
+            0x14 => self.dnctrl.load(Ordering::Acquire),
+            // The command ring pointer reads as zero, CRR is up to the
+            // controller
+            0x18 | 0x1C => 0,
+            0x30 => self.dcbaap.load(Ordering::Acquire) as u32,
+            0x34 => (self.dcbaap.load(Ordering::Acquire) >> 32) as u32,
             0x38 => self.config.load(Ordering::Acquire),
             0x400.. => {
                 let port_idx = ((offset - 0x400) / 0x10) as usize;
@@ -321,68 +352,125 @@ impl OperationalRegisters {
         }
     }
 
-    /// Write register at offset
-    pub fn write(&mut self, offset: u64, value: u32, state: &mut XhciState) {
+    /// Write register at offset, returns what the controller has to act
+    /// upon
+    pub fn write(
+        &mut self,
+        offset: u64,
+        value: u32,
+        state: &mut XhciState,
+    ) -> Option<OperationalWrite> {
         match offset {
             0x00 => {
                 // USBCMD
-                self.handle_usbcmd(value, state);
+                return self.handle_usbcmd(value, state);
             }
             0x04 => {
                 // USBSTS - write 1 to clear
-                let current = self.usbsts.load(Ordering::Acquire);
                 let clear_bits = value & (USBSTS_EINT | USBSTS_PCD | USBSTS_SRE | USBSTS_HCE);
-                self.usbsts.store(current & !clear_bits, Ordering::Release);
+                self.usbsts.fetch_and(!clear_bits, Ordering::AcqRel);
+            }
+            0x14 => self.dnctrl.store(value, Ordering::Release),
+            0x18 => {
+                if value & (CRCR_CS | CRCR_CA) != 0 {
+                    return Some(OperationalWrite::CommandRingStop);
+                }
+                let current = self.crcr.load(Ordering::Acquire);
+                let low = value as u64 & (CRCR_PTR_MASK | CRCR_RCS) & 0xFFFF_FFFF;
+                self.crcr
+                    .store((current & !0xFFFF_FFFF) | low, Ordering::Release);
+                return Some(OperationalWrite::CommandRing);
             }
-            0x0C => self.dnctrl.store(value, Ordering::Release),
-            0x10..=0x17 => {
+            0x1C => {
                 let current = self.crcr.load(Ordering::Acquire);
-                // Only update low bits, preserve high address bits
-                let new_val = (current & !0xFF) | (value & 0xFF);
-                self.crcr.store(new_val, Ordering::Release);
+                self.crcr.store(
+                    (current & 0xFFFF_FFFF) | ((value as u64) << 32),
+                    Ordering::Release,
+                );
+                return Some(OperationalWrite::CommandRing);
+            }
+            0x30 => {
+                let current = self.dcbaap.load(Ordering::Acquire);
+                self.dcbaap.store(
+                    (current & !0xFFFF_FFFF) | (value as u64 & !0x3F),
+                    Ordering::Release,
+                );
+            }
+            0x34 => {
+                let current = self.dcbaap.load(Ordering::Acquire);
+                self.dcbaap.store(
+                    (current & 0xFFFF_FFFF) | ((value as u64) << 32),
+                    Ordering::Release,
+                );
             }
-            0x30..=0x37 => self.dcbaap.store(value, Ordering::Release),
             0x38 => self.config.store(value, Ordering::Release),
             0x400.. => {
                 let port_idx = ((offset - 0x400) / 0x10) as usize;
                 let port_offset = (offset - 0x400) % 0x10;
-                if port_idx < self.ports.len() {
-                    self.ports[port_idx].write(port_offset, value);
+                if port_idx < self.ports.len() && self.ports[port_idx].write(port_offset, value) {
+                    self.usbsts.fetch_or(USBSTS_PCD, Ordering::AcqRel);
+                    return Some(OperationalWrite::PortReset(port_idx as u8 + 1));
                 }
             }
             _ => {}
         }
+        None
     }
 
     /// Handle USBCMD register write
-    fn handle_usbcmd(&mut self, value: u32, state: &mut XhciState) {
+    fn handle_usbcmd(&mut self, value: u32, state: &mut XhciState) -> Option<OperationalWrite> {
         let current = self.usbcmd.load(Ordering::Acquire);
 
-        // Host Controller Reset
+        // Host Controller Reset, which completes at once
         if value & USBCMD_HCRST != 0 {
             *state = XhciState::Reset;
-            // Reset internal state
-            self.usbsts.store(USBSTS_HCH | USBSTS_CNR, Ordering::Release);
+            self.usbcmd.store(0, Ordering::Release);
+            self.usbsts.store(USBSTS_HCH, Ordering::Release);
+            self.dnctrl.store(0, Ordering::Release);
             self.crcr.store(0, Ordering::Release);
             self.dcbaap.store(0, Ordering::Release);
+            self.config.store(0, Ordering::Release);
             *state = XhciState::Halted;
-            return;
+            return Some(OperationalWrite::Reset);
         }
 
+        self.usbcmd.store(value, Ordering::Release);
+
         // Run/Stop
         if (value ^ current) & USBCMD_RS != 0 {
             if value & USBCMD_RS != 0 {
                 // Start controller
                 *state = XhciState::Running;
-                self.usbsts.store(USBSTS_CNR, Ordering::Release);
+                self.usbsts.fetch_and(!USBSTS_HCH, Ordering::AcqRel);
             } else {
                 // Stop controller
                 *state = XhciState::Halted;
-                self.usbsts.store(USBSTS_HCH, Ordering::Release);
+                self.usbsts.fetch_or(USBSTS_HCH, Ordering::AcqRel);
+                return Some(OperationalWrite::Halted);
             }
         }
 
-        self.usbcmd.store(value, Ordering::Release);
+        None
+    }
+
+    /// Get USBCMD
+    pub fn usbcmd(&self) -> u32 {
+        self.usbcmd.load(Ordering::Acquire)
+    }
+
+    /// Set USBSTS.EINT, an interrupter has events for the guest
+    pub fn set_event_interrupt(&self) {
+        self.usbsts.fetch_or(USBSTS_EINT, Ordering::AcqRel);
+    }
+
+    /// Get the command ring dequeue pointer and RCS last written to CRCR
+    pub fn crcr(&self) -> u64 {
+        self.crcr.load(Ordering::Acquire)
+    }
+
+    /// Get the device context base address array pointer
+    pub fn dcbaap(&self) -> u64 {
+        self.dcbaap.load(Ordering::Acquire)
     }
 
     /// Set port connected state
@@ -391,6 +479,7 @@ impl OperationalRegisters {
             return;
         }
         self.ports[port as usize].set_connected(connected);
+        self.usbsts.fetch_or(USBSTS_PCD, Ordering::AcqRel);
     }
 
     /// Check if controller is running
@@ -441,7 +530,9 @@ impl PortRegister {
         }
     }
 
-    fn write(&mut self, offset: u64, value: u32) {
+    /// Returns whether a port reset completed, which the guest learns
+    /// about through a Port Status Change Event
+    fn write(&mut self, offset: u64, value: u32) -> bool {
         match offset {
             0x00 => {
                 // PORTSC - some bits are write-clear, others are RW
@@ -450,23 +541,45 @@ impl PortRegister {
                 // Clear status change bits on write of 1
                 let clear_mask = value & (PORTSC_CSC | PORTSC_PEC | PORTSC_WRC
                     | PORTSC_OCC | PORTSC_PRC | PORTSC_PLC | PORTSC_CEC);
-                let new_val = (current & !clear_mask)
-                    | (value & (PORTSC_PED | PORTSC_PR | PORTSC_PLS_MASK | PORTSC_LWS | PORTSC_PP | PORTSC_WPR));
+                // PED can only be cleared by software, PR and WPR are RW1S
+                let mut new_val = (current & !clear_mask & !PORTSC_PED)
+                    | (current & value & PORTSC_PED)
+                    | (value & PORTSC_PP);
+                if value & PORTSC_LWS != 0 {
+                    new_val = (new_val & !PORTSC_PLS_MASK) | (value & PORTSC_PLS_MASK);
+                }
+
+                // A reset of a connected port completes at once, leaving it
+                // enabled in U0
+                let reset = value & (PORTSC_PR | PORTSC_WPR) != 0 && current & PORTSC_CCS != 0;
+                if reset {
+                    new_val = (new_val & !PORTSC_PLS_MASK) | PORTSC_PED | PORTSC_PRC;
+                    if value & PORTSC_WPR != 0 {
+                        new_val |= PORTSC_WRC;
+                    }
+                }
 
                 self.portsc.store(new_val, Ordering::Release);
+                reset
             }
-            0x04 => self.portpmsc.store(value, Ordering::Release),
-            0x08 => self.portli.store(value, Ordering::Release),
-            _ => {}
+            0x04 => {
+                self.portpmsc.store(value, Ordering::Release);
+                false
+            }
+            0x08 => {
+                self.portli.store(value, Ordering::Release);
+                false
+            }
+            _ => false,
         }
     }
 
     fn set_connected(&mut self, connected: bool) {
         let current = self.portsc.load(Ordering::Acquire);
         let new_val = if connected {
-            current | PORTSC_CCS | PORTSC_CSC | PORTSC_PP | (4 << 10) // Connected, power on, full speed
+            current | PORTSC_CCS | PORTSC_CSC | PORTSC_PP | (1 << 10) // Connected, power on, full speed
         } else {
-            current & !PORTSC_CCS
+            current & !(PORTSC_CCS | PORTSC_PED)
         };
         self.portsc.store(new_val | PORTSC_CSC, Ordering::Release); // Set change bit
     }
@@ -513,20 +626,73 @@ impl RuntimeRegisters {
         }
     }
 
-    /// Write register at offset
-    pub fn write(&mut self, offset: u64, value: u32) {
+    /// Write register at offset, returns what the controller has to act
+    /// upon
+    pub fn write(&mut self, offset: u64, value: u32) -> Option<InterrupterWrite> {
         match offset {
-            0x00 => {} // MFINDEX is read-only
+            0x00 => None, // MFINDEX is read-only
             0x20.. => {
                 let intr_idx = ((offset - 0x20) / 0x20) as usize;
                 let intr_offset = (offset - 0x20) % 0x20;
                 if intr_idx < self.interrupters.len() {
-                    self.interrupters[intr_idx].write(intr_offset, value);
+                    match self.interrupters[intr_idx].write(intr_offset, value) {
+                        0x10 | 0x14 => Some(InterrupterWrite::SegmentTable(intr_idx)),
+                        0x18 | 0x1C => Some(InterrupterWrite::DequeuePointer(intr_idx)),
+                        _ => None,
+                    }
+                } else {
+                    None
                 }
             }
-            _ => {}
+            _ => None,
         }
     }
+
+    /// Get the event ring segment table size and base address of an
+    /// interrupter
+    pub fn segment_table(&self, intr: usize) -> Option<(u32, u64)> {
+        let intr = self.interrupters.get(intr)?;
+        Some((
+            intr.erstsz.load(Ordering::Acquire) & 0xFFFF,
+            intr.erstba.load(Ordering::Acquire),
+        ))
+    }
+
+    /// Get the event ring dequeue pointer of an interrupter
+    pub fn dequeue_pointer(&self, intr: usize) -> Option<u64> {
+        let intr = self.interrupters.get(intr)?;
+        Some(intr.erdp.load(Ordering::Acquire) & ERDP_PTR_MASK)
+    }
+
+    /// Flag an interrupt (IMAN.IP and ERDP.EHB) for the events written to
+    /// the event ring of an interrupter, unless the guest is still handling
+    /// the previous ones. Returns whether the interrupt got flagged.
+    pub fn assert_interrupt(&self, intr: usize) -> bool {
+        let Some(intr) = self.interrupters.get(intr) else {
+            return false;
+        };
+        if intr.erdp.fetch_or(ERDP_EHB, Ordering::AcqRel) & ERDP_EHB != 0 {
+            return false;
+        }
+        intr.iman.fetch_or(IMAN_IP, Ordering::AcqRel);
+        true
+    }
+
+    /// Check if an interrupter has interrupts enabled (IMAN.IE)
+    pub fn interrupt_enabled(&self, intr: usize) -> bool {
+        self.interrupters
+            .get(intr)
+            .is_some_and(|intr| intr.iman.load(Ordering::Acquire) & IMAN_IE != 0)
+    }
+}
+
+/// Interrupter register write the controller has to act upon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterrupterWrite {
+    /// ERSTBA of an interrupter written, its event ring is set up anew
+    SegmentTable(usize),
+    /// ERDP of an interrupter written, the guest consumed events
+    DequeuePointer(usize),
 }
 
 /// Interrupter Register Set
@@ -542,46 +708,77 @@ struct InterrupterRegister {
     /// Reserved (0x0C)
     _reserved1: AtomicU32,
     /// ERSTBA: Event Ring Segment Table Base Address (0x10)
-    erstba: AtomicU32,
-    /// Reserved (0x14)
-    _reserved2: AtomicU32,
+    erstba: AtomicU64,
     /// ERDP: Event Ring Dequeue Pointer (0x18)
-    erdp: AtomicU32,
+    erdp: AtomicU64,
 }
 
 /// IMAN register bits
 const IMAN_IE: u32 = 1 << 1;  // Interrupt Enable
 const IMAN_IP: u32 = 1 << 0;  // Interrupt Pending
 
+/// ERDP register bits
+const ERDP_EHB: u64 = 1 << 3;          // Event Handler Busy
+const ERDP_PTR_MASK: u64 = !0xF;       // Event Ring Dequeue Pointer
+
 impl InterrupterRegister {
     fn read(&self, offset: u64) -> u32 {
         match offset {
             0x00 => self.iman.load(Ordering::Acquire),
             0x04 => self.imod.load(Ordering::Acquire),
             0x08 => self.erstsz.load(Ordering::Acquire),
-            0x10 => self.erstba.load(Ordering::Acquire),
-            0x18 => self.erdp.load(Ordering::Acquire),
+            0x10 => self.erstba.load(Ordering::Acquire) as u32,
+            0x14 => (self.erstba.load(Ordering::Acquire) >> 32) as u32,
+            0x18 => self.erdp.load(Ordering::Acquire) as u32,
+            0x1C => (self.erdp.load(Ordering::Acquire) >> 32) as u32,
             _ => 0,
         }
     }
 
-    fn write(&mut self, offset: u64, value: u32) {
+    /// Returns the offset of the register written
+    fn write(&mut self, offset: u64, value: u32) -> u64 {
         match offset {
             0x00 => {
                 // IMAN - IP bit is write-clear
                 let current = self.iman.load(Ordering::Acquire);
-                let new_val = (current & !IMAN_IP) | (value & IMAN_IE);
-                if value & IMAN_IP != 0 {
-                    // Clear IP
-                }
+                let new_val = (current & !(value & IMAN_IP) & IMAN_IP) | (value & IMAN_IE);
                 self.iman.store(new_val, Ordering::Release);
             }
             0x04 => self.imod.store(value, Ordering::Release),
             0x08 => self.erstsz.store(value, Ordering::Release),
-            0x10 => self.erstba.store(value, Ordering::Release),
-            0x18 => self.erdp.store(value, Ordering::Release),
+            0x10 => {
+                let current = self.erstba.load(Ordering::Acquire);
+                self.erstba.store(
+                    (current & !0xFFFF_FFFF) | (value as u64 & !0x3F),
+                    Ordering::Release,
+                );
+            }
+            0x14 => {
+                let current = self.erstba.load(Ordering::Acquire);
+                self.erstba.store(
+                    (current & 0xFFFF_FFFF) | ((value as u64) << 32),
+                    Ordering::Release,
+                );
+            }
+            0x18 => {
+                // ERDP - EHB bit is write-clear
+                let current = self.erdp.load(Ordering::Acquire);
+                let ehb = current & ERDP_EHB & !(value as u64 & ERDP_EHB);
+                self.erdp.store(
+                    (current & !0xFFFF_FFFF) | (value as u64 & !ERDP_EHB) | ehb,
+                    Ordering::Release,
+                );
+            }
+            0x1C => {
+                let current = self.erdp.load(Ordering::Acquire);
+                self.erdp.store(
+                    (current & 0xFFFF_FFFF) | ((value as u64) << 32),
+                    Ordering::Release,
+                );
+            }
             _ => {}
         }
+        offset
     }
 }
 
@@ -627,6 +824,11 @@ mod tests {
     fn test_capability_registers() {
         let caps = CapabilityRegisters::new(32, 8, 8);
         assert_eq!(caps.hciversion(), 0x0100);
+
+        // CAPLENGTH in the low byte, HCIVERSION in the upper half
+        let dword = caps.read(0x00);
+        assert_eq!(dword & 0xff, XHCI_CAP_LENGTH as u32);
+        assert_eq!(dword >> 16, 0x0100);
     }
 
     #[test]
@@ -7,9 +7,17 @@
 //! - Command Ring
 //! - Event Ring
 //! - Transfer Ring
+//!
+//! The rings live in guest memory: the controller consumes the TRBs the
+//! guest produces on the command and transfer rings, and produces the
+//! events on the segments of the event ring.
 
 use std::collections::VecDeque;
 
+use vm_memory::{Bytes, GuestAddress};
+
+use super::GuestMemoryMmap;
+
 // ============================================================================
 // TRB (Transfer Request Block) Definitions
 // ============================================================================
@@ -226,6 +234,46 @@ impl Trb {
         self.status = (self.status & !(0xFF << 24)) | ((code as u32) << 24);
     }
 
+    /// Get slot ID
+    pub fn slot_id(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    /// Get endpoint ID
+    pub fn endpoint_id(&self) -> u8 {
+        ((self.control >> 16) & 0x1F) as u8
+    }
+
+    /// Check if the cycle state toggles (Link TRB)
+    pub fn is_toggle_cycle(&self) -> bool {
+        (self.control >> 1) & 1 != 0
+    }
+
+    /// Check if interrupt on short packet is requested
+    pub fn is_interrupt_on_short_packet(&self) -> bool {
+        (self.control >> 2) & 1 != 0
+    }
+
+    /// Check if the data stage goes to the host
+    pub fn is_direction_in(&self) -> bool {
+        (self.control >> 16) & 1 != 0
+    }
+
+    /// Read a TRB from guest memory
+    pub fn read_from(mem: &GuestMemoryMmap, addr: u64) -> Option<Self> {
+        let mut buf = [0u8; TRB_SIZE];
+        mem.read_slice(&mut buf, GuestAddress(addr)).ok()?;
+        Some(Self::from_bytes(&buf))
+    }
+
+    /// Write a TRB to guest memory. The dword with the cycle bit goes last,
+    /// so the guest never sees a TRB it owns half written.
+    pub fn write_to(&self, mem: &GuestMemoryMmap, addr: u64) -> bool {
+        let buf = self.as_bytes();
+        mem.write_slice(&buf[..12], GuestAddress(addr)).is_ok()
+            && mem.write_slice(&buf[12..], GuestAddress(addr + 12)).is_ok()
+    }
+
     /// Convert to bytes
     pub fn as_bytes(&self) -> [u8; TRB_SIZE] {
         let mut buf = [0u8; TRB_SIZE];
@@ -258,6 +306,56 @@ impl Trb {
     }
 }
 
+// ============================================================================
+// Ring Consumer
+// ============================================================================
+
+/// Maximum number of Link TRBs followed in a row, a ring made of Link TRBs
+/// only must not keep the controller busy
+const MAX_LINK_TRBS: usize = 16;
+
+/// Position of the controller in a ring the guest produces
+#[derive(Debug, Clone, Copy)]
+struct RingConsumer {
+    /// Address of the next TRB to consume
+    dequeue: u64,
+    /// Consumer cycle state
+    ccs: bool,
+}
+
+impl RingConsumer {
+    fn new(dequeue: u64, ccs: bool) -> Self {
+        Self {
+            dequeue: dequeue & !0xF,
+            ccs,
+        }
+    }
+
+    /// Get the next TRB handed over by the guest, with its address, without
+    /// consuming it. The Link TRBs on the way are followed.
+    fn peek(&mut self, mem: &GuestMemoryMmap) -> Option<(u64, Trb)> {
+        for _ in 0..=MAX_LINK_TRBS {
+            let trb = Trb::read_from(mem, self.dequeue)?;
+            if trb.cycle_bit() != self.ccs {
+                return None;
+            }
+            if trb.trb_type() != Some(TrbType::Link) {
+                return Some((self.dequeue, trb));
+            }
+            self.dequeue = trb.parameter & !0xF;
+            if trb.is_toggle_cycle() {
+                self.ccs = !self.ccs;
+            }
+        }
+        None
+    }
+
+    /// Consume the TRB returned by [`Self::peek`]
+    fn advance(&mut self) {
+        self.dequeue += TRB_SIZE as u64;
+    }
+}
+
 // ============================================================================
 // Command Ring
 // ============================================================================
@@ -273,51 +371,32 @@ pub enum CommandRingState {
 /// Command Ring
 #[derive(Debug)]
 pub struct CommandRing {
-    /// Ring base address
-    base: u64,
-    /// Ring size in TRBs
-    size: usize,
-    /// Current enqueue pointer
-    enqueue: u64,
-    /// Current cycle bit
-    cycle: bool,
+    /// Position of the next command
+    consumer: RingConsumer,
     /// Ring state
     state: CommandRingState,
-    /// Pending command queue
-    pending: VecDeque<Trb>,
 }
 
 impl CommandRing {
     /// Create a new command ring
     pub fn new() -> Self {
         Self {
-            base: 0,
-            size: 256,
-            enqueue: 0,
-            cycle: true,
+            consumer: RingConsumer::new(0, true),
             state: CommandRingState::Stopped,
-            pending: VecDeque::new(),
         }
     }
 
-    /// Initialize the ring
-    pub fn init(&mut self, base: u64, size: usize) {
-        self.base = base;
-        self.size = size;
-        self.enqueue = base;
-        self.cycle = true;
-        self.state = CommandRingState::Running;
-    }
-
-    /// Set base address
-    pub fn set_base(&mut self, base: u64) {
-        self.base = base;
-        self.enqueue = base;
+    /// Set the dequeue pointer and the consumer cycle state, as written to
+    /// CRCR. Ignored while the ring is running.
+    pub fn set_dequeue_ptr(&mut self, ptr: u64, cycle: bool) {
+        if !self.is_running() {
+            self.consumer = RingConsumer::new(ptr, cycle);
+        }
     }
 
-    /// Get enqueue pointer
-    pub fn enqueue_ptr(&self) -> u64 {
-        self.enqueue
+    /// Get dequeue pointer
+    pub fn dequeue_ptr(&self) -> u64 {
+        self.consumer.dequeue
     }
 
     /// Set ring running
@@ -340,23 +419,26 @@ impl CommandRing {
         self.state == CommandRingState::Running
     }
 
-    /// Queue a command TRB
-    pub fn queue(&mut self, trb: Trb) {
-        self.pending.push_back(trb);
-    }
-
-    /// Get next command
-    pub fn next(&mut self) -> Option<Trb> {
-        self.pending.pop_front()
-    }
-
-    /// Create event TRB for command completion
-    pub fn create_completion_event(&self, command: &Trb, slot_id: u8, code: CompletionCode) -> Trb {
-        let mut event = Trb::new(command.parameter, 0, 0);
+    /// Get the next command from guest memory, with its address
+    pub fn next(&mut self, mem: &GuestMemoryMmap) -> Option<(u64, Trb)> {
+        if !self.is_running() {
+            return None;
+        }
+        let command = self.consumer.peek(mem)?;
+        self.consumer.advance();
+        Some(command)
+    }
+
+    /// Create event TRB for the completion of the command at `command_addr`
+    pub fn create_completion_event(
+        &self,
+        command_addr: u64,
+        slot_id: u8,
+        code: CompletionCode,
+    ) -> Trb {
+        let mut event = Trb::new(command_addr, 0, (slot_id as u32) << 24);
         event.set_trb_type(TrbType::CommandCompletion);
         event.set_completion_code(code);
-        event.status |= slot_id as u32;
-        event.set_cycle_bit(true);
         event
     }
 }
@@ -383,6 +465,9 @@ pub struct EventRingSegment {
 }
 
 impl EventRingSegment {
+    /// Size of a segment table entry in bytes
+    pub const ENTRY_SIZE: u64 = 16;
+
     /// Create new segment
     pub fn new(base: u64, size: u16) -> Self {
         Self {
@@ -391,20 +476,36 @@ impl EventRingSegment {
             _reserved: [0; 6],
         }
     }
+
+    /// Read the entry `index` of the segment table at `table` in guest
+    /// memory
+    pub fn read_from(mem: &GuestMemoryMmap, table: u64, index: u64) -> Option<Self> {
+        let mut buf = [0u8; Self::ENTRY_SIZE as usize];
+        mem.read_slice(&mut buf, GuestAddress(table + index * Self::ENTRY_SIZE))
+            .ok()?;
+        let base = u64::from_le_bytes(buf[0..8].try_into().expect("slice has correct length"));
+        let size = u16::from_le_bytes(buf[8..10].try_into().expect("slice has correct length"));
+        Some(Self::new(base & !0x3F, size))
+    }
 }
 
 /// Event Ring
+///
+/// Events are written to the segments in guest memory as long as the guest
+/// left room for them, the others wait until it moves the dequeue pointer.
 #[derive(Debug)]
 pub struct EventRing {
     /// Event ring segments
     segments: Vec<EventRingSegment>,
-    /// Current segment index
+    /// Segment of the enqueue pointer
     segment_idx: usize,
-    /// Current dequeue pointer within segment
-    dequeue_idx: usize,
+    /// Index of the enqueue pointer within the segment
+    enqueue_idx: usize,
     /// Producer cycle state
     cycle: bool,
-    /// Pending events
+    /// Dequeue pointer, as written by the guest to ERDP
+    dequeue: u64,
+    /// Events waiting for room in the ring
     pending: VecDeque<Trb>,
 }
 
@@ -414,63 +515,90 @@ impl EventRing {
         Self {
             segments: Vec::new(),
             segment_idx: 0,
-            dequeue_idx: 0,
+            enqueue_idx: 0,
             cycle: true,
+            dequeue: 0,
             pending: VecDeque::new(),
         }
     }
 
-    /// Set segment table
-    pub fn set_segments(&mut self, segments: Vec<EventRingSegment>) {
+    /// Set segment table, the ring starts over from the first segment
+    pub fn set_segments(&mut self, mut segments: Vec<EventRingSegment>) {
+        segments.retain(|segment| segment.size > 0);
         self.segments = segments;
         self.segment_idx = 0;
-        self.dequeue_idx = 0;
+        self.enqueue_idx = 0;
+        self.cycle = true;
+        self.dequeue = self.enqueue_ptr();
     }
 
     /// Get dequeue pointer
     pub fn dequeue_ptr(&self) -> u64 {
-        if let Some(segment) = self.segments.get(self.segment_idx) {
-            segment.base + (self.dequeue_idx as u64 * TRB_SIZE as u64)
-        } else {
-            0
-        }
+        self.dequeue
     }
 
-    /// Set dequeue pointer
+    /// Set dequeue pointer, up to which the guest consumed the events
     pub fn set_dequeue_ptr(&mut self, ptr: u64) {
-        // Find the segment containing this pointer
-        for (idx, segment) in self.segments.iter().enumerate() {
-            if ptr >= segment.base && ptr < segment.base + (segment.size as u64 * TRB_SIZE as u64) {
-                self.segment_idx = idx;
-                self.dequeue_idx = ((ptr - segment.base) / TRB_SIZE as u64) as usize;
-                break;
-            }
+        self.dequeue = ptr & !0xF;
+    }
+
+    /// Get enqueue pointer
+    pub fn enqueue_ptr(&self) -> u64 {
+        self.segments.get(self.segment_idx).map_or(0, |segment| {
+            segment.base + (self.enqueue_idx * TRB_SIZE) as u64
+        })
+    }
+
+    /// Position of the enqueue pointer after the next event, with the cycle
+    /// state to write it with
+    fn next_position(&self) -> (usize, usize, bool) {
+        if self.enqueue_idx + 1 < self.segments[self.segment_idx].size as usize {
+            return (self.segment_idx, self.enqueue_idx + 1, self.cycle);
         }
+        let segment_idx = (self.segment_idx + 1) % self.segments.len();
+        // Going back to the first segment toggles the cycle state
+        (segment_idx, 0, self.cycle ^ (segment_idx == 0))
+    }
+
+    /// Check if the guest left no room for another event. One TRB always
+    /// stays free, an enqueue pointer equal to the dequeue pointer stands
+    /// for an empty ring.
+    fn is_full(&self) -> bool {
+        let (segment_idx, enqueue_idx, _) = self.next_position();
+        self.segments[segment_idx].base + (enqueue_idx * TRB_SIZE) as u64 == self.dequeue
     }
 
-    /// Queue an event TRB
-    pub fn queue(&mut self, mut event: Trb) -> bool {
-        event.set_cycle_bit(self.cycle);
+    /// Queue an event TRB, written to the ring by [`Self::flush`]
+    pub fn queue(&mut self, event: Trb) {
         self.pending.push_back(event);
+    }
 
-        // Check if we need to toggle cycle state
-        if let Some(segment) = self.segments.get(self.segment_idx) {
-            if self.dequeue_idx >= segment.size as usize - 1 {
-                self.dequeue_idx = 0;
-                self.segment_idx = (self.segment_idx + 1) % self.segments.len();
-                self.cycle = !self.cycle;
+    /// Write the queued events to the ring while there is room for them.
+    /// Returns the number of events written.
+    pub fn flush(&mut self, mem: &GuestMemoryMmap) -> usize {
+        let mut written = 0;
+        while !self.segments.is_empty() && !self.is_full() {
+            let Some(mut event) = self.pending.front().copied() else {
+                break;
+            };
+            event.set_cycle_bit(self.cycle);
+            if !event.write_to(mem, self.enqueue_ptr()) {
+                break;
             }
+            self.pending.pop_front();
+            (self.segment_idx, self.enqueue_idx, self.cycle) = self.next_position();
+            written += 1;
         }
-
-        true
+        written
     }
 
-    /// Get next event
-    pub fn next(&mut self) -> Option<Trb> {
-        self.pending.pop_front()
+    /// Check if the guest consumed all the events written to the ring, a
+    /// ring without segments holds none
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty() || self.enqueue_ptr() == self.dequeue
     }
 
-    /// Check if events are pending
+    /// Check if events are waiting for room in the ring
     pub fn has_pending(&self) -> bool {
         !self.pending.is_empty()
     }
@@ -489,87 +617,66 @@ impl Default for EventRing {
 /// Transfer Ring (for endpoints)
 #[derive(Debug, Clone)]
 pub struct TransferRing {
-    /// Endpoint ID (1-31)
+    /// Endpoint ID (DCI, 1-31)
     ep_id: u8,
-    /// Ring base address
-    base: u64,
-    /// Ring size in TRBs
-    size: usize,
-    /// Current enqueue pointer
-    enqueue: u64,
-    /// Current dequeue pointer
-    dequeue: u64,
-    /// Producer cycle state
-    pcs: bool,
-    /// Consumer cycle state
-    ccs: bool,
-    /// Transfer TRBs pending processing
-    pending: VecDeque<Trb>,
+    /// Position of the next transfer TRB
+    consumer: RingConsumer,
 }
 
 impl TransferRing {
-    /// Create a new transfer ring
-    pub fn new(ep_id: u8) -> Self {
+    /// Create a new transfer ring, starting at `dequeue` with the dequeue
+    /// cycle state `dcs` of the endpoint context
+    pub fn new(ep_id: u8, dequeue: u64, dcs: bool) -> Self {
         Self {
             ep_id,
-            base: 0,
-            size: 256,
-            enqueue: 0,
-            dequeue: 0,
-            pcs: true,
-            ccs: true,
-            pending: VecDeque::new(),
+            consumer: RingConsumer::new(dequeue, dcs),
         }
     }
 
-    /// Initialize the ring
-    pub fn init(&mut self, base: u64, size: usize) {
-        self.base = base;
-        self.size = size;
-        self.enqueue = base;
-        self.dequeue = base;
-        self.pcs = true;
-        self.ccs = true;
-    }
-
-    /// Get enqueue pointer
-    pub fn enqueue_ptr(&self) -> u64 {
-        self.enqueue
+    /// Get endpoint ID
+    pub fn ep_id(&self) -> u8 {
+        self.ep_id
     }
 
     /// Get dequeue pointer
     pub fn dequeue_ptr(&self) -> u64 {
-        self.dequeue
+        self.consumer.dequeue
+    }
+
+    /// Get dequeue cycle state
+    pub fn dcs(&self) -> bool {
+        self.consumer.ccs
     }
 
     /// Set dequeue pointer
     pub fn set_dequeue_ptr(&mut self, ptr: u64, cycle: bool) {
-        self.dequeue = ptr;
-        self.ccs = cycle;
+        self.consumer = RingConsumer::new(ptr, cycle);
     }
 
-    /// Queue a transfer TRB
-    pub fn queue(&mut self, trb: Trb) {
-        self.pending.push_back(trb);
+    /// Get the next transfer TRB from guest memory, with its address,
+    /// without consuming it
+    pub fn peek(&mut self, mem: &GuestMemoryMmap) -> Option<(u64, Trb)> {
+        self.consumer.peek(mem)
     }
 
-    /// Get next transfer TRB
-    pub fn next(&mut self) -> Option<Trb> {
-        self.pending.pop_front()
+    /// Consume the TRB returned by [`Self::peek`]
+    pub fn advance(&mut self) {
+        self.consumer.advance();
     }
 
-    /// Check if pending transfers exist
-    pub fn has_pending(&self) -> bool {
-        !self.pending.is_empty()
-    }
-
-    /// Create transfer event
-    pub fn create_transfer_event(&self, trb: &Trb, code: CompletionCode, length: u32) -> Trb {
-        let mut event = Trb::new(trb.parameter, 0, 0);
+    /// Create transfer event for the TRB at `trb_addr`, `residual` bytes
+    /// of it not being transferred
+    pub fn create_transfer_event(
+        &self,
+        trb_addr: u64,
+        slot_id: u8,
+        code: CompletionCode,
+        residual: u32,
+    ) -> Trb {
+        let control = ((slot_id as u32) << 24) | ((self.ep_id as u32) << 16);
+        let mut event = Trb::new(trb_addr, residual & 0xFF_FFFF, control);
         event.set_trb_type(TrbType::TransferEvent);
         event.set_completion_code(code);
-        event.status |= length;
-        event.set_cycle_bit(true);
         event
     }
 }
@@ -578,6 +685,17 @@ impl TransferRing {
 mod tests {
     use super::*;
 
+    fn guest_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap()
+    }
+
+    fn trb(trb_type: TrbType, parameter: u64, cycle: bool) -> Trb {
+        let mut trb = Trb::new(parameter, 0, 0);
+        trb.set_trb_type(trb_type);
+        trb.set_cycle_bit(cycle);
+        trb
+    }
+
     #[test]
     fn test_trb_creation() {
         let mut trb = Trb::new(0x12345678, 0x100, 0);
@@ -592,20 +710,107 @@ mod tests {
 
     #[test]
     fn test_command_ring() {
+        let mem = guest_memory();
         let mut ring = CommandRing::new();
-        ring.init(0x10000, 256);
+        ring.set_dequeue_ptr(0x10000, true);
+        assert!(ring.next(&mem).is_none());
 
+        ring.start();
         assert!(ring.is_running());
-        assert_eq!(ring.enqueue_ptr(), 0x10000);
+        assert_eq!(ring.dequeue_ptr(), 0x10000);
+
+        // Two commands, then a Link TRB back to the start of the ring
+        trb(TrbType::Noop, 0, true).write_to(&mem, 0x10000);
+        trb(TrbType::EnableSlot, 0, true).write_to(&mem, 0x10010);
+        let mut link = trb(TrbType::Link, 0x10000, true);
+        link.control |= 1 << 1;
+        link.write_to(&mem, 0x10020);
+
+        let (addr, command) = ring.next(&mem).unwrap();
+        assert_eq!((addr, command.trb_type()), (0x10000, Some(TrbType::Noop)));
+        let (addr, command) = ring.next(&mem).unwrap();
+        assert_eq!(
+            (addr, command.trb_type()),
+            (0x10010, Some(TrbType::EnableSlot))
+        );
+
+        // The old commands don't match the toggled cycle state
+        assert!(ring.next(&mem).is_none());
+        assert_eq!(ring.dequeue_ptr(), 0x10000);
+        trb(TrbType::Noop, 0, false).write_to(&mem, 0x10000);
+        let (addr, _) = ring.next(&mem).unwrap();
+        assert_eq!(addr, 0x10000);
+
+        // The pointer can't move under a running ring
+        ring.set_dequeue_ptr(0x20000, true);
+        assert_eq!(ring.dequeue_ptr(), 0x10010);
     }
 
     #[test]
     fn test_event_ring() {
+        let mem = guest_memory();
         let mut ring = EventRing::new();
-        let segment = EventRingSegment::new(0x20000, 16);
-        ring.set_segments(vec![segment]);
+        // The dequeue pointer may be written before the segment table
+        ring.set_dequeue_ptr(0x20000);
+        assert!(ring.is_empty());
+
+        // A segment table of two 2-TRB segments
+        mem.write_obj(0x20000u64, GuestAddress(0x1000)).unwrap();
+        mem.write_obj(2u32, GuestAddress(0x1008)).unwrap();
+        mem.write_obj(0x30000u64, GuestAddress(0x1010)).unwrap();
+        mem.write_obj(2u32, GuestAddress(0x1018)).unwrap();
+        let segments = (0..2)
+            .map(|i| EventRingSegment::read_from(&mem, 0x1000, i).unwrap())
+            .collect();
+        ring.set_segments(segments);
+        assert_eq!(ring.dequeue_ptr(), 0x20000);
+
+        // One TRB stays free
+        for i in 0..4 {
+            ring.queue(Trb::new(i, 0, 0));
+        }
+        assert_eq!(ring.flush(&mem), 3);
+        assert!(ring.has_pending());
+        let event = Trb::read_from(&mem, 0x30000).unwrap();
+        assert_eq!(event.parameter, 2);
+        assert!(event.cycle_bit());
+
+        // Consuming events makes room for the pending one, written with the
+        // cycle state toggled when wrapping around
+        ring.set_dequeue_ptr(0x20010);
+        assert_eq!(ring.flush(&mem), 1);
+        assert!(!ring.has_pending());
+        let event = Trb::read_from(&mem, 0x30010).unwrap();
+        assert_eq!(event.parameter, 3);
+        assert!(event.cycle_bit());
+        assert_eq!(ring.enqueue_ptr(), 0x20000);
+
+        ring.queue(Trb::new(4, 0, 0));
+        assert_eq!(ring.flush(&mem), 0);
+        ring.set_dequeue_ptr(0x30010);
+        assert_eq!(ring.flush(&mem), 1);
+        let event = Trb::read_from(&mem, 0x20000).unwrap();
+        assert_eq!(event.parameter, 4);
+        assert!(!event.cycle_bit());
+    }
 
-        let event = Trb::new(0, 0, 0);
-        assert!(ring.queue(event));
+    #[test]
+    fn test_transfer_ring() {
+        let mem = guest_memory();
+        let mut ring = TransferRing::new(3, 0x40000, true);
+        assert!(ring.peek(&mem).is_none());
+
+        trb(TrbType::Normal, 0x50000, true).write_to(&mem, 0x40000);
+        // Peeking doesn't consume the TRB
+        assert_eq!(ring.peek(&mem).unwrap().0, 0x40000);
+        assert_eq!(ring.peek(&mem).unwrap().0, 0x40000);
+        ring.advance();
+        assert!(ring.peek(&mem).is_none());
+
+        let event = ring.create_transfer_event(0x40000, 1, CompletionCode::ShortPacket, 5);
+        assert_eq!(event.trb_type(), Some(TrbType::TransferEvent));
+        assert_eq!(event.completion_code(), CompletionCode::ShortPacket as u8);
+        assert_eq!(event.transfer_length(), 5);
+        assert_eq!((event.slot_id(), event.endpoint_id()), (1, 3));
     }
 }
@@ -112,6 +112,46 @@ impl RequestHandler for StubApiRequestHandler {
         Ok(())
     }
 
+    fn vm_disk_internal_snapshot_list(&mut self, _: String) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vm_disk_internal_snapshot_create(&mut self, _: String, _: String) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_disk_internal_snapshot_apply(&mut self, _: String, _: String) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_disk_internal_snapshot_delete(&mut self, _: String, _: String) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_disk_snapshot(&mut self, _: String, _: PathBuf) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_disk_commit(&mut self, _: String) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_disk_commit_status(&mut self, _: String) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vm_disk_backup(&mut self, _: String, _: PathBuf, _: bool) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_disk_backup_status(&mut self, _: String) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vm_disk_backup_cancel(&mut self, _: String) -> Result<(), VmError> {
+        Ok(())
+    }
+
     #[cfg(target_arch = "x86_64")]
     fn vm_coredump(&mut self, _: &str) -> Result<(), VmError> {
         Ok(())
@@ -204,6 +244,11 @@ impl RequestHandler for StubApiRequestHandler {
                 landlock_rules: None,
                 #[cfg(feature = "ivshmem")]
                 ivshmem: None,
+                usb: None,
+                gpu: None,
+                input: None,
+                #[cfg(feature = "vnc")]
+                vnc: None,
             }),
             state: VmState::Running,
             memory_actual_size: 0,
@@ -279,6 +324,10 @@ impl RequestHandler for StubApiRequestHandler {
         Ok(None)
     }
 
+    fn vm_add_gpu(&mut self, _: GpuConfig) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
     fn vm_counters(&mut self) -> Result<Option<Vec<u8>>, VmError> {
         Ok(None)
     }
@@ -5,4 +5,13 @@
 
 fn main() {
     println!("cargo::rustc-check-cfg=cfg(fuzzing)");
+
+    // Blob resources need virglrenderer 0.9.0 or later
+    #[cfg(feature = "virgl")]
+    if let Err(e) = pkg_config::Config::new()
+        .atleast_version("0.9.0")
+        .probe("virglrenderer")
+    {
+        panic!("The virgl feature requires virglrenderer: {e}");
+    }
 }
@@ -14,11 +14,16 @@ use std::ops::Deref;
 use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel};
 use std::sync::{Arc, Barrier};
 use std::{io, result};
 
 use anyhow::anyhow;
-use block::async_io::{AsyncIo, AsyncIoError, DiskFile, DiskFileError};
+use block::async_io::{
+    AsyncIo, AsyncIoError, DiskFile, DiskFileError, DiskFileResult, DiskSnapshot,
+};
+use block::copy_before_write::{CopyBeforeWrite, CopyBeforeWriteIo};
+use block::dirty_bitmap::DirtyBitmap;
 use block::fcntl::{LockError, LockGranularity, LockType, get_lock_state};
 use block::{
     ExecuteAsync, ExecuteError, Request, RequestType, VirtioBlockConfig, build_serial, fcntl,
@@ -57,6 +62,8 @@ const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 const COMPLETION_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // New 'wake up' event from the rate limiter
 const RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// The queue must stop processing requests to be updated.
+const UPDATE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
 
 // latency scale, for reduce precision loss in calculate.
 const LATENCY_SCALE: u64 = 10000;
@@ -105,6 +112,18 @@ pub enum Error {
     ConfigChange(#[source] io::Error),
     #[error("Disk resize failed")]
     DiskResize(#[source] DiskFileError),
+    #[error("Disk snapshot operation failed")]
+    DiskSnapshot(#[source] DiskFileError),
+    #[error("Disk dirty bitmap operation failed")]
+    DiskDirtyBitmap(#[source] DiskFileError),
+    #[error("Failed to create the asynchronous I/O of the disk image")]
+    CreateAsyncIo(#[source] DiskFileError),
+    #[error("Failed waiting for the requests in flight")]
+    DrainRequests(#[source] io::Error),
+    #[error("Failed to signal a queue update")]
+    QueueUpdate(#[source] io::Error),
+    #[error("A queue thread exited before its update")]
+    QueueThreadExited,
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -142,6 +161,23 @@ impl Default for BlockCounters {
     }
 }
 
+// Changes applied by a queue thread once it has no request in flight.
+struct QueueUpdate {
+    // Backend for the new disk image, if it was replaced.
+    disk_image: Option<Box<dyn AsyncIo>>,
+    dirty_bitmap: Option<Arc<DirtyBitmap>>,
+}
+
+// Device side of the channel updating a queue thread.
+struct QueueUpdater {
+    queue_size: u16,
+    update_evt: EventFd,
+    update_tx: Sender<QueueUpdate>,
+    ack_rx: Receiver<()>,
+    // Backend created by set_disk_image(), sent with the next update.
+    disk_image: Option<Box<dyn AsyncIo>>,
+}
+
 struct BlockEpollHandler {
     queue_index: u16,
     queue: Queue,
@@ -161,6 +197,10 @@ struct BlockEpollHandler {
     host_cpus: Option<Vec<usize>>,
     acked_features: u64,
     disable_sector0_writes: bool,
+    update_evt: EventFd,
+    update_rx: Receiver<QueueUpdate>,
+    ack_tx: Sender<()>,
+    dirty_bitmap: Option<Arc<DirtyBitmap>>,
 }
 
 fn has_feature(features: u64, feature_flag: u64) -> bool {
@@ -401,6 +441,12 @@ impl BlockEpollHandler {
             let mut read_avg = self.counters.read_latency_avg.load(Ordering::Relaxed);
             let mut write_avg = self.counters.write_latency_avg.load(Ordering::Relaxed);
             let (status, len) = if result >= 0 {
+                if let (Some(dirty_bitmap), Some((offset, len))) =
+                    (&self.dirty_bitmap, request.written_range())
+                {
+                    dirty_bitmap.set(offset, len);
+                }
+
                 match request.request_type {
                     RequestType::In => {
                         for (_, data_len) in &request.data_descriptors {
@@ -514,6 +560,67 @@ impl BlockEpollHandler {
         Ok(())
     }
 
+    // Completes the requests in flight, waiting for the backend if needed.
+    fn drain_inflight_requests(&mut self) -> Result<()> {
+        loop {
+            self.process_queue_complete()?;
+            if self.inflight_requests.is_empty() {
+                return Ok(());
+            }
+
+            let mut pollfd = libc::pollfd {
+                fd: self.disk_image.notifier().as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: FFI call with a valid pollfd
+            if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(Error::DrainRequests(e));
+                }
+                continue;
+            }
+            // The completions are collected by process_queue_complete().
+            let _ = self.disk_image.notifier().read();
+        }
+    }
+
+    // Stops processing requests until the device sends the update, once the
+    // requests in flight have completed.
+    fn process_update(&mut self, helper: &mut EpollHelper) -> result::Result<(), EpollHelperError> {
+        self.drain_inflight_requests().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to drain the queue: {e:?}"))
+        })?;
+        self.try_signal_used_queue()?;
+
+        self.ack_tx.send(()).map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to acknowledge the update: {e:?}"))
+        })?;
+        let update = self.update_rx.recv().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to receive the update: {e:?}"))
+        })?;
+
+        if let Some(disk_image) = update.disk_image {
+            helper.del_event_custom(
+                self.disk_image.notifier().as_raw_fd(),
+                COMPLETION_EVENT,
+                epoll::Events::EPOLLIN,
+            )?;
+            self.disk_image = disk_image;
+            helper.add_event(self.disk_image.notifier().as_raw_fd(), COMPLETION_EVENT)?;
+        }
+        self.dirty_bitmap = update.dirty_bitmap;
+
+        // Requests may have been made available meanwhile.
+        let rate_limit_reached = self.rate_limiter.as_ref().is_some_and(|r| r.is_blocked());
+        if !rate_limit_reached {
+            self.process_queue_submit_and_signal()?;
+        }
+
+        Ok(())
+    }
+
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         self.interrupt_cb
             .trigger(VirtioInterruptType::Queue(self.queue_index))
@@ -569,6 +676,7 @@ impl BlockEpollHandler {
         if let Some(rate_limiter) = &self.rate_limiter {
             helper.add_event(rate_limiter.as_raw_fd(), RATE_LIMITER_EVENT)?;
         }
+        helper.add_event(self.update_evt.as_raw_fd(), UPDATE_EVENT)?;
         self.set_queue_thread_affinity();
         helper.run(paused, paused_sync, self)?;
 
@@ -579,7 +687,7 @@ impl BlockEpollHandler {
 impl EpollHelperHandler for BlockEpollHandler {
     fn handle_event(
         &mut self,
-        _helper: &mut EpollHelper,
+        helper: &mut EpollHelper,
         event: &epoll::Event,
     ) -> result::Result<(), EpollHelperError> {
         let ev_type = event.data as u16;
@@ -597,9 +705,15 @@ impl EpollHelperHandler for BlockEpollHandler {
                 }
             }
             COMPLETION_EVENT => {
-                self.disk_image.notifier().read().map_err(|e| {
-                    EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {e:?}"))
-                })?;
+                // The completions may have been collected already, by an
+                // update handled along with this event.
+                if let Err(e) = self.disk_image.notifier().read()
+                    && e.kind() != io::ErrorKind::WouldBlock
+                {
+                    return Err(EpollHelperError::HandleEvent(anyhow!(
+                        "Failed to get queue event: {e:?}"
+                    )));
+                }
 
                 self.process_queue_complete().map_err(|e| {
                     EpollHelperError::HandleEvent(anyhow!(
@@ -636,6 +750,13 @@ impl EpollHelperHandler for BlockEpollHandler {
                     )));
                 }
             }
+            UPDATE_EVENT => {
+                self.update_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get update event: {e:?}"))
+                })?;
+
+                self.process_update(helper)?;
+            }
             _ => {
                 return Err(EpollHelperError::HandleEvent(anyhow!(
                     "Unexpected event: {ev_type}"
@@ -662,6 +783,9 @@ pub struct Block {
     serial: Vec<u8>,
     queue_affinity: BTreeMap<u16, Vec<usize>>,
     disable_sector0_writes: bool,
+    queue_updaters: Vec<QueueUpdater>,
+    dirty_bitmap: Option<Arc<DirtyBitmap>>,
+    copy_before_write: Option<Arc<CopyBeforeWrite>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -807,6 +931,9 @@ impl Block {
             serial,
             queue_affinity,
             disable_sector0_writes,
+            queue_updaters: Vec::new(),
+            dirty_bitmap: None,
+            copy_before_write: None,
         })
     }
 
@@ -943,6 +1070,173 @@ impl Block {
         }
     }
 
+    /// Returns the internal snapshots of the disk image.
+    pub fn snapshots(&mut self) -> Result<Vec<DiskSnapshot>> {
+        self.disk_image.snapshots().map_err(Error::DiskSnapshot)
+    }
+
+    /// Runs `f` while the queues don't process any request, the requests in
+    /// flight having completed, then lets them go on with the disk image and
+    /// dirty bitmap set by `f`. A paused device is resumed for the queues to
+    /// stop, and paused again afterwards.
+    pub fn with_queues_quiesced<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> Result<T> {
+        if self.queue_updaters.is_empty() {
+            return Ok(f(self));
+        }
+
+        let paused = self.common.paused.load(Ordering::SeqCst);
+        if paused {
+            self.common.resume().map_err(Error::ResumeVcpus)?;
+        }
+
+        let mut result = Ok(());
+        let mut signaled = 0;
+        for updater in &self.queue_updaters {
+            if let Err(e) = updater.update_evt.write(1) {
+                result = Err(Error::QueueUpdate(e));
+                break;
+            }
+            signaled += 1;
+        }
+        for updater in &self.queue_updaters[..signaled] {
+            if updater.ack_rx.recv().is_err() && result.is_ok() {
+                result = Err(Error::QueueThreadExited);
+            }
+        }
+
+        let result = result.map(|()| f(self));
+
+        for updater in &mut self.queue_updaters[..signaled] {
+            // A queue thread which exited doesn't take its update.
+            let _ = updater.update_tx.send(QueueUpdate {
+                disk_image: updater.disk_image.take(),
+                dirty_bitmap: self.dirty_bitmap.clone(),
+            });
+        }
+
+        if paused {
+            self.common.pause().map_err(Error::PauseVcpus)?;
+        }
+
+        result
+    }
+
+    /// Takes an internal snapshot of the disk image. Once the device is
+    /// activated, this must be called from [`Block::with_queues_quiesced`].
+    pub fn create_snapshot(&mut self, name: &str, vm_clock_nsec: u64) -> Result<()> {
+        self.disk_image
+            .create_snapshot(name, vm_clock_nsec)
+            .map_err(Error::DiskSnapshot)
+    }
+
+    /// Reverts the disk image to one of its internal snapshots. Once the
+    /// device is activated, this must be called from
+    /// [`Block::with_queues_quiesced`].
+    pub fn apply_snapshot(&mut self, id_or_name: &str) -> Result<()> {
+        self.disk_image
+            .apply_snapshot(id_or_name)
+            .map_err(Error::DiskSnapshot)
+    }
+
+    /// Deletes one of the internal snapshots of the disk image. Once the
+    /// device is activated, this must be called from
+    /// [`Block::with_queues_quiesced`].
+    pub fn delete_snapshot(&mut self, id_or_name: &str) -> Result<()> {
+        self.disk_image
+            .delete_snapshot(id_or_name)
+            .map_err(Error::DiskSnapshot)
+    }
+
+    /// Loads a dirty bitmap stored in the disk image.
+    pub fn load_dirty_bitmap(&mut self, name: &str, granularity: u64) -> Result<DirtyBitmap> {
+        self.disk_image
+            .load_dirty_bitmap(name, granularity)
+            .map_err(Error::DiskDirtyBitmap)
+    }
+
+    /// Stores a dirty bitmap in the disk image. The device must not be
+    /// processing requests.
+    pub fn store_dirty_bitmap(&mut self, name: &str, bitmap: &DirtyBitmap) -> Result<()> {
+        self.disk_image
+            .store_dirty_bitmap(name, bitmap)
+            .map_err(Error::DiskDirtyBitmap)
+    }
+
+    /// Replaces the disk image of the device and returns the previous one.
+    /// Once the device is activated, the queues only switch to it if this is
+    /// called from [`Block::with_queues_quiesced`].
+    pub fn set_disk_image(
+        &mut self,
+        disk_image: Box<dyn DiskFile>,
+        disk_path: PathBuf,
+    ) -> Result<Box<dyn DiskFile>> {
+        let async_ios = self.new_queue_async_ios(disk_image.as_ref())?;
+        self.set_queue_async_ios(async_ios);
+        self.disk_path = disk_path;
+        Ok(std::mem::replace(&mut self.disk_image, disk_image))
+    }
+
+    /// Sets the copy the queues make of the chunks the guest is about to
+    /// write. Once the device is activated, the queues only use it if this is
+    /// called from [`Block::with_queues_quiesced`].
+    pub fn set_copy_before_write(
+        &mut self,
+        copy_before_write: Option<Arc<CopyBeforeWrite>>,
+    ) -> Result<()> {
+        self.copy_before_write = copy_before_write;
+        let async_ios = self.new_queue_async_ios(self.disk_image.as_ref())?;
+        self.set_queue_async_ios(async_ios);
+        Ok(())
+    }
+
+    fn queue_async_io(
+        &self,
+        disk_image: &dyn DiskFile,
+        queue_size: u16,
+    ) -> DiskFileResult<Box<dyn AsyncIo>> {
+        let async_io = disk_image.new_async_io(queue_size as u32)?;
+        Ok(match &self.copy_before_write {
+            Some(copy_before_write) => {
+                Box::new(CopyBeforeWriteIo::new(async_io, copy_before_write.clone()))
+            }
+            None => async_io,
+        })
+    }
+
+    fn new_queue_async_ios(&self, disk_image: &dyn DiskFile) -> Result<Vec<Box<dyn AsyncIo>>> {
+        self.queue_updaters
+            .iter()
+            .map(|updater| self.queue_async_io(disk_image, updater.queue_size))
+            .collect::<result::Result<Vec<_>, _>>()
+            .map_err(Error::CreateAsyncIo)
+    }
+
+    fn set_queue_async_ios(&mut self, async_ios: Vec<Box<dyn AsyncIo>>) {
+        for (updater, async_io) in self.queue_updaters.iter_mut().zip(async_ios) {
+            updater.disk_image = Some(async_io);
+        }
+    }
+
+    /// Sets the bitmap the queues mark the chunks written by the guest in.
+    /// Once the device is activated, the queues only use it if this is called
+    /// from [`Block::with_queues_quiesced`].
+    pub fn set_dirty_bitmap(&mut self, dirty_bitmap: Option<Arc<DirtyBitmap>>) {
+        self.dirty_bitmap = dirty_bitmap;
+    }
+
+    /// Returns the size of the disk as seen by the guest.
+    pub fn disk_size(&self) -> u64 {
+        self.disk_nsectors.load(Ordering::SeqCst) * SECTOR_SIZE
+    }
+
+    /// Returns a new asynchronous I/O context on the disk image, separate from
+    /// the ones of the queues.
+    pub fn new_async_io(&self, ring_depth: u32) -> Result<Box<dyn AsyncIo>> {
+        self.disk_image
+            .new_async_io(ring_depth)
+            .map_err(Error::CreateAsyncIo)
+    }
+
     #[cfg(fuzzing)]
     pub fn wait_for_epoll_threads(&mut self) {
         self.common.wait_for_epoll_threads();
@@ -1016,6 +1310,7 @@ impl VirtioDevice for Block {
         self.update_writeback();
 
         let mut epoll_threads = Vec::new();
+        let mut queue_updaters = Vec::new();
         let event_idx = self.common.feature_acked(VIRTIO_RING_F_EVENT_IDX.into());
 
         for i in 0..queues.len() {
@@ -1026,13 +1321,29 @@ impl VirtioDevice for Block {
             let (kill_evt, pause_evt) = self.common.dup_eventfds();
             let queue_idx = i as u16;
 
+            let update_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(|e| {
+                error!("failed creating update EventFd: {e}");
+                ActivateError::BadActivate
+            })?;
+            let (update_tx, update_rx) = channel();
+            let (ack_tx, ack_rx) = channel();
+            queue_updaters.push(QueueUpdater {
+                queue_size,
+                update_evt: update_evt.try_clone().map_err(|e| {
+                    error!("failed cloning update EventFd: {e}");
+                    ActivateError::BadActivate
+                })?,
+                update_tx,
+                ack_rx,
+                disk_image: None,
+            });
+
             let mut handler = BlockEpollHandler {
                 queue_index: queue_idx,
                 queue,
                 mem: mem.clone(),
                 disk_image: self
-                    .disk_image
-                    .new_async_io(queue_size as u32)
+                    .queue_async_io(self.disk_image.as_ref(), queue_size)
                     .map_err(|e| {
                         error!("failed to create new AsyncIo: {e}");
                         ActivateError::BadActivate
@@ -1059,6 +1370,10 @@ impl VirtioDevice for Block {
                 host_cpus: self.queue_affinity.get(&queue_idx).cloned(),
                 acked_features: self.common.acked_features,
                 disable_sector0_writes: self.disable_sector0_writes,
+                update_evt,
+                update_rx,
+                ack_tx,
+                dirty_bitmap: self.dirty_bitmap.clone(),
             };
 
             let paused = self.common.paused.clone();
@@ -1075,6 +1390,7 @@ impl VirtioDevice for Block {
         }
 
         self.common.epoll_threads = Some(epoll_threads);
+        self.queue_updaters = queue_updaters;
         event!("virtio-device", "activated", "id", &self.id);
 
         Ok(())
@@ -1082,6 +1398,7 @@ impl VirtioDevice for Block {
 
     fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
         let result = self.common.reset();
+        self.queue_updaters.clear();
         event!("virtio-device", "reset", "id", &self.id);
         result
     }
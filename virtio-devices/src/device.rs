@@ -41,6 +41,8 @@ pub trait VirtioInterrupt: Send + Sync {
 
 #[derive(Clone)]
 pub struct VirtioSharedMemory {
+    /// Shared memory region identifier, as defined by the device type
+    pub id: u8,
     pub offset: u64,
     pub len: u64,
 }
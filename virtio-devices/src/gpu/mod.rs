@@ -4,10 +4,31 @@
 //! VirtIO GPU Device
 //!
 //! This module provides a VirtIO GPU device with basic 2D rendering support.
+//!
+//! Whenever the guest flushes a resource that is attached to a scanout, the
+//! scanout contents are copied into a host-visible [`ScanoutFrame`] which the
+//! VMM can read back through [`Gpu::scanout_frame`].
+//!
+//! Each scanout is a display which can be connected with its own resolution,
+//! described to the guest by a generated EDID. Connecting or disconnecting a
+//! display at runtime through [`Gpu::set_display`] raises the
+//! VIRTIO_GPU_EVENT_DISPLAY event, and the guest reads the displays again.
+//!
+//! With VIRTIO_GPU_F_RESOURCE_BLOB, the guest can scan out blob resources
+//! directly from its own memory, so flushes are published without going
+//! through TRANSFER_TO_HOST_2D. Mappable host blobs are placed in the
+//! host-visible shared memory region exposed through the PCI shared memory
+//! capability.
+//!
+//! With the `virgl` feature, the 3D command set is executed by virglrenderer
+//! on a surfaceless EGL display, see the [`virgl`] module. Scanouts backed by
+//! 3D resources are read back from the renderer when flushed.
 
 use std::collections::HashMap;
+#[cfg(feature = "virgl")]
+use std::collections::VecDeque;
 use std::io;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Barrier, Mutex};
 
@@ -17,20 +38,28 @@ use seccompiler::SeccompAction;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use virtio_queue::{Queue, QueueT};
+use vm_device::UserspaceMapping;
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
-    GuestMemoryError,
+    GuestMemoryError, VolatileMemory,
 };
 use vm_migration::{Migratable, Pausable, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 
-use super::{ActivateResult, VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterrupt};
+use super::{
+    ActivateResult, VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterrupt,
+    VirtioSharedMemoryList,
+};
 use crate::seccomp_filters::Thread;
 use crate::thread_helper::spawn_virtio_thread;
 use crate::{
     EPOLL_HELPER_EVENT_LAST, EpollHelper, EpollHelperError, EpollHelperHandler, GuestMemoryMmap,
+    MmapRegion,
 };
 
+#[cfg(feature = "virgl")]
+pub mod virgl;
+
 /// Queue sizes
 const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 2;
@@ -46,6 +75,12 @@ pub const VIRTIO_GPU_F_EDID: u64 = 1;
 /// VIRGL 3D feature bit
 pub const VIRTIO_GPU_F_VIRGL: u64 = 2;
 
+/// Blob resources feature bit
+pub const VIRTIO_GPU_F_RESOURCE_BLOB: u64 = 3;
+
+/// Shared memory region id of the host-visible region
+pub const VIRTIO_GPU_SHM_ID_HOST_VISIBLE: u8 = 1;
+
 // VirtIO GPU commands
 const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
 const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
@@ -55,6 +90,15 @@ const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
 const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
 const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
 const VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+const VIRTIO_GPU_CMD_GET_CAPSET_INFO: u32 = 0x0108;
+const VIRTIO_GPU_CMD_GET_CAPSET: u32 = 0x0109;
+const VIRTIO_GPU_CMD_GET_EDID: u32 = 0x010a;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB: u32 = 0x010c;
+const VIRTIO_GPU_CMD_SET_SCANOUT_BLOB: u32 = 0x010d;
+
+// Cursor commands
+const VIRTIO_GPU_CMD_UPDATE_CURSOR: u32 = 0x0300;
+const VIRTIO_GPU_CMD_MOVE_CURSOR: u32 = 0x0301;
 
 // VIRGL 3D commands
 const VIRTIO_GPU_CMD_CTX_CREATE: u32 = 0x0200;
@@ -65,22 +109,61 @@ const VIRTIO_GPU_CMD_RESOURCE_CREATE_3D: u32 = 0x0204;
 const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_3D: u32 = 0x0205;
 const VIRTIO_GPU_CMD_TRANSFER_FROM_HOST_3D: u32 = 0x0206;
 const VIRTIO_GPU_CMD_SUBMIT_3D: u32 = 0x0207;
+const VIRTIO_GPU_CMD_RESOURCE_MAP_BLOB: u32 = 0x0208;
+const VIRTIO_GPU_CMD_RESOURCE_UNMAP_BLOB: u32 = 0x0209;
 
 // VirtIO GPU responses
 const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
 const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+const VIRTIO_GPU_RESP_OK_CAPSET_INFO: u32 = 0x1102;
+const VIRTIO_GPU_RESP_OK_CAPSET: u32 = 0x1103;
+const VIRTIO_GPU_RESP_OK_EDID: u32 = 0x1104;
+const VIRTIO_GPU_RESP_OK_MAP_INFO: u32 = 0x1106;
 const VIRTIO_GPU_RESP_ERR_UNSPEC: u32 = 0x1200;
 const VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
 const VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1202;
 const VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1203;
+const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
 
 // Response flags
 const VIRTIO_GPU_FLAG_FENCE: u32 = 1 << 0;
 
+/// Display configuration changed event
+pub const VIRTIO_GPU_EVENT_DISPLAY: u32 = 1 << 0;
+
+/// Size of the EDID blob carried by the GET_EDID response
+const VIRTIO_GPU_EDID_BLOB_SIZE: usize = 1024;
+
+/// Size of a base EDID block
+const EDID_BLOCK_SIZE: usize = 128;
+
+// Blob memory types
+const VIRTIO_GPU_BLOB_MEM_GUEST: u32 = 1;
+const VIRTIO_GPU_BLOB_MEM_HOST3D: u32 = 2;
+const VIRTIO_GPU_BLOB_MEM_HOST3D_GUEST: u32 = 3;
+
+// Blob flags
+const VIRTIO_GPU_BLOB_FLAG_USE_MAPPABLE: u32 = 1 << 0;
+
+// Caching of mapped blobs
+const VIRTIO_GPU_MAP_CACHE_CACHED: u32 = 1;
+
+/// Maximum number of scanouts defined by the VirtIO GPU specification
+pub const VIRTIO_GPU_MAX_SCANOUTS: u32 = 16;
+
+/// Number of capsets advertised when VIRGL is enabled
+#[cfg(feature = "virgl")]
+const NUM_CAPSETS: u32 = virgl::VIRGL_CAPSETS.len() as u32;
+#[cfg(not(feature = "virgl"))]
+const NUM_CAPSETS: u32 = 0;
+
 // Control queue event
 const CONTROL_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 // Cursor queue event
 const CURSOR_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// Renderer fences to retire
+#[cfg(feature = "virgl")]
+const FENCE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
 
 // Pixel formats
 const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 1;
@@ -115,6 +198,15 @@ pub enum Error {
     ResourceNotFound,
     #[error("Invalid format")]
     InvalidFormat,
+    #[error("Host blob resources require virglrenderer")]
+    HostBlobUnsupported,
+    #[error("Blob mapping does not fit in the host-visible region")]
+    InvalidBlobMapping,
+    #[error("Failed to map blob in the host-visible region")]
+    MapBlob(#[source] io::Error),
+    #[cfg(feature = "virgl")]
+    #[error("virglrenderer failure")]
+    Virgl(#[source] virgl::Error),
 }
 
 /// Command header
@@ -245,6 +337,181 @@ struct ResourceAttachBacking {
 // SAFETY: ResourceAttachBacking is POD
 unsafe impl ByteValued for ResourceAttachBacking {}
 
+/// Resource detach backing command
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ResourceDetachBacking {
+    header: CtrlHeader,
+    resource_id: u32,
+    padding: u32,
+}
+
+// SAFETY: ResourceDetachBacking is POD
+unsafe impl ByteValued for ResourceDetachBacking {}
+
+/// Get capset info command
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct GetCapsetInfo {
+    header: CtrlHeader,
+    capset_index: u32,
+    padding: u32,
+}
+
+// SAFETY: GetCapsetInfo is POD
+unsafe impl ByteValued for GetCapsetInfo {}
+
+/// Get capset info response
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct CapsetInfo {
+    header: CtrlHeader,
+    capset_id: u32,
+    capset_max_version: u32,
+    capset_max_size: u32,
+    padding: u32,
+}
+
+// SAFETY: CapsetInfo is POD
+unsafe impl ByteValued for CapsetInfo {}
+
+/// Get capset command
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct GetCapset {
+    header: CtrlHeader,
+    capset_id: u32,
+    capset_version: u32,
+}
+
+// SAFETY: GetCapset is POD
+unsafe impl ByteValued for GetCapset {}
+
+/// Get EDID command
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct GetEdid {
+    header: CtrlHeader,
+    scanout: u32,
+    padding: u32,
+}
+
+// SAFETY: GetEdid is POD and has no implicit padding
+unsafe impl ByteValued for GetEdid {}
+
+/// EDID response
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct RespEdid {
+    header: CtrlHeader,
+    size: u32,
+    padding: u32,
+    edid: [u8; VIRTIO_GPU_EDID_BLOB_SIZE],
+}
+
+// SAFETY: RespEdid is POD and has no implicit padding
+unsafe impl ByteValued for RespEdid {}
+
+/// Cursor position
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct CursorPos {
+    scanout_id: u32,
+    x: u32,
+    y: u32,
+    padding: u32,
+}
+
+// SAFETY: CursorPos is POD and has no implicit padding
+unsafe impl ByteValued for CursorPos {}
+
+/// Update cursor and move cursor command
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct UpdateCursor {
+    header: CtrlHeader,
+    pos: CursorPos,
+    resource_id: u32,
+    hot_x: u32,
+    hot_y: u32,
+    padding: u32,
+}
+
+// SAFETY: UpdateCursor is POD and has no implicit padding
+unsafe impl ByteValued for UpdateCursor {}
+
+/// Resource create blob command, followed by `nr_entries` memory entries
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ResourceCreateBlob {
+    header: CtrlHeader,
+    resource_id: u32,
+    blob_mem: u32,
+    blob_flags: u32,
+    nr_entries: u32,
+    blob_id: u64,
+    size: u64,
+}
+
+// SAFETY: ResourceCreateBlob is POD and has no implicit padding
+unsafe impl ByteValued for ResourceCreateBlob {}
+
+/// Set scanout blob command
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct SetScanoutBlob {
+    header: CtrlHeader,
+    r: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+    width: u32,
+    height: u32,
+    format: u32,
+    padding: u32,
+    strides: [u32; 4],
+    offsets: [u32; 4],
+}
+
+// SAFETY: SetScanoutBlob is POD and has no implicit padding
+unsafe impl ByteValued for SetScanoutBlob {}
+
+/// Resource map blob command
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ResourceMapBlob {
+    header: CtrlHeader,
+    resource_id: u32,
+    padding: u32,
+    offset: u64,
+}
+
+// SAFETY: ResourceMapBlob is POD and has no implicit padding
+unsafe impl ByteValued for ResourceMapBlob {}
+
+/// Resource map blob response
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct RespMapInfo {
+    header: CtrlHeader,
+    map_info: u32,
+    padding: u32,
+}
+
+// SAFETY: RespMapInfo is POD and has no implicit padding
+unsafe impl ByteValued for RespMapInfo {}
+
+/// Resource unmap blob command
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ResourceUnmapBlob {
+    header: CtrlHeader,
+    resource_id: u32,
+    padding: u32,
+}
+
+// SAFETY: ResourceUnmapBlob is POD and has no implicit padding
+unsafe impl ByteValued for ResourceUnmapBlob {}
+
 /// Memory entry for backing storage
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Default)]
@@ -278,6 +545,7 @@ unsafe impl ByteValued for Box3D {}
 struct CtxCreate {
     header: CtrlHeader,
     nctx: u32,  // Context name length
+    context_init: u32,
     context_name: [u8; 64],
 }
 
@@ -325,12 +593,14 @@ unsafe impl ByteValued for CtxDetachResource {}
 struct ResourceCreate3D {
     header: CtrlHeader,
     resource_id: u32,
+    target: u32,
     format: u32,
+    bind: u32,
     width: u32,
     height: u32,
     depth: u32,
-    target: u32,
-    bind: u32,
+    array_size: u32,
+    last_level: u32,
     nr_samples: u32,
     flags: u32,
     padding: u32,
@@ -344,12 +614,12 @@ unsafe impl ByteValued for ResourceCreate3D {}
 #[derive(Debug, Clone, Copy)]
 struct Transfer3D {
     header: CtrlHeader,
+    box_: Box3D,
+    offset: u64,
     resource_id: u32,
     level: u32,
     stride: u32,
     layer_stride: u32,
-    box_: Box3D,
-    offset: u64,
 }
 
 // SAFETY: Transfer3D is POD
@@ -361,6 +631,7 @@ unsafe impl ByteValued for Transfer3D {}
 struct Submit3D {
     header: CtrlHeader,
     size: u32,  // Size of command buffer in bytes
+    padding: u32,
 }
 
 // SAFETY: Submit3D is POD
@@ -380,16 +651,7 @@ struct Resource2D {
 
 impl Resource2D {
     fn new(width: u32, height: u32, format: u32) -> Result<Self, Error> {
-        let bytes_per_pixel = match format {
-            VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM
-            | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM
-            | VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM
-            | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM
-            | VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM
-            | VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM
-            | VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM => 4,
-            _ => return Err(Error::InvalidFormat),
-        };
+        let bytes_per_pixel = bytes_per_pixel(format).ok_or(Error::InvalidFormat)?;
 
         let size = (width as usize) * (height as usize) * (bytes_per_pixel as usize);
         Ok(Self {
@@ -402,6 +664,36 @@ impl Resource2D {
     }
 }
 
+/// Size of a pixel of a supported format
+fn bytes_per_pixel(format: u32) -> Option<u32> {
+    match format {
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM
+        | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM
+        | VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM
+        | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM
+        | VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM
+        | VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM
+        | VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM => Some(4),
+        _ => None,
+    }
+}
+
+/// Blob resource
+#[derive(Debug, Clone)]
+struct ResourceBlob {
+    /// Where the blob memory lives (VIRTIO_GPU_BLOB_MEM_*)
+    blob_mem: u32,
+    /// VIRTIO_GPU_BLOB_FLAG_* flags
+    blob_flags: u32,
+    /// Size in bytes
+    size: u64,
+    /// Guest pages backing the resource, shared with the frames published
+    /// from it
+    backing: Arc<[MemEntry]>,
+    /// Offset of the blob within the host-visible region, when mapped
+    mapped_offset: Option<u64>,
+}
+
 /// VIRGL 3D context
 #[derive(Debug, Clone)]
 struct VirglContext {
@@ -444,6 +736,8 @@ struct Resource3D {
     nr_samples: u32,
     /// Flags
     flags: u32,
+    /// Backing storage entries
+    backing: Vec<MemEntry>,
     /// Data buffer
     data: Vec<u8>,
 }
@@ -472,6 +766,7 @@ impl Resource3D {
             bind,
             nr_samples,
             flags,
+            backing: Vec::new(),
             data: vec![0u8; size],
         }
     }
@@ -481,15 +776,21 @@ impl Resource3D {
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct GpuConfig {
+    /// Pending events signaled to the driver
+    pub events_read: u32,
+    /// Events acknowledged by the driver
+    pub events_clear: u32,
     pub num_scanouts: u32,
-    pub reserved: u32,
+    pub num_capsets: u32,
 }
 
 impl Default for GpuConfig {
     fn default() -> Self {
         Self {
+            events_read: 0,
+            events_clear: 0,
             num_scanouts: 1, // Default to 1 scanout
-            reserved: 0,
+            num_capsets: 0,
         }
     }
 }
@@ -506,19 +807,492 @@ impl GpuConfig {
             )
         }
     }
+}
 
-    fn as_mut_slice(&mut self) -> &mut [u8] {
-        unsafe {
-            std::slice::from_raw_parts_mut(
-                self as *mut GpuConfig as *mut u8,
-                std::mem::size_of::<GpuConfig>(),
-            )
+/// Scanout state tracked by the control queue handler
+#[derive(Debug, Clone, Copy, Default)]
+struct Scanout {
+    /// Resource attached to the scanout, 0 when disabled
+    resource_id: u32,
+    /// Region of the resource displayed on the scanout
+    r: Rect,
+    /// Layout of the blob resource, for scanouts set with SET_SCANOUT_BLOB
+    blob: Option<BlobScanout>,
+}
+
+/// Layout of a blob resource displayed on a scanout
+#[derive(Debug, Clone, Copy, Default)]
+struct BlobScanout {
+    format: u32,
+    /// Bytes per row
+    stride: u32,
+    /// Offset of the first pixel within the blob
+    offset: u32,
+}
+
+/// Host-visible view of a flushed scanout
+#[derive(Debug, Clone, Default)]
+pub struct ScanoutFrame {
+    /// Frame width in pixels
+    pub width: u32,
+    /// Frame height in pixels
+    pub height: u32,
+    /// VirtIO GPU pixel format of the data
+    pub format: u32,
+    /// Bytes per row of the pixel data
+    pub stride: u32,
+    /// Pixel data, `stride * height` bytes once copied
+    pub pixels: ScanoutPixels,
+    /// Number of flushes published on this scanout
+    pub frame_number: u64,
+}
+
+/// Pixels of a flushed scanout
+#[derive(Debug, Clone)]
+pub enum ScanoutPixels {
+    /// Copy of the pixels, taken when the scanout was flushed
+    Owned(Vec<u8>),
+    /// Pixels left in the blob resource displayed on the scanout
+    Blob(BlobPixels),
+}
+
+impl Default for ScanoutPixels {
+    fn default() -> Self {
+        ScanoutPixels::Owned(Vec::new())
+    }
+}
+
+/// Blob resource memory a scanout is read from, only copied when the pixels
+/// are needed. The guest keeps drawing to the blob, a read returns its
+/// content at that time.
+#[derive(Clone)]
+pub struct BlobPixels {
+    source: BlobSource,
+    /// Offset of the first pixel of the scanout within the blob
+    offset: u64,
+    /// Bytes per row of the blob
+    stride: u64,
+}
+
+#[derive(Clone)]
+enum BlobSource {
+    /// Guest pages backing the blob
+    Guest {
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        backing: Arc<[MemEntry]>,
+    },
+    /// Blob mapped at `offset` within the host-visible region
+    HostVisible {
+        region: Arc<MmapRegion>,
+        offset: u64,
+    },
+}
+
+impl std::fmt::Debug for BlobPixels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let source = match self.source {
+            BlobSource::Guest { .. } => "guest",
+            BlobSource::HostVisible { .. } => "host-visible",
+        };
+        f.debug_struct("BlobPixels")
+            .field("source", &source)
+            .field("offset", &self.offset)
+            .field("stride", &self.stride)
+            .finish()
+    }
+}
+
+impl BlobPixels {
+    /// Copy `height` rows of `row_len` bytes
+    fn read(&self, row_len: usize, height: usize) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0u8; row_len * height];
+        if row_len == 0 {
+            return Ok(data);
+        }
+        for (row, dst) in data.chunks_exact_mut(row_len).enumerate() {
+            let offset = self.offset + row as u64 * self.stride;
+            match &self.source {
+                BlobSource::Guest { mem, backing } => {
+                    read_backing(&*mem.memory(), backing, offset, dst)?;
+                }
+                BlobSource::HostVisible {
+                    region,
+                    offset: mapped_offset,
+                } => read_host_visible(region, mapped_offset + offset, dst)?,
+            }
+        }
+        Ok(data)
+    }
+}
+
+/// Host-visible state of the cursor, as set through the cursor queue
+#[derive(Debug, Clone, Default)]
+pub struct CursorState {
+    /// Scanout the cursor is displayed on
+    pub scanout_id: u32,
+    /// Cursor X position in pixels
+    pub x: u32,
+    /// Cursor Y position in pixels
+    pub y: u32,
+    /// Whether a cursor image is displayed
+    pub visible: bool,
+    /// Cursor width in pixels
+    pub width: u32,
+    /// Cursor height in pixels
+    pub height: u32,
+    /// Cursor hotspot X offset
+    pub hot_x: u32,
+    /// Cursor hotspot Y offset
+    pub hot_y: u32,
+    /// VirtIO GPU pixel format of the data
+    pub format: u32,
+    /// Pixel data, `width * height * 4` bytes
+    pub data: Vec<u8>,
+    /// Number of cursor updates and moves
+    pub update_count: u32,
+}
+
+impl ScanoutFrame {
+    /// Name of the pixel format, using the frame buffer naming convention
+    pub fn format_name(&self) -> &'static str {
+        format_name(self.format)
+    }
+
+    /// Take the pixel data, `stride * height` bytes, copying it out of the
+    /// blob resource of the scanout if needed. Returns `None` if the blob
+    /// can't be read anymore.
+    pub fn into_data(self) -> Option<Vec<u8>> {
+        match self.pixels {
+            ScanoutPixels::Owned(data) => Some(data),
+            ScanoutPixels::Blob(blob) => blob
+                .read(self.stride as usize, self.height as usize)
+                .map_err(|e| error!("Failed to read blob scanout: {e:?}"))
+                .ok(),
+        }
+    }
+}
+
+/// Map a VirtIO GPU pixel format to the name used by the frame-info API.
+///
+/// The name describes the byte order in memory.
+pub fn format_name(format: u32) -> &'static str {
+    match format {
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM => "BGRA32",
+        VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM => "ARGB32",
+        VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM => "RGBA32",
+        VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM | VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM => "ABGR32",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Generate the EDID 1.4 base block of a display.
+///
+/// The preferred timing is the requested resolution at 60Hz with reduced
+/// blanking. The serial number is derived from the scanout, so each display
+/// is reported as a distinct monitor.
+fn generate_edid(scanout_id: u32, width: u32, height: u32) -> [u8; EDID_BLOCK_SIZE] {
+    const H_BLANK: u32 = 160;
+    const H_FRONT_PORCH: u32 = 48;
+    const H_SYNC: u32 = 32;
+    const V_BLANK: u32 = 35;
+    const V_FRONT_PORCH: u32 = 3;
+    const V_SYNC: u32 = 5;
+
+    let mut edid = [0u8; EDID_BLOCK_SIZE];
+    edid[0..8].copy_from_slice(&[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]);
+
+    // Manufacturer "CHV", big endian, 5 bits per letter
+    let vendor = b"CHV"
+        .iter()
+        .fold(0u16, |id, c| (id << 5) | u16::from(c - b'@'));
+    edid[8..10].copy_from_slice(&vendor.to_be_bytes());
+    edid[10..12].copy_from_slice(&(scanout_id as u16).to_le_bytes());
+    edid[12..16].copy_from_slice(&(scanout_id + 1).to_le_bytes());
+    // Model year 2024, EDID 1.4
+    edid[16] = 0xff;
+    edid[17] = 34;
+    edid[18] = 1;
+    edid[19] = 4;
+    // Digital input, 8 bits per color, DisplayPort
+    edid[20] = 0xa5;
+
+    // Physical size assuming 96 DPI
+    let width_mm = width * 254 / 960;
+    let height_mm = height * 254 / 960;
+    edid[21] = (width_mm / 10).min(255) as u8;
+    edid[22] = (height_mm / 10).min(255) as u8;
+    // Gamma 2.2, sRGB default color space, preferred timing is native
+    edid[23] = 120;
+    edid[24] = 0x06;
+    // sRGB chromaticity coordinates
+    edid[25..35].copy_from_slice(&[0xee, 0x91, 0xa3, 0x54, 0x4c, 0x99, 0x26, 0x0f, 0x50, 0x54]);
+    // No established timings, unused standard timings
+    edid[38..54].fill(0x01);
+
+    // Preferred detailed timing, the DTD fields are 12 bits wide
+    let h_active = width.min(0xfff);
+    let v_active = height.min(0xfff);
+    let pixel_clock = (h_active + H_BLANK) * (v_active + V_BLANK) * 60 / 10_000;
+    let dtd = &mut edid[54..72];
+    dtd[0..2].copy_from_slice(&(pixel_clock.min(0xffff) as u16).to_le_bytes());
+    dtd[2] = h_active as u8;
+    dtd[3] = H_BLANK as u8;
+    dtd[4] = (((h_active >> 8) << 4) | (H_BLANK >> 8)) as u8;
+    dtd[5] = v_active as u8;
+    dtd[6] = V_BLANK as u8;
+    dtd[7] = (((v_active >> 8) << 4) | (V_BLANK >> 8)) as u8;
+    dtd[8] = H_FRONT_PORCH as u8;
+    dtd[9] = H_SYNC as u8;
+    dtd[10] = ((V_FRONT_PORCH << 4) | V_SYNC) as u8;
+    dtd[11] = 0;
+    let (width_mm, height_mm) = (width_mm.min(0xfff), height_mm.min(0xfff));
+    dtd[12] = width_mm as u8;
+    dtd[13] = height_mm as u8;
+    dtd[14] = (((width_mm >> 8) << 4) | (height_mm >> 8)) as u8;
+    // Digital separate sync, positive polarities
+    dtd[17] = 0x1e;
+
+    // Monitor name, terminated by a line feed and padded with spaces
+    let name = format!("CHV-GPU-{scanout_id}");
+    let desc = &mut edid[72..90];
+    desc[3] = 0xfc;
+    desc[5..].fill(b' ');
+    desc[5..5 + name.len()].copy_from_slice(name.as_bytes());
+    desc[5 + name.len()] = b'\n';
+
+    // Dummy descriptors
+    edid[93] = 0x10;
+    edid[111] = 0x10;
+
+    let sum = edid[..EDID_BLOCK_SIZE - 1]
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b));
+    edid[EDID_BLOCK_SIZE - 1] = sum.wrapping_neg();
+    edid
+}
+
+/// Read `buf.len()` bytes starting at `offset` within the guest backing pages.
+fn read_backing<M: GuestMemory>(
+    mem: &M,
+    backing: &[MemEntry],
+    mut offset: u64,
+    buf: &mut [u8],
+) -> Result<(), Error> {
+    let mut done = 0usize;
+    for entry in backing {
+        if done == buf.len() {
+            break;
+        }
+        let length = u64::from(entry.length);
+        if offset >= length {
+            offset -= length;
+            continue;
+        }
+        let count = std::cmp::min((length - offset) as usize, buf.len() - done);
+        mem.read_slice(
+            &mut buf[done..done + count],
+            GuestAddress(entry.addr + offset),
+        )
+        .map_err(Error::GuestMemory)?;
+        done += count;
+        offset = 0;
+    }
+
+    if done == buf.len() {
+        Ok(())
+    } else {
+        Err(Error::InvalidRequest)
+    }
+}
+
+/// Read `nr_entries` memory entries stored at `addr`.
+fn read_mem_entries<M: GuestMemory>(mem: &M, addr: GuestAddress, nr_entries: u32) -> Vec<MemEntry> {
+    let mut entries = Vec::new();
+    for i in 0..nr_entries as usize {
+        let offset = i * std::mem::size_of::<MemEntry>();
+        if let Some(addr) = addr.checked_add(offset as u64)
+            && let Ok(entry) = mem.read_obj::<MemEntry>(addr)
+        {
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+/// Write `buf` starting at `offset` within the guest backing pages.
+fn write_backing<M: GuestMemory>(
+    mem: &M,
+    backing: &[MemEntry],
+    mut offset: u64,
+    buf: &[u8],
+) -> Result<(), Error> {
+    let mut done = 0usize;
+    for entry in backing {
+        if done == buf.len() {
+            break;
+        }
+        let length = u64::from(entry.length);
+        if offset >= length {
+            offset -= length;
+            continue;
+        }
+        let count = std::cmp::min((length - offset) as usize, buf.len() - done);
+        mem.write_slice(&buf[done..done + count], GuestAddress(entry.addr + offset))
+            .map_err(Error::GuestMemory)?;
+        done += count;
+        offset = 0;
+    }
+
+    if done == buf.len() {
+        Ok(())
+    } else {
+        Err(Error::InvalidRequest)
+    }
+}
+
+/// Map the guest backing pages of a resource for virglrenderer.
+#[cfg(feature = "virgl")]
+fn backing_iovecs<M: GuestMemory>(
+    mem: &M,
+    backing: &[MemEntry],
+) -> Result<Vec<libc::iovec>, Error> {
+    backing
+        .iter()
+        .map(|entry| {
+            let slice = mem
+                .get_slice(GuestAddress(entry.addr), entry.length as usize)
+                .map_err(Error::GuestMemory)?;
+            Ok(libc::iovec {
+                iov_base: slice.ptr_guard_mut().as_ptr() as *mut libc::c_void,
+                iov_len: slice.len(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(feature = "virgl")]
+impl From<Box3D> for virgl::VirglBox {
+    fn from(b: Box3D) -> Self {
+        virgl::VirglBox {
+            x: b.x,
+            y: b.y,
+            z: b.z,
+            w: b.w,
+            h: b.h,
+            d: b.d,
         }
     }
 }
 
+/// Copy the `r` region out of a 4 bytes per pixel buffer of `src_stride` bytes per row.
+fn copy_rect(src: &[u8], src_stride: usize, r: &Rect) -> Vec<u8> {
+    let stride = r.width as usize * 4;
+    let mut data = Vec::with_capacity(stride * r.height as usize);
+    for row in 0..r.height as usize {
+        let start = (r.y as usize + row) * src_stride + r.x as usize * 4;
+        data.extend_from_slice(&src[start..start + stride]);
+    }
+    data
+}
+
+/// Publish a flushed frame for `scanout_id`.
+fn publish_frame(
+    frames: &Mutex<Vec<Option<ScanoutFrame>>>,
+    scanout_id: usize,
+    r: &Rect,
+    format: u32,
+    pixels: ScanoutPixels,
+) {
+    let mut frames = frames.lock().unwrap();
+    let frame = &mut frames[scanout_id];
+    let frame_number = frame.as_ref().map_or(0, |f| f.frame_number) + 1;
+    *frame = Some(ScanoutFrame {
+        width: r.width,
+        height: r.height,
+        format,
+        stride: r.width * 4,
+        pixels,
+        frame_number,
+    });
+}
+
+/// Read `buf.len()` bytes at `offset` within the host-visible region.
+fn read_host_visible(region: &MmapRegion, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+    let slice = region
+        .get_slice(offset as usize, buf.len())
+        .map_err(|_| Error::InvalidBlobMapping)?;
+    slice.copy_to(buf);
+    Ok(())
+}
+
+/// Map `size` bytes of `fd` at `offset` within the host-visible region.
+fn map_into_region(region: &MmapRegion, offset: u64, size: u64, fd: RawFd) -> Result<(), Error> {
+    // SAFETY: sysconf has no side effect.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let size = size.next_multiple_of(page_size);
+    if !offset.is_multiple_of(page_size)
+        || offset
+            .checked_add(size)
+            .is_none_or(|end| end > region.size() as u64)
+    {
+        return Err(Error::InvalidBlobMapping);
+    }
+
+    // SAFETY: The range lies within the region, whose pages are replaced by
+    // the blob pages. KVM picks up the new pages through the existing slot.
+    let ret = unsafe {
+        libc::mmap(
+            region.as_ptr().add(offset as usize) as *mut libc::c_void,
+            size as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_FIXED,
+            fd,
+            0,
+        )
+    };
+    if ret == libc::MAP_FAILED {
+        return Err(Error::MapBlob(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+/// Replace the blob mapped at `offset` within the host-visible region with
+/// anonymous memory.
+fn unmap_from_region(region: &MmapRegion, offset: u64, size: u64) -> Result<(), Error> {
+    // SAFETY: sysconf has no side effect.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let size = size.next_multiple_of(page_size);
+    if offset
+        .checked_add(size)
+        .is_none_or(|end| end > region.size() as u64)
+    {
+        return Err(Error::InvalidBlobMapping);
+    }
+
+    // SAFETY: The range lies within the region and was previously mapped
+    // with map_into_region().
+    let ret = unsafe {
+        libc::mmap(
+            region.as_ptr().add(offset as usize) as *mut libc::c_void,
+            size as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_FIXED,
+            -1,
+            0,
+        )
+    };
+    if ret == libc::MAP_FAILED {
+        return Err(Error::MapBlob(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
 /// VirtIO GPU device
 pub struct Gpu {
+    /// Device identifier
+    id: String,
     /// Common virtio device data
     common: VirtioCommon,
     /// GPU configuration
@@ -527,14 +1301,24 @@ pub struct Gpu {
     display_width: u32,
     /// Display height
     display_height: u32,
+    /// Display connected to each scanout
+    displays: Arc<Mutex<Vec<DisplayOne>>>,
     /// 2D resources
     resources: Arc<Mutex<HashMap<u32, Resource2D>>>,
     /// VIRGL contexts
     virgl_contexts: Arc<Mutex<HashMap<u32, VirglContext>>>,
     /// 3D resources
     resources_3d: Arc<Mutex<HashMap<u32, Resource3D>>>,
+    /// Blob resources
+    resources_blob: Arc<Mutex<HashMap<u32, ResourceBlob>>>,
     /// VIRGL feature enabled
     virgl_enabled: bool,
+    /// Host-visible region mappable blobs are placed in
+    host_visible: Option<VirtioSharedMemoryList>,
+    /// Flushed scanouts published to the host, indexed by scanout id
+    scanout_frames: Arc<Mutex<Vec<Option<ScanoutFrame>>>>,
+    /// Cursor set by the guest
+    cursor: Arc<Mutex<CursorState>>,
     /// Seccomp action
     seccomp_action: SeccompAction,
     /// Exit event
@@ -562,31 +1346,94 @@ impl Gpu {
         seccomp_action: SeccompAction,
         exit_evt: EventFd,
     ) -> io::Result<Self> {
-        let mut avail_features = 1u64 << VIRTIO_GPU_F_EDID;
-        if virgl_enabled {
-            avail_features |= 1u64 << VIRTIO_GPU_F_VIRGL;
-        }
-
-        Ok(Self {
-            common: VirtioCommon {
-                device_type: VirtioDeviceType::Gpu as u32,
-                queue_sizes: QUEUE_SIZES.to_vec(),
-                avail_features,
-                paused_sync: Some(Arc::new(Barrier::new(2))),
-                min_queues: NUM_QUEUES as u16,
-                ..Default::default()
-            },
-            config: Arc::new(Mutex::new(GpuConfig::default())),
+        Self::new_with_config(
+            "virtio-gpu".to_string(),
             display_width,
             display_height,
-            resources: Arc::new(Mutex::new(HashMap::new())),
-            virgl_contexts: Arc::new(Mutex::new(HashMap::new())),
-            resources_3d: Arc::new(Mutex::new(HashMap::new())),
+            1,
+            true,
             virgl_enabled,
             seccomp_action,
             exit_evt,
-            interrupt_cb: None,
-        })
+        )
+    }
+
+    /// Create a new VirtIO GPU device exposing `max_outputs` scanouts
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_config(
+        id: String,
+        display_width: u32,
+        display_height: u32,
+        max_outputs: u32,
+        edid: bool,
+        virgl_enabled: bool,
+        seccomp_action: SeccompAction,
+        exit_evt: EventFd,
+    ) -> io::Result<Self> {
+        if max_outputs == 0 || max_outputs > VIRTIO_GPU_MAX_SCANOUTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid number of GPU outputs: {max_outputs}"),
+            ));
+        }
+
+        let mut avail_features = 0u64;
+        if edid {
+            avail_features |= 1u64 << VIRTIO_GPU_F_EDID;
+        }
+        if virgl_enabled {
+            avail_features |= 1u64 << VIRTIO_GPU_F_VIRGL;
+        }
+
+        // Only the first display is connected, the other scanouts are
+        // available to the guest but report no display until connected.
+        let mut displays = vec![
+            DisplayOne {
+                r: Rect {
+                    x: 0,
+                    y: 0,
+                    width: display_width,
+                    height: display_height,
+                },
+                enabled: 0,
+                flags: 0,
+            };
+            max_outputs as usize
+        ];
+        displays[0].enabled = 1;
+
+        let config = GpuConfig {
+            num_scanouts: max_outputs,
+            num_capsets: if virgl_enabled { NUM_CAPSETS } else { 0 },
+            ..Default::default()
+        };
+
+        Ok(Self {
+            id,
+            common: VirtioCommon {
+                device_type: VirtioDeviceType::Gpu as u32,
+                queue_sizes: QUEUE_SIZES.to_vec(),
+                avail_features,
+                paused_sync: Some(Arc::new(Barrier::new(2))),
+                min_queues: NUM_QUEUES as u16,
+                ..Default::default()
+            },
+            config: Arc::new(Mutex::new(config)),
+            display_width,
+            display_height,
+            displays: Arc::new(Mutex::new(displays)),
+            resources: Arc::new(Mutex::new(HashMap::new())),
+            virgl_contexts: Arc::new(Mutex::new(HashMap::new())),
+            resources_3d: Arc::new(Mutex::new(HashMap::new())),
+            resources_blob: Arc::new(Mutex::new(HashMap::new())),
+            virgl_enabled,
+            host_visible: None,
+            scanout_frames: Arc::new(Mutex::new(vec![None; max_outputs as usize])),
+            cursor: Arc::new(Mutex::new(CursorState::default())),
+            seccomp_action,
+            exit_evt,
+            interrupt_cb: None,
+        })
     }
 
     /// Get display dimensions
@@ -598,6 +1445,106 @@ impl Gpu {
     pub fn is_virgl_enabled(&self) -> bool {
         self.virgl_enabled
     }
+
+    /// Offer blob resources to the guest
+    ///
+    /// Mappable blobs can only be mapped when `host_visible` describes the
+    /// host-visible shared memory region.
+    pub fn enable_resource_blob(&mut self, host_visible: Option<VirtioSharedMemoryList>) {
+        self.common.avail_features |= 1u64 << VIRTIO_GPU_F_RESOURCE_BLOB;
+        self.host_visible = host_visible;
+    }
+
+    /// Number of scanouts exposed to the guest
+    pub fn num_scanouts(&self) -> u32 {
+        self.config.lock().unwrap().num_scanouts
+    }
+
+    /// Connect a display with the resolution `mode` to `scanout_id`, or
+    /// disconnect it when `mode` is `None`
+    ///
+    /// Once the device is activated, the guest is notified through the
+    /// VIRTIO_GPU_EVENT_DISPLAY event.
+    pub fn set_display(&self, scanout_id: u32, mode: Option<(u32, u32)>) -> io::Result<()> {
+        {
+            let mut displays = self.displays.lock().unwrap();
+            let Some(display) = displays.get_mut(scanout_id as usize) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid scanout: {scanout_id}"),
+                ));
+            };
+
+            match mode {
+                Some((0, _)) | Some((_, 0)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Invalid display resolution",
+                    ));
+                }
+                Some((width, height)) => {
+                    display.r.width = width;
+                    display.r.height = height;
+                    display.enabled = 1;
+                }
+                None => display.enabled = 0,
+            }
+        }
+
+        // A disconnected display no longer has any content to capture
+        if mode.is_none()
+            && let Some(frame) = self
+                .scanout_frames
+                .lock()
+                .unwrap()
+                .get_mut(scanout_id as usize)
+        {
+            *frame = None;
+        }
+
+        if let Some(interrupt_cb) = self.interrupt_cb.as_ref() {
+            self.config.lock().unwrap().events_read |= VIRTIO_GPU_EVENT_DISPLAY;
+            interrupt_cb.trigger(super::VirtioInterruptType::Config)?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolution of the display connected to each scanout, `None` for
+    /// scanouts without a display
+    pub fn displays(&self) -> Vec<Option<(u32, u32)>> {
+        self.displays
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|d| (d.enabled != 0).then_some((d.r.width, d.r.height)))
+            .collect()
+    }
+
+    /// Get a copy of the last frame flushed on `scanout_id`
+    pub fn scanout_frame(&self, scanout_id: u32) -> Option<ScanoutFrame> {
+        self.scanout_frames
+            .lock()
+            .unwrap()
+            .get(scanout_id as usize)
+            .cloned()
+            .flatten()
+    }
+
+    /// Get `(width, height, format, frame_number)` of the last frame flushed
+    /// on `scanout_id` without copying the pixel data
+    pub fn scanout_frame_info(&self, scanout_id: u32) -> Option<(u32, u32, u32, u64)> {
+        let frames = self.scanout_frames.lock().unwrap();
+        frames
+            .get(scanout_id as usize)?
+            .as_ref()
+            .map(|f| (f.width, f.height, f.format, f.frame_number))
+    }
+
+    /// Get a copy of the cursor set by the guest
+    pub fn cursor(&self) -> CursorState {
+        self.cursor.lock().unwrap().clone()
+    }
 }
 
 impl Drop for Gpu {
@@ -610,6 +1557,14 @@ impl Drop for Gpu {
     }
 }
 
+/// Control queue descriptor returned once the fence of its command retired
+#[cfg(feature = "virgl")]
+struct PendingFence {
+    fence: u32,
+    head_index: u16,
+    len: u32,
+}
+
 /// GPU epoll handler
 struct GpuEpollHandler {
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
@@ -622,9 +1577,21 @@ struct GpuEpollHandler {
     resources: Arc<Mutex<HashMap<u32, Resource2D>>>,
     virgl_contexts: Arc<Mutex<HashMap<u32, VirglContext>>>,
     resources_3d: Arc<Mutex<HashMap<u32, Resource3D>>>,
-    display_width: u32,
-    display_height: u32,
+    resources_blob: Arc<Mutex<HashMap<u32, ResourceBlob>>>,
+    host_visible: Option<Arc<MmapRegion>>,
+    displays: Arc<Mutex<Vec<DisplayOne>>>,
+    /// VIRTIO_GPU_F_EDID negotiated
+    edid: bool,
     virgl_enabled: bool,
+    scanouts: Vec<Scanout>,
+    scanout_frames: Arc<Mutex<Vec<Option<ScanoutFrame>>>>,
+    cursor: Arc<Mutex<CursorState>>,
+    /// virglrenderer instance, created on the epoll thread
+    #[cfg(feature = "virgl")]
+    renderer: Option<virgl::VirglRenderer>,
+    /// Fenced commands waiting for the renderer, in fence order
+    #[cfg(feature = "virgl")]
+    pending_fences: VecDeque<PendingFence>,
 }
 
 impl GpuEpollHandler {
@@ -648,17 +1615,13 @@ impl GpuEpollHandler {
 
     /// Handle GET_DISPLAY_INFO command
     fn handle_get_display_info(&self) -> DisplayInfo {
-        let mut pmodes = [DisplayOne::default(); 16];
-        pmodes[0] = DisplayOne {
-            r: Rect {
-                x: 0,
-                y: 0,
-                width: self.display_width,
-                height: self.display_height,
-            },
-            enabled: 1,
-            flags: 0,
-        };
+        let mut pmodes = [DisplayOne::default(); VIRTIO_GPU_MAX_SCANOUTS as usize];
+        let displays = self.displays.lock().expect("Failed to lock displays mutex: another thread panicked while holding the lock");
+        for (pmode, display) in pmodes.iter_mut().zip(displays.iter()) {
+            if display.enabled != 0 {
+                *pmode = *display;
+            }
+        }
 
         DisplayInfo {
             header: Self::create_response_header(VIRTIO_GPU_RESP_OK_DISPLAY_INFO),
@@ -666,6 +1629,32 @@ impl GpuEpollHandler {
         }
     }
 
+    /// Handle GET_EDID command
+    fn handle_get_edid(&self, cmd: &GetEdid) -> Result<RespEdid, CtrlHeader> {
+        if !self.edid {
+            return Err(Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC));
+        }
+
+        let displays = self.displays.lock().expect("Failed to lock displays mutex: another thread panicked while holding the lock");
+        let Some(display) = displays.get(cmd.scanout as usize) else {
+            return Err(Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID));
+        };
+
+        let mut edid = [0u8; VIRTIO_GPU_EDID_BLOB_SIZE];
+        edid[..EDID_BLOCK_SIZE].copy_from_slice(&generate_edid(
+            cmd.scanout,
+            display.r.width,
+            display.r.height,
+        ));
+
+        Ok(RespEdid {
+            header: Self::create_response_header(VIRTIO_GPU_RESP_OK_EDID),
+            size: EDID_BLOCK_SIZE as u32,
+            padding: 0,
+            edid,
+        })
+    }
+
     /// Handle RESOURCE_CREATE_2D command
     fn handle_resource_create_2d(&mut self, cmd: &ResourceCreate2D) -> CtrlHeader {
         if cmd.resource_id == 0 {
@@ -692,18 +1681,80 @@ impl GpuEpollHandler {
         }
 
         let mut resources = self.resources.lock().expect("Failed to lock resources mutex: another thread panicked while holding the lock");
-        resources.remove(&cmd.resource_id);
+        if resources.remove(&cmd.resource_id).is_none() {
+            let mut resources_3d = self.resources_3d.lock().expect("Failed to lock resources_3d mutex: another thread panicked while holding the lock");
+            let mut resources_blob = self.resources_blob.lock().expect("Failed to lock resources_blob mutex: another thread panicked while holding the lock");
+            let mut host_resource = resources_3d.remove(&cmd.resource_id).is_some();
+            if let Some(resource) = resources_blob.remove(&cmd.resource_id) {
+                if let (Some(offset), Some(region)) = (resource.mapped_offset, &self.host_visible)
+                    && let Err(e) = unmap_from_region(region, offset, resource.size)
+                {
+                    error!("Failed to unmap blob {}: {e:?}", cmd.resource_id);
+                }
+                host_resource = resource.blob_mem != VIRTIO_GPU_BLOB_MEM_GUEST;
+            }
+            #[cfg(feature = "virgl")]
+            if host_resource && let Some(renderer) = self.renderer.as_mut() {
+                renderer.unref_resource(cmd.resource_id);
+            }
+            #[cfg(not(feature = "virgl"))]
+            let _ = host_resource;
+        }
+
+        for scanout in self.scanouts.iter_mut() {
+            if scanout.resource_id == cmd.resource_id {
+                *scanout = Scanout::default();
+            }
+        }
+
         Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
     }
 
+    /// Disable a scanout and drop its last published frame
+    fn disable_scanout(&mut self, scanout_id: usize) {
+        self.scanouts[scanout_id] = Scanout::default();
+        if let Some(frame) = self.scanout_frames.lock().unwrap().get_mut(scanout_id) {
+            *frame = None;
+        }
+    }
+
     /// Handle SET_SCANOUT command
     fn handle_set_scanout(&mut self, cmd: &SetScanout) -> CtrlHeader {
-        if cmd.scanout_id >= 16 {
+        let scanout_id = cmd.scanout_id as usize;
+        if scanout_id >= self.scanouts.len() {
             return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID);
         }
 
-        // For now, we just accept the scanout configuration
-        // In a full implementation, this would configure the display output
+        // A null resource disables the scanout
+        if cmd.resource_id == 0 {
+            self.disable_scanout(scanout_id);
+            return Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA);
+        }
+
+        let resources = self.resources.lock().expect("Failed to lock resources mutex: another thread panicked while holding the lock");
+        let resources_3d = self.resources_3d.lock().expect("Failed to lock resources_3d mutex: another thread panicked while holding the lock");
+        let (width, height) = if let Some(resource) = resources.get(&cmd.resource_id) {
+            (resource.width, resource.height)
+        } else if let Some(resource) = resources_3d.get(&cmd.resource_id) {
+            (resource.width, resource.height)
+        } else {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+        };
+
+        // The displayed region must lie within the resource
+        if cmd.r.width == 0
+            || cmd.r.height == 0
+            || u64::from(cmd.r.x) + u64::from(cmd.r.width) > u64::from(width)
+            || u64::from(cmd.r.y) + u64::from(cmd.r.height) > u64::from(height)
+        {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+
+        self.scanouts[scanout_id] = Scanout {
+            resource_id: cmd.resource_id,
+            r: cmd.r,
+            blob: None,
+        };
         Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
     }
 
@@ -720,10 +1771,73 @@ impl GpuEpollHandler {
         let mut resources = self.resources.lock().expect("Failed to lock resources mutex: another thread panicked while holding the lock");
         if let Some(resource) = resources.get_mut(&cmd.resource_id) {
             resource.backing = entries;
-            Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
-        } else {
-            Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)
+            return Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA);
+        }
+
+        let mut resources_blob = self.resources_blob.lock().expect("Failed to lock resources_blob mutex: another thread panicked while holding the lock");
+        if let Some(resource) = resources_blob.get_mut(&cmd.resource_id) {
+            if resource.blob_mem != VIRTIO_GPU_BLOB_MEM_GUEST {
+                return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+            }
+            resource.backing = entries.into();
+            return Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA);
+        }
+
+        let mut resources_3d = self.resources_3d.lock().expect("Failed to lock resources_3d mutex: another thread panicked while holding the lock");
+        let Some(resource) = resources_3d.get_mut(&cmd.resource_id) else {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+        };
+
+        #[cfg(feature = "virgl")]
+        if let Some(renderer) = self.renderer.as_mut() {
+            let iovecs = match backing_iovecs(&*self.mem.memory(), &entries) {
+                Ok(iovecs) => iovecs,
+                Err(e) => {
+                    error!("Invalid backing for resource {}: {e:?}", cmd.resource_id);
+                    return Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC);
+                }
+            };
+            // SAFETY: Guest RAM stays mapped while the device is active and
+            // the backing is detached when the resource is released.
+            if let Err(e) = unsafe { renderer.attach_backing(cmd.resource_id, iovecs) } {
+                error!("Failed to attach backing to resource {}: {e}", cmd.resource_id);
+                return Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC);
+            }
+        }
+
+        resource.backing = entries;
+        Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
+    }
+
+    /// Handle RESOURCE_DETACH_BACKING command
+    fn handle_resource_detach_backing(&mut self, cmd: &ResourceDetachBacking) -> CtrlHeader {
+        let mut resources = self.resources.lock().expect("Failed to lock resources mutex: another thread panicked while holding the lock");
+        if let Some(resource) = resources.get_mut(&cmd.resource_id) {
+            resource.backing.clear();
+            return Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA);
+        }
+
+        let mut resources_blob = self.resources_blob.lock().expect("Failed to lock resources_blob mutex: another thread panicked while holding the lock");
+        if let Some(resource) = resources_blob.get_mut(&cmd.resource_id) {
+            if resource.blob_mem != VIRTIO_GPU_BLOB_MEM_GUEST {
+                return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+            }
+            resource.backing = Arc::default();
+            return Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA);
+        }
+
+        let mut resources_3d = self.resources_3d.lock().expect("Failed to lock resources_3d mutex: another thread panicked while holding the lock");
+        let Some(resource) = resources_3d.get_mut(&cmd.resource_id) else {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+        };
+        resource.backing.clear();
+
+        #[cfg(feature = "virgl")]
+        if let Some(renderer) = self.renderer.as_mut() {
+            renderer.detach_backing(cmd.resource_id);
         }
+
+        Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
     }
 
     /// Handle TRANSFER_TO_HOST_2D command
@@ -737,32 +1851,35 @@ impl GpuEpollHandler {
         }
 
         let mut resources = self.resources.lock().expect("Failed to lock resources mutex: another thread panicked while holding the lock");
-        if let Some(resource) = resources.get_mut(&cmd.resource_id) {
-            // Copy data from guest memory to resource buffer
-            if !resource.backing.is_empty() {
-                let backing = &resource.backing[0];
-                let src_addr = GuestAddress(backing.addr + cmd.offset);
-                let dst_start = (cmd.r.y as usize * resource.width as usize + cmd.r.x as usize) * 4;
-                let bytes_to_copy = std::cmp::min(
-                    (cmd.r.width * cmd.r.height * 4) as usize,
-                    resource.data.len().saturating_sub(dst_start),
-                );
+        let Some(resource) = resources.get_mut(&cmd.resource_id) else {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+        };
 
-                // Read from guest memory
-                if bytes_to_copy > 0 && dst_start + bytes_to_copy <= resource.data.len() {
-                    if mem
-                        .read_slice(&mut resource.data[dst_start..dst_start + bytes_to_copy], src_addr)
-                        .is_err()
-                    {
-                        // Log read error but continue processing
-                        error!("Failed to read from guest memory during TRANSFER_TO_HOST_2D");
-                    }
-                }
+        if u64::from(cmd.r.x) + u64::from(cmd.r.width) > u64::from(resource.width)
+            || u64::from(cmd.r.y) + u64::from(cmd.r.height) > u64::from(resource.height)
+        {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+
+        // Copy the rectangle row by row, the backing store being laid out
+        // with the full resource stride.
+        let stride = resource.width as usize * 4;
+        let row_len = cmd.r.width as usize * 4;
+        for row in 0..cmd.r.height as usize {
+            let src_offset = cmd.offset + (row * stride) as u64;
+            let dst_start = (cmd.r.y as usize + row) * stride + cmd.r.x as usize * 4;
+            if let Err(e) = read_backing(
+                mem,
+                &resource.backing,
+                src_offset,
+                &mut resource.data[dst_start..dst_start + row_len],
+            ) {
+                error!("Failed to read from guest memory during TRANSFER_TO_HOST_2D: {e:?}");
+                return Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC);
             }
-            Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
-        } else {
-            Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)
         }
+
+        Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
     }
 
     /// Handle RESOURCE_FLUSH command
@@ -771,8 +1888,313 @@ impl GpuEpollHandler {
             return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
         }
 
-        // In a full implementation, this would trigger a redraw of the specified region
-        // For now, we just acknowledge the flush
+        let resources = self.resources.lock().expect("Failed to lock resources mutex: another thread panicked while holding the lock");
+        let Some(resource) = resources.get(&cmd.resource_id) else {
+            drop(resources);
+            return self.flush_resource_blob(cmd.resource_id);
+        };
+
+        // Publish every scanout displaying this resource
+        let src_stride = resource.width as usize * 4;
+        for (scanout_id, scanout) in self.scanouts.iter().enumerate() {
+            if scanout.resource_id != cmd.resource_id {
+                continue;
+            }
+
+            let data = copy_rect(&resource.data, src_stride, &scanout.r);
+            publish_frame(
+                &self.scanout_frames,
+                scanout_id,
+                &scanout.r,
+                resource.format,
+                ScanoutPixels::Owned(data),
+            );
+        }
+
+        Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
+    }
+
+    /// Publish the scanouts displaying a blob resource
+    ///
+    /// The pixels are read straight from the blob memory, no transfer is
+    /// needed beforehand.
+    fn flush_resource_blob(&mut self, resource_id: u32) -> CtrlHeader {
+        let resources_blob = self.resources_blob.lock().expect("Failed to lock resources_blob mutex: another thread panicked while holding the lock");
+        let Some(resource) = resources_blob.get(&resource_id) else {
+            drop(resources_blob);
+            return self.flush_resource_3d(resource_id);
+        };
+
+        // The frames point at the blob memory, the pixels are only copied by
+        // the consumers needing them.
+        let source = match (resource.mapped_offset, self.host_visible.as_ref()) {
+            (Some(offset), Some(region)) => BlobSource::HostVisible {
+                region: region.clone(),
+                offset,
+            },
+            _ => BlobSource::Guest {
+                mem: self.mem.clone(),
+                backing: resource.backing.clone(),
+            },
+        };
+        for (scanout_id, scanout) in self.scanouts.iter().enumerate() {
+            if scanout.resource_id != resource_id {
+                continue;
+            }
+            let Some(blob) = scanout.blob else {
+                continue;
+            };
+
+            let r = &scanout.r;
+            let pixels = ScanoutPixels::Blob(BlobPixels {
+                source: source.clone(),
+                offset: u64::from(blob.offset)
+                    + u64::from(r.y) * u64::from(blob.stride)
+                    + u64::from(r.x) * 4,
+                stride: u64::from(blob.stride),
+            });
+            publish_frame(&self.scanout_frames, scanout_id, r, blob.format, pixels);
+        }
+
+        Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
+    }
+
+    /// Publish the scanouts displaying a 3D resource
+    fn flush_resource_3d(&mut self, resource_id: u32) -> CtrlHeader {
+        let resources_3d = self.resources_3d.lock().expect("Failed to lock resources_3d mutex: another thread panicked while holding the lock");
+        let Some(resource) = resources_3d.get(&resource_id) else {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+        };
+
+        for (scanout_id, scanout) in self.scanouts.iter().enumerate() {
+            if scanout.resource_id != resource_id {
+                continue;
+            }
+
+            // The rendered contents only exist in the renderer
+            #[cfg(feature = "virgl")]
+            if let Some(renderer) = self.renderer.as_mut() {
+                let r = &scanout.r;
+                match renderer.read_pixels(resource_id, r.x, r.y, r.width, r.height) {
+                    Ok(data) => {
+                        publish_frame(
+                            &self.scanout_frames,
+                            scanout_id,
+                            r,
+                            resource.format,
+                            ScanoutPixels::Owned(data),
+                        );
+                    }
+                    Err(e) => error!("Failed to read back scanout {scanout_id}: {e}"),
+                }
+                continue;
+            }
+
+            let data = copy_rect(&resource.data, resource.width as usize * 4, &scanout.r);
+            publish_frame(
+                &self.scanout_frames,
+                scanout_id,
+                &scanout.r,
+                resource.format,
+                ScanoutPixels::Owned(data),
+            );
+        }
+
+        Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
+    }
+
+    // ============== Blob Command Handlers ==============
+
+    /// Handle RESOURCE_CREATE_BLOB command
+    fn handle_resource_create_blob(
+        &mut self,
+        cmd: &ResourceCreateBlob,
+        entries: Vec<MemEntry>,
+    ) -> CtrlHeader {
+        if cmd.resource_id == 0 || cmd.size == 0 {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+
+        if self.resources_blob.lock().expect("Failed to lock resources_blob mutex: another thread panicked while holding the lock").contains_key(&cmd.resource_id) {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+        }
+
+        match cmd.blob_mem {
+            VIRTIO_GPU_BLOB_MEM_GUEST => {
+                // Guest blobs live in guest memory, they can't be mapped
+                let backing_size: u64 = entries.iter().map(|e| u64::from(e.length)).sum();
+                if cmd.blob_flags & VIRTIO_GPU_BLOB_FLAG_USE_MAPPABLE != 0
+                    || (!entries.is_empty() && backing_size < cmd.size)
+                {
+                    return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+                }
+            }
+            VIRTIO_GPU_BLOB_MEM_HOST3D | VIRTIO_GPU_BLOB_MEM_HOST3D_GUEST => {
+                if let Err(e) = self.create_host_blob(cmd, &entries) {
+                    error!("Failed to create host blob {}: {e:?}", cmd.resource_id);
+                    return Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC);
+                }
+            }
+            _ => return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER),
+        }
+
+        let resource = ResourceBlob {
+            blob_mem: cmd.blob_mem,
+            blob_flags: cmd.blob_flags,
+            size: cmd.size,
+            backing: entries.into(),
+            mapped_offset: None,
+        };
+        self.resources_blob.lock().expect("Failed to lock resources_blob mutex: another thread panicked while holding the lock").insert(cmd.resource_id, resource);
+
+        Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
+    }
+
+    /// Create the renderer side of a host blob
+    #[cfg_attr(not(feature = "virgl"), allow(unused_variables))]
+    fn create_host_blob(
+        &mut self,
+        cmd: &ResourceCreateBlob,
+        entries: &[MemEntry],
+    ) -> Result<(), Error> {
+        #[cfg(feature = "virgl")]
+        if let Some(renderer) = self.renderer.as_mut() {
+            let iovecs = backing_iovecs(&*self.mem.memory(), entries)?;
+            let args = virgl::BlobCreateArgs {
+                handle: cmd.resource_id,
+                ctx_id: cmd.header.ctx_id,
+                blob_mem: cmd.blob_mem,
+                blob_flags: cmd.blob_flags,
+                blob_id: cmd.blob_id,
+                size: cmd.size,
+            };
+            // SAFETY: Guest RAM stays mapped while the device is active and
+            // the backing is released when the resource is.
+            return unsafe { renderer.create_blob(&args, iovecs) }.map_err(Error::Virgl);
+        }
+
+        Err(Error::HostBlobUnsupported)
+    }
+
+    /// Export the memory of a host blob
+    #[cfg_attr(not(feature = "virgl"), allow(unused_variables))]
+    fn export_host_blob(&mut self, resource_id: u32) -> Result<std::os::fd::OwnedFd, Error> {
+        #[cfg(feature = "virgl")]
+        if let Some(renderer) = self.renderer.as_mut() {
+            return renderer.export_blob(resource_id).map_err(Error::Virgl);
+        }
+
+        Err(Error::HostBlobUnsupported)
+    }
+
+    /// Handle SET_SCANOUT_BLOB command
+    fn handle_set_scanout_blob(&mut self, cmd: &SetScanoutBlob) -> CtrlHeader {
+        let scanout_id = cmd.scanout_id as usize;
+        if scanout_id >= self.scanouts.len() {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID);
+        }
+
+        if cmd.resource_id == 0 {
+            self.disable_scanout(scanout_id);
+            return Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA);
+        }
+
+        let resources_blob = self.resources_blob.lock().expect("Failed to lock resources_blob mutex: another thread panicked while holding the lock");
+        let Some(resource) = resources_blob.get(&cmd.resource_id) else {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+        };
+
+        // Only single plane formats are supported, the displayed region must
+        // lie within both the buffer and the blob.
+        let Some(bpp) = bytes_per_pixel(cmd.format) else {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        };
+        let stride = u64::from(cmd.strides[0]);
+        let last_row = u64::from(cmd.r.y) + u64::from(cmd.r.height);
+        let end = u64::from(cmd.offsets[0])
+            + last_row.saturating_sub(1) * stride
+            + (u64::from(cmd.r.x) + u64::from(cmd.r.width)) * u64::from(bpp);
+        if cmd.r.width == 0
+            || cmd.r.height == 0
+            || u64::from(cmd.r.x) + u64::from(cmd.r.width) > u64::from(cmd.width)
+            || last_row > u64::from(cmd.height)
+            || stride < u64::from(cmd.width) * u64::from(bpp)
+            || end > resource.size
+        {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+        drop(resources_blob);
+
+        self.scanouts[scanout_id] = Scanout {
+            resource_id: cmd.resource_id,
+            r: cmd.r,
+            blob: Some(BlobScanout {
+                format: cmd.format,
+                stride: cmd.strides[0],
+                offset: cmd.offsets[0],
+            }),
+        };
+        Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
+    }
+
+    /// Handle RESOURCE_MAP_BLOB command
+    fn handle_resource_map_blob(
+        &mut self,
+        cmd: &ResourceMapBlob,
+    ) -> Result<RespMapInfo, CtrlHeader> {
+        let Some(region) = self.host_visible.clone() else {
+            return Err(Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC));
+        };
+
+        let size = {
+            let resources_blob = self.resources_blob.lock().expect("Failed to lock resources_blob mutex: another thread panicked while holding the lock");
+            let Some(resource) = resources_blob.get(&cmd.resource_id) else {
+                return Err(Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID));
+            };
+            if resource.blob_mem == VIRTIO_GPU_BLOB_MEM_GUEST
+                || resource.blob_flags & VIRTIO_GPU_BLOB_FLAG_USE_MAPPABLE == 0
+                || resource.mapped_offset.is_some()
+            {
+                return Err(Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER));
+            }
+            resource.size
+        };
+
+        let res = self
+            .export_host_blob(cmd.resource_id)
+            .and_then(|fd| map_into_region(&region, cmd.offset, size, fd.as_raw_fd()));
+        if let Err(e) = res {
+            error!("Failed to map blob {}: {e:?}", cmd.resource_id);
+            return Err(Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC));
+        }
+
+        if let Some(resource) = self.resources_blob.lock().expect("Failed to lock resources_blob mutex: another thread panicked while holding the lock").get_mut(&cmd.resource_id) {
+            resource.mapped_offset = Some(cmd.offset);
+        }
+
+        Ok(RespMapInfo {
+            header: Self::create_response_header(VIRTIO_GPU_RESP_OK_MAP_INFO),
+            map_info: VIRTIO_GPU_MAP_CACHE_CACHED,
+            padding: 0,
+        })
+    }
+
+    /// Handle RESOURCE_UNMAP_BLOB command
+    fn handle_resource_unmap_blob(&mut self, cmd: &ResourceUnmapBlob) -> CtrlHeader {
+        let mut resources_blob = self.resources_blob.lock().expect("Failed to lock resources_blob mutex: another thread panicked while holding the lock");
+        let Some(resource) = resources_blob.get_mut(&cmd.resource_id) else {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+        };
+        let (Some(offset), Some(region)) = (resource.mapped_offset, &self.host_visible) else {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        };
+
+        if let Err(e) = unmap_from_region(region, offset, resource.size) {
+            error!("Failed to unmap blob {}: {e:?}", cmd.resource_id);
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC);
+        }
+        resource.mapped_offset = None;
+
         Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
     }
 
@@ -789,6 +2211,15 @@ impl GpuEpollHandler {
         let name = String::from_utf8_lossy(&cmd.context_name[..name_len]).into_owned();
 
         let ctx_id = cmd.header.ctx_id;
+
+        #[cfg(feature = "virgl")]
+        if let Some(renderer) = self.renderer.as_mut()
+            && let Err(e) = renderer.create_context(ctx_id, &cmd.context_name[..name_len])
+        {
+            error!("Failed to create virgl context {ctx_id}: {e}");
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC);
+        }
+
         let context = VirglContext::new(ctx_id, name);
 
         let mut contexts = self.virgl_contexts.lock().expect("Failed to lock virgl_contexts mutex: another thread panicked while holding the lock");
@@ -805,7 +2236,12 @@ impl GpuEpollHandler {
 
         let ctx_id = cmd.header.ctx_id;
         let mut contexts = self.virgl_contexts.lock().expect("Failed to lock virgl_contexts mutex: another thread panicked while holding the lock");
-        contexts.remove(&ctx_id);
+        if contexts.remove(&ctx_id).is_some() {
+            #[cfg(feature = "virgl")]
+            if let Some(renderer) = self.renderer.as_mut() {
+                renderer.destroy_context(ctx_id);
+            }
+        }
 
         Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
     }
@@ -827,6 +2263,10 @@ impl GpuEpollHandler {
             if !context.resources.contains(&cmd.resource_id) {
                 context.resources.push(cmd.resource_id);
             }
+            #[cfg(feature = "virgl")]
+            if let Some(renderer) = self.renderer.as_mut() {
+                renderer.ctx_attach_resource(ctx_id, cmd.resource_id);
+            }
             Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
         } else {
             Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC)
@@ -844,6 +2284,10 @@ impl GpuEpollHandler {
 
         if let Some(context) = contexts.get_mut(&ctx_id) {
             context.resources.retain(|&id| id != cmd.resource_id);
+            #[cfg(feature = "virgl")]
+            if let Some(renderer) = self.renderer.as_mut() {
+                renderer.ctx_detach_resource(ctx_id, cmd.resource_id);
+            }
             Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
         } else {
             Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC)
@@ -860,6 +2304,27 @@ impl GpuEpollHandler {
             return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
         }
 
+        #[cfg(feature = "virgl")]
+        if let Some(renderer) = self.renderer.as_mut() {
+            let args = virgl::ResourceCreateArgs {
+                handle: cmd.resource_id,
+                target: cmd.target,
+                format: cmd.format,
+                bind: cmd.bind,
+                width: cmd.width,
+                height: cmd.height,
+                depth: cmd.depth,
+                array_size: cmd.array_size,
+                last_level: cmd.last_level,
+                nr_samples: cmd.nr_samples,
+                flags: cmd.flags,
+            };
+            if let Err(e) = renderer.create_resource(&args) {
+                error!("Failed to create 3D resource {}: {e}", cmd.resource_id);
+                return Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC);
+            }
+        }
+
         let resource = Resource3D::new(
             cmd.resource_id,
             cmd.width,
@@ -894,6 +2359,28 @@ impl GpuEpollHandler {
 
         let mut resources_3d = self.resources_3d.lock().expect("Failed to lock resources_3d mutex: another thread panicked while holding the lock");
 
+        #[cfg(feature = "virgl")]
+        if let Some(renderer) = self.renderer.as_mut() {
+            if !resources_3d.contains_key(&cmd.resource_id) {
+                return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+            }
+            return match renderer.transfer_write(
+                cmd.resource_id,
+                cmd.header.ctx_id,
+                cmd.level,
+                cmd.stride,
+                cmd.layer_stride,
+                cmd.box_.into(),
+                cmd.offset,
+            ) {
+                Ok(()) => Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA),
+                Err(e) => {
+                    error!("TRANSFER_TO_HOST_3D failed: {e}");
+                    Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC)
+                }
+            };
+        }
+
         if let Some(resource) = resources_3d.get_mut(&cmd.resource_id) {
             // Calculate destination offset based on box coordinates
             let dst_start = (cmd.box_.z as usize * resource.width as usize * resource.height as usize
@@ -906,12 +2393,14 @@ impl GpuEpollHandler {
             );
 
             if bytes_to_copy > 0 && dst_start + bytes_to_copy <= resource.data.len() {
-                let src_addr = GuestAddress(cmd.offset);
-                if mem
-                    .read_slice(&mut resource.data[dst_start..dst_start + bytes_to_copy], src_addr)
-                    .is_err()
-                {
-                    error!("Failed to read from guest memory during TRANSFER_TO_HOST_3D");
+                let res = read_backing(
+                    mem,
+                    &resource.backing,
+                    cmd.offset,
+                    &mut resource.data[dst_start..dst_start + bytes_to_copy],
+                );
+                if let Err(e) = res {
+                    error!("Failed to read from guest memory during TRANSFER_TO_HOST_3D: {e:?}");
                 }
             }
 
@@ -937,6 +2426,28 @@ impl GpuEpollHandler {
 
         let resources_3d = self.resources_3d.lock().expect("Failed to lock resources_3d mutex: another thread panicked while holding the lock");
 
+        #[cfg(feature = "virgl")]
+        if let Some(renderer) = self.renderer.as_mut() {
+            if !resources_3d.contains_key(&cmd.resource_id) {
+                return Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+            }
+            return match renderer.transfer_read(
+                cmd.resource_id,
+                cmd.header.ctx_id,
+                cmd.level,
+                cmd.stride,
+                cmd.layer_stride,
+                cmd.box_.into(),
+                cmd.offset,
+            ) {
+                Ok(()) => Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA),
+                Err(e) => {
+                    error!("TRANSFER_FROM_HOST_3D failed: {e}");
+                    Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC)
+                }
+            };
+        }
+
         if let Some(resource) = resources_3d.get(&cmd.resource_id) {
             // Calculate source offset based on box coordinates
             let src_start = (cmd.box_.z as usize * resource.width as usize * resource.height as usize
@@ -949,12 +2460,14 @@ impl GpuEpollHandler {
             );
 
             if bytes_to_copy > 0 && src_start + bytes_to_copy <= resource.data.len() {
-                let dst_addr = GuestAddress(cmd.offset);
-                if mem
-                    .write_slice(&resource.data[src_start..src_start + bytes_to_copy], dst_addr)
-                    .is_err()
-                {
-                    error!("Failed to write to guest memory during TRANSFER_FROM_HOST_3D");
+                let res = write_backing(
+                    mem,
+                    &resource.backing,
+                    cmd.offset,
+                    &resource.data[src_start..src_start + bytes_to_copy],
+                );
+                if let Err(e) = res {
+                    error!("Failed to write to guest memory during TRANSFER_FROM_HOST_3D: {e:?}");
                 }
             }
 
@@ -965,23 +2478,117 @@ impl GpuEpollHandler {
     }
 
     /// Handle submit 3D (Gallium command buffer)
-    /// This is a simplified implementation that just acknowledges the command.
-    /// A full implementation would parse and execute Gallium commands.
-    fn handle_submit_3d(&mut self, ctx_id: u32, _cmd_data: &[u32]) -> CtrlHeader {
+    ///
+    /// The command stream is executed by virglrenderer when it is available,
+    /// otherwise the submission is only acknowledged.
+    #[cfg_attr(not(feature = "virgl"), allow(unused_variables))]
+    fn handle_submit_3d(&mut self, ctx_id: u32, cmd_data: &mut [u32]) -> CtrlHeader {
         if !self.virgl_enabled {
             return Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC);
         }
 
         let contexts = self.virgl_contexts.lock().expect("Failed to lock virgl_contexts mutex: another thread panicked while holding the lock");
 
-        if contexts.contains_key(&ctx_id) {
-            // In a full implementation, this would parse and execute Gallium commands
-            // For software rendering, this would be a simplified interpreter
-            // For now, we just acknowledge the submission
-            Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
-        } else {
-            Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC)
+        if !contexts.contains_key(&ctx_id) {
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC);
+        }
+
+        #[cfg(feature = "virgl")]
+        if let Some(renderer) = self.renderer.as_mut()
+            && let Err(e) = renderer.submit(ctx_id, cmd_data)
+        {
+            error!("SUBMIT_3D failed on context {ctx_id}: {e}");
+            return Self::create_response_header(VIRTIO_GPU_RESP_ERR_UNSPEC);
+        }
+
+        Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
+    }
+
+    /// Handle GET_CAPSET_INFO command, the response header is left to the
+    /// caller
+    #[cfg_attr(not(feature = "virgl"), allow(unused_variables))]
+    fn handle_get_capset_info(&self, cmd: &GetCapsetInfo) -> Option<CapsetInfo> {
+        #[cfg(feature = "virgl")]
+        if let Some(renderer) = self.renderer.as_ref() {
+            return match renderer.capset_info(cmd.capset_index) {
+                Ok((capset_id, capset_max_version, capset_max_size)) => Some(CapsetInfo {
+                    header: CtrlHeader::default(),
+                    capset_id,
+                    capset_max_version,
+                    capset_max_size,
+                    padding: 0,
+                }),
+                Err(e) => {
+                    error!("GET_CAPSET_INFO failed: {e}");
+                    None
+                }
+            };
         }
+
+        None
+    }
+
+    /// Handle GET_CAPSET command, returning the capabilities blob
+    #[cfg_attr(not(feature = "virgl"), allow(unused_variables))]
+    fn handle_get_capset(&self, cmd: &GetCapset) -> Option<Vec<u8>> {
+        #[cfg(feature = "virgl")]
+        if let Some(renderer) = self.renderer.as_ref()
+            && virgl::VIRGL_CAPSETS.contains(&cmd.capset_id)
+        {
+            return Some(renderer.capset(cmd.capset_id, cmd.capset_version));
+        }
+
+        None
+    }
+
+    /// Fence the commands executed so far by the renderer
+    ///
+    /// Returns `None` when there is nothing to wait for.
+    #[cfg(feature = "virgl")]
+    fn create_fence(&mut self, ctx_id: u32) -> Option<u32> {
+        let renderer = self.renderer.as_mut()?;
+        match renderer.create_fence(ctx_id) {
+            Ok(fence) => Some(fence),
+            Err(e) => {
+                error!("Failed to create fence on context {ctx_id}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Return the descriptors of the fenced commands that completed
+    ///
+    /// Returns whether any descriptor was used.
+    #[cfg(feature = "virgl")]
+    fn retire_fences(&mut self) -> Result<bool, Error> {
+        let Some(renderer) = self.renderer.as_mut() else {
+            return Ok(false);
+        };
+        renderer.poll();
+
+        // Without a poll fd nothing would wake the thread up later
+        if renderer.poll_fd().is_none() {
+            while let Some(last) = self.pending_fences.back()
+                && !renderer.fence_retired(last.fence)
+            {
+                std::thread::yield_now();
+                renderer.poll();
+            }
+        }
+
+        let mem = self.mem.memory();
+        let mut used_descs = false;
+        while let Some(pending) = self.pending_fences.front()
+            && renderer.fence_retired(pending.fence)
+        {
+            self.queues[CONTROL_QUEUE]
+                .add_used(&*mem, pending.head_index, pending.len)
+                .map_err(Error::QueueAddUsed)?;
+            self.pending_fences.pop_front();
+            used_descs = true;
+        }
+
+        Ok(used_descs)
     }
 
     /// Process the control queue
@@ -1004,7 +2611,7 @@ impl GpuEpollHandler {
                 .read_obj(head_desc.addr())
                 .map_err(Error::GuestMemory)?;
 
-            let response = match header.hdr_type {
+            let mut response = match header.hdr_type {
                 VIRTIO_GPU_CMD_GET_DISPLAY_INFO => {
                     let display_info = self.handle_get_display_info();
                     // Write response to the next descriptor
@@ -1023,6 +2630,31 @@ impl GpuEpollHandler {
                     used_descs = true;
                     continue;
                 }
+                VIRTIO_GPU_CMD_GET_EDID => {
+                    let cmd: GetEdid = desc_chain
+                        .memory()
+                        .read_obj(head_desc.addr())
+                        .map_err(Error::GuestMemory)?;
+                    match self.handle_get_edid(&cmd) {
+                        Ok(resp) => {
+                            if let Some(resp_desc) = desc_chain.next()
+                                && resp_desc.is_write_only()
+                            {
+                                let _ = desc_chain.memory().write_obj(resp, resp_desc.addr());
+                            }
+                            self.queues[CONTROL_QUEUE]
+                                .add_used(
+                                    desc_chain.memory(),
+                                    desc_chain.head_index(),
+                                    std::mem::size_of::<RespEdid>() as u32,
+                                )
+                                .map_err(Error::QueueAddUsed)?;
+                            used_descs = true;
+                            continue;
+                        }
+                        Err(header) => header,
+                    }
+                }
                 VIRTIO_GPU_CMD_RESOURCE_CREATE_2D => {
                     let cmd: ResourceCreate2D = desc_chain
                         .memory()
@@ -1044,43 +2676,153 @@ impl GpuEpollHandler {
                         .map_err(Error::GuestMemory)?;
                     self.handle_set_scanout(&cmd)
                 }
-                VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D => {
-                    let cmd: TransferToHost2D = desc_chain
+                VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D => {
+                    let cmd: TransferToHost2D = desc_chain
+                        .memory()
+                        .read_obj(head_desc.addr())
+                        .map_err(Error::GuestMemory)?;
+                    self.handle_transfer_to_host_2d(desc_chain.memory(), &cmd)
+                }
+                VIRTIO_GPU_CMD_RESOURCE_FLUSH => {
+                    let cmd: ResourceFlush = desc_chain
+                        .memory()
+                        .read_obj(head_desc.addr())
+                        .map_err(Error::GuestMemory)?;
+                    self.handle_resource_flush(&cmd)
+                }
+                VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => {
+                    let cmd: ResourceAttachBacking = desc_chain
+                        .memory()
+                        .read_obj(head_desc.addr())
+                        .map_err(Error::GuestMemory)?;
+
+                    // Read memory entries from the next descriptor
+                    let entries = desc_chain.next().map_or_else(Vec::new, |entry_desc| {
+                        read_mem_entries(desc_chain.memory(), entry_desc.addr(), cmd.nr_entries)
+                    });
+                    self.handle_resource_attach_backing(&cmd, entries)
+                }
+                VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING => {
+                    let cmd: ResourceDetachBacking = desc_chain
+                        .memory()
+                        .read_obj(head_desc.addr())
+                        .map_err(Error::GuestMemory)?;
+                    self.handle_resource_detach_backing(&cmd)
+                }
+                VIRTIO_GPU_CMD_GET_CAPSET_INFO => {
+                    let cmd: GetCapsetInfo = desc_chain
+                        .memory()
+                        .read_obj(head_desc.addr())
+                        .map_err(Error::GuestMemory)?;
+                    match self.handle_get_capset_info(&cmd) {
+                        Some(mut info) => {
+                            info.header =
+                                Self::create_response_header(VIRTIO_GPU_RESP_OK_CAPSET_INFO);
+                            if let Some(resp_desc) = desc_chain.next()
+                                && resp_desc.is_write_only()
+                            {
+                                let _ = desc_chain.memory().write_obj(info, resp_desc.addr());
+                            }
+                            self.queues[CONTROL_QUEUE]
+                                .add_used(
+                                    desc_chain.memory(),
+                                    desc_chain.head_index(),
+                                    std::mem::size_of::<CapsetInfo>() as u32,
+                                )
+                                .map_err(Error::QueueAddUsed)?;
+                            used_descs = true;
+                            continue;
+                        }
+                        None => Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER),
+                    }
+                }
+                VIRTIO_GPU_CMD_GET_CAPSET => {
+                    let cmd: GetCapset = desc_chain
+                        .memory()
+                        .read_obj(head_desc.addr())
+                        .map_err(Error::GuestMemory)?;
+                    match self.handle_get_capset(&cmd) {
+                        Some(caps) => {
+                            // The capabilities blob follows the response header
+                            let header = Self::create_response_header(VIRTIO_GPU_RESP_OK_CAPSET);
+                            let header_len = std::mem::size_of::<CtrlHeader>();
+                            if let Some(resp_desc) = desc_chain.next()
+                                && resp_desc.is_write_only()
+                            {
+                                let _ = desc_chain.memory().write_obj(header, resp_desc.addr());
+                                if let Some(addr) = resp_desc.addr().checked_add(header_len as u64)
+                                {
+                                    let _ = desc_chain.memory().write_slice(&caps, addr);
+                                }
+                            }
+                            self.queues[CONTROL_QUEUE]
+                                .add_used(
+                                    desc_chain.memory(),
+                                    desc_chain.head_index(),
+                                    (header_len + caps.len()) as u32,
+                                )
+                                .map_err(Error::QueueAddUsed)?;
+                            used_descs = true;
+                            continue;
+                        }
+                        None => Self::create_response_header(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER),
+                    }
+                }
+                VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB => {
+                    let cmd: ResourceCreateBlob = desc_chain
                         .memory()
                         .read_obj(head_desc.addr())
                         .map_err(Error::GuestMemory)?;
-                    self.handle_transfer_to_host_2d(desc_chain.memory(), &cmd)
+
+                    // Guest blobs come with their memory entries, in the
+                    // next descriptor
+                    let entries = if cmd.nr_entries > 0 {
+                        desc_chain.next().map_or_else(Vec::new, |entry_desc| {
+                            read_mem_entries(desc_chain.memory(), entry_desc.addr(), cmd.nr_entries)
+                        })
+                    } else {
+                        Vec::new()
+                    };
+                    self.handle_resource_create_blob(&cmd, entries)
                 }
-                VIRTIO_GPU_CMD_RESOURCE_FLUSH => {
-                    let cmd: ResourceFlush = desc_chain
+                VIRTIO_GPU_CMD_SET_SCANOUT_BLOB => {
+                    let cmd: SetScanoutBlob = desc_chain
                         .memory()
                         .read_obj(head_desc.addr())
                         .map_err(Error::GuestMemory)?;
-                    self.handle_resource_flush(&cmd)
+                    self.handle_set_scanout_blob(&cmd)
                 }
-                VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => {
-                    let cmd: ResourceAttachBacking = desc_chain
+                VIRTIO_GPU_CMD_RESOURCE_MAP_BLOB => {
+                    let cmd: ResourceMapBlob = desc_chain
                         .memory()
                         .read_obj(head_desc.addr())
                         .map_err(Error::GuestMemory)?;
-
-                    // Read memory entries from the next descriptor
-                    let mut entries = Vec::new();
-                    if let Some(entry_desc) = desc_chain.next() {
-                        for i in 0..cmd.nr_entries as usize {
-                            let offset = i * std::mem::size_of::<MemEntry>();
-                            if let Some(addr) = entry_desc.addr().checked_add(offset as u64) {
-                                if let Ok(entry) = desc_chain.memory().read_obj::<MemEntry>(addr) {
-                                    entries.push(entry);
-                                }
+                    match self.handle_resource_map_blob(&cmd) {
+                        Ok(map_info) => {
+                            if let Some(resp_desc) = desc_chain.next()
+                                && resp_desc.is_write_only()
+                            {
+                                let _ = desc_chain.memory().write_obj(map_info, resp_desc.addr());
                             }
+                            self.queues[CONTROL_QUEUE]
+                                .add_used(
+                                    desc_chain.memory(),
+                                    desc_chain.head_index(),
+                                    std::mem::size_of::<RespMapInfo>() as u32,
+                                )
+                                .map_err(Error::QueueAddUsed)?;
+                            used_descs = true;
+                            continue;
                         }
+                        Err(response) => response,
                     }
-                    self.handle_resource_attach_backing(&cmd, entries)
                 }
-                VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING => {
-                    // Simplified handling - just acknowledge
-                    Self::create_response_header(VIRTIO_GPU_RESP_OK_NODATA)
+                VIRTIO_GPU_CMD_RESOURCE_UNMAP_BLOB => {
+                    let cmd: ResourceUnmapBlob = desc_chain
+                        .memory()
+                        .read_obj(head_desc.addr())
+                        .map_err(Error::GuestMemory)?;
+                    self.handle_resource_unmap_blob(&cmd)
                 }
                 // VIRGL 3D commands
                 VIRTIO_GPU_CMD_CTX_CREATE => {
@@ -1154,7 +2896,7 @@ impl GpuEpollHandler {
                             }
                         }
                     }
-                    self.handle_submit_3d(header.ctx_id, &cmd_data)
+                    self.handle_submit_3d(header.ctx_id, &mut cmd_data)
                 }
                 _ => {
                     error!("Unknown GPU command: 0x{:x}", header.hdr_type);
@@ -1162,6 +2904,14 @@ impl GpuEpollHandler {
                 }
             };
 
+            // The fence is signaled along with the response, which the guest
+            // only gets back once the renderer is done with the command.
+            if header.flags & VIRTIO_GPU_FLAG_FENCE != 0 {
+                response.flags |= VIRTIO_GPU_FLAG_FENCE;
+                response.fence_id = header.fence_id;
+                response.ctx_id = header.ctx_id;
+            }
+
             // Write response to the next descriptor (if writeable)
             if let Some(resp_desc) = desc_chain.next() {
                 if resp_desc.is_write_only() {
@@ -1169,6 +2919,18 @@ impl GpuEpollHandler {
                 }
             }
 
+            #[cfg(feature = "virgl")]
+            if header.flags & VIRTIO_GPU_FLAG_FENCE != 0
+                && let Some(fence) = self.create_fence(header.ctx_id)
+            {
+                self.pending_fences.push_back(PendingFence {
+                    fence,
+                    head_index: desc_chain.head_index(),
+                    len: std::mem::size_of::<CtrlHeader>() as u32,
+                });
+                continue;
+            }
+
             self.queues[CONTROL_QUEUE]
                 .add_used(
                     desc_chain.memory(),
@@ -1179,6 +2941,11 @@ impl GpuEpollHandler {
             used_descs = true;
         }
 
+        #[cfg(feature = "virgl")]
+        {
+            used_descs |= self.retire_fences()?;
+        }
+
         if used_descs {
             self.signal(super::VirtioInterruptType::Queue(CONTROL_QUEUE as u16))
         } else {
@@ -1186,22 +2953,121 @@ impl GpuEpollHandler {
         }
     }
 
-    /// Process the cursor queue (minimal implementation)
+    // ============== Cursor Command Handlers ==============
+
+    /// Read the image of a cursor resource as
+    /// `(width, height, format, data)`
+    fn read_cursor_image(&mut self, resource_id: u32) -> Option<(u32, u32, u32, Vec<u8>)> {
+        if let Some(resource) = self.resources.lock().unwrap().get(&resource_id) {
+            return Some((
+                resource.width,
+                resource.height,
+                resource.format,
+                resource.data.clone(),
+            ));
+        }
+
+        let resources_3d = self.resources_3d.lock().unwrap();
+        let resource = resources_3d.get(&resource_id)?;
+
+        // The rendered contents only exist in the renderer
+        #[cfg(feature = "virgl")]
+        if let Some(renderer) = self.renderer.as_mut() {
+            let (width, height) = (resource.width, resource.height);
+            return match renderer.read_pixels(resource_id, 0, 0, width, height) {
+                Ok(data) => Some((width, height, resource.format, data)),
+                Err(e) => {
+                    error!("Failed to read back cursor resource {resource_id}: {e}");
+                    None
+                }
+            };
+        }
+
+        Some((
+            resource.width,
+            resource.height,
+            resource.format,
+            resource.data.clone(),
+        ))
+    }
+
+    /// Handle UPDATE_CURSOR command
+    ///
+    /// A null resource hides the cursor.
+    fn handle_update_cursor(&mut self, cmd: &UpdateCursor) {
+        let image = if cmd.resource_id == 0 {
+            None
+        } else {
+            let image = self.read_cursor_image(cmd.resource_id);
+            if image.is_none() {
+                error!("Invalid cursor resource {}", cmd.resource_id);
+                return;
+            }
+            image
+        };
+
+        let mut cursor = self.cursor.lock().unwrap();
+        cursor.scanout_id = cmd.pos.scanout_id;
+        cursor.x = cmd.pos.x;
+        cursor.y = cmd.pos.y;
+        match image {
+            Some((width, height, format, data)) => {
+                cursor.visible = true;
+                cursor.width = width;
+                cursor.height = height;
+                cursor.hot_x = cmd.hot_x;
+                cursor.hot_y = cmd.hot_y;
+                cursor.format = format;
+                cursor.data = data;
+            }
+            None => cursor.visible = false,
+        }
+        cursor.update_count = cursor.update_count.wrapping_add(1);
+    }
+
+    /// Handle MOVE_CURSOR command
+    fn handle_move_cursor(&mut self, cmd: &UpdateCursor) {
+        let mut cursor = self.cursor.lock().unwrap();
+        cursor.scanout_id = cmd.pos.scanout_id;
+        cursor.x = cmd.pos.x;
+        cursor.y = cmd.pos.y;
+        cursor.update_count = cursor.update_count.wrapping_add(1);
+    }
+
+    /// Process the cursor queue
+    ///
+    /// Cursor commands have no response, the descriptors are returned
+    /// without any data written.
     fn process_cursor_queue(&mut self) -> Result<(), Error> {
         let mut used_descs = false;
 
         while let Some(mut desc_chain) = self.queues[CURSOR_QUEUE]
             .pop_descriptor_chain(self.mem.memory())
         {
-            // For now, just acknowledge and discard cursor commands
             let head_desc = desc_chain.next().ok_or(Error::DescriptorChainTooShort)?;
 
+            if head_desc.is_write_only() {
+                error!("The head descriptor is write-only");
+                return Err(Error::UnexpectedWriteOnlyDescriptor);
+            }
+
+            if (head_desc.len() as usize) < std::mem::size_of::<UpdateCursor>() {
+                error!("Cursor command is too short: {}", head_desc.len());
+            } else {
+                let cmd: UpdateCursor = desc_chain
+                    .memory()
+                    .read_obj(head_desc.addr())
+                    .map_err(Error::GuestMemory)?;
+
+                match cmd.header.hdr_type {
+                    VIRTIO_GPU_CMD_UPDATE_CURSOR => self.handle_update_cursor(&cmd),
+                    VIRTIO_GPU_CMD_MOVE_CURSOR => self.handle_move_cursor(&cmd),
+                    hdr_type => error!("Unknown cursor command: {hdr_type:#x}"),
+                }
+            }
+
             self.queues[CURSOR_QUEUE]
-                .add_used(
-                    desc_chain.memory(),
-                    desc_chain.head_index(),
-                    head_desc.len(),
-                )
+                .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
                 .map_err(Error::QueueAddUsed)?;
             used_descs = true;
         }
@@ -1218,9 +3084,22 @@ impl GpuEpollHandler {
         paused: &AtomicBool,
         paused_sync: &Barrier,
     ) -> std::result::Result<(), EpollHelperError> {
+        // The renderer is bound to the thread processing the control queue
+        #[cfg(feature = "virgl")]
+        if self.virgl_enabled {
+            match virgl::VirglRenderer::new() {
+                Ok(renderer) => self.renderer = Some(renderer),
+                Err(e) => error!("Failed to initialize virglrenderer, 3D commands won't be executed: {e}"),
+            }
+        }
+
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.control_queue_evt.as_raw_fd(), CONTROL_QUEUE_EVENT)?;
         helper.add_event(self.cursor_queue_evt.as_raw_fd(), CURSOR_QUEUE_EVENT)?;
+        #[cfg(feature = "virgl")]
+        if let Some(fd) = self.renderer.as_ref().and_then(|r| r.poll_fd()) {
+            helper.add_event(fd, FENCE_EVENT)?;
+        }
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -1259,6 +3138,20 @@ impl EpollHelperHandler for GpuEpollHandler {
                     ))
                 })?;
             }
+            #[cfg(feature = "virgl")]
+            FENCE_EVENT => {
+                let used_descs = self.retire_fences().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to retire fences: {e:?}"))
+                })?;
+                if used_descs {
+                    self.signal(super::VirtioInterruptType::Queue(CONTROL_QUEUE as u16))
+                        .map_err(|e| {
+                            EpollHelperError::HandleEvent(anyhow!(
+                                "Failed to signal control queue: {e:?}"
+                            ))
+                        })?;
+                }
+            }
             _ => {
                 return Err(EpollHelperError::HandleEvent(anyhow!(
                     "Unknown event for virtio-gpu"
@@ -1293,13 +3186,17 @@ impl VirtioDevice for Gpu {
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        // GPU config is mostly read-only for now
+        // Only events_clear is writable, acknowledging the matching events
         let mut config = self.config.lock().expect("Failed to lock config mutex: another thread panicked while holding the lock");
-        if offset + data.len() as u64 <= std::mem::size_of::<GpuConfig>() as u64 {
-            let start = offset as usize;
-            let end = start + data.len();
-            config.as_mut_slice()[start..end].copy_from_slice(data);
+        let events_clear_offset = std::mem::offset_of!(GpuConfig, events_clear) as u64;
+        if offset != events_clear_offset || data.len() != 4 {
+            error!("Invalid virtio-gpu config write: offset = {offset:x} length = {}", data.len());
+            return;
         }
+
+        let value = u32::from_le_bytes(data.try_into().unwrap());
+        config.events_read &= !value;
+        config.events_clear = 0;
     }
 
     fn activate(
@@ -1332,9 +3229,18 @@ impl VirtioDevice for Gpu {
             resources: self.resources.clone(),
             virgl_contexts: self.virgl_contexts.clone(),
             resources_3d: self.resources_3d.clone(),
-            display_width: self.display_width,
-            display_height: self.display_height,
+            resources_blob: self.resources_blob.clone(),
+            host_visible: self.host_visible.as_ref().map(|shm| shm.mapping.clone()),
+            displays: self.displays.clone(),
+            edid: self.common.feature_acked(VIRTIO_GPU_F_EDID),
             virgl_enabled: self.virgl_enabled,
+            scanouts: vec![Scanout::default(); self.num_scanouts() as usize],
+            scanout_frames: self.scanout_frames.clone(),
+            cursor: self.cursor.clone(),
+            #[cfg(feature = "virgl")]
+            renderer: None,
+            #[cfg(feature = "virgl")]
+            pending_fences: VecDeque::new(),
         };
 
         let paused = self.common.paused.clone();
@@ -1354,6 +3260,34 @@ impl VirtioDevice for Gpu {
         Ok(())
     }
 
+    fn get_shm_regions(&self) -> Option<VirtioSharedMemoryList> {
+        self.host_visible.clone()
+    }
+
+    fn set_shm_regions(
+        &mut self,
+        shm_regions: VirtioSharedMemoryList,
+    ) -> std::result::Result<(), crate::Error> {
+        if let Some(host_visible) = self.host_visible.as_mut() {
+            *host_visible = shm_regions;
+            Ok(())
+        } else {
+            Err(crate::Error::SetShmRegionsNotSupported)
+        }
+    }
+
+    fn userspace_mappings(&self) -> Vec<UserspaceMapping> {
+        self.host_visible
+            .iter()
+            .map(|shm| UserspaceMapping {
+                mem_slot: shm.mem_slot,
+                addr: shm.addr,
+                mapping: shm.mapping.clone(),
+                mergeable: false,
+            })
+            .collect()
+    }
+
     fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
         self.common.reset()
     }
@@ -1362,7 +3296,7 @@ impl VirtioDevice for Gpu {
 impl Pausable for Gpu {}
 impl Snapshottable for Gpu {
     fn id(&self) -> String {
-        "virtio-gpu".to_string()
+        self.id.clone()
     }
 }
 impl Transportable for Gpu {}
@@ -1371,6 +3305,56 @@ impl Migratable for Gpu {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::VirtioInterruptType;
+
+    struct NoopVirtioInterrupt {}
+
+    impl VirtioInterrupt for NoopVirtioInterrupt {
+        fn trigger(&self, _int_type: VirtioInterruptType) -> std::result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    fn test_handler(num_scanouts: usize) -> GpuEpollHandler {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        GpuEpollHandler {
+            mem: GuestMemoryAtomic::new(mem),
+            queues: Vec::new(),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            control_queue_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            cursor_queue_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            kill_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            resources: Arc::new(Mutex::new(HashMap::new())),
+            virgl_contexts: Arc::new(Mutex::new(HashMap::new())),
+            resources_3d: Arc::new(Mutex::new(HashMap::new())),
+            resources_blob: Arc::new(Mutex::new(HashMap::new())),
+            host_visible: None,
+            displays: Arc::new(Mutex::new(
+                (0..num_scanouts)
+                    .map(|i| DisplayOne {
+                        r: Rect {
+                            x: 0,
+                            y: 0,
+                            width: 64,
+                            height: 32,
+                        },
+                        enabled: u32::from(i == 0),
+                        flags: 0,
+                    })
+                    .collect(),
+            )),
+            edid: true,
+            virgl_enabled: false,
+            scanouts: vec![Scanout::default(); num_scanouts],
+            scanout_frames: Arc::new(Mutex::new(vec![None; num_scanouts])),
+            cursor: Arc::new(Mutex::new(CursorState::default())),
+            #[cfg(feature = "virgl")]
+            renderer: None,
+            #[cfg(feature = "virgl")]
+            pending_fences: VecDeque::new(),
+        }
+    }
 
     #[test]
     fn test_gpu_creation() {
@@ -1386,6 +3370,22 @@ mod tests {
         assert_eq!(config.num_scanouts, 1);
     }
 
+    #[test]
+    fn test_gpu_config_layout() {
+        let exit_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut gpu = Gpu::new_with_virgl(1024, 768, false, SeccompAction::Allow, exit_evt).unwrap();
+        assert_eq!(std::mem::size_of::<GpuConfig>(), 16);
+
+        let mut data = [0u8; 4];
+        gpu.read_config(8, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 1);
+
+        gpu.config.lock().unwrap().events_read = 0x3;
+        gpu.write_config(4, &1u32.to_le_bytes());
+        gpu.read_config(0, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x2);
+    }
+
     #[test]
     fn test_resource_creation() {
         let resource = Resource2D::new(800, 600, VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM).unwrap();
@@ -1593,6 +3593,7 @@ mod tests {
         let cmd = CtxCreate {
             header,
             nctx: name_str.len() as u32,
+            context_init: 0,
             context_name: name,
         };
         assert_eq!(cmd.header.ctx_id, 1);
@@ -1612,12 +3613,14 @@ mod tests {
         let cmd = ResourceCreate3D {
             header,
             resource_id: 1,
+            target: 2,
             format: 67,
+            bind: 1,
             width: 512,
             height: 512,
             depth: 1,
-            target: 2,
-            bind: 1,
+            array_size: 1,
+            last_level: 0,
             nr_samples: 0,
             flags: 0,
             padding: 0,
@@ -1659,4 +3662,557 @@ mod tests {
         assert_eq!(cmd.box_.w, 64);
         assert_eq!(cmd.box_.h, 64);
     }
+
+    #[test]
+    fn test_gpu_with_config() {
+        let exit_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let gpu = Gpu::new_with_config(
+            "_gpu0".to_string(),
+            1920,
+            1080,
+            4,
+            false,
+            false,
+            SeccompAction::Allow,
+            exit_evt,
+        )
+        .unwrap();
+        assert_eq!(gpu.id(), "_gpu0");
+        assert_eq!(gpu.num_scanouts(), 4);
+        assert!(gpu.features() & (1u64 << VIRTIO_GPU_F_EDID) == 0);
+        assert!(gpu.scanout_frame(0).is_none());
+
+        let exit_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        assert!(
+            Gpu::new_with_config(
+                "_gpu1".to_string(),
+                1920,
+                1080,
+                VIRTIO_GPU_MAX_SCANOUTS + 1,
+                true,
+                false,
+                SeccompAction::Allow,
+                exit_evt,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_display_hotplug() {
+        let exit_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut gpu = Gpu::new_with_config(
+            "_gpu0".to_string(),
+            1920,
+            1080,
+            3,
+            true,
+            false,
+            SeccompAction::Allow,
+            exit_evt,
+        )
+        .unwrap();
+        assert_eq!(gpu.displays(), vec![Some((1920, 1080)), None, None]);
+
+        // The guest is only notified once the device is activated
+        gpu.set_display(2, Some((1280, 720))).unwrap();
+        assert_eq!(gpu.config.lock().unwrap().events_read, 0);
+        gpu.interrupt_cb = Some(Arc::new(NoopVirtioInterrupt {}));
+        gpu.set_display(0, None).unwrap();
+        gpu.set_display(3, Some((1280, 720))).unwrap_err();
+        gpu.set_display(1, Some((0, 720))).unwrap_err();
+        assert_eq!(gpu.displays(), vec![None, None, Some((1280, 720))]);
+        assert_eq!(
+            gpu.config.lock().unwrap().events_read,
+            VIRTIO_GPU_EVENT_DISPLAY
+        );
+
+        // The guest reads the new layout once notified
+        let mut handler = test_handler(3);
+        handler.displays = gpu.displays.clone();
+        let info = handler.handle_get_display_info();
+        assert_eq!(info.pmodes[0].enabled, 0);
+        assert_eq!(info.pmodes[1].enabled, 0);
+        assert_eq!(info.pmodes[2].enabled, 1);
+        assert_eq!(
+            (info.pmodes[2].r.width, info.pmodes[2].r.height),
+            (1280, 720)
+        );
+    }
+
+    #[test]
+    fn test_get_edid() {
+        let mut handler = test_handler(2);
+        handler.displays.lock().unwrap()[1].r = Rect {
+            x: 0,
+            y: 0,
+            width: 2560,
+            height: 1440,
+        };
+        let header = GpuEpollHandler::create_response_header(0);
+
+        let resp = handler
+            .handle_get_edid(&GetEdid {
+                header,
+                scanout: 1,
+                padding: 0,
+            })
+            .unwrap();
+        assert_eq!(resp.header.hdr_type, VIRTIO_GPU_RESP_OK_EDID);
+        assert_eq!(resp.size as usize, EDID_BLOCK_SIZE);
+
+        let edid = &resp.edid[..EDID_BLOCK_SIZE];
+        assert_eq!(
+            &edid[..8],
+            &[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]
+        );
+        assert_eq!(edid.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)), 0);
+        assert_eq!(u32::from_le_bytes(edid[12..16].try_into().unwrap()), 2);
+        // Preferred timing
+        let dtd = &edid[54..72];
+        assert_eq!(u32::from(dtd[2]) | (u32::from(dtd[4] >> 4) << 8), 2560);
+        assert_eq!(u32::from(dtd[5]) | (u32::from(dtd[7] >> 4) << 8), 1440);
+        // Monitor name
+        assert_eq!(edid[75], 0xfc);
+        assert_eq!(&edid[77..87], b"CHV-GPU-1\n");
+
+        let invalid = handler.handle_get_edid(&GetEdid {
+            header,
+            scanout: 2,
+            padding: 0,
+        });
+        assert_eq!(
+            invalid.unwrap_err().hdr_type,
+            VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID
+        );
+
+        handler.edid = false;
+        let unsupported = handler.handle_get_edid(&GetEdid {
+            header,
+            scanout: 0,
+            padding: 0,
+        });
+        assert_eq!(
+            unsupported.unwrap_err().hdr_type,
+            VIRTIO_GPU_RESP_ERR_UNSPEC
+        );
+    }
+
+    #[test]
+    fn test_cursor_update_and_move() {
+        let mut handler = test_handler(2);
+
+        let header = GpuEpollHandler::create_response_header(0);
+        let create = ResourceCreate2D {
+            header,
+            resource_id: 1,
+            format: VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM,
+            width: 2,
+            height: 2,
+        };
+        handler.handle_resource_create_2d(&create);
+        handler
+            .resources
+            .lock()
+            .unwrap()
+            .get_mut(&1)
+            .unwrap()
+            .data
+            .copy_from_slice(&[0xaa; 16]);
+
+        // Unknown resources leave the cursor untouched
+        let mut cmd = UpdateCursor {
+            header,
+            pos: CursorPos {
+                scanout_id: 1,
+                x: 10,
+                y: 20,
+                padding: 0,
+            },
+            resource_id: 2,
+            hot_x: 1,
+            hot_y: 1,
+            padding: 0,
+        };
+        handler.handle_update_cursor(&cmd);
+        assert_eq!(handler.cursor.lock().unwrap().update_count, 0);
+
+        cmd.resource_id = 1;
+        handler.handle_update_cursor(&cmd);
+        {
+            let cursor = handler.cursor.lock().unwrap();
+            assert!(cursor.visible);
+            assert_eq!((cursor.scanout_id, cursor.x, cursor.y), (1, 10, 20));
+            assert_eq!((cursor.width, cursor.height), (2, 2));
+            assert_eq!((cursor.hot_x, cursor.hot_y), (1, 1));
+            assert_eq!(cursor.format, VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM);
+            assert_eq!(cursor.data, vec![0xaa; 16]);
+            assert_eq!(cursor.update_count, 1);
+        }
+
+        // Moving keeps the shape
+        cmd.pos.x = 30;
+        cmd.pos.y = 40;
+        handler.handle_move_cursor(&cmd);
+        {
+            let cursor = handler.cursor.lock().unwrap();
+            assert!(cursor.visible);
+            assert_eq!((cursor.x, cursor.y), (30, 40));
+            assert_eq!(cursor.data.len(), 16);
+            assert_eq!(cursor.update_count, 2);
+        }
+
+        // A null resource hides the cursor
+        cmd.resource_id = 0;
+        handler.handle_update_cursor(&cmd);
+        assert!(!handler.cursor.lock().unwrap().visible);
+    }
+
+    #[test]
+    fn test_scanout_flush_publishes_frame() {
+        let mut handler = test_handler(2);
+        let mem = handler.mem.memory();
+
+        // 4x2 resource whose backing is split across two guest regions
+        let header = GpuEpollHandler::create_response_header(0);
+        let create = ResourceCreate2D {
+            header,
+            resource_id: 1,
+            format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+            width: 4,
+            height: 2,
+        };
+        assert_eq!(
+            handler.handle_resource_create_2d(&create).hdr_type,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        let pixels: Vec<u8> = (0..32).collect();
+        mem.write_slice(&pixels[..16], GuestAddress(0x1000)).unwrap();
+        mem.write_slice(&pixels[16..], GuestAddress(0x8000)).unwrap();
+        let attach = ResourceAttachBacking {
+            header,
+            resource_id: 1,
+            nr_entries: 2,
+        };
+        let entries = vec![
+            MemEntry {
+                addr: 0x1000,
+                length: 16,
+                padding: 0,
+            },
+            MemEntry {
+                addr: 0x8000,
+                length: 16,
+                padding: 0,
+            },
+        ];
+        handler.handle_resource_attach_backing(&attach, entries);
+
+        let r = Rect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 2,
+        };
+        let transfer = TransferToHost2D {
+            header,
+            r,
+            offset: 0,
+            resource_id: 1,
+            padding: 0,
+        };
+        assert_eq!(
+            handler.handle_transfer_to_host_2d(&*mem, &transfer).hdr_type,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        // Unknown scanouts and resources are rejected
+        let mut set_scanout = SetScanout {
+            header,
+            r: Rect {
+                x: 1,
+                y: 0,
+                width: 2,
+                height: 2,
+            },
+            scanout_id: 2,
+            resource_id: 1,
+        };
+        assert_eq!(
+            handler.handle_set_scanout(&set_scanout).hdr_type,
+            VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID
+        );
+        set_scanout.scanout_id = 1;
+        set_scanout.resource_id = 2;
+        assert_eq!(
+            handler.handle_set_scanout(&set_scanout).hdr_type,
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+        set_scanout.resource_id = 1;
+        assert_eq!(
+            handler.handle_set_scanout(&set_scanout).hdr_type,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        let flush = ResourceFlush {
+            header,
+            r,
+            resource_id: 1,
+            padding: 0,
+        };
+        handler.handle_resource_flush(&flush);
+        handler.handle_resource_flush(&flush);
+
+        let frames = handler.scanout_frames.lock().unwrap();
+        assert!(frames[0].is_none());
+        let frame = frames[1].as_ref().unwrap();
+        assert_eq!((frame.width, frame.height, frame.stride), (2, 2, 8));
+        assert_eq!(frame.format_name(), "BGRA32");
+        assert_eq!(frame.frame_number, 2);
+        let expected: Vec<u8> = (4..12).chain(20..28).collect();
+        assert_eq!(frame.clone().into_data().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_3d_scanout_flush_publishes_frame() {
+        let mut handler = test_handler(1);
+        handler.virgl_enabled = true;
+        let mem = handler.mem.memory();
+
+        let header = GpuEpollHandler::create_response_header(0);
+        let create = ResourceCreate3D {
+            header,
+            resource_id: 3,
+            target: 2,
+            format: VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM,
+            bind: 1 << 1,
+            width: 4,
+            height: 2,
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+            padding: 0,
+        };
+        assert_eq!(
+            handler.handle_resource_create_3d(&create).hdr_type,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        let pixels: Vec<u8> = (0..32).collect();
+        mem.write_slice(&pixels, GuestAddress(0x2000)).unwrap();
+        let attach = ResourceAttachBacking {
+            header,
+            resource_id: 3,
+            nr_entries: 1,
+        };
+        let entries = vec![MemEntry {
+            addr: 0x2000,
+            length: 32,
+            padding: 0,
+        }];
+        assert_eq!(
+            handler.handle_resource_attach_backing(&attach, entries).hdr_type,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        let transfer = Transfer3D {
+            header,
+            box_: Box3D {
+                x: 0,
+                y: 0,
+                z: 0,
+                w: 4,
+                h: 2,
+                d: 1,
+            },
+            offset: 0,
+            resource_id: 3,
+            level: 0,
+            stride: 16,
+            layer_stride: 0,
+        };
+        assert_eq!(
+            handler.handle_transfer_to_host_3d(&*mem, &transfer).hdr_type,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        // 3D resources can be displayed, within their bounds only
+        let mut set_scanout = SetScanout {
+            header,
+            r: Rect {
+                x: 1,
+                y: 0,
+                width: 4,
+                height: 2,
+            },
+            scanout_id: 0,
+            resource_id: 3,
+        };
+        assert_eq!(
+            handler.handle_set_scanout(&set_scanout).hdr_type,
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+        set_scanout.r.width = 2;
+        assert_eq!(
+            handler.handle_set_scanout(&set_scanout).hdr_type,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        let flush = ResourceFlush {
+            header,
+            r: set_scanout.r,
+            resource_id: 3,
+            padding: 0,
+        };
+        assert_eq!(
+            handler.handle_resource_flush(&flush).hdr_type,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        let frame = handler.scanout_frames.lock().unwrap()[0].clone().unwrap();
+        assert_eq!((frame.width, frame.height, frame.stride), (2, 2, 8));
+        assert_eq!(frame.frame_number, 1);
+        let expected: Vec<u8> = (4..12).chain(20..28).collect();
+        assert_eq!(frame.clone().into_data().unwrap(), expected);
+
+        // Releasing the resource disables the scanout
+        let unref = ResourceUnref {
+            header,
+            resource_id: 3,
+            padding: 0,
+        };
+        handler.handle_resource_unref(&unref);
+        assert_eq!(
+            handler.handle_resource_flush(&flush).hdr_type,
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+    }
+
+    #[test]
+    fn test_guest_blob_scanout_flush_reads_guest_memory() {
+        let mut handler = test_handler(1);
+        let mem = handler.mem.memory();
+
+        // 4x2 BGRA buffer with a 32 bytes stride, starting 8 bytes in
+        let pixels: Vec<u8> = (0..72).collect();
+        mem.write_slice(&pixels, GuestAddress(0x3000)).unwrap();
+
+        let header = GpuEpollHandler::create_response_header(0);
+        let mut create = ResourceCreateBlob {
+            header,
+            resource_id: 5,
+            blob_mem: VIRTIO_GPU_BLOB_MEM_GUEST,
+            blob_flags: VIRTIO_GPU_BLOB_FLAG_USE_MAPPABLE,
+            nr_entries: 2,
+            blob_id: 0,
+            size: 72,
+        };
+        let entries = vec![
+            MemEntry {
+                addr: 0x3000,
+                length: 40,
+                padding: 0,
+            },
+            MemEntry {
+                addr: 0x3028,
+                length: 32,
+                padding: 0,
+            },
+        ];
+        // Guest blobs can't be mapped
+        assert_eq!(
+            handler
+                .handle_resource_create_blob(&create, entries.clone())
+                .hdr_type,
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+        create.blob_flags = 0;
+        assert_eq!(
+            handler.handle_resource_create_blob(&create, entries).hdr_type,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        let r = Rect {
+            x: 1,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        let mut set_scanout = SetScanoutBlob {
+            header,
+            r,
+            scanout_id: 0,
+            resource_id: 5,
+            width: 4,
+            height: 2,
+            format: VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM,
+            padding: 0,
+            strides: [32, 0, 0, 0],
+            offsets: [8, 0, 0, 0],
+        };
+        set_scanout.offsets[0] = 48;
+        assert_eq!(
+            handler.handle_set_scanout_blob(&set_scanout).hdr_type,
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+        set_scanout.offsets[0] = 8;
+        assert_eq!(
+            handler.handle_set_scanout_blob(&set_scanout).hdr_type,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        let flush = ResourceFlush {
+            header,
+            r,
+            resource_id: 5,
+            padding: 0,
+        };
+        assert_eq!(
+            handler.handle_resource_flush(&flush).hdr_type,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        // No transfer happened, the frame points at guest memory
+        let frame = handler.scanout_frames.lock().unwrap()[0].clone().unwrap();
+        assert_eq!((frame.width, frame.height, frame.stride), (2, 2, 8));
+        assert!(matches!(frame.pixels, ScanoutPixels::Blob(_)));
+        let expected: Vec<u8> = (12..20).chain(44..52).collect();
+        assert_eq!(frame.clone().into_data().unwrap(), expected);
+
+        // The pixels are only copied when read
+        mem.write_slice(&[0xff; 8], GuestAddress(0x300c)).unwrap();
+        let expected: Vec<u8> = [0xff; 8].into_iter().chain(44..52).collect();
+        assert_eq!(frame.into_data().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_host_visible_region_mapping() {
+        // SAFETY: sysconf has no side effect.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let region = MmapRegion::build(
+            None,
+            4 * page_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+        )
+        .unwrap();
+
+        let blob = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        blob.set_len(page_size as u64).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&blob, b"blob", 0).unwrap();
+
+        map_into_region(&region, 3, 4, blob.as_raw_fd()).unwrap_err();
+        map_into_region(&region, 3 * page_size as u64, 2 * page_size as u64, -1).unwrap_err();
+        map_into_region(&region, page_size as u64, 4, blob.as_raw_fd()).unwrap();
+
+        let mut data = [0u8; 4];
+        region.get_slice(page_size, 4).unwrap().copy_to(&mut data[..]);
+        assert_eq!(&data, b"blob");
+
+        unmap_from_region(&region, page_size as u64, 4).unwrap();
+        region.get_slice(page_size, 4).unwrap().copy_to(&mut data[..]);
+        assert_eq!(data, [0u8; 4]);
+    }
 }
@@ -0,0 +1,750 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! virglrenderer backend for the VirtIO GPU 3D command set
+//!
+//! The renderer runs on the virtio-gpu epoll thread using a surfaceless EGL
+//! display. Fences are retired by the virglrenderer sync thread, which wakes
+//! the epoll thread through the renderer poll fd.
+
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, c_void};
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use libc::iovec;
+use thiserror::Error;
+
+/// Gallium command stream capset
+pub const VIRTIO_GPU_CAPSET_VIRGL: u32 = 1;
+/// Gallium command stream capset, second revision
+pub const VIRTIO_GPU_CAPSET_VIRGL2: u32 = 2;
+/// Capsets exposed to the guest, in capset index order
+pub const VIRGL_CAPSETS: [u32; 2] = [VIRTIO_GPU_CAPSET_VIRGL, VIRTIO_GPU_CAPSET_VIRGL2];
+
+const VIRGL_RENDERER_USE_EGL: c_int = 1 << 0;
+const VIRGL_RENDERER_THREAD_SYNC: c_int = 1 << 1;
+const VIRGL_RENDERER_USE_SURFACELESS: c_int = 1 << 3;
+const VIRGL_RENDERER_USE_GLES: c_int = 1 << 4;
+
+const VIRGL_RENDERER_CALLBACKS_VERSION: c_int = 1;
+
+/// The resource origin is at the top left corner
+const VIRGL_RESOURCE_Y_0_TOP: u32 = 1 << 0;
+
+/// virglrenderer keeps global state, only one renderer may exist at a time
+static RENDERER_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("A virglrenderer instance already exists")]
+    AlreadyInitialized,
+    #[error("virgl_renderer_init failed: {0}")]
+    Init(i32),
+    #[error("virglrenderer call {0} failed: {1}")]
+    Call(&'static str, i32),
+    #[error("Unknown capset index: {0}")]
+    InvalidCapsetIndex(u32),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+mod ffi {
+    use super::*;
+
+    #[repr(C)]
+    pub struct virgl_renderer_callbacks {
+        pub version: c_int,
+        pub write_fence: Option<unsafe extern "C" fn(cookie: *mut c_void, fence: u32)>,
+        pub create_gl_context: Option<
+            unsafe extern "C" fn(
+                cookie: *mut c_void,
+                scanout_idx: c_int,
+                param: *mut c_void,
+            ) -> *mut c_void,
+        >,
+        pub destroy_gl_context: Option<unsafe extern "C" fn(cookie: *mut c_void, ctx: *mut c_void)>,
+        pub make_current: Option<
+            unsafe extern "C" fn(
+                cookie: *mut c_void,
+                scanout_idx: c_int,
+                ctx: *mut c_void,
+            ) -> c_int,
+        >,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct virgl_renderer_resource_create_args {
+        pub handle: u32,
+        pub target: u32,
+        pub format: u32,
+        pub bind: u32,
+        pub width: u32,
+        pub height: u32,
+        pub depth: u32,
+        pub array_size: u32,
+        pub last_level: u32,
+        pub nr_samples: u32,
+        pub flags: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct virgl_renderer_resource_info {
+        pub handle: u32,
+        pub virgl_format: u32,
+        pub width: u32,
+        pub height: u32,
+        pub depth: u32,
+        pub flags: u32,
+        pub tex_id: u32,
+        pub stride: u32,
+        pub drm_fourcc: c_int,
+    }
+
+    #[repr(C)]
+    pub struct virgl_renderer_resource_create_blob_args {
+        pub res_handle: u32,
+        pub ctx_id: u32,
+        pub blob_mem: u32,
+        pub blob_flags: u32,
+        pub blob_id: u64,
+        pub size: u64,
+        pub iovecs: *const iovec,
+        pub num_iovs: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct virgl_box {
+        pub x: u32,
+        pub y: u32,
+        pub z: u32,
+        pub w: u32,
+        pub h: u32,
+        pub d: u32,
+    }
+
+    unsafe extern "C" {
+        pub fn virgl_renderer_init(
+            cookie: *mut c_void,
+            flags: c_int,
+            cb: *mut virgl_renderer_callbacks,
+        ) -> c_int;
+        pub fn virgl_renderer_cleanup(cookie: *mut c_void);
+        pub fn virgl_renderer_poll();
+        pub fn virgl_renderer_get_poll_fd() -> c_int;
+        pub fn virgl_renderer_create_fence(client_fence_id: c_int, ctx_id: u32) -> c_int;
+        pub fn virgl_renderer_get_cap_set(set: u32, max_ver: *mut u32, max_size: *mut u32);
+        pub fn virgl_renderer_fill_caps(set: u32, version: u32, caps: *mut c_void);
+        pub fn virgl_renderer_context_create(handle: u32, nlen: u32, name: *const c_char) -> c_int;
+        pub fn virgl_renderer_context_destroy(handle: u32);
+        pub fn virgl_renderer_ctx_attach_resource(ctx_id: c_int, res_handle: c_int);
+        pub fn virgl_renderer_ctx_detach_resource(ctx_id: c_int, res_handle: c_int);
+        pub fn virgl_renderer_resource_create(
+            args: *mut virgl_renderer_resource_create_args,
+            iov: *mut iovec,
+            num_iovs: u32,
+        ) -> c_int;
+        pub fn virgl_renderer_resource_unref(res_handle: u32);
+        pub fn virgl_renderer_resource_attach_iov(
+            res_handle: c_int,
+            iov: *mut iovec,
+            num_iovs: c_int,
+        ) -> c_int;
+        pub fn virgl_renderer_resource_detach_iov(
+            res_handle: c_int,
+            iov: *mut *mut iovec,
+            num_iovs: *mut c_int,
+        );
+        pub fn virgl_renderer_resource_get_info(
+            res_handle: c_int,
+            info: *mut virgl_renderer_resource_info,
+        ) -> c_int;
+        pub fn virgl_renderer_resource_create_blob(
+            args: *const virgl_renderer_resource_create_blob_args,
+        ) -> c_int;
+        pub fn virgl_renderer_resource_export_blob(
+            res_id: u32,
+            fd_type: *mut u32,
+            fd: *mut c_int,
+        ) -> c_int;
+        pub fn virgl_renderer_submit_cmd(buffer: *mut c_void, ctx_id: c_int, ndw: c_int) -> c_int;
+        pub fn virgl_renderer_transfer_read_iov(
+            handle: u32,
+            ctx_id: u32,
+            level: u32,
+            stride: u32,
+            layer_stride: u32,
+            box_: *mut virgl_box,
+            offset: u64,
+            iov: *mut iovec,
+            iovec_cnt: c_int,
+        ) -> c_int;
+        pub fn virgl_renderer_transfer_write_iov(
+            handle: u32,
+            ctx_id: u32,
+            level: c_int,
+            stride: u32,
+            layer_stride: u32,
+            box_: *mut virgl_box,
+            offset: u64,
+            iovec: *mut iovec,
+            iovec_cnt: u32,
+        ) -> c_int;
+    }
+}
+
+/// Region of a 3D resource, in texels
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VirglBox {
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+    pub w: u32,
+    pub h: u32,
+    pub d: u32,
+}
+
+impl From<VirglBox> for ffi::virgl_box {
+    fn from(b: VirglBox) -> Self {
+        ffi::virgl_box {
+            x: b.x,
+            y: b.y,
+            z: b.z,
+            w: b.w,
+            h: b.h,
+            d: b.d,
+        }
+    }
+}
+
+/// Arguments of a 3D resource creation
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceCreateArgs {
+    pub handle: u32,
+    pub target: u32,
+    pub format: u32,
+    pub bind: u32,
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+    pub array_size: u32,
+    pub last_level: u32,
+    pub nr_samples: u32,
+    pub flags: u32,
+}
+
+/// Arguments of a blob resource creation
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlobCreateArgs {
+    pub handle: u32,
+    pub ctx_id: u32,
+    pub blob_mem: u32,
+    pub blob_flags: u32,
+    pub blob_id: u64,
+    pub size: u64,
+}
+
+unsafe extern "C" fn write_fence(cookie: *mut c_void, fence: u32) {
+    // SAFETY: The cookie is the retired fence counter owned by the renderer,
+    // which outlives virglrenderer.
+    let retired = unsafe { &*(cookie as *const AtomicU32) };
+    // Fences of the global timeline retire in creation order
+    retired.store(fence, Ordering::Release);
+}
+
+fn check(call: &'static str, ret: c_int) -> Result<()> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(Error::Call(call, ret))
+    }
+}
+
+/// Handle on the process wide virglrenderer instance
+///
+/// The EGL context is current on the thread that created the renderer, so
+/// the handle must only be used from that thread.
+pub struct VirglRenderer {
+    /// Callbacks handed to virglrenderer, which keeps a pointer to them
+    _callbacks: Box<ffi::virgl_renderer_callbacks>,
+    /// Last fence reported by write_fence, also used as the callbacks cookie
+    retired_fence: Box<AtomicU32>,
+    /// Last fence handed to virglrenderer
+    last_fence: u32,
+    /// Guest backing attached to resources, virglrenderer does not copy
+    /// the iovec arrays
+    backing: HashMap<u32, Vec<iovec>>,
+}
+
+// SAFETY: The renderer is created and dropped on the virtio-gpu thread. Send
+// is only needed to move the epoll handler, whose renderer slot is still
+// empty, to that thread.
+unsafe impl Send for VirglRenderer {}
+
+impl VirglRenderer {
+    /// Initialize virglrenderer on the calling thread
+    pub fn new() -> Result<Self> {
+        if RENDERER_ACTIVE.swap(true, Ordering::SeqCst) {
+            return Err(Error::AlreadyInitialized);
+        }
+
+        let mut callbacks = Box::new(ffi::virgl_renderer_callbacks {
+            version: VIRGL_RENDERER_CALLBACKS_VERSION,
+            write_fence: Some(write_fence),
+            create_gl_context: None,
+            destroy_gl_context: None,
+            make_current: None,
+        });
+        let retired_fence = Box::new(AtomicU32::new(0));
+
+        // SAFETY: The callbacks and the cookie outlive the renderer, which is
+        // cleaned up when this handle is dropped.
+        let ret = unsafe {
+            ffi::virgl_renderer_init(
+                retired_fence.as_ref() as *const AtomicU32 as *mut c_void,
+                VIRGL_RENDERER_USE_EGL
+                    | VIRGL_RENDERER_THREAD_SYNC
+                    | VIRGL_RENDERER_USE_SURFACELESS
+                    | VIRGL_RENDERER_USE_GLES,
+                callbacks.as_mut(),
+            )
+        };
+        if ret != 0 {
+            RENDERER_ACTIVE.store(false, Ordering::SeqCst);
+            return Err(Error::Init(ret));
+        }
+
+        Ok(Self {
+            _callbacks: callbacks,
+            retired_fence,
+            last_fence: 0,
+            backing: HashMap::new(),
+        })
+    }
+
+    /// File descriptor becoming readable when fences need to be retired with
+    /// `poll()`
+    ///
+    /// There is none when virglrenderer could not start its sync thread, the
+    /// renderer must then be polled until the fences retire.
+    pub fn poll_fd(&self) -> Option<RawFd> {
+        // SAFETY: Trivially safe, the fd stays owned by virglrenderer.
+        let fd = unsafe { ffi::virgl_renderer_get_poll_fd() };
+        (fd >= 0).then_some(fd)
+    }
+
+    /// Retire the completed fences
+    pub fn poll(&mut self) {
+        // SAFETY: The renderer was successfully initialized by new().
+        unsafe { ffi::virgl_renderer_poll() };
+    }
+
+    /// Queue a fence after the commands submitted so far, returning its id
+    pub fn create_fence(&mut self, ctx_id: u32) -> Result<u32> {
+        let fence = self.last_fence.wrapping_add(1);
+        // SAFETY: Trivially safe.
+        let ret = unsafe { ffi::virgl_renderer_create_fence(fence as c_int, ctx_id) };
+        check("create_fence", ret)?;
+        self.last_fence = fence;
+        Ok(fence)
+    }
+
+    /// Whether the fence returned by `create_fence()` has retired
+    pub fn fence_retired(&self, fence: u32) -> bool {
+        let retired = self.retired_fence.load(Ordering::Acquire);
+        // Fence ids wrap around, compare them as a distance
+        retired.wrapping_sub(fence) as i32 >= 0
+    }
+
+    /// Get `(capset_id, max_version, max_size)` for the capset at `index`
+    pub fn capset_info(&self, index: u32) -> Result<(u32, u32, u32)> {
+        let id = *VIRGL_CAPSETS
+            .get(index as usize)
+            .ok_or(Error::InvalidCapsetIndex(index))?;
+        let mut max_ver = 0;
+        let mut max_size = 0;
+        // SAFETY: Both pointers are valid for writes.
+        unsafe { ffi::virgl_renderer_get_cap_set(id, &mut max_ver, &mut max_size) };
+        Ok((id, max_ver, max_size))
+    }
+
+    /// Get the capabilities blob of `capset_id`
+    pub fn capset(&self, capset_id: u32, version: u32) -> Vec<u8> {
+        let mut max_ver = 0;
+        let mut max_size = 0;
+        // SAFETY: Both pointers are valid for writes.
+        unsafe { ffi::virgl_renderer_get_cap_set(capset_id, &mut max_ver, &mut max_size) };
+        let mut caps = vec![0u8; max_size as usize];
+        if !caps.is_empty() {
+            // SAFETY: caps holds max_size bytes as reported by virglrenderer.
+            unsafe {
+                ffi::virgl_renderer_fill_caps(capset_id, version, caps.as_mut_ptr() as *mut c_void);
+            }
+        }
+        caps
+    }
+
+    pub fn create_context(&mut self, ctx_id: u32, name: &[u8]) -> Result<()> {
+        // SAFETY: name is valid for name.len() bytes.
+        let ret = unsafe {
+            ffi::virgl_renderer_context_create(
+                ctx_id,
+                name.len() as u32,
+                name.as_ptr() as *const c_char,
+            )
+        };
+        check("context_create", ret)
+    }
+
+    pub fn destroy_context(&mut self, ctx_id: u32) {
+        // SAFETY: Unknown contexts are ignored by virglrenderer.
+        unsafe { ffi::virgl_renderer_context_destroy(ctx_id) };
+    }
+
+    pub fn ctx_attach_resource(&mut self, ctx_id: u32, resource_id: u32) {
+        // SAFETY: Unknown contexts and resources are ignored by virglrenderer.
+        unsafe { ffi::virgl_renderer_ctx_attach_resource(ctx_id as c_int, resource_id as c_int) };
+    }
+
+    pub fn ctx_detach_resource(&mut self, ctx_id: u32, resource_id: u32) {
+        // SAFETY: Unknown contexts and resources are ignored by virglrenderer.
+        unsafe { ffi::virgl_renderer_ctx_detach_resource(ctx_id as c_int, resource_id as c_int) };
+    }
+
+    pub fn create_resource(&mut self, args: &ResourceCreateArgs) -> Result<()> {
+        let mut args = ffi::virgl_renderer_resource_create_args {
+            handle: args.handle,
+            target: args.target,
+            format: args.format,
+            bind: args.bind,
+            width: args.width,
+            height: args.height,
+            depth: args.depth,
+            array_size: args.array_size,
+            last_level: args.last_level,
+            nr_samples: args.nr_samples,
+            flags: args.flags,
+        };
+        // SAFETY: args is valid and no backing is passed at creation time.
+        let ret =
+            unsafe { ffi::virgl_renderer_resource_create(&mut args, std::ptr::null_mut(), 0) };
+        check("resource_create", ret)
+    }
+
+    /// Create a blob resource, `iovecs` being its guest backing if any
+    ///
+    /// # Safety
+    ///
+    /// Same requirements as [`VirglRenderer::attach_backing`].
+    pub unsafe fn create_blob(&mut self, args: &BlobCreateArgs, iovecs: Vec<iovec>) -> Result<()> {
+        let ffi_args = ffi::virgl_renderer_resource_create_blob_args {
+            res_handle: args.handle,
+            ctx_id: args.ctx_id,
+            blob_mem: args.blob_mem,
+            blob_flags: args.blob_flags,
+            blob_id: args.blob_id,
+            size: args.size,
+            iovecs: if iovecs.is_empty() {
+                std::ptr::null()
+            } else {
+                iovecs.as_ptr()
+            },
+            num_iovs: iovecs.len() as u32,
+        };
+        // SAFETY: The caller guarantees the iovecs are valid. The array is
+        // kept alive in self.backing until the resource is released.
+        let ret = unsafe { ffi::virgl_renderer_resource_create_blob(&ffi_args) };
+        check("resource_create_blob", ret)?;
+        if !iovecs.is_empty() {
+            self.backing.insert(args.handle, iovecs);
+        }
+        Ok(())
+    }
+
+    /// Get a file descriptor on the memory of a blob resource
+    pub fn export_blob(&mut self, resource_id: u32) -> Result<OwnedFd> {
+        let mut fd_type = 0;
+        let mut fd: c_int = -1;
+        // SAFETY: Both pointers are valid for writes.
+        let ret =
+            unsafe { ffi::virgl_renderer_resource_export_blob(resource_id, &mut fd_type, &mut fd) };
+        check("resource_export_blob", ret)?;
+        // SAFETY: On success, virglrenderer hands over a new file descriptor.
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    pub fn unref_resource(&mut self, resource_id: u32) {
+        self.detach_backing(resource_id);
+        // SAFETY: Unknown resources are ignored by virglrenderer.
+        unsafe { ffi::virgl_renderer_resource_unref(resource_id) };
+    }
+
+    /// Attach guest backing to a resource
+    ///
+    /// # Safety
+    ///
+    /// Every entry of `iovecs` must point to host memory mapping guest RAM
+    /// which stays mapped until the backing is detached.
+    pub unsafe fn attach_backing(
+        &mut self,
+        resource_id: u32,
+        mut iovecs: Vec<iovec>,
+    ) -> Result<()> {
+        self.detach_backing(resource_id);
+        // SAFETY: The caller guarantees the iovecs are valid. The array is
+        // kept alive in self.backing until it is detached.
+        let ret = unsafe {
+            ffi::virgl_renderer_resource_attach_iov(
+                resource_id as c_int,
+                iovecs.as_mut_ptr(),
+                iovecs.len() as c_int,
+            )
+        };
+        check("resource_attach_iov", ret)?;
+        self.backing.insert(resource_id, iovecs);
+        Ok(())
+    }
+
+    pub fn detach_backing(&mut self, resource_id: u32) {
+        if self.backing.remove(&resource_id).is_some() {
+            let mut iov: *mut iovec = std::ptr::null_mut();
+            let mut num_iovs: c_int = 0;
+            // SAFETY: The returned array is the one owned by self.backing,
+            // which has just been released.
+            unsafe {
+                ffi::virgl_renderer_resource_detach_iov(
+                    resource_id as c_int,
+                    &mut iov,
+                    &mut num_iovs,
+                );
+            }
+        }
+    }
+
+    /// Copy from the resource backing into the host resource
+    #[allow(clippy::too_many_arguments)]
+    pub fn transfer_write(
+        &mut self,
+        resource_id: u32,
+        ctx_id: u32,
+        level: u32,
+        stride: u32,
+        layer_stride: u32,
+        box_: VirglBox,
+        offset: u64,
+    ) -> Result<()> {
+        let mut box_ = ffi::virgl_box::from(box_);
+        // SAFETY: A null iovec makes virglrenderer use the attached backing.
+        let ret = unsafe {
+            ffi::virgl_renderer_transfer_write_iov(
+                resource_id,
+                ctx_id,
+                level as c_int,
+                stride,
+                layer_stride,
+                &mut box_,
+                offset,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        check("transfer_write_iov", ret)
+    }
+
+    /// Copy from the host resource into the resource backing
+    #[allow(clippy::too_many_arguments)]
+    pub fn transfer_read(
+        &mut self,
+        resource_id: u32,
+        ctx_id: u32,
+        level: u32,
+        stride: u32,
+        layer_stride: u32,
+        box_: VirglBox,
+        offset: u64,
+    ) -> Result<()> {
+        let mut box_ = ffi::virgl_box::from(box_);
+        // SAFETY: A null iovec makes virglrenderer use the attached backing.
+        let ret = unsafe {
+            ffi::virgl_renderer_transfer_read_iov(
+                resource_id,
+                ctx_id,
+                level,
+                stride,
+                layer_stride,
+                &mut box_,
+                offset,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        check("transfer_read_iov", ret)
+    }
+
+    pub fn submit(&mut self, ctx_id: u32, commands: &mut [u32]) -> Result<()> {
+        // SAFETY: commands is valid for commands.len() dwords.
+        let ret = unsafe {
+            ffi::virgl_renderer_submit_cmd(
+                commands.as_mut_ptr() as *mut c_void,
+                ctx_id as c_int,
+                commands.len() as c_int,
+            )
+        };
+        check("submit_cmd", ret)
+    }
+
+    /// Read the `w x h` rectangle at `(x, y)` of a 2D resource as 4 bytes per
+    /// pixel rows, top row first
+    pub fn read_pixels(
+        &mut self,
+        resource_id: u32,
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
+    ) -> Result<Vec<u8>> {
+        let mut info = ffi::virgl_renderer_resource_info::default();
+        // SAFETY: info is valid for writes.
+        let ret = unsafe { ffi::virgl_renderer_resource_get_info(resource_id as c_int, &mut info) };
+        check("resource_get_info", ret)?;
+
+        // Resources rendered by GL have their origin at the bottom left
+        let y_0_top = info.flags & VIRGL_RESOURCE_Y_0_TOP != 0;
+        let read_y = if y_0_top {
+            y
+        } else {
+            info.height.saturating_sub(y + h)
+        };
+
+        let stride = w as usize * 4;
+        let mut data = vec![0u8; stride * h as usize];
+        let mut iov = iovec {
+            iov_base: data.as_mut_ptr() as *mut c_void,
+            iov_len: data.len(),
+        };
+        let mut box_ = ffi::virgl_box {
+            x,
+            y: read_y,
+            z: 0,
+            w,
+            h,
+            d: 1,
+        };
+        // SAFETY: iov describes the data buffer which outlives the call.
+        let ret = unsafe {
+            ffi::virgl_renderer_transfer_read_iov(
+                resource_id,
+                0,
+                0,
+                stride as u32,
+                0,
+                &mut box_,
+                0,
+                &mut iov,
+                1,
+            )
+        };
+        check("transfer_read_iov", ret)?;
+
+        if !y_0_top {
+            let rows: Vec<&[u8]> = data.chunks_exact(stride).rev().collect();
+            data = rows.concat();
+        }
+
+        Ok(data)
+    }
+}
+
+impl Drop for VirglRenderer {
+    fn drop(&mut self) {
+        let resources: Vec<u32> = self.backing.keys().copied().collect();
+        for resource_id in resources {
+            self.detach_backing(resource_id);
+        }
+        // SAFETY: The renderer was successfully initialized by new().
+        unsafe {
+            ffi::virgl_renderer_cleanup(
+                self.retired_fence.as_ref() as *const AtomicU32 as *mut c_void
+            );
+        }
+        RENDERER_ACTIVE.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // B8G8R8A8_UNORM
+    const FORMAT: u32 = 1;
+    const PIPE_TEXTURE_2D: u32 = 2;
+    // PIPE_BIND_RENDER_TARGET | PIPE_BIND_SAMPLER_VIEW
+    const BIND: u32 = (1 << 1) | (1 << 3);
+
+    // These tests need virglrenderer and Mesa, rendering happens on llvmpipe
+    // so no GPU is required.
+    #[test]
+    fn test_virgl_software_roundtrip() {
+        let mut renderer = VirglRenderer::new().unwrap();
+        assert!(matches!(
+            VirglRenderer::new(),
+            Err(Error::AlreadyInitialized)
+        ));
+
+        let (id, max_ver, max_size) = renderer.capset_info(0).unwrap();
+        assert_eq!(id, VIRTIO_GPU_CAPSET_VIRGL);
+        assert!(max_ver >= 1);
+        assert_eq!(renderer.capset(id, max_ver).len(), max_size as usize);
+        renderer.capset_info(2).unwrap_err();
+
+        renderer.create_context(1, b"test").unwrap();
+        renderer
+            .create_resource(&ResourceCreateArgs {
+                handle: 1,
+                target: PIPE_TEXTURE_2D,
+                format: FORMAT,
+                bind: BIND,
+                width: 4,
+                height: 2,
+                depth: 1,
+                array_size: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        renderer.ctx_attach_resource(1, 1);
+
+        let mut pixels: Vec<u8> = (0..32).collect();
+        let iovecs = vec![iovec {
+            iov_base: pixels.as_mut_ptr() as *mut c_void,
+            iov_len: pixels.len(),
+        }];
+        // SAFETY: pixels outlives the backing, which is detached by unref.
+        unsafe { renderer.attach_backing(1, iovecs).unwrap() };
+        let full = VirglBox {
+            w: 4,
+            h: 2,
+            d: 1,
+            ..Default::default()
+        };
+        renderer.transfer_write(1, 1, 0, 16, 0, full, 0).unwrap();
+
+        let data = renderer.read_pixels(1, 1, 0, 2, 2).unwrap();
+        let expected: Vec<u8> = (4..12).chain(20..28).collect();
+        assert_eq!(data, expected);
+
+        let fence = renderer.create_fence(1).unwrap();
+        assert!(renderer.create_fence(1).unwrap() > fence);
+        while !renderer.fence_retired(fence + 1) {
+            renderer.poll();
+            std::thread::yield_now();
+        }
+        assert!(renderer.fence_retired(fence));
+
+        renderer.ctx_detach_resource(1, 1);
+        renderer.unref_resource(1);
+        renderer.destroy_context(1);
+    }
+}
@@ -2,41 +2,123 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 //
-// VirtIO Input Device Implementation - Minimal stub for input injection API
+// VirtIO Input Device Implementation
 
 use std::collections::VecDeque;
-use std::io;
-use std::result;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::{fmt, io, result};
 
 use anyhow::anyhow;
-use log::error;
+use event_monitor::event;
+use log::{debug, error, info, warn};
 use seccompiler::SeccompAction;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use virtio_queue::{Queue, QueueT};
-use vm_memory::{ByteValued, GuestMemoryAtomic};
-use vm_migration::{
-    Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable,
-};
-use vm_virtio::AccessPlatform;
+use vm_memory::{ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vm_virtio::{AccessPlatform, Translatable};
 use vmm_sys_util::eventfd::EventFd;
 
 use super::{
-    ActivateError, ActivateResult, Error as DeviceError, VirtioDevice, VirtioDeviceType,
-    VirtioInterrupt,
+    ActivateError, ActivateResult, EPOLL_HELPER_EVENT_LAST, EpollHelper, EpollHelperError,
+    EpollHelperHandler, Error as DeviceError, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
+    VirtioCommon, VirtioDevice, VirtioDeviceType,
 };
-use crate::GuestMemoryMmap;
-
-/// VirtIO Input device ID type
-pub const VIRTIO_ID_INPUT: u32 = 18;
+use crate::seccomp_filters::Thread;
+use crate::thread_helper::spawn_virtio_thread;
+use crate::{GuestMemoryMmap, VirtioInterrupt, VirtioInterruptType};
 
 /// Queue sizes
-const QUEUE_SIZES: &[u16] = &[64, 64];
+const QUEUE_SIZE: u16 = 64;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
+
+// Events sent to the guest, and LED updates sent by the guest.
+const EVENT_QUEUE: usize = 0;
+const STATUS_QUEUE: usize = 1;
+
+// New descriptors are pending on the event queue.
+const EVENT_QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// New descriptors are pending on the status queue.
+const STATUS_QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// New events were injected by the VMM.
+const INJECT_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+
+// Config space selectors, see the virtio-input section of the specification.
+const VIRTIO_INPUT_CFG_UNSET: u8 = 0x00;
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_PROP_BITS: u8 = 0x10;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+// select, subsel, size and reserved bytes, followed by the payload
+const CONFIG_HEADER_SIZE: usize = 8;
+const CONFIG_PAYLOAD_SIZE: usize = 128;
+
+/// Event types (include/uapi/linux/input-event-codes.h)
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+pub const EV_LED: u16 = 0x11;
+
+/// Relative axes
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
+
+/// Absolute axes
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+pub const ABS_MT_SLOT: u16 = 0x2f;
+pub const ABS_MT_POSITION_X: u16 = 0x35;
+pub const ABS_MT_POSITION_Y: u16 = 0x36;
+pub const ABS_MT_TRACKING_ID: u16 = 0x39;
+
+/// Mouse buttons
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_EXTRA: u16 = 0x114;
+pub const BTN_TOUCH: u16 = 0x14a;
+
+/// Input properties
+pub const INPUT_PROP_DIRECT: u16 = 0x01;
+
+/// Keyboard LEDs
+pub const LED_NUML: u16 = 0x00;
+pub const LED_CAPSL: u16 = 0x01;
+pub const LED_SCROLLL: u16 = 0x02;
+
+// Keyboard keys are the codes from KEY_ESC up to BTN_MISC.
+const KEY_CODES: std::ops::Range<u16> = 0x01..0x100;
+const BUTTON_CODES: std::ops::RangeInclusive<u16> = BTN_LEFT..=BTN_EXTRA;
+const LED_CODES: std::ops::RangeInclusive<u16> = LED_NUML..=LED_SCROLLL;
+
+/// Largest value of the absolute position axes, the host scales screen
+/// coordinates to 0..=ABS_MAX and the guest scales them back to its own
+/// resolution.
+pub const ABS_MAX: u32 = 0x7fff;
+
+/// Number of contacts tracked by the multi-touch pointer
+pub const TOUCH_SLOTS: u32 = 10;
+// Tracking IDs wrap around within 0..=TRACKING_ID_MAX, and -1 ends a contact
+const TRACKING_ID_MAX: u32 = 0xffff;
+const TRACKING_ID_NONE: u32 = u32::MAX;
+
+// Same identifiers as the QEMU virtio-input devices, so that the guests
+// treat the device the same way.
+const BUS_VIRTUAL: u16 = 0x06;
+const DEVICE_VENDOR: u16 = 0x0627;
+const DEVICE_PRODUCT: u16 = 0x0001;
+const DEVICE_VERSION: u16 = 0x0001;
 
 /// VirtIO Input event structure
 #[repr(C)]
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct VirtioInputEvent {
     /// Event type
     pub ev_type: u16,
@@ -46,13 +128,14 @@ pub struct VirtioInputEvent {
     pub value: u32,
 }
 
+// SAFETY: VirtioInputEvent only contains integers and has no implicit padding
 unsafe impl ByteValued for VirtioInputEvent {}
 
 impl VirtioInputEvent {
     /// Create a keyboard event
     pub fn keyboard(code: u16, pressed: bool) -> Self {
         Self {
-            ev_type: 0x01, // EV_KEY
+            ev_type: EV_KEY,
             code,
             value: if pressed { 1 } else { 0 },
         }
@@ -61,16 +144,25 @@ impl VirtioInputEvent {
     /// Create a relative mouse event
     pub fn rel(code: u16, value: i32) -> Self {
         Self {
-            ev_type: 0x02, // EV_REL
+            ev_type: EV_REL,
             code,
             value: value as u32,
         }
     }
 
+    /// Create an absolute axis event
+    pub fn abs(code: u16, value: u32) -> Self {
+        Self {
+            ev_type: EV_ABS,
+            code,
+            value,
+        }
+    }
+
     /// Create a sync event
     pub fn syn() -> Self {
         Self {
-            ev_type: 0x00, // EV_SYN
+            ev_type: EV_SYN,
             code: 0,
             value: 0,
         }
@@ -80,62 +172,388 @@ impl VirtioInputEvent {
 /// Maximum event queue size
 const EVENT_QUEUE_SIZE: usize = 256;
 
+/// Pointer exposed next to the keyboard
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PointerMode {
+    /// Mouse reporting relative movements
+    #[default]
+    Relative,
+    /// Tablet reporting absolute positions
+    Absolute,
+    /// Touchscreen reporting up to TOUCH_SLOTS contacts
+    Touch,
+}
+
+impl fmt::Display for PointerMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PointerMode::Relative => write!(f, "relative"),
+            PointerMode::Absolute => write!(f, "absolute"),
+            PointerMode::Touch => write!(f, "touch"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PointerModeParseError {
+    #[error("Invalid pointer mode: {0}")]
+    InvalidValue(String),
+}
+
+impl FromStr for PointerMode {
+    type Err = PointerModeParseError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "relative" => Ok(PointerMode::Relative),
+            "absolute" => Ok(PointerMode::Absolute),
+            "touch" => Ok(PointerMode::Touch),
+            _ => Err(PointerModeParseError::InvalidValue(s.to_string())),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+enum Error {
+    #[error("Descriptor chain too short")]
+    DescriptorChainTooShort,
+    #[error("Invalid descriptor")]
+    InvalidDescriptor,
+    #[error("Failed to read from guest memory")]
+    GuestMemoryRead(#[source] vm_memory::guest_memory::Error),
+    #[error("Failed to write to guest memory")]
+    GuestMemoryWrite(#[source] vm_memory::guest_memory::Error),
+    #[error("Failed adding used index")]
+    QueueAddUsed(#[source] virtio_queue::Error),
+}
+
+struct InputEpollHandler {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    queues: Vec<Queue>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    event_queue_evt: EventFd,
+    status_queue_evt: EventFd,
+    inject_evt: EventFd,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    events: Arc<Mutex<VecDeque<VirtioInputEvent>>>,
+    leds: Arc<AtomicU32>,
+    access_platform: Option<Arc<dyn AccessPlatform>>,
+}
+
+impl InputEpollHandler {
+    /// Hand the pending events over to the guest, as long as it provides
+    /// buffers for them. The remaining ones are sent when it posts more.
+    fn process_event_queue(&mut self) -> result::Result<bool, Error> {
+        let queue = &mut self.queues[EVENT_QUEUE];
+        let mut events = self.events.lock().unwrap();
+
+        let mut used_descs = false;
+        while let Some(&event) = events.front() {
+            let Some(mut desc_chain) = queue.pop_descriptor_chain(self.mem.memory()) else {
+                break;
+            };
+            let desc = desc_chain.next().ok_or(Error::DescriptorChainTooShort)?;
+
+            let len = std::mem::size_of::<VirtioInputEvent>();
+            if !desc.is_write_only() || (desc.len() as usize) < len {
+                return Err(Error::InvalidDescriptor);
+            }
+
+            desc_chain
+                .memory()
+                .write_obj(
+                    event,
+                    desc.addr()
+                        .translate_gva(self.access_platform.as_deref(), len),
+                )
+                .map_err(Error::GuestMemoryWrite)?;
+            events.pop_front();
+
+            queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), len as u32)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        Ok(used_descs)
+    }
+
+    /// Apply the events sent by the guest, which are only LED updates for
+    /// the supported event types.
+    fn process_status_queue(&mut self) -> result::Result<bool, Error> {
+        let queue = &mut self.queues[STATUS_QUEUE];
+
+        let mut used_descs = false;
+        while let Some(mut desc_chain) = queue.pop_descriptor_chain(self.mem.memory()) {
+            let desc = desc_chain.next().ok_or(Error::DescriptorChainTooShort)?;
+
+            let len = std::mem::size_of::<VirtioInputEvent>();
+            if desc.is_write_only() || (desc.len() as usize) < len {
+                return Err(Error::InvalidDescriptor);
+            }
+
+            let event: VirtioInputEvent = desc_chain
+                .memory()
+                .read_obj(
+                    desc.addr()
+                        .translate_gva(self.access_platform.as_deref(), len),
+                )
+                .map_err(Error::GuestMemoryRead)?;
+            handle_status_event(&self.leds, &event);
+
+            queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        Ok(used_descs)
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(VirtioInterruptType::Queue(queue_index as u16))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {e:?}");
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn handle_event_queue(&mut self) -> result::Result<(), EpollHelperError> {
+        let needs_notification = self.process_event_queue().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to process event queue: {e:?}"))
+        })?;
+        if needs_notification {
+            self.signal_used_queue(EVENT_QUEUE).map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {e:?}"))
+            })?;
+        }
+        Ok(())
+    }
+
+    fn run(
+        &mut self,
+        paused: &AtomicBool,
+        paused_sync: &Barrier,
+    ) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.event_queue_evt.as_raw_fd(), EVENT_QUEUE_AVAIL_EVENT)?;
+        helper.add_event(self.status_queue_evt.as_raw_fd(), STATUS_QUEUE_AVAIL_EVENT)?;
+        helper.add_event(self.inject_evt.as_raw_fd(), INJECT_EVENT)?;
+        helper.run(paused, paused_sync, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for InputEpollHandler {
+    fn handle_event(
+        &mut self,
+        _helper: &mut EpollHelper,
+        event: &epoll::Event,
+    ) -> result::Result<(), EpollHelperError> {
+        let ev_type = event.data as u16;
+        match ev_type {
+            EVENT_QUEUE_AVAIL_EVENT => {
+                self.event_queue_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {e:?}"))
+                })?;
+                self.handle_event_queue()?;
+            }
+            INJECT_EVENT => {
+                self.inject_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get inject event: {e:?}"))
+                })?;
+                self.handle_event_queue()?;
+            }
+            STATUS_QUEUE_AVAIL_EVENT => {
+                self.status_queue_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {e:?}"))
+                })?;
+                let needs_notification = self.process_status_queue().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to process status queue: {e:?}"))
+                })?;
+                if needs_notification {
+                    self.signal_used_queue(STATUS_QUEUE).map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {e:?}"))
+                    })?;
+                }
+            }
+            _ => {
+                return Err(EpollHelperError::HandleEvent(anyhow!(
+                    "Unexpected event: {ev_type}"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Record the LED state sent by the guest through the status queue
+fn handle_status_event(leds: &AtomicU32, event: &VirtioInputEvent) {
+    match event.ev_type {
+        EV_LED if LED_CODES.contains(&event.code) => {
+            let bit = 1 << event.code;
+            let previous = if event.value != 0 {
+                leds.fetch_or(bit, Ordering::SeqCst)
+            } else {
+                leds.fetch_and(!bit, Ordering::SeqCst)
+            };
+            debug!("LED {} set to {}", event.code, event.value);
+
+            let current = leds.load(Ordering::SeqCst);
+            if current != previous {
+                let lit = |code: u16| (current & (1 << code) != 0).to_string();
+                event!(
+                    "input",
+                    "state-changed",
+                    "backend",
+                    "virtio",
+                    "num-lock",
+                    lit(LED_NUML),
+                    "caps-lock",
+                    lit(LED_CAPSL),
+                    "scroll-lock",
+                    lit(LED_SCROLLL)
+                );
+            }
+        }
+        EV_SYN => {}
+        _ => warn!(
+            "Ignoring status event type {:#x} code {:#x}",
+            event.ev_type, event.code
+        ),
+    }
+}
+
 /// VirtIO Input device
+///
+/// Exposes a keyboard combined with the pointer selected by [`PointerMode`]
+/// to the guest. The VMM injects events through
+/// [`inject_keyboard`](Input::inject_keyboard) and friends, and reads back
+/// the keyboard LEDs set by the guest through [`leds`](Input::leds).
 pub struct Input {
-    /// Device ID
+    common: VirtioCommon,
     id: String,
-    /// Available features
-    avail_features: u64,
-    /// Acked features
-    acked_features: u64,
-    /// Event queue for injection
+    /// Name reported through VIRTIO_INPUT_CFG_ID_NAME
+    name: String,
+    /// Serial reported through VIRTIO_INPUT_CFG_ID_SERIAL
+    serial: String,
+    /// Kind of pointer, which decides the event codes reported
+    pointer: PointerMode,
+    /// Config space selector written by the guest
+    select: u8,
+    /// Config space sub-selector written by the guest
+    subsel: u8,
+    /// Events waiting for a buffer from the guest
     events: Arc<Mutex<VecDeque<VirtioInputEvent>>>,
     /// EventFd to signal new events
-    event_fd: EventFd,
-    /// Activated flag
-    activated: Arc<AtomicBool>,
-    /// Interrupt callback
-    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
-    /// Access platform
-    access_platform: Option<Arc<dyn AccessPlatform>>,
-    /// Seccomp action
+    inject_evt: EventFd,
+    /// Keyboard LEDs set by the guest, one bit per LED code
+    leds: Arc<AtomicU32>,
+    /// Touch slots with a contact, one bit per slot
+    touch_contacts: AtomicU32,
+    /// Tracking ID given to the next contact
+    next_tracking_id: AtomicU32,
     seccomp_action: SeccompAction,
-    /// Exit event
     exit_evt: EventFd,
 }
 
+/// Input device state for migration
+#[derive(Deserialize, Serialize)]
+pub struct InputState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+    #[serde(default)]
+    pub leds: u32,
+}
+
 impl Input {
     /// Create a new VirtIO Input device
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
-        _seccomp_action: SeccompAction,
+        name: String,
+        serial: String,
+        pointer: PointerMode,
+        iommu: bool,
+        seccomp_action: SeccompAction,
         exit_evt: EventFd,
+        state: Option<InputState>,
     ) -> io::Result<Self> {
+        let (avail_features, acked_features, leds, paused) = if let Some(state) = state {
+            info!("Restoring virtio-input {id}");
+            (state.avail_features, state.acked_features, state.leds, true)
+        } else {
+            let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+            if iommu {
+                avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+            }
+
+            (avail_features, 0, 0, false)
+        };
+
         Ok(Input {
+            common: VirtioCommon {
+                device_type: VirtioDeviceType::Input as u32,
+                queue_sizes: QUEUE_SIZES.to_vec(),
+                paused_sync: Some(Arc::new(Barrier::new(2))),
+                avail_features,
+                acked_features,
+                min_queues: 2,
+                paused: Arc::new(AtomicBool::new(paused)),
+                ..Default::default()
+            },
             id,
-            avail_features: 0u64,
-            acked_features: 0u64,
+            name,
+            serial,
+            pointer,
+            select: VIRTIO_INPUT_CFG_UNSET,
+            subsel: 0,
             events: Arc::new(Mutex::new(VecDeque::with_capacity(EVENT_QUEUE_SIZE))),
-            event_fd: EventFd::new(libc::EFD_NONBLOCK)?,
-            activated: Arc::new(AtomicBool::new(false)),
-            interrupt_cb: None,
-            access_platform: None,
-            seccomp_action: SeccompAction::Trap,
+            inject_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            leds: Arc::new(AtomicU32::new(leds)),
+            touch_contacts: AtomicU32::new(0),
+            next_tracking_id: AtomicU32::new(0),
+            seccomp_action,
             exit_evt,
         })
     }
 
+    fn state(&self) -> InputState {
+        InputState {
+            avail_features: self.common.avail_features,
+            acked_features: self.common.acked_features,
+            leds: self.leds(),
+        }
+    }
+
     /// Inject an input event
     pub fn inject_event(&self, event: VirtioInputEvent) -> io::Result<()> {
-        if let Ok(mut events) = self.events.lock() {
-            if events.len() < EVENT_QUEUE_SIZE {
-                events.push_back(event);
-                // Signal that new event is available
-                self.event_fd.write(1)?;
-                return Ok(());
-            }
+        self.inject_events(&[event])
+    }
+
+    /// Inject a group of events followed by a sync event, so that the
+    /// guest sees them at once. Nothing is queued if they don't all fit.
+    pub fn inject_events(&self, events: &[VirtioInputEvent]) -> io::Result<()> {
+        let mut queue = self.events.lock().unwrap();
+        if queue.len() + events.len() + 1 > EVENT_QUEUE_SIZE {
+            return Err(io::Error::other("Event queue full"));
         }
-        Err(io::Error::new(io::ErrorKind::Other, "Event queue full"))
+        queue.extend(events);
+        queue.push_back(VirtioInputEvent::syn());
+        drop(queue);
+
+        // Signal that new event is available
+        self.inject_evt.write(1)
+    }
+
+    /// Events the queue can still take, sync events included, before the
+    /// guest hands more buffers over
+    pub fn queue_space(&self) -> usize {
+        EVENT_QUEUE_SIZE.saturating_sub(self.events.lock().unwrap().len())
     }
 
     /// Inject a keyboard event
@@ -143,71 +561,337 @@ impl Input {
         self.inject_event(VirtioInputEvent::keyboard(code, pressed))
     }
 
+    /// Kind of pointer exposed to the guest
+    pub fn pointer(&self) -> PointerMode {
+        self.pointer
+    }
+
+    /// Fail unless the pointer is one of `modes`
+    fn check_pointer(&self, modes: &[PointerMode]) -> io::Result<()> {
+        if modes.contains(&self.pointer) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Not supported by a {} pointer", self.pointer),
+            ))
+        }
+    }
+
     /// Inject a mouse relative movement
     pub fn inject_mouse_rel(&self, dx: i32, dy: i32) -> io::Result<()> {
-        self.inject_event(VirtioInputEvent::rel(0x00, dx))?; // REL_X
-        self.inject_event(VirtioInputEvent::rel(0x01, dy))?; // REL_Y
-        self.inject_event(VirtioInputEvent::syn())
+        self.check_pointer(&[PointerMode::Relative])?;
+        self.inject_events(&[
+            VirtioInputEvent::rel(REL_X, dx),
+            VirtioInputEvent::rel(REL_Y, dy),
+        ])
+    }
+
+    /// Inject an absolute pointer position, both axes ranging from 0 to
+    /// [`ABS_MAX`]
+    pub fn inject_mouse_abs(&self, x: u32, y: u32) -> io::Result<()> {
+        self.check_pointer(&[PointerMode::Absolute])?;
+        self.inject_events(&[
+            VirtioInputEvent::abs(ABS_X, x.min(ABS_MAX)),
+            VirtioInputEvent::abs(ABS_Y, y.min(ABS_MAX)),
+        ])
     }
 
     /// Inject a mouse button event
     pub fn inject_mouse_button(&self, button: u16, pressed: bool) -> io::Result<()> {
+        self.check_pointer(&[PointerMode::Relative, PointerMode::Absolute])?;
         self.inject_event(VirtioInputEvent::keyboard(button, pressed))
     }
 
     /// Inject a mouse wheel event
     pub fn inject_mouse_wheel(&self, delta: i32) -> io::Result<()> {
-        self.inject_event(VirtioInputEvent::rel(0x08, delta)) // REL_WHEEL
+        self.check_pointer(&[PointerMode::Relative, PointerMode::Absolute])?;
+        self.inject_event(VirtioInputEvent::rel(REL_WHEEL, delta))
+    }
+
+    /// Inject a touch contact on `slot`, at a position ranging from 0 to
+    /// [`ABS_MAX`] on both axes, or lift it when `position` is `None`
+    ///
+    /// The lowest active slot also drives the single-touch axes and
+    /// BTN_TOUCH, for the guests that ignore multi-touch events.
+    pub fn inject_touch(&self, slot: u32, position: Option<(u32, u32)>) -> io::Result<()> {
+        self.check_pointer(&[PointerMode::Touch])?;
+        if slot >= TOUCH_SLOTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Touch slot {slot} out of range"),
+            ));
+        }
+
+        let contacts = self.touch_contacts.load(Ordering::SeqCst);
+        let bit = 1 << slot;
+        let mut events = vec![VirtioInputEvent::abs(ABS_MT_SLOT, slot)];
+        let new_contacts = match position {
+            Some((x, y)) => {
+                let (x, y) = (x.min(ABS_MAX), y.min(ABS_MAX));
+                if contacts & bit == 0 {
+                    let tracking_id = self.next_tracking_id.fetch_add(1, Ordering::SeqCst);
+                    events.push(VirtioInputEvent::abs(
+                        ABS_MT_TRACKING_ID,
+                        tracking_id & TRACKING_ID_MAX,
+                    ));
+                }
+                events.push(VirtioInputEvent::abs(ABS_MT_POSITION_X, x));
+                events.push(VirtioInputEvent::abs(ABS_MT_POSITION_Y, y));
+                if contacts & (bit - 1) == 0 {
+                    events.push(VirtioInputEvent::abs(ABS_X, x));
+                    events.push(VirtioInputEvent::abs(ABS_Y, y));
+                }
+                if contacts == 0 {
+                    events.push(VirtioInputEvent::keyboard(BTN_TOUCH, true));
+                }
+                contacts | bit
+            }
+            None => {
+                if contacts & bit == 0 {
+                    return Ok(());
+                }
+                events.push(VirtioInputEvent::abs(ABS_MT_TRACKING_ID, TRACKING_ID_NONE));
+                if contacts == bit {
+                    events.push(VirtioInputEvent::keyboard(BTN_TOUCH, false));
+                }
+                contacts & !bit
+            }
+        };
+
+        self.inject_events(&events)?;
+        self.touch_contacts.store(new_contacts, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Keyboard LEDs set by the guest, bit N being LED code N
+    pub fn leds(&self) -> u32 {
+        self.leds.load(Ordering::SeqCst)
+    }
+
+    /// Whether the guest driver activated the device, and reads events
+    pub fn activated(&self) -> bool {
+        self.common.interrupt_cb.is_some()
+    }
+
+    /// Event codes reported for an event type
+    fn event_codes(&self, ev_type: u16) -> Vec<u16> {
+        match (ev_type, self.pointer) {
+            (EV_KEY, PointerMode::Touch) => KEY_CODES.chain([BTN_TOUCH]).collect(),
+            (EV_KEY, _) => KEY_CODES.chain(BUTTON_CODES).collect(),
+            (EV_REL, PointerMode::Relative) => vec![REL_X, REL_Y, REL_WHEEL],
+            (EV_REL, PointerMode::Absolute) => vec![REL_WHEEL],
+            (EV_ABS, PointerMode::Absolute) => vec![ABS_X, ABS_Y],
+            (EV_ABS, PointerMode::Touch) => vec![
+                ABS_X,
+                ABS_Y,
+                ABS_MT_SLOT,
+                ABS_MT_POSITION_X,
+                ABS_MT_POSITION_Y,
+                ABS_MT_TRACKING_ID,
+            ],
+            (EV_LED, _) => LED_CODES.collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Minimum and maximum values of an absolute axis
+    fn abs_range(&self, axis: u16) -> Option<(u32, u32)> {
+        if !self.event_codes(EV_ABS).contains(&axis) {
+            return None;
+        }
+
+        Some(match axis {
+            ABS_MT_SLOT => (0, TOUCH_SLOTS - 1),
+            ABS_MT_TRACKING_ID => (0, TRACKING_ID_MAX),
+            _ => (0, ABS_MAX),
+        })
+    }
+
+    /// Payload selected by `select` and `subsel`, empty when there is none
+    fn config_payload(&self) -> Vec<u8> {
+        match self.select {
+            VIRTIO_INPUT_CFG_ID_NAME => self.name.as_bytes().to_vec(),
+            VIRTIO_INPUT_CFG_ID_SERIAL => self.serial.as_bytes().to_vec(),
+            VIRTIO_INPUT_CFG_ID_DEVIDS => {
+                [BUS_VIRTUAL, DEVICE_VENDOR, DEVICE_PRODUCT, DEVICE_VERSION]
+                    .iter()
+                    .flat_map(|id| id.to_le_bytes())
+                    .collect()
+            }
+            VIRTIO_INPUT_CFG_EV_BITS => bitmap(&self.event_codes(self.subsel.into())),
+            // A touchscreen maps directly onto the display
+            VIRTIO_INPUT_CFG_PROP_BITS if self.pointer == PointerMode::Touch => {
+                bitmap(&[INPUT_PROP_DIRECT])
+            }
+            VIRTIO_INPUT_CFG_PROP_BITS => Vec::new(),
+            // min, max, fuzz, flat and res of the axis
+            VIRTIO_INPUT_CFG_ABS_INFO => match self.abs_range(self.subsel.into()) {
+                Some((min, max)) => [min, max, 0, 0, 0]
+                    .iter()
+                    .flat_map(|value| value.to_le_bytes())
+                    .collect(),
+                None => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    #[cfg(fuzzing)]
+    pub fn wait_for_epoll_threads(&mut self) {
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+/// Bitmap with the bits of `codes` set, as long as needed for the highest one
+fn bitmap(codes: &[u16]) -> Vec<u8> {
+    let mut bitmap = Vec::new();
+    for &code in codes {
+        let byte = code as usize / 8;
+        if bitmap.len() <= byte {
+            bitmap.resize(byte + 1, 0);
+        }
+        bitmap[byte] |= 1 << (code % 8);
+    }
+    bitmap
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.common.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+        self.common.wait_for_epoll_threads();
     }
 }
 
 impl VirtioDevice for Input {
     fn device_type(&self) -> u32 {
-        VIRTIO_ID_INPUT
+        self.common.device_type
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        &self.common.queue_sizes
     }
 
     fn features(&self) -> u64 {
-        self.avail_features
+        self.common.avail_features
     }
 
     fn ack_features(&mut self, value: u64) {
-        let _ = value;
+        self.common.ack_features(value);
     }
 
-    fn read_config(&self, _offset: u64, _data: &mut [u8]) {
-        // No config space for now
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let mut payload = self.config_payload();
+        payload.truncate(CONFIG_PAYLOAD_SIZE);
+
+        let mut config = [0u8; CONFIG_HEADER_SIZE + CONFIG_PAYLOAD_SIZE];
+        config[0] = self.select;
+        config[1] = self.subsel;
+        config[2] = payload.len() as u8;
+        config[CONFIG_HEADER_SIZE..CONFIG_HEADER_SIZE + payload.len()].copy_from_slice(&payload);
+        self.read_config_from_slice(&config, offset, data);
     }
 
-    fn write_config(&mut self, _offset: u64, _data: &[u8]) {
-        // No config space for now
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Only select and subsel are writable
+        for (offset, &value) in (offset..).zip(data) {
+            match offset {
+                0 => self.select = value,
+                1 => self.subsel = value,
+                _ => {
+                    error!("Invalid virtio-input config write: offset = {offset:x}");
+                    return;
+                }
+            }
+        }
     }
 
     fn activate(
         &mut self,
-        _mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
         interrupt_cb: Arc<dyn VirtioInterrupt>,
-        _queues: Vec<(usize, Queue, EventFd)>,
+        mut queues: Vec<(usize, Queue, EventFd)>,
     ) -> ActivateResult {
-        self.interrupt_cb = Some(interrupt_cb);
-        self.activated.store(true, Ordering::SeqCst);
+        self.common.activate(&queues, interrupt_cb.clone())?;
+        let (kill_evt, pause_evt) = self.common.dup_eventfds();
+
+        let (_, event_queue, event_queue_evt) = queues.remove(0);
+        let (_, status_queue, status_queue_evt) = queues.remove(0);
+
+        let mut handler = InputEpollHandler {
+            mem,
+            queues: vec![event_queue, status_queue],
+            interrupt_cb,
+            event_queue_evt,
+            status_queue_evt,
+            inject_evt: self
+                .inject_evt
+                .try_clone()
+                .map_err(ActivateError::CloneExitEventFd)?,
+            kill_evt,
+            pause_evt,
+            events: self.events.clone(),
+            leds: self.leds.clone(),
+            access_platform: self.common.access_platform.clone(),
+        };
+
+        let paused = self.common.paused.clone();
+        let paused_sync = self.common.paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        spawn_virtio_thread(
+            &self.id,
+            &self.seccomp_action,
+            Thread::VirtioInput,
+            &mut epoll_threads,
+            &self.exit_evt,
+            move || handler.run(&paused, paused_sync.as_ref().unwrap()),
+        )?;
+
+        self.common.epoll_threads = Some(epoll_threads);
+
+        event!("virtio-device", "activated", "id", &self.id);
+        event!(
+            "input",
+            "state-changed",
+            "backend",
+            "virtio",
+            "ready",
+            "true"
+        );
         Ok(())
     }
 
     fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
-        self.activated.store(false, Ordering::SeqCst);
-        self.interrupt_cb.take()
+        let result = self.common.reset();
+        event!("virtio-device", "reset", "id", &self.id);
+        event!(
+            "input",
+            "state-changed",
+            "backend",
+            "virtio",
+            "ready",
+            "false"
+        );
+        result
     }
 
     fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
-        self.access_platform = Some(access_platform);
+        self.common.set_access_platform(access_platform);
     }
 }
 
-impl Pausable for Input {}
+impl Pausable for Input {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.common.pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
 
 impl Snapshottable for Input {
     fn id(&self) -> String {
@@ -215,10 +899,7 @@ impl Snapshottable for Input {
     }
 
     fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
-        Snapshot::new_from_state(&InputState {
-            avail_features: self.avail_features,
-            acked_features: self.acked_features,
-        })
+        Snapshot::new_from_state(&self.state())
     }
 }
 
@@ -226,9 +907,209 @@ impl Transportable for Input {}
 
 impl Migratable for Input {}
 
-/// Input device state for migration
-#[derive(Deserialize, Serialize)]
-pub struct InputState {
-    pub avail_features: u64,
-    pub acked_features: u64,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_input() -> Input {
+        test_pointer(PointerMode::Relative)
+    }
+
+    fn test_pointer(pointer: PointerMode) -> Input {
+        Input::new(
+            "input0".to_string(),
+            "test-input".to_string(),
+            "serial0".to_string(),
+            pointer,
+            false,
+            SeccompAction::Allow,
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            None,
+        )
+        .unwrap()
+    }
+
+    fn select(input: &mut Input, select: u8, subsel: u8) -> Vec<u8> {
+        input.write_config(0, &[select, subsel]);
+        let mut header = [0u8; CONFIG_HEADER_SIZE];
+        input.read_config(0, &mut header);
+        assert_eq!((header[0], header[1]), (select, subsel));
+
+        let mut payload = vec![0u8; header[2] as usize];
+        input.read_config(CONFIG_HEADER_SIZE as u64, &mut payload);
+        payload
+    }
+
+    #[test]
+    fn test_input_config_selectors() {
+        let mut input = test_input();
+        assert_eq!(input.device_type(), VirtioDeviceType::Input as u32);
+
+        assert_eq!(select(&mut input, VIRTIO_INPUT_CFG_UNSET, 0), b"");
+        assert_eq!(
+            select(&mut input, VIRTIO_INPUT_CFG_ID_NAME, 0),
+            b"test-input"
+        );
+        assert_eq!(
+            select(&mut input, VIRTIO_INPUT_CFG_ID_SERIAL, 0),
+            b"serial0"
+        );
+        assert_eq!(
+            select(&mut input, VIRTIO_INPUT_CFG_ID_DEVIDS, 0),
+            [0x06, 0x00, 0x27, 0x06, 0x01, 0x00, 0x01, 0x00]
+        );
+        assert_eq!(select(&mut input, VIRTIO_INPUT_CFG_PROP_BITS, 0), b"");
+        assert_eq!(select(&mut input, VIRTIO_INPUT_CFG_ABS_INFO, 0), b"");
+
+        // Keys from KEY_ESC and the mouse buttons
+        let key_bits = select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8);
+        assert_eq!(key_bits.len(), 0x22 + 1);
+        assert_eq!(key_bits[0], 0xfe);
+        assert_eq!(key_bits[0x1f], 0xff);
+        assert_eq!(key_bits[0x20], 0x00);
+        assert_eq!(key_bits[0x22], 0x1f);
+        assert_eq!(
+            select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_REL as u8),
+            [0x03, 0x01]
+        );
+        assert_eq!(
+            select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_LED as u8),
+            [0x07]
+        );
+        assert_eq!(
+            select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8),
+            b""
+        );
+    }
+
+    #[test]
+    fn test_input_inject_events() {
+        let input = test_input();
+        input.inject_mouse_rel(10, -5).unwrap();
+        input.inject_keyboard(0x1e, true).unwrap();
+
+        let events: Vec<_> = input.events.lock().unwrap().iter().copied().collect();
+        assert_eq!(
+            events,
+            [
+                VirtioInputEvent::rel(REL_X, 10),
+                VirtioInputEvent::rel(REL_Y, -5),
+                VirtioInputEvent::syn(),
+                VirtioInputEvent::keyboard(0x1e, true),
+                VirtioInputEvent::syn(),
+            ]
+        );
+        assert_eq!(input.inject_evt.read().unwrap(), 2);
+
+        // Groups are never split when the queue fills up
+        while input.inject_keyboard(0x1e, false).is_ok() {}
+        assert_eq!(input.events.lock().unwrap().len(), EVENT_QUEUE_SIZE - 1);
+        assert_eq!(input.queue_space(), 1);
+        input.inject_mouse_rel(1, 1).unwrap_err();
+    }
+
+    #[test]
+    fn test_input_status_leds() {
+        let input = test_input();
+        let led = |code, value| VirtioInputEvent {
+            ev_type: EV_LED,
+            code,
+            value,
+        };
+
+        handle_status_event(&input.leds, &led(LED_CAPSL, 1));
+        handle_status_event(&input.leds, &led(LED_NUML, 1));
+        handle_status_event(&input.leds, &VirtioInputEvent::syn());
+        assert_eq!(input.leds(), 0x3);
+
+        handle_status_event(&input.leds, &led(LED_CAPSL, 0));
+        // Unknown LEDs are ignored
+        handle_status_event(&input.leds, &led(0x08, 1));
+        assert_eq!(input.leds(), 0x1);
+        assert_eq!(input.state().leds, 0x1);
+    }
+
+    #[test]
+    fn test_input_absolute_pointer() {
+        let mut input = test_pointer(PointerMode::Absolute);
+        assert_eq!(
+            select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8),
+            [0x03]
+        );
+        assert_eq!(
+            select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_REL as u8),
+            [0x00, 0x01]
+        );
+        let abs_info = select(&mut input, VIRTIO_INPUT_CFG_ABS_INFO, ABS_Y as u8);
+        assert_eq!(abs_info.len(), 20);
+        assert_eq!(abs_info[..8], [0, 0, 0, 0, 0xff, 0x7f, 0, 0]);
+        assert_eq!(
+            select(&mut input, VIRTIO_INPUT_CFG_ABS_INFO, ABS_MT_SLOT as u8),
+            b""
+        );
+        assert_eq!(select(&mut input, VIRTIO_INPUT_CFG_PROP_BITS, 0), b"");
+
+        input.inject_mouse_abs(100, 0x10000).unwrap();
+        input.inject_mouse_rel(1, 1).unwrap_err();
+        let events: Vec<_> = input.events.lock().unwrap().iter().copied().collect();
+        assert_eq!(
+            events,
+            [
+                VirtioInputEvent::abs(ABS_X, 100),
+                VirtioInputEvent::abs(ABS_Y, ABS_MAX),
+                VirtioInputEvent::syn(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_input_touch() {
+        let mut input = test_pointer(PointerMode::Touch);
+        assert_eq!(select(&mut input, VIRTIO_INPUT_CFG_PROP_BITS, 0), [0x02]);
+        assert_eq!(
+            select(&mut input, VIRTIO_INPUT_CFG_ABS_INFO, ABS_MT_SLOT as u8)[..8],
+            [0, 0, 0, 0, 9, 0, 0, 0]
+        );
+        let key_bits = select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8);
+        assert_eq!(key_bits.len(), 0x29 + 1);
+        assert_eq!(key_bits[0x22], 0x00);
+        assert_eq!(key_bits[0x29], 0x04);
+
+        input.inject_touch(1, Some((10, 20))).unwrap();
+        input.inject_touch(0, Some((30, 40))).unwrap();
+        input.inject_touch(1, None).unwrap();
+        input.inject_touch(0, None).unwrap();
+        input.inject_touch(0, None).unwrap();
+        input.inject_touch(TOUCH_SLOTS, Some((0, 0))).unwrap_err();
+        input.inject_mouse_button(BTN_LEFT, true).unwrap_err();
+
+        let events: Vec<_> = input.events.lock().unwrap().iter().copied().collect();
+        assert_eq!(
+            events,
+            [
+                VirtioInputEvent::abs(ABS_MT_SLOT, 1),
+                VirtioInputEvent::abs(ABS_MT_TRACKING_ID, 0),
+                VirtioInputEvent::abs(ABS_MT_POSITION_X, 10),
+                VirtioInputEvent::abs(ABS_MT_POSITION_Y, 20),
+                VirtioInputEvent::abs(ABS_X, 10),
+                VirtioInputEvent::abs(ABS_Y, 20),
+                VirtioInputEvent::keyboard(BTN_TOUCH, true),
+                VirtioInputEvent::syn(),
+                VirtioInputEvent::abs(ABS_MT_SLOT, 0),
+                VirtioInputEvent::abs(ABS_MT_TRACKING_ID, 1),
+                VirtioInputEvent::abs(ABS_MT_POSITION_X, 30),
+                VirtioInputEvent::abs(ABS_MT_POSITION_Y, 40),
+                VirtioInputEvent::abs(ABS_X, 30),
+                VirtioInputEvent::abs(ABS_Y, 40),
+                VirtioInputEvent::syn(),
+                VirtioInputEvent::abs(ABS_MT_SLOT, 1),
+                VirtioInputEvent::abs(ABS_MT_TRACKING_ID, TRACKING_ID_NONE),
+                VirtioInputEvent::syn(),
+                VirtioInputEvent::abs(ABS_MT_SLOT, 0),
+                VirtioInputEvent::abs(ABS_MT_TRACKING_ID, TRACKING_ID_NONE),
+                VirtioInputEvent::keyboard(BTN_TOUCH, false),
+                VirtioInputEvent::syn(),
+            ]
+        );
+    }
 }
@@ -45,13 +45,16 @@ pub use self::block::{Block, BlockState};
 pub use self::console::{Console, ConsoleResizer, Endpoint};
 pub use self::device::{
     DmaRemapping, VirtioCommon, VirtioDevice, VirtioInterrupt, VirtioInterruptType,
-    VirtioSharedMemoryList,
+    VirtioSharedMemory, VirtioSharedMemoryList,
 };
 pub use self::epoll_helper::{
     EPOLL_HELPER_EVENT_LAST, EpollHelper, EpollHelperError, EpollHelperHandler,
 };
 pub use self::iommu::{AccessPlatformMapping, Iommu, IommuMapping};
-pub use self::input::{Input as VirtioInput, VirtioInputEvent, InputState};
+pub use self::input::{
+    ABS_MAX as VIRTIO_INPUT_ABS_MAX, Input as VirtioInput, InputState, PointerMode,
+    VirtioInputEvent,
+};
 pub use self::mem::{BlocksState, Mem, VIRTIO_MEM_ALIGN_SIZE, VirtioMemMappingSource};
 pub use self::net::{Net, NetCtrlEpollHandler};
 pub use self::pmem::Pmem;
@@ -59,7 +62,7 @@ pub use self::rng::Rng;
 pub use self::vdpa::{Vdpa, VdpaDmaMapping};
 pub use self::vsock::Vsock;
 pub use self::watchdog::Watchdog;
-pub use self::gpu::{Gpu, GpuConfig};
+pub use self::gpu::{CursorState, Gpu, GpuConfig, ScanoutFrame};
 
 type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 type GuestRegionMmap = vm_memory::GuestRegionMmap<AtomicBitmap>;
@@ -17,6 +17,7 @@ pub enum Thread {
     VirtioBlock,
     VirtioConsole,
     VirtioGpu,
+    VirtioInput,
     VirtioIommu,
     VirtioMem,
     VirtioNet,
@@ -113,6 +114,10 @@ fn virtio_block_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
         (libc::SYS_io_submit, vec![]),
         (libc::SYS_io_uring_enter, vec![]),
         (libc::SYS_lseek, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_poll, vec![]),
+        #[cfg(not(target_arch = "x86_64"))]
+        (libc::SYS_ppoll, vec![]),
         (libc::SYS_pread64, vec![]),
         (libc::SYS_preadv, vec![]),
         (libc::SYS_pwritev, vec![]),
@@ -136,6 +141,15 @@ fn virtio_iommu_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![(libc::SYS_ioctl, create_virtio_iommu_ioctl_seccomp_rule())]
 }
 
+fn virtio_input_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
+    vec![
+        (libc::SYS_sched_getaffinity, vec![]),
+        (libc::SYS_set_robust_list, vec![]),
+        #[cfg(feature = "sev_snp")]
+        (libc::SYS_ioctl, create_mshv_sev_snp_ioctl_seccomp_rule()),
+    ]
+}
+
 fn virtio_mem_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![
         (libc::SYS_fallocate, vec![]),
@@ -181,10 +195,40 @@ fn virtio_rng_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
 }
 
 fn virtio_gpu_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
-    vec![
+    #[allow(unused_mut)]
+    let mut rules = vec![
         (libc::SYS_sched_getaffinity, vec![]),
         (libc::SYS_set_robust_list, vec![]),
-    ]
+    ];
+
+    // virglrenderer loads Mesa, which opens the DRI drivers and spawns the
+    // llvmpipe rasterizer threads from the virtio-gpu thread.
+    #[cfg(feature = "virgl")]
+    rules.extend(vec![
+        (libc::SYS_clone, vec![]),
+        (libc::SYS_clone3, vec![]),
+        (libc::SYS_fcntl, vec![]),
+        (libc::SYS_fstat, vec![]),
+        (libc::SYS_ftruncate, vec![]),
+        (libc::SYS_getdents64, vec![]),
+        (libc::SYS_getpid, vec![]),
+        (libc::SYS_getrandom, vec![]),
+        (libc::SYS_ioctl, vec![]),
+        (libc::SYS_lseek, vec![]),
+        (libc::SYS_memfd_create, vec![]),
+        (libc::SYS_newfstatat, vec![]),
+        (libc::SYS_pread64, vec![]),
+        (libc::SYS_prctl, vec![]),
+        (libc::SYS_readlinkat, vec![]),
+        (libc::SYS_rseq, vec![]),
+        (libc::SYS_sched_setaffinity, vec![]),
+        (libc::SYS_sched_yield, vec![]),
+        (libc::SYS_statx, vec![]),
+        (libc::SYS_sysinfo, vec![]),
+        (libc::SYS_uname, vec![]),
+    ]);
+
+    rules
 }
 
 fn virtio_vhost_fs_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
@@ -287,6 +331,7 @@ fn get_seccomp_rules(thread_type: Thread) -> Vec<(i64, Vec<SeccompRule>)> {
         Thread::VirtioBlock => virtio_block_thread_rules(),
         Thread::VirtioConsole => virtio_console_thread_rules(),
         Thread::VirtioGpu => virtio_gpu_thread_rules(),
+        Thread::VirtioInput => virtio_input_thread_rules(),
         Thread::VirtioIommu => virtio_iommu_thread_rules(),
         Thread::VirtioMem => virtio_mem_thread_rules(),
         Thread::VirtioNet => virtio_net_thread_rules(),
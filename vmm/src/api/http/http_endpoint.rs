@@ -46,10 +46,15 @@ use crate::api::http::http_endpoint::fds_helper::{attach_fds_to_cfg, attach_fds_
 use crate::api::http::{EndpointHandler, HttpError, error_response};
 use crate::api::{
     AddDisk, ApiAction, ApiError, ApiRequest, NetConfig, VmAddDevice, VmAddFs,
-    VmAddGenericVhostUser, VmAddNet, VmAddPmem, VmAddUserDevice, VmAddVdpa, VmAddVsock, VmBoot,
-    VmConfig, VmCounters, VmDelete, VmInjectInput, VmNmi, VmPause, VmPowerButton, VmReboot,
-    VmReceiveMigration, VmRemoveDevice, VmResize, VmResizeDisk, VmResizeZone, VmRestore, VmResume,
-    VmSendMigration, VmShutdown, VmSnapshot,
+    VmAddGenericVhostUser, VmAddGpu, VmAddInput, VmAddNet, VmAddPmem, VmAddUserDevice, VmAddVdpa,
+    VmAddVsock, VmBoot, VmConfig, VmCounters, VmDelete, VmDiskBackup, VmDiskBackupCancel,
+    VmDiskBackupStatus, VmDiskCommit, VmDiskCommitStatus, VmDiskInternalSnapshotApply,
+    VmDiskInternalSnapshotCreate, VmDiskInternalSnapshotDelete, VmDiskInternalSnapshotList,
+    VmDiskSnapshot, VmDisplayChange, VmFrameCaptureRecord, VmInjectInput, VmInputRecordStart,
+    VmInputRecordStop, VmInputReplayStart, VmInputReplayStatus, VmInputReplayStop, VmInputState,
+    VmInputSwitchBackend, VmNmi, VmPause, VmPowerButton, VmReboot, VmReceiveMigration,
+    VmRemoveDevice, VmResize, VmResizeDisk, VmResizeZone, VmRestore, VmResume, VmSendMigration,
+    VmShutdown, VmSnapshot, VmTypeText,
 };
 use crate::config::RestoreConfig;
 use crate::cpu::Error as CpuError;
@@ -416,6 +421,7 @@ vm_action_put_handler!(VmPause);
 vm_action_put_handler!(VmResume);
 vm_action_put_handler!(VmPowerButton);
 vm_action_put_handler!(VmNmi);
+vm_action_put_handler!(VmInputRecordStart);
 
 vm_action_put_handler_body!(VmAddDevice);
 vm_action_put_handler_body!(AddDisk);
@@ -424,10 +430,24 @@ vm_action_put_handler_body!(VmAddGenericVhostUser);
 vm_action_put_handler_body!(VmAddPmem);
 vm_action_put_handler_body!(VmAddVdpa);
 vm_action_put_handler_body!(VmAddVsock);
+vm_action_put_handler_body!(VmAddGpu);
+vm_action_put_handler_body!(VmAddInput);
 vm_action_put_handler_body!(VmAddUserDevice);
 vm_action_put_handler_body!(VmRemoveDevice);
 vm_action_put_handler_body!(VmResizeDisk);
+vm_action_put_handler_body!(VmDiskInternalSnapshotList);
+vm_action_put_handler_body!(VmDiskInternalSnapshotCreate);
+vm_action_put_handler_body!(VmDiskInternalSnapshotApply);
+vm_action_put_handler_body!(VmDiskInternalSnapshotDelete);
+vm_action_put_handler_body!(VmDiskSnapshot);
+vm_action_put_handler_body!(VmDiskBackup);
+vm_action_put_handler_body!(VmDiskBackupCancel);
+vm_action_put_handler_body!(VmDiskBackupStatus);
+vm_action_put_handler_body!(VmDiskCommit);
+vm_action_put_handler_body!(VmDiskCommitStatus);
 vm_action_put_handler_body!(VmResizeZone);
+vm_action_put_handler_body!(VmDisplayChange);
+vm_action_put_handler_body!(VmFrameCaptureRecord);
 vm_action_put_handler_body!(VmSnapshot);
 vm_action_put_handler_body!(VmReceiveMigration);
 vm_action_put_handler_body!(VmSendMigration);
@@ -461,6 +481,160 @@ impl PutHandler for VmInjectInput {
 
 impl GetHandler for VmInjectInput {}
 
+// VmInputSwitchBackend handler - returns the input backend state as JSON body
+impl PutHandler for VmInputSwitchBackend {
+    fn handle_request(
+        &'static self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+        body: &Option<Body>,
+        _files: Vec<File>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        if let Some(body) = body {
+            let state = self
+                .send(
+                    api_notifier,
+                    api_sender,
+                    serde_json::from_slice(body.raw())?,
+                )
+                .map_err(HttpError::ApiError)?;
+            Ok(Some(Body::new(serde_json::to_string(&state)?)))
+        } else {
+            Err(HttpError::BadRequest)
+        }
+    }
+}
+
+impl GetHandler for VmInputSwitchBackend {}
+
+// VmTypeText handler - returns what was typed as JSON body
+impl PutHandler for VmTypeText {
+    fn handle_request(
+        &'static self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+        body: &Option<Body>,
+        _files: Vec<File>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        if let Some(body) = body {
+            let typed = self
+                .send(
+                    api_notifier,
+                    api_sender,
+                    serde_json::from_slice(body.raw())?,
+                )
+                .map_err(HttpError::ApiError)?;
+            Ok(Some(Body::new(serde_json::to_string(&typed)?)))
+        } else {
+            Err(HttpError::BadRequest)
+        }
+    }
+}
+
+impl GetHandler for VmTypeText {}
+
+// VmInputRecordStop handler - returns the recorded script as JSON body
+impl PutHandler for VmInputRecordStop {
+    fn handle_request(
+        &'static self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+        body: &Option<Body>,
+        _files: Vec<File>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        // The body is optional, without one the script is only returned
+        let record_stop_data = match body {
+            Some(body) => serde_json::from_slice(body.raw())?,
+            None => Default::default(),
+        };
+        let script = self
+            .send(api_notifier, api_sender, record_stop_data)
+            .map_err(HttpError::ApiError)?;
+        Ok(Some(Body::new(serde_json::to_string(&script)?)))
+    }
+}
+
+impl GetHandler for VmInputRecordStop {}
+
+// VmInputReplayStart handler - returns the replay progress as JSON body
+impl PutHandler for VmInputReplayStart {
+    fn handle_request(
+        &'static self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+        body: &Option<Body>,
+        _files: Vec<File>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        if let Some(body) = body {
+            let status = self
+                .send(
+                    api_notifier,
+                    api_sender,
+                    serde_json::from_slice(body.raw())?,
+                )
+                .map_err(HttpError::ApiError)?;
+            Ok(Some(Body::new(serde_json::to_string(&status)?)))
+        } else {
+            Err(HttpError::BadRequest)
+        }
+    }
+}
+
+impl GetHandler for VmInputReplayStart {}
+
+// VmInputReplayStop handler - returns the final replay progress as JSON body
+impl PutHandler for VmInputReplayStop {
+    fn handle_request(
+        &'static self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+        body: &Option<Body>,
+        _files: Vec<File>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        if body.is_some() {
+            return Err(HttpError::BadRequest);
+        }
+        let status = self
+            .send(api_notifier, api_sender, ())
+            .map_err(HttpError::ApiError)?;
+        Ok(Some(Body::new(serde_json::to_string(&status)?)))
+    }
+}
+
+impl GetHandler for VmInputReplayStop {}
+
+// VmInputReplayStatus handler - returns the replay progress as JSON body
+impl GetHandler for VmInputReplayStatus {
+    fn handle_request(
+        &'static self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        let status = self
+            .send(api_notifier, api_sender, ())
+            .map_err(HttpError::ApiError)?;
+        Ok(Some(Body::new(serde_json::to_string(&status)?)))
+    }
+}
+
+impl PutHandler for VmInputReplayStatus {}
+
+// VmInputState handler - returns the input state of the guest as JSON body
+impl GetHandler for VmInputState {
+    fn handle_request(
+        &'static self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        let state = self
+            .send(api_notifier, api_sender, ())
+            .map_err(HttpError::ApiError)?;
+        Ok(Some(Body::new(serde_json::to_string(&state)?)))
+    }
+}
+
+impl PutHandler for VmInputState {}
+
 // Special handling for virtio-net devices backed by network FDs.
 // See module description for more info.
 impl PutHandler for VmAddNet {
@@ -713,19 +887,32 @@ impl EndpointHandler for VmFrameInfo {
         api_sender: Sender<ApiRequest>,
     ) -> Response {
         match req.method() {
-            Method::Get => match crate::api::VmFrameInfo
-                .send(api_notifier, api_sender, ())
-                .map_err(HttpError::ApiError)
-            {
-                Ok(info) => {
-                    let mut response = Response::new(Version::Http11, StatusCode::OK);
-                    let info_serialized = serde_json::to_string(&info).unwrap();
+            Method::Get => {
+                // The scanout can optionally be selected through the body
+                let frame_info_data = match &req.body {
+                    Some(body) => match serde_json::from_slice(body.raw())
+                        .map_err(HttpError::SerdeJsonDeserialize)
+                    {
+                        Ok(data) => data,
+                        Err(e) => return error_response(e, StatusCode::BadRequest),
+                    },
+                    None => crate::api::VmFrameInfoData::default(),
+                };
+
+                match crate::api::VmFrameInfo
+                    .send(api_notifier, api_sender, frame_info_data)
+                    .map_err(HttpError::ApiError)
+                {
+                    Ok(info) => {
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        let info_serialized = serde_json::to_string(&info).unwrap();
 
-                    response.set_body(Body::new(info_serialized));
-                    response
+                        response.set_body(Body::new(info_serialized));
+                        response
+                    }
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
                 }
-                Err(e) => error_response(e, StatusCode::InternalServerError),
-            },
+            }
             _ => error_response(HttpError::BadRequest, StatusCode::BadRequest),
         }
     }
@@ -874,6 +1061,48 @@ impl EndpointHandler for VmCursorInfo {
     }
 }
 
+// /api/v1/vm.frame-capture.snapshot handler
+pub struct VmFrameSnapshot {}
+
+impl EndpointHandler for VmFrameSnapshot {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => {
+                // The scanout and encoding can optionally be selected through the body
+                let snapshot_data = match &req.body {
+                    Some(body) => match serde_json::from_slice(body.raw())
+                        .map_err(HttpError::SerdeJsonDeserialize)
+                    {
+                        Ok(data) => data,
+                        Err(e) => return error_response(e, StatusCode::BadRequest),
+                    },
+                    None => crate::api::VmFrameSnapshotData::default(),
+                };
+
+                match crate::api::VmFrameSnapshot
+                    .send(api_notifier, api_sender, snapshot_data)
+                    .map_err(HttpError::ApiError)
+                {
+                    Ok(snapshot) => {
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        let snapshot_serialized = serde_json::to_string(&snapshot).unwrap();
+
+                        response.set_body(Body::new(snapshot_serialized));
+                        response
+                    }
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
+                }
+            }
+            _ => error_response(HttpError::BadRequest, StatusCode::BadRequest),
+        }
+    }
+}
+
 #[cfg(test)]
 mod external_fds_tests {
     use super::*;
@@ -24,15 +24,19 @@ use serde_json::Error as SerdeError;
 use thiserror::Error;
 use vmm_sys_util::eventfd::EventFd;
 
-use self::http_endpoint::{VmActionHandler, VmCreate, VmCursorInfo, VmFrameCaptureSetFormat, VmFrameCaptureStart, VmFrameCaptureStatus, VmFrameCaptureStop, VmFrameInfo, VmInfo, VmmPing, VmmShutdown};
+use self::http_endpoint::{VmActionHandler, VmCreate, VmCursorInfo, VmFrameCaptureSetFormat, VmFrameCaptureStart, VmFrameCaptureStatus, VmFrameCaptureStop, VmFrameInfo, VmFrameSnapshot, VmInfo, VmmPing, VmmShutdown};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::api::VmCoredump;
 use crate::api::{
-    AddDisk, ApiError, ApiRequest, VmAddDevice, VmAddFs, VmAddGenericVhostUser, VmAddNet,
-    VmAddPmem, VmAddUserDevice, VmAddVdpa, VmAddVsock, VmBoot, VmCounters, VmDelete,
-    VmInjectInput, VmNmi, VmPause, VmPowerButton, VmReboot, VmReceiveMigration, VmRemoveDevice,
-    VmResize, VmResizeDisk, VmResizeZone, VmRestore, VmResume, VmSendMigration, VmShutdown,
-    VmSnapshot,
+    AddDisk, ApiError, ApiRequest, VmAddDevice, VmAddFs, VmAddGenericVhostUser, VmAddGpu,
+    VmAddInput, VmAddNet, VmAddPmem, VmAddUserDevice, VmAddVdpa, VmAddVsock, VmBoot, VmCounters,
+    VmDelete, VmDiskBackup, VmDiskBackupCancel, VmDiskBackupStatus, VmDiskCommit,
+    VmDiskCommitStatus, VmDiskInternalSnapshotApply, VmDiskInternalSnapshotCreate,
+    VmDiskInternalSnapshotDelete, VmDiskInternalSnapshotList, VmDiskSnapshot, VmDisplayChange,
+    VmFrameCaptureRecord, VmInjectInput, VmInputRecordStart, VmInputRecordStop, VmInputReplayStart,
+    VmInputReplayStatus, VmInputReplayStop, VmInputState, VmInputSwitchBackend, VmNmi, VmPause,
+    VmPowerButton, VmReboot, VmReceiveMigration, VmRemoveDevice, VmResize, VmResizeDisk,
+    VmResizeZone, VmRestore, VmResume, VmSendMigration, VmShutdown, VmSnapshot, VmTypeText,
 };
 use crate::landlock::Landlock;
 use crate::seccomp_filters::{Thread, get_seccomp_filter};
@@ -201,6 +205,14 @@ pub static HTTP_ROUTES: LazyLock<HttpRoutes> = LazyLock::new(|| {
         endpoint!("/vm.add-generic-vhost-user"),
         Box::new(VmActionHandler::new(&VmAddGenericVhostUser)),
     );
+    r.routes.insert(
+        endpoint!("/vm.add-gpu"),
+        Box::new(VmActionHandler::new(&VmAddGpu)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.add-input"),
+        Box::new(VmActionHandler::new(&VmAddInput)),
+    );
     r.routes.insert(
         endpoint!("/vm.add-net"),
         Box::new(VmActionHandler::new(&VmAddNet)),
@@ -231,6 +243,50 @@ pub static HTTP_ROUTES: LazyLock<HttpRoutes> = LazyLock::new(|| {
         endpoint!("/vm.delete"),
         Box::new(VmActionHandler::new(&VmDelete)),
     );
+    r.routes.insert(
+        endpoint!("/vm.disk-backup"),
+        Box::new(VmActionHandler::new(&VmDiskBackup)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.disk-backup.cancel"),
+        Box::new(VmActionHandler::new(&VmDiskBackupCancel)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.disk-backup.status"),
+        Box::new(VmActionHandler::new(&VmDiskBackupStatus)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.disk-commit"),
+        Box::new(VmActionHandler::new(&VmDiskCommit)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.disk-commit.status"),
+        Box::new(VmActionHandler::new(&VmDiskCommitStatus)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.disk-internal-snapshot.list"),
+        Box::new(VmActionHandler::new(&VmDiskInternalSnapshotList)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.disk-internal-snapshot.create"),
+        Box::new(VmActionHandler::new(&VmDiskInternalSnapshotCreate)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.disk-internal-snapshot.apply"),
+        Box::new(VmActionHandler::new(&VmDiskInternalSnapshotApply)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.disk-internal-snapshot.delete"),
+        Box::new(VmActionHandler::new(&VmDiskInternalSnapshotDelete)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.disk-snapshot"),
+        Box::new(VmActionHandler::new(&VmDiskSnapshot)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.display-change"),
+        Box::new(VmActionHandler::new(&VmDisplayChange)),
+    );
     r.routes.insert(endpoint!("/vm.info"), Box::new(VmInfo {}));
     r.routes.insert(
         endpoint!("/vm.pause"),
@@ -299,6 +355,38 @@ pub static HTTP_ROUTES: LazyLock<HttpRoutes> = LazyLock::new(|| {
         endpoint!("/vm.inject-input"),
         Box::new(VmActionHandler::new(&VmInjectInput)),
     );
+    r.routes.insert(
+        endpoint!("/vm.input.switch-backend"),
+        Box::new(VmActionHandler::new(&VmInputSwitchBackend)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.input.record.start"),
+        Box::new(VmActionHandler::new(&VmInputRecordStart)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.input.record.stop"),
+        Box::new(VmActionHandler::new(&VmInputRecordStop)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.input.replay.start"),
+        Box::new(VmActionHandler::new(&VmInputReplayStart)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.input.replay.stop"),
+        Box::new(VmActionHandler::new(&VmInputReplayStop)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.input.replay.status"),
+        Box::new(VmActionHandler::new(&VmInputReplayStatus)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.input-state"),
+        Box::new(VmActionHandler::new(&VmInputState)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.type-text"),
+        Box::new(VmActionHandler::new(&VmTypeText)),
+    );
     r.routes
         .insert(endpoint!("/vm.frame-info"), Box::new(VmFrameInfo {}));
     r.routes.insert(
@@ -317,6 +405,14 @@ pub static HTTP_ROUTES: LazyLock<HttpRoutes> = LazyLock::new(|| {
         endpoint!("/vm.frame-capture.set-format"),
         Box::new(VmFrameCaptureSetFormat {}),
     );
+    r.routes.insert(
+        endpoint!("/vm.frame-capture.record"),
+        Box::new(VmActionHandler::new(&VmFrameCaptureRecord)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.frame-capture.snapshot"),
+        Box::new(VmFrameSnapshot {}),
+    );
     r.routes
         .insert(endpoint!("/vm.cursor-info"), Box::new(VmCursorInfo {}));
 
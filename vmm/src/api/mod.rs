@@ -33,7 +33,9 @@
 pub mod dbus;
 pub mod http;
 
+use std::collections::BTreeMap;
 use std::io;
+use std::path::PathBuf;
 use std::sync::mpsc::{RecvError, SendError, Sender, channel};
 
 use log::info;
@@ -49,11 +51,15 @@ pub use self::http::{start_http_fd_thread, start_http_path_thread};
 use crate::Error as VmmError;
 use crate::config::RestoreConfig;
 use crate::device_tree::DeviceTree;
-use crate::input::InputRequest;
+use crate::frame_export::{Frame, FrameExportError, VideoContainer, base64_encode};
+use crate::input::{
+    BackendStats, DEFAULT_KEY_DELAY_MS, InputRequest, InputScript, InputState, KeyboardLayout,
+    ReplayStatus, UntypableChar,
+};
 use crate::vm::{Error as VmError, VmState};
 use crate::vm_config::{
-    DeviceConfig, DiskConfig, FsConfig, GenericVhostUserConfig, NetConfig, PmemConfig,
-    UserDeviceConfig, VdpaConfig, VmConfig, VsockConfig,
+    DeviceConfig, DiskConfig, FsConfig, GenericVhostUserConfig, GpuConfig, NetConfig, PmemConfig,
+    UserDeviceConfig, VdpaConfig, VirtioInputConfig, VmConfig, VsockConfig,
 };
 
 /// API errors are sent back from the VMM API server through the ApiResponse.
@@ -139,6 +145,46 @@ pub enum ApiError {
     #[error("The disk could not be resized")]
     VmResizeDisk(#[source] VmError),
 
+    /// The internal snapshots of the disk could not be listed.
+    #[error("The internal snapshots of the disk could not be listed")]
+    VmDiskInternalSnapshotList(#[source] VmError),
+
+    /// The internal snapshot of the disk could not be created.
+    #[error("The internal snapshot of the disk could not be created")]
+    VmDiskInternalSnapshotCreate(#[source] VmError),
+
+    /// The internal snapshot of the disk could not be applied.
+    #[error("The internal snapshot of the disk could not be applied")]
+    VmDiskInternalSnapshotApply(#[source] VmError),
+
+    /// The internal snapshot of the disk could not be deleted.
+    #[error("The internal snapshot of the disk could not be deleted")]
+    VmDiskInternalSnapshotDelete(#[source] VmError),
+
+    /// The disk could not be switched to an overlay.
+    #[error("The disk could not be switched to an overlay")]
+    VmDiskSnapshot(#[source] VmError),
+
+    /// The overlay of the disk could not be committed.
+    #[error("The overlay of the disk could not be committed")]
+    VmDiskCommit(#[source] VmError),
+
+    /// The status of the commit of the disk could not be retrieved.
+    #[error("The status of the commit of the disk could not be retrieved")]
+    VmDiskCommitStatus(#[source] VmError),
+
+    /// The disk could not be backed up.
+    #[error("The disk could not be backed up")]
+    VmDiskBackup(#[source] VmError),
+
+    /// The status of the backup of the disk could not be retrieved.
+    #[error("The status of the backup of the disk could not be retrieved")]
+    VmDiskBackupStatus(#[source] VmError),
+
+    /// The backup of the disk could not be cancelled.
+    #[error("The backup of the disk could not be cancelled")]
+    VmDiskBackupCancel(#[source] VmError),
+
     /// The memory zone could not be resized.
     #[error("The memory zone could not be resized")]
     VmResizeZone(#[source] VmError),
@@ -191,6 +237,14 @@ pub enum ApiError {
     #[error("The vsock device could not be added to the VM")]
     VmAddVsock(#[source] VmError),
 
+    /// The gpu device could not be added to the VM.
+    #[error("The gpu device could not be added to the VM")]
+    VmAddGpu(#[source] VmError),
+
+    /// The input device could not be added to the VM.
+    #[error("The input device could not be added to the VM")]
+    VmAddInput(#[source] VmError),
+
     /// Error starting migration receiver
     #[error("Error starting migration receiver")]
     VmReceiveMigration(#[source] MigratableError),
@@ -211,6 +265,26 @@ pub enum ApiError {
     #[error("Error injecting input")]
     VmInjectInput(#[source] VmError),
 
+    /// Error switching the input backend
+    #[error("Error switching the input backend")]
+    VmInputSwitchBackend(#[source] VmError),
+
+    /// Error typing text
+    #[error("Error typing text")]
+    VmTypeText(#[source] VmError),
+
+    /// Error recording input
+    #[error("Error recording input")]
+    VmInputRecord(#[source] VmError),
+
+    /// Error replaying input
+    #[error("Error replaying input")]
+    VmInputReplay(#[source] VmError),
+
+    /// Error getting the input state
+    #[error("Error getting the input state")]
+    VmInputState(#[source] VmError),
+
     /// Error getting frame info
     #[error("Error getting frame info")]
     VmFrameInfo(#[source] VmError),
@@ -231,9 +305,25 @@ pub enum ApiError {
     #[error("Error setting frame capture format")]
     VmFrameCaptureSetFormat(#[source] VmError),
 
+    /// Error starting the frame capture recording
+    #[error("Error starting the frame capture recording")]
+    VmFrameCaptureRecord(#[source] VmError),
+
     /// Error getting cursor info
     #[error("Error getting cursor info")]
     VmCursorInfo(#[source] VmError),
+
+    /// The display could not be changed
+    #[error("The display could not be changed")]
+    VmDisplayChange(#[source] VmError),
+
+    /// Error getting a frame snapshot
+    #[error("Error getting a frame snapshot")]
+    VmFrameSnapshot(#[source] VmError),
+
+    /// Error encoding a frame snapshot
+    #[error("Error encoding a frame snapshot")]
+    VmFrameSnapshotEncode(#[source] FrameExportError),
 }
 pub type ApiResult<T> = Result<T, ApiError>;
 
@@ -266,6 +356,65 @@ pub struct VmResizeDiskData {
     pub desired_size: u64,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmDiskInternalSnapshotListData {
+    /// Disk the snapshots are stored in
+    pub id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmDiskInternalSnapshotData {
+    /// Disk the snapshot is stored in
+    pub id: String,
+    /// Name of the snapshot to create, or ID or name of the one to apply or
+    /// delete
+    pub name: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmDiskSnapshotData {
+    /// Disk to switch to the overlay
+    pub id: String,
+    /// Path of the qcow2 overlay to create, backed by the current image
+    pub overlay: PathBuf,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmDiskCommitData {
+    /// Disk whose overlay is merged into its backing file
+    pub id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmDiskBackupData {
+    /// Disk to back up
+    pub id: String,
+    /// Path of the qcow2 image to create with the content of the disk
+    pub target: PathBuf,
+    /// Only copy the chunks written since the previous backup
+    #[serde(default)]
+    pub incremental: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmDiskBackupJobData {
+    /// Disk whose backup is reported or cancelled
+    pub id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmDisplayChangeData {
+    /// virtio-gpu scanout the display is connected to
+    pub scanout_id: u32,
+    /// Whether a display is connected to the scanout
+    pub connected: bool,
+    /// Display resolution, required when connecting a display
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
 pub struct VmResizeZoneData {
     pub id: String,
@@ -311,10 +460,124 @@ pub struct VmInjectInputResponse {
     pub keyboard_events: u64,
     /// Number of mouse events injected
     pub mouse_events: u64,
+    /// Number of touch events injected
+    #[serde(default)]
+    pub touch_events: u64,
     /// Total events injected
     pub total_events: u64,
     /// Number of errors during injection
     pub errors: u64,
+    /// Number of events queued until the backend can take them
+    #[serde(default)]
+    pub deferred_events: u64,
+    /// Number of events dropped because too many were queued
+    #[serde(default)]
+    pub dropped_events: u64,
+    /// Backend the events were sent to
+    #[serde(default)]
+    pub backend: String,
+    /// Counters of each backend since the VM was created, by backend name
+    #[serde(default)]
+    pub backends: BTreeMap<String, BackendStats>,
+}
+
+/// Input backend switch request
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmInputSwitchBackendData {
+    /// Backend to send the events to when a request doesn't name one
+    pub backend: String,
+}
+
+/// Text typing request
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmTypeTextData {
+    /// UTF-8 text to type
+    pub text: String,
+    /// Keyboard layout of the guest
+    #[serde(default)]
+    pub layout: KeyboardLayout,
+    /// Delay between two characters, in milliseconds
+    #[serde(default = "default_key_delay_ms")]
+    pub delay_ms: u64,
+    /// Backend to send the events to, the active one by default
+    #[serde(default)]
+    pub backend: Option<String>,
+}
+
+fn default_key_delay_ms() -> u64 {
+    DEFAULT_KEY_DELAY_MS
+}
+
+/// Text typing response
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmTypeTextResponse {
+    /// Number of characters typed, including the ones left to type
+    pub characters: u64,
+    /// Characters the layout cannot produce, which were skipped
+    pub untypable: Vec<UntypableChar>,
+    /// Number of keyboard events injected before responding
+    pub keyboard_events: u64,
+    /// Number of errors during injection
+    pub errors: u64,
+    /// Number of events queued until the backend can take them
+    #[serde(default)]
+    pub deferred_events: u64,
+    /// Number of events dropped because too many were queued
+    #[serde(default)]
+    pub dropped_events: u64,
+    /// Number of events left to send, `delay_ms` apart, after responding
+    #[serde(default)]
+    pub queued_events: u64,
+    /// Backend the events were sent to
+    pub backend: String,
+}
+
+/// Input recording stop request
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmInputRecordStopData {
+    /// File to save the recorded script to, as JSON
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+/// Input replay request
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmInputReplayData {
+    /// Script to replay
+    #[serde(default)]
+    pub script: Option<InputScript>,
+    /// JSON file to read the script from, when `script` isn't given
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Speed of the replay, 2.0 replays twice as fast as recorded
+    #[serde(default = "default_replay_speed")]
+    pub speed: f64,
+    /// Backend to send the events to, the active one by default
+    #[serde(default)]
+    pub backend: Option<String>,
+}
+
+fn default_replay_speed() -> f64 {
+    1.0
+}
+
+/// Input backend state response
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmInputBackendResponse {
+    /// Backend the events are sent to when a request doesn't name one
+    pub active_backend: String,
+    /// Backends with a device in the VM
+    pub available_backends: Vec<String>,
+    /// Counters of each backend since the VM was created, by backend name
+    pub backends: BTreeMap<String, BackendStats>,
+}
+
+/// Frame buffer information request
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmFrameInfoData {
+    /// virtio-gpu scanout to report, the first one by default
+    #[serde(default)]
+    pub scanout_id: u32,
 }
 
 /// Frame buffer information response
@@ -352,6 +615,72 @@ pub struct VmFrameCaptureStatusResponse {
     pub height: u32,
     /// Frame format
     pub format: String,
+    /// Whether the frames are being recorded to a file
+    #[serde(default)]
+    pub recording: bool,
+    /// Number of frames written to the recording
+    #[serde(default)]
+    pub recorded_frames: u64,
+}
+
+fn default_record_fps() -> u32 {
+    30
+}
+
+/// Frame capture recording request
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct VmFrameCaptureRecordData {
+    /// Video file, the audio is written next to it with a `.wav` extension
+    pub path: PathBuf,
+    /// Frame rate of the video, frames published faster are dropped
+    #[serde(default = "default_record_fps")]
+    pub fps: u32,
+    /// Container of the video
+    #[serde(default)]
+    pub container: VideoContainer,
+}
+
+/// Encoding of a frame snapshot
+#[derive(Clone, Copy, Deserialize, Serialize, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameEncoding {
+    /// 8-bit RGB PNG image
+    #[default]
+    Png,
+    /// Pixel data in the frame format, without row padding
+    Raw,
+}
+
+/// Frame snapshot request
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmFrameSnapshotData {
+    /// virtio-gpu scanout to capture, the first one by default
+    #[serde(default)]
+    pub scanout_id: u32,
+    /// Encoding of the returned image
+    #[serde(default)]
+    pub encoding: FrameEncoding,
+}
+
+/// Frame snapshot response
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmFrameSnapshotResponse {
+    /// Frame width in pixels
+    pub width: u32,
+    /// Frame height in pixels
+    pub height: u32,
+    /// Pixel format of the frame (e.g., "BGRA32", "NV12")
+    pub format: String,
+    /// Encoding of `data`
+    pub encoding: FrameEncoding,
+    /// Sequence number of the frame
+    pub frame_number: u64,
+    /// Frame timestamp in nanoseconds, 0 if unknown
+    pub timestamp_ns: u64,
+    /// Buffer the frame was read from
+    pub buffer_index: u32,
+    /// Base64 encoded image
+    pub data: String,
 }
 
 /// Frame capture format configuration
@@ -386,6 +715,9 @@ pub struct VmCursorInfoResponse {
     pub has_shape: bool,
     /// Cursor update counter
     pub update_count: u32,
+    /// Scanout the cursor is displayed on
+    #[serde(default)]
+    pub scanout_id: u32,
 }
 
 pub enum ApiResponsePayload {
@@ -404,6 +736,21 @@ pub enum ApiResponsePayload {
     /// Vm inject input response
     VmInjectInput(VmInjectInputResponse),
 
+    /// Vm input backend switch response
+    VmInputSwitchBackend(VmInputBackendResponse),
+
+    /// Vm type text response
+    VmTypeText(VmTypeTextResponse),
+
+    /// Vm input recording stop response
+    VmInputRecordStop(InputScript),
+
+    /// Vm input replay response
+    VmInputReplay(ReplayStatus),
+
+    /// Vm input state response
+    VmInputState(InputState),
+
     /// Vm frame info response
     VmFrameInfo(VmFrameInfoResponse),
 
@@ -412,6 +759,9 @@ pub enum ApiResponsePayload {
 
     /// Vm cursor info response
     VmCursorInfo(VmCursorInfoResponse),
+
+    /// Vm frame snapshot, encoded by the API thread
+    VmFrameSnapshot(Frame),
 }
 
 /// This is the response sent by the VMM API server through the mpsc channel.
@@ -454,8 +804,37 @@ pub trait RequestHandler {
 
     fn vm_resize_zone(&mut self, id: String, desired_ram: u64) -> Result<(), VmError>;
 
+    fn vm_display_change(&mut self, display_data: VmDisplayChangeData) -> Result<(), VmError>;
+
     fn vm_resize_disk(&mut self, id: String, desired_size: u64) -> Result<(), VmError>;
 
+    fn vm_disk_internal_snapshot_list(&mut self, id: String) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vm_disk_internal_snapshot_create(&mut self, id: String, name: String)
+    -> Result<(), VmError>;
+
+    fn vm_disk_internal_snapshot_apply(&mut self, id: String, name: String) -> Result<(), VmError>;
+
+    fn vm_disk_internal_snapshot_delete(&mut self, id: String, name: String)
+    -> Result<(), VmError>;
+
+    fn vm_disk_snapshot(&mut self, id: String, overlay: PathBuf) -> Result<(), VmError>;
+
+    fn vm_disk_commit(&mut self, id: String) -> Result<(), VmError>;
+
+    fn vm_disk_commit_status(&mut self, id: String) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vm_disk_backup(
+        &mut self,
+        id: String,
+        target: PathBuf,
+        incremental: bool,
+    ) -> Result<(), VmError>;
+
+    fn vm_disk_backup_status(&mut self, id: String) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vm_disk_backup_cancel(&mut self, id: String) -> Result<(), VmError>;
+
     fn vm_add_device(&mut self, device_cfg: DeviceConfig) -> Result<Option<Vec<u8>>, VmError>;
 
     fn vm_add_user_device(
@@ -482,6 +861,10 @@ pub trait RequestHandler {
 
     fn vm_add_vsock(&mut self, vsock_cfg: VsockConfig) -> Result<Option<Vec<u8>>, VmError>;
 
+    fn vm_add_gpu(&mut self, gpu_cfg: GpuConfig) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vm_add_input(&mut self, input_cfg: VirtioInputConfig) -> Result<Option<Vec<u8>>, VmError>;
+
     fn vm_counters(&mut self) -> Result<Option<Vec<u8>>, VmError>;
 
     fn vm_power_button(&mut self) -> Result<(), VmError>;
@@ -500,7 +883,35 @@ pub trait RequestHandler {
 
     fn vm_inject_input(&mut self, input_request: InputRequest) -> Result<VmInjectInputResponse, VmError>;
 
-    fn vm_frame_info(&self) -> Result<VmFrameInfoResponse, VmError>;
+    fn vm_input_switch_backend(
+        &mut self,
+        switch_data: VmInputSwitchBackendData,
+    ) -> Result<VmInputBackendResponse, VmError>;
+
+    fn vm_type_text(
+        &mut self,
+        type_text_data: VmTypeTextData,
+    ) -> Result<VmTypeTextResponse, VmError>;
+
+    fn vm_input_record_start(&mut self) -> Result<(), VmError>;
+
+    fn vm_input_record_stop(
+        &mut self,
+        record_stop_data: VmInputRecordStopData,
+    ) -> Result<InputScript, VmError>;
+
+    fn vm_input_replay_start(
+        &mut self,
+        replay_data: VmInputReplayData,
+    ) -> Result<ReplayStatus, VmError>;
+
+    fn vm_input_replay_stop(&mut self) -> Result<ReplayStatus, VmError>;
+
+    fn vm_input_replay_status(&self) -> Result<ReplayStatus, VmError>;
+
+    fn vm_input_state(&self) -> Result<InputState, VmError>;
+
+    fn vm_frame_info(&self, scanout_id: u32) -> Result<VmFrameInfoResponse, VmError>;
 
     fn vm_frame_capture_start(&mut self) -> Result<(), VmError>;
 
@@ -510,7 +921,14 @@ pub trait RequestHandler {
 
     fn vm_frame_capture_set_format(&mut self, format: VmFrameCaptureFormat) -> Result<(), VmError>;
 
+    fn vm_frame_capture_record(
+        &mut self,
+        record_data: VmFrameCaptureRecordData,
+    ) -> Result<(), VmError>;
+
     fn vm_cursor_info(&self) -> Result<VmCursorInfoResponse, VmError>;
+
+    fn vm_frame_snapshot(&self, scanout_id: u32) -> Result<Frame, VmError>;
 }
 
 /// It would be nice if we could pass around an object like this:
@@ -872,6 +1290,80 @@ impl ApiAction for VmAddVsock {
     }
 }
 
+pub struct VmAddGpu;
+
+impl ApiAction for VmAddGpu {
+    type RequestBody = GpuConfig;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        config: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmAddGpu {config:?}");
+
+            let response = vmm
+                .vm_add_gpu(config)
+                .map_err(ApiError::VmAddGpu)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmAddInput;
+
+impl ApiAction for VmAddInput {
+    type RequestBody = VirtioInputConfig;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        config: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmAddInput {config:?}");
+
+            let response = vmm
+                .vm_add_input(config)
+                .map_err(ApiError::VmAddInput)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
 pub struct VmAddUserDevice;
 
 impl ApiAction for VmAddUserDevice {
@@ -1369,24 +1861,24 @@ impl ApiAction for VmResizeDisk {
     }
 }
 
-pub struct VmResizeZone;
+pub struct VmDiskInternalSnapshotList;
 
-impl ApiAction for VmResizeZone {
-    type RequestBody = VmResizeZoneData;
+impl ApiAction for VmDiskInternalSnapshotList {
+    type RequestBody = VmDiskInternalSnapshotListData;
     type ResponseBody = Option<Body>;
 
     fn request(
         &self,
-        resize_zone_data: Self::RequestBody,
+        snapshot_data: Self::RequestBody,
         response_sender: Sender<ApiResponse>,
     ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmResizeZone {resize_zone_data:?}");
+            info!("API request event: VmDiskInternalSnapshotList {snapshot_data:?}");
 
             let response = vmm
-                .vm_resize_zone(resize_zone_data.id, resize_zone_data.desired_ram)
-                .map_err(ApiError::VmResizeZone)
-                .map(|_| ApiResponsePayload::Empty);
+                .vm_disk_internal_snapshot_list(snapshot_data.id)
+                .map_err(ApiError::VmDiskInternalSnapshotList)
+                .map(ApiResponsePayload::VmAction);
 
             response_sender
                 .send(response)
@@ -1406,23 +1898,23 @@ impl ApiAction for VmResizeZone {
     }
 }
 
-pub struct VmRestore;
+pub struct VmDiskInternalSnapshotCreate;
 
-impl ApiAction for VmRestore {
-    type RequestBody = RestoreConfig;
+impl ApiAction for VmDiskInternalSnapshotCreate {
+    type RequestBody = VmDiskInternalSnapshotData;
     type ResponseBody = Option<Body>;
 
     fn request(
         &self,
-        config: Self::RequestBody,
+        snapshot_data: Self::RequestBody,
         response_sender: Sender<ApiResponse>,
     ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmRestore {config:?}");
+            info!("API request event: VmDiskInternalSnapshotCreate {snapshot_data:?}");
 
             let response = vmm
-                .vm_restore(config)
-                .map_err(ApiError::VmRestore)
+                .vm_disk_internal_snapshot_create(snapshot_data.id, snapshot_data.name)
+                .map_err(ApiError::VmDiskInternalSnapshotCreate)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
@@ -1443,19 +1935,23 @@ impl ApiAction for VmRestore {
     }
 }
 
-pub struct VmResume;
+pub struct VmDiskInternalSnapshotApply;
 
-impl ApiAction for VmResume {
-    type RequestBody = ();
+impl ApiAction for VmDiskInternalSnapshotApply {
+    type RequestBody = VmDiskInternalSnapshotData;
     type ResponseBody = Option<Body>;
 
-    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+    fn request(
+        &self,
+        snapshot_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmResume");
+            info!("API request event: VmDiskInternalSnapshotApply {snapshot_data:?}");
 
             let response = vmm
-                .vm_resume()
-                .map_err(ApiError::VmResume)
+                .vm_disk_internal_snapshot_apply(snapshot_data.id, snapshot_data.name)
+                .map_err(ApiError::VmDiskInternalSnapshotApply)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
@@ -1476,19 +1972,422 @@ impl ApiAction for VmResume {
     }
 }
 
-pub struct VmSendMigration;
+pub struct VmDiskInternalSnapshotDelete;
 
-impl ApiAction for VmSendMigration {
-    type RequestBody = VmSendMigrationData;
+impl ApiAction for VmDiskInternalSnapshotDelete {
+    type RequestBody = VmDiskInternalSnapshotData;
     type ResponseBody = Option<Body>;
 
-    fn request(&self, data: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+    fn request(
+        &self,
+        snapshot_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmSendMigration {data:?}");
+            info!("API request event: VmDiskInternalSnapshotDelete {snapshot_data:?}");
 
             let response = vmm
-                .vm_send_migration(data)
-                .map_err(ApiError::VmSendMigration)
+                .vm_disk_internal_snapshot_delete(snapshot_data.id, snapshot_data.name)
+                .map_err(ApiError::VmDiskInternalSnapshotDelete)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmDiskSnapshot;
+
+impl ApiAction for VmDiskSnapshot {
+    type RequestBody = VmDiskSnapshotData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        snapshot_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmDiskSnapshot {snapshot_data:?}");
+
+            let response = vmm
+                .vm_disk_snapshot(snapshot_data.id, snapshot_data.overlay)
+                .map_err(ApiError::VmDiskSnapshot)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmDiskCommit;
+
+impl ApiAction for VmDiskCommit {
+    type RequestBody = VmDiskCommitData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        commit_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmDiskCommit {commit_data:?}");
+
+            let response = vmm
+                .vm_disk_commit(commit_data.id)
+                .map_err(ApiError::VmDiskCommit)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmDiskCommitStatus;
+
+impl ApiAction for VmDiskCommitStatus {
+    type RequestBody = VmDiskCommitData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        commit_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmDiskCommitStatus {commit_data:?}");
+
+            let response = vmm
+                .vm_disk_commit_status(commit_data.id)
+                .map_err(ApiError::VmDiskCommitStatus)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmDiskBackup;
+
+impl ApiAction for VmDiskBackup {
+    type RequestBody = VmDiskBackupData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        backup_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmDiskBackup {backup_data:?}");
+
+            let response = vmm
+                .vm_disk_backup(backup_data.id, backup_data.target, backup_data.incremental)
+                .map_err(ApiError::VmDiskBackup)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmDiskBackupStatus;
+
+impl ApiAction for VmDiskBackupStatus {
+    type RequestBody = VmDiskBackupJobData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        backup_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmDiskBackupStatus {backup_data:?}");
+
+            let response = vmm
+                .vm_disk_backup_status(backup_data.id)
+                .map_err(ApiError::VmDiskBackupStatus)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmDiskBackupCancel;
+
+impl ApiAction for VmDiskBackupCancel {
+    type RequestBody = VmDiskBackupJobData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        backup_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmDiskBackupCancel {backup_data:?}");
+
+            let response = vmm
+                .vm_disk_backup_cancel(backup_data.id)
+                .map_err(ApiError::VmDiskBackupCancel)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmDisplayChange;
+
+impl ApiAction for VmDisplayChange {
+    type RequestBody = VmDisplayChangeData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        display_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmDisplayChange {display_data:?}");
+
+            let response = vmm
+                .vm_display_change(display_data)
+                .map_err(ApiError::VmDisplayChange)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmResizeZone;
+
+impl ApiAction for VmResizeZone {
+    type RequestBody = VmResizeZoneData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        resize_zone_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmResizeZone {resize_zone_data:?}");
+
+            let response = vmm
+                .vm_resize_zone(resize_zone_data.id, resize_zone_data.desired_ram)
+                .map_err(ApiError::VmResizeZone)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmRestore;
+
+impl ApiAction for VmRestore {
+    type RequestBody = RestoreConfig;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        config: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmRestore {config:?}");
+
+            let response = vmm
+                .vm_restore(config)
+                .map_err(ApiError::VmRestore)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmResume;
+
+impl ApiAction for VmResume {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmResume");
+
+            let response = vmm
+                .vm_resume()
+                .map_err(ApiError::VmResume)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmSendMigration;
+
+impl ApiAction for VmSendMigration {
+    type RequestBody = VmSendMigrationData;
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, data: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmSendMigration {data:?}");
+
+            let response = vmm
+                .vm_send_migration(data)
+                .map_err(ApiError::VmSendMigration)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
@@ -1511,22 +2410,232 @@ impl ApiAction for VmSendMigration {
 
 pub struct VmShutdown;
 
-impl ApiAction for VmShutdown {
-    type RequestBody = ();
-    type ResponseBody = Option<Body>;
+impl ApiAction for VmShutdown {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        config: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmShutdown {config:?}");
+
+            let response = vmm
+                .vm_shutdown()
+                .map_err(ApiError::VmShutdown)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmSnapshot;
+
+impl ApiAction for VmSnapshot {
+    type RequestBody = VmSnapshotConfig;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        config: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmSnapshot {config:?}");
+
+            let response = vmm
+                .vm_snapshot(&config.destination_url)
+                .map_err(ApiError::VmSnapshot)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmmPing;
+
+impl ApiAction for VmmPing {
+    type RequestBody = ();
+    type ResponseBody = VmmPingResponse;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmPing");
+
+            let response = ApiResponsePayload::VmmPing(vmm.vmm_ping());
+
+            response_sender
+                .send(Ok(response))
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: (),
+    ) -> ApiResult<VmmPingResponse> {
+        let vmm_pong = get_response(self, api_evt, api_sender, data)?;
+
+        match vmm_pong {
+            ApiResponsePayload::VmmPing(pong) => Ok(pong),
+            _ => Err(ApiError::ResponsePayloadType),
+        }
+    }
+}
+
+pub struct VmmShutdown;
+
+impl ApiAction for VmmShutdown {
+    type RequestBody = ();
+    type ResponseBody = ();
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmShutdown");
+
+            let response = vmm
+                .vmm_shutdown()
+                .map_err(ApiError::VmmShutdown)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(true)
+        })
+    }
+
+    fn send(&self, api_evt: EventFd, api_sender: Sender<ApiRequest>, data: ()) -> ApiResult<()> {
+        get_response(self, api_evt, api_sender, data)?;
+
+        Ok(())
+    }
+}
+
+pub struct VmNmi;
+
+impl ApiAction for VmNmi {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmNmi");
+
+            let response = vmm
+                .vm_nmi()
+                .map_err(ApiError::VmNmi)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmInjectInput;
+
+impl ApiAction for VmInjectInput {
+    type RequestBody = InputRequest;
+    type ResponseBody = VmInjectInputResponse;
+
+    fn request(&self, input_request: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmInjectInput");
+
+            let response = vmm
+                .vm_inject_input(input_request)
+                .map_err(ApiError::VmInjectInput)
+                .map(ApiResponsePayload::VmInjectInput);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        let response = get_response(self, api_evt, api_sender, data)?;
+
+        match response {
+            ApiResponsePayload::VmInjectInput(stats) => Ok(stats),
+            _ => Err(ApiError::ResponsePayloadType),
+        }
+    }
+}
+
+pub struct VmInputSwitchBackend;
+
+impl ApiAction for VmInputSwitchBackend {
+    type RequestBody = VmInputSwitchBackendData;
+    type ResponseBody = VmInputBackendResponse;
 
     fn request(
         &self,
-        config: Self::RequestBody,
+        switch_data: Self::RequestBody,
         response_sender: Sender<ApiResponse>,
     ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmShutdown {config:?}");
+            info!("API request event: VmInputSwitchBackend {switch_data:?}");
 
             let response = vmm
-                .vm_shutdown()
-                .map_err(ApiError::VmShutdown)
-                .map(|_| ApiResponsePayload::Empty);
+                .vm_input_switch_backend(switch_data)
+                .map_err(ApiError::VmInputSwitchBackend)
+                .map(ApiResponsePayload::VmInputSwitchBackend);
 
             response_sender
                 .send(response)
@@ -1542,28 +2651,37 @@ impl ApiAction for VmShutdown {
         api_sender: Sender<ApiRequest>,
         data: Self::RequestBody,
     ) -> ApiResult<Self::ResponseBody> {
-        get_response_body(self, api_evt, api_sender, data)
+        let response = get_response(self, api_evt, api_sender, data)?;
+
+        match response {
+            ApiResponsePayload::VmInputSwitchBackend(state) => Ok(state),
+            _ => Err(ApiError::ResponsePayloadType),
+        }
     }
 }
 
-pub struct VmSnapshot;
+pub struct VmTypeText;
 
-impl ApiAction for VmSnapshot {
-    type RequestBody = VmSnapshotConfig;
-    type ResponseBody = Option<Body>;
+impl ApiAction for VmTypeText {
+    type RequestBody = VmTypeTextData;
+    type ResponseBody = VmTypeTextResponse;
 
     fn request(
         &self,
-        config: Self::RequestBody,
+        type_text_data: Self::RequestBody,
         response_sender: Sender<ApiResponse>,
     ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmSnapshot {config:?}");
+            info!(
+                "API request event: VmTypeText {} characters, layout {}",
+                type_text_data.text.chars().count(),
+                type_text_data.layout.name()
+            );
 
             let response = vmm
-                .vm_snapshot(&config.destination_url)
-                .map_err(ApiError::VmSnapshot)
-                .map(|_| ApiResponsePayload::Empty);
+                .vm_type_text(type_text_data)
+                .map_err(ApiError::VmTypeText)
+                .map(ApiResponsePayload::VmTypeText);
 
             response_sender
                 .send(response)
@@ -1579,24 +2697,32 @@ impl ApiAction for VmSnapshot {
         api_sender: Sender<ApiRequest>,
         data: Self::RequestBody,
     ) -> ApiResult<Self::ResponseBody> {
-        get_response_body(self, api_evt, api_sender, data)
+        let response = get_response(self, api_evt, api_sender, data)?;
+
+        match response {
+            ApiResponsePayload::VmTypeText(typed) => Ok(typed),
+            _ => Err(ApiError::ResponsePayloadType),
+        }
     }
 }
 
-pub struct VmmPing;
+pub struct VmInputRecordStart;
 
-impl ApiAction for VmmPing {
+impl ApiAction for VmInputRecordStart {
     type RequestBody = ();
-    type ResponseBody = VmmPingResponse;
+    type ResponseBody = Option<Body>;
 
     fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmmPing");
+            info!("API request event: VmInputRecordStart");
 
-            let response = ApiResponsePayload::VmmPing(vmm.vmm_ping());
+            let response = vmm
+                .vm_input_record_start()
+                .map_err(ApiError::VmInputRecord)
+                .map(|_| ApiResponsePayload::Empty);
 
             response_sender
-                .send(Ok(response))
+                .send(response)
                 .map_err(VmmError::ApiResponseSend)?;
 
             Ok(false)
@@ -1607,61 +2733,151 @@ impl ApiAction for VmmPing {
         &self,
         api_evt: EventFd,
         api_sender: Sender<ApiRequest>,
-        data: (),
-    ) -> ApiResult<VmmPingResponse> {
-        let vmm_pong = get_response(self, api_evt, api_sender, data)?;
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
 
-        match vmm_pong {
-            ApiResponsePayload::VmmPing(pong) => Ok(pong),
+pub struct VmInputRecordStop;
+
+impl ApiAction for VmInputRecordStop {
+    type RequestBody = VmInputRecordStopData;
+    type ResponseBody = InputScript;
+
+    fn request(
+        &self,
+        record_stop_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmInputRecordStop {record_stop_data:?}");
+
+            let response = vmm
+                .vm_input_record_stop(record_stop_data)
+                .map_err(ApiError::VmInputRecord)
+                .map(ApiResponsePayload::VmInputRecordStop);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        let response = get_response(self, api_evt, api_sender, data)?;
+
+        match response {
+            ApiResponsePayload::VmInputRecordStop(script) => Ok(script),
             _ => Err(ApiError::ResponsePayloadType),
         }
     }
 }
 
-pub struct VmmShutdown;
+pub struct VmInputReplayStart;
 
-impl ApiAction for VmmShutdown {
+impl ApiAction for VmInputReplayStart {
+    type RequestBody = VmInputReplayData;
+    type ResponseBody = ReplayStatus;
+
+    fn request(
+        &self,
+        replay_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!(
+                "API request event: VmInputReplayStart path {:?}, speed {}",
+                replay_data.path, replay_data.speed
+            );
+
+            let response = vmm
+                .vm_input_replay_start(replay_data)
+                .map_err(ApiError::VmInputReplay)
+                .map(ApiResponsePayload::VmInputReplay);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        let response = get_response(self, api_evt, api_sender, data)?;
+
+        match response {
+            ApiResponsePayload::VmInputReplay(status) => Ok(status),
+            _ => Err(ApiError::ResponsePayloadType),
+        }
+    }
+}
+
+pub struct VmInputReplayStop;
+
+impl ApiAction for VmInputReplayStop {
     type RequestBody = ();
-    type ResponseBody = ();
+    type ResponseBody = ReplayStatus;
 
     fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmmShutdown");
+            info!("API request event: VmInputReplayStop");
 
             let response = vmm
-                .vmm_shutdown()
-                .map_err(ApiError::VmmShutdown)
-                .map(|_| ApiResponsePayload::Empty);
+                .vm_input_replay_stop()
+                .map_err(ApiError::VmInputReplay)
+                .map(ApiResponsePayload::VmInputReplay);
 
             response_sender
                 .send(response)
                 .map_err(VmmError::ApiResponseSend)?;
 
-            Ok(true)
+            Ok(false)
         })
     }
 
-    fn send(&self, api_evt: EventFd, api_sender: Sender<ApiRequest>, data: ()) -> ApiResult<()> {
-        get_response(self, api_evt, api_sender, data)?;
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        let response = get_response(self, api_evt, api_sender, data)?;
 
-        Ok(())
+        match response {
+            ApiResponsePayload::VmInputReplay(status) => Ok(status),
+            _ => Err(ApiError::ResponsePayloadType),
+        }
     }
 }
 
-pub struct VmNmi;
+pub struct VmInputReplayStatus;
 
-impl ApiAction for VmNmi {
+impl ApiAction for VmInputReplayStatus {
     type RequestBody = ();
-    type ResponseBody = Option<Body>;
+    type ResponseBody = ReplayStatus;
 
     fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmNmi");
+            info!("API request event: VmInputReplayStatus");
 
             let response = vmm
-                .vm_nmi()
-                .map_err(ApiError::VmNmi)
-                .map(|_| ApiResponsePayload::Empty);
+                .vm_input_replay_status()
+                .map_err(ApiError::VmInputReplay)
+                .map(ApiResponsePayload::VmInputReplay);
 
             response_sender
                 .send(response)
@@ -1677,24 +2893,29 @@ impl ApiAction for VmNmi {
         api_sender: Sender<ApiRequest>,
         data: Self::RequestBody,
     ) -> ApiResult<Self::ResponseBody> {
-        get_response_body(self, api_evt, api_sender, data)
+        let response = get_response(self, api_evt, api_sender, data)?;
+
+        match response {
+            ApiResponsePayload::VmInputReplay(status) => Ok(status),
+            _ => Err(ApiError::ResponsePayloadType),
+        }
     }
 }
 
-pub struct VmInjectInput;
+pub struct VmInputState;
 
-impl ApiAction for VmInjectInput {
-    type RequestBody = InputRequest;
-    type ResponseBody = VmInjectInputResponse;
+impl ApiAction for VmInputState {
+    type RequestBody = ();
+    type ResponseBody = InputState;
 
-    fn request(&self, input_request: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmInjectInput");
+            info!("API request event: VmInputState");
 
             let response = vmm
-                .vm_inject_input(input_request)
-                .map_err(ApiError::VmInjectInput)
-                .map(ApiResponsePayload::VmInjectInput);
+                .vm_input_state()
+                .map_err(ApiError::VmInputState)
+                .map(ApiResponsePayload::VmInputState);
 
             response_sender
                 .send(response)
@@ -1713,7 +2934,7 @@ impl ApiAction for VmInjectInput {
         let response = get_response(self, api_evt, api_sender, data)?;
 
         match response {
-            ApiResponsePayload::VmInjectInput(stats) => Ok(stats),
+            ApiResponsePayload::VmInputState(state) => Ok(state),
             _ => Err(ApiError::ResponsePayloadType),
         }
     }
@@ -1722,15 +2943,19 @@ impl ApiAction for VmInjectInput {
 pub struct VmFrameInfo;
 
 impl ApiAction for VmFrameInfo {
-    type RequestBody = ();
+    type RequestBody = VmFrameInfoData;
     type ResponseBody = VmFrameInfoResponse;
 
-    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+    fn request(
+        &self,
+        frame_info_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmFrameInfo");
+            info!("API request event: VmFrameInfo {frame_info_data:?}");
 
             let response = vmm
-                .vm_frame_info()
+                .vm_frame_info(frame_info_data.scanout_id)
                 .map_err(ApiError::VmFrameInfo)
                 .map(ApiResponsePayload::VmFrameInfo);
 
@@ -1897,6 +3122,43 @@ impl ApiAction for VmFrameCaptureSetFormat {
     }
 }
 
+pub struct VmFrameCaptureRecord;
+
+impl ApiAction for VmFrameCaptureRecord {
+    type RequestBody = VmFrameCaptureRecordData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        record_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmFrameCaptureRecord {record_data:?}");
+
+            let response = vmm
+                .vm_frame_capture_record(record_data)
+                .map_err(ApiError::VmFrameCaptureRecord)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
 pub struct VmCursorInfo;
 
 impl ApiAction for VmCursorInfo {
@@ -1934,3 +3196,62 @@ impl ApiAction for VmCursorInfo {
         }
     }
 }
+
+pub struct VmFrameSnapshot;
+
+impl ApiAction for VmFrameSnapshot {
+    type RequestBody = VmFrameSnapshotData;
+    type ResponseBody = VmFrameSnapshotResponse;
+
+    fn request(
+        &self,
+        snapshot_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmFrameSnapshot {snapshot_data:?}");
+
+            let response = vmm
+                .vm_frame_snapshot(snapshot_data.scanout_id)
+                .map_err(ApiError::VmFrameSnapshot)
+                .map(ApiResponsePayload::VmFrameSnapshot);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        let encoding = data.encoding;
+        let response = get_response(self, api_evt, api_sender, data)?;
+
+        let ApiResponsePayload::VmFrameSnapshot(frame) = response else {
+            return Err(ApiError::ResponsePayloadType);
+        };
+        // Encoding happens on the API thread, not to hold the VMM thread
+        let image = match encoding {
+            FrameEncoding::Png => frame.to_png(),
+            FrameEncoding::Raw => frame.to_raw(),
+        }
+        .map_err(ApiError::VmFrameSnapshotEncode)?;
+
+        Ok(VmFrameSnapshotResponse {
+            width: frame.width,
+            height: frame.height,
+            format: frame.format,
+            encoding,
+            frame_number: frame.frame_number,
+            timestamp_ns: frame.timestamp_ns,
+            buffer_index: frame.buffer_index,
+            data: base64_encode(&image),
+        })
+    }
+}
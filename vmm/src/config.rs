@@ -21,8 +21,9 @@ use thiserror::Error;
 use virtio_bindings::virtio_blk::VIRTIO_BLK_ID_BYTES;
 use virtio_bindings::virtio_ids::*;
 use virtio_devices::block::MINIMUM_BLOCK_QUEUE_SIZE;
+use virtio_devices::gpu::VIRTIO_GPU_MAX_SCANOUTS;
 use virtio_devices::vhost_user::VIRTIO_FS_TAG_LEN;
-use virtio_devices::{RateLimiterConfig, TokenBucketConfig};
+use virtio_devices::{PointerMode, RateLimiterConfig, TokenBucketConfig};
 
 use crate::landlock::LandlockAccess;
 use crate::vm_config::*;
@@ -198,6 +199,39 @@ pub enum Error {
     /// Missing path for ivsmem device
     #[error("Error parsing --ivshmem: path missing")]
     ParseIvshmemPathMissing,
+    #[cfg(feature = "ivshmem")]
+    /// Both a path and a server for ivsmem device
+    #[error("Error parsing --ivshmem: path and server are mutually exclusive")]
+    ParseIvshmemPathWithServer,
+    #[cfg(feature = "ivshmem")]
+    /// Invalid frame buffer for ivsmem device
+    #[error("Error parsing --ivshmem: frame_buffer must be WIDTHxHEIGHT:FORMAT[:BUFFER_COUNT]")]
+    ParseIvshmemFrameBuffer,
+    #[cfg(feature = "ivshmem")]
+    /// Frame notifications without frame buffer for ivsmem device
+    #[error("Error parsing --ivshmem: frame_notify requires frame_buffer")]
+    ParseIvshmemFrameNotifyWithoutFrameBuffer,
+    /// Failed parsing USB controller
+    #[error("Error parsing --usb")]
+    ParseUsb(#[source] OptionParserError),
+    /// Failed parsing GPU device
+    #[error("Error parsing --gpu")]
+    ParseGpu(#[source] OptionParserError),
+    /// Failed parsing virtio-input device
+    #[error("Error parsing --input")]
+    ParseInput(#[source] OptionParserError),
+    #[cfg(feature = "vnc")]
+    /// Failed parsing VNC server
+    #[error("Error parsing --vnc")]
+    ParseVnc(#[source] OptionParserError),
+    #[cfg(feature = "vnc")]
+    /// Missing socket for VNC server
+    #[error("Error parsing --vnc: socket or addr missing")]
+    ParseVncListenerMissing,
+    #[cfg(feature = "vnc")]
+    /// Both a UNIX socket and a TCP address for VNC server
+    #[error("Error parsing --vnc: socket and addr are mutually exclusive")]
+    ParseVncSocketWithAddr,
     /// Error parsing Landlock rules
     #[error("Error parsing --landlock-rules")]
     ParseLandlockRules(#[source] OptionParserError),
@@ -365,6 +399,15 @@ pub enum ValidationError {
     /// Invalid block device serial length
     #[error("Block device serial length ({0}) exceeds maximum allowed length ({1})")]
     InvalidSerialLength(usize, usize),
+    /// Dirty bitmap on a disk the VMM doesn't write to
+    #[error("Dirty bitmap is not supported on read-only or vhost-user disks")]
+    DirtyBitmapNotSupported,
+    /// Both a key file and a key secret for a disk
+    #[error("Only one of key_file and key_secret can be set")]
+    DiskKeyConflict,
+    /// Key for a disk the VMM doesn't open
+    #[error("Disk keys are not supported on vhost-user disks")]
+    DiskKeyNotSupported,
     #[cfg(feature = "fw_cfg")]
     /// FwCfg missing kernel
     #[error("Error --fw-cfg-config: missing --kernel")]
@@ -389,6 +432,10 @@ pub enum ValidationError {
     /// Invalid Ivshmem backend file path
     #[error("Invalid ivshmem backend file path")]
     InvalidIvshmemPath,
+    #[cfg(feature = "ivshmem")]
+    /// Invalid number of Ivshmem interrupt vectors
+    #[error("Invalid number of ivshmem vectors: {0}")]
+    InvalidIvshmemVectors(u16),
     #[error("Payload configuration is not bootable")]
     PayloadError(#[from] PayloadConfigError),
     #[error("Mask provided without an IP")]
@@ -398,6 +445,40 @@ pub enum ValidationError {
     /// Invalid NUMA Configuration
     #[error("NUMA Configuration is invalid")]
     InvalidNumaConfig(String),
+    /// Invalid GPU display resolution
+    #[error("Invalid GPU resolution: {0}x{1}")]
+    InvalidGpuResolution(u32, u32),
+    /// Invalid number of GPU outputs
+    #[error("Invalid number of GPU outputs: {0} (must be between 1 and {1})")]
+    InvalidGpuMaxOutputs(u32, u32),
+    /// 3D acceleration requested without the virgl feature
+    #[error("virtio-gpu 3D acceleration requires the \"virgl\" feature")]
+    GpuVirglNotSupported,
+    /// Invalid size for the GPU host-visible region
+    #[error("Invalid GPU hostmem size: {0} (must be a power of two)")]
+    InvalidGpuHostmem(u64),
+    /// More GPU displays than scanouts
+    #[error("Too many GPU outputs: {0} (max_outputs is {1})")]
+    TooManyGpuOutputs(usize, u32),
+    /// GPU host-visible region requested without blob resources
+    #[error("GPU hostmem requires blob=on")]
+    GpuHostmemWithoutBlob,
+    #[cfg(feature = "vnc")]
+    /// Invalid VNC frame rate
+    #[error("Invalid VNC frame rate: {0} (must be between 1 and {MAX_VNC_FPS})")]
+    InvalidVncFrameRate(u32),
+    #[cfg(feature = "vnc")]
+    /// VNC display out of the GPU scanouts
+    #[error("Invalid VNC scanout: {0} (must be lower than {VIRTIO_GPU_MAX_SCANOUTS})")]
+    InvalidVncScanout(u32),
+    #[cfg(feature = "vnc")]
+    /// No VNC client allowed
+    #[error("Invalid VNC max_clients: 0")]
+    InvalidVncMaxClients,
+    #[cfg(feature = "vnc")]
+    /// Unauthenticated VNC server reachable from other hosts
+    #[error("VNC address {0} is not a loopback address, allow_remote=on is needed")]
+    VncRemoteAddress(std::net::SocketAddr),
 }
 
 type ValidationResult<T> = std::result::Result<T, ValidationError>;
@@ -456,6 +537,11 @@ pub struct VmParams<'a> {
     pub fw_cfg_config: Option<&'a str>,
     #[cfg(feature = "ivshmem")]
     pub ivshmem: Option<&'a str>,
+    pub usb: Option<&'a str>,
+    pub gpu: Option<&'a str>,
+    pub input: Option<&'a str>,
+    #[cfg(feature = "vnc")]
+    pub vnc: Option<&'a str>,
 }
 
 impl<'a> VmParams<'a> {
@@ -531,6 +617,11 @@ impl<'a> VmParams<'a> {
             args.get_one::<String>("fw-cfg-config").map(|x| x as &str);
         #[cfg(feature = "ivshmem")]
         let ivshmem: Option<&str> = args.get_one::<String>("ivshmem").map(|x| x as &str);
+        let usb: Option<&str> = args.get_one::<String>("usb").map(|x| x as &str);
+        let gpu: Option<&str> = args.get_one::<String>("gpu").map(|x| x as &str);
+        let input: Option<&str> = args.get_one::<String>("input").map(|x| x as &str);
+        #[cfg(feature = "vnc")]
+        let vnc: Option<&str> = args.get_one::<String>("vnc").map(|x| x as &str);
         VmParams {
             cpus,
             memory,
@@ -575,6 +666,11 @@ impl<'a> VmParams<'a> {
             fw_cfg_config,
             #[cfg(feature = "ivshmem")]
             ivshmem,
+            usb,
+            gpu,
+            input,
+            #[cfg(feature = "vnc")]
+            vnc,
         }
     }
 }
@@ -1159,7 +1255,8 @@ impl DiskConfig {
          id=<device_id>,pci_segment=<segment_id>,rate_limit_group=<group_id>,\
          queue_affinity=<list_of_queue_indices_with_their_associated_cpuset>,\
          serial=<serial_number>,backing_files=on|off,sparse=on|off,\
-         image_type=<raw,qcow2,vhd,vhdx>";
+         image_type=<raw,qcow2,vhd,dynamic-vhd,vhdx,luks>,dirty_bitmap=on|off,\
+         key_file=<path_to_key_of_encrypted_image>,key_secret=<key_of_encrypted_image>";
 
     pub fn parse(disk: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1187,7 +1284,10 @@ impl DiskConfig {
             .add("queue_affinity")
             .add("backing_files")
             .add("sparse")
-            .add("image_type");
+            .add("image_type")
+            .add("dirty_bitmap")
+            .add("key_file")
+            .add("key_secret");
 
         parser.parse(disk).map_err(Error::ParseDisk)?;
 
@@ -1320,6 +1420,13 @@ impl DiskConfig {
             .map_err(Error::ParseDisk)?
             .unwrap_or_else(|| Toggle(default_diskconfig_sparse()))
             .0;
+        let dirty_bitmap = parser
+            .convert::<Toggle>("dirty_bitmap")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let key_file = parser.get("key_file").map(PathBuf::from);
+        let key_secret = parser.get("key_secret");
 
         Ok(DiskConfig {
             path,
@@ -1341,6 +1448,9 @@ impl DiskConfig {
             backing_files,
             sparse,
             image_type,
+            dirty_bitmap,
+            key_file,
+            key_secret,
         })
     }
 
@@ -1377,6 +1487,19 @@ impl DiskConfig {
             return Err(ValidationError::InvalidRateLimiterGroup);
         }
 
+        if self.dirty_bitmap && (self.vhost_user || self.readonly) {
+            return Err(ValidationError::DirtyBitmapNotSupported);
+        }
+
+        if self.key_file.is_some() || self.key_secret.is_some() {
+            if self.key_file.is_some() && self.key_secret.is_some() {
+                return Err(ValidationError::DiskKeyConflict);
+            }
+            if self.vhost_user {
+                return Err(ValidationError::DiskKeyNotSupported);
+            }
+        }
+
         // Check Block device serial length
         if let Some(ref serial) = self.serial
             && serial.len() > VIRTIO_BLK_ID_BYTES as usize
@@ -2723,24 +2846,84 @@ impl IvshmemConfig {
     pub const SYNTAX: &'static str = "Ivshmem device. Specify the backend file path and size \
     for the shared memory: \"path=</path/to/a/file>, size=<file_size>\" \
     \nThe <file_size> must be a power of 2 (e.g., 2M, 4M, etc.), as it represents the size \
-    of the memory region mapped to the guest. Default size is 128M.";
+    of the memory region mapped to the guest. Default size is 128M. \
+    \nFor the doorbell mode, replace the path with the ivshmem-server socket providing the \
+    shared memory: \"server=</path/to/socket>,vectors=<number_of_vectors>,size=<size>\" \
+    \nFor lg-capture, lay a frame buffer out in the shared memory with \
+    \"frame_buffer=<width>x<height>:<BGRA32|RGBA32|NV12>[:<buffer_count>]\" and stream a \
+    notification for every frame published by the guest with \"frame_notify=</path/to/socket>\"";
     pub fn parse(ivshmem: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("path").add("size");
+        parser
+            .add("path")
+            .add("size")
+            .add("server")
+            .add("vectors")
+            .add("frame_buffer")
+            .add("frame_notify");
         parser.parse(ivshmem).map_err(Error::ParseIvshmem)?;
-        let path = parser
-            .get("path")
-            .map(PathBuf::from)
-            .ok_or(Error::ParseIvshmemPathMissing)?;
+        let server = parser.get("server").map(PathBuf::from);
+        let path = match (parser.get("path"), &server) {
+            (Some(_), Some(_)) => return Err(Error::ParseIvshmemPathWithServer),
+            (Some(path), None) => PathBuf::from(path),
+            (None, Some(_)) => PathBuf::new(),
+            (None, None) => return Err(Error::ParseIvshmemPathMissing),
+        };
         let size = parser
             .convert::<ByteSized>("size")
             .map_err(Error::ParseIvshmem)?
             .unwrap_or(ByteSized((DEFAULT_IVSHMEM_SIZE << 20) as u64))
             .0;
+        let vectors = parser
+            .convert("vectors")
+            .map_err(Error::ParseIvshmem)?
+            .unwrap_or_else(default_ivshmem_vectors);
+        let mut frame_buffer = parser
+            .get("frame_buffer")
+            .map(|fb| Self::parse_frame_buffer(&fb))
+            .transpose()?;
+        if let Some(notify_socket) = parser.get("frame_notify") {
+            frame_buffer
+                .as_mut()
+                .ok_or(Error::ParseIvshmemFrameNotifyWithoutFrameBuffer)?
+                .notify_socket = Some(PathBuf::from(notify_socket));
+        }
         Ok(IvshmemConfig {
             path,
             size: size as usize,
-            frame_buffer: None,
+            frame_buffer,
+            server,
+            vectors,
+        })
+    }
+
+    /// Parse the `<width>x<height>:<format>[:<buffer_count>]` frame buffer
+    fn parse_frame_buffer(frame_buffer: &str) -> Result<FrameBufferConfig> {
+        let mut fields = frame_buffer.split(':');
+        let (width, height) = fields
+            .next()
+            .and_then(|size| size.split_once('x'))
+            .ok_or(Error::ParseIvshmemFrameBuffer)?;
+        let format = fields.next().ok_or(Error::ParseIvshmemFrameBuffer)?;
+        if !matches!(format, "BGRA32" | "RGBA32" | "NV12") {
+            return Err(Error::ParseIvshmemFrameBuffer);
+        }
+        let buffer_count = fields
+            .next()
+            .map(str::parse)
+            .transpose()
+            .map_err(|_| Error::ParseIvshmemFrameBuffer)?
+            .unwrap_or(devices::DEFAULT_BUFFER_COUNT);
+        if fields.next().is_some() {
+            return Err(Error::ParseIvshmemFrameBuffer);
+        }
+
+        Ok(FrameBufferConfig {
+            width: width.parse().map_err(|_| Error::ParseIvshmemFrameBuffer)?,
+            height: height.parse().map_err(|_| Error::ParseIvshmemFrameBuffer)?,
+            format: format.to_string(),
+            buffer_count,
+            notify_socket: None,
         })
     }
 
@@ -2751,6 +2934,13 @@ impl IvshmemConfig {
         if !size.is_power_of_two() {
             return Err(ValidationError::InvalidIvshmemInputSize(size));
         }
+        // The server shared memory is checked once connected
+        if self.server.is_some() {
+            if self.vectors == 0 || self.vectors > devices::ivshmem::IVSHMEM_MAX_VECTORS {
+                return Err(ValidationError::InvalidIvshmemVectors(self.vectors));
+            }
+            return Ok(());
+        }
         let metadata = fs::metadata(path.to_str().unwrap())
             .map_err(|_| ValidationError::InvalidIvshmemPath)?;
         if metadata.len() < size {
@@ -2760,6 +2950,353 @@ impl IvshmemConfig {
     }
 }
 
+impl VirtioInputConfig {
+    pub const SYNTAX: &'static str = "virtio-input parameters \
+        \"name=<device_name>,serial=<device_serial>,pointer=relative|absolute|touch,\
+        iommu=on|off,id=<device_id>,pci_segment=<segment_id>\"";
+
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("name")
+            .add("serial")
+            .add("pointer")
+            .add("iommu")
+            .add("id")
+            .add("pci_segment");
+        parser.parse(input).map_err(Error::ParseInput)?;
+
+        let name = parser
+            .get("name")
+            .unwrap_or_else(default_virtioinputconfig_name);
+        let serial = parser.get("serial").unwrap_or_default();
+        let pointer = parser
+            .convert::<PointerMode>("pointer")
+            .map_err(Error::ParseInput)?
+            .unwrap_or_default();
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseInput)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let id = parser.get("id");
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseInput)?
+            .unwrap_or_default();
+
+        Ok(VirtioInputConfig {
+            name,
+            serial,
+            pointer,
+            iommu,
+            id,
+            pci_segment,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if let Some(platform_config) = vm_config.platform.as_ref() {
+            if self.pci_segment >= platform_config.num_pci_segments {
+                return Err(ValidationError::InvalidPciSegment(self.pci_segment));
+            }
+
+            if let Some(iommu_segments) = platform_config.iommu_segments.as_ref()
+                && iommu_segments.contains(&self.pci_segment)
+                && !self.iommu
+            {
+                return Err(ValidationError::OnIommuSegment(self.pci_segment));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "vnc")]
+impl VncConfig {
+    pub const SYNTAX: &'static str = "Built-in VNC server parameters \
+        \"socket=<unix_socket_path>,addr=<host:port>,scanout=<scanout_id>,fps=<frame_rate>,\
+        layout=us|de|fr|jp,max_clients=<client_count>,allow_remote=on|off\"\nListens on either \
+        a UNIX socket or a TCP address, the clients are not authenticated so only loopback TCP \
+        addresses are accepted unless allow_remote=on";
+
+    pub fn parse(vnc: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("socket")
+            .add("addr")
+            .add("scanout")
+            .add("fps")
+            .add("layout")
+            .add("max_clients")
+            .add("allow_remote");
+        parser.parse(vnc).map_err(Error::ParseVnc)?;
+
+        let socket = parser.get("socket").map(PathBuf::from);
+        let addr = parser
+            .convert::<std::net::SocketAddr>("addr")
+            .map_err(Error::ParseVnc)?;
+        match (&socket, &addr) {
+            (Some(_), Some(_)) => return Err(Error::ParseVncSocketWithAddr),
+            (None, None) => return Err(Error::ParseVncListenerMissing),
+            _ => {}
+        }
+        let scanout = parser
+            .convert("scanout")
+            .map_err(Error::ParseVnc)?
+            .unwrap_or_default();
+        let fps = parser
+            .convert("fps")
+            .map_err(Error::ParseVnc)?
+            .unwrap_or_else(default_vncconfig_fps);
+        let layout = parser
+            .get("layout")
+            .map(|name| {
+                crate::input::KeyboardLayout::from_name(&name).ok_or_else(|| {
+                    Error::ParseVnc(OptionParserError::Conversion("layout".to_owned(), name))
+                })
+            })
+            .transpose()?
+            .unwrap_or_default();
+        let max_clients = parser
+            .convert("max_clients")
+            .map_err(Error::ParseVnc)?
+            .unwrap_or_else(default_vncconfig_max_clients);
+        let allow_remote = parser
+            .convert::<Toggle>("allow_remote")
+            .map_err(Error::ParseVnc)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        Ok(VncConfig {
+            socket,
+            addr,
+            scanout,
+            fps,
+            layout,
+            max_clients,
+            allow_remote,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.fps == 0 || self.fps > MAX_VNC_FPS {
+            return Err(ValidationError::InvalidVncFrameRate(self.fps));
+        }
+        if self.scanout >= VIRTIO_GPU_MAX_SCANOUTS {
+            return Err(ValidationError::InvalidVncScanout(self.scanout));
+        }
+        if self.max_clients == 0 {
+            return Err(ValidationError::InvalidVncMaxClients);
+        }
+        if let Some(addr) = self.addr
+            && !addr.ip().is_loopback()
+            && !self.allow_remote
+        {
+            return Err(ValidationError::VncRemoteAddress(addr));
+        }
+
+        Ok(())
+    }
+}
+
+impl UsbConfig {
+    pub const SYNTAX: &'static str = "USB (xHCI) controller parameters \
+        \"keyboard=on|off,mouse=on|off,tablet=on|off,pci_segment=<segment_id>\"";
+
+    pub fn parse(usb: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("keyboard")
+            .add("mouse")
+            .add("tablet")
+            .add("pci_segment");
+        parser.parse(usb).map_err(Error::ParseUsb)?;
+
+        let keyboard = parser
+            .convert::<Toggle>("keyboard")
+            .map_err(Error::ParseUsb)?
+            .unwrap_or(Toggle(default_usbconfig_true()))
+            .0;
+        let mouse = parser
+            .convert::<Toggle>("mouse")
+            .map_err(Error::ParseUsb)?
+            .unwrap_or(Toggle(default_usbconfig_true()))
+            .0;
+        let tablet = parser
+            .convert::<Toggle>("tablet")
+            .map_err(Error::ParseUsb)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseUsb)?
+            .unwrap_or_default();
+
+        Ok(UsbConfig {
+            keyboard,
+            mouse,
+            tablet,
+            pci_segment,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if let Some(platform_config) = vm_config.platform.as_ref()
+            && self.pci_segment >= platform_config.num_pci_segments
+        {
+            return Err(ValidationError::InvalidPciSegment(self.pci_segment));
+        }
+
+        Ok(())
+    }
+}
+
+impl GpuConfig {
+    pub const SYNTAX: &'static str = "virtio-gpu parameters \
+        \"width=<display_width>,height=<display_height>,max_outputs=<number_of_scanouts>,\
+        outputs=[<width>x<height>,...],edid=on|off,virgl=on|off,blob=on|off,hostmem=<host_visible_region_size>,\
+        id=<device_id>,pci_segment=<segment_id>\"";
+
+    pub fn parse(gpu: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("width")
+            .add("height")
+            .add("max_outputs")
+            .add("outputs")
+            .add("edid")
+            .add("virgl")
+            .add("blob")
+            .add("hostmem")
+            .add("id")
+            .add("pci_segment");
+        parser.parse(gpu).map_err(Error::ParseGpu)?;
+
+        let width = parser
+            .convert("width")
+            .map_err(Error::ParseGpu)?
+            .unwrap_or_else(default_gpuconfig_width);
+        let height = parser
+            .convert("height")
+            .map_err(Error::ParseGpu)?
+            .unwrap_or_else(default_gpuconfig_height);
+        let max_outputs = parser
+            .convert("max_outputs")
+            .map_err(Error::ParseGpu)?
+            .unwrap_or_else(default_gpuconfig_max_outputs);
+        let outputs = parser
+            .convert::<StringList>("outputs")
+            .map_err(Error::ParseGpu)?
+            .map(|modes| {
+                modes
+                    .0
+                    .iter()
+                    .map(|mode| {
+                        mode.split_once('x')
+                            .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                            .map(|(width, height)| GpuOutputConfig { width, height })
+                            .ok_or_else(|| {
+                                Error::ParseGpu(OptionParserError::Conversion(
+                                    "outputs".to_owned(),
+                                    mode.clone(),
+                                ))
+                            })
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+        let edid = parser
+            .convert::<Toggle>("edid")
+            .map_err(Error::ParseGpu)?
+            .unwrap_or(Toggle(default_gpuconfig_edid()))
+            .0;
+        let virgl = parser
+            .convert::<Toggle>("virgl")
+            .map_err(Error::ParseGpu)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let blob = parser
+            .convert::<Toggle>("blob")
+            .map_err(Error::ParseGpu)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let hostmem = parser
+            .convert::<ByteSized>("hostmem")
+            .map_err(Error::ParseGpu)?
+            .map(|v| v.0)
+            .unwrap_or_default();
+        let id = parser.get("id");
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseGpu)?
+            .unwrap_or_default();
+
+        Ok(GpuConfig {
+            width,
+            height,
+            max_outputs,
+            edid,
+            outputs,
+            virgl,
+            blob,
+            hostmem,
+            id,
+            pci_segment,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(ValidationError::InvalidGpuResolution(self.width, self.height));
+        }
+
+        if self.max_outputs == 0 || self.max_outputs > VIRTIO_GPU_MAX_SCANOUTS {
+            return Err(ValidationError::InvalidGpuMaxOutputs(
+                self.max_outputs,
+                VIRTIO_GPU_MAX_SCANOUTS,
+            ));
+        }
+
+        if let Some(outputs) = &self.outputs {
+            if outputs.len() > self.max_outputs as usize {
+                return Err(ValidationError::TooManyGpuOutputs(
+                    outputs.len(),
+                    self.max_outputs,
+                ));
+            }
+            if let Some(output) = outputs.iter().find(|o| o.width == 0 || o.height == 0) {
+                return Err(ValidationError::InvalidGpuResolution(
+                    output.width,
+                    output.height,
+                ));
+            }
+        }
+
+        if self.virgl && !cfg!(feature = "virgl") {
+            return Err(ValidationError::GpuVirglNotSupported);
+        }
+
+        if self.hostmem != 0 {
+            if !self.blob {
+                return Err(ValidationError::GpuHostmemWithoutBlob);
+            }
+            if !self.hostmem.is_power_of_two() {
+                return Err(ValidationError::InvalidGpuHostmem(self.hostmem));
+            }
+        }
+
+        if let Some(platform_config) = vm_config.platform.as_ref()
+            && self.pci_segment >= platform_config.num_pci_segments
+        {
+            return Err(ValidationError::InvalidPciSegment(self.pci_segment));
+        }
+
+        Ok(())
+    }
+}
+
 impl VmConfig {
     fn validate_identifier(
         id_list: &mut BTreeSet<String>,
@@ -3143,6 +3680,28 @@ impl VmConfig {
             ivshmem_config.validate()?;
         }
 
+        if let Some(usb_config) = &self.usb {
+            usb_config.validate(self)?;
+        }
+
+        if let Some(gpu_config) = &self.gpu {
+            gpu_config.validate(self)?;
+
+            Self::validate_identifier(&mut id_list, &gpu_config.id)?;
+        }
+
+        if let Some(input_config) = &self.input {
+            input_config.validate(self)?;
+            self.iommu |= input_config.iommu;
+
+            Self::validate_identifier(&mut id_list, &input_config.id)?;
+        }
+
+        #[cfg(feature = "vnc")]
+        if let Some(vnc_config) = &self.vnc {
+            vnc_config.validate()?;
+        }
+
         Ok(id_list)
     }
 
@@ -3341,6 +3900,28 @@ impl VmConfig {
             ivshmem = Some(ivshmem_conf);
         }
 
+        let mut usb: Option<UsbConfig> = None;
+        if let Some(u) = vm_params.usb {
+            usb = Some(UsbConfig::parse(u)?);
+        }
+
+        let mut gpu: Option<GpuConfig> = None;
+        if let Some(g) = vm_params.gpu {
+            gpu = Some(GpuConfig::parse(g)?);
+        }
+
+        let mut input: Option<VirtioInputConfig> = None;
+        if let Some(i) = vm_params.input {
+            input = Some(VirtioInputConfig::parse(i)?);
+        }
+
+        #[cfg(feature = "vnc")]
+        let mut vnc: Option<VncConfig> = None;
+        #[cfg(feature = "vnc")]
+        if let Some(v) = vm_params.vnc {
+            vnc = Some(VncConfig::parse(v)?);
+        }
+
         let mut config = VmConfig {
             cpus: CpusConfig::parse(vm_params.cpus)?,
             memory: MemoryConfig::parse(vm_params.memory, vm_params.memory_zones)?,
@@ -3377,6 +3958,11 @@ impl VmConfig {
             landlock_rules,
             #[cfg(feature = "ivshmem")]
             ivshmem,
+            usb,
+            gpu,
+            input,
+            #[cfg(feature = "vnc")]
+            vnc,
         };
         config.validate().map_err(Error::Validation)?;
         Ok(config)
@@ -3449,6 +4035,22 @@ impl VmConfig {
             removed = true;
         }
 
+        // Remove if GPU device
+        if let Some(gpu) = self.gpu.as_ref()
+            && gpu.id.as_ref().map(|id| id.as_ref()) == Some(id)
+        {
+            self.gpu = None;
+            removed = true;
+        }
+
+        // Remove if virtio-input device
+        if let Some(input) = self.input.as_ref()
+            && input.id.as_ref().map(|id| id.as_ref()) == Some(id)
+        {
+            self.input = None;
+            removed = true;
+        }
+
         removed
     }
 
@@ -3514,6 +4116,11 @@ impl Clone for VmConfig {
             landlock_rules: self.landlock_rules.clone(),
             #[cfg(feature = "ivshmem")]
             ivshmem: self.ivshmem.clone(),
+            usb: self.usb.clone(),
+            gpu: self.gpu.clone(),
+            input: self.input.clone(),
+            #[cfg(feature = "vnc")]
+            vnc: self.vnc.clone(),
             ..*self
         }
     }
@@ -3801,6 +4408,9 @@ mod unit_tests {
             backing_files: false,
             sparse: true,
             image_type: ImageType::Unknown,
+            dirty_bitmap: false,
+            key_file: None,
+            key_secret: None,
         }
     }
 
@@ -3858,6 +4468,36 @@ mod unit_tests {
                 ..disk_fixture()
             }
         );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,dirty_bitmap=on")?,
+            DiskConfig {
+                dirty_bitmap: true,
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,image_type=luks,key_file=/path/to_key")?,
+            DiskConfig {
+                image_type: ImageType::Luks,
+                key_file: Some(PathBuf::from("/path/to_key")),
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,image_type=dynamic-vhd,backing_files=on")?,
+            DiskConfig {
+                image_type: ImageType::DynamicVhd,
+                backing_files: true,
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,key_secret=secret")?,
+            DiskConfig {
+                key_secret: Some(String::from("secret")),
+                ..disk_fixture()
+            }
+        );
         assert_eq!(
             DiskConfig::parse("path=/path/to_file,serial=test")?,
             DiskConfig {
@@ -4336,6 +4976,217 @@ id=\"{id}\",pci_segment={pci_segment},queue_sizes={queue_sizes}"
         Ok(())
     }
 
+    #[test]
+    fn test_usb_parsing() -> Result<()> {
+        // keyboard and mouse default to on
+        assert_eq!(UsbConfig::parse("")?, UsbConfig::default());
+        assert_eq!(
+            UsbConfig::parse("mouse=off,pci_segment=1")?,
+            UsbConfig {
+                keyboard: true,
+                mouse: false,
+                tablet: false,
+                pci_segment: 1,
+            }
+        );
+        assert!(UsbConfig::parse("tablet=on")?.tablet);
+        UsbConfig::parse("keyboard=maybe").unwrap_err();
+        Ok(())
+    }
+
+    #[cfg(feature = "vnc")]
+    #[test]
+    fn test_vnc_parsing() -> Result<()> {
+        use crate::input::KeyboardLayout;
+
+        assert_eq!(
+            VncConfig::parse("socket=/tmp/vnc.sock")?,
+            VncConfig {
+                socket: Some(PathBuf::from("/tmp/vnc.sock")),
+                addr: None,
+                scanout: 0,
+                fps: DEFAULT_VNC_FPS,
+                layout: KeyboardLayout::Us,
+                max_clients: DEFAULT_VNC_MAX_CLIENTS,
+                allow_remote: false,
+            }
+        );
+        assert_eq!(
+            VncConfig::parse("addr=127.0.0.1:5900,scanout=1,fps=10,layout=de,max_clients=1")?,
+            VncConfig {
+                socket: None,
+                addr: Some("127.0.0.1:5900".parse().unwrap()),
+                scanout: 1,
+                fps: 10,
+                layout: KeyboardLayout::De,
+                max_clients: 1,
+                allow_remote: false,
+            }
+        );
+        assert!(matches!(
+            VncConfig::parse("fps=10"),
+            Err(Error::ParseVncListenerMissing)
+        ));
+        assert!(matches!(
+            VncConfig::parse("socket=/tmp/vnc.sock,addr=[::1]:5900"),
+            Err(Error::ParseVncSocketWithAddr)
+        ));
+        VncConfig::parse("addr=localhost").unwrap_err();
+        VncConfig::parse("socket=/tmp/vnc.sock,layout=dvorak").unwrap_err();
+
+        let config = VncConfig::parse("socket=/tmp/vnc.sock,fps=0")?;
+        assert!(matches!(
+            config.validate(),
+            Err(ValidationError::InvalidVncFrameRate(0))
+        ));
+        let config = VncConfig::parse("socket=/tmp/vnc.sock,scanout=16")?;
+        assert!(matches!(
+            config.validate(),
+            Err(ValidationError::InvalidVncScanout(16))
+        ));
+        let config = VncConfig::parse("socket=/tmp/vnc.sock,max_clients=0")?;
+        assert!(matches!(
+            config.validate(),
+            Err(ValidationError::InvalidVncMaxClients)
+        ));
+        let config = VncConfig::parse("addr=0.0.0.0:5900")?;
+        assert!(matches!(
+            config.validate(),
+            Err(ValidationError::VncRemoteAddress(_))
+        ));
+        VncConfig::parse("addr=[::1]:5900")?.validate().unwrap();
+        VncConfig::parse("addr=0.0.0.0:5900,allow_remote=on")?
+            .validate()
+            .unwrap();
+        Ok(())
+    }
+
+    #[cfg(feature = "ivshmem")]
+    #[test]
+    fn test_ivshmem_parsing() -> Result<()> {
+        assert_eq!(
+            IvshmemConfig::parse("path=/tmp/ivshmem.data,size=1M")?,
+            IvshmemConfig {
+                path: PathBuf::from("/tmp/ivshmem.data"),
+                size: 1 << 20,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            IvshmemConfig::parse("server=/tmp/ivshmem_socket,vectors=4,size=4M")?,
+            IvshmemConfig {
+                size: 4 << 20,
+                server: Some(PathBuf::from("/tmp/ivshmem_socket")),
+                vectors: 4,
+                ..Default::default()
+            }
+        );
+        assert!(matches!(
+            IvshmemConfig::parse("size=1M"),
+            Err(Error::ParseIvshmemPathMissing)
+        ));
+        assert!(matches!(
+            IvshmemConfig::parse("path=/tmp/ivshmem.data,server=/tmp/ivshmem_socket"),
+            Err(Error::ParseIvshmemPathWithServer)
+        ));
+        assert_eq!(
+            IvshmemConfig::parse(
+                "path=/tmp/ivshmem.data,size=64M,frame_buffer=1280x720:NV12:4,\
+                 frame_notify=/tmp/frames.sock"
+            )?,
+            IvshmemConfig {
+                path: PathBuf::from("/tmp/ivshmem.data"),
+                size: 64 << 20,
+                frame_buffer: Some(FrameBufferConfig {
+                    width: 1280,
+                    height: 720,
+                    format: "NV12".to_string(),
+                    buffer_count: 4,
+                    notify_socket: Some(PathBuf::from("/tmp/frames.sock")),
+                }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            IvshmemConfig::parse("path=/tmp/ivshmem.data,frame_buffer=1920x1080:BGRA32")?
+                .frame_buffer,
+            Some(FrameBufferConfig::default())
+        );
+        assert!(matches!(
+            IvshmemConfig::parse("path=/tmp/ivshmem.data,frame_buffer=1920x1080:YUY2"),
+            Err(Error::ParseIvshmemFrameBuffer)
+        ));
+        assert!(matches!(
+            IvshmemConfig::parse("path=/tmp/ivshmem.data,frame_notify=/tmp/frames.sock"),
+            Err(Error::ParseIvshmemFrameNotifyWithoutFrameBuffer)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gpu_parsing() -> Result<()> {
+        assert_eq!(GpuConfig::parse("")?, GpuConfig::default());
+        assert_eq!(
+            GpuConfig::parse("width=1920,height=1080,max_outputs=2,edid=off,id=mygpu0")?,
+            GpuConfig {
+                width: 1920,
+                height: 1080,
+                max_outputs: 2,
+                edid: false,
+                outputs: None,
+                virgl: false,
+                blob: false,
+                hostmem: 0,
+                id: Some("mygpu0".to_owned()),
+                pci_segment: 0,
+            }
+        );
+        assert!(GpuConfig::parse("virgl=on")?.virgl);
+        assert_eq!(
+            GpuConfig::parse("max_outputs=2,outputs=[1920x1080,1280x720]")?.outputs,
+            Some(vec![
+                GpuOutputConfig {
+                    width: 1920,
+                    height: 1080,
+                },
+                GpuOutputConfig {
+                    width: 1280,
+                    height: 720,
+                },
+            ])
+        );
+        GpuConfig::parse("outputs=[1920]").unwrap_err();
+        let gpu = GpuConfig::parse("blob=on,hostmem=256M")?;
+        assert!(gpu.blob);
+        assert_eq!(gpu.hostmem, 256 << 20);
+        GpuConfig::parse("width=wide").unwrap_err();
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_parsing() -> Result<()> {
+        assert_eq!(VirtioInputConfig::parse("")?, VirtioInputConfig::default());
+        assert_eq!(
+            VirtioInputConfig::parse("name=keyboard,serial=0001,id=myinput0")?,
+            VirtioInputConfig {
+                name: "keyboard".to_owned(),
+                serial: "0001".to_owned(),
+                pointer: PointerMode::Relative,
+                iommu: false,
+                id: Some("myinput0".to_owned()),
+                pci_segment: 0,
+            }
+        );
+        assert!(VirtioInputConfig::parse("iommu=on")?.iommu);
+        VirtioInputConfig::parse("iommu=maybe").unwrap_err();
+        assert_eq!(
+            VirtioInputConfig::parse("pointer=touch")?.pointer,
+            PointerMode::Touch
+        );
+        VirtioInputConfig::parse("pointer=pen").unwrap_err();
+        Ok(())
+    }
+
     #[test]
     fn test_vsock_parsing() -> Result<()> {
         // socket and cid is required
@@ -4571,6 +5422,11 @@ id=\"{id}\",pci_segment={pci_segment},queue_sizes={queue_sizes}"
             landlock_rules: None,
             #[cfg(feature = "ivshmem")]
             ivshmem: None,
+            usb: None,
+            gpu: None,
+            input: None,
+            #[cfg(feature = "vnc")]
+            vnc: None,
         };
 
         let valid_config = RestoreConfig {
@@ -4766,6 +5622,11 @@ id=\"{id}\",pci_segment={pci_segment},queue_sizes={queue_sizes}"
             landlock_rules: None,
             #[cfg(feature = "ivshmem")]
             ivshmem: None,
+            usb: None,
+            gpu: None,
+            input: None,
+            #[cfg(feature = "vnc")]
+            vnc: None,
         };
 
         valid_config.validate().unwrap();
@@ -4859,6 +5720,42 @@ id=\"{id}\",pci_segment={pci_segment},queue_sizes={queue_sizes}"
         still_valid_config.memory.shared = true;
         still_valid_config.validate().unwrap();
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            readonly: true,
+            dirty_bitmap: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DirtyBitmapNotSupported)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            key_file: Some(PathBuf::from("/path/to_key")),
+            key_secret: Some(String::from("secret")),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskKeyConflict)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            key_secret: Some(String::from("secret")),
+            ..disk_fixture()
+        }]);
+        invalid_config.memory.shared = true;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskKeyNotSupported)
+        );
+
         let mut invalid_config = valid_config.clone();
         invalid_config.net = Some(vec![NetConfig {
             vhost_user: true,
@@ -5282,6 +6179,78 @@ id=\"{id}\",pci_segment={pci_segment},queue_sizes={queue_sizes}"
             Err(ValidationError::InvalidRateLimiterGroup)
         );
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.gpu = Some(GpuConfig {
+            max_outputs: 17,
+            ..Default::default()
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidGpuMaxOutputs(17, 16))
+        );
+        invalid_config.gpu = Some(GpuConfig {
+            width: 0,
+            ..Default::default()
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidGpuResolution(0, DEFAULT_GPU_HEIGHT))
+        );
+        #[cfg(not(feature = "virgl"))]
+        {
+            invalid_config.gpu = Some(GpuConfig {
+                virgl: true,
+                ..Default::default()
+            });
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::GpuVirglNotSupported)
+            );
+        }
+        invalid_config.gpu = Some(GpuConfig {
+            outputs: Some(vec![
+                GpuOutputConfig {
+                    width: 1920,
+                    height: 1080,
+                };
+                2
+            ]),
+            ..Default::default()
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::TooManyGpuOutputs(2, 1))
+        );
+        invalid_config.gpu = Some(GpuConfig {
+            max_outputs: 2,
+            outputs: Some(vec![GpuOutputConfig {
+                width: 1920,
+                height: 0,
+            }]),
+            ..Default::default()
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidGpuResolution(1920, 0))
+        );
+        invalid_config.gpu = Some(GpuConfig {
+            hostmem: 1 << 28,
+            ..Default::default()
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::GpuHostmemWithoutBlob)
+        );
+        invalid_config.gpu = Some(GpuConfig {
+            blob: true,
+            hostmem: 3 << 20,
+            ..Default::default()
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidGpuHostmem(3 << 20))
+        );
+
         // Test serial length validation
         let mut valid_serial_config = valid_config.clone();
         valid_serial_config.disks = Some(vec![DiskConfig {
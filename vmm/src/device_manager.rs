@@ -15,9 +15,7 @@ use std::io::{self, IsTerminal, Seek, SeekFrom, stdout};
 use std::num::Wrapping;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd};
-#[cfg(not(target_arch = "riscv64"))]
-use std::path::Path;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::{Arc, Mutex};
 #[cfg(not(target_arch = "riscv64"))]
@@ -32,18 +30,23 @@ use arch::layout::{APIC_START, IOAPIC_SIZE, IOAPIC_START};
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 use arch::{DeviceType, MmioDeviceInfo};
 use arch::{NumaNodes, layout};
-use block::async_io::DiskFile;
+use block::async_io::{DiskFile, DiskSnapshot};
+use block::dynamic_vhd_sync::DynamicVhdDiskSync;
 use block::fixed_vhd_sync::FixedVhdDiskSync;
+use block::luks::read_key_file;
+use block::luks_sync::LuksDiskSync;
 use block::qcow_sync::QcowDiskSync;
 use block::raw_async_aio::RawFileDiskAio;
 use block::raw_sync::RawFileDiskSync;
 use block::vhdx_sync::VhdxDiskSync;
 use block::{
-    ImageType, block_aio_is_supported, block_io_uring_is_supported, detect_image_type,
-    preallocate_disk, qcow, vhdx,
+    ImageType, block_aio_is_supported, block_io_uring_is_supported, detect_image_type, dynamic_vhd,
+    luks, preallocate_disk, qcow, vhdx,
 };
 #[cfg(feature = "io_uring")]
-use block::{fixed_vhd_async::FixedVhdDiskAsync, raw_async::RawFileDisk};
+use block::{
+    fixed_vhd_async::FixedVhdDiskAsync, qcow_async::QcowDiskAsync, raw_async::RawFileDisk,
+};
 #[cfg(target_arch = "riscv64")]
 use devices::aia;
 #[cfg(target_arch = "x86_64")]
@@ -56,7 +59,9 @@ use devices::interrupt_controller::InterruptController;
 #[cfg(target_arch = "x86_64")]
 use devices::ioapic;
 #[cfg(feature = "ivshmem")]
-use devices::ivshmem::{IvshmemError, IvshmemOps};
+use devices::ivshmem::{IvshmemDoorbellConfig, IvshmemError, IvshmemOps};
+#[cfg(feature = "ivshmem")]
+use devices::ivshmem_client::{IvshmemClient, IvshmemClientError};
 #[cfg(feature = "ivshmem")]
 use devices::{
     FrameBufferHeader, FrameBufferLayout, FrameFormat,
@@ -80,8 +85,8 @@ use hypervisor::IoEventAddress;
 #[cfg(target_arch = "aarch64")]
 use hypervisor::arch::aarch64::regs::AARCH64_PMU_IRQ;
 use libc::{
-    MAP_NORESERVE, MAP_PRIVATE, MAP_SHARED, O_TMPFILE, PROT_READ, PROT_WRITE, TCSANOW, tcsetattr,
-    termios,
+    MAP_ANONYMOUS, MAP_NORESERVE, MAP_PRIVATE, MAP_SHARED, O_TMPFILE, PROT_READ, PROT_WRITE,
+    TCSANOW, tcsetattr, termios,
 };
 use log::{debug, error, info, warn};
 use pci::{
@@ -106,7 +111,6 @@ use vm_device::interrupt::{
     InterruptIndex, InterruptManager, LegacyIrqGroupConfig, MsiIrqGroupConfig,
 };
 use vm_device::{Bus, BusDevice, BusDeviceSync, Resource, UserspaceMapping};
-#[cfg(feature = "ivshmem")]
 use vm_memory::bitmap::AtomicBitmap;
 use vm_memory::guest_memory::FileOffset;
 use vm_memory::{Address, GuestAddress, GuestMemoryRegion, GuestUsize, MmapRegion, VolatileMemory};
@@ -123,6 +127,8 @@ use vmm_sys_util::eventfd::EventFd;
 use crate::console_devices::{ConsoleDeviceError, ConsoleInfo, ConsoleOutput};
 use crate::cpu::{CPU_MANAGER_ACPI_SIZE, CpuManager};
 use crate::device_tree::{DeviceNode, DeviceTree};
+use crate::disk_backup::{DiskBackupError, DiskBackupJob, DiskBackupStatus, PersistentDirtyBitmap};
+use crate::disk_commit::{DiskCommitError, DiskCommitJob, DiskCommitStatus};
 use crate::interrupt::{LegacyUserspaceInterruptManager, MsiInterruptManager};
 use crate::memory_manager::{Error as MemoryManagerError, MEMORY_MANAGER_ACPI_SIZE, MemoryManager};
 use crate::pci_segment::PciSegment;
@@ -131,8 +137,8 @@ use crate::serial_manager::{Error as SerialManagerError, SerialManager};
 use crate::vm_config::IvshmemConfig;
 use crate::vm_config::{
     ConsoleOutputMode, DEFAULT_IOMMU_ADDRESS_WIDTH_BITS, DEFAULT_PCI_SEGMENT_APERTURE_WEIGHT,
-    DeviceConfig, DiskConfig, FsConfig, GenericVhostUserConfig, NetConfig, PmemConfig,
-    UserDeviceConfig, VdpaConfig, VhostMode, VmConfig, VsockConfig,
+    DeviceConfig, DiskConfig, FsConfig, GenericVhostUserConfig, GpuConfig, NetConfig, PmemConfig,
+    UsbConfig, UserDeviceConfig, VdpaConfig, VhostMode, VirtioInputConfig, VmConfig, VsockConfig,
 };
 use crate::{DEVICE_MANAGER_SNAPSHOT_ID, GuestRegionMmap, PciDeviceInfo, device_node};
 
@@ -156,6 +162,7 @@ const CONSOLE_DEVICE_NAME: &str = "__console";
 const PVPANIC_DEVICE_NAME: &str = "__pvpanic";
 #[cfg(feature = "ivshmem")]
 const IVSHMEM_DEVICE_NAME: &str = "__ivshmem";
+const USB_DEVICE_NAME: &str = "__usb";
 
 // Devices that the user may name and for which we generate
 // identifiers if the user doesn't give one
@@ -166,6 +173,8 @@ const GENERIC_VHOST_USER_DEVICE_NAME_PREFIX: &str = "_generic_vhost_user";
 const PMEM_DEVICE_NAME_PREFIX: &str = "_pmem";
 const VDPA_DEVICE_NAME_PREFIX: &str = "_vdpa";
 const VSOCK_DEVICE_NAME_PREFIX: &str = "_vsock";
+const GPU_DEVICE_NAME_PREFIX: &str = "_gpu";
+const INPUT_DEVICE_NAME_PREFIX: &str = "_input";
 const WATCHDOG_DEVICE_NAME: &str = "__watchdog";
 const VFIO_DEVICE_NAME_PREFIX: &str = "_vfio";
 const VFIO_USER_DEVICE_NAME_PREFIX: &str = "_vfio_user";
@@ -234,6 +243,14 @@ pub enum DeviceManagerError {
     #[error("Cannot create virtio-vsock device")]
     CreateVirtioVsock(#[source] io::Error),
 
+    /// Cannot create virtio-gpu device
+    #[error("Cannot create virtio-gpu device")]
+    CreateVirtioGpu(#[source] io::Error),
+
+    /// Cannot create virtio-input device
+    #[error("Cannot create virtio-input device")]
+    CreateVirtioInput(#[source] io::Error),
+
     /// Cannot create tpm device
     #[error("Cannot create tmp device")]
     CreateTpmDevice(#[source] anyhow::Error),
@@ -331,6 +348,18 @@ pub enum DeviceManagerError {
     #[error("Cannot find a memory range for persistent memory")]
     PmemRangeAllocation,
 
+    /// No virtio-gpu device
+    #[error("No virtio-gpu device")]
+    NoGpuDevice,
+
+    /// Failed to change a virtio-gpu display
+    #[error("Failed to change a virtio-gpu display")]
+    GpuDisplayChange(#[source] io::Error),
+
+    /// Cannot find a memory range for the virtio-gpu host-visible region
+    #[error("Cannot find a memory range for the virtio-gpu host-visible region")]
+    GpuHostVisibleRangeAllocation,
+
     /// Cannot find a memory range for virtio-fs
     #[error("Cannot find a memory range for virtio-fs")]
     FsRangeAllocation,
@@ -581,14 +610,38 @@ pub enum DeviceManagerError {
     #[error("Failed to create FixedVhdDiskSync")]
     CreateFixedVhdDiskSync(#[source] io::Error),
 
+    /// Failed to create QcowDiskAsync
+    #[error("Failed to create QcowDiskAsync")]
+    CreateQcowDiskAsync(#[source] qcow::Error),
+
     /// Failed to create QcowDiskSync
     #[error("Failed to create QcowDiskSync")]
     CreateQcowDiskSync(#[source] qcow::Error),
 
+    /// Failed to unlock an encrypted QCOW2 disk
+    #[error("Failed to unlock the encrypted QCOW2 disk")]
+    UnlockQcowDisk(#[source] qcow::Error),
+
+    /// Failed to create LuksDiskSync
+    #[error("Failed to create LuksDiskSync")]
+    CreateLuksDiskSync(#[source] luks::LuksError),
+
+    /// Failed to read the key of an encrypted disk
+    #[error("Failed to read the key of the encrypted disk")]
+    ReadDiskKey(#[source] luks::LuksError),
+
+    /// Encrypted disk without a key
+    #[error("The disk is encrypted but neither key_file nor key_secret is set")]
+    MissingDiskKey,
+
     /// Failed to create FixedVhdxDiskSync
     #[error("Failed to create FixedVhdxDiskSync")]
     CreateFixedVhdxDiskSync(#[source] vhdx::VhdxError),
 
+    /// Failed to create DynamicVhdDiskSync
+    #[error("Failed to create DynamicVhdDiskSync")]
+    CreateDynamicVhdDiskSync(#[source] dynamic_vhd::DynamicVhdError),
+
     /// Failed to add DMA mapping handler to virtio-mem device.
     #[error("Failed to add DMA mapping handler to virtio-mem device")]
     AddDmaMappingHandlerVirtioMem(#[source] virtio_devices::mem::Error),
@@ -662,6 +715,34 @@ pub enum DeviceManagerError {
     #[error("Cannot create a ivshmem device: {0}")]
     IvshmemCreate(IvshmemError),
 
+    #[cfg(feature = "ivshmem")]
+    /// Cannot register with the ivshmem server
+    #[error("Cannot register with the ivshmem server")]
+    IvshmemServer(#[source] IvshmemClientError),
+
+    #[cfg(feature = "ivshmem")]
+    /// The ivshmem server shared memory is too small
+    #[error("The ivshmem server shared memory is too small: {0} bytes")]
+    IvshmemServerShmSize(u64),
+
+    #[cfg(feature = "ivshmem")]
+    /// Cannot stream the frame ready notifications
+    #[error("Cannot stream the frame ready notifications")]
+    FrameNotifier(#[source] devices::FrameNotifierError),
+
+    /// No ivshmem frame buffer
+    #[error("No ivshmem frame buffer")]
+    NoFrameBuffer,
+
+    #[cfg(feature = "ivshmem")]
+    /// Cannot record the frame buffer
+    #[error("Cannot record the frame buffer")]
+    FrameRecorder(#[source] crate::frame_recorder::FrameRecorderError),
+
+    /// Cannot create a USB (xHCI) controller
+    #[error("Cannot create a USB controller")]
+    UsbCreate(#[source] devices::usb::XhciPciError),
+
     /// Cannot create a RateLimiterGroup
     #[error("Cannot create a RateLimiterGroup")]
     RateLimiterGroupCreate(#[source] rate_limiter::group::Error),
@@ -691,6 +772,62 @@ pub enum DeviceManagerError {
     #[error("Disk resize error")]
     DiskResize(#[source] virtio_devices::block::Error),
 
+    /// Disk internal snapshot operation failed.
+    #[error("Disk internal snapshot error")]
+    DiskSnapshot(#[source] virtio_devices::block::Error),
+
+    /// The disk can't be switched to an overlay.
+    #[error("Disk {0} doesn't support live snapshots")]
+    DiskLiveSnapshotUnsupported(String),
+
+    /// Cannot create the overlay of a disk.
+    #[error("Cannot create the disk overlay {0:?}")]
+    CreateDiskOverlay(PathBuf, #[source] io::Error),
+
+    /// Cannot create the qcow2 image of a disk overlay.
+    #[error("Cannot create the qcow2 image of the disk overlay")]
+    CreateDiskOverlayImage(#[source] qcow::Error),
+
+    /// Cannot flush a disk.
+    #[error("Cannot flush the disk")]
+    FlushDisk(#[source] block::async_io::AsyncIoError),
+
+    /// Disk live snapshot failed.
+    #[error("Disk live snapshot error")]
+    DiskLiveSnapshot(#[source] virtio_devices::block::Error),
+
+    /// Cannot commit the overlay of a disk.
+    #[error("Cannot commit the disk overlay")]
+    DiskCommit(#[source] DiskCommitError),
+
+    /// The overlay of a disk is being committed.
+    #[error("The overlay of disk {0} is being committed")]
+    DiskCommitInProgress(String),
+
+    /// No commit of the disk was started.
+    #[error("No commit of disk {0}")]
+    NoDiskCommit(String),
+
+    /// Cannot load or store the dirty bitmap of a disk.
+    #[error("Disk dirty bitmap error")]
+    DiskDirtyBitmap(#[source] DiskBackupError),
+
+    /// The disk tracks its dirty chunks, which an overlay would lose.
+    #[error("Disk {0} tracks its dirty chunks for backups")]
+    DiskDirtyBitmapInUse(String),
+
+    /// Cannot back up a disk.
+    #[error("Cannot back up the disk")]
+    DiskBackup(#[source] DiskBackupError),
+
+    /// A backup of the disk is going on.
+    #[error("Disk {0} is being backed up")]
+    DiskBackupInProgress(String),
+
+    /// No backup of the disk was started.
+    #[error("No backup of disk {0}")]
+    NoDiskBackup(String),
+
     /// Disk image type does not match expected type.
     #[error(
         "Disk image type does not match expected type: specified = {specified}, detected = {detected}"
@@ -1074,6 +1211,9 @@ pub struct DeviceManager {
     // Possible handle to the virtio-balloon device
     balloon: Option<Arc<Mutex<virtio_devices::Balloon>>>,
 
+    // Possible handle to the virtio-gpu device
+    gpu: Option<Arc<Mutex<virtio_devices::Gpu>>>,
+
     // Virtio Device activation EventFd to allow the VMM thread to trigger device
     // activation and thus start the threads from the VMM thread
     activate_evt: EventFd,
@@ -1146,9 +1286,36 @@ pub struct DeviceManager {
     // We wrap the raw pointer to implement Send
     frame_buffer_header_ptr: Option<FrameBufferHeaderPtr>,
 
+    #[cfg(feature = "ivshmem")]
+    // Shared memory region holding the frame buffer
+    frame_buffer_region: Option<Arc<MmapRegion<AtomicBitmap>>>,
+
+    #[cfg(feature = "ivshmem")]
+    // Streams the frames published in the frame buffer
+    frame_notifier: Option<devices::FrameNotifier>,
+
+    #[cfg(feature = "ivshmem")]
+    // Records the frame buffer to a file
+    frame_recorder: Option<crate::frame_recorder::FrameRecorder>,
+
     #[cfg(target_arch = "x86_64")]
     // i8042 device for PS/2 keyboard and mouse input injection
     i8042: Option<Arc<Mutex<devices::legacy::I8042Device>>>,
+
+    // xHCI controller with the USB HID keyboard and mouse
+    usb_device: Option<Arc<Mutex<devices::usb::XhciPciDevice>>>,
+
+    // virtio-input device for input injection
+    virtio_input: Option<Arc<Mutex<virtio_devices::VirtioInput>>>,
+
+    // Commits of disk overlays, by disk id
+    disk_commits: HashMap<String, DiskCommitJob>,
+
+    // Dirty bitmaps of the disks kept across runs for backups, by disk id
+    disk_dirty_bitmaps: HashMap<String, PersistentDirtyBitmap>,
+
+    // Backups of the disks, by disk id
+    disk_backups: HashMap<String, DiskBackupJob>,
 }
 
 /// Wrapper for frame buffer header pointer to implement Send
@@ -1186,6 +1353,157 @@ fn create_mmio_allocators(
     mmio_allocators
 }
 
+/// Opens the file of the disk image of `disk_cfg`, with the access and
+/// flags it's configured with.
+pub(crate) fn open_disk_file(disk_cfg: &DiskConfig) -> DeviceManagerResult<File> {
+    let mut options = OpenOptions::new();
+    options.read(true);
+    options.write(!disk_cfg.readonly);
+    if disk_cfg.direct {
+        options.custom_flags(libc::O_DIRECT);
+    }
+    options
+        .open(
+            disk_cfg
+                .path
+                .as_ref()
+                .ok_or(DeviceManagerError::NoDiskPath)?,
+        )
+        .map_err(DeviceManagerError::Disk)
+}
+
+/// Opens the disk image of `disk_cfg` from `file`, with the backend matching
+/// its image type and the asynchronous I/O supported by the host.
+pub(crate) fn open_disk_image(
+    file: File,
+    disk_cfg: &DiskConfig,
+    io_uring_supported: bool,
+    aio_supported: bool,
+) -> DeviceManagerResult<Box<dyn DiskFile>> {
+    let key_file = disk_cfg
+        .key_file
+        .as_deref()
+        .map(read_key_file)
+        .transpose()
+        .map_err(DeviceManagerError::ReadDiskKey)?;
+    let key = key_file
+        .as_ref()
+        .map(|key| key.as_slice())
+        .or_else(|| disk_cfg.key_secret.as_ref().map(|key| key.as_bytes()));
+
+    let image = match disk_cfg.image_type {
+        ImageType::DynamicVhd => {
+            info!("Using synchronous dynamic VHD disk file");
+            Box::new(
+                DynamicVhdDiskSync::new(file, disk_cfg.backing_files)
+                    .map_err(DeviceManagerError::CreateDynamicVhdDiskSync)?,
+            ) as Box<dyn DiskFile>
+        }
+        ImageType::FixedVhd => {
+            // Use asynchronous backend relying on io_uring if the
+            // syscalls are supported.
+            if cfg!(feature = "io_uring") && !disk_cfg.disable_io_uring && io_uring_supported {
+                info!("Using asynchronous fixed VHD disk file (io_uring)");
+
+                #[cfg(not(feature = "io_uring"))]
+                unreachable!("Checked in if statement above");
+                #[cfg(feature = "io_uring")]
+                {
+                    Box::new(
+                        FixedVhdDiskAsync::new(file)
+                            .map_err(DeviceManagerError::CreateFixedVhdDiskAsync)?,
+                    ) as Box<dyn DiskFile>
+                }
+            } else {
+                info!("Using synchronous fixed VHD disk file");
+                Box::new(
+                    FixedVhdDiskSync::new(file)
+                        .map_err(DeviceManagerError::CreateFixedVhdDiskSync)?,
+                ) as Box<dyn DiskFile>
+            }
+        }
+        ImageType::Raw => {
+            // Use asynchronous backend relying on io_uring if the
+            // syscalls are supported.
+            if cfg!(feature = "io_uring") && !disk_cfg.disable_io_uring && io_uring_supported {
+                info!("Using asynchronous RAW disk file (io_uring)");
+
+                #[cfg(not(feature = "io_uring"))]
+                unreachable!("Checked in if statement above");
+                #[cfg(feature = "io_uring")]
+                {
+                    Box::new(RawFileDisk::new(file)) as Box<dyn DiskFile>
+                }
+            } else if !disk_cfg.disable_aio && aio_supported {
+                info!("Using asynchronous RAW disk file (aio)");
+                Box::new(RawFileDiskAio::new(file)) as Box<dyn DiskFile>
+            } else {
+                info!("Using synchronous RAW disk file");
+                Box::new(RawFileDiskSync::new(file)) as Box<dyn DiskFile>
+            }
+        }
+        ImageType::Qcow2 => {
+            // Use asynchronous backend relying on io_uring if the
+            // syscalls are supported. Encrypted images are decrypted by the
+            // synchronous backend.
+            if cfg!(feature = "io_uring")
+                && !disk_cfg.disable_io_uring
+                && io_uring_supported
+                && key.is_none()
+            {
+                info!("Using asynchronous QCOW2 disk file (io_uring)");
+
+                #[cfg(not(feature = "io_uring"))]
+                unreachable!("Checked in if statement above");
+                #[cfg(feature = "io_uring")]
+                {
+                    Box::new(
+                        QcowDiskAsync::new(
+                            file,
+                            disk_cfg.direct,
+                            disk_cfg.backing_files,
+                            disk_cfg.sparse,
+                        )
+                        .map_err(DeviceManagerError::CreateQcowDiskAsync)?,
+                    ) as Box<dyn DiskFile>
+                }
+            } else {
+                info!("Using synchronous QCOW2 disk file");
+                let mut disk = QcowDiskSync::new(
+                    file,
+                    disk_cfg.direct,
+                    disk_cfg.backing_files,
+                    disk_cfg.sparse,
+                )
+                .map_err(DeviceManagerError::CreateQcowDiskSync)?;
+                if let Some(key) = key {
+                    disk.unlock(key)
+                        .map_err(DeviceManagerError::UnlockQcowDisk)?;
+                } else if disk.is_encrypted() {
+                    return Err(DeviceManagerError::MissingDiskKey);
+                }
+                Box::new(disk) as Box<dyn DiskFile>
+            }
+        }
+        ImageType::Luks => {
+            info!("Using synchronous LUKS disk file");
+            let key = key.ok_or(DeviceManagerError::MissingDiskKey)?;
+            Box::new(
+                LuksDiskSync::new(file, disk_cfg.direct, key)
+                    .map_err(DeviceManagerError::CreateLuksDiskSync)?,
+            ) as Box<dyn DiskFile>
+        }
+        ImageType::Vhdx => {
+            info!("Using synchronous VHDX disk file");
+            Box::new(VhdxDiskSync::new(file).map_err(DeviceManagerError::CreateFixedVhdxDiskSync)?)
+                as Box<dyn DiskFile>
+        }
+        ImageType::Unknown => unreachable!(),
+    };
+
+    Ok(image)
+}
+
 impl DeviceManager {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -1394,6 +1712,7 @@ impl DeviceManager {
             numa_nodes,
             device_id_to_bdf: HashMap::new(),
             balloon: None,
+            gpu: None,
             activate_evt: activate_evt
                 .try_clone()
                 .map_err(DeviceManagerError::EventFd)?,
@@ -1429,8 +1748,19 @@ impl DeviceManager {
             frame_buffer_layout: None,
             #[cfg(feature = "ivshmem")]
             frame_buffer_header_ptr: None,
+            #[cfg(feature = "ivshmem")]
+            frame_buffer_region: None,
+            #[cfg(feature = "ivshmem")]
+            frame_notifier: None,
+            #[cfg(feature = "ivshmem")]
+            frame_recorder: None,
             #[cfg(target_arch = "x86_64")]
             i8042: None,
+            usb_device: None,
+            virtio_input: None,
+            disk_commits: HashMap::new(),
+            disk_dirty_bitmaps: HashMap::new(),
+            disk_backups: HashMap::new(),
         };
 
         let device_manager = Arc::new(Mutex::new(device_manager));
@@ -1451,25 +1781,33 @@ impl DeviceManager {
         self.console_resize_pipe.clone()
     }
 
-    /// Inject keyboard event into the i8042 device (x86_64 only)
+    /// Get the i8042 controller used for PS/2 input injection (x86_64 only)
     #[cfg(target_arch = "x86_64")]
-    pub fn inject_keyboard(&mut self, event: &devices::legacy::KeyboardEvent) -> bool {
-        if let Some(ref i8042) = self.i8042 {
-            i8042.lock().unwrap().inject_keyboard(event.clone());
-            true
-        } else {
-            false
-        }
+    pub fn i8042_device(&self) -> Option<Arc<Mutex<devices::legacy::I8042Device>>> {
+        self.i8042.clone()
     }
 
-    /// Inject mouse event into the i8042 device (x86_64 only)
-    #[cfg(target_arch = "x86_64")]
-    pub fn inject_mouse(&mut self, event: &devices::legacy::MouseEvent) -> bool {
-        if let Some(ref i8042) = self.i8042 {
-            i8042.lock().unwrap().inject_mouse(event.clone());
-            true
-        } else {
-            false
+    /// Get the virtio-input device used for input injection
+    pub fn virtio_input_device(&self) -> Option<Arc<Mutex<virtio_devices::VirtioInput>>> {
+        self.virtio_input.clone()
+    }
+
+    /// Get the USB HID keyboard, mouse and tablet handles attached to the
+    /// xHCI controller
+    /// Returns: (keyboard, mouse, tablet)
+    pub fn usb_hid_devices(
+        &self,
+    ) -> (
+        Option<devices::usb::SharedUsbHidDevice>,
+        Option<devices::usb::SharedUsbHidDevice>,
+        Option<devices::usb::SharedUsbHidDevice>,
+    ) {
+        match self.usb_device {
+            Some(ref usb) => {
+                let usb = usb.lock().unwrap();
+                (usb.keyboard(), usb.mouse(), usb.tablet())
+            }
+            None => (None, None, None),
         }
     }
 
@@ -1499,6 +1837,221 @@ impl DeviceManager {
         ))
     }
 
+    /// Get information about the last frame flushed by the guest on the
+    /// virtio-gpu scanout `scanout_id`
+    /// Returns: (width, height, format, buffer_count, frame_number, active_index)
+    pub fn gpu_frame_info(&self, scanout_id: u32) -> Option<(u32, u32, String, u32, u64, u32)> {
+        let gpu = self.gpu.as_ref()?;
+        let (width, height, format, frame_number) =
+            gpu.lock().unwrap().scanout_frame_info(scanout_id)?;
+
+        Some((
+            width,
+            height,
+            virtio_devices::gpu::format_name(format).to_string(),
+            1,
+            frame_number,
+            scanout_id,
+        ))
+    }
+
+    /// Connect a display with the resolution `mode` to the virtio-gpu
+    /// scanout `scanout_id`, or disconnect it when `mode` is `None`
+    pub fn gpu_display_change(
+        &self,
+        scanout_id: u32,
+        mode: Option<(u32, u32)>,
+    ) -> DeviceManagerResult<()> {
+        self.gpu
+            .as_ref()
+            .ok_or(DeviceManagerError::NoGpuDevice)?
+            .lock()
+            .unwrap()
+            .set_display(scanout_id, mode)
+            .map_err(DeviceManagerError::GpuDisplayChange)
+    }
+
+    /// Resolution of the display connected to each virtio-gpu scanout
+    pub fn gpu_displays(&self) -> Option<Vec<Option<(u32, u32)>>> {
+        Some(self.gpu.as_ref()?.lock().unwrap().displays())
+    }
+
+    /// Get a copy of the last frame flushed by the guest on the virtio-gpu
+    /// scanout `scanout_id`
+    pub fn gpu_scanout_frame(&self, scanout_id: u32) -> Option<virtio_devices::ScanoutFrame> {
+        self.gpu.as_ref()?.lock().unwrap().scanout_frame(scanout_id)
+    }
+
+    /// Copy the last frame shown on the display `scanout_id`, the ivshmem
+    /// frame buffer standing for the first one
+    pub fn frame_snapshot(&self, scanout_id: u32) -> Option<crate::frame_export::Frame> {
+        #[cfg(feature = "ivshmem")]
+        if scanout_id == 0
+            && let Some(frame) = self.frame_buffer_snapshot()
+        {
+            return Some(frame);
+        }
+
+        // Blob scanouts are only copied here, out of the guest memory
+        let frame = self.gpu_scanout_frame(scanout_id)?;
+        let (width, height, stride, frame_number) =
+            (frame.width, frame.height, frame.stride, frame.frame_number);
+        let format = frame.format_name().to_string();
+        Some(crate::frame_export::Frame {
+            width,
+            height,
+            format,
+            stride,
+            data: frame.into_data()?,
+            frame_number,
+            timestamp_ns: 0,
+            buffer_index: 0,
+        })
+    }
+
+    /// Number of the last frame shown on the display `scanout_id`, without
+    /// copying it
+    pub fn frame_number(&self, scanout_id: u32) -> Option<u64> {
+        #[cfg(feature = "ivshmem")]
+        if scanout_id == 0
+            && let Some((_, _, _, _, frame_number, _)) = self.frame_buffer_info()
+            && frame_number > 0
+        {
+            return Some(frame_number);
+        }
+
+        let (_, _, _, _, frame_number, _) = self.gpu_frame_info(scanout_id)?;
+        Some(frame_number)
+    }
+
+    /// Copy the cursor image shown on the display `scanout_id`, `None` until
+    /// the guest sets one
+    pub fn cursor_image(&self, scanout_id: u32) -> Option<crate::frame_export::CursorImage> {
+        use devices::frame_buffer::CursorShapeInfo;
+
+        #[cfg(feature = "ivshmem")]
+        if scanout_id == 0
+            && let Some(cursor) = self.frame_buffer_cursor_image()
+        {
+            return Some(cursor);
+        }
+
+        let cursor = self.gpu.as_ref()?.lock().unwrap().cursor();
+        if cursor.update_count == 0 || cursor.scanout_id != scanout_id {
+            return None;
+        }
+
+        // Byte of the cursor data holding each of blue, green, red and alpha
+        let order = match virtio_devices::gpu::format_name(cursor.format) {
+            "BGRA32" => [0, 1, 2, 3],
+            "RGBA32" => [2, 1, 0, 3],
+            "ARGB32" => [3, 2, 1, 0],
+            "ABGR32" => [1, 2, 3, 0],
+            _ => return None,
+        };
+        let size = cursor.width as usize * cursor.height as usize * 4;
+        if cursor.data.len() < size {
+            return None;
+        }
+        let data: Vec<u8> = cursor.data[..size]
+            .chunks_exact(4)
+            .flat_map(|pixel| order.map(|offset| pixel[offset]))
+            .collect();
+
+        Some(crate::frame_export::CursorImage {
+            shape: CursorShapeInfo {
+                width: cursor.width.min(u16::MAX.into()) as u16,
+                height: cursor.height.min(u16::MAX.into()) as u16,
+                hot_x: cursor.hot_x.min(i16::MAX as u32) as i16,
+                hot_y: cursor.hot_y.min(i16::MAX as u32) as i16,
+                data_size: data.len() as u32,
+                reserved: [0u8; 20],
+            },
+            data,
+        })
+    }
+
+    /// Copy the cursor image published by the guest agent in the ivshmem
+    /// frame buffer
+    #[cfg(feature = "ivshmem")]
+    fn frame_buffer_cursor_image(&self) -> Option<crate::frame_export::CursorImage> {
+        let layout = self.frame_buffer_layout.as_ref()?;
+        let header_ptr = self.frame_buffer_header_ptr.as_ref()?.0;
+
+        // SAFETY: The header sits at the start of the region, which was
+        // checked to be large enough for the layout. The cursor data region
+        // of the layout is `CURSOR_DATA_SIZE` bytes long.
+        unsafe {
+            let header = &*header_ptr;
+            if !header.has_cursor_data() {
+                return None;
+            }
+            let mut shape = header.get_cursor_shape();
+            let size = (shape.width as usize * shape.height as usize * 4)
+                .min(shape.data_size as usize)
+                .min(FrameBufferLayout::CURSOR_DATA_SIZE);
+            let data = std::slice::from_raw_parts(
+                (header_ptr as *const u8).add(layout.cursor_data_offset),
+                size,
+            )
+            .to_vec();
+            shape.data_size = size as u32;
+
+            Some(crate::frame_export::CursorImage { shape, data })
+        }
+    }
+
+    /// Copy the last frame published by the guest agent in the ivshmem frame
+    /// buffer, `None` until the first one
+    #[cfg(feature = "ivshmem")]
+    pub fn frame_buffer_snapshot(&self) -> Option<crate::frame_export::Frame> {
+        let fb_cfg = self.frame_buffer_config.as_ref()?;
+        let layout = self.frame_buffer_layout.as_ref()?;
+        let header_ptr = self.frame_buffer_header_ptr.as_ref()?.0;
+
+        // SAFETY: The header sits at the start of the region, which was
+        // checked to be large enough for the layout
+        unsafe { crate::frame_recorder::read_frame(header_ptr as *const u8, layout, fb_cfg) }
+    }
+
+    /// Record the frames published in the ivshmem frame buffer to `path`,
+    /// stopping the ongoing recording if any
+    #[cfg(feature = "ivshmem")]
+    pub fn frame_capture_record(
+        &mut self,
+        path: &Path,
+        fps: u32,
+        container: crate::frame_export::VideoContainer,
+    ) -> DeviceManagerResult<()> {
+        use devices::frame_buffer::GuestCommand;
+
+        let (Some(fb_cfg), Some(layout), Some(region), Some(header_ptr)) = (
+            self.frame_buffer_config.clone(),
+            self.frame_buffer_layout.clone(),
+            self.frame_buffer_region.clone(),
+            self.frame_buffer_header_ptr.as_ref().map(|ptr| ptr.0),
+        ) else {
+            return Err(DeviceManagerError::NoFrameBuffer);
+        };
+
+        // Finish the previous file before creating the next one
+        self.stop_frame_recording();
+        self.frame_recorder = Some(
+            crate::frame_recorder::FrameRecorder::new(path, fps, container, region, layout, fb_cfg)
+                .map_err(DeviceManagerError::FrameRecorder)?,
+        );
+
+        // SAFETY: The pointer is valid as long as the ivshmem device exists
+        unsafe { &*header_ptr }.set_command(GuestCommand::StartCapture);
+        Ok(())
+    }
+
+    /// Finish the frame buffer recording, if any
+    #[cfg(feature = "ivshmem")]
+    pub fn stop_frame_recording(&mut self) {
+        self.frame_recorder = None;
+    }
+
     /// Start frame capture by sending StartCapture command to Guest Agent
     #[cfg(feature = "ivshmem")]
     pub fn frame_capture_start(&self) -> Option<crate::api::VmFrameCaptureStatusResponse> {
@@ -1517,11 +2070,14 @@ impl DeviceManager {
         self.frame_capture_status()
     }
 
-    /// Stop frame capture by sending StopCapture command to Guest Agent
+    /// Stop frame capture by sending StopCapture command to Guest Agent,
+    /// and the recording if any
     #[cfg(feature = "ivshmem")]
-    pub fn frame_capture_stop(&self) -> Option<crate::api::VmFrameCaptureStatusResponse> {
+    pub fn frame_capture_stop(&mut self) -> Option<crate::api::VmFrameCaptureStatusResponse> {
         use devices::frame_buffer::GuestCommand;
 
+        self.stop_frame_recording();
+
         let header_wrapper = self.frame_buffer_header_ptr.as_ref()?;
         let header_ptr = header_wrapper.0;
 
@@ -1566,6 +2122,14 @@ impl DeviceManager {
             width: fb_cfg.width,
             height: fb_cfg.height,
             format: fb_cfg.format.clone(),
+            recording: self
+                .frame_recorder
+                .as_ref()
+                .is_some_and(|recorder| recorder.is_recording()),
+            recorded_frames: self
+                .frame_recorder
+                .as_ref()
+                .map_or(0, |recorder| recorder.recorded_frames()),
         })
     }
 
@@ -1602,7 +2166,7 @@ impl DeviceManager {
     }
 
     #[cfg(not(feature = "ivshmem"))]
-    pub fn frame_capture_stop(&self) -> Option<crate::api::VmFrameCaptureStatusResponse> {
+    pub fn frame_capture_stop(&mut self) -> Option<crate::api::VmFrameCaptureStatusResponse> {
         None
     }
 
@@ -1637,6 +2201,7 @@ impl DeviceManager {
                 hot_y: shape.hot_y,
                 has_shape: header.has_cursor_data(),
                 update_count: header.cursor_update_count(),
+                scanout_id: 0,
             })
         }
     }
@@ -1646,6 +2211,39 @@ impl DeviceManager {
         None
     }
 
+    /// Get cursor information from the virtio-gpu cursor queue, once the
+    /// guest has set a cursor
+    pub fn gpu_cursor_info(&self) -> Option<crate::api::VmCursorInfoResponse> {
+        use devices::frame_buffer::CursorShapeInfo;
+
+        let cursor = self.gpu.as_ref()?.lock().unwrap().cursor();
+        if cursor.update_count == 0 {
+            return None;
+        }
+
+        let shape = CursorShapeInfo {
+            width: cursor.width.min(u16::MAX.into()) as u16,
+            height: cursor.height.min(u16::MAX.into()) as u16,
+            hot_x: cursor.hot_x.min(i16::MAX as u32) as i16,
+            hot_y: cursor.hot_y.min(i16::MAX as u32) as i16,
+            data_size: cursor.data.len() as u32,
+            reserved: [0u8; 20],
+        };
+
+        Some(crate::api::VmCursorInfoResponse {
+            x: cursor.x as i32,
+            y: cursor.y as i32,
+            visible: cursor.visible,
+            width: shape.width,
+            height: shape.height,
+            hot_x: shape.hot_x,
+            hot_y: shape.hot_y,
+            has_shape: shape.data_size > 0,
+            update_count: cursor.update_count,
+            scanout_id: cursor.scanout_id,
+        })
+    }
+
     pub fn create_interrupt_controller(
         &mut self,
     ) -> DeviceManagerResult<Arc<Mutex<dyn InterruptController>>> {
@@ -1749,6 +2347,10 @@ impl DeviceManager {
             self.ivshmem_device = self.add_ivshmem_device(ivshmem)?;
         }
 
+        if let Some(usb) = self.config.clone().lock().unwrap().usb.as_ref() {
+            self.usb_device = self.add_usb_device(usb)?;
+        }
+
         Ok(())
     }
 
@@ -2800,6 +3402,12 @@ impl DeviceManager {
         // Add virtio-vsock if required
         self.make_virtio_vsock_devices()?;
 
+        // Add virtio-gpu if required
+        self.make_virtio_gpu_devices()?;
+
+        // Add virtio-input if required
+        self.make_virtio_input_devices()?;
+
         self.make_virtio_mem_devices()?;
 
         // Add virtio-balloon if required
@@ -2895,22 +3503,8 @@ impl DeviceManager {
                 vhost_user_block as Arc<Mutex<dyn Migratable>>,
             )
         } else {
-            let mut options = OpenOptions::new();
-            options.read(true);
-            options.write(!disk_cfg.readonly);
-            if disk_cfg.direct {
-                options.custom_flags(libc::O_DIRECT);
-            }
             // Open block device path
-            let mut file: File = options
-                .open(
-                    disk_cfg
-                        .path
-                        .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .clone(),
-                )
-                .map_err(DeviceManagerError::Disk)?;
+            let mut file = open_disk_file(disk_cfg)?;
 
             let detected_image_type =
                 detect_image_type(&mut file).map_err(DeviceManagerError::DetectImageType)?;
@@ -2937,6 +3531,11 @@ impl DeviceManager {
                     disk_cfg.backing_files = false;
                 }
 
+                if detected_image_type == ImageType::DynamicVhd && disk_cfg.backing_files {
+                    warn!("Dynamic VHD image type autodetected. Disabling backing files");
+                    disk_cfg.backing_files = false;
+                }
+
                 disk_cfg.image_type = detected_image_type;
             } else if disk_cfg.image_type != detected_image_type {
                 return Err(DeviceManagerError::DiskImageTypeMismatch {
@@ -2945,89 +3544,26 @@ impl DeviceManager {
                 });
             }
 
-            if disk_cfg.image_type != ImageType::Qcow2 && disk_cfg.backing_files {
-                warn!("Enabling backing_files option only applies for QCOW2 files");
+            if !matches!(
+                disk_cfg.image_type,
+                ImageType::Qcow2 | ImageType::DynamicVhd
+            ) && disk_cfg.backing_files
+            {
+                warn!("Enabling backing_files option only applies for QCOW2 and dynamic VHD files");
             }
 
-            let image = match disk_cfg.image_type {
-                ImageType::FixedVhd => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if cfg!(feature = "io_uring")
-                        && !disk_cfg.disable_io_uring
-                        && self.io_uring_is_supported()
-                    {
-                        info!("Using asynchronous fixed VHD disk file (io_uring)");
-
-                        #[cfg(not(feature = "io_uring"))]
-                        unreachable!("Checked in if statement above");
-                        #[cfg(feature = "io_uring")]
-                        {
-                            Box::new(
-                                FixedVhdDiskAsync::new(file)
-                                    .map_err(DeviceManagerError::CreateFixedVhdDiskAsync)?,
-                            ) as Box<dyn DiskFile>
-                        }
-                    } else {
-                        info!("Using synchronous fixed VHD disk file");
-                        Box::new(
-                            FixedVhdDiskSync::new(file)
-                                .map_err(DeviceManagerError::CreateFixedVhdDiskSync)?,
-                        ) as Box<dyn DiskFile>
-                    }
-                }
-                ImageType::Raw => {
-                    // For non-sparse RAW disks, preallocate disk space
-                    if !disk_cfg.readonly
-                        && !disk_cfg.sparse
-                        && let Some(path) = &disk_cfg.path
-                    {
-                        preallocate_disk(&file, path);
-                    }
-
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if cfg!(feature = "io_uring")
-                        && !disk_cfg.disable_io_uring
-                        && self.io_uring_is_supported()
-                    {
-                        info!("Using asynchronous RAW disk file (io_uring)");
+            // For non-sparse RAW disks, preallocate disk space
+            if disk_cfg.image_type == ImageType::Raw
+                && !disk_cfg.readonly
+                && !disk_cfg.sparse
+                && let Some(path) = &disk_cfg.path
+            {
+                preallocate_disk(&file, path);
+            }
 
-                        #[cfg(not(feature = "io_uring"))]
-                        unreachable!("Checked in if statement above");
-                        #[cfg(feature = "io_uring")]
-                        {
-                            Box::new(RawFileDisk::new(file)) as Box<dyn DiskFile>
-                        }
-                    } else if !disk_cfg.disable_aio && self.aio_is_supported() {
-                        info!("Using asynchronous RAW disk file (aio)");
-                        Box::new(RawFileDiskAio::new(file)) as Box<dyn DiskFile>
-                    } else {
-                        info!("Using synchronous RAW disk file");
-                        Box::new(RawFileDiskSync::new(file)) as Box<dyn DiskFile>
-                    }
-                }
-                ImageType::Qcow2 => {
-                    info!("Using synchronous QCOW2 disk file");
-                    Box::new(
-                        QcowDiskSync::new(
-                            file,
-                            disk_cfg.direct,
-                            disk_cfg.backing_files,
-                            disk_cfg.sparse,
-                        )
-                        .map_err(DeviceManagerError::CreateQcowDiskSync)?,
-                    ) as Box<dyn DiskFile>
-                }
-                ImageType::Vhdx => {
-                    info!("Using synchronous VHDX disk file");
-                    Box::new(
-                        VhdxDiskSync::new(file)
-                            .map_err(DeviceManagerError::CreateFixedVhdxDiskSync)?,
-                    ) as Box<dyn DiskFile>
-                }
-                ImageType::Unknown => unreachable!(),
-            };
+            let io_uring_supported = self.io_uring_is_supported();
+            let aio_supported = self.aio_is_supported();
+            let image = open_disk_image(file, disk_cfg, io_uring_supported, aio_supported)?;
 
             let rate_limit_group =
                 if let Some(rate_limiter_cfg) = disk_cfg.rate_limiter_config.as_ref() {
@@ -3106,6 +3642,12 @@ impl DeviceManager {
                     .map_err(DeviceManagerError::DiskLockError)?;
             }
 
+            if disk_cfg.dirty_bitmap {
+                let dirty_bitmap = PersistentDirtyBitmap::load(&mut virtio_block, disk_cfg)
+                    .map_err(DeviceManagerError::DiskDirtyBitmap)?;
+                self.disk_dirty_bitmaps.insert(id.clone(), dirty_bitmap);
+            }
+
             let virtio_block = Arc::new(Mutex::new(virtio_block));
 
             self.block_devices.push(virtio_block.clone());
@@ -3746,6 +4288,224 @@ impl DeviceManager {
         Ok(())
     }
 
+    fn make_virtio_gpu_device(
+        &mut self,
+        gpu_cfg: &mut GpuConfig,
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
+        let id = if let Some(id) = &gpu_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(GPU_DEVICE_NAME_PREFIX)?;
+            gpu_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Creating virtio-gpu device: {gpu_cfg:?}");
+
+        let mut gpu = virtio_devices::Gpu::new_with_config(
+            id.clone(),
+            gpu_cfg.width,
+            gpu_cfg.height,
+            gpu_cfg.max_outputs,
+            gpu_cfg.edid,
+            gpu_cfg.virgl,
+            self.seccomp_action.clone(),
+            self.exit_evt
+                .try_clone()
+                .map_err(DeviceManagerError::EventFd)?,
+        )
+        .map_err(DeviceManagerError::CreateVirtioGpu)?;
+
+        if let Some(outputs) = &gpu_cfg.outputs {
+            for scanout_id in 0..gpu_cfg.max_outputs {
+                let mode = outputs
+                    .get(scanout_id as usize)
+                    .map(|output| (output.width, output.height));
+                gpu.set_display(scanout_id, mode)
+                    .map_err(DeviceManagerError::CreateVirtioGpu)?;
+            }
+        }
+
+        let mut node = device_node!(id);
+
+        if gpu_cfg.blob {
+            let host_visible = if gpu_cfg.hostmem != 0 {
+                let (base, size) = self.allocate_gpu_host_visible_range(&id, gpu_cfg)?;
+                node.resources
+                    .push(Resource::MmioAddressRange { base, size });
+
+                let mmap_region = MmapRegion::<AtomicBitmap>::build(
+                    None,
+                    size as usize,
+                    PROT_READ | PROT_WRITE,
+                    MAP_NORESERVE | MAP_PRIVATE | MAP_ANONYMOUS,
+                )
+                .map_err(DeviceManagerError::NewMmapRegion)?;
+                let host_addr = mmap_region.as_ptr();
+
+                // SAFETY: host_addr points to size bytes of mmap-allocated memory.
+                let mem_slot = unsafe {
+                    self.memory_manager
+                        .lock()
+                        .unwrap()
+                        .create_userspace_mapping(
+                            base,
+                            size as usize,
+                            host_addr,
+                            false,
+                            false,
+                            false,
+                        )
+                        .map_err(DeviceManagerError::MemoryManager)
+                }?;
+
+                Some(virtio_devices::VirtioSharedMemoryList {
+                    mem_slot,
+                    addr: GuestAddress(base),
+                    mapping: Arc::new(mmap_region),
+                    region_list: vec![virtio_devices::VirtioSharedMemory {
+                        id: virtio_devices::gpu::VIRTIO_GPU_SHM_ID_HOST_VISIBLE,
+                        offset: 0,
+                        len: size,
+                    }],
+                })
+            } else {
+                None
+            };
+            gpu.enable_resource_blob(host_visible);
+        }
+
+        let gpu_device = Arc::new(Mutex::new(gpu));
+        self.gpu = Some(gpu_device.clone());
+
+        // Fill the device tree with a new node. In case of restore, we
+        // know there is nothing to do, so we can simply override the
+        // existing entry.
+        node.migratable = Some(Arc::clone(&gpu_device) as Arc<Mutex<dyn Migratable>>);
+        self.device_tree.lock().unwrap().insert(id.clone(), node);
+
+        Ok(MetaVirtioDevice {
+            virtio_device: Arc::clone(&gpu_device) as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+            iommu: false,
+            id,
+            pci_segment: gpu_cfg.pci_segment,
+            dma_handler: None,
+        })
+    }
+
+    fn allocate_gpu_host_visible_range(
+        &mut self,
+        id: &str,
+        gpu_cfg: &GpuConfig,
+    ) -> DeviceManagerResult<(u64, u64)> {
+        // Look for the id in the device tree. If it can be found, that means
+        // the device is being restored and the range must be reused.
+        let mut restored_range = None;
+        if let Some(node) = self.device_tree.lock().unwrap().get(id) {
+            for resource in node.resources.iter() {
+                match resource {
+                    Resource::MmioAddressRange { base, size } => {
+                        if restored_range.is_some() {
+                            return Err(DeviceManagerError::ResourceAlreadyExists);
+                        }
+                        restored_range = Some((*base, *size));
+                    }
+                    _ => {
+                        error!("Unexpected resource {resource:?} for {id}");
+                    }
+                }
+            }
+        }
+
+        // The shared memory BAR is sized from the region, which must then be
+        // naturally aligned.
+        let size = restored_range.map_or(gpu_cfg.hostmem, |(_, size)| size);
+        let base = self.pci_segments[gpu_cfg.pci_segment as usize]
+            .mem64_allocator
+            .lock()
+            .unwrap()
+            .allocate(
+                restored_range.map(|(base, _)| GuestAddress(base)),
+                size as GuestUsize,
+                Some(size),
+            )
+            .ok_or(DeviceManagerError::GpuHostVisibleRangeAllocation)?;
+
+        Ok((base.raw_value(), size))
+    }
+
+    fn make_virtio_gpu_devices(&mut self) -> DeviceManagerResult<()> {
+        let mut gpu = self.config.lock().unwrap().gpu.take();
+        if let Some(gpu_cfg) = &mut gpu {
+            let device = self.make_virtio_gpu_device(gpu_cfg)?;
+            self.virtio_devices.push(device);
+        }
+        self.config.lock().unwrap().gpu = gpu;
+
+        Ok(())
+    }
+
+    fn make_virtio_input_device(
+        &mut self,
+        input_cfg: &mut VirtioInputConfig,
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
+        let id = if let Some(id) = &input_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(INPUT_DEVICE_NAME_PREFIX)?;
+            input_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Creating virtio-input device: {input_cfg:?}");
+
+        let input_device = Arc::new(Mutex::new(
+            virtio_devices::VirtioInput::new(
+                id.clone(),
+                input_cfg.name.clone(),
+                input_cfg.serial.clone(),
+                input_cfg.pointer,
+                self.force_iommu | input_cfg.iommu,
+                self.seccomp_action.clone(),
+                self.exit_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+                state_from_id(self.snapshot.as_ref(), id.as_str())
+                    .map_err(DeviceManagerError::RestoreGetState)?,
+            )
+            .map_err(DeviceManagerError::CreateVirtioInput)?,
+        ));
+        self.virtio_input = Some(input_device.clone());
+
+        // Fill the device tree with a new node. In case of restore, we
+        // know there is nothing to do, so we can simply override the
+        // existing entry.
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(id.clone(), device_node!(id, input_device));
+
+        Ok(MetaVirtioDevice {
+            virtio_device: Arc::clone(&input_device)
+                as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+            iommu: input_cfg.iommu,
+            id,
+            pci_segment: input_cfg.pci_segment,
+            dma_handler: None,
+        })
+    }
+
+    fn make_virtio_input_devices(&mut self) -> DeviceManagerResult<()> {
+        let mut input = self.config.lock().unwrap().input.take();
+        if let Some(input_cfg) = &mut input {
+            let device = self.make_virtio_input_device(input_cfg)?;
+            self.virtio_devices.push(device);
+        }
+        self.config.lock().unwrap().input = input;
+
+        Ok(())
+    }
+
     fn make_virtio_mem_devices(&mut self) -> DeviceManagerResult<()> {
         let mm = self.memory_manager.clone();
         let mut mm = mm.lock().unwrap();
@@ -4591,6 +5351,64 @@ impl DeviceManager {
         Ok(Some(pvpanic_device))
     }
 
+    fn add_usb_device(
+        &mut self,
+        usb_cfg: &UsbConfig,
+    ) -> DeviceManagerResult<Option<Arc<Mutex<devices::usb::XhciPciDevice>>>> {
+        let id = String::from(USB_DEVICE_NAME);
+
+        info!("Creating USB controller {id}: {usb_cfg:?}");
+
+        let (pci_segment_id, pci_device_bdf, resources) =
+            self.pci_resources(&id, usb_cfg.pci_segment)?;
+
+        let snapshot = snapshot_from_id(self.snapshot.as_ref(), id.as_str());
+
+        let mut usb_device = devices::usb::XhciPciDevice::new(
+            id.clone(),
+            pci_device_bdf.into(),
+            self.memory_manager.lock().unwrap().guest_memory(),
+            self.msi_interrupt_manager.as_ref(),
+            snapshot,
+        )
+        .map_err(DeviceManagerError::UsbCreate)?;
+
+        // The HID handles are shared with the input manager, which queues
+        // reports on them for the guest driver to collect.
+        let keyboard = usb_cfg
+            .keyboard
+            .then(|| Arc::new(Mutex::new(devices::usb::UsbHidDevice::new_keyboard())));
+        let mouse = usb_cfg
+            .mouse
+            .then(|| Arc::new(Mutex::new(devices::usb::UsbHidDevice::new_mouse())));
+        let tablet = usb_cfg
+            .tablet
+            .then(|| Arc::new(Mutex::new(devices::usb::UsbHidDevice::new_tablet())));
+        usb_device
+            .attach_hid_devices(keyboard, mouse, tablet)
+            .map_err(DeviceManagerError::UsbCreate)?;
+
+        let usb_device = Arc::new(Mutex::new(usb_device));
+
+        let new_resources = self.add_pci_device(
+            usb_device.clone(),
+            usb_device.clone(),
+            pci_segment_id,
+            pci_device_bdf,
+            resources,
+        )?;
+
+        let mut node = device_node!(id, usb_device);
+
+        node.resources = new_resources;
+        node.pci_bdf = Some(pci_device_bdf);
+        node.pci_device_handle = None;
+
+        self.device_tree.lock().unwrap().insert(id, node);
+
+        Ok(Some(usb_device))
+    }
+
     #[cfg(feature = "ivshmem")]
     fn add_ivshmem_device(
         &mut self,
@@ -4607,12 +5425,41 @@ impl DeviceManager {
         let ivshmem_ops = Arc::new(Mutex::new(IvshmemHandler {
             memory_manager: self.memory_manager.clone(),
         }));
+
+        // In doorbell mode, the shared memory is the one of the server. It
+        // can be reopened through the client until the device goes away.
+        let mut backend_file = ivshmem_cfg.path.clone();
+        let doorbell = if let Some(server) = ivshmem_cfg.server.as_ref() {
+            let client =
+                IvshmemClient::connect(server).map_err(DeviceManagerError::IvshmemServer)?;
+            let shm_size = client
+                .shm()
+                .metadata()
+                .map_err(|e| DeviceManagerError::IvshmemServer(IvshmemClientError::Receive(e)))?
+                .len();
+            if shm_size < ivshmem_cfg.size as u64 {
+                return Err(DeviceManagerError::IvshmemServerShmSize(shm_size));
+            }
+            info!("Registered with ivshmem server {server:?} as peer {}", client.id());
+
+            backend_file = client.shm_path();
+            Some(IvshmemDoorbellConfig {
+                client,
+                vectors: ivshmem_cfg.vectors,
+                pci_device_bdf: pci_device_bdf.into(),
+                interrupt_manager: self.msi_interrupt_manager.as_ref(),
+            })
+        } else {
+            None
+        };
+
         let ivshmem_device = Arc::new(Mutex::new(
             devices::IvshmemDevice::new(
                 id.clone(),
                 ivshmem_cfg.size as u64,
-                Some(ivshmem_cfg.path.clone()),
+                Some(backend_file.clone()),
                 ivshmem_ops.clone(),
+                doorbell,
                 snapshot,
             )
             .map_err(DeviceManagerError::IvshmemCreate)?,
@@ -4629,7 +5476,7 @@ impl DeviceManager {
         let (region, mapping) = ivshmem_ops
             .lock()
             .unwrap()
-            .map_ram_region(start_addr, ivshmem_cfg.size, Some(ivshmem_cfg.path.clone()))
+            .map_ram_region(start_addr, ivshmem_cfg.size, Some(backend_file))
             .map_err(DeviceManagerError::IvshmemCreate)?;
         ivshmem_device.lock().unwrap().set_region(region.clone(), mapping);
 
@@ -4694,6 +5541,24 @@ impl DeviceManager {
             self.frame_buffer_config = Some(fb_cfg.clone());
             self.frame_buffer_layout = Some(layout);
             self.frame_buffer_header_ptr = Some(FrameBufferHeaderPtr(header_ptr));
+            self.frame_buffer_region = Some(region.clone());
+
+            // The guest agent rings the doorbell once a frame is published
+            if let Some(notify_socket) = fb_cfg.notify_socket.as_ref() {
+                let doorbell_evt =
+                    EventFd::new(libc::EFD_NONBLOCK).map_err(DeviceManagerError::EventFd)?;
+                let device_evt = doorbell_evt.try_clone().map_err(DeviceManagerError::EventFd)?;
+                ivshmem_device.lock().unwrap().set_doorbell_evt(device_evt);
+                self.frame_notifier = Some(
+                    devices::FrameNotifier::new(
+                        notify_socket,
+                        region.clone(),
+                        doorbell_evt,
+                        devices::frame_notifier::FRAME_NOTIFIER_POLL_INTERVAL,
+                    )
+                    .map_err(DeviceManagerError::FrameNotifier)?,
+                );
+            }
 
             info!(
                 "Frame buffer initialized: header at offset 0, data at offset {}, total size {} bytes",
@@ -4929,6 +5794,15 @@ impl DeviceManager {
                 })
                 .map(|(i, _)| i);
             if let Some(index) = maybe_block_device_index {
+                // Stop the backup before the disk goes away.
+                self.disk_backups.remove(id);
+                // The guest doesn't write to the disk anymore.
+                if let Some(dirty_bitmap) = self.disk_dirty_bitmaps.remove(id)
+                    && let Err(e) =
+                        dirty_bitmap.store(&mut self.block_devices[index].lock().unwrap())
+                {
+                    error!("Failed to store the dirty bitmap of disk {id}: {e:?}");
+                }
                 let _ = self.block_devices.swap_remove(index);
             }
         }
@@ -5206,6 +6080,20 @@ impl DeviceManager {
 
             self.virtio_devices
                 .retain(|handler| !Arc::ptr_eq(&handler.virtio_device, &virtio_device));
+
+            if self
+                .gpu
+                .as_ref()
+                .is_some_and(|gpu| std::ptr::addr_eq(Arc::as_ptr(gpu), Arc::as_ptr(&virtio_device)))
+            {
+                self.gpu = None;
+            }
+
+            if self.virtio_input.as_ref().is_some_and(|input| {
+                std::ptr::addr_eq(Arc::as_ptr(input), Arc::as_ptr(&virtio_device))
+            }) {
+                self.virtio_input = None;
+            }
         }
 
         event!(
@@ -5341,6 +6229,27 @@ impl DeviceManager {
         self.hotplug_virtio_pci_device(device)
     }
 
+    pub fn add_gpu(&mut self, gpu_cfg: &mut GpuConfig) -> DeviceManagerResult<PciDeviceInfo> {
+        self.validate_identifier(&gpu_cfg.id)?;
+
+        let device = self.make_virtio_gpu_device(gpu_cfg)?;
+        self.hotplug_virtio_pci_device(device)
+    }
+
+    pub fn add_input(
+        &mut self,
+        input_cfg: &mut VirtioInputConfig,
+    ) -> DeviceManagerResult<PciDeviceInfo> {
+        self.validate_identifier(&input_cfg.id)?;
+
+        if input_cfg.iommu && !self.is_iommu_segment(input_cfg.pci_segment) {
+            return Err(DeviceManagerError::InvalidIommuHotplug);
+        }
+
+        let device = self.make_virtio_input_device(input_cfg)?;
+        self.hotplug_virtio_pci_device(device)
+    }
+
     pub fn counters(&self) -> HashMap<String, HashMap<&'static str, Wrapping<u64>>> {
         let mut counters = HashMap::new();
 
@@ -5376,17 +6285,311 @@ impl DeviceManager {
     }
 
     pub fn resize_disk(&mut self, device_id: &str, new_size: u64) -> DeviceManagerResult<()> {
+        self.check_no_disk_backup(device_id)?;
+        for dev in &self.block_devices {
+            let mut disk = dev.lock().unwrap();
+            if disk.id() == device_id {
+                disk.resize(new_size)
+                    .map_err(DeviceManagerError::DiskResize)?;
+                if let Some(dirty_bitmap) = self.disk_dirty_bitmaps.get_mut(device_id) {
+                    dirty_bitmap
+                        .resize(&mut disk)
+                        .map_err(DeviceManagerError::DiskDirtyBitmap)?;
+                }
+                return Ok(());
+            }
+        }
+        Err(DeviceManagerError::UnknownDeviceId(device_id.to_string()))
+    }
+
+    fn with_block_device<T>(
+        &self,
+        device_id: &str,
+        f: impl FnOnce(&mut Block) -> virtio_devices::block::Result<T>,
+    ) -> DeviceManagerResult<T> {
         for dev in &self.block_devices {
             let mut disk = dev.lock().unwrap();
             if disk.id() == device_id {
-                return disk
-                    .resize(new_size)
-                    .map_err(DeviceManagerError::DiskResize);
+                return f(&mut disk).map_err(DeviceManagerError::DiskSnapshot);
             }
         }
         Err(DeviceManagerError::UnknownDeviceId(device_id.to_string()))
     }
 
+    pub fn disk_snapshots(&self, device_id: &str) -> DeviceManagerResult<Vec<DiskSnapshot>> {
+        self.with_block_device(device_id, |disk| disk.snapshots())
+    }
+
+    pub fn create_disk_snapshot(&self, device_id: &str, name: &str) -> DeviceManagerResult<()> {
+        #[cfg(not(target_arch = "riscv64"))]
+        let vm_clock_nsec = self.timestamp.elapsed().as_nanos() as u64;
+        #[cfg(target_arch = "riscv64")]
+        let vm_clock_nsec = 0;
+        self.with_block_device(device_id, |disk| {
+            disk.with_queues_quiesced(|disk| disk.create_snapshot(name, vm_clock_nsec))?
+        })
+    }
+
+    pub fn apply_disk_snapshot(
+        &self,
+        device_id: &str,
+        id_or_name: &str,
+    ) -> DeviceManagerResult<()> {
+        self.check_no_disk_backup(device_id)?;
+        self.with_block_device(device_id, |disk| {
+            disk.with_queues_quiesced(|disk| disk.apply_snapshot(id_or_name))?
+        })?;
+        // The next backup can't rely on the chunks written so far.
+        if let Some(dirty_bitmap) = self.disk_dirty_bitmaps.get(device_id) {
+            dirty_bitmap.set_all();
+        }
+        Ok(())
+    }
+
+    pub fn delete_disk_snapshot(
+        &self,
+        device_id: &str,
+        id_or_name: &str,
+    ) -> DeviceManagerResult<()> {
+        self.with_block_device(device_id, |disk| {
+            disk.with_queues_quiesced(|disk| disk.delete_snapshot(id_or_name))?
+        })
+    }
+
+    fn disk_config(&self, device_id: &str) -> DeviceManagerResult<DiskConfig> {
+        self.config
+            .lock()
+            .unwrap()
+            .disks
+            .iter()
+            .flatten()
+            .find(|disk_cfg| disk_cfg.id.as_deref() == Some(device_id))
+            .cloned()
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(device_id.to_string()))
+    }
+
+    fn block_device(&self, device_id: &str) -> DeviceManagerResult<Arc<Mutex<Block>>> {
+        self.block_devices
+            .iter()
+            .find(|dev| dev.lock().unwrap().id() == device_id)
+            .cloned()
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(device_id.to_string()))
+    }
+
+    fn check_no_disk_commit(&self, device_id: &str) -> DeviceManagerResult<()> {
+        if self
+            .disk_commits
+            .get(device_id)
+            .is_some_and(DiskCommitJob::is_running)
+        {
+            return Err(DeviceManagerError::DiskCommitInProgress(
+                device_id.to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_no_disk_backup(&self, device_id: &str) -> DeviceManagerResult<()> {
+        if self
+            .disk_backups
+            .get(device_id)
+            .is_some_and(DiskBackupJob::is_running)
+        {
+            return Err(DeviceManagerError::DiskBackupInProgress(
+                device_id.to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_no_disk_dirty_bitmap(&self, device_id: &str) -> DeviceManagerResult<()> {
+        if self.disk_dirty_bitmaps.contains_key(device_id) {
+            return Err(DeviceManagerError::DiskDirtyBitmapInUse(
+                device_id.to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Switches a disk to a new qcow2 overlay at `overlay`, backed by the
+    /// current disk image, which isn't written to anymore.
+    pub fn disk_live_snapshot(
+        &mut self,
+        device_id: &str,
+        overlay: &Path,
+    ) -> DeviceManagerResult<()> {
+        self.check_no_disk_commit(device_id)?;
+        self.check_no_disk_backup(device_id)?;
+        self.check_no_disk_dirty_bitmap(device_id)?;
+        let disk_cfg = self.disk_config(device_id)?;
+        let backing_format = match disk_cfg.image_type {
+            ImageType::Raw => qcow::ImageType::Raw,
+            ImageType::Qcow2 => qcow::ImageType::Qcow2,
+            _ => {
+                return Err(DeviceManagerError::DiskLiveSnapshotUnsupported(
+                    device_id.to_string(),
+                ));
+            }
+        };
+        // Encrypted images can't back an overlay.
+        if disk_cfg.vhost_user
+            || disk_cfg.readonly
+            || disk_cfg.key_file.is_some()
+            || disk_cfg.key_secret.is_some()
+        {
+            return Err(DeviceManagerError::DiskLiveSnapshotUnsupported(
+                device_id.to_string(),
+            ));
+        }
+        // The overlay refers to its backing file wherever it is created.
+        let backing = disk_cfg
+            .path
+            .as_ref()
+            .ok_or(DeviceManagerError::NoDiskPath)?
+            .canonicalize()
+            .map_err(DeviceManagerError::Disk)?;
+        let backing_config = qcow::BackingFileConfig {
+            path: backing.to_string_lossy().into_owned(),
+            format: Some(backing_format),
+        };
+        let overlay_cfg = DiskConfig {
+            path: Some(overlay.to_path_buf()),
+            image_type: ImageType::Qcow2,
+            backing_files: true,
+            ..disk_cfg
+        };
+        let io_uring_supported = self.io_uring_is_supported();
+        let aio_supported = self.aio_is_supported();
+
+        let disk = self.block_device(device_id)?;
+        let mut disk = disk.lock().unwrap();
+        let backing_image = disk.with_queues_quiesced(|disk| {
+            // The overlay reads the current image from its own file.
+            disk.new_async_io(1)
+                .map_err(DeviceManagerError::DiskLiveSnapshot)?
+                .fsync(None)
+                .map_err(DeviceManagerError::FlushDisk)?;
+
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(overlay)
+                .map_err(|e| DeviceManagerError::CreateDiskOverlay(overlay.to_path_buf(), e))?;
+            let image = qcow::QcowFile::new_from_backing(
+                qcow::RawFile::new(file, false),
+                3,
+                disk.disk_size(),
+                &backing_config,
+                overlay_cfg.sparse,
+            )
+            .map_err(DeviceManagerError::CreateDiskOverlayImage)
+            .and_then(|image| {
+                // Write the metadata of the overlay before opening it again.
+                drop(image);
+                let file = open_disk_file(&overlay_cfg)?;
+                open_disk_image(file, &overlay_cfg, io_uring_supported, aio_supported)
+            })
+            .and_then(|image| {
+                disk.set_disk_image(image, overlay.to_path_buf())
+                    .map_err(DeviceManagerError::DiskLiveSnapshot)
+            });
+            if image.is_err() {
+                let _ = std::fs::remove_file(overlay);
+            }
+            image
+        });
+        // Closing the previous image releases its lock.
+        drop(backing_image.map_err(DeviceManagerError::DiskLiveSnapshot)??);
+        disk.try_lock_image()
+            .map_err(DeviceManagerError::DiskLockError)?;
+        drop(disk);
+
+        if let Some(disk_cfg) = self
+            .config
+            .lock()
+            .unwrap()
+            .disks
+            .iter_mut()
+            .flatten()
+            .find(|disk_cfg| disk_cfg.id.as_deref() == Some(device_id))
+        {
+            *disk_cfg = overlay_cfg;
+        }
+
+        info!("Disk {device_id} switched to {overlay:?}, backed by {backing:?}");
+        Ok(())
+    }
+
+    /// Starts merging the overlay a disk uses into its backing file, and
+    /// switching the disk to it.
+    pub fn disk_commit(&mut self, device_id: &str) -> DeviceManagerResult<()> {
+        self.check_no_disk_commit(device_id)?;
+        self.check_no_disk_backup(device_id)?;
+        self.check_no_disk_dirty_bitmap(device_id)?;
+        let disk_cfg = self.disk_config(device_id)?;
+        let disk = self.block_device(device_id)?;
+        let io_uring_supported = self.io_uring_is_supported();
+        let aio_supported = self.aio_is_supported();
+        let job = DiskCommitJob::start(
+            &disk,
+            self.config.clone(),
+            &disk_cfg,
+            io_uring_supported,
+            aio_supported,
+        )
+        .map_err(DeviceManagerError::DiskCommit)?;
+        self.disk_commits.insert(device_id.to_string(), job);
+        Ok(())
+    }
+
+    /// Returns the progress of the last commit of a disk.
+    pub fn disk_commit_status(&self, device_id: &str) -> DeviceManagerResult<DiskCommitStatus> {
+        self.disk_commits
+            .get(device_id)
+            .map(DiskCommitJob::status)
+            .ok_or_else(|| DeviceManagerError::NoDiskCommit(device_id.to_string()))
+    }
+
+    /// Starts copying a disk to a new qcow2 image at `target`, or only the
+    /// chunks written since the previous backup if `incremental`.
+    pub fn disk_backup(
+        &mut self,
+        device_id: &str,
+        target: &Path,
+        incremental: bool,
+    ) -> DeviceManagerResult<()> {
+        self.check_no_disk_commit(device_id)?;
+        self.check_no_disk_backup(device_id)?;
+        let disk = self.block_device(device_id)?;
+        let job = DiskBackupJob::start(
+            &disk,
+            target,
+            self.disk_dirty_bitmaps.get(device_id),
+            incremental,
+        )
+        .map_err(DeviceManagerError::DiskBackup)?;
+        self.disk_backups.insert(device_id.to_string(), job);
+        Ok(())
+    }
+
+    /// Returns the progress of the last backup of a disk.
+    pub fn disk_backup_status(&self, device_id: &str) -> DeviceManagerResult<DiskBackupStatus> {
+        self.disk_backups
+            .get(device_id)
+            .map(DiskBackupJob::status)
+            .ok_or_else(|| DeviceManagerError::NoDiskBackup(device_id.to_string()))
+    }
+
+    /// Stops the backup of a disk going on.
+    pub fn disk_backup_cancel(&self, device_id: &str) -> DeviceManagerResult<()> {
+        self.disk_backups
+            .get(device_id)
+            .filter(|job| job.is_running())
+            .map(DiskBackupJob::cancel)
+            .ok_or_else(|| DeviceManagerError::NoDiskBackup(device_id.to_string()))
+    }
+
     pub fn device_tree(&self) -> Arc<Mutex<DeviceTree>> {
         self.device_tree.clone()
     }
@@ -5969,6 +7172,23 @@ impl BusDevice for DeviceManager {
 
 impl Drop for DeviceManager {
     fn drop(&mut self) {
+        // Stop the disk commits and backups while their disks are still
+        // running.
+        self.disk_commits.clear();
+        self.disk_backups.clear();
+
+        // Keep the dirty bitmaps of the disks for the next run.
+        for (id, dirty_bitmap) in self.disk_dirty_bitmaps.drain() {
+            if let Some(disk) = self
+                .block_devices
+                .iter()
+                .find(|dev| dev.lock().unwrap().id() == id)
+                && let Err(e) = dirty_bitmap.store(&mut disk.lock().unwrap())
+            {
+                error!("Failed to store the dirty bitmap of disk {id}: {e:?}");
+            }
+        }
+
         // Wake up the DeviceManager threads (mainly virtio device workers),
         // to avoid deadlock on waiting for paused/parked worker threads.
         if let Err(e) = self.resume() {
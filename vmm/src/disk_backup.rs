@@ -0,0 +1,426 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Disk backup
+//!
+//! Tracks the chunks of a disk written by the guest in a dirty bitmap kept
+//! across runs, stored in the image for qcow2 disks and next to it for raw
+//! ones. A backup copies the whole disk, or only the chunks dirty since the
+//! previous backup, to a new qcow2 image from a dedicated thread, and starts
+//! tracking the writes from a clean bitmap. The queues of the disk are only
+//! stopped to start and end the backup: in between, the guest keeps writing
+//! to the disk, the chunks it writes being copied first, so that the image
+//! holds the content the disk had when the backup started.
+//!
+//! The image is written to the target path with a `.partial` suffix, and
+//! renamed once complete. It is removed if the backup fails or is cancelled,
+//! but left behind if the VMM exits in the middle of it.
+
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use block::ImageType;
+use block::copy_before_write::CopyBeforeWrite;
+use block::dirty_bitmap::{DirtyBitmap, DirtyBitmapFile};
+use block::qcow::{self, QcowFile, RawFile};
+use event_monitor::event;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use virtio_devices::Block;
+use vm_migration::Snapshottable;
+
+use crate::vm_config::DiskConfig;
+
+/// Name of the dirty bitmap in qcow2 images
+const DIRTY_BITMAP_NAME: &str = "cloud-hypervisor-backup";
+
+/// Number of bytes covered by each bit, the cluster size of the backups, so
+/// that an incremental backup only allocates the clusters that changed
+const DIRTY_BITMAP_GRANULARITY: u64 = 64 << 10;
+
+/// Suffix of the file holding the dirty bitmap of a raw disk
+const DIRTY_BITMAP_FILE_SUFFIX: &str = ".dirty-bitmap";
+
+/// Suffix of the image of a backup until it is complete
+const PARTIAL_SUFFIX: &str = ".partial";
+
+#[derive(Debug, Error)]
+pub enum DiskBackupError {
+    #[error("Dirty bitmaps are not supported for {0} disks")]
+    UnsupportedImageType(ImageType),
+    #[error("Failed to access the disk")]
+    Disk(#[source] virtio_devices::block::Error),
+    #[error("Failed to access the dirty bitmap file")]
+    DirtyBitmapFile(#[source] io::Error),
+    #[error("Disk {0} doesn't track its dirty chunks")]
+    NoDirtyBitmap(String),
+    #[error("Failed to create the backup {0:?}")]
+    CreateTarget(PathBuf, #[source] io::Error),
+    #[error("Failed to create the qcow2 image of the backup")]
+    CreateTargetImage(#[source] qcow::Error),
+    #[error("The backup {0:?} already exists")]
+    TargetExists(PathBuf),
+    #[error("Failed to copy the disk to the backup")]
+    Copy(#[source] io::Error),
+    #[error("Failed to spawn the disk backup thread")]
+    SpawnThread(#[source] io::Error),
+    #[error("The backup was cancelled")]
+    Cancelled,
+}
+
+/// Where a dirty bitmap is kept between runs
+enum DirtyBitmapStore {
+    /// In the qcow2 image of the disk
+    Image,
+    /// In a file next to the raw image of the disk
+    File(DirtyBitmapFile),
+}
+
+/// Dirty bitmap of a disk, kept across runs
+pub struct PersistentDirtyBitmap {
+    bitmap: Arc<DirtyBitmap>,
+    store: DirtyBitmapStore,
+}
+
+impl PersistentDirtyBitmap {
+    /// Load the dirty bitmap of `disk`, configured by `disk_cfg`, and have
+    /// the queues mark the chunks the guest writes in it. The whole disk is
+    /// dirty if the bitmap wasn't stored when the disk was last used.
+    pub fn load(disk: &mut Block, disk_cfg: &DiskConfig) -> Result<Self, DiskBackupError> {
+        let size = disk.disk_size();
+        let (bitmap, store) = match disk_cfg.image_type {
+            ImageType::Qcow2 => (
+                disk.load_dirty_bitmap(DIRTY_BITMAP_NAME, DIRTY_BITMAP_GRANULARITY)
+                    .map_err(DiskBackupError::Disk)?,
+                DirtyBitmapStore::Image,
+            ),
+            ImageType::Raw => {
+                let mut path = OsString::from(disk_cfg.path.clone().unwrap_or_default());
+                path.push(DIRTY_BITMAP_FILE_SUFFIX);
+                let file = DirtyBitmapFile::new(Path::new(&path));
+                (
+                    file.load(size, DIRTY_BITMAP_GRANULARITY)
+                        .map_err(DiskBackupError::DirtyBitmapFile)?,
+                    DirtyBitmapStore::File(file),
+                )
+            }
+            image_type => return Err(DiskBackupError::UnsupportedImageType(image_type)),
+        };
+
+        let bitmap = Arc::new(bitmap);
+        disk.set_dirty_bitmap(Some(bitmap.clone()));
+        Ok(PersistentDirtyBitmap { bitmap, store })
+    }
+
+    /// Stop tracking the writes to `disk` and store the bitmap for the next
+    /// run, once the guest doesn't write to the disk anymore.
+    pub fn store(self, disk: &mut Block) -> Result<(), DiskBackupError> {
+        disk.with_queues_quiesced(|disk| {
+            disk.set_dirty_bitmap(None);
+            match &self.store {
+                DirtyBitmapStore::Image => disk
+                    .store_dirty_bitmap(DIRTY_BITMAP_NAME, &self.bitmap)
+                    .map_err(DiskBackupError::Disk),
+                DirtyBitmapStore::File(file) => file
+                    .store(&self.bitmap, false)
+                    .map_err(DiskBackupError::DirtyBitmapFile),
+            }
+        })
+        .map_err(DiskBackupError::Disk)?
+    }
+
+    /// Follow a resize of `disk`, the chunks added being dirty.
+    pub fn resize(&mut self, disk: &mut Block) -> Result<(), DiskBackupError> {
+        disk.with_queues_quiesced(|disk| {
+            self.bitmap = Arc::new(self.bitmap.resized(disk.disk_size()));
+            disk.set_dirty_bitmap(Some(self.bitmap.clone()));
+        })
+        .map_err(DiskBackupError::Disk)
+    }
+
+    /// Mark the whole disk as dirty, after its content was replaced.
+    pub fn set_all(&self) {
+        self.bitmap.set_all();
+    }
+}
+
+/// State of a disk backup
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskBackupState {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// Progress of a disk backup
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskBackupStatus {
+    pub state: DiskBackupState,
+    pub target: PathBuf,
+    pub copied_bytes: u64,
+    pub remaining_bytes: u64,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Final state of a backup, along with the error it failed with
+type DiskBackupOutcome = (DiskBackupState, Option<String>);
+
+/// Copies a disk to a new qcow2 image from a dedicated thread
+pub struct DiskBackupJob {
+    target: PathBuf,
+    copy_before_write: Arc<CopyBeforeWrite>,
+    copied: Arc<AtomicU64>,
+    cancel: Arc<AtomicBool>,
+    /// Outcome of the backup, `None` while it is running
+    result: Arc<Mutex<Option<DiskBackupOutcome>>>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl DiskBackupJob {
+    /// Start copying `disk` to a new qcow2 image at `target`, with the
+    /// content the disk has now. An `incremental` backup only copies the
+    /// chunks set in `dirty_bitmap`, the other clusters of the image being
+    /// left unallocated. `dirty_bitmap` is cleared for the next backup to
+    /// start from this one, unless the backup fails.
+    pub fn start(
+        disk: &Arc<Mutex<Block>>,
+        target: &Path,
+        dirty_bitmap: Option<&PersistentDirtyBitmap>,
+        incremental: bool,
+    ) -> Result<Self, DiskBackupError> {
+        let id = disk.lock().unwrap().id();
+        if incremental && dirty_bitmap.is_none() {
+            return Err(DiskBackupError::NoDirtyBitmap(id));
+        }
+        if target.exists() {
+            return Err(DiskBackupError::TargetExists(target.to_path_buf()));
+        }
+
+        let partial = partial_path(target);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&partial)
+            .map_err(|e| DiskBackupError::CreateTarget(partial.clone(), e))?;
+        let copied = Arc::new(AtomicU64::new(0));
+        let started = disk
+            .lock()
+            .unwrap()
+            .with_queues_quiesced(|disk| {
+                let chunks = match dirty_bitmap {
+                    Some(dirty_bitmap) if incremental => dirty_bitmap.bitmap.as_ref().clone(),
+                    _ => {
+                        let chunks = DirtyBitmap::new(disk.disk_size(), DIRTY_BITMAP_GRANULARITY);
+                        chunks.set_all();
+                        chunks
+                    }
+                };
+                let image = QcowFile::new(RawFile::new(file, false), 3, chunks.size(), true)
+                    .map_err(DiskBackupError::CreateTargetImage)?;
+                let copy_before_write = Arc::new(CopyBeforeWrite::new(
+                    disk.new_async_io(1).map_err(DiskBackupError::Disk)?,
+                    Box::new(image),
+                    Arc::new(chunks),
+                    copied.clone(),
+                ));
+                disk.set_copy_before_write(Some(copy_before_write.clone()))
+                    .map_err(DiskBackupError::Disk)?;
+                // The guest writes from now on go to the next backup.
+                let previous = dirty_bitmap.map(|dirty_bitmap| {
+                    let previous = dirty_bitmap.bitmap.as_ref().clone();
+                    dirty_bitmap.bitmap.clear();
+                    (dirty_bitmap.bitmap.clone(), previous)
+                });
+                Ok((copy_before_write, previous))
+            })
+            .map_err(DiskBackupError::Disk)
+            .and_then(|started| started);
+        let (copy_before_write, previous) = match started {
+            Ok(started) => started,
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                return Err(e);
+            }
+        };
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let result = Arc::new(Mutex::new(None));
+        let worker = DiskBackupWorker {
+            id: id.clone(),
+            disk: disk.clone(),
+            copy_before_write: copy_before_write.clone(),
+            partial,
+            target: target.to_path_buf(),
+            previous,
+            cancel: cancel.clone(),
+        };
+        let worker_result = result.clone();
+        let worker = thread::Builder::new()
+            .name(format!("{id}_backup"))
+            .spawn(move || {
+                let result = worker.run();
+                let outcome = match &result {
+                    Ok(()) => {
+                        event!("vm", "disk-backup-completed", "id", &worker.id);
+                        (DiskBackupState::Completed, None)
+                    }
+                    Err(e) => {
+                        worker.abort();
+                        if matches!(e, DiskBackupError::Cancelled) {
+                            info!("Backup of disk {} cancelled", worker.id);
+                            event!("vm", "disk-backup-cancelled", "id", &worker.id);
+                            (DiskBackupState::Cancelled, None)
+                        } else {
+                            error!("Backup of disk {} failed: {e}", worker.id);
+                            event!("vm", "disk-backup-failed", "id", &worker.id);
+                            (DiskBackupState::Failed, Some(e.to_string()))
+                        }
+                    }
+                };
+                *worker_result.lock().unwrap() = Some(outcome);
+            })
+            .map_err(DiskBackupError::SpawnThread);
+        let worker = match worker {
+            Ok(worker) => worker,
+            Err(e) => {
+                // The worker was dropped along with the closure.
+                remove_copy_before_write(disk);
+                let _ = fs::remove_file(partial_path(target));
+                if let Some(dirty_bitmap) = dirty_bitmap {
+                    dirty_bitmap.set_all();
+                }
+                return Err(e);
+            }
+        };
+
+        info!("Backing up disk {id} to {target:?}");
+
+        Ok(DiskBackupJob {
+            target: target.to_path_buf(),
+            copy_before_write,
+            copied,
+            cancel,
+            result,
+            worker: Some(worker),
+        })
+    }
+
+    /// Whether the backup is still going on
+    pub fn is_running(&self) -> bool {
+        self.result.lock().unwrap().is_none()
+    }
+
+    /// Stop the backup, leaving no image behind.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Release);
+    }
+
+    /// Progress of the backup
+    pub fn status(&self) -> DiskBackupStatus {
+        let (state, error) = self
+            .result
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or((DiskBackupState::Running, None));
+        DiskBackupStatus {
+            state,
+            target: self.target.clone(),
+            copied_bytes: self.copied.load(Ordering::Acquire),
+            remaining_bytes: self.copy_before_write.remaining_bytes(),
+            error,
+        }
+    }
+}
+
+impl Drop for DiskBackupJob {
+    fn drop(&mut self) {
+        self.cancel();
+        if let Some(worker) = self.worker.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = worker.join();
+        }
+    }
+}
+
+struct DiskBackupWorker {
+    id: String,
+    disk: Arc<Mutex<Block>>,
+    copy_before_write: Arc<CopyBeforeWrite>,
+    /// Image being written, renamed to `target` once complete
+    partial: PathBuf,
+    target: PathBuf,
+    /// Dirty bitmap of the disk and the chunks it had set before the backup
+    previous: Option<(Arc<DirtyBitmap>, DirtyBitmap)>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl DiskBackupWorker {
+    fn run(&self) -> Result<(), DiskBackupError> {
+        self.copy_before_write
+            .copy_pending(&self.cancel)
+            .map_err(DiskBackupError::Copy)?;
+        if self.cancel.load(Ordering::Acquire) {
+            return Err(DiskBackupError::Cancelled);
+        }
+
+        // The guest writes don't need to be copied anymore.
+        self.disk
+            .lock()
+            .unwrap()
+            .with_queues_quiesced(|disk| disk.set_copy_before_write(None))
+            .map_err(DiskBackupError::Disk)?
+            .map_err(DiskBackupError::Disk)?;
+        self.copy_before_write
+            .flush()
+            .map_err(DiskBackupError::Copy)?;
+        fs::rename(&self.partial, &self.target)
+            .map_err(|e| DiskBackupError::CreateTarget(self.target.clone(), e))?;
+
+        info!("Disk {} backed up to {:?}", self.id, self.target);
+        Ok(())
+    }
+
+    /// Undo a backup that didn't complete: the guest writes aren't copied
+    /// anymore, the image is removed, and the next backup copies the chunks
+    /// this one was to copy.
+    fn abort(&self) {
+        remove_copy_before_write(&self.disk);
+        if let Err(e) = fs::remove_file(&self.partial) {
+            warn!("Failed to remove {:?}: {e}", self.partial);
+        }
+        if let Some((dirty_bitmap, previous)) = &self.previous {
+            dirty_bitmap.merge(previous);
+        }
+    }
+}
+
+/// Path the image of a backup to `target` is written to until complete
+fn partial_path(target: &Path) -> PathBuf {
+    let mut path = target.as_os_str().to_owned();
+    path.push(PARTIAL_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Stop copying the chunks of `disk` the guest writes
+fn remove_copy_before_write(disk: &Mutex<Block>) {
+    if let Err(e) = disk
+        .lock()
+        .unwrap()
+        .with_queues_quiesced(|disk| disk.set_copy_before_write(None))
+        .and_then(|result| result)
+    {
+        warn!("Failed to stop copying the writes to the disk: {e}");
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright © 2025 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Disk commit
+//!
+//! Merges the overlay a disk was switched to by a live snapshot back into
+//! its backing file, from a dedicated thread, while the guest keeps using
+//! the overlay. Each pass copies the chunks written so far, until few enough
+//! are left to copy them with the queues of the disk stopped, before
+//! switching the disk to the backing file.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use block::commit::DiskCommit;
+use block::dirty_bitmap::DirtyBitmap;
+use block::qcow::{self, QcowFile, RawFile};
+use block::{BlockBackend, ImageType};
+use event_monitor::event;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use virtio_devices::Block;
+
+use crate::device_manager::{DeviceManagerError, open_disk_file, open_disk_image};
+use crate::vm_config::{DiskConfig, VmConfig};
+
+/// Number of dirty bytes small enough to be copied with the queues stopped
+const PIVOT_DIRTY_BYTES: u64 = 16 << 20;
+
+/// Number of passes after which the disk is switched even if the guest
+/// writes faster than the chunks are copied
+const MAX_COPY_PASSES: u32 = 32;
+
+#[derive(Debug, Error)]
+pub enum DiskCommitError {
+    #[error("Disk {0:?} is not a qcow2 overlay")]
+    NotAnOverlay(PathBuf),
+    #[error("Failed to open {0:?}")]
+    OpenFile(PathBuf, #[source] io::Error),
+    #[error("Failed to open the image {0:?}")]
+    OpenImage(PathBuf, #[source] qcow::Error),
+    #[error("Failed to read the clusters allocated in the overlay")]
+    ReadAllocatedClusters(#[source] io::Error),
+    #[error("Failed to open the backing file as the disk image")]
+    OpenDiskImage(#[source] Box<DeviceManagerError>),
+    #[error("Failed to flush the overlay")]
+    Flush(#[source] block::async_io::AsyncIoError),
+    #[error("Failed to update the disk")]
+    Disk(#[source] virtio_devices::block::Error),
+    #[error("Failed to copy the overlay to the backing file")]
+    Copy(#[source] io::Error),
+    #[error("Failed to spawn the disk commit thread")]
+    SpawnThread(#[source] io::Error),
+    #[error("The commit was cancelled")]
+    Cancelled,
+}
+
+/// State of a disk commit
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskCommitState {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// Progress of a disk commit
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskCommitStatus {
+    pub state: DiskCommitState,
+    pub overlay: PathBuf,
+    pub backing: PathBuf,
+    pub copied_bytes: u64,
+    pub remaining_bytes: u64,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Commits the overlay of a disk to its backing file from a dedicated thread
+pub struct DiskCommitJob {
+    overlay: PathBuf,
+    backing: PathBuf,
+    dirty_bitmap: Arc<DirtyBitmap>,
+    copied: Arc<AtomicU64>,
+    cancel: Arc<AtomicBool>,
+    /// Outcome of the commit, `None` while it is running
+    result: Arc<Mutex<Option<Result<(), String>>>>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl DiskCommitJob {
+    /// Start committing the overlay `disk` uses, as configured by
+    /// `disk_cfg`, to its backing file. The chunks to copy are the clusters
+    /// allocated in the overlay, and the ones the guest writes from now on.
+    /// Once done, the disk and its configuration in `config` are switched to
+    /// the backing file, opened with io_uring or aio if supported.
+    pub fn start(
+        disk: &Arc<Mutex<Block>>,
+        config: Arc<Mutex<VmConfig>>,
+        disk_cfg: &DiskConfig,
+        io_uring_supported: bool,
+        aio_supported: bool,
+    ) -> Result<Self, DiskCommitError> {
+        let id = disk_cfg.id.clone().unwrap_or_default();
+        let overlay = disk_cfg.path.clone().unwrap_or_default();
+        if disk_cfg.image_type != ImageType::Qcow2 || disk_cfg.readonly {
+            return Err(DiskCommitError::NotAnOverlay(overlay));
+        }
+
+        let (backing, backing_cfg) = open_backing(&overlay, disk_cfg)?;
+
+        let dirty_bitmap = disk.lock().unwrap().with_queues_quiesced(|disk| {
+            // The clusters allocated so far are read from the overlay once
+            // its metadata is on disk, the queues mark the next ones.
+            let mut reader = disk.new_async_io(1).map_err(DiskCommitError::Disk)?;
+            reader.fsync(None).map_err(DiskCommitError::Flush)?;
+            let dirty_bitmap = Arc::new(allocated_clusters(&overlay)?);
+            disk.set_dirty_bitmap(Some(dirty_bitmap.clone()));
+            Ok((reader, dirty_bitmap))
+        });
+        let (reader, dirty_bitmap) = dirty_bitmap.map_err(DiskCommitError::Disk)??;
+
+        let copied = Arc::new(AtomicU64::new(0));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let result = Arc::new(Mutex::new(None));
+        let backing_path = backing_cfg.path.clone().unwrap_or_default();
+        let mut worker = DiskCommitWorker {
+            id: id.clone(),
+            commit: Some(DiskCommit::new(
+                reader,
+                backing,
+                dirty_bitmap.clone(),
+                copied.clone(),
+            )),
+            dirty_bitmap: dirty_bitmap.clone(),
+            disk: disk.clone(),
+            config,
+            backing_cfg,
+            io_uring_supported,
+            aio_supported,
+            cancel: cancel.clone(),
+        };
+        let worker_result = result.clone();
+        let worker = thread::Builder::new()
+            .name(format!("{id}_commit"))
+            .spawn(move || {
+                let result = worker.run();
+                if let Err(e) = &result {
+                    error!("Disk commit of {} failed: {e}", worker.id);
+                    remove_dirty_bitmap(&worker.disk);
+                    event!("vm", "disk-commit-failed", "id", &worker.id);
+                } else {
+                    event!("vm", "disk-commit-completed", "id", &worker.id);
+                }
+                *worker_result.lock().unwrap() = Some(result.map_err(|e| e.to_string()));
+            })
+            .map_err(|e| {
+                remove_dirty_bitmap(disk);
+                DiskCommitError::SpawnThread(e)
+            })?;
+
+        info!("Committing {overlay:?} to {backing_path:?}");
+
+        Ok(DiskCommitJob {
+            overlay,
+            backing: backing_path,
+            dirty_bitmap,
+            copied,
+            cancel,
+            result,
+            worker: Some(worker),
+        })
+    }
+
+    /// Whether the commit is still going on
+    pub fn is_running(&self) -> bool {
+        self.result.lock().unwrap().is_none()
+    }
+
+    /// Progress of the commit
+    pub fn status(&self) -> DiskCommitStatus {
+        let (state, error) = match &*self.result.lock().unwrap() {
+            None => (DiskCommitState::Running, None),
+            Some(Ok(())) => (DiskCommitState::Completed, None),
+            Some(Err(e)) => (DiskCommitState::Failed, Some(e.clone())),
+        };
+        DiskCommitStatus {
+            state,
+            overlay: self.overlay.clone(),
+            backing: self.backing.clone(),
+            copied_bytes: self.copied.load(Ordering::Acquire),
+            remaining_bytes: self.dirty_bitmap.dirty_bytes(),
+            error,
+        }
+    }
+}
+
+impl Drop for DiskCommitJob {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = worker.join();
+        }
+    }
+}
+
+struct DiskCommitWorker {
+    id: String,
+    /// Dropped before the backing file is opened as the disk image
+    commit: Option<DiskCommit>,
+    dirty_bitmap: Arc<DirtyBitmap>,
+    disk: Arc<Mutex<Block>>,
+    config: Arc<Mutex<VmConfig>>,
+    /// Configuration of the disk once switched to the backing file
+    backing_cfg: DiskConfig,
+    io_uring_supported: bool,
+    aio_supported: bool,
+    cancel: Arc<AtomicBool>,
+}
+
+impl DiskCommitWorker {
+    fn run(&mut self) -> Result<(), DiskCommitError> {
+        let commit = self.commit.as_mut().unwrap();
+        for _ in 0..MAX_COPY_PASSES {
+            commit
+                .copy_dirty(&self.cancel)
+                .map_err(DiskCommitError::Copy)?;
+            if self.cancel.load(Ordering::Acquire) {
+                return Err(DiskCommitError::Cancelled);
+            }
+            if self.dirty_bitmap.dirty_bytes() <= PIVOT_DIRTY_BYTES {
+                break;
+            }
+        }
+
+        let mut commit = self.commit.take().unwrap();
+        let backing_path = self.backing_cfg.path.clone().unwrap_or_default();
+        let overlay = self.disk.lock().unwrap().with_queues_quiesced(|disk| {
+            commit
+                .copy_dirty(&AtomicBool::new(false))
+                .and_then(|_| commit.flush())
+                .map_err(DiskCommitError::Copy)?;
+            drop(commit);
+
+            let file = open_disk_file(&self.backing_cfg)
+                .map_err(|e| DiskCommitError::OpenDiskImage(Box::new(e)))?;
+            let image = open_disk_image(
+                file,
+                &self.backing_cfg,
+                self.io_uring_supported,
+                self.aio_supported,
+            )
+            .map_err(|e| DiskCommitError::OpenDiskImage(Box::new(e)))?;
+            disk.set_dirty_bitmap(None);
+            let overlay = disk
+                .set_disk_image(image, backing_path.clone())
+                .map_err(DiskCommitError::Disk)?;
+            if let Err(e) = disk.try_lock_image() {
+                warn!("Failed to lock {backing_path:?}: {e}");
+            }
+            Ok(overlay)
+        });
+        // Closing the overlay releases its lock.
+        drop(overlay.map_err(DiskCommitError::Disk)??);
+
+        let mut config = self.config.lock().unwrap();
+        if let Some(disk_cfg) = config
+            .disks
+            .iter_mut()
+            .flatten()
+            .find(|disk_cfg| disk_cfg.id.as_deref() == Some(self.id.as_str()))
+        {
+            disk_cfg.path = Some(backing_path.clone());
+            disk_cfg.image_type = self.backing_cfg.image_type;
+            disk_cfg.backing_files = self.backing_cfg.backing_files;
+        }
+
+        info!("Disk {} switched to {backing_path:?}", self.id);
+        Ok(())
+    }
+}
+
+fn open_read_only(path: &Path) -> Result<File, DiskCommitError> {
+    File::open(path).map_err(|e| DiskCommitError::OpenFile(path.to_path_buf(), e))
+}
+
+/// Open the backing file of `overlay` for writing, and return it along with
+/// the configuration of the disk described by `disk_cfg` once switched to it
+fn open_backing(
+    overlay: &Path,
+    disk_cfg: &DiskConfig,
+) -> Result<(Box<dyn BlockBackend>, DiskConfig), DiskCommitError> {
+    let backing_file = QcowFile::from(RawFile::new(open_read_only(overlay)?, false))
+        .map_err(|e| DiskCommitError::OpenImage(overlay.to_path_buf(), e))?
+        .header()
+        .backing_file
+        .clone()
+        .ok_or_else(|| DiskCommitError::NotAnOverlay(overlay.to_path_buf()))?;
+    let path = PathBuf::from(backing_file.path);
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .map_err(|e| DiskCommitError::OpenFile(path.clone(), e))?;
+    let mut raw_file = RawFile::new(file, false);
+    let format = match backing_file.format {
+        Some(format) => format,
+        None => qcow::detect_image_type(&mut raw_file)
+            .map_err(|e| DiskCommitError::OpenImage(path.clone(), e))?,
+    };
+    let (backing, image_type): (Box<dyn BlockBackend>, _) = match format {
+        qcow::ImageType::Raw => (Box::new(raw_file), ImageType::Raw),
+        qcow::ImageType::Qcow2 => (
+            Box::new(
+                QcowFile::from(raw_file)
+                    .map_err(|e| DiskCommitError::OpenImage(path.clone(), e))?,
+            ),
+            ImageType::Qcow2,
+        ),
+    };
+
+    let backing_cfg = DiskConfig {
+        path: Some(path),
+        image_type,
+        backing_files: image_type == ImageType::Qcow2,
+        ..disk_cfg.clone()
+    };
+    Ok((backing, backing_cfg))
+}
+
+/// Bitmap of the clusters allocated in `overlay`
+fn allocated_clusters(overlay: &Path) -> Result<DirtyBitmap, DiskCommitError> {
+    let mut qcow = QcowFile::from(RawFile::new(open_read_only(overlay)?, false))
+        .map_err(|e| DiskCommitError::OpenImage(overlay.to_path_buf(), e))?;
+    let cluster_size = qcow.cluster_size();
+    let dirty_bitmap = DirtyBitmap::new(qcow.header().size, cluster_size);
+    qcow.for_each_allocated_cluster(|address| dirty_bitmap.set(address, cluster_size))
+        .map_err(DiskCommitError::ReadAllocatedClusters)?;
+    Ok(dirty_bitmap)
+}
+
+/// Stop tracking the writes to `disk` after a commit failed
+fn remove_dirty_bitmap(disk: &Mutex<Block>) {
+    if let Err(e) = disk
+        .lock()
+        .unwrap()
+        .with_queues_quiesced(|disk| disk.set_dirty_bitmap(None))
+    {
+        warn!("Failed to remove the dirty bitmap of the disk: {e}");
+    }
+}
@@ -0,0 +1,682 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Frame export
+//!
+//! Encodes the frames published by the guest, either in the ivshmem frame
+//! buffer or on a virtio-gpu scanout, so that host consumers get the pixels
+//! through the API instead of mapping the shared memory and redoing the
+//! `FrameBufferLayout` math themselves, and writes them to video and audio
+//! files when recording.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+use devices::frame_buffer::CursorShapeInfo;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum FrameExportError {
+    #[error("Unsupported {0} frame of {1}x{2}")]
+    UnsupportedFrame(String, u32, u32),
+    #[error("Frame data too short: {0} bytes, expected {1}")]
+    TruncatedFrame(usize, usize),
+    #[error("Failed to compress the PNG image data")]
+    Png(#[source] io::Error),
+    #[error("Invalid base64 data")]
+    InvalidBase64,
+    #[error("Failed to write the recording")]
+    Write(#[source] io::Error),
+}
+
+type Result<T> = std::result::Result<T, FrameExportError>;
+
+/// Rows of one plane of a frame
+type PlaneRows<'a> = Vec<&'a [u8]>;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+const PNG_COLOR_TYPE_RGB: u8 = 2;
+
+const WAV_HEADER_SIZE: u32 = 44;
+const WAV_FORMAT_PCM: u16 = 1;
+const WAV_FORMAT_IEEE_FLOAT: u16 = 3;
+
+/// Frame copied out of the guest
+#[derive(Clone, Debug, Default)]
+pub struct Frame {
+    /// Frame width in pixels
+    pub width: u32,
+    /// Frame height in pixels
+    pub height: u32,
+    /// Pixel format, named after the byte order in memory ("BGRA32", "NV12"...)
+    pub format: String,
+    /// Bytes per row, of the Y and UV planes for NV12
+    pub stride: u32,
+    /// Pixel data
+    pub data: Vec<u8>,
+    /// Sequence number of the frame
+    pub frame_number: u64,
+    /// Timestamp of the frame in nanoseconds, 0 if unknown
+    pub timestamp_ns: u64,
+    /// Buffer the frame was read from
+    pub buffer_index: u32,
+}
+
+impl Frame {
+    /// Offsets of the red, green and blue bytes of the 32-bit formats
+    fn rgb_offsets(&self) -> Option<[usize; 3]> {
+        match self.format.as_str() {
+            "BGRA32" => Some([2, 1, 0]),
+            "RGBA32" => Some([0, 1, 2]),
+            "ARGB32" => Some([1, 2, 3]),
+            "ABGR32" => Some([3, 2, 1]),
+            _ => None,
+        }
+    }
+
+    fn is_nv12(&self) -> bool {
+        self.format == "NV12"
+    }
+
+    fn unsupported(&self) -> FrameExportError {
+        FrameExportError::UnsupportedFrame(self.format.clone(), self.width, self.height)
+    }
+
+    /// Rows of `row_len` bytes of the plane starting at `offset`
+    fn plane(&self, offset: usize, rows: usize, row_len: usize) -> Result<PlaneRows<'_>> {
+        let stride = self.stride as usize;
+        if rows == 0 {
+            return Ok(Vec::new());
+        }
+        let end = offset + stride * (rows - 1) + row_len;
+        if row_len > stride || self.data.len() < end {
+            return Err(FrameExportError::TruncatedFrame(self.data.len(), end));
+        }
+
+        Ok((0..rows)
+            .map(|row| &self.data[offset + row * stride..offset + row * stride + row_len])
+            .collect())
+    }
+
+    /// Y plane rows, then UV plane rows
+    fn nv12_planes(&self) -> Result<(PlaneRows<'_>, PlaneRows<'_>)> {
+        // 4:2:0 subsampling needs even dimensions
+        if !self.width.is_multiple_of(2) || !self.height.is_multiple_of(2) {
+            return Err(self.unsupported());
+        }
+        let (width, height) = (self.width as usize, self.height as usize);
+        let y = self.plane(0, height, width)?;
+        let uv = self.plane(self.stride as usize * height, height / 2, width)?;
+        Ok((y, uv))
+    }
+
+    /// Pixel data in the frame format, without row padding
+    pub fn to_raw(&self) -> Result<Vec<u8>> {
+        let rows = if self.is_nv12() {
+            let (y, uv) = self.nv12_planes()?;
+            [y, uv].concat()
+        } else if self.rgb_offsets().is_some() {
+            self.plane(0, self.height as usize, self.width as usize * 4)?
+        } else {
+            return Err(self.unsupported());
+        };
+
+        Ok(rows.concat())
+    }
+
+    /// Pixel data as 8-bit RGB triplets, row after row
+    pub fn to_rgb(&self) -> Result<Vec<u8>> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let mut rgb = Vec::with_capacity(width * height * 3);
+
+        if self.is_nv12() {
+            let (y_rows, uv_rows) = self.nv12_planes()?;
+            for (row, y_row) in y_rows.iter().enumerate() {
+                let uv_row = uv_rows[row / 2];
+                for (col, y) in y_row.iter().enumerate() {
+                    let uv = col & !1;
+                    rgb.extend_from_slice(&yuv_to_rgb(*y, uv_row[uv], uv_row[uv + 1]));
+                }
+            }
+        } else {
+            let offsets = self.rgb_offsets().ok_or_else(|| self.unsupported())?;
+            for row in self.plane(0, height, width * 4)? {
+                for pixel in row.chunks_exact(4) {
+                    rgb.extend(offsets.iter().map(|offset| pixel[*offset]));
+                }
+            }
+        }
+
+        Ok(rgb)
+    }
+
+    /// Frame encoded as an opaque 8-bit RGB PNG image
+    pub fn to_png(&self) -> Result<Vec<u8>> {
+        let rgb = self.to_rgb()?;
+        let row_len = self.width as usize * 3;
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        if row_len > 0 {
+            for row in rgb.chunks_exact(row_len) {
+                // Filter type None
+                encoder.write_all(&[0]).map_err(FrameExportError::Png)?;
+                encoder.write_all(row).map_err(FrameExportError::Png)?;
+            }
+        }
+        let image_data = encoder.finish().map_err(FrameExportError::Png)?;
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&self.width.to_be_bytes());
+        header.extend_from_slice(&self.height.to_be_bytes());
+        // 8-bit depth, no interlacing, default compression and filter methods
+        header.extend_from_slice(&[8, PNG_COLOR_TYPE_RGB, 0, 0, 0]);
+
+        let mut png = PNG_SIGNATURE.to_vec();
+        png_chunk(&mut png, b"IHDR", &header);
+        png_chunk(&mut png, b"IDAT", &image_data);
+        png_chunk(&mut png, b"IEND", &[]);
+        Ok(png)
+    }
+
+    /// Frame converted to planar 4:2:0 YCbCr, the Y plane followed by the
+    /// U and V planes
+    pub fn to_i420(&self) -> Result<Vec<u8>> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let mut yuv = Vec::with_capacity(width * height * 3 / 2);
+
+        if self.is_nv12() {
+            let (y_rows, uv_rows) = self.nv12_planes()?;
+            yuv.extend(y_rows.concat());
+            for offset in 0..2 {
+                for row in uv_rows.iter() {
+                    yuv.extend(row.iter().skip(offset).step_by(2));
+                }
+            }
+            return Ok(yuv);
+        }
+
+        if !self.width.is_multiple_of(2) || !self.height.is_multiple_of(2) {
+            return Err(self.unsupported());
+        }
+        let rgb = self.to_rgb()?;
+        let pixel = |col: usize, row: usize| {
+            let offset = (row * width + col) * 3;
+            [rgb[offset], rgb[offset + 1], rgb[offset + 2]]
+        };
+
+        for row in 0..height {
+            yuv.extend((0..width).map(|col| rgb_to_yuv(pixel(col, row))[0]));
+        }
+        // Chroma of the average of every 2x2 block
+        let mut u = Vec::with_capacity(width * height / 4);
+        let mut v = Vec::with_capacity(width * height / 4);
+        for row in (0..height).step_by(2) {
+            for col in (0..width).step_by(2) {
+                let block = [
+                    pixel(col, row),
+                    pixel(col + 1, row),
+                    pixel(col, row + 1),
+                    pixel(col + 1, row + 1),
+                ];
+                let average = [0, 1, 2].map(|component| {
+                    let sum: u32 = block.iter().map(|p| u32::from(p[component])).sum();
+                    ((sum + 2) / 4) as u8
+                });
+                let [_, cb, cr] = rgb_to_yuv(average);
+                u.push(cb);
+                v.push(cr);
+            }
+        }
+        yuv.extend(u);
+        yuv.extend(v);
+        Ok(yuv)
+    }
+}
+
+/// Cursor image copied out of the guest
+#[derive(Clone, Debug, Default)]
+pub struct CursorImage {
+    /// Size and hotspot, `data_size` being the length of `data`
+    pub shape: CursorShapeInfo,
+    /// BGRA pixels, row after row
+    pub data: Vec<u8>,
+}
+
+/// Container of a recorded video
+#[derive(Clone, Copy, Deserialize, Serialize, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoContainer {
+    /// YUV4MPEG2 stream of 4:2:0 frames
+    #[default]
+    Y4m,
+    /// Frames in their own format without row padding, back to back
+    Raw,
+}
+
+/// Writes frames to a constant frame rate video stream
+pub struct VideoWriter<W: Write> {
+    writer: W,
+    container: VideoContainer,
+    width: u32,
+    height: u32,
+    fps: u32,
+    /// Last frame, encoded for the container
+    last_frame: Vec<u8>,
+    frames: u64,
+}
+
+impl<W: Write> VideoWriter<W> {
+    /// Stream of `width`x`height` frames at `fps` frames per second
+    pub fn new(writer: W, container: VideoContainer, width: u32, height: u32, fps: u32) -> Self {
+        VideoWriter {
+            writer,
+            container,
+            width,
+            height,
+            fps,
+            last_frame: Vec::new(),
+            frames: 0,
+        }
+    }
+
+    /// Number of frames written so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Append `frame` to the stream
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        if frame.width != self.width || frame.height != self.height {
+            return Err(frame.unsupported());
+        }
+        self.last_frame = match self.container {
+            VideoContainer::Y4m => [b"FRAME\n".to_vec(), frame.to_i420()?].concat(),
+            VideoContainer::Raw => frame.to_raw()?,
+        };
+
+        if self.frames == 0 && self.container == VideoContainer::Y4m {
+            // Chroma siting of the I420 planes, in BT.601 limited range
+            writeln!(
+                self.writer,
+                "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420jpeg XCOLORRANGE=LIMITED",
+                self.width, self.height, self.fps
+            )
+            .map_err(FrameExportError::Write)?;
+        }
+        self.repeat_frame(1)
+    }
+
+    /// Show the last frame for `count` more frame periods
+    pub fn repeat_frame(&mut self, count: u64) -> Result<()> {
+        if self.last_frame.is_empty() {
+            return Ok(());
+        }
+        for _ in 0..count {
+            self.writer
+                .write_all(&self.last_frame)
+                .map_err(FrameExportError::Write)?;
+            self.frames += 1;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush().map_err(FrameExportError::Write)
+    }
+}
+
+/// Writes interleaved PCM samples to a WAV file. The sizes in the RIFF
+/// header are only right once `finish()` has been called.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    data_size: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// WAV file of `channels` channels at `sample_rate` Hz, with integer
+    /// samples, or floating point ones if `float` is set
+    pub fn new(
+        mut writer: W,
+        channels: u16,
+        sample_rate: u32,
+        bits_per_sample: u16,
+        float: bool,
+    ) -> Result<Self> {
+        let block_align = channels * bits_per_sample.div_ceil(8);
+        let format = if float {
+            WAV_FORMAT_IEEE_FLOAT
+        } else {
+            WAV_FORMAT_PCM
+        };
+
+        let mut header = Vec::with_capacity(WAV_HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(WAV_HEADER_SIZE - 8).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&format.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * u32::from(block_align)).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&bits_per_sample.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_all(&header).map_err(FrameExportError::Write)?;
+
+        Ok(WavWriter {
+            writer,
+            data_size: 0,
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[u8]) -> Result<()> {
+        self.writer
+            .write_all(samples)
+            .map_err(FrameExportError::Write)?;
+        self.data_size = self.data_size.saturating_add(samples.len() as u32);
+        Ok(())
+    }
+
+    /// Fill in the sizes of the RIFF header and flush the file
+    pub fn finish(&mut self) -> Result<()> {
+        let riff_size = self.data_size.saturating_add(WAV_HEADER_SIZE - 8);
+        for (offset, size) in [(4, riff_size), (WAV_HEADER_SIZE - 4, self.data_size)] {
+            self.writer
+                .seek(SeekFrom::Start(offset.into()))
+                .and_then(|_| self.writer.write_all(&size.to_le_bytes()))
+                .map_err(FrameExportError::Write)?;
+        }
+        self.writer
+            .seek(SeekFrom::End(0))
+            .and_then(|_| self.writer.flush())
+            .map_err(FrameExportError::Write)?;
+        Ok(())
+    }
+}
+
+/// BT.601 limited range YCbCr to RGB
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let c = 298 * (i32::from(y) - 16);
+    let d = i32::from(u) - 128;
+    let e = i32::from(v) - 128;
+    let clamp = |value: i32| ((value + 128) >> 8).clamp(0, 255) as u8;
+    [
+        clamp(c + 409 * e),
+        clamp(c - 100 * d - 208 * e),
+        clamp(c + 516 * d),
+    ]
+}
+
+/// RGB to BT.601 limited range YCbCr
+fn rgb_to_yuv([r, g, b]: [u8; 3]) -> [u8; 3] {
+    let (r, g, b) = (i32::from(r), i32::from(g), i32::from(b));
+    let scale = |value: i32, offset: i32| (((value + 128) >> 8) + offset) as u8;
+    [
+        scale(66 * r + 129 * g + 25 * b, 16),
+        scale(-38 * r - 74 * g + 112 * b, 128),
+        scale(112 * r - 94 * g - 18 * b, 128),
+    ]
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 encoding, with padding
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decode standard base64 data, with padding
+pub fn base64_decode(encoded: &str) -> Result<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return Err(FrameExportError::InvalidBase64);
+    }
+
+    let mut data = Vec::with_capacity(encoded.len() / 4 * 3);
+    for (n, chunk) in encoded.chunks_exact(4).enumerate() {
+        let last = n + 1 == encoded.len() / 4;
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return Err(FrameExportError::InvalidBase64);
+        }
+
+        let mut group = 0u32;
+        for c in &chunk[..4 - padding] {
+            let value = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return Err(FrameExportError::InvalidBase64),
+            };
+            group = (group << 6) | u32::from(value);
+        }
+        group <<= 6 * padding;
+        data.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+
+    fn bgra_frame() -> Frame {
+        // 2x2 frame with 4 bytes of padding per row
+        Frame {
+            width: 2,
+            height: 2,
+            format: "BGRA32".to_string(),
+            stride: 12,
+            data: vec![
+                0, 0, 255, 255, 0, 255, 0, 255, 0xaa, 0xaa, 0xaa, 0xaa, //
+                255, 0, 0, 255, 255, 255, 255, 255, 0xaa, 0xaa, 0xaa, 0xaa,
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_frame_to_raw_and_rgb() {
+        let frame = bgra_frame();
+        assert_eq!(
+            frame.to_raw().unwrap(),
+            vec![
+                0, 0, 255, 255, 0, 255, 0, 255, 255, 0, 0, 255, 255, 255, 255, 255
+            ]
+        );
+        assert_eq!(
+            frame.to_rgb().unwrap(),
+            vec![255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255]
+        );
+
+        // NV12 mid gray
+        let nv12 = Frame {
+            width: 2,
+            height: 2,
+            format: "NV12".to_string(),
+            stride: 2,
+            data: vec![126, 126, 126, 126, 128, 128],
+            ..Default::default()
+        };
+        assert_eq!(nv12.to_raw().unwrap(), nv12.data);
+        assert_eq!(nv12.to_rgb().unwrap(), vec![128; 12]);
+
+        let truncated = Frame {
+            data: vec![0; 19],
+            ..bgra_frame()
+        };
+        assert!(matches!(
+            truncated.to_rgb(),
+            Err(FrameExportError::TruncatedFrame(19, 20))
+        ));
+        let unknown = Frame {
+            format: "YUY2".to_string(),
+            ..bgra_frame()
+        };
+        assert!(matches!(
+            unknown.to_png(),
+            Err(FrameExportError::UnsupportedFrame(..))
+        ));
+    }
+
+    #[test]
+    fn test_frame_to_png() {
+        let frame = bgra_frame();
+        let png = frame.to_png().unwrap();
+        assert_eq!(png[..8], PNG_SIGNATURE);
+
+        // IHDR
+        assert_eq!(&png[8..16], &[0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(&png[16..24], &[0, 0, 0, 2, 0, 0, 0, 2]);
+        assert_eq!(png[25], PNG_COLOR_TYPE_RGB);
+
+        // IDAT holds the filtered rows
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let mut rows = Vec::new();
+        ZlibDecoder::new(&png[41..41 + idat_len])
+            .read_to_end(&mut rows)
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![0, 255, 0, 0, 0, 255, 0, 0, 0, 0, 255, 255, 255, 255]
+        );
+
+        // IEND with its well known CRC
+        assert_eq!(
+            &png[png.len() - 12..],
+            &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]
+        );
+    }
+
+    #[test]
+    fn test_frame_to_i420() {
+        // Red, green, blue and white average to mid gray
+        assert_eq!(
+            bgra_frame().to_i420().unwrap(),
+            vec![82, 144, 41, 235, 128, 128]
+        );
+
+        let nv12 = Frame {
+            width: 2,
+            height: 2,
+            format: "NV12".to_string(),
+            stride: 2,
+            data: vec![16, 32, 48, 64, 90, 240],
+            ..Default::default()
+        };
+        assert_eq!(nv12.to_i420().unwrap(), vec![16, 32, 48, 64, 90, 240]);
+    }
+
+    #[test]
+    fn test_video_writer() {
+        let frame = bgra_frame();
+        let mut writer = VideoWriter::new(Vec::new(), VideoContainer::Y4m, 2, 2, 30);
+        writer.repeat_frame(2).unwrap();
+        assert_eq!(writer.frames(), 0);
+        writer.write_frame(&frame).unwrap();
+        writer.repeat_frame(1).unwrap();
+        assert_eq!(writer.frames(), 2);
+
+        let header = b"YUV4MPEG2 W2 H2 F30:1 Ip A1:1 C420jpeg XCOLORRANGE=LIMITED\n";
+        let y4m_frame = b"FRAME\n\x52\x90\x29\xeb\x80\x80";
+        assert_eq!(
+            writer.writer,
+            [&header[..], &y4m_frame[..], &y4m_frame[..]].concat()
+        );
+
+        let mut writer = VideoWriter::new(Vec::new(), VideoContainer::Raw, 2, 2, 30);
+        writer.write_frame(&frame).unwrap();
+        assert_eq!(writer.writer, frame.to_raw().unwrap());
+        // The stream size is fixed
+        let resized = Frame {
+            width: 4,
+            stride: 16,
+            data: vec![0; 32],
+            ..bgra_frame()
+        };
+        assert!(writer.write_frame(&resized).is_err());
+    }
+
+    #[test]
+    fn test_wav_writer() {
+        let mut writer = WavWriter::new(io::Cursor::new(Vec::new()), 2, 48000, 16, false).unwrap();
+        writer.write_samples(&[1, 2, 3, 4]).unwrap();
+        writer.write_samples(&[5, 6, 7, 8]).unwrap();
+        writer.finish().unwrap();
+
+        let wav = writer.writer.into_inner();
+        assert_eq!(wav.len(), WAV_HEADER_SIZE as usize + 8);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), 44);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        // PCM, 2 channels, 48 kHz, 192000 bytes per second, 4 bytes blocks,
+        // 16 bits per sample
+        assert_eq!(
+            &wav[20..36],
+            &[1, 0, 2, 0, 0x80, 0xbb, 0, 0, 0, 0xee, 2, 0, 4, 0, 16, 0]
+        );
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 8);
+        assert_eq!(&wav[44..], &[1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_base64() {
+        for (data, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64_encode(data), encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), data);
+        }
+
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(base64_decode(&base64_encode(&data)).unwrap(), data);
+
+        base64_decode("Zm9").unwrap_err();
+        base64_decode("Zg==Zm8=").unwrap_err();
+        base64_decode("Zm9*").unwrap_err();
+    }
+}
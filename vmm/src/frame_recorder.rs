@@ -0,0 +1,625 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Frame recording
+//!
+//! Records the frames published by the guest agent in the ivshmem frame
+//! buffer to a video file, and the samples of its audio ring to a WAV file
+//! next to it, until the recording is stopped or the VM shuts down.
+//!
+//! The video has a constant frame rate, the frames are placed on its
+//! timeline according to `FrameMetadata::timestamp_ns`. Frames published
+//! faster than the frame rate are dropped, and the previous frame is repeated
+//! while the guest doesn't publish any.
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use devices::frame_buffer::{
+    AUDIO_BUFFER_MAGIC, AUDIO_BUFFER_VERSION, AudioBufferHeader, AudioFormat,
+};
+use devices::{FrameBufferHeader, FrameBufferLayout, FrameMetadata};
+use log::{error, info, warn};
+use thiserror::Error;
+use vm_memory::bitmap::AtomicBitmap;
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::frame_export::{Frame, FrameExportError, VideoContainer, VideoWriter, WavWriter};
+use crate::vm_config::FrameBufferConfig;
+
+type MmapRegion = vm_memory::MmapRegion<AtomicBitmap>;
+
+/// Interval at which the frame buffer and the audio ring are read
+pub const FRAME_RECORDER_POLL_INTERVAL: Duration = Duration::from_millis(4);
+
+/// Highest supported frame rate
+pub const MAX_RECORD_FPS: u32 = 240;
+
+#[derive(Debug, Error)]
+pub enum FrameRecorderError {
+    #[error("Invalid frame rate: {0}")]
+    InvalidFrameRate(u32),
+    #[error("Failed to create {0:?}")]
+    CreateFile(PathBuf, #[source] io::Error),
+    #[error("Failed to create the frame recorder kill event")]
+    CreateKillEvent(#[source] io::Error),
+    #[error("Failed to spawn the frame recorder thread")]
+    SpawnThread(#[source] io::Error),
+    #[error("Failed to wait for the frame recorder events")]
+    Epoll(#[source] io::Error),
+    #[error("Failed to record the frames")]
+    Record(#[source] FrameExportError),
+}
+
+/// Copy the last frame published by the guest agent in the frame buffer
+/// starting at `base`, `None` until the first one
+///
+/// # Safety
+///
+/// `base` must point to a frame buffer described by `layout`, mapped for
+/// its whole size.
+pub unsafe fn read_frame(
+    base: *const u8,
+    layout: &FrameBufferLayout,
+    config: &FrameBufferConfig,
+) -> Option<Frame> {
+    // Number of times the copy is retried while the guest keeps
+    // overwriting the buffer
+    const MAX_ATTEMPTS: u32 = 3;
+
+    // SAFETY: the header sits at the start of the frame buffer
+    let header = unsafe { &*(base as *const FrameBufferHeader) };
+    for _ in 0..MAX_ATTEMPTS {
+        let (active_index, frame_number) = header.read_frame_info();
+        if frame_number == 0 {
+            return None;
+        }
+        if active_index >= layout.buffer_count {
+            warn!("Ignoring frame buffer with invalid active index {active_index}");
+            return None;
+        }
+
+        // SAFETY: the metadata entry and the buffer lie within the frame
+        // buffer, the index was checked against the layout above
+        let (metadata, data) = unsafe {
+            let metadata = std::ptr::read_volatile(
+                base.add(layout.metadata_offset_for(active_index)) as *const FrameMetadata,
+            );
+            let data = std::slice::from_raw_parts(
+                base.add(layout.data_offset_for(active_index)),
+                layout.buffer_size as usize,
+            );
+            (metadata, data.to_vec())
+        };
+
+        // The guest agent only writes to the buffer following the active
+        // one. The copy is consistent unless the agent went all the way
+        // around the ring and started writing to this buffer again.
+        let published = header.frame_count().wrapping_sub(frame_number);
+        if published + 1 < layout.buffer_count as u64 {
+            return Some(Frame {
+                width: config.width,
+                height: config.height,
+                format: config.format.clone(),
+                stride: match config.format.as_str() {
+                    "NV12" => config.width,
+                    _ => config.width * 4,
+                },
+                data,
+                frame_number,
+                timestamp_ns: metadata.timestamp_ns,
+                buffer_index: active_index,
+            });
+        }
+    }
+
+    warn!("Frame buffer overwritten while being copied");
+    None
+}
+
+/// Records the frame buffer to a video file, and its audio ring to a WAV
+/// file, from a dedicated thread
+pub struct FrameRecorder {
+    path: PathBuf,
+    kill_evt: EventFd,
+    frames: Arc<AtomicU64>,
+    running: Arc<AtomicBool>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl FrameRecorder {
+    /// Record the frame buffer described by `layout` and `config`, starting
+    /// at the beginning of `region`, to a `container` video at `path` with
+    /// at most `fps` frames per second. The audio goes to the same path with
+    /// a `.wav` extension, once the guest agent set up the audio ring.
+    pub fn new(
+        path: &Path,
+        fps: u32,
+        container: VideoContainer,
+        region: Arc<MmapRegion>,
+        layout: FrameBufferLayout,
+        config: FrameBufferConfig,
+    ) -> Result<Self, FrameRecorderError> {
+        if fps == 0 || fps > MAX_RECORD_FPS {
+            return Err(FrameRecorderError::InvalidFrameRate(fps));
+        }
+
+        let file = File::create(path)
+            .map_err(|e| FrameRecorderError::CreateFile(path.to_path_buf(), e))?;
+        let kill_evt =
+            EventFd::new(libc::EFD_NONBLOCK).map_err(FrameRecorderError::CreateKillEvent)?;
+        let frames = Arc::new(AtomicU64::new(0));
+        let running = Arc::new(AtomicBool::new(true));
+
+        // SAFETY: the header sits at the start of the region
+        let header = unsafe { &*(region.as_ptr() as *const FrameBufferHeader) };
+        let mut worker = FrameRecorderWorker {
+            video: VideoWriter::new(
+                BufWriter::new(file),
+                container,
+                config.width,
+                config.height,
+                fps,
+            ),
+            fps,
+            start_ns: None,
+            last_frame: 0,
+            first_new_frame: header.frame_count() + 1,
+            audio_path: path.with_extension("wav"),
+            audio: None,
+            region,
+            layout,
+            config,
+            kill_evt: kill_evt
+                .try_clone()
+                .map_err(FrameRecorderError::CreateKillEvent)?,
+            frames: frames.clone(),
+        };
+        let worker_running = running.clone();
+        let worker = thread::Builder::new()
+            .name("frame-recorder".to_string())
+            .spawn(move || {
+                if let Err(e) = worker.run() {
+                    error!("Frame recorder thread failed: {e}");
+                }
+                worker_running.store(false, Ordering::Release);
+            })
+            .map_err(FrameRecorderError::SpawnThread)?;
+
+        info!("Recording frames to {path:?} at up to {fps} frames per second");
+
+        Ok(FrameRecorder {
+            path: path.to_path_buf(),
+            kill_evt,
+            frames,
+            running,
+            worker: Some(worker),
+        })
+    }
+
+    /// Video file being written
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of frames written to the video so far
+    pub fn recorded_frames(&self) -> u64 {
+        self.frames.load(Ordering::Acquire)
+    }
+
+    /// Whether the recording is still going on, it stops on errors
+    pub fn is_recording(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for FrameRecorder {
+    fn drop(&mut self) {
+        // Ignore the results because there is nothing we can do about it.
+        let _ = self.kill_evt.write(1);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        info!(
+            "Recorded {} frames to {:?}",
+            self.recorded_frames(),
+            self.path
+        );
+    }
+}
+
+struct AudioRecording {
+    wav: WavWriter<BufWriter<File>>,
+    buffer_size: u32,
+}
+
+struct FrameRecorderWorker {
+    video: VideoWriter<BufWriter<File>>,
+    fps: u32,
+    /// Timestamp of the first frame of the video timeline
+    start_ns: Option<u64>,
+    /// Number of the last frame read from the frame buffer
+    last_frame: u64,
+    /// Number of the first frame published after the recording started
+    first_new_frame: u64,
+    audio_path: PathBuf,
+    audio: Option<AudioRecording>,
+    region: Arc<MmapRegion>,
+    layout: FrameBufferLayout,
+    config: FrameBufferConfig,
+    kill_evt: EventFd,
+    frames: Arc<AtomicU64>,
+}
+
+impl FrameRecorderWorker {
+    fn run(&mut self) -> Result<(), FrameRecorderError> {
+        let epoll = Epoll::new().map_err(FrameRecorderError::Epoll)?;
+        epoll
+            .ctl(
+                ControlOperation::Add,
+                self.kill_evt.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, 0),
+            )
+            .map_err(FrameRecorderError::Epoll)?;
+
+        let timeout = FRAME_RECORDER_POLL_INTERVAL.as_millis() as i32;
+        let mut events = [EpollEvent::default(); 1];
+        let result = loop {
+            match epoll.wait(timeout, &mut events) {
+                Ok(0) => {}
+                Ok(_) => break Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(FrameRecorderError::Epoll(e)),
+            }
+
+            if let Err(e) = self.record_frame().and_then(|_| self.record_audio()) {
+                break Err(FrameRecorderError::Record(e));
+            }
+        };
+
+        // Keep whatever was recorded so far playable
+        let audio = self.record_audio();
+        if let Some(audio) = self.audio.as_mut() {
+            audio.wav.finish().map_err(FrameRecorderError::Record)?;
+        }
+        self.video.flush().map_err(FrameRecorderError::Record)?;
+        result.and(audio.map_err(FrameRecorderError::Record))
+    }
+
+    fn record_frame(&mut self) -> Result<(), FrameExportError> {
+        // SAFETY: the region holds the frame buffer described by the layout,
+        // which the device manager checked when creating it
+        let frame = unsafe { read_frame(self.region.as_ptr(), &self.layout, &self.config) };
+        let Some(frame) = frame.filter(|frame| frame.frame_number != self.last_frame) else {
+            return Ok(());
+        };
+        self.last_frame = frame.frame_number;
+
+        let period_ns = 1_000_000_000 / u64::from(self.fps);
+        if frame.frame_number < self.first_new_frame {
+            // The frame on screen when the recording started opens the
+            // video, the timeline starts with the next one.
+            self.video.write_frame(&frame)?;
+        } else {
+            let timestamp_ns = match frame.timestamp_ns {
+                0 => monotonic_ns(),
+                timestamp_ns => timestamp_ns,
+            };
+            let start_ns = *self
+                .start_ns
+                .get_or_insert(timestamp_ns.saturating_sub(self.video.frames() * period_ns));
+            let slot = timestamp_ns.saturating_sub(start_ns) / period_ns;
+            // Cap the frame rate
+            if slot < self.video.frames() {
+                return Ok(());
+            }
+            self.video.repeat_frame(slot - self.video.frames())?;
+            self.video.write_frame(&frame)?;
+        }
+
+        self.frames.store(self.video.frames(), Ordering::Release);
+        Ok(())
+    }
+
+    /// Header of the audio ring, once set up by the guest agent
+    ///
+    /// The header is accessed through a raw pointer since the guest could
+    /// have stored any value in its `format` field.
+    fn audio_header(&self) -> Option<*const AudioBufferHeader> {
+        let offset = self.layout.audio_offset_for();
+        if self.region.size() < offset + std::mem::size_of::<AudioBufferHeader>() {
+            return None;
+        }
+
+        // SAFETY: the header lies within the region as checked above
+        let header = unsafe { self.region.as_ptr().add(offset) } as *const AudioBufferHeader;
+        // SAFETY: the header is mapped and its integer fields have no invalid
+        // values
+        let (magic, version) = unsafe {
+            (
+                std::ptr::read_volatile(&raw const (*header).magic),
+                std::ptr::read_volatile(&raw const (*header).version),
+            )
+        };
+        (magic == AUDIO_BUFFER_MAGIC && version == AUDIO_BUFFER_VERSION).then_some(header)
+    }
+
+    fn start_audio(&mut self, header: *const AudioBufferHeader) -> Result<(), FrameExportError> {
+        // SAFETY: the header is mapped and the format is read as an integer
+        let (format, sample_rate, channels, buffer_size) = unsafe {
+            (
+                std::ptr::read_volatile(&raw const (*header).format as *const u32),
+                std::ptr::read_volatile(&raw const (*header).sample_rate),
+                std::ptr::read_volatile(&raw const (*header).channels),
+                std::ptr::read_volatile(&raw const (*header).buffer_size),
+            )
+        };
+        let ring_end = self.layout.audio_offset_for()
+            + std::mem::size_of::<AudioBufferHeader>()
+            + buffer_size as usize;
+        let Ok(format) = AudioFormat::try_from(format) else {
+            return Ok(());
+        };
+        if buffer_size == 0 || channels == 0 || ring_end > self.region.size() {
+            return Ok(());
+        }
+
+        // Only the samples from now on are recorded
+        // SAFETY: the positions are atomics within the mapped header
+        unsafe {
+            let write_pos = &(*header).write_pos;
+            let read_pos = &(*header).read_pos;
+            read_pos.store(write_pos.load(Ordering::Acquire), Ordering::Release);
+        }
+
+        let file = File::create(&self.audio_path).map_err(FrameExportError::Write)?;
+        let wav = WavWriter::new(
+            BufWriter::new(file),
+            channels.into(),
+            sample_rate,
+            u16::from(format.bytes_per_sample()) * 8,
+            format == AudioFormat::FloatLe,
+        )?;
+        info!(
+            "Recording {channels} channels {format:?} audio at {sample_rate} Hz to {:?}",
+            self.audio_path
+        );
+        self.audio = Some(AudioRecording { wav, buffer_size });
+        Ok(())
+    }
+
+    fn record_audio(&mut self) -> Result<(), FrameExportError> {
+        let Some(header) = self.audio_header() else {
+            return Ok(());
+        };
+        if self.audio.is_none() {
+            return self.start_audio(header);
+        }
+        let audio = self.audio.as_mut().unwrap();
+
+        // SAFETY: the positions are atomics within the mapped header
+        let (write_pos, read_pos) = unsafe { (&(*header).write_pos, &(*header).read_pos) };
+        let write = write_pos.load(Ordering::Acquire);
+        let read = read_pos.load(Ordering::Acquire);
+        if write >= audio.buffer_size || read >= audio.buffer_size {
+            return Ok(());
+        }
+
+        // SAFETY: the ring was checked to lie within the region
+        let ring = unsafe {
+            std::slice::from_raw_parts(
+                (header as *const u8).add(std::mem::size_of::<AudioBufferHeader>()),
+                audio.buffer_size as usize,
+            )
+        };
+        let (read, write) = (read as usize, write as usize);
+        if write >= read {
+            audio.wav.write_samples(&ring[read..write])?;
+        } else {
+            audio.wav.write_samples(&ring[read..])?;
+            audio.wav.write_samples(&ring[..write])?;
+        }
+        read_pos.store(write as u32, Ordering::Release);
+        Ok(())
+    }
+}
+
+fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: FFI call with a valid timespec
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use devices::FrameFormat;
+    use devices::frame_buffer::FrameFlags;
+    use vmm_sys_util::tempdir::TempDir;
+
+    use super::*;
+
+    const WIDTH: u32 = 4;
+    const HEIGHT: u32 = 2;
+
+    fn frame_buffer() -> (Arc<MmapRegion>, FrameBufferLayout, FrameBufferConfig) {
+        let buffer_size = u64::from(WIDTH * HEIGHT * 4);
+        let layout = FrameBufferLayout::new(3, buffer_size);
+        let audio_size = std::mem::size_of::<AudioBufferHeader>() + 64;
+        let region = Arc::new(MmapRegion::new(layout.audio_offset_for() + audio_size).unwrap());
+        // SAFETY: the region is large enough for the frame buffer
+        unsafe {
+            (region.as_ptr() as *mut FrameBufferHeader).write(FrameBufferHeader::new(
+                3,
+                buffer_size,
+                WIDTH,
+                HEIGHT,
+                FrameFormat::Bgra32,
+            ));
+        }
+        let config = FrameBufferConfig {
+            width: WIDTH,
+            height: HEIGHT,
+            format: "BGRA32".to_string(),
+            buffer_count: 3,
+            notify_socket: None,
+        };
+        (region, layout, config)
+    }
+
+    /// Publish a frame filled with `value` at `timestamp_ns`, as the guest
+    /// agent does
+    fn publish(region: &MmapRegion, layout: &FrameBufferLayout, value: u8, timestamp_ns: u64) {
+        // SAFETY: the region holds the frame buffer described by the layout
+        unsafe {
+            let header = &*(region.as_ptr() as *const FrameBufferHeader);
+            let index = header.begin_write_frame();
+            std::ptr::write_bytes(
+                region.as_ptr().add(layout.data_offset_for(index)),
+                value,
+                layout.buffer_size as usize,
+            );
+            (region.as_ptr().add(layout.metadata_offset_for(index)) as *mut FrameMetadata)
+                .write(FrameMetadata::new(0, timestamp_ns, 0, FrameFlags::empty()));
+            header.end_write_frame(index);
+        }
+    }
+
+    #[test]
+    fn test_read_frame() {
+        let (region, layout, config) = frame_buffer();
+        // SAFETY: the region holds the frame buffer described by the layout
+        let read = || unsafe { read_frame(region.as_ptr(), &layout, &config) };
+        assert!(read().is_none());
+
+        publish(&region, &layout, 0x11, 1000);
+        let frame = read().unwrap();
+        assert_eq!(frame.frame_number, 1);
+        assert_eq!(frame.buffer_index, 1);
+        assert_eq!(frame.timestamp_ns, 1000);
+        assert_eq!(frame.stride, WIDTH * 4);
+        assert_eq!(frame.data, vec![0x11; (WIDTH * HEIGHT * 4) as usize]);
+    }
+
+    #[test]
+    fn test_frame_recorder_timeline() {
+        let (region, layout, config) = frame_buffer();
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("capture.raw");
+        let file = File::create(&path).unwrap();
+
+        // Drive the worker by hand
+        let mut worker = FrameRecorderWorker {
+            video: VideoWriter::new(BufWriter::new(file), VideoContainer::Raw, WIDTH, HEIGHT, 10),
+            fps: 10,
+            start_ns: None,
+            last_frame: 0,
+            first_new_frame: 1,
+            audio_path: path.with_extension("wav"),
+            audio: None,
+            region: region.clone(),
+            layout: layout.clone(),
+            config,
+            kill_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            frames: Arc::new(AtomicU64::new(0)),
+        };
+        const MS: u64 = 1_000_000;
+        for (value, timestamp_ns) in [(1, 1000 * MS), (2, 1020 * MS), (3, 1250 * MS)] {
+            publish(&region, &layout, value, timestamp_ns);
+            worker.record_frame().unwrap();
+            // Already recorded
+            worker.record_frame().unwrap();
+        }
+        worker.video.flush().unwrap();
+
+        // The second frame is dropped by the 10 fps cap, the first one is
+        // shown until the third one 250ms later
+        let frame_size = (WIDTH * HEIGHT * 4) as usize;
+        let video = std::fs::read(&path).unwrap();
+        let frames: Vec<u8> = video.chunks(frame_size).map(|frame| frame[0]).collect();
+        assert_eq!(frames, vec![1, 1, 3]);
+        assert_eq!(worker.frames.load(Ordering::Acquire), 3);
+    }
+
+    #[test]
+    fn test_frame_recorder_audio() {
+        let (region, layout, config) = frame_buffer();
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("capture.y4m");
+
+        // Guest agent audio ring setup
+        // SAFETY: the region is large enough for the audio ring
+        let (header, ring) = unsafe {
+            let header_ptr =
+                region.as_ptr().add(layout.audio_offset_for()) as *mut AudioBufferHeader;
+            header_ptr.write(AudioBufferHeader::new(AudioFormat::PcmS16Le, 48000, 2, 64));
+            let ring = (header_ptr as *mut u8).add(std::mem::size_of::<AudioBufferHeader>());
+            (&*header_ptr, ring)
+        };
+        // Samples already in the ring are not recorded
+        header.write_pos.store(60, Ordering::Release);
+
+        let recorder = FrameRecorder::new(
+            &path,
+            30,
+            VideoContainer::Y4m,
+            region.clone(),
+            layout,
+            config,
+        )
+        .unwrap();
+        assert!(recorder.is_recording());
+        // Wait for the recorder to pick the audio ring up
+        let wav_path = path.with_extension("wav");
+        while !wav_path.exists() {
+            thread::sleep(FRAME_RECORDER_POLL_INTERVAL);
+        }
+
+        // Wrap around the end of the ring
+        let write_pos = header.write_pos.load(Ordering::Acquire) as usize;
+        for i in 0..8 {
+            // SAFETY: the offset is within the ring
+            unsafe { ring.add((write_pos + i) % 64).write(i as u8 + 1) };
+        }
+        header
+            .write_pos
+            .store(((write_pos + 8) % 64) as u32, Ordering::Release);
+        while header.read_pos.load(Ordering::Acquire) != header.write_pos.load(Ordering::Acquire) {
+            thread::sleep(FRAME_RECORDER_POLL_INTERVAL);
+        }
+        drop(recorder);
+
+        let wav = std::fs::read(&wav_path).unwrap();
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 8);
+        assert_eq!(&wav[44..], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        // No frame published
+        assert!(std::fs::read(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_frame_recorder_invalid_fps() {
+        let (region, layout, config) = frame_buffer();
+        let dir = TempDir::new().unwrap();
+        assert!(matches!(
+            FrameRecorder::new(
+                &dir.as_path().join("capture.y4m"),
+                0,
+                VideoContainer::Y4m,
+                region,
+                layout,
+                config,
+            ),
+            Err(FrameRecorderError::InvalidFrameRate(0))
+        ));
+    }
+}
@@ -37,9 +37,16 @@
 //! backend.inject_keyboard(&event)?;
 //! ```
 
-use super::event::{InputEvent, KeyboardEvent, MouseEvent};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use super::event::{InputEvent, KeyboardEvent, MouseEvent, TouchAction, TouchEvent};
 use super::{InputError, Result};
-use devices::usb::hid::SharedUsbHidDevice;
+use devices::legacy::{I8042Device, MouseProtocol};
+use devices::usb::hid::{HidState, SharedUsbHidDevice, TABLET_ABS_MAX, send_report};
+use virtio_devices::{PointerMode, VIRTIO_INPUT_ABS_MAX};
 
 /// Stealth level indicates how detectable the input backend is.
 ///
@@ -108,7 +115,8 @@ pub enum StealthLevel {
 pub struct InputCapabilities {
     /// Maximum keyboard rate (events per second).
     ///
-    /// Exceeding this rate may result in dropped events or errors.
+    /// The input manager paces the events sent to the backend to this
+    /// rate, holding back the ones coming faster.
     pub max_keyboard_rate: u32,
     /// Supports absolute mouse positioning.
     ///
@@ -116,7 +124,8 @@ pub struct InputCapabilities {
     pub supports_absolute_mouse: bool,
     /// Supports multi-touch input.
     ///
-    /// When true, the backend can handle multiple simultaneous touch points.
+    /// When true, the backend can handle multiple simultaneous touch points
+    /// through [`inject_touch`](InputBackend::inject_touch).
     pub supports_multi_touch: bool,
     /// Supports scroll wheel.
     ///
@@ -155,6 +164,45 @@ impl Default for InputCapabilities {
     }
 }
 
+/// Keyboard LEDs lit by the guest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyboardLeds {
+    pub num_lock: bool,
+    pub caps_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl KeyboardLeds {
+    /// Decode a bitmask ordered like the evdev LED codes, which the USB HID
+    /// boot keyboard output report shares: bit 0 is Num Lock, bit 1 Caps
+    /// Lock and bit 2 Scroll Lock.
+    pub fn from_bits(bits: u32) -> Self {
+        Self {
+            num_lock: bits & 0x01 != 0,
+            caps_lock: bits & 0x02 != 0,
+            scroll_lock: bits & 0x04 != 0,
+        }
+    }
+}
+
+/// State the guest driver set up on a backend.
+///
+/// Input can be injected before the guest is ready, but it is lost until
+/// the driver enables the device; automation waits for the `*_ready`
+/// flags instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackendState {
+    /// The guest driver enabled the keyboard.
+    pub keyboard_ready: bool,
+    /// The guest driver enabled the mouse, tablet or touchscreen.
+    pub pointer_ready: bool,
+    /// Keyboard LEDs set by the guest.
+    pub leds: KeyboardLeds,
+    /// Protocol the guest negotiated with the PS/2 mouse.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mouse_protocol: Option<MouseProtocol>,
+}
+
 /// Input backend trait for unified input injection.
 ///
 /// All input backends must implement this trait to provide
@@ -213,11 +261,28 @@ pub trait InputBackend: Send {
     /// initialized or the device is in an error state.
     fn is_ready(&self) -> bool;
 
+    /// Get the state the guest driver set up on the devices.
+    ///
+    /// # Default Implementation
+    ///
+    /// The default implementation reports both devices as ready along
+    /// with the backend, and no LEDs.
+    fn state(&self) -> BackendState {
+        BackendState {
+            keyboard_ready: self.is_ready(),
+            pointer_ready: self.is_ready(),
+            ..Default::default()
+        }
+    }
+
     /// Inject a keyboard event.
     ///
     /// # Errors
     ///
     /// Returns [`InputError::DeviceNotReady`] if the backend is not ready.
+    /// Returns [`InputError::BufferOverflow`] if the device can't take the
+    /// event until the guest reads the previous ones, in which case nothing
+    /// was sent.
     /// Returns [`InputError::InjectionFailed`] if the injection fails.
     fn inject_keyboard(&mut self, event: &KeyboardEvent) -> Result<()>;
 
@@ -226,9 +291,25 @@ pub trait InputBackend: Send {
     /// # Errors
     ///
     /// Returns [`InputError::DeviceNotReady`] if the backend is not ready.
+    /// Returns [`InputError::BufferOverflow`] if the device can't take the
+    /// event until the guest reads the previous ones, in which case nothing
+    /// was sent.
     /// Returns [`InputError::InjectionFailed`] if the injection fails.
     fn inject_mouse(&mut self, event: &MouseEvent) -> Result<()>;
 
+    /// Inject a touch event.
+    ///
+    /// # Default Implementation
+    ///
+    /// The default implementation returns [`InputError::UnsupportedAction`],
+    /// for the backends without a touch device.
+    fn inject_touch(&mut self, _event: &TouchEvent) -> Result<()> {
+        Err(InputError::UnsupportedAction(format!(
+            "{} backend doesn't support touch input",
+            self.name()
+        )))
+    }
+
     /// Inject a generic input event.
     ///
     /// This is a convenience method that dispatches to the appropriate
@@ -237,19 +318,23 @@ pub trait InputBackend: Send {
     /// # Default Implementation
     ///
     /// The default implementation matches on the event type and calls
-    /// [`inject_keyboard`](InputBackend::inject_keyboard) or
-    /// [`inject_mouse`](InputBackend::inject_mouse) accordingly.
+    /// [`inject_keyboard`](InputBackend::inject_keyboard),
+    /// [`inject_mouse`](InputBackend::inject_mouse) or
+    /// [`inject_touch`](InputBackend::inject_touch) accordingly.
     fn inject(&mut self, event: &InputEvent) -> Result<()> {
         match event {
             InputEvent::Keyboard(kb) => self.inject_keyboard(kb),
             InputEvent::Mouse(m) => self.inject_mouse(m),
+            InputEvent::Touch(t) => self.inject_touch(t),
         }
     }
 
     /// Flush any pending events.
     ///
     /// Some backends may buffer events for efficiency. This method
-    /// forces any buffered events to be sent immediately.
+    /// forces any buffered events to be sent immediately, and returns
+    /// [`InputError::BufferOverflow`] when the device can't take all of
+    /// them yet.
     ///
     /// # Default Implementation
     ///
@@ -259,6 +344,16 @@ pub trait InputBackend: Send {
     }
 }
 
+/// Scale a screen coordinate to an absolute axis ranging from 0 to `max`.
+///
+/// The coordinate is clamped to the screen, so that the last pixel maps to
+/// `max` whatever the resolution.
+fn scale_to_axis(value: i32, size: u32, max: u32) -> u32 {
+    let last = i64::from(size.max(2) - 1);
+    let value = i64::from(value).clamp(0, last);
+    (value * i64::from(max) / last) as u32
+}
+
 // ============================================================================
 // PS/2 Backend (i8042)
 // ============================================================================
@@ -283,6 +378,13 @@ pub trait InputBackend: Send {
 /// - Only supports relative mouse positioning
 /// - No multi-touch support
 /// - Lower event rate compared to VirtIO
+/// - The i8042 buffers only 16 bytes; the packets of a long movement
+///   which don't fit wait in the backend until [`flush`](InputBackend::flush)
+///
+/// # Setup
+///
+/// The backend must be connected to the VM's i8042 controller using
+/// [`set_device`](Ps2Backend::set_device) before it can inject events.
 ///
 /// # Example
 ///
@@ -291,26 +393,36 @@ pub trait InputBackend: Send {
 /// use vmm::input::event::{KeyboardEvent, KeyboardAction};
 ///
 /// let mut backend = Ps2Backend::new();
+/// backend.set_device(i8042);
 ///
 /// // Check if ready
 /// if backend.is_ready() {
 ///     let event = KeyboardEvent {
 ///         action: KeyboardAction::Type,
 ///         code: 0x1E, // A key
-///         modifiers: Default::default(),
+///         ..Default::default()
 ///     };
 ///     backend.inject_keyboard(&event)?;
 /// }
 /// ```
 pub struct Ps2Backend {
     capabilities: InputCapabilities,
+    /// Reference to the i8042 controller for injection
+    device: Option<Arc<Mutex<I8042Device>>>,
+    /// Buttons currently held, reported in every mouse packet
+    buttons: devices::legacy::MouseButtons,
+    /// Mouse packets waiting for room in the i8042 buffer
+    backlog: VecDeque<devices::legacy::MouseEvent>,
 }
 
 impl Ps2Backend {
+    /// Largest movement a single i8042 mouse packet carries
+    const MAX_MOUSE_DELTA: i32 = 127;
+
     /// Create a new PS/2 backend.
     ///
-    /// The PS/2 backend is always ready once created, as it uses
-    /// the emulated i8042 controller which is always available.
+    /// The backend is created in a not-ready state. You must call
+    /// [`set_device`](Ps2Backend::set_device) before injecting events.
     pub fn new() -> Self {
         Self {
             capabilities: InputCapabilities {
@@ -323,6 +435,97 @@ impl Ps2Backend {
                 name: "ps2",
                 description: "PS/2 keyboard and mouse (i8042)",
             },
+            device: None,
+            buttons: devices::legacy::MouseButtons::default(),
+            backlog: VecDeque::new(),
+        }
+    }
+
+    /// Set the i8042 device reference.
+    ///
+    /// This must be called before injecting any events.
+    pub fn set_device(&mut self, device: Arc<Mutex<I8042Device>>) {
+        self.device = Some(device);
+    }
+
+    fn device(&self) -> Result<std::sync::MutexGuard<'_, I8042Device>> {
+        self.device
+            .as_ref()
+            .ok_or_else(|| InputError::BackendNotAvailable("i8042 device not set".to_string()))?
+            .lock()
+            .map_err(|_| InputError::InjectionFailed("Failed to lock i8042 device".to_string()))
+    }
+
+    /// Update the held buttons, the side and extra buttons only exist once
+    /// the guest switched the mouse to the Intellimouse Explorer protocol.
+    fn set_button(&mut self, button: super::event::MouseButton, pressed: bool) -> Result<()> {
+        match button {
+            super::event::MouseButton::Left => self.buttons.left = pressed,
+            super::event::MouseButton::Right => self.buttons.right = pressed,
+            super::event::MouseButton::Middle => self.buttons.middle = pressed,
+            super::event::MouseButton::Side | super::event::MouseButton::Extra => {
+                let protocol = self.device()?.state().mouse_protocol;
+                if protocol != MouseProtocol::IntellimouseExplorer {
+                    return Err(InputError::UnsupportedAction(format!(
+                        "{button:?} button is not available with the {} PS/2 mouse protocol",
+                        protocol.name()
+                    )));
+                }
+                if button == super::event::MouseButton::Side {
+                    self.buttons.side = pressed;
+                } else {
+                    self.buttons.extra = pressed;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a single mouse packet with the held buttons, or keep it in the
+    /// backlog when the i8042 buffer is full.
+    fn send_packet(&mut self, dx: i16, dy: i16, dz: i8) -> Result<()> {
+        self.backlog.push_back(devices::legacy::MouseEvent {
+            dx,
+            dy,
+            dz,
+            buttons: self.buttons.clone(),
+        });
+        // The backlog is only left with the packets which don't fit
+        self.send_backlog()?;
+        Ok(())
+    }
+
+    /// Move the backlog to the i8042 buffer as far as it fits, true when
+    /// the backlog is empty.
+    fn send_backlog(&mut self) -> Result<bool> {
+        if self.backlog.is_empty() {
+            return Ok(true);
+        }
+        let device = self
+            .device
+            .as_ref()
+            .ok_or_else(|| InputError::BackendNotAvailable("i8042 device not set".to_string()))?;
+        let mut device = device
+            .lock()
+            .map_err(|_| InputError::InjectionFailed("Failed to lock i8042 device".to_string()))?;
+        let count = device.mouse_space().min(self.backlog.len());
+        for packet in self.backlog.drain(..count) {
+            device.inject_mouse(packet);
+        }
+        Ok(self.backlog.is_empty())
+    }
+
+    /// Send a relative movement, split into as many packets as needed.
+    fn send_movement(&mut self, mut dx: i32, mut dy: i32) -> Result<()> {
+        loop {
+            let step_x = dx.clamp(-Self::MAX_MOUSE_DELTA, Self::MAX_MOUSE_DELTA);
+            let step_y = dy.clamp(-Self::MAX_MOUSE_DELTA, Self::MAX_MOUSE_DELTA);
+            self.send_packet(step_x as i16, step_y as i16, 0)?;
+            dx -= step_x;
+            dy -= step_y;
+            if dx == 0 && dy == 0 {
+                return Ok(());
+            }
         }
     }
 }
@@ -343,20 +546,96 @@ impl InputBackend for Ps2Backend {
     }
 
     fn is_ready(&self) -> bool {
-        // PS/2 is always ready once the device is created
-        true
+        // The i8042 exists for the whole life of the VM
+        self.device.is_some()
     }
 
-    fn inject_keyboard(&mut self, _event: &KeyboardEvent) -> Result<()> {
-        // This will be connected to the actual i8042 device
-        // For now, return Ok to indicate the backend structure is correct
-        Ok(())
+    fn state(&self) -> BackendState {
+        let Ok(device) = self.device() else {
+            return BackendState::default();
+        };
+        let state = device.state();
+        BackendState {
+            keyboard_ready: state.keyboard_enabled,
+            pointer_ready: state.mouse_enabled,
+            leds: KeyboardLeds {
+                num_lock: state.num_lock,
+                caps_lock: state.caps_lock,
+                scroll_lock: state.scroll_lock,
+            },
+            mouse_protocol: Some(state.mouse_protocol),
+        }
     }
 
-    fn inject_mouse(&mut self, _event: &MouseEvent) -> Result<()> {
-        // This will be connected to the actual i8042 device
+    fn inject_keyboard(&mut self, event: &KeyboardEvent) -> Result<()> {
+        let key = event.key()?;
+
+        let mut device = self.device()?;
+        // Sent in whatever set the guest reads, which it may have changed
+        let set = device.scancode_set();
+        let releases: &[bool] = match event.action {
+            super::event::KeyboardAction::Press => &[false],
+            super::event::KeyboardAction::Release => &[true],
+            super::event::KeyboardAction::Type => &[false, true],
+        };
+        let sequences: Vec<Vec<u8>> = releases
+            .iter()
+            .map(|&release| key.ps2_bytes(set, release))
+            .filter(|bytes| !bytes.is_empty())
+            .collect();
+        if sequences.iter().map(Vec::len).sum::<usize>() > device.keyboard_space() {
+            return Err(InputError::BufferOverflow);
+        }
+        for bytes in sequences {
+            device.inject_keyboard_bytes(&bytes);
+        }
+
         Ok(())
     }
+
+    fn inject_mouse(&mut self, event: &MouseEvent) -> Result<()> {
+        // The packets of the previous events go first
+        if !self.send_backlog()? {
+            return Err(InputError::BufferOverflow);
+        }
+
+        match event.action {
+            super::event::MouseAction::Move => self.send_movement(event.x, event.y),
+            super::event::MouseAction::MoveAbsolute => Err(InputError::UnsupportedAction(
+                "PS/2 mouse only supports relative movement".to_string(),
+            )),
+            super::event::MouseAction::ButtonPress | super::event::MouseAction::ButtonRelease => {
+                let button = event.button.ok_or_else(|| {
+                    InputError::InvalidEvent("Button action without a button".to_string())
+                })?;
+                let pressed = matches!(event.action, super::event::MouseAction::ButtonPress);
+                self.set_button(button, pressed)?;
+                self.send_packet(0, 0, 0)
+            }
+            super::event::MouseAction::Click => {
+                let button = event.button.ok_or_else(|| {
+                    InputError::InvalidEvent("Click action without a button".to_string())
+                })?;
+                self.set_button(button, true)?;
+                self.send_packet(0, 0, 0)?;
+                self.set_button(button, false)?;
+                self.send_packet(0, 0, 0)
+            }
+            super::event::MouseAction::Scroll => {
+                // Intellimouse packets carry a 4-bit wheel delta
+                let dz = event.z.clamp(-8, 7) as i8;
+                self.send_packet(0, 0, dz)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.send_backlog()? {
+            Ok(())
+        } else {
+            Err(InputError::BufferOverflow)
+        }
+    }
 }
 
 // ============================================================================
@@ -373,15 +652,20 @@ impl InputBackend for Ps2Backend {
 ///
 /// - **Stealth Level**: Low (easily detected as virtual device)
 /// - **Max Keyboard Rate**: 1000 events/second
-/// - **Absolute Mouse**: Yes
-/// - **Multi-touch**: Yes
+/// - **Absolute Mouse**: Yes, with an `absolute` pointer device
+/// - **Multi-touch**: Yes, with a `touch` pointer device
 /// - **Scroll Wheel**: Yes
 ///
+/// With a `relative` pointer device, absolute positions are emulated with
+/// relative movements from the last position set through the backend.
+///
 /// # Setup
 ///
 /// The backend must be connected to a VirtIO Input device using
 /// [`set_device`](VirtioInputBackend::set_device) before it can inject events.
 /// The ready state must be set using [`set_ready`](VirtioInputBackend::set_ready).
+/// Absolute positions are scaled from the screen dimensions set through
+/// [`set_screen_dimensions`](VirtioInputBackend::set_screen_dimensions).
 ///
 /// # Example
 ///
@@ -418,6 +702,10 @@ pub struct VirtioInputBackend {
 }
 
 impl VirtioInputBackend {
+    /// Queue entries the largest event takes: a touch contact is up to
+    /// seven events and their sync event
+    const MAX_QUEUE_ENTRIES: usize = 8;
+
     /// Create a new virtio-input backend.
     ///
     /// The backend is created in a not-ready state. You must call
@@ -454,15 +742,19 @@ impl VirtioInputBackend {
 
     /// Set the VirtIO Input device reference.
     ///
-    /// This must be called before injecting any events.
+    /// This must be called before injecting any events. The capabilities
+    /// then follow the pointer of the device.
     pub fn set_device(&mut self, device: std::sync::Arc<std::sync::Mutex<virtio_devices::VirtioInput>>) {
+        let pointer = device.lock().unwrap().pointer();
+        self.capabilities.supports_absolute_mouse = pointer != PointerMode::Relative;
+        self.capabilities.supports_multi_touch = pointer == PointerMode::Touch;
         self.device = Some(device);
     }
 
     /// Set screen dimensions for absolute positioning.
     ///
-    /// When using absolute mouse positioning, these dimensions are used
-    /// to clamp the mouse coordinates.
+    /// Absolute mouse and touch coordinates are clamped to these dimensions,
+    /// and scaled from them to the axes of the device.
     pub fn set_screen_dimensions(&mut self, width: u32, height: u32) {
         self.screen_width = width;
         self.screen_height = height;
@@ -477,6 +769,14 @@ impl VirtioInputBackend {
         self.mouse_y = 0;
     }
 
+    /// Fail unless the event queue of `dev` can take any single event
+    fn check_queue_space(dev: &virtio_devices::VirtioInput) -> Result<()> {
+        if dev.queue_space() < Self::MAX_QUEUE_ENTRIES {
+            return Err(InputError::BufferOverflow);
+        }
+        Ok(())
+    }
+
     /// Convert keyboard action to pressed boolean.
     fn keyboard_action_to_pressed(action: super::event::KeyboardAction) -> bool {
         match action {
@@ -516,6 +816,21 @@ impl InputBackend for VirtioInputBackend {
         self.ready
     }
 
+    fn state(&self) -> BackendState {
+        let Some(device) = self.device.as_ref() else {
+            return BackendState::default();
+        };
+        let device = device.lock().unwrap();
+        // The keyboard and the pointer are a single device
+        let activated = device.activated();
+        BackendState {
+            keyboard_ready: activated,
+            pointer_ready: activated,
+            leds: KeyboardLeds::from_bits(device.leds()),
+            mouse_protocol: None,
+        }
+    }
+
     fn inject_keyboard(&mut self, event: &KeyboardEvent) -> Result<()> {
         if !self.ready {
             return Err(InputError::DeviceNotReady);
@@ -525,19 +840,23 @@ impl InputBackend for VirtioInputBackend {
             InputError::BackendNotAvailable("VirtIO Input device not set".to_string())
         })?;
 
+        // virtio-input reports evdev codes
+        let code = event.key()?.evdev;
         let pressed = Self::keyboard_action_to_pressed(event.action);
 
         // Handle Type action as Press + Release
         if matches!(event.action, super::event::KeyboardAction::Type) {
             if let Ok(dev) = device.lock() {
-                dev.inject_keyboard(event.code, true)
+                Self::check_queue_space(&dev)?;
+                dev.inject_keyboard(code, true)
                     .map_err(|e| InputError::InjectionFailed(e.to_string()))?;
-                dev.inject_keyboard(event.code, false)
+                dev.inject_keyboard(code, false)
                     .map_err(|e| InputError::InjectionFailed(e.to_string()))?;
             }
         } else {
             if let Ok(dev) = device.lock() {
-                dev.inject_keyboard(event.code, pressed)
+                Self::check_queue_space(&dev)?;
+                dev.inject_keyboard(code, pressed)
                     .map_err(|e| InputError::InjectionFailed(e.to_string()))?;
             }
         }
@@ -555,12 +874,24 @@ impl InputBackend for VirtioInputBackend {
         })?;
 
         if let Ok(dev) = device.lock() {
+            Self::check_queue_space(&dev)?;
             match event.action {
                 super::event::MouseAction::Move => {
                     // Relative movement
                     dev.inject_mouse_rel(event.x, event.y)
                         .map_err(|e| InputError::InjectionFailed(e.to_string()))?;
                 }
+                super::event::MouseAction::MoveAbsolute
+                    if dev.pointer() == PointerMode::Absolute =>
+                {
+                    self.mouse_x = event.x.clamp(0, self.screen_width as i32);
+                    self.mouse_y = event.y.clamp(0, self.screen_height as i32);
+                    dev.inject_mouse_abs(
+                        scale_to_axis(event.x, self.screen_width, VIRTIO_INPUT_ABS_MAX),
+                        scale_to_axis(event.y, self.screen_height, VIRTIO_INPUT_ABS_MAX),
+                    )
+                    .map_err(|e| InputError::InjectionFailed(e.to_string()))?;
+                }
                 super::event::MouseAction::MoveAbsolute => {
                     // Clamp the target position to screen bounds
                     let target_x = event.x.clamp(0, self.screen_width as i32);
@@ -606,6 +937,35 @@ impl InputBackend for VirtioInputBackend {
 
         Ok(())
     }
+
+    fn inject_touch(&mut self, event: &TouchEvent) -> Result<()> {
+        if !self.ready {
+            return Err(InputError::DeviceNotReady);
+        }
+
+        let device = self.device.as_ref().ok_or_else(|| {
+            InputError::BackendNotAvailable("VirtIO Input device not set".to_string())
+        })?;
+        let dev = device.lock().map_err(|_| {
+            InputError::InjectionFailed("Failed to lock VirtIO Input device".to_string())
+        })?;
+        if dev.pointer() != PointerMode::Touch {
+            return Err(InputError::UnsupportedAction(
+                "VirtIO Input device is not a touchscreen".to_string(),
+            ));
+        }
+        Self::check_queue_space(&dev)?;
+
+        let position = match event.action {
+            TouchAction::Down | TouchAction::Move => Some((
+                scale_to_axis(event.x, self.screen_width, VIRTIO_INPUT_ABS_MAX),
+                scale_to_axis(event.y, self.screen_height, VIRTIO_INPUT_ABS_MAX),
+            )),
+            TouchAction::Up => None,
+        };
+        dev.inject_touch(event.slot, position)
+            .map_err(|e| InputError::InjectionFailed(e.to_string()))
+    }
 }
 
 // ============================================================================
@@ -629,7 +989,7 @@ impl InputBackend for VirtioInputBackend {
 /// // Get backend name for logging
 /// println!("Using backend: {}", backend.name());
 /// ```
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum BackendType {
     /// PS/2 backend (i8042)
     Ps2,
@@ -700,7 +1060,7 @@ impl BackendType {
 ///
 /// - **Stealth Level**: Medium (may be detected with inspection)
 /// - **Max Keyboard Rate**: 1000 events/second
-/// - **Absolute Mouse**: Yes
+/// - **Absolute Mouse**: Yes, with a tablet device
 /// - **Multi-touch**: No
 /// - **Scroll Wheel**: Yes
 ///
@@ -709,6 +1069,9 @@ impl BackendType {
 /// The backend requires USB HID devices to be configured:
 /// - Keyboard device: [`set_keyboard_device`](UsbHidBackend::set_keyboard_device)
 /// - Mouse device: [`set_mouse_device`](UsbHidBackend::set_mouse_device)
+/// - Tablet device: [`set_tablet_device`](UsbHidBackend::set_tablet_device),
+///   for absolute positions scaled from the screen dimensions set through
+///   [`set_screen_dimensions`](UsbHidBackend::set_screen_dimensions)
 ///
 /// # Example
 ///
@@ -724,24 +1087,37 @@ impl BackendType {
 /// let event = KeyboardEvent {
 ///     action: KeyboardAction::Type,
 ///     code: 0x1E,
-///     modifiers: Default::default(),
+///     ..Default::default()
 /// };
 /// backend.inject_keyboard(&event)?;
 /// ```
 pub struct UsbHidBackend {
     capabilities: InputCapabilities,
     ready: bool,
-    /// Keyboard LED state (bitmask: NumLock=0x01, CapsLock=0x02, ScrollLock=0x04)
-    keyboard_leds: u8,
     /// Mouse button state (bitmask: Left=0x01, Right=0x02, Middle=0x04)
     mouse_buttons: u8,
+    /// Modifier keys held (bitmask: LCtrl=0x01 .. RGui=0x80)
+    held_modifiers: u8,
+    /// Other keys held, as HID usages, at most six
+    held_keys: Vec<u8>,
     /// Keyboard HID device reference
     keyboard_device: Option<SharedUsbHidDevice>,
     /// Mouse HID device reference
     mouse_device: Option<SharedUsbHidDevice>,
+    /// Tablet HID device reference
+    tablet_device: Option<SharedUsbHidDevice>,
+    /// Screen dimensions for absolute positioning (width)
+    screen_width: u32,
+    /// Screen dimensions for absolute positioning (height)
+    screen_height: u32,
 }
 
 impl UsbHidBackend {
+    /// Keys a boot keyboard report carries besides the modifiers
+    const MAX_HELD_KEYS: usize = 6;
+    /// Largest usage of the keyboard report descriptor, modifiers aside
+    const MAX_KEY_USAGE: u8 = 0x65;
+
     /// Create a new USB HID backend.
     ///
     /// The backend is created in a not-ready state. You must configure
@@ -759,10 +1135,14 @@ impl UsbHidBackend {
                 description: "USB Human Interface Device (keyboard + mouse)",
             },
             ready: false,
-            keyboard_leds: 0,
             mouse_buttons: 0,
+            held_modifiers: 0,
+            held_keys: Vec::with_capacity(Self::MAX_HELD_KEYS),
             keyboard_device: None,
             mouse_device: None,
+            tablet_device: None,
+            screen_width: 1920,  // Default screen width
+            screen_height: 1080, // Default screen height
         }
     }
 
@@ -787,14 +1167,51 @@ impl UsbHidBackend {
         self.mouse_device = Some(device);
     }
 
+    /// Set tablet HID device.
+    ///
+    /// This must be called before injecting absolute mouse events.
+    pub fn set_tablet_device(&mut self, device: SharedUsbHidDevice) {
+        self.tablet_device = Some(device);
+    }
+
+    /// Set screen dimensions for absolute positioning.
+    ///
+    /// Absolute mouse coordinates are clamped to these dimensions, and
+    /// scaled from them to the axes of the tablet.
+    pub fn set_screen_dimensions(&mut self, width: u32, height: u32) {
+        self.screen_width = width;
+        self.screen_height = height;
+    }
+
     /// Get keyboard LED state.
     ///
-    /// Returns a bitmask of LED states:
+    /// Returns a bitmask of the LEDs the guest set on the keyboard:
     /// - Bit 0: NumLock
     /// - Bit 1: CapsLock
     /// - Bit 2: ScrollLock
     pub fn keyboard_leds(&self) -> u8 {
-        self.keyboard_leds
+        self.keyboard_device
+            .as_ref()
+            .map_or(0, |device| device.lock().unwrap().leds())
+    }
+
+    /// Whether the guest configured one of `devices`
+    fn configured(devices: &[&Option<SharedUsbHidDevice>]) -> bool {
+        devices
+            .iter()
+            .filter_map(|device| device.as_ref())
+            .any(|device| device.lock().unwrap().state() == HidState::Configured)
+    }
+
+    /// Fail unless `device`, if any, can queue `reports` more reports
+    /// without dropping older ones
+    fn check_queue_space(device: &Option<SharedUsbHidDevice>, reports: usize) -> Result<()> {
+        match device {
+            Some(device) if device.lock().unwrap().queue_space() < reports => {
+                Err(InputError::BufferOverflow)
+            }
+            _ => Ok(()),
+        }
     }
 
     /// Get mouse button state.
@@ -807,12 +1224,36 @@ impl UsbHidBackend {
         self.mouse_buttons
     }
 
-    /// Convert keyboard event to USB HID report
-    fn keyboard_to_hid_report(&self, event: &KeyboardEvent) -> [u8; 8] {
+    /// Update the held keys for a press or release of `usage`
+    fn set_key(&mut self, usage: u8, pressed: bool) -> Result<()> {
+        if let 0xE0..=0xE7 = usage {
+            let mask = 1 << (usage - 0xE0);
+            if pressed {
+                self.held_modifiers |= mask;
+            } else {
+                self.held_modifiers &= !mask;
+            }
+        } else if !pressed {
+            self.held_keys.retain(|&key| key != usage);
+        } else if !self.held_keys.contains(&usage) {
+            if self.held_keys.len() == Self::MAX_HELD_KEYS {
+                return Err(InputError::UnsupportedAction(format!(
+                    "USB HID keyboard can't hold more than {} keys",
+                    Self::MAX_HELD_KEYS
+                )));
+            }
+            self.held_keys.push(usage);
+        }
+        Ok(())
+    }
+
+    /// Build the USB HID keyboard report of the held keys
+    fn keyboard_report(&self, event: &KeyboardEvent) -> [u8; 8] {
         // Standard USB HID keyboard report: modifier + reserved + 6 key codes
         let mut report = [0u8; 8];
 
         // Modifier byte (bit 0-7: LCtrl, LShift, LAlt, LGui, RCtrl, RShift, RAlt, RGui)
+        report[0] = self.held_modifiers;
         if event.modifiers.ctrl {
             report[0] |= 0x01; // Left Ctrl
         }
@@ -826,38 +1267,37 @@ impl UsbHidBackend {
             report[0] |= 0x08; // Left GUI
         }
 
-        // Key code (position 2-7 can hold up to 6 simultaneous keys)
-        // Only set key on press, clear on release
-        match event.action {
-            super::event::KeyboardAction::Press | super::event::KeyboardAction::Type => {
-                // Convert scancode to HID usage code (simplified)
-                let hid_code = self.scancode_to_hid(event.code);
-                report[2] = hid_code;
-            }
-            super::event::KeyboardAction::Release => {
-                // Release: no key code in report
-            }
-        }
-
+        report[2..2 + self.held_keys.len()].copy_from_slice(&self.held_keys);
         report
     }
 
-    /// Convert PS/2 scancode to USB HID usage code
-    fn scancode_to_hid(&self, scancode: u16) -> u8 {
-        // Simplified mapping - PS/2 Set 1 to USB HID
-        match scancode {
-            0x01 => 0x29, // Escape
-            0x02..=0x0B => (scancode - 0x02 + 0x1E) as u8, // 1-0
-            0x0E => 0x2A, // Backspace
-            0x0F => 0x2B, // Tab
-            0x1E..=0x26 => (scancode - 0x1E + 0x04) as u8, // Q-P
-            0x1C => 0x28, // Enter
-            0x2A => 0xE1, // Left Shift
-            0x1D => 0xE0, // Left Ctrl
-            0x38 => 0xE2, // Left Alt
-            0x39 => 0x2C, // Space
-            _ => scancode as u8, // Fallback
+    /// Convert mouse event to the button byte of a USB HID report
+    fn mouse_buttons_byte(event: &MouseEvent) -> u8 {
+        let mut buttons = 0;
+        if event.buttons.left {
+            buttons |= 0x01;
         }
+        if event.buttons.right {
+            buttons |= 0x02;
+        }
+        if event.buttons.middle {
+            buttons |= 0x04;
+        }
+        buttons
+    }
+
+    /// Convert absolute mouse event to USB HID tablet report
+    fn tablet_to_hid_report(&self, event: &MouseEvent) -> [u8; 6] {
+        // Tablet report: buttons + 16-bit X + 16-bit Y + wheel
+        let max = u32::from(TABLET_ABS_MAX);
+        let x = scale_to_axis(event.x, self.screen_width, max) as u16;
+        let y = scale_to_axis(event.y, self.screen_height, max) as u16;
+
+        let mut report = [0u8; 6];
+        report[0] = Self::mouse_buttons_byte(event);
+        report[1..3].copy_from_slice(&x.to_le_bytes());
+        report[3..5].copy_from_slice(&y.to_le_bytes());
+        report
     }
 
     /// Convert mouse event to USB HID report
@@ -866,15 +1306,7 @@ impl UsbHidBackend {
         let mut report = [0u8; 6];
 
         // Button byte
-        if event.buttons.left {
-            report[0] |= 0x01;
-        }
-        if event.buttons.right {
-            report[0] |= 0x02;
-        }
-        if event.buttons.middle {
-            report[0] |= 0x04;
-        }
+        report[0] = Self::mouse_buttons_byte(event);
 
         // Handle button actions
         if let Some(ref btn) = event.button {
@@ -926,18 +1358,44 @@ impl InputBackend for UsbHidBackend {
         self.ready
     }
 
+    fn state(&self) -> BackendState {
+        BackendState {
+            keyboard_ready: Self::configured(&[&self.keyboard_device]),
+            pointer_ready: Self::configured(&[&self.mouse_device, &self.tablet_device]),
+            leds: KeyboardLeds::from_bits(u32::from(self.keyboard_leds())),
+            mouse_protocol: None,
+        }
+    }
+
     fn inject_keyboard(&mut self, event: &KeyboardEvent) -> Result<()> {
         if !self.ready {
             return Err(InputError::DeviceNotReady);
         }
 
-        // Generate HID report
-        let report = self.keyboard_to_hid_report(event);
-
-        // Send to HID device
-        if let Some(ref device) = self.keyboard_device {
-            if let Ok(mut dev) = device.lock() {
-                dev.queue_report(report.to_vec());
+        let key = event.key()?;
+        let usage = key
+            .hid
+            .filter(|&usage| usage <= Self::MAX_KEY_USAGE || (0xE0..=0xE7).contains(&usage))
+            .ok_or_else(|| {
+                InputError::UnsupportedAction(format!(
+                    "Key {:?} is not on the USB HID keyboard",
+                    key.qcode
+                ))
+            })?;
+
+        let presses: &[bool] = match event.action {
+            super::event::KeyboardAction::Press => &[true],
+            super::event::KeyboardAction::Release => &[false],
+            super::event::KeyboardAction::Type => &[true, false],
+        };
+        Self::check_queue_space(&self.keyboard_device, presses.len())?;
+        for &pressed in presses {
+            self.set_key(usage, pressed)?;
+            let report = self.keyboard_report(event);
+
+            // Send to HID device
+            if let Some(ref device) = self.keyboard_device {
+                send_report(device, report.to_vec());
             }
         }
 
@@ -949,14 +1407,26 @@ impl InputBackend for UsbHidBackend {
             return Err(InputError::DeviceNotReady);
         }
 
+        // Absolute positions go through the tablet, which the boot mouse
+        // can't report
+        if event.action == super::event::MouseAction::MoveAbsolute {
+            let device = self.tablet_device.as_ref().ok_or_else(|| {
+                InputError::UnsupportedAction("No USB HID tablet attached".to_string())
+            })?;
+            Self::check_queue_space(&self.tablet_device, 1)?;
+            let report = self.tablet_to_hid_report(event);
+            send_report(device, report.to_vec());
+            return Ok(());
+        }
+
+        Self::check_queue_space(&self.mouse_device, 1)?;
+
         // Generate HID report
         let report = self.mouse_to_hid_report(event);
 
         // Send to HID device
         if let Some(ref device) = self.mouse_device {
-            if let Ok(mut dev) = device.lock() {
-                dev.queue_report(report.to_vec());
-            }
+            send_report(device, report.to_vec());
         }
 
         Ok(())
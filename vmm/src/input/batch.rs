@@ -11,7 +11,7 @@
 use std::collections::VecDeque;
 use std::time::{Duration, Instant};
 
-use super::event::{InputEvent, InputRequest, KeyboardEvent, MouseEvent};
+use super::event::{InputEvent, InputRequest, KeyboardEvent, MouseEvent, TouchEvent};
 use super::{InputError, Result};
 
 /// Default batch size (events)
@@ -48,6 +48,8 @@ pub struct EventBatch {
     pub keyboard: Vec<KeyboardEvent>,
     /// Mouse events
     pub mouse: Vec<MouseEvent>,
+    /// Touch events
+    pub touch: Vec<TouchEvent>,
     /// Creation timestamp
     pub created_at: Instant,
 }
@@ -64,6 +66,7 @@ impl EventBatch {
         Self {
             keyboard: Vec::new(),
             mouse: Vec::new(),
+            touch: Vec::new(),
             created_at: Instant::now(),
         }
     }
@@ -73,6 +76,7 @@ impl EventBatch {
         Self {
             keyboard: Vec::with_capacity(capacity),
             mouse: Vec::with_capacity(capacity),
+            touch: Vec::new(),
             created_at: Instant::now(),
         }
     }
@@ -87,28 +91,35 @@ impl EventBatch {
         self.mouse.push(event);
     }
 
+    /// Add touch event
+    pub fn push_touch(&mut self, event: TouchEvent) {
+        self.touch.push(event);
+    }
+
     /// Add generic event
     pub fn push(&mut self, event: InputEvent) {
         match event {
             InputEvent::Keyboard(kb) => self.keyboard.push(kb),
             InputEvent::Mouse(m) => self.mouse.push(m),
+            InputEvent::Touch(t) => self.touch.push(t),
         }
     }
 
     /// Total event count
     pub fn len(&self) -> usize {
-        self.keyboard.len() + self.mouse.len()
+        self.keyboard.len() + self.mouse.len() + self.touch.len()
     }
 
     /// Check if batch is empty
     pub fn is_empty(&self) -> bool {
-        self.keyboard.is_empty() && self.mouse.is_empty()
+        self.keyboard.is_empty() && self.mouse.is_empty() && self.touch.is_empty()
     }
 
     /// Clear the batch
     pub fn clear(&mut self) {
         self.keyboard.clear();
         self.mouse.clear();
+        self.touch.clear();
         self.created_at = Instant::now();
     }
 
@@ -118,9 +129,20 @@ impl EventBatch {
             backend: None,
             keyboard: self.keyboard,
             mouse: self.mouse,
+            touch: self.touch,
         }
     }
 
+    /// Convert to the events, in the order of [`into_request`](Self::into_request):
+    /// keyboard, then mouse, then touch
+    pub fn into_events(self) -> impl Iterator<Item = InputEvent> {
+        self.keyboard
+            .into_iter()
+            .map(InputEvent::Keyboard)
+            .chain(self.mouse.into_iter().map(InputEvent::Mouse))
+            .chain(self.touch.into_iter().map(InputEvent::Touch))
+    }
+
     /// Age of the batch
     pub fn age(&self) -> Duration {
         self.created_at.elapsed()
@@ -203,6 +225,33 @@ impl EventBatcher {
         }
     }
 
+    /// Push touch event
+    pub fn push_touch(&mut self, event: TouchEvent) {
+        self.current_batch.push_touch(event);
+
+        if self.current_batch.len() >= self.config.max_batch_size {
+            self.flush_current();
+        }
+    }
+
+    /// Push an event, keeping it behind the events pushed before
+    ///
+    /// A batch hands its keyboard events out before its mouse and touch
+    /// events, so the current batch is flushed first when `event` would
+    /// overtake some of them.
+    pub fn push_in_order(&mut self, event: InputEvent) {
+        let batch = &self.current_batch;
+        let overtakes = match event {
+            InputEvent::Keyboard(_) => !batch.mouse.is_empty() || !batch.touch.is_empty(),
+            InputEvent::Mouse(_) => !batch.touch.is_empty(),
+            InputEvent::Touch(_) => false,
+        };
+        if overtakes {
+            self.flush_current();
+        }
+        self.push(event);
+    }
+
     /// Push multiple events from InputRequest
     pub fn push_request(&mut self, request: InputRequest) {
         for kb in request.keyboard {
@@ -211,6 +260,9 @@ impl EventBatcher {
         for m in request.mouse {
             self.push_mouse(m);
         }
+        for t in request.touch {
+            self.push_touch(t);
+        }
     }
 
     /// Flush current batch to pending queue
@@ -351,7 +403,7 @@ mod tests {
         batch.push_keyboard(KeyboardEvent {
             action: KeyboardAction::Press,
             code: 0x1E,
-            modifiers: Default::default(),
+            ..Default::default()
         });
         batch.push_mouse(MouseEvent {
             action: MouseAction::Move,
@@ -380,7 +432,7 @@ mod tests {
             batcher.push_keyboard(KeyboardEvent {
                 action: KeyboardAction::Press,
                 code: i,
-                modifiers: Default::default(),
+                ..Default::default()
             });
         }
 
@@ -391,7 +443,7 @@ mod tests {
         batcher.push_keyboard(KeyboardEvent {
             action: KeyboardAction::Press,
             code: 3,
-            modifiers: Default::default(),
+            ..Default::default()
         });
 
         // Should have pending batch
@@ -407,7 +459,7 @@ mod tests {
         batch.push_keyboard(KeyboardEvent {
             action: KeyboardAction::Type,
             code: 0x1E,
-            modifiers: Default::default(),
+            ..Default::default()
         });
         batch.push_mouse(MouseEvent {
             action: MouseAction::Click,
@@ -436,7 +488,7 @@ mod tests {
         batcher.push_keyboard(KeyboardEvent {
             action: KeyboardAction::Press,
             code: 0,
-            modifiers: Default::default(),
+            ..Default::default()
         });
 
         // Wait a bit
@@ -455,7 +507,7 @@ mod tests {
             batcher.push_keyboard(KeyboardEvent {
                 action: KeyboardAction::Press,
                 code: i,
-                modifiers: Default::default(),
+                ..Default::default()
             });
         }
 
@@ -478,7 +530,7 @@ mod tests {
             batcher.push_keyboard(KeyboardEvent {
                 action: KeyboardAction::Press,
                 code: 0,
-                modifiers: Default::default(),
+                ..Default::default()
             });
         }
 
@@ -495,7 +547,7 @@ mod tests {
         batcher.push_keyboard(KeyboardEvent {
             action: KeyboardAction::Press,
             code: 0,
-            modifiers: Default::default(),
+            ..Default::default()
         });
 
         // Should not have pending before flush
@@ -520,12 +572,12 @@ mod tests {
         batcher.push_keyboard(KeyboardEvent {
             action: KeyboardAction::Press,
             code: 1,
-            modifiers: Default::default(),
+            ..Default::default()
         });
         batcher.push_keyboard(KeyboardEvent {
             action: KeyboardAction::Press,
             code: 2,
-            modifiers: Default::default(),
+            ..Default::default()
         });
 
         // Should have pending batch
@@ -561,7 +613,7 @@ mod tests {
             batcher.push_keyboard(KeyboardEvent {
                 action: KeyboardAction::Press,
                 code: i,
-                modifiers: Default::default(),
+                ..Default::default()
             });
         }
 
@@ -580,7 +632,7 @@ mod tests {
         batcher.push_keyboard(KeyboardEvent {
             action: KeyboardAction::Press,
             code: 0x1E,
-            modifiers: Default::default(),
+            ..Default::default()
         });
         batcher.push_mouse(MouseEvent {
             action: MouseAction::Move,
@@ -613,7 +665,7 @@ mod tests {
                 KeyboardEvent {
                     action: KeyboardAction::Press,
                     code: 0x1E,
-                    modifiers: Default::default(),
+                    ..Default::default()
                 },
             ],
             mouse: vec![
@@ -626,6 +678,7 @@ mod tests {
                     buttons: Default::default(),
                 },
             ],
+            touch: Vec::new(),
         };
 
         batcher.push_request(request);
@@ -637,6 +690,36 @@ mod tests {
         assert!(batcher.has_pending());
     }
 
+    #[test]
+    fn test_push_in_order() {
+        let mut batcher = EventBatcher::new(BatchConfig {
+            max_batch_size: 16,
+            flush_interval_us: 1000000,
+            adaptive: false,
+        });
+
+        let mouse = InputEvent::Mouse(MouseEvent {
+            action: MouseAction::Click,
+            x: 0,
+            y: 0,
+            z: 0,
+            button: Some(MouseButton::Left),
+            buttons: Default::default(),
+        });
+        batcher.push_in_order(InputEvent::keyboard(KeyboardAction::Type, 0x1E));
+        batcher.push_in_order(mouse.clone());
+        batcher.push_in_order(InputEvent::keyboard(KeyboardAction::Type, 0x30));
+        batcher.flush_all();
+
+        let events: Vec<InputEvent> = std::iter::from_fn(|| batcher.pop_batch())
+            .flat_map(EventBatch::into_events)
+            .collect();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], InputEvent::Keyboard(kb) if kb.code == 0x1E));
+        assert!(matches!(&events[1], InputEvent::Mouse(_)));
+        assert!(matches!(&events[2], InputEvent::Keyboard(kb) if kb.code == 0x30));
+    }
+
     #[test]
     fn test_config_update() {
         let mut batcher = EventBatcher::default_batcher();
@@ -661,7 +744,7 @@ mod tests {
             batcher.push_keyboard(KeyboardEvent {
                 action: KeyboardAction::Press,
                 code: i,
-                modifiers: Default::default(),
+                ..Default::default()
             });
         }
 
@@ -696,7 +779,7 @@ mod tests {
         batch.push_keyboard(KeyboardEvent {
             action: KeyboardAction::Press,
             code: 0,
-            modifiers: Default::default(),
+            ..Default::default()
         });
 
         assert!(!batch.is_empty());
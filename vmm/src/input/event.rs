@@ -8,7 +8,7 @@
 //!
 //! - Keyboard events (press/release/type)
 //! - Mouse events (move/buttons/scroll)
-//! - Touch events (planned)
+//! - Touch events (multi-touch contacts)
 //! - Gamepad events (planned)
 //!
 //! # Event Structure
@@ -40,6 +40,9 @@
 
 use serde::{Deserialize, Serialize};
 
+use super::keymap::{self, Key, KeyCodeSet};
+use super::{InputError, Result};
+
 /// Input device type.
 ///
 /// Represents the category of input device that generated an event.
@@ -50,7 +53,7 @@ pub enum InputDevice {
     Keyboard,
     /// Mouse device
     Mouse,
-    /// Touch device (planned)
+    /// Touch device
     Touch,
     /// Gamepad device (planned)
     Gamepad,
@@ -88,13 +91,16 @@ pub enum InputAction {
 /// # Fields
 ///
 /// * `action` - The type of keyboard action (press, release, or type)
-/// * `code` - PS/2 Set 1 scancode
+/// * `code` - Key code in `code_set`
+/// * `code_set` - Code set of `code` (PS/2 Set 1 by default)
+/// * `qcode` - QEMU key name, used instead of `code` when set
 /// * `modifiers` - Active keyboard modifiers (Ctrl, Shift, etc.)
 ///
 /// # Scancodes
 ///
-/// The `code` field uses PS/2 Set 1 scancodes. Common codes are available
-/// in the [`keys`] module.
+/// The `code` field uses PS/2 Set 1 scancodes unless `code_set` says
+/// otherwise. Common codes are available in the [`keys`] module. Backends
+/// translate the key to their own code set, see [`key`](KeyboardEvent::key).
     ///
     /// [`keys`]: #reexports
 ///
@@ -115,9 +121,21 @@ pub enum InputAction {
 ///     action: KeyboardAction::Type,
 ///     code: 0x2E, // C key
 ///     modifiers: KeyboardModifiers { ctrl: true, ..Default::default() },
+///     ..Default::default()
+/// };
+///
+/// // Right Ctrl, as a Linux evdev code and as a QEMU key name
+/// let right_ctrl = KeyboardEvent {
+///     code: 97,
+///     code_set: KeyCodeSet::Evdev,
+///     ..Default::default()
+/// };
+/// let right_ctrl = KeyboardEvent {
+///     qcode: Some("ctrl_r".to_string()),
+///     ..Default::default()
 /// };
 /// ```
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct KeyboardEvent {
     /// Action to perform.
     ///
@@ -125,12 +143,21 @@ pub struct KeyboardEvent {
     /// - `Release`: Key up event
     /// - `Type`: Press followed by release (convenience)
     pub action: KeyboardAction,
-    /// Key code (PS/2 Set 1 scancode).
+    /// Key code, in `code_set`.
     ///
-    /// See the [`keys`] module for common codes.
+    /// See the [`keys`] module for common PS/2 Set 1 codes.
     ///
     /// [`keys`]: #reexports
+    #[serde(default)]
     pub code: u16,
+    /// Code set of `code`.
+    #[serde(default)]
+    pub code_set: KeyCodeSet,
+    /// QEMU key name (`"a"`, `"ctrl_r"`, `"meta_l"`, ...).
+    ///
+    /// When set, it names the key instead of `code`.
+    #[serde(default)]
+    pub qcode: Option<String>,
     /// Modifier keys (optional, for complex shortcuts).
     ///
     /// Note: Modifiers should typically be sent as separate key events.
@@ -142,7 +169,7 @@ pub struct KeyboardEvent {
 /// Keyboard action type.
 ///
 /// Defines the type of keyboard action to perform.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum KeyboardAction {
     /// Key press (key down)
@@ -150,9 +177,30 @@ pub enum KeyboardAction {
     /// Key release (key up)
     Release,
     /// Key press and release (convenience for typing)
+    #[default]
     Type,
 }
 
+impl KeyboardEvent {
+    /// Key this event is about.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`InputError::InvalidEvent`] if the key is unknown.
+    pub fn key(&self) -> Result<&'static Key> {
+        match &self.qcode {
+            Some(qcode) => keymap::lookup_qcode(qcode)
+                .ok_or_else(|| InputError::InvalidEvent(format!("Unknown key name {qcode:?}"))),
+            None => keymap::lookup(self.code_set, self.code).ok_or_else(|| {
+                InputError::InvalidEvent(format!(
+                    "Unknown {:?} key code 0x{:X}",
+                    self.code_set, self.code
+                ))
+            }),
+        }
+    }
+}
+
 /// Keyboard modifier states.
 ///
 /// Represents the state of keyboard modifier keys.
@@ -508,6 +556,57 @@ impl MouseButtons {
     }
 }
 
+// ============================================================================
+// Touch Events
+// ============================================================================
+
+/// Touch event for input injection.
+///
+/// Represents a single contact on a touchscreen. Each finger uses its own
+/// slot, so that several contacts can be held at once.
+///
+/// # Example
+///
+/// ```ignore
+/// use vmm::input::event::{TouchEvent, TouchAction};
+///
+/// // Two-finger tap
+/// let events = [
+///     TouchEvent { action: TouchAction::Down, slot: 0, x: 100, y: 100 },
+///     TouchEvent { action: TouchAction::Down, slot: 1, x: 200, y: 100 },
+///     TouchEvent { action: TouchAction::Up, slot: 0, x: 0, y: 0 },
+///     TouchEvent { action: TouchAction::Up, slot: 1, x: 0, y: 0 },
+/// ];
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TouchEvent {
+    /// Action to perform.
+    pub action: TouchAction,
+    /// Contact slot, from 0 to the number of contacts of the device.
+    #[serde(default)]
+    pub slot: u32,
+    /// X coordinate in screen space (ignored for `Up`).
+    #[serde(default)]
+    pub x: i32,
+    /// Y coordinate in screen space (ignored for `Up`).
+    #[serde(default)]
+    pub y: i32,
+}
+
+/// Touch action type.
+///
+/// Defines the type of touch action to perform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TouchAction {
+    /// Contact starts
+    Down,
+    /// Contact moves
+    Move,
+    /// Contact ends
+    Up,
+}
+
 // ============================================================================
 // Generic Input Event
 // ============================================================================
@@ -541,6 +640,8 @@ pub enum InputEvent {
     Keyboard(KeyboardEvent),
     /// Mouse event
     Mouse(MouseEvent),
+    /// Touch event
+    Touch(TouchEvent),
 }
 
 impl InputEvent {
@@ -556,7 +657,7 @@ impl InputEvent {
         InputEvent::Keyboard(KeyboardEvent {
             action,
             code,
-            modifiers: KeyboardModifiers::default(),
+            ..Default::default()
         })
     }
 
@@ -619,6 +720,20 @@ impl InputEvent {
             buttons: MouseButtons::default(),
         })
     }
+
+    /// Create a touch event.
+    ///
+    /// Convenience constructor for touch contacts.
+    ///
+    /// # Arguments
+    ///
+    /// * `action` - Touch action (Down, Move, Up)
+    /// * `slot` - Contact slot
+    /// * `x` - X coordinate in screen space
+    /// * `y` - Y coordinate in screen space
+    pub fn touch(action: TouchAction, slot: u32, x: i32, y: i32) -> Self {
+        InputEvent::Touch(TouchEvent { action, slot, x, y })
+    }
 }
 
 // ============================================================================
@@ -627,7 +742,7 @@ impl InputEvent {
 
 /// Batch input injection request.
 ///
-/// Represents a batch of keyboard and mouse events to be injected
+/// Represents a batch of keyboard, mouse and touch events to be injected
 /// together. This is used for efficient bulk injection over the API.
 ///
 /// # Example
@@ -644,6 +759,7 @@ impl InputEvent {
 ///         KeyboardEvent { action: KeyboardAction::Release, code: 0x1D, ..Default::default() },
 ///     ],
 ///     mouse: vec![],
+///     touch: vec![],
 /// };
 ///
 /// assert_eq!(request.event_count(), 4);
@@ -661,20 +777,23 @@ pub struct InputRequest {
     /// Mouse events to inject.
     #[serde(default)]
     pub mouse: Vec<MouseEvent>,
+    /// Touch events to inject, after the keyboard and mouse ones.
+    #[serde(default)]
+    pub touch: Vec<TouchEvent>,
 }
 
 impl InputRequest {
     /// Check if request is empty.
     ///
-    /// Returns `true` if there are no keyboard or mouse events.
+    /// Returns `true` if there are no keyboard, mouse or touch events.
     pub fn is_empty(&self) -> bool {
-        self.keyboard.is_empty() && self.mouse.is_empty()
+        self.keyboard.is_empty() && self.mouse.is_empty() && self.touch.is_empty()
     }
 
     /// Count total events.
     ///
-    /// Returns the sum of keyboard and mouse event counts.
+    /// Returns the sum of keyboard, mouse and touch event counts.
     pub fn event_count(&self) -> usize {
-        self.keyboard.len() + self.mouse.len()
+        self.keyboard.len() + self.mouse.len() + self.touch.len()
     }
 }
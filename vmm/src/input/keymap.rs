@@ -0,0 +1,353 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Key Code Translation
+//!
+//! Keyboard events name their key in one of several code sets, and each
+//! backend needs it in its own:
+//!
+//! | Code set | Used by |
+//! |----------|---------|
+//! | PS/2 Set 1 | Callers, and the i8042 when the guest selects it or the controller translates |
+//! | PS/2 Set 2 | The i8042 by default |
+//! | Linux evdev `KEY_*` | virtio-input |
+//! | USB HID usage (keyboard page) | USB HID keyboard |
+//! | QEMU `QKeyCode` name | Callers |
+//!
+//! All of them are columns of a single [`Key`] table, so that a key given
+//! in any set can be sent through any backend which has it.
+//!
+//! # Extended Keys
+//!
+//! PS/2 scancodes with an `0xE0` prefix are written `0xE0XX`, in both Set 1
+//! and Set 2. Pause, which is the only key with an `0xE1` prefix, is
+//! `0xE11D` in Set 1 and `0xE114` in Set 2. Pause and Print Screen send
+//! multi-byte sequences, see [`Key::ps2_bytes`].
+//!
+//! # Example
+//!
+//! ```ignore
+//! use devices::legacy::ScancodeSet;
+//! use vmm::input::{KeyCodeSet, KeyboardEvent};
+//!
+//! let event = KeyboardEvent {
+//!     code: 0xE01D, // Right Ctrl
+//!     code_set: KeyCodeSet::Set1,
+//!     ..Default::default()
+//! };
+//! let key = event.key()?;
+//! assert_eq!(key.evdev, 97);
+//! assert_eq!(key.ps2_bytes(ScancodeSet::Set2, true), [0xE0, 0xF0, 0x14]);
+//! ```
+
+use devices::legacy::ScancodeSet;
+use serde::{Deserialize, Serialize};
+
+/// Code set a key code belongs to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyCodeSet {
+    /// PS/2 Set 1 scancode
+    #[default]
+    Set1,
+    /// PS/2 Set 2 scancode
+    Set2,
+    /// Linux evdev `KEY_*` code
+    Evdev,
+    /// USB HID usage on the keyboard page (0x07)
+    Hid,
+}
+
+/// A key, as known in each code set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Key {
+    /// QEMU `QKeyCode` name
+    pub qcode: &'static str,
+    /// Linux evdev `KEY_*` code
+    pub evdev: u16,
+    /// PS/2 Set 1 scancode
+    pub set1: u16,
+    /// PS/2 Set 2 scancode
+    pub set2: u16,
+    /// USB HID usage on the keyboard page, for the keys which have one
+    pub hid: Option<u8>,
+}
+
+const fn key(qcode: &'static str, evdev: u16, set1: u16, set2: u16, hid: u8) -> Key {
+    Key {
+        qcode,
+        evdev,
+        set1,
+        set2,
+        hid: Some(hid),
+    }
+}
+
+// Consumer and system keys live outside of the HID keyboard page
+const fn media_key(qcode: &'static str, evdev: u16, set1: u16, set2: u16) -> Key {
+    Key {
+        qcode,
+        evdev,
+        set1,
+        set2,
+        hid: None,
+    }
+}
+
+const KEY_SYSRQ: u16 = 99;
+const KEY_PAUSE: u16 = 119;
+
+#[rustfmt::skip]
+static KEYS: &[Key] = &[
+    //   qcode               evdev  set1    set2    hid
+    key("esc",                   1, 0x01,   0x76,   0x29),
+    key("1",                     2, 0x02,   0x16,   0x1E),
+    key("2",                     3, 0x03,   0x1E,   0x1F),
+    key("3",                     4, 0x04,   0x26,   0x20),
+    key("4",                     5, 0x05,   0x25,   0x21),
+    key("5",                     6, 0x06,   0x2E,   0x22),
+    key("6",                     7, 0x07,   0x36,   0x23),
+    key("7",                     8, 0x08,   0x3D,   0x24),
+    key("8",                     9, 0x09,   0x3E,   0x25),
+    key("9",                    10, 0x0A,   0x46,   0x26),
+    key("0",                    11, 0x0B,   0x45,   0x27),
+    key("minus",                12, 0x0C,   0x4E,   0x2D),
+    key("equal",                13, 0x0D,   0x55,   0x2E),
+    key("backspace",            14, 0x0E,   0x66,   0x2A),
+    key("tab",                  15, 0x0F,   0x0D,   0x2B),
+    key("q",                    16, 0x10,   0x15,   0x14),
+    key("w",                    17, 0x11,   0x1D,   0x1A),
+    key("e",                    18, 0x12,   0x24,   0x08),
+    key("r",                    19, 0x13,   0x2D,   0x15),
+    key("t",                    20, 0x14,   0x2C,   0x17),
+    key("y",                    21, 0x15,   0x35,   0x1C),
+    key("u",                    22, 0x16,   0x3C,   0x18),
+    key("i",                    23, 0x17,   0x43,   0x0C),
+    key("o",                    24, 0x18,   0x44,   0x12),
+    key("p",                    25, 0x19,   0x4D,   0x13),
+    key("bracket_left",         26, 0x1A,   0x54,   0x2F),
+    key("bracket_right",        27, 0x1B,   0x5B,   0x30),
+    key("ret",                  28, 0x1C,   0x5A,   0x28),
+    key("ctrl",                 29, 0x1D,   0x14,   0xE0),
+    key("a",                    30, 0x1E,   0x1C,   0x04),
+    key("s",                    31, 0x1F,   0x1B,   0x16),
+    key("d",                    32, 0x20,   0x23,   0x07),
+    key("f",                    33, 0x21,   0x2B,   0x09),
+    key("g",                    34, 0x22,   0x34,   0x0A),
+    key("h",                    35, 0x23,   0x33,   0x0B),
+    key("j",                    36, 0x24,   0x3B,   0x0D),
+    key("k",                    37, 0x25,   0x42,   0x0E),
+    key("l",                    38, 0x26,   0x4B,   0x0F),
+    key("semicolon",            39, 0x27,   0x4C,   0x33),
+    key("apostrophe",           40, 0x28,   0x52,   0x34),
+    key("grave_accent",         41, 0x29,   0x0E,   0x35),
+    key("shift",                42, 0x2A,   0x12,   0xE1),
+    key("backslash",            43, 0x2B,   0x5D,   0x31),
+    key("z",                    44, 0x2C,   0x1A,   0x1D),
+    key("x",                    45, 0x2D,   0x22,   0x1B),
+    key("c",                    46, 0x2E,   0x21,   0x06),
+    key("v",                    47, 0x2F,   0x2A,   0x19),
+    key("b",                    48, 0x30,   0x32,   0x05),
+    key("n",                    49, 0x31,   0x31,   0x11),
+    key("m",                    50, 0x32,   0x3A,   0x10),
+    key("comma",                51, 0x33,   0x41,   0x36),
+    key("dot",                  52, 0x34,   0x49,   0x37),
+    key("slash",                53, 0x35,   0x4A,   0x38),
+    key("shift_r",              54, 0x36,   0x59,   0xE5),
+    key("kp_multiply",          55, 0x37,   0x7C,   0x55),
+    key("alt",                  56, 0x38,   0x11,   0xE2),
+    key("spc",                  57, 0x39,   0x29,   0x2C),
+    key("caps_lock",            58, 0x3A,   0x58,   0x39),
+    key("f1",                   59, 0x3B,   0x05,   0x3A),
+    key("f2",                   60, 0x3C,   0x06,   0x3B),
+    key("f3",                   61, 0x3D,   0x04,   0x3C),
+    key("f4",                   62, 0x3E,   0x0C,   0x3D),
+    key("f5",                   63, 0x3F,   0x03,   0x3E),
+    key("f6",                   64, 0x40,   0x0B,   0x3F),
+    key("f7",                   65, 0x41,   0x83,   0x40),
+    key("f8",                   66, 0x42,   0x0A,   0x41),
+    key("f9",                   67, 0x43,   0x01,   0x42),
+    key("f10",                  68, 0x44,   0x09,   0x43),
+    key("num_lock",             69, 0x45,   0x77,   0x53),
+    key("scroll_lock",          70, 0x46,   0x7E,   0x47),
+    key("kp_7",                 71, 0x47,   0x6C,   0x5F),
+    key("kp_8",                 72, 0x48,   0x75,   0x60),
+    key("kp_9",                 73, 0x49,   0x7D,   0x61),
+    key("kp_subtract",          74, 0x4A,   0x7B,   0x56),
+    key("kp_4",                 75, 0x4B,   0x6B,   0x5C),
+    key("kp_5",                 76, 0x4C,   0x73,   0x5D),
+    key("kp_6",                 77, 0x4D,   0x74,   0x5E),
+    key("kp_add",               78, 0x4E,   0x79,   0x57),
+    key("kp_1",                 79, 0x4F,   0x69,   0x59),
+    key("kp_2",                 80, 0x50,   0x72,   0x5A),
+    key("kp_3",                 81, 0x51,   0x7A,   0x5B),
+    key("kp_0",                 82, 0x52,   0x70,   0x62),
+    key("kp_decimal",           83, 0x53,   0x71,   0x63),
+    key("less",                 86, 0x56,   0x61,   0x64),
+    key("f11",                  87, 0x57,   0x78,   0x44),
+    key("f12",                  88, 0x58,   0x07,   0x45),
+    key("ro",                   89, 0x73,   0x51,   0x87),
+    key("henkan",               92, 0x79,   0x64,   0x8A),
+    key("katakanahiragana",     93, 0x70,   0x13,   0x88),
+    key("muhenkan",             94, 0x7B,   0x67,   0x8B),
+    key("kp_enter",             96, 0xE01C, 0xE05A, 0x58),
+    key("ctrl_r",               97, 0xE01D, 0xE014, 0xE4),
+    key("kp_divide",            98, 0xE035, 0xE04A, 0x54),
+    key("print",         KEY_SYSRQ, 0xE037, 0xE07C, 0x46),
+    key("alt_r",               100, 0xE038, 0xE011, 0xE6),
+    key("home",                102, 0xE047, 0xE06C, 0x4A),
+    key("up",                  103, 0xE048, 0xE075, 0x52),
+    key("pgup",                104, 0xE049, 0xE07D, 0x4B),
+    key("left",                105, 0xE04B, 0xE06B, 0x50),
+    key("right",               106, 0xE04D, 0xE074, 0x4F),
+    key("end",                 107, 0xE04F, 0xE069, 0x4D),
+    key("down",                108, 0xE050, 0xE072, 0x51),
+    key("pgdn",                109, 0xE051, 0xE07A, 0x4E),
+    key("insert",              110, 0xE052, 0xE070, 0x49),
+    key("delete",              111, 0xE053, 0xE071, 0x4C),
+    key("power",               116, 0xE05E, 0xE037, 0x66),
+    key("pause",         KEY_PAUSE, 0xE11D, 0xE114, 0x48),
+    key("yen",                 124, 0x7D,   0x6A,   0x89),
+    key("meta_l",              125, 0xE05B, 0xE01F, 0xE3),
+    key("meta_r",              126, 0xE05C, 0xE027, 0xE7),
+    key("menu",                127, 0xE05D, 0xE02F, 0x65),
+    media_key("audiomute",     113, 0xE020, 0xE023),
+    media_key("volumedown",    114, 0xE02E, 0xE021),
+    media_key("volumeup",      115, 0xE030, 0xE032),
+    media_key("calculator",    140, 0xE021, 0xE02B),
+    media_key("sleep",         142, 0xE05F, 0xE03F),
+    media_key("wake",          143, 0xE063, 0xE05E),
+    media_key("mail",          155, 0xE06C, 0xE048),
+    media_key("ac_bookmarks",  156, 0xE066, 0xE018),
+    media_key("computer",      157, 0xE06B, 0xE040),
+    media_key("ac_back",       158, 0xE06A, 0xE038),
+    media_key("ac_forward",    159, 0xE069, 0xE030),
+    media_key("audionext",     163, 0xE019, 0xE04D),
+    media_key("audioplay",     164, 0xE022, 0xE034),
+    media_key("audioprev",     165, 0xE010, 0xE015),
+    media_key("audiostop",     166, 0xE024, 0xE03B),
+    media_key("ac_home",       172, 0xE032, 0xE03A),
+    media_key("ac_refresh",    173, 0xE067, 0xE020),
+];
+
+/// Find a key from its code in `code_set`.
+pub fn lookup(code_set: KeyCodeSet, code: u16) -> Option<&'static Key> {
+    KEYS.iter().find(|key| match code_set {
+        KeyCodeSet::Set1 => key.set1 == code,
+        KeyCodeSet::Set2 => key.set2 == code,
+        KeyCodeSet::Evdev => key.evdev == code,
+        KeyCodeSet::Hid => key.hid.map(u16::from) == Some(code),
+    })
+}
+
+/// Find a key from its QEMU `QKeyCode` name.
+pub fn lookup_qcode(name: &str) -> Option<&'static Key> {
+    KEYS.iter().find(|key| key.qcode.eq_ignore_ascii_case(name))
+}
+
+impl Key {
+    /// Bytes a PS/2 keyboard sends for this key in `set`.
+    ///
+    /// Print Screen sends a fake Shift around its code, and Pause sends a
+    /// whole make sequence on press and nothing on release.
+    pub fn ps2_bytes(&self, set: ScancodeSet, release: bool) -> Vec<u8> {
+        match (self.evdev, set, release) {
+            (KEY_PAUSE, ScancodeSet::Set1, false) => vec![0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5],
+            (KEY_PAUSE, ScancodeSet::Set2, false) => {
+                vec![0xE1, 0x14, 0x77, 0xE1, 0xF0, 0x14, 0xF0, 0x77]
+            }
+            (KEY_PAUSE, _, true) => Vec::new(),
+            (KEY_SYSRQ, ScancodeSet::Set1, false) => vec![0xE0, 0x2A, 0xE0, 0x37],
+            (KEY_SYSRQ, ScancodeSet::Set1, true) => vec![0xE0, 0xB7, 0xE0, 0xAA],
+            (KEY_SYSRQ, ScancodeSet::Set2, false) => vec![0xE0, 0x12, 0xE0, 0x7C],
+            (KEY_SYSRQ, ScancodeSet::Set2, true) => vec![0xE0, 0xF0, 0x7C, 0xE0, 0xF0, 0x12],
+            (_, ScancodeSet::Set1, _) => {
+                let mut bytes = Self::prefix(self.set1);
+                // Break codes have the top bit set
+                bytes.push(self.set1 as u8 | if release { 0x80 } else { 0 });
+                bytes
+            }
+            (_, ScancodeSet::Set2, _) => {
+                let mut bytes = Self::prefix(self.set2);
+                // Break codes come after a 0xF0 byte
+                if release {
+                    bytes.push(0xF0);
+                }
+                bytes.push(self.set2 as u8);
+                bytes
+            }
+        }
+    }
+
+    fn prefix(scancode: u16) -> Vec<u8> {
+        match scancode >> 8 {
+            0 => Vec::new(),
+            prefix => vec![prefix as u8],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_are_unique() {
+        for (i, key) in KEYS.iter().enumerate() {
+            for other in &KEYS[i + 1..] {
+                assert_ne!(key.qcode, other.qcode);
+                assert_ne!(key.evdev, other.evdev, "{}", key.qcode);
+                assert_ne!(key.set1, other.set1, "{}", key.qcode);
+                assert_ne!(key.set2, other.set2, "{}", key.qcode);
+                if key.hid.is_some() {
+                    assert_ne!(key.hid, other.hid, "{}", key.qcode);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let right_ctrl = lookup_qcode("ctrl_r").unwrap();
+        assert_eq!(lookup(KeyCodeSet::Set1, 0xE01D), Some(right_ctrl));
+        assert_eq!(lookup(KeyCodeSet::Set2, 0xE014), Some(right_ctrl));
+        assert_eq!(lookup(KeyCodeSet::Evdev, 97), Some(right_ctrl));
+        assert_eq!(lookup(KeyCodeSet::Hid, 0xE4), Some(right_ctrl));
+
+        // Plain Set 1 scancodes are evdev codes
+        assert_eq!(
+            lookup(KeyCodeSet::Set1, 0x1E),
+            lookup(KeyCodeSet::Evdev, 0x1E)
+        );
+        assert_eq!(
+            lookup(KeyCodeSet::Set1, 0x1E),
+            lookup(KeyCodeSet::Set2, 0x1C)
+        );
+        assert_eq!(lookup_qcode("Meta_L").unwrap().evdev, 125);
+        assert!(lookup(KeyCodeSet::Hid, 0).is_none());
+        assert!(lookup_qcode("nope").is_none());
+    }
+
+    #[test]
+    fn test_ps2_bytes() {
+        let up = lookup_qcode("up").unwrap();
+        assert_eq!(up.ps2_bytes(ScancodeSet::Set1, false), [0xE0, 0x48]);
+        assert_eq!(up.ps2_bytes(ScancodeSet::Set1, true), [0xE0, 0xC8]);
+        assert_eq!(up.ps2_bytes(ScancodeSet::Set2, false), [0xE0, 0x75]);
+        assert_eq!(up.ps2_bytes(ScancodeSet::Set2, true), [0xE0, 0xF0, 0x75]);
+
+        let a = lookup_qcode("a").unwrap();
+        assert_eq!(a.ps2_bytes(ScancodeSet::Set1, true), [0x9E]);
+        assert_eq!(a.ps2_bytes(ScancodeSet::Set2, true), [0xF0, 0x1C]);
+
+        let pause = lookup_qcode("pause").unwrap();
+        assert_eq!(pause.ps2_bytes(ScancodeSet::Set2, false).len(), 8);
+        assert!(pause.ps2_bytes(ScancodeSet::Set1, true).is_empty());
+
+        let print = lookup_qcode("print").unwrap();
+        assert_eq!(
+            print.ps2_bytes(ScancodeSet::Set2, true),
+            [0xE0, 0xF0, 0x7C, 0xE0, 0xF0, 0x12]
+        );
+    }
+}
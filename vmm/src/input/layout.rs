@@ -0,0 +1,432 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Keyboard Layouts
+//!
+//! Typing text means pressing, for each character, the key which produces
+//! it with the keyboard layout the guest uses, holding Shift or AltGr when
+//! the character needs them. Keys are named by their PS/2 Set 1 scancode,
+//! which identifies the physical key whatever the layout.
+//!
+//! Characters produced by a dead key, such as `^` on German keyboards, are
+//! typed as the dead key followed by Space. Other characters which need a
+//! dead key, such as `é` on German keyboards, cannot be typed.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use vmm::input::{push_text, EventBatcher, KeyboardLayout};
+//!
+//! let mut batcher = EventBatcher::default_batcher();
+//! let untypable = push_text(&mut batcher, "Grüße", KeyboardLayout::De);
+//! assert!(untypable.is_empty());
+//! assert_eq!(batcher.pending_count(), 5);
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+use super::batch::EventBatcher;
+use super::event::{KeyboardAction, KeyboardEvent};
+
+/// Default delay between two typed characters (milliseconds)
+pub const DEFAULT_KEY_DELAY_MS: u64 = 10;
+
+const KEY_SHIFT: u16 = 0x2A;
+const KEY_ALTGR: u16 = 0xE038;
+const KEY_SPACE: u16 = 0x39;
+const KEY_TAB: u16 = 0x0F;
+const KEY_ENTER: u16 = 0x1C;
+
+/// Keyboard layout of the guest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyboardLayout {
+    /// US English (QWERTY)
+    #[default]
+    Us,
+    /// German (QWERTZ)
+    De,
+    /// French (AZERTY)
+    Fr,
+    /// Japanese (JIS, 106/109 keys)
+    Jp,
+}
+
+impl KeyboardLayout {
+    /// Get the layout name
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyboardLayout::Us => "us",
+            KeyboardLayout::De => "de",
+            KeyboardLayout::Fr => "fr",
+            KeyboardLayout::Jp => "jp",
+        }
+    }
+
+    /// Parse a layout name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "us" | "en-us" => Some(KeyboardLayout::Us),
+            "de" | "de-de" => Some(KeyboardLayout::De),
+            "fr" | "fr-fr" => Some(KeyboardLayout::Fr),
+            "jp" | "ja" => Some(KeyboardLayout::Jp),
+            _ => None,
+        }
+    }
+
+    /// Keystrokes producing `c`, or `None` if the layout has no key for it.
+    pub fn keystrokes(&self, c: char) -> Option<Vec<Keystroke>> {
+        let code = match c {
+            ' ' => KEY_SPACE,
+            '\t' => KEY_TAB,
+            '\n' | '\r' => KEY_ENTER,
+            _ => return self.rows().iter().find_map(|row| row.keystrokes(c)),
+        };
+        Some(vec![Keystroke::plain(code)])
+    }
+
+    fn rows(&self) -> &'static [Row] {
+        match self {
+            KeyboardLayout::Us => US,
+            KeyboardLayout::De => DE,
+            KeyboardLayout::Fr => FR,
+            KeyboardLayout::Jp => JP,
+        }
+    }
+}
+
+/// A key press, with the modifiers held around it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keystroke {
+    /// PS/2 Set 1 scancode of the key
+    pub code: u16,
+    /// Hold Shift
+    pub shift: bool,
+    /// Hold AltGr (Right Alt)
+    pub altgr: bool,
+}
+
+impl Keystroke {
+    fn plain(code: u16) -> Self {
+        Keystroke {
+            code,
+            shift: false,
+            altgr: false,
+        }
+    }
+
+    fn modifiers(&self) -> impl Iterator<Item = u16> {
+        [(self.shift, KEY_SHIFT), (self.altgr, KEY_ALTGR)]
+            .into_iter()
+            .filter_map(|(held, code)| held.then_some(code))
+    }
+}
+
+/// Character of a text which the layout cannot produce.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UntypableChar {
+    /// Position of the character in the text, in characters
+    pub index: usize,
+    /// The character
+    pub character: char,
+}
+
+/// Queue the keyboard events typing `text` into `batcher`.
+///
+/// Each character is flushed as a batch of its own, so that the caller can
+/// wait between characters. Returns the characters which were skipped
+/// because `layout` cannot produce them.
+pub fn push_text(
+    batcher: &mut EventBatcher,
+    text: &str,
+    layout: KeyboardLayout,
+) -> Vec<UntypableChar> {
+    let mut untypable = Vec::new();
+    let mut chars = text.chars().enumerate().peekable();
+
+    while let Some((index, character)) = chars.next() {
+        // A CRLF line ending is a single Enter
+        if character == '\r' && matches!(chars.peek(), Some((_, '\n'))) {
+            continue;
+        }
+
+        let Some(keystrokes) = layout.keystrokes(character) else {
+            untypable.push(UntypableChar { index, character });
+            continue;
+        };
+
+        for keystroke in keystrokes {
+            for modifier in keystroke.modifiers() {
+                batcher.push_keyboard(key_event(KeyboardAction::Press, modifier));
+            }
+            batcher.push_keyboard(key_event(KeyboardAction::Type, keystroke.code));
+            for modifier in keystroke.modifiers() {
+                batcher.push_keyboard(key_event(KeyboardAction::Release, modifier));
+            }
+        }
+        batcher.flush_all();
+    }
+
+    untypable
+}
+
+fn key_event(action: KeyboardAction, code: u16) -> KeyboardEvent {
+    KeyboardEvent {
+        action,
+        code,
+        ..Default::default()
+    }
+}
+
+/// Characters of a row of keys, without modifier, with Shift and with AltGr.
+///
+/// Spaces stand for keys which produce nothing at that level.
+struct Row {
+    keys: &'static [u16],
+    plain: &'static str,
+    shift: &'static str,
+    altgr: &'static str,
+    /// The keys are dead keys
+    dead: bool,
+}
+
+impl Row {
+    fn keystrokes(&self, c: char) -> Option<Vec<Keystroke>> {
+        let levels = [
+            (self.plain, false, false),
+            (self.shift, true, false),
+            (self.altgr, false, true),
+        ];
+        let keystroke = levels.iter().find_map(|&(chars, shift, altgr)| {
+            chars
+                .chars()
+                .zip(self.keys)
+                .find(|&(key_char, _)| key_char == c && key_char != ' ')
+                .map(|(_, &code)| Keystroke { code, shift, altgr })
+        })?;
+
+        let mut keystrokes = vec![keystroke];
+        if self.dead {
+            keystrokes.push(Keystroke::plain(KEY_SPACE));
+        }
+        Some(keystrokes)
+    }
+}
+
+const fn row(
+    keys: &'static [u16],
+    plain: &'static str,
+    shift: &'static str,
+    altgr: &'static str,
+) -> Row {
+    Row {
+        keys,
+        plain,
+        shift,
+        altgr,
+        dead: false,
+    }
+}
+
+const fn dead_keys(keys: &'static [u16], plain: &'static str, shift: &'static str) -> Row {
+    Row {
+        keys,
+        plain,
+        shift,
+        altgr: "",
+        dead: true,
+    }
+}
+
+// Physical key positions, by Set 1 scancode. 0x2B is above Enter on ISO
+// keyboards and 0x56 is next to the left Shift.
+const NUMBER_ROW: &[u16] = &[
+    0x29, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D,
+];
+const TOP_ROW: &[u16] = &[
+    0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x1B, 0x2B,
+];
+const HOME_ROW: &[u16] = &[
+    0x1E, 0x1F, 0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28,
+];
+const BOTTOM_ROW: &[u16] = &[
+    0x56, 0x2C, 0x2D, 0x2E, 0x2F, 0x30, 0x31, 0x32, 0x33, 0x34, 0x35,
+];
+// Yen and Ro keys of JIS keyboards
+const JIS_KEYS: &[u16] = &[0x7D, 0x73];
+
+#[rustfmt::skip]
+static US: &[Row] = &[
+    row(NUMBER_ROW, "`1234567890-=", "~!@#$%^&*()_+", ""),
+    row(TOP_ROW,    "qwertyuiop[]\\", "QWERTYUIOP{}|", ""),
+    row(HOME_ROW,   "asdfghjkl;'",   "ASDFGHJKL:\"",  ""),
+    row(BOTTOM_ROW, " zxcvbnm,./",   " ZXCVBNM<>?",   ""),
+];
+
+#[rustfmt::skip]
+static DE: &[Row] = &[
+    row(NUMBER_ROW, " 1234567890ß ", "°!\"§$%&/()=? ", "  ²³   {[]}\\ "),
+    row(TOP_ROW,    "qwertzuiopü+#", "QWERTZUIOPÜ*'", "@ €        ~ "),
+    row(HOME_ROW,   "asdfghjklöä",   "ASDFGHJKLÖÄ",   ""),
+    row(BOTTOM_ROW, "<yxcvbnm,.-",   ">YXCVBNM;:_",   "|      µ   "),
+    dead_keys(&[0x29, 0x0D], "^´", " `"),
+];
+
+#[rustfmt::skip]
+static FR: &[Row] = &[
+    row(NUMBER_ROW, "²&é\"'(-è_çà)=", " 1234567890°+", "  ~#{[|`\\^@]}"),
+    row(TOP_ROW,    "azertyuiop $*", "AZERTYUIOP £µ", "  €        ¤ "),
+    row(HOME_ROW,   "qsdfghjklmù",   "QSDFGHJKLM%",   ""),
+    row(BOTTOM_ROW, "<wxcvbn,;:!",   ">WXCVBN?./§",   ""),
+    dead_keys(&[0x1A], "^", "¨"),
+];
+
+#[rustfmt::skip]
+static JP: &[Row] = &[
+    row(NUMBER_ROW, " 1234567890-^", " !\"#$%&'() =~", ""),
+    row(TOP_ROW,    "qwertyuiop@[]", "QWERTYUIOP`{}", ""),
+    row(HOME_ROW,   "asdfghjkl;:",   "ASDFGHJKL+*",   ""),
+    row(BOTTOM_ROW, " zxcvbnm,./",   " ZXCVBNM<>?",   ""),
+    row(JIS_KEYS,   "\\\\",          "|_",            ""),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keystroke(code: u16, shift: bool, altgr: bool) -> Keystroke {
+        Keystroke { code, shift, altgr }
+    }
+
+    #[test]
+    fn test_rows_fit_keys() {
+        for layout in [
+            KeyboardLayout::Us,
+            KeyboardLayout::De,
+            KeyboardLayout::Fr,
+            KeyboardLayout::Jp,
+        ] {
+            for row in layout.rows() {
+                for chars in [row.plain, row.shift, row.altgr] {
+                    assert!(
+                        chars.chars().count() <= row.keys.len(),
+                        "{}: {chars}",
+                        layout.name()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_keystrokes() {
+        let us = KeyboardLayout::Us;
+        assert_eq!(
+            us.keystrokes('a'),
+            Some(vec![keystroke(0x1E, false, false)])
+        );
+        assert_eq!(us.keystrokes('A'), Some(vec![keystroke(0x1E, true, false)]));
+        assert_eq!(us.keystrokes('|'), Some(vec![keystroke(0x2B, true, false)]));
+        assert_eq!(
+            us.keystrokes('\n'),
+            Some(vec![keystroke(KEY_ENTER, false, false)])
+        );
+        assert_eq!(us.keystrokes('€'), None);
+
+        let de = KeyboardLayout::De;
+        assert_eq!(
+            de.keystrokes('z'),
+            Some(vec![keystroke(0x15, false, false)])
+        );
+        assert_eq!(de.keystrokes('@'), Some(vec![keystroke(0x10, false, true)]));
+        assert_eq!(
+            de.keystrokes('ß'),
+            Some(vec![keystroke(0x0C, false, false)])
+        );
+        assert_eq!(
+            de.keystrokes('^'),
+            Some(vec![
+                keystroke(0x29, false, false),
+                keystroke(KEY_SPACE, false, false)
+            ])
+        );
+        assert_eq!(
+            de.keystrokes('`'),
+            Some(vec![
+                keystroke(0x0D, true, false),
+                keystroke(KEY_SPACE, false, false)
+            ])
+        );
+        assert_eq!(de.keystrokes('é'), None);
+
+        // AltGr+9 gives a plain circumflex, no need for the dead key
+        let fr = KeyboardLayout::Fr;
+        assert_eq!(fr.keystrokes('^'), Some(vec![keystroke(0x0A, false, true)]));
+        assert_eq!(fr.keystrokes('1'), Some(vec![keystroke(0x02, true, false)]));
+        assert_eq!(
+            fr.keystrokes('a'),
+            Some(vec![keystroke(0x10, false, false)])
+        );
+
+        let jp = KeyboardLayout::Jp;
+        assert_eq!(
+            jp.keystrokes('@'),
+            Some(vec![keystroke(0x1A, false, false)])
+        );
+        assert_eq!(
+            jp.keystrokes('\\'),
+            Some(vec![keystroke(0x7D, false, false)])
+        );
+        assert_eq!(jp.keystrokes('_'), Some(vec![keystroke(0x73, true, false)]));
+    }
+
+    #[test]
+    fn test_push_text() {
+        let mut batcher = EventBatcher::default_batcher();
+        let untypable = push_text(&mut batcher, "a€B\r\n", KeyboardLayout::Us);
+
+        assert_eq!(
+            untypable,
+            vec![UntypableChar {
+                index: 1,
+                character: '€'
+            }]
+        );
+        // One batch per typed character, CRLF being one
+        assert_eq!(batcher.pending_count(), 3);
+
+        let request = batcher.pop_request().unwrap();
+        assert_eq!(request.keyboard.len(), 1);
+        assert_eq!(request.keyboard[0].code, 0x1E);
+
+        let request = batcher.pop_request().unwrap();
+        let events: Vec<_> = request
+            .keyboard
+            .iter()
+            .map(|event| (event.action, event.code))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (KeyboardAction::Press, KEY_SHIFT),
+                (KeyboardAction::Type, 0x30),
+                (KeyboardAction::Release, KEY_SHIFT),
+            ]
+        );
+
+        let request = batcher.pop_request().unwrap();
+        assert_eq!(request.keyboard.len(), 1);
+        assert_eq!(request.keyboard[0].code, KEY_ENTER);
+    }
+
+    #[test]
+    fn test_layout_names() {
+        assert_eq!(KeyboardLayout::from_name("DE"), Some(KeyboardLayout::De));
+        assert_eq!(KeyboardLayout::from_name("ja"), Some(KeyboardLayout::Jp));
+        assert_eq!(KeyboardLayout::from_name("dvorak"), None);
+        assert_eq!(
+            serde_json::from_str::<KeyboardLayout>("\"fr\"").unwrap(),
+            KeyboardLayout::Fr
+        );
+    }
+}
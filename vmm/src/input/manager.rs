@@ -6,11 +6,22 @@
 //! The Input Manager coordinates input injection across multiple backends
 //! and provides a unified API for input operations.
 
-use super::backend::{BackendType, InputBackend, InputCapabilities, Ps2Backend, VirtioInputBackend};
+use super::backend::{
+    BackendState, BackendType, InputBackend, InputCapabilities, KeyboardLeds, Ps2Backend,
+    UsbHidBackend, VirtioInputBackend,
+};
+use super::batch::{BatchConfig, EventBatcher};
 use super::event::{InputEvent, InputRequest, KeyboardEvent, MouseEvent};
+use super::layout::{self, KeyboardLayout, UntypableChar};
+use super::pacing::{BackendPacer, Delivery, InputPacer};
+use super::script::{InputRecorder, InputReplay, InputScript, ReplayStatus, ScriptEvent};
 use super::{InputError, Result};
-use std::collections::HashMap;
+use devices::legacy::I8042Device;
+use devices::usb::SharedUsbHidDevice;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 /// Input manager configuration
 #[derive(Clone, Debug, Default)]
@@ -67,17 +78,154 @@ pub struct InputManager {
     ps2_backend: Option<Ps2Backend>,
     /// VirtIO backend
     virtio_backend: Option<VirtioInputBackend>,
+    /// USB HID backend
+    usb_backend: Option<UsbHidBackend>,
     /// Statistics
     stats: InputStats,
+    /// Recording of the injected events
+    recorder: Option<InputRecorder>,
+    /// Script being replayed
+    replay: Option<InputReplay>,
+    /// Characters left to type by [`InputManager::type_text`]
+    typing: Option<InputReplay>,
+    /// Rate limit and deferred events of each backend
+    pacers: HashMap<BackendType, BackendPacer>,
+    /// Thread sending the deferred events
+    pacer: Option<InputPacer>,
+}
+
+/// Event counters of a single backend
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackendStats {
+    pub keyboard_events: u64,
+    pub mouse_events: u64,
+    #[serde(default)]
+    pub touch_events: u64,
+    pub total_events: u64,
+    pub errors: u64,
+    #[serde(default)]
+    pub deferred_events: u64,
+    #[serde(default)]
+    pub dropped_events: u64,
 }
 
 /// Input statistics
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct InputStats {
     pub keyboard_events: u64,
     pub mouse_events: u64,
+    #[serde(default)]
+    pub touch_events: u64,
     pub total_events: u64,
     pub errors: u64,
+    /// Events which had to wait for the backend, sent or not yet
+    #[serde(default)]
+    pub deferred_events: u64,
+    /// Events dropped because too many were waiting for the backend
+    #[serde(default)]
+    pub dropped_events: u64,
+    /// Same counters for each backend which was sent events, by backend name
+    #[serde(default)]
+    pub backends: BTreeMap<String, BackendStats>,
+}
+
+impl InputStats {
+    /// Get the counters of a backend
+    pub fn backend(&self, backend: BackendType) -> BackendStats {
+        self.backends
+            .get(backend.name())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Account for an event submitted to `backend`
+    fn record(&mut self, backend: BackendType, event: &InputEvent, result: &Result<Delivery>) {
+        let backend_stats = self.backends.entry(backend.name().to_string()).or_default();
+        match result {
+            Err(_) => {
+                self.errors += 1;
+                backend_stats.errors += 1;
+                return;
+            }
+            Ok(Delivery::Deferred) => {
+                self.deferred_events += 1;
+                backend_stats.deferred_events += 1;
+                return;
+            }
+            Ok(Delivery::Dropped) => {
+                self.dropped_events += 1;
+                backend_stats.dropped_events += 1;
+                return;
+            }
+            Ok(Delivery::Sent) => {}
+        }
+
+        match event {
+            InputEvent::Keyboard(_) => {
+                self.keyboard_events += 1;
+                backend_stats.keyboard_events += 1;
+            }
+            InputEvent::Mouse(_) => {
+                self.mouse_events += 1;
+                backend_stats.mouse_events += 1;
+            }
+            InputEvent::Touch(_) => {
+                self.touch_events += 1;
+                backend_stats.touch_events += 1;
+            }
+        }
+        self.total_events += 1;
+        backend_stats.total_events += 1;
+    }
+
+    /// Add the counters of `other`
+    fn merge(&mut self, other: &InputStats) {
+        self.keyboard_events += other.keyboard_events;
+        self.mouse_events += other.mouse_events;
+        self.touch_events += other.touch_events;
+        self.total_events += other.total_events;
+        self.errors += other.errors;
+        self.deferred_events += other.deferred_events;
+        self.dropped_events += other.dropped_events;
+        for (name, other) in &other.backends {
+            let backend_stats = self.backends.entry(name.clone()).or_default();
+            backend_stats.keyboard_events += other.keyboard_events;
+            backend_stats.mouse_events += other.mouse_events;
+            backend_stats.touch_events += other.touch_events;
+            backend_stats.total_events += other.total_events;
+            backend_stats.errors += other.errors;
+            backend_stats.deferred_events += other.deferred_events;
+            backend_stats.dropped_events += other.dropped_events;
+        }
+    }
+}
+
+/// State the guest set up on the input devices
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct InputState {
+    /// Backend the events are sent to when a request doesn't name one
+    pub active_backend: String,
+    /// The guest driver enabled the keyboard of the active backend
+    pub keyboard_ready: bool,
+    /// The guest driver enabled the pointer of the active backend
+    pub pointer_ready: bool,
+    /// Keyboard LEDs of the active backend
+    pub leds: KeyboardLeds,
+    /// State of each backend with a device in the VM, by backend name
+    pub backends: BTreeMap<String, BackendState>,
+}
+
+/// Outcome of [`InputManager::type_text`]
+#[derive(Clone, Debug, Default)]
+pub struct TypedText {
+    /// Number of characters typed, including the ones left to type
+    pub characters: u64,
+    /// Characters skipped because the layout cannot produce them
+    pub untypable: Vec<UntypableChar>,
+    /// Counters of the events sent before returning
+    pub stats: InputStats,
+    /// Number of events left to send, `key_delay` apart, after returning
+    pub queued_events: u64,
 }
 
 impl InputManager {
@@ -90,7 +238,13 @@ impl InputManager {
             active_backend,
             ps2_backend: None,
             virtio_backend: None,
+            usb_backend: None,
             stats: InputStats::default(),
+            recorder: None,
+            replay: None,
+            typing: None,
+            pacers: HashMap::new(),
+            pacer: None,
         }
     }
 
@@ -104,13 +258,92 @@ impl InputManager {
     // ========================================================================
 
     /// Initialize PS/2 backend
-    pub fn init_ps2_backend(&mut self) {
-        self.ps2_backend = Some(Ps2Backend::new());
+    ///
+    /// The handle is the VM's i8042 controller.
+    pub fn init_ps2_backend(&mut self, device: Arc<Mutex<I8042Device>>) {
+        let mut backend = Ps2Backend::new();
+        backend.set_device(device);
+        self.add_pacer(BackendType::Ps2, &backend);
+        self.ps2_backend = Some(backend);
     }
 
     /// Initialize VirtIO backend
-    pub fn init_virtio_backend(&mut self) {
-        self.virtio_backend = Some(VirtioInputBackend::new());
+    ///
+    /// The handle is the VM's virtio-input device.
+    pub fn init_virtio_backend(&mut self, device: Arc<Mutex<virtio_devices::VirtioInput>>) {
+        let mut backend = VirtioInputBackend::new();
+        backend.set_device(device);
+        backend.set_ready(true);
+        self.add_pacer(BackendType::Virtio, &backend);
+        self.virtio_backend = Some(backend);
+    }
+
+    /// Drop the VirtIO backend once its device is unplugged
+    ///
+    /// When it was the active backend, injection moves to the first
+    /// remaining one.
+    pub fn remove_virtio_backend(&mut self) {
+        self.virtio_backend = None;
+        self.pacers.remove(&BackendType::Virtio);
+        if self.active_backend == BackendType::Virtio
+            && let Some(&backend) = self.available_backends().first()
+        {
+            self.active_backend = backend;
+        }
+    }
+
+    /// Initialize USB HID backend
+    ///
+    /// The handles are the HID devices attached to the VM's xHCI
+    /// controller; the backend is ready as soon as one of them is present.
+    pub fn init_usb_backend(
+        &mut self,
+        keyboard: Option<SharedUsbHidDevice>,
+        mouse: Option<SharedUsbHidDevice>,
+        tablet: Option<SharedUsbHidDevice>,
+    ) {
+        let mut backend = UsbHidBackend::new();
+        let ready = keyboard.is_some() || mouse.is_some() || tablet.is_some();
+        if let Some(keyboard) = keyboard {
+            backend.set_keyboard_device(keyboard);
+        }
+        if let Some(mouse) = mouse {
+            backend.set_mouse_device(mouse);
+        }
+        if let Some(tablet) = tablet {
+            backend.set_tablet_device(tablet);
+        }
+        backend.set_ready(ready);
+        self.add_pacer(BackendType::UsbHid, &backend);
+        self.usb_backend = Some(backend);
+    }
+
+    /// Pace the events of `backend` to the rate `instance` takes
+    fn add_pacer(&mut self, backend: BackendType, instance: &dyn InputBackend) {
+        let rate = instance.capabilities().max_keyboard_rate;
+        self.pacers.insert(backend, BackendPacer::new(rate));
+    }
+
+    /// Start the thread sending the events `manager` defers
+    ///
+    /// Without it, the deferred events are only sent by
+    /// [`send_deferred`](Self::send_deferred).
+    pub fn start_pacing(manager: &Arc<Mutex<Self>>) -> Result<()> {
+        let pacer = InputPacer::start(manager)?;
+        manager.lock().unwrap().pacer = Some(pacer);
+        Ok(())
+    }
+
+    /// Set the screen size absolute positions are relative to
+    ///
+    /// The backends with absolute pointers scale the positions from it.
+    pub fn set_screen_size(&mut self, width: u32, height: u32) {
+        if let Some(backend) = self.virtio_backend.as_mut() {
+            backend.set_screen_dimensions(width, height);
+        }
+        if let Some(backend) = self.usb_backend.as_mut() {
+            backend.set_screen_dimensions(width, height);
+        }
     }
 
     /// Get active backend type
@@ -126,48 +359,76 @@ impl InputManager {
             ));
         }
 
-        // Check if backend is available
-        match backend {
-            BackendType::Ps2 => {
-                if self.ps2_backend.is_none() {
-                    return Err(InputError::BackendNotAvailable(
-                        "PS/2 backend not initialized".to_string(),
-                    ));
-                }
-            }
-            BackendType::Virtio => {
-                if self.virtio_backend.is_none() {
-                    return Err(InputError::BackendNotAvailable(
-                        "VirtIO backend not initialized".to_string(),
-                    ));
-                }
-            }
-            BackendType::UsbHid => {
-                return Err(InputError::BackendNotAvailable(
-                    "USB HID backend not yet implemented".to_string(),
-                ));
-            }
-        }
+        self.backend_mut(backend)?;
 
         self.active_backend = backend;
         Ok(())
     }
 
+    /// Get the initialized backends
+    pub fn available_backends(&self) -> Vec<BackendType> {
+        let mut backends = Vec::new();
+        if self.ps2_backend.is_some() {
+            backends.push(BackendType::Ps2);
+        }
+        if self.virtio_backend.is_some() {
+            backends.push(BackendType::Virtio);
+        }
+        if self.usb_backend.is_some() {
+            backends.push(BackendType::UsbHid);
+        }
+        backends
+    }
+
+    fn backend(&self, backend: BackendType) -> Option<&dyn InputBackend> {
+        match backend {
+            BackendType::Ps2 => self.ps2_backend.as_ref().map(|b| b as &dyn InputBackend),
+            BackendType::Virtio => self.virtio_backend.as_ref().map(|b| b as &dyn InputBackend),
+            BackendType::UsbHid => self.usb_backend.as_ref().map(|b| b as &dyn InputBackend),
+        }
+    }
+
+    fn backend_mut(&mut self, backend: BackendType) -> Result<&mut dyn InputBackend> {
+        let (instance, label) = match backend {
+            BackendType::Ps2 => (
+                self.ps2_backend
+                    .as_mut()
+                    .map(|b| b as &mut dyn InputBackend),
+                "PS/2",
+            ),
+            BackendType::Virtio => (
+                self.virtio_backend
+                    .as_mut()
+                    .map(|b| b as &mut dyn InputBackend),
+                "VirtIO",
+            ),
+            BackendType::UsbHid => (
+                self.usb_backend
+                    .as_mut()
+                    .map(|b| b as &mut dyn InputBackend),
+                "USB HID",
+            ),
+        };
+        instance.ok_or_else(|| {
+            InputError::BackendNotAvailable(format!("{label} backend not initialized"))
+        })
+    }
+
     /// Get active backend capabilities
     pub fn capabilities(&self) -> Option<InputCapabilities> {
         match self.active_backend {
             BackendType::Ps2 => self.ps2_backend.as_ref().map(|b| b.capabilities()),
             BackendType::Virtio => self.virtio_backend.as_ref().map(|b| b.capabilities()),
-            BackendType::UsbHid => None,
+            BackendType::UsbHid => self.usb_backend.as_ref().map(|b| b.capabilities()),
         }
     }
 
     /// Check if input is ready
     pub fn is_ready(&self) -> bool {
         match self.active_backend {
-            BackendType::Ps2 => self.ps2_backend.as_ref().map_or(false, |b| b.is_ready()),
-            BackendType::Virtio => self.virtio_backend.as_ref().map_or(false, |b| b.is_ready()),
-            BackendType::UsbHid => false,
+            BackendType::Ps2 => self.ps2_backend.as_ref().is_some_and(|b| b.is_ready()),
+            BackendType::Virtio => self.virtio_backend.as_ref().is_some_and(|b| b.is_ready()),
+            BackendType::UsbHid => self.usb_backend.as_ref().is_some_and(|b| b.is_ready()),
         }
     }
 
@@ -175,103 +436,292 @@ impl InputManager {
     // Input Injection
     // ========================================================================
 
-    /// Inject a single event
+    /// Inject a single event through the active backend
     pub fn inject(&mut self, event: &InputEvent) -> Result<()> {
-        match self.active_backend {
-            BackendType::Ps2 => {
-                if let Some(ref mut backend) = self.ps2_backend {
-                    backend.inject(event)?;
+        self.inject_with(self.active_backend, event)
+    }
+
+    /// Inject a single event through `backend`, whether it is active or not
+    ///
+    /// An event the backend can't take yet is deferred, which still counts
+    /// as injected. It fails with [`InputError::BufferOverflow`] only when
+    /// too many events are deferred already and it is dropped.
+    pub fn inject_with(&mut self, backend: BackendType, event: &InputEvent) -> Result<()> {
+        match self.submit(backend, event)? {
+            Delivery::Dropped => Err(InputError::BufferOverflow),
+            Delivery::Sent | Delivery::Deferred => Ok(()),
+        }
+    }
+
+    /// Send `event` through `backend`, or defer it behind the events
+    /// already deferred, over the rate of the backend or while its device
+    /// is full
+    fn submit(&mut self, backend: BackendType, event: &InputEvent) -> Result<Delivery> {
+        let result = match event {
+            InputEvent::Keyboard(_) if !self.config.enable_keyboard => Err(
+                InputError::UnsupportedAction("Keyboard input is disabled".to_string()),
+            ),
+            InputEvent::Mouse(_) if !self.config.enable_mouse => Err(
+                InputError::UnsupportedAction("Mouse input is disabled".to_string()),
+            ),
+            _ => self.send_or_defer(backend, event),
+        };
+
+        self.stats.record(backend, event, &result);
+        if matches!(result, Ok(Delivery::Sent | Delivery::Deferred))
+            && let Some(recorder) = self.recorder.as_mut()
+        {
+            recorder.record(event);
+        }
+        result
+    }
+
+    fn send_or_defer(&mut self, backend: BackendType, event: &InputEvent) -> Result<Delivery> {
+        self.backend_mut(backend)?;
+        let throttled = match self.pacers.get_mut(&backend) {
+            Some(pacer) => !pacer.is_idle() || !pacer.acquire(event),
+            None => false,
+        };
+        if throttled {
+            return Ok(self.defer(backend, event));
+        }
+
+        let instance = self.backend_mut(backend)?;
+        match instance.inject(event) {
+            Ok(()) => {
+                // The PS/2 backend holds back the mouse packets the i8042
+                // can't take, the pacing thread sends them later
+                if let Err(InputError::BufferOverflow) = instance.flush() {
+                    self.wake_pacer();
                 }
+                Ok(Delivery::Sent)
             }
-            BackendType::Virtio => {
-                if let Some(ref mut backend) = self.virtio_backend {
-                    backend.inject(event)?;
+            Err(InputError::BufferOverflow) => {
+                if let Some(pacer) = self.pacers.get_mut(&backend) {
+                    pacer.release(event);
                 }
+                Ok(self.defer(backend, event))
             }
-            BackendType::UsbHid => {
-                return Err(InputError::BackendNotAvailable(
-                    "USB HID not implemented".to_string(),
-                ));
-            }
+            Err(e) => Err(e),
         }
+    }
 
-        // Update statistics
-        match event {
-            InputEvent::Keyboard(_) => self.stats.keyboard_events += 1,
-            InputEvent::Mouse(_) => self.stats.mouse_events += 1,
+    /// Queue `event` until `backend` can take it
+    fn defer(&mut self, backend: BackendType, event: &InputEvent) -> Delivery {
+        let Some(pacer) = self.pacers.get_mut(&backend) else {
+            return Delivery::Dropped;
+        };
+        // The pacing thread is only woken when the queue starts, it keeps
+        // going on its own until the queue is empty
+        let was_idle = pacer.is_idle();
+        if !pacer.defer(event.clone()) {
+            return Delivery::Dropped;
         }
-        self.stats.total_events += 1;
+        if was_idle {
+            self.wake_pacer();
+        }
+        Delivery::Deferred
+    }
 
-        Ok(())
+    fn wake_pacer(&self) {
+        if let Some(pacer) = self.pacer.as_ref() {
+            pacer.wake();
+        }
     }
 
-    /// Inject a keyboard event
-    pub fn inject_keyboard(&mut self, event: &KeyboardEvent) -> Result<()> {
-        if !self.config.enable_keyboard {
-            return Err(InputError::UnsupportedAction(
-                "Keyboard input is disabled".to_string(),
-            ));
+    /// Send the deferred events the backends can take now
+    ///
+    /// Returns how long to wait before sending the others, or `None` once
+    /// every event is sent.
+    pub fn send_deferred(&mut self) -> Option<Duration> {
+        let mut retry: Option<Duration> = None;
+        for backend in self.available_backends() {
+            let Some(pacer) = self.pacers.get(&backend) else {
+                continue;
+            };
+            let device_retry = pacer.retry_interval(false);
+            let throttled_retry = pacer.retry_interval(true);
+
+            // What the backend holds back goes before the deferred events
+            let Ok(instance) = self.backend_mut(backend) else {
+                continue;
+            };
+            if let Err(InputError::BufferOverflow) = instance.flush() {
+                retry = Some(retry.map_or(device_retry, |r| r.min(device_retry)));
+                continue;
+            }
+
+            while let Some(pacer) = self.pacers.get_mut(&backend)
+                && let Some(event) = pacer.pop()
+            {
+                if !pacer.acquire(&event) {
+                    pacer.unpop(event);
+                    retry = Some(retry.map_or(throttled_retry, |r| r.min(throttled_retry)));
+                    break;
+                }
+
+                let result = self
+                    .backend_mut(backend)
+                    .and_then(|instance| instance.inject(&event));
+                match result {
+                    Err(InputError::BufferOverflow) => {
+                        if let Some(pacer) = self.pacers.get_mut(&backend) {
+                            pacer.release(&event);
+                            pacer.unpop(event);
+                        }
+                        retry = Some(retry.map_or(device_retry, |r| r.min(device_retry)));
+                        break;
+                    }
+                    Err(ref e) => {
+                        log::warn!(
+                            "Deferred input injection through {} failed: {}",
+                            backend.name(),
+                            e
+                        );
+                    }
+                    Ok(()) => {}
+                }
+                self.stats
+                    .record(backend, &event, &result.map(|()| Delivery::Sent));
+            }
         }
+        retry
+    }
 
+    /// Inject a keyboard event
+    pub fn inject_keyboard(&mut self, event: &KeyboardEvent) -> Result<()> {
         self.inject(&InputEvent::Keyboard(event.clone()))
     }
 
     /// Inject a mouse event
     pub fn inject_mouse(&mut self, event: &MouseEvent) -> Result<()> {
-        if !self.config.enable_mouse {
-            return Err(InputError::UnsupportedAction(
-                "Mouse input is disabled".to_string(),
-            ));
-        }
-
         self.inject(&InputEvent::Mouse(event.clone()))
     }
 
     /// Process a batch input request
+    ///
+    /// The events go to the backend named in the request, or to the active
+    /// backend. Naming a backend doesn't change the active one. The returned
+    /// statistics only cover this request.
     pub fn process_request(&mut self, request: &InputRequest) -> Result<InputStats> {
-        // Switch backend if specified
-        if let Some(ref backend_name) = request.backend {
-            if let Some(backend_type) = BackendType::from_name(backend_name) {
-                self.switch_backend(backend_type)?;
-            } else {
-                return Err(InputError::BackendNotAvailable(format!(
-                    "Unknown backend: {}",
-                    backend_name
-                )));
-            }
-        }
+        let backend = match request.backend {
+            Some(ref backend_name) => BackendType::from_name(backend_name).ok_or_else(|| {
+                InputError::BackendNotAvailable(format!("Unknown backend: {backend_name}"))
+            })?,
+            None => self.active_backend,
+        };
+        self.backend_mut(backend)?;
 
-        let mut stats = InputStats::default();
+        let events = request
+            .keyboard
+            .iter()
+            .cloned()
+            .map(InputEvent::Keyboard)
+            .chain(request.mouse.iter().cloned().map(InputEvent::Mouse))
+            .chain(request.touch.iter().cloned().map(InputEvent::Touch));
 
-        // Process keyboard events
-        for event in &request.keyboard {
-            match self.inject_keyboard(event) {
-                Ok(()) => {
-                    stats.keyboard_events += 1;
-                    stats.total_events += 1;
+        let mut stats = InputStats::default();
+        for event in events {
+            let result = self.submit(backend, &event);
+            match result {
+                Err(ref e) => {
+                    log::warn!("Input injection through {} failed: {}", backend.name(), e);
                 }
-                Err(e) => {
-                    stats.errors += 1;
-                    log::warn!("Keyboard injection failed: {}", e);
+                Ok(Delivery::Dropped) => {
+                    log::warn!(
+                        "Input event dropped, too many are deferred on {}",
+                        backend.name()
+                    );
                 }
+                Ok(_) => {}
             }
+            stats.record(backend, &event, &result);
         }
 
-        // Process mouse events
-        for event in &request.mouse {
-            match self.inject_mouse(event) {
-                Ok(()) => {
-                    stats.mouse_events += 1;
-                    stats.total_events += 1;
-                }
-                Err(e) => {
-                    stats.errors += 1;
-                    log::warn!("Mouse injection failed: {}", e);
-                }
+        Ok(stats)
+    }
+
+    /// Type a UTF-8 string as it would be typed on a `layout` keyboard
+    ///
+    /// Each character is sent as a request of its own through the backend
+    /// named by `backend`, or the active one, waiting `key_delay` between
+    /// two characters so that the guest keeps up. Only the first character
+    /// is sent before returning, the others are sent by a thread of their
+    /// own, like a replay, which a new call stops. Characters which `layout`
+    /// cannot produce are skipped and reported.
+    pub fn type_text(
+        manager: &Arc<Mutex<Self>>,
+        text: &str,
+        layout: KeyboardLayout,
+        backend: Option<&str>,
+        key_delay: Duration,
+    ) -> Result<TypedText> {
+        // The characters left to type can't be sent before the lock is
+        // released
+        let mut locked = manager.lock().unwrap();
+        locked.typing = None;
+        let backend = match backend {
+            Some(backend_name) => BackendType::from_name(backend_name).ok_or_else(|| {
+                InputError::BackendNotAvailable(format!("Unknown backend: {backend_name}"))
+            })?,
+            None => locked.active_backend,
+        };
+        locked.backend_mut(backend)?;
+
+        let mut batcher = EventBatcher::new(BatchConfig {
+            // A character is at most a dozen events, keep them in one batch
+            max_batch_size: 32,
+            adaptive: false,
+            ..Default::default()
+        });
+        let untypable = layout::push_text(&mut batcher, text, layout);
+        for UntypableChar { index, character } in &untypable {
+            log::warn!(
+                "Cannot type {:?} (character {}) with the {} layout",
+                character,
+                index,
+                layout.name()
+            );
+        }
+
+        let mut typed = TypedText {
+            untypable,
+            ..Default::default()
+        };
+        let mut script = InputScript::default();
+        while let Some(mut request) = batcher.pop_request() {
+            if typed.characters > 0 && !key_delay.is_zero() {
+                let time_us = (key_delay * typed.characters as u32).as_micros() as u64;
+                script.events.extend(
+                    request
+                        .keyboard
+                        .into_iter()
+                        .map(InputEvent::Keyboard)
+                        .map(|event| ScriptEvent { time_us, event }),
+                );
+            } else {
+                // The pacing thread can't take the lock while typing goes on
+                locked.send_deferred();
+                request.backend = Some(backend.name().to_string());
+                typed.stats.merge(&locked.process_request(&request)?);
             }
+            typed.characters += 1;
         }
 
-        Ok(stats)
+        if !script.events.is_empty() {
+            typed.queued_events = script.events.len() as u64;
+            // The first character was typed at the start of the script.
+            locked.typing = Some(InputReplay::start(manager, script, 1.0, Some(backend))?);
+        }
+
+        Ok(typed)
+    }
+
+    /// Whether characters are left to type by [`InputManager::type_text`]
+    pub fn is_typing(&self) -> bool {
+        self.typing
+            .as_ref()
+            .is_some_and(|typing| typing.status().running)
     }
 
     // ========================================================================
@@ -288,6 +738,93 @@ impl InputManager {
         self.stats = InputStats::default();
     }
 
+    // ========================================================================
+    // Guest State
+    // ========================================================================
+
+    /// Get the state the guest set up on the devices of every backend
+    pub fn input_state(&self) -> InputState {
+        let backends: BTreeMap<String, BackendState> = self
+            .available_backends()
+            .into_iter()
+            .filter_map(|backend| {
+                let state = self.backend(backend)?.state();
+                Some((backend.name().to_string(), state))
+            })
+            .collect();
+
+        let active = backends
+            .get(self.active_backend.name())
+            .copied()
+            .unwrap_or_default();
+        InputState {
+            active_backend: self.active_backend.name().to_string(),
+            keyboard_ready: active.keyboard_ready,
+            pointer_ready: active.pointer_ready,
+            leds: active.leds,
+            backends,
+        }
+    }
+
+    // ========================================================================
+    // Recording and Replay
+    // ========================================================================
+
+    /// Start recording the injected events, dropping any previous recording
+    pub fn start_recording(&mut self) {
+        self.recorder = Some(InputRecorder::new());
+    }
+
+    /// Stop recording and get the recorded script
+    pub fn stop_recording(&mut self) -> Result<InputScript> {
+        self.recorder
+            .take()
+            .map(InputRecorder::finish)
+            .ok_or_else(|| InputError::InvalidScript("Not recording".to_string()))
+    }
+
+    /// Check if the injected events are being recorded
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Start replaying `script` through `manager`, stopping any previous replay
+    ///
+    /// See [`InputReplay::start`].
+    pub fn start_replay(
+        manager: &Arc<Mutex<Self>>,
+        script: InputScript,
+        speed: f64,
+        backend: Option<BackendType>,
+    ) -> Result<ReplayStatus> {
+        // The new replay can't send anything before the lock is released
+        let mut locked = manager.lock().unwrap();
+        locked.replay = None;
+        let replay = InputReplay::start(manager, script, speed, backend)?;
+        let status = replay.status();
+        locked.replay = Some(replay);
+        Ok(status)
+    }
+
+    /// Stop the replay, if any, and get its final progress
+    pub fn stop_replay(&mut self) -> ReplayStatus {
+        self.replay
+            .take()
+            .map(|replay| ReplayStatus {
+                running: false,
+                ..replay.status()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Progress of the last replay
+    pub fn replay_status(&self) -> ReplayStatus {
+        self.replay
+            .as_ref()
+            .map(InputReplay::status)
+            .unwrap_or_default()
+    }
+
     // ========================================================================
     // Convenience Methods
     // ========================================================================
@@ -299,13 +836,13 @@ impl InputManager {
         self.inject_keyboard(&KeyboardEvent {
             action: KeyboardAction::Press,
             code,
-            modifiers: Default::default(),
+            ..Default::default()
         })?;
 
         self.inject_keyboard(&KeyboardEvent {
             action: KeyboardAction::Release,
             code,
-            modifiers: Default::default(),
+            ..Default::default()
         })?;
 
         Ok(())
@@ -435,3 +972,443 @@ impl Default for MultiVmInputManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Instant;
+
+    use vm_device::BusDevice;
+    use vmm_sys_util::eventfd::EventFd;
+
+    use super::*;
+    use crate::input::event::{
+        KeyboardAction, MouseAction, MouseButton, TouchAction, TouchEvent, keys,
+    };
+    use crate::input::keymap::KeyCodeSet;
+
+    fn create_i8042() -> Arc<Mutex<I8042Device>> {
+        Arc::new(Mutex::new(I8042Device::new(
+            EventFd::new(0).unwrap(),
+            Arc::new(AtomicBool::new(false)),
+        )))
+    }
+
+    fn read_output(i8042: &Arc<Mutex<I8042Device>>) -> Vec<u8> {
+        let mut i8042 = i8042.lock().unwrap();
+        let mut output = Vec::new();
+        loop {
+            let mut status = [0u8];
+            i8042.read(0, 4, &mut status);
+            // Output buffer full
+            if status[0] & 0x01 == 0 {
+                return output;
+            }
+            let mut data = [0u8];
+            i8042.read(0, 0, &mut data);
+            output.push(data[0]);
+        }
+    }
+
+    fn keyboard_request(backend: Option<&str>, actions: &[KeyboardAction]) -> InputRequest {
+        InputRequest {
+            backend: backend.map(str::to_string),
+            keyboard: actions
+                .iter()
+                .map(|&action| KeyboardEvent {
+                    action,
+                    code: 0x1E, // A key
+                    ..Default::default()
+                })
+                .collect(),
+            mouse: Vec::new(),
+            touch: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_ps2_injection() {
+        let i8042 = create_i8042();
+        let mut manager = InputManager::default_config();
+        manager.init_ps2_backend(i8042.clone());
+        assert!(manager.is_ready());
+
+        let stats = manager
+            .process_request(&keyboard_request(None, &[KeyboardAction::Type]))
+            .unwrap();
+        assert_eq!(stats.keyboard_events, 1);
+        assert_eq!(read_output(&i8042), [0x1C, 0xF0, 0x1C]);
+
+        manager.mouse_click(MouseButton::Left).unwrap();
+        assert_eq!(
+            read_output(&i8042),
+            [
+                0x09, 0, 0, 0, // Left button down
+                0x08, 0, 0, 0, // Left button up
+            ]
+        );
+
+        manager.mouse_move(300, 0).unwrap();
+        assert_eq!(
+            read_output(&i8042),
+            [
+                0x08, 127, 0, 0, // Largest movement
+                0x08, 127, 0, 0, // Largest movement
+                0x08, 46, 0, 0, // Remaining movement
+            ]
+        );
+
+        let absolute = MouseEvent {
+            action: MouseAction::MoveAbsolute,
+            x: 10,
+            y: 10,
+            z: 0,
+            button: None,
+            buttons: Default::default(),
+        };
+        assert!(manager.inject_mouse(&absolute).is_err());
+        assert_eq!(manager.stats().errors, 1);
+    }
+
+    #[test]
+    fn test_backend_selection() {
+        let i8042 = create_i8042();
+        let mut manager = InputManager::default_config();
+        manager.init_ps2_backend(i8042.clone());
+        manager.init_usb_backend(None, None, None);
+
+        // Naming a backend in a request doesn't switch the active one
+        let stats = manager
+            .process_request(&keyboard_request(
+                Some("usb"),
+                &[KeyboardAction::Press, KeyboardAction::Release],
+            ))
+            .unwrap();
+        assert_eq!(stats.errors, 2);
+        assert_eq!(manager.active_backend(), BackendType::Ps2);
+        assert!(read_output(&i8042).is_empty());
+
+        manager
+            .process_request(&keyboard_request(None, &[KeyboardAction::Press]))
+            .unwrap();
+        assert_eq!(read_output(&i8042), [0x1C]);
+
+        assert!(manager.switch_backend(BackendType::Virtio).is_err());
+        manager
+            .process_request(&keyboard_request(Some("virtio"), &[KeyboardAction::Press]))
+            .unwrap_err();
+        manager.switch_backend(BackendType::UsbHid).unwrap();
+        assert_eq!(
+            manager.available_backends(),
+            [BackendType::Ps2, BackendType::UsbHid]
+        );
+
+        let stats = manager.stats();
+        assert_eq!(stats.total_events, 1);
+        assert_eq!(stats.errors, 2);
+        assert_eq!(
+            stats.backend(BackendType::Ps2),
+            BackendStats {
+                keyboard_events: 1,
+                mouse_events: 0,
+                touch_events: 0,
+                total_events: 1,
+                errors: 0,
+                deferred_events: 0,
+                dropped_events: 0,
+            }
+        );
+        assert_eq!(stats.backend(BackendType::UsbHid).errors, 2);
+        assert_eq!(stats.backend(BackendType::Virtio), BackendStats::default());
+    }
+
+    #[test]
+    fn test_usb_tablet() {
+        let tablet = Arc::new(Mutex::new(devices::usb::UsbHidDevice::new_tablet()));
+        let mut manager = InputManager::default_config();
+        manager.init_usb_backend(None, None, Some(tablet.clone()));
+        manager.switch_backend(BackendType::UsbHid).unwrap();
+        manager.set_screen_size(1025, 769);
+
+        let absolute = |x, y| MouseEvent {
+            action: MouseAction::MoveAbsolute,
+            x,
+            y,
+            z: 0,
+            button: None,
+            buttons: Default::default(),
+        };
+        manager.inject_mouse(&absolute(1024, 384)).unwrap();
+        manager.inject_mouse(&absolute(-5, 2000)).unwrap();
+        let mut tablet = tablet.lock().unwrap();
+        assert_eq!(
+            tablet.get_report(),
+            Some(vec![0, 0xff, 0x7f, 0xff, 0x3f, 0])
+        );
+        assert_eq!(tablet.get_report(), Some(vec![0, 0, 0, 0xff, 0x7f, 0]));
+
+        // There is no touchscreen behind the USB HID backend
+        let touch = InputRequest {
+            backend: None,
+            keyboard: Vec::new(),
+            mouse: Vec::new(),
+            touch: vec![TouchEvent {
+                action: TouchAction::Down,
+                slot: 0,
+                x: 10,
+                y: 10,
+            }],
+        };
+        let stats = manager.process_request(&touch).unwrap();
+        assert_eq!(stats.errors, 1);
+        assert_eq!(manager.stats().mouse_events, 2);
+    }
+
+    #[test]
+    fn test_type_text() {
+        let i8042 = create_i8042();
+        let mut manager = InputManager::default_config();
+        manager.init_ps2_backend(i8042.clone());
+        let manager = Arc::new(Mutex::new(manager));
+
+        let typed = InputManager::type_text(
+            &manager,
+            "H€i",
+            KeyboardLayout::Us,
+            Some("ps2"),
+            Duration::ZERO,
+        )
+        .unwrap();
+        assert_eq!(typed.characters, 2);
+        assert_eq!(typed.queued_events, 0);
+        assert_eq!(
+            typed.untypable,
+            vec![UntypableChar {
+                index: 1,
+                character: '€'
+            }]
+        );
+        assert_eq!(typed.stats.keyboard_events, 4);
+        assert_eq!(typed.stats.backend(BackendType::Ps2).keyboard_events, 4);
+        assert_eq!(
+            read_output(&i8042),
+            [0x12, 0x33, 0xF0, 0x33, 0xF0, 0x12, 0x43, 0xF0, 0x43]
+        );
+    }
+
+    #[test]
+    fn test_deferred_input() {
+        let i8042 = create_i8042();
+        let mut manager = InputManager::default_config();
+        manager.init_ps2_backend(i8042.clone());
+        let manager = Arc::new(Mutex::new(manager));
+
+        // The i8042 only holds five characters
+        let typed = InputManager::type_text(
+            &manager,
+            "hello world",
+            KeyboardLayout::Us,
+            None,
+            Duration::ZERO,
+        )
+        .unwrap();
+        let mut manager = manager.lock().unwrap();
+        assert_eq!(typed.characters, 11);
+        assert!(typed.stats.deferred_events > 0);
+        assert_eq!(typed.stats.dropped_events, 0);
+        assert_eq!(
+            typed.stats.keyboard_events + typed.stats.deferred_events,
+            11
+        );
+
+        // The deferred events go out as the guest reads the i8042
+        let mut output = read_output(&i8042);
+        while let Some(delay) = manager.send_deferred() {
+            assert!(delay <= Duration::from_millis(5));
+            output.extend(read_output(&i8042));
+        }
+        output.extend(read_output(&i8042));
+        let expected: Vec<u8> = [
+            0x33, 0x24, 0x4B, 0x4B, 0x44, 0x29, 0x1D, 0x44, 0x2D, 0x4B, 0x23,
+        ]
+        .iter()
+        .flat_map(|&code| [code, 0xF0, code])
+        .collect();
+        assert_eq!(output, expected);
+        assert_eq!(manager.stats().keyboard_events, 11);
+    }
+
+    #[test]
+    fn test_type_text_in_background() {
+        let i8042 = create_i8042();
+        let mut manager = InputManager::default_config();
+        manager.init_ps2_backend(i8042.clone());
+        let manager = Arc::new(Mutex::new(manager));
+
+        // Only the first character is typed before returning
+        let typed = InputManager::type_text(
+            &manager,
+            "ab",
+            KeyboardLayout::Us,
+            None,
+            Duration::from_millis(5),
+        )
+        .unwrap();
+        assert_eq!(typed.characters, 2);
+        assert_eq!(typed.stats.keyboard_events, 1);
+        assert_eq!(typed.queued_events, 1);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while manager.lock().unwrap().is_typing() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(read_output(&i8042), [0x1C, 0xF0, 0x1C, 0x32, 0xF0, 0x32]);
+        assert_eq!(manager.lock().unwrap().stats().keyboard_events, 2);
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let i8042 = create_i8042();
+        let mut manager = InputManager::default_config();
+        manager.init_ps2_backend(i8042.clone());
+
+        manager.stop_recording().unwrap_err();
+        manager.start_recording();
+        manager.type_key(0x1E).unwrap();
+        manager.mouse_move(1, 1).unwrap();
+        let script = manager.stop_recording().unwrap();
+        assert_eq!(script.events.len(), 3);
+        assert_eq!(read_output(&i8042).len(), 3 + 4);
+
+        let manager = Arc::new(Mutex::new(manager));
+        let status = InputManager::start_replay(&manager, script, 4.0, None).unwrap();
+        assert!(status.running);
+        assert_eq!(status.events_total, 3);
+        for _ in 0..500 {
+            if !manager.lock().unwrap().replay_status().running {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let status = manager.lock().unwrap().replay_status();
+        assert_eq!(status.events_replayed, 3);
+        assert_eq!(status.errors, 0);
+        // Nothing was recorded while replaying
+        assert!(!manager.lock().unwrap().is_recording());
+        // The mouse packet is read ahead of the pending keyboard data
+        let output = read_output(&i8042);
+        assert_eq!(output.len(), 4 + 3);
+        assert_eq!(&output[4..], [0x1C, 0xF0, 0x1C]);
+    }
+
+    #[test]
+    fn test_input_state() {
+        use devices::usb::UsbDevice;
+
+        let i8042 = create_i8042();
+        let keyboard = Arc::new(Mutex::new(devices::usb::UsbHidDevice::new_keyboard()));
+        let mut manager = InputManager::default_config();
+        manager.init_ps2_backend(i8042.clone());
+        manager.init_usb_backend(Some(keyboard.clone()), None, None);
+
+        let state = manager.input_state();
+        assert_eq!(state.active_backend, "ps2");
+        assert!(state.keyboard_ready);
+        assert!(!state.pointer_ready);
+        assert_eq!(state.leds, KeyboardLeds::default());
+        assert!(!state.backends["usb"].keyboard_ready);
+
+        // The guest lights Caps Lock and enables the mouse
+        {
+            let mut i8042 = i8042.lock().unwrap();
+            i8042.write(0, 0, &[0xED]);
+            i8042.write(0, 0, &[0x04]);
+            i8042.write(0, 4, &[0xD4]);
+            i8042.write(0, 0, &[0xF4]);
+        }
+        read_output(&i8042);
+        let state = manager.input_state();
+        assert!(state.pointer_ready);
+        assert!(state.leds.caps_lock && !state.leds.num_lock);
+        assert_eq!(
+            state.backends["ps2"].mouse_protocol,
+            Some(devices::legacy::MouseProtocol::Intellimouse)
+        );
+
+        // The guest configures the USB keyboard and lights Num Lock
+        {
+            let mut keyboard = keyboard.lock().unwrap();
+            keyboard
+                .handle_control(&[0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00])
+                .unwrap();
+            keyboard.handle_transfer(0x01, &[0x01]).unwrap();
+        }
+        manager.switch_backend(BackendType::UsbHid).unwrap();
+        let state = manager.input_state();
+        assert!(state.keyboard_ready);
+        assert!(state.leds.num_lock && !state.leds.caps_lock);
+        assert_eq!(state.backends["usb"].mouse_protocol, None);
+    }
+
+    #[test]
+    fn test_key_translation() {
+        let i8042 = create_i8042();
+        let keyboard = Arc::new(Mutex::new(devices::usb::UsbHidDevice::new_keyboard()));
+        let mut manager = InputManager::default_config();
+        manager.init_ps2_backend(i8042.clone());
+        manager.init_usb_backend(Some(keyboard.clone()), None, None);
+
+        let right_ctrl = KeyboardEvent {
+            code: keys::RIGHT_CTRL,
+            ..Default::default()
+        };
+        manager.inject_keyboard(&right_ctrl).unwrap();
+        assert_eq!(read_output(&i8042), [0xE0, 0x14, 0xE0, 0xF0, 0x14]);
+
+        // The guest switches the keyboard to Set 1
+        i8042.lock().unwrap().write(0, 0, &[0xF0]);
+        i8042.lock().unwrap().write(0, 0, &[0x01]);
+        assert_eq!(read_output(&i8042), [0xFA, 0xFA]);
+        let up = KeyboardEvent {
+            action: KeyboardAction::Press,
+            qcode: Some("up".to_string()),
+            ..Default::default()
+        };
+        manager.inject_keyboard(&up).unwrap();
+        assert_eq!(read_output(&i8042), [0xE0, 0x48]);
+
+        let unknown = KeyboardEvent {
+            code: 0x1E,
+            code_set: KeyCodeSet::Hid,
+            qcode: Some("nope".to_string()),
+            ..Default::default()
+        };
+        assert!(manager.inject_keyboard(&unknown).is_err());
+
+        manager.switch_backend(BackendType::UsbHid).unwrap();
+        let left_meta = KeyboardEvent {
+            action: KeyboardAction::Press,
+            code: 125,
+            code_set: KeyCodeSet::Evdev,
+            ..Default::default()
+        };
+        manager.inject_keyboard(&left_meta).unwrap();
+        manager.inject_keyboard(&up).unwrap();
+        manager
+            .inject_keyboard(&KeyboardEvent {
+                action: KeyboardAction::Release,
+                ..left_meta
+            })
+            .unwrap();
+        let mut keyboard = keyboard.lock().unwrap();
+        assert_eq!(keyboard.get_report(), Some(vec![0x08, 0, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(
+            keyboard.get_report(),
+            Some(vec![0x08, 0, 0x52, 0, 0, 0, 0, 0])
+        );
+        assert_eq!(keyboard.get_report(), Some(vec![0, 0, 0x52, 0, 0, 0, 0, 0]));
+    }
+}
@@ -72,18 +72,29 @@
 mod backend;
 mod batch;
 mod event;
+mod keymap;
+mod layout;
 mod manager;
+mod pacing;
+mod script;
 
-pub use backend::{InputBackend, InputCapabilities, Ps2Backend, StealthLevel, UsbHidBackend, VirtioInputBackend};
+pub use backend::{
+    BackendState, BackendType, InputBackend, InputCapabilities, KeyboardLeds, Ps2Backend,
+    StealthLevel, UsbHidBackend, VirtioInputBackend,
+};
 pub use batch::{
     BatchConfig, BatchProcessor, BatchStats, EventBatch, EventBatcher,
     DEFAULT_BATCH_SIZE, DEFAULT_FLUSH_INTERVAL_US,
 };
 pub use event::{
     InputAction, InputDevice, InputEvent, InputRequest, KeyboardAction, KeyboardEvent,
-    KeyboardModifiers, MouseAction, MouseButton, MouseButtons, MouseEvent,
+    KeyboardModifiers, MouseAction, MouseButton, MouseButtons, MouseEvent, TouchAction, TouchEvent,
 };
-pub use manager::{InputConfig, InputManager};
+pub use keymap::{Key, KeyCodeSet};
+pub use layout::{DEFAULT_KEY_DELAY_MS, KeyboardLayout, Keystroke, UntypableChar, push_text};
+pub use manager::{BackendStats, InputConfig, InputManager, InputState, InputStats, TypedText};
+pub use pacing::{BackendPacer, Delivery, InputPacer, MAX_DEFERRED_EVENTS};
+pub use script::{InputRecorder, InputReplay, InputScript, ReplayStatus, ScriptEvent};
 
 /// Result type for input operations.
 ///
@@ -149,11 +160,19 @@ pub enum InputError {
 
     /// The input buffer has overflowed.
     ///
-    /// This error occurs when too many events are queued and the buffer
-    /// cannot accommodate additional events.
+    /// This error occurs when a device cannot take an event until the
+    /// guest reads the ones it holds, or when too many events are deferred
+    /// on a backend and the event is dropped.
     #[error("Buffer overflow")]
     BufferOverflow,
 
+    /// The input script is invalid or cannot be recorded or replayed.
+    ///
+    /// This error occurs when stopping a recording which was not started,
+    /// or when replaying a malformed script or at an invalid speed.
+    #[error("Invalid input script: {0}")]
+    InvalidScript(String),
+
     /// An I/O error occurred.
     ///
     /// This error wraps standard I/O errors that may occur during
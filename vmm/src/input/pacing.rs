@@ -0,0 +1,323 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Input Pacing
+//!
+//! Guests only take so many input events at once: the i8042 buffers 16
+//! bytes, virtio-input 256 events and a USB HID device 16 reports. Events
+//! are sent to each backend no faster than the `max_keyboard_rate` of its
+//! [`InputCapabilities`], through a token bucket, and the ones the backend
+//! can't take yet are deferred rather than dropped.
+//!
+//! Deferred events wait in an [`EventBatcher`] of their backend, in the
+//! order they were submitted, and every event submitted to the backend
+//! queues behind them. A thread of the input manager sends them as tokens
+//! come back and as the guest reads its devices. Events are only dropped
+//! once [`MAX_DEFERRED_EVENTS`] of them are waiting, which happens when the
+//! guest stops reading a device.
+//!
+//! [`InputCapabilities`]: super::InputCapabilities
+
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use log::{error, warn};
+use rate_limiter::{BucketReduction, TokenBucket};
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
+
+use super::Result;
+use super::batch::{BatchConfig, EventBatch, EventBatcher};
+use super::event::{InputEvent, KeyboardAction, MouseAction};
+use super::manager::InputManager;
+
+/// Events deferred on a backend before the following ones are dropped
+pub const MAX_DEFERRED_EVENTS: usize = 4096;
+
+/// Time the rate of a backend is measured over, its events can come in a
+/// burst within it
+const BURST_WINDOW_MS: u64 = 100;
+
+/// Time between two attempts at a device which couldn't take an event
+const DEVICE_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+
+/// What became of a submitted event
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Sent to the device
+    Sent,
+    /// Queued until the backend can take it
+    Deferred,
+    /// Dropped because too many events were already queued
+    Dropped,
+}
+
+/// Rate limit and deferred events of a backend
+pub struct BackendPacer {
+    /// Events the backend may still take, refilled at its rate
+    bucket: Option<TokenBucket>,
+    /// Time a token takes to come back
+    token_interval: Duration,
+    /// Deferred events, in submission order
+    deferred: EventBatcher,
+    /// Events of the batch being sent, taken out of `deferred`
+    head: VecDeque<InputEvent>,
+    /// Number of deferred events, `head` included
+    deferred_events: usize,
+}
+
+impl BackendPacer {
+    /// Pace a backend taking `rate` events per second, or no limit for 0
+    pub fn new(rate: u32) -> Self {
+        let rate = u64::from(rate);
+        let size = (rate * BURST_WINDOW_MS / 1000).max(1);
+        let refill_time_ms = size * 1000 / rate.max(1);
+        Self {
+            bucket: TokenBucket::new(if rate == 0 { 0 } else { size }, 0, refill_time_ms),
+            token_interval: Duration::from_millis(refill_time_ms)
+                .div_f64(size as f64)
+                .max(Duration::from_millis(1)),
+            deferred: EventBatcher::new(BatchConfig {
+                adaptive: false,
+                ..Default::default()
+            }),
+            head: VecDeque::new(),
+            deferred_events: 0,
+        }
+    }
+
+    /// Tokens `event` costs, one for each key press or release and each
+    /// pointer report it turns into
+    fn cost(event: &InputEvent) -> u64 {
+        match event {
+            InputEvent::Keyboard(kb) if kb.action == KeyboardAction::Type => 2,
+            InputEvent::Mouse(m) if m.action == MouseAction::Click => 2,
+            _ => 1,
+        }
+    }
+
+    /// Take the tokens of `event`, false when the backend is over its rate
+    pub fn acquire(&mut self, event: &InputEvent) -> bool {
+        match self.bucket.as_mut() {
+            Some(bucket) => bucket.reduce(Self::cost(event)) != BucketReduction::Failure,
+            None => true,
+        }
+    }
+
+    /// Give the tokens of `event` back, when the device didn't take it
+    pub fn release(&mut self, event: &InputEvent) {
+        if let Some(bucket) = self.bucket.as_mut() {
+            bucket.replenish(Self::cost(event));
+        }
+    }
+
+    /// Whether no event is deferred
+    pub fn is_idle(&self) -> bool {
+        self.deferred_events == 0
+    }
+
+    /// Number of deferred events
+    pub fn deferred_events(&self) -> usize {
+        self.deferred_events
+    }
+
+    /// Queue `event` behind the deferred ones, false when the queue is full
+    pub fn defer(&mut self, event: InputEvent) -> bool {
+        if self.deferred_events >= MAX_DEFERRED_EVENTS {
+            return false;
+        }
+        self.deferred.push_in_order(event);
+        self.deferred_events += 1;
+        true
+    }
+
+    /// Take the oldest deferred event
+    pub fn pop(&mut self) -> Option<InputEvent> {
+        if self.head.is_empty() {
+            self.deferred.flush_all();
+            self.head
+                .extend(self.deferred.pop_batch().map(EventBatch::into_events)?);
+        }
+        let event = self.head.pop_front()?;
+        self.deferred_events -= 1;
+        Some(event)
+    }
+
+    /// Put an event taken with [`pop`](Self::pop) back in front
+    pub fn unpop(&mut self, event: InputEvent) {
+        self.head.push_front(event);
+        self.deferred_events += 1;
+    }
+
+    /// Time to wait before trying again, after the backend was over its
+    /// rate or its device was full
+    pub fn retry_interval(&self, throttled: bool) -> Duration {
+        if throttled {
+            self.token_interval
+        } else {
+            DEVICE_RETRY_INTERVAL
+        }
+    }
+}
+
+/// Thread sending the deferred events of an input manager.
+///
+/// The thread sleeps until the manager defers events, then sends them as
+/// the backends can take them. It stops when this is dropped or when the
+/// input manager goes away.
+pub struct InputPacer {
+    wake_evt: EventFd,
+    kill_evt: EventFd,
+}
+
+impl InputPacer {
+    /// Start sending the events `manager` defers
+    pub fn start(manager: &Arc<Mutex<InputManager>>) -> Result<Self> {
+        let wake_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        let mut worker = PacerWorker {
+            manager: Arc::downgrade(manager),
+            wake_evt: wake_evt.try_clone()?,
+            kill_evt: kill_evt.try_clone()?,
+            timer: TimerFd::new().map_err(io::Error::from)?,
+        };
+
+        thread::Builder::new()
+            .name("input-pacer".to_string())
+            .spawn(move || {
+                if let Err(e) = worker.run() {
+                    error!("Input pacing thread failed: {e}");
+                }
+            })?;
+
+        Ok(InputPacer { wake_evt, kill_evt })
+    }
+
+    /// Have the thread look at the deferred events
+    pub fn wake(&self) {
+        if let Err(e) = self.wake_evt.write(1) {
+            warn!("Failed to wake the input pacing thread: {e}");
+        }
+    }
+}
+
+impl Drop for InputPacer {
+    fn drop(&mut self) {
+        // The thread isn't joined because this is dropped with the input
+        // manager, which may be locked by the thread.
+        // Ignore the result because there is nothing we can do about it.
+        let _ = self.kill_evt.write(1);
+    }
+}
+
+struct PacerWorker {
+    manager: Weak<Mutex<InputManager>>,
+    wake_evt: EventFd,
+    kill_evt: EventFd,
+    timer: TimerFd,
+}
+
+impl PacerWorker {
+    const KILL_EVENT: u64 = 0;
+    const WAKE_EVENT: u64 = 1;
+    const TIMER_EVENT: u64 = 2;
+
+    fn run(&mut self) -> io::Result<()> {
+        let epoll = Epoll::new()?;
+        for (fd, data) in [
+            (self.kill_evt.as_raw_fd(), Self::KILL_EVENT),
+            (self.wake_evt.as_raw_fd(), Self::WAKE_EVENT),
+            (self.timer.as_raw_fd(), Self::TIMER_EVENT),
+        ] {
+            epoll.ctl(
+                ControlOperation::Add,
+                fd,
+                EpollEvent::new(EventSet::IN, data),
+            )?;
+        }
+
+        let mut events = [EpollEvent::default(); 3];
+        loop {
+            let count = match epoll.wait(-1, &mut events) {
+                Ok(count) => count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            for event in &events[..count] {
+                match event.data() {
+                    Self::KILL_EVENT => return Ok(()),
+                    Self::WAKE_EVENT => {
+                        self.wake_evt.read()?;
+                    }
+                    Self::TIMER_EVENT => {
+                        self.timer.wait()?;
+                    }
+                    _ => {}
+                }
+            }
+
+            let Some(manager) = self.manager.upgrade() else {
+                return Ok(());
+            };
+            let retry = manager.lock().unwrap().send_deferred();
+            match retry {
+                // A zero duration would disarm the timer
+                Some(delay) => self
+                    .timer
+                    .reset(delay.max(Duration::from_micros(1)), None)?,
+                None => self.timer.clear()?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend_pacer() {
+        // Bursts of 5 events, refilled in 100ms
+        let mut pacer = BackendPacer::new(50);
+        let event = InputEvent::keyboard(KeyboardAction::Press, 0x1E);
+        for _ in 0..5 {
+            assert!(pacer.acquire(&event));
+        }
+        assert!(!pacer.acquire(&event));
+        assert_eq!(pacer.retry_interval(true), Duration::from_millis(20));
+
+        // The events come back out in order
+        assert!(pacer.is_idle());
+        for code in 0..3 {
+            assert!(pacer.defer(InputEvent::keyboard(KeyboardAction::Press, code)));
+        }
+        assert_eq!(pacer.deferred_events(), 3);
+        let first = pacer.pop().unwrap();
+        assert!(matches!(&first, InputEvent::Keyboard(kb) if kb.code == 0));
+        pacer.unpop(first);
+        for code in 0..3 {
+            let event = pacer.pop().unwrap();
+            assert!(matches!(&event, InputEvent::Keyboard(kb) if kb.code == code));
+        }
+        assert!(pacer.pop().is_none());
+        assert!(pacer.is_idle());
+
+        // Only so many events are kept
+        for _ in 0..MAX_DEFERRED_EVENTS {
+            assert!(pacer.defer(event.clone()));
+        }
+        assert!(!pacer.defer(event));
+
+        // No limit
+        let mut pacer = BackendPacer::new(0);
+        let event = InputEvent::keyboard(KeyboardAction::Type, 0x1E);
+        for _ in 0..1000 {
+            assert!(pacer.acquire(&event));
+        }
+    }
+}
@@ -0,0 +1,361 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Input Scripts
+//!
+//! An [`InputScript`] is a sequence of input events with the time at which
+//! each one was injected, relative to the first one. Scripts are recorded
+//! by the [`InputManager`] from every event it injects, whether it comes from
+//! the HTTP API or another client, and saved as JSON:
+//!
+//! ```json
+//! {
+//!   "events": [
+//!     {"time_us": 0, "device": "keyboard", "action": "press", "code": 30},
+//!     {"time_us": 85000, "device": "keyboard", "action": "release", "code": 30}
+//!   ]
+//! }
+//! ```
+//!
+//! A script is replayed by a thread of its own, which waits for the time of
+//! each event on a timer. The times are taken from the start of the replay,
+//! so that the delays between the events don't add up, and can be scaled
+//! to replay faster or slower than recorded.
+//!
+//! [`InputManager`]: super::InputManager
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
+
+use super::backend::BackendType;
+use super::event::InputEvent;
+use super::manager::InputManager;
+use super::{InputError, Result};
+
+/// An event of a script.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScriptEvent {
+    /// Time of the event since the start of the script (microseconds)
+    pub time_us: u64,
+    /// The event
+    #[serde(flatten)]
+    pub event: InputEvent,
+}
+
+/// Recorded sequence of input events.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct InputScript {
+    /// Events, in time order
+    pub events: Vec<ScriptEvent>,
+}
+
+impl InputScript {
+    /// Time of the last event
+    pub fn duration(&self) -> Duration {
+        self.events
+            .last()
+            .map_or(Duration::ZERO, |event| Duration::from_micros(event.time_us))
+    }
+}
+
+/// Records the events injected by an input manager.
+#[derive(Debug)]
+pub struct InputRecorder {
+    start: Option<Instant>,
+    script: InputScript,
+}
+
+impl InputRecorder {
+    /// Create a recorder, the script starts with the first event
+    pub fn new() -> Self {
+        Self {
+            start: None,
+            script: InputScript::default(),
+        }
+    }
+
+    /// Append an event to the script
+    pub fn record(&mut self, event: &InputEvent) {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        self.script.events.push(ScriptEvent {
+            time_us: start.elapsed().as_micros() as u64,
+            event: event.clone(),
+        });
+    }
+
+    /// Number of events recorded so far
+    pub fn len(&self) -> usize {
+        self.script.events.len()
+    }
+
+    /// Check if no event was recorded
+    pub fn is_empty(&self) -> bool {
+        self.script.events.is_empty()
+    }
+
+    /// End the recording
+    pub fn finish(self) -> InputScript {
+        self.script
+    }
+}
+
+impl Default for InputRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Progress of a replay.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayStatus {
+    /// The replay is going on
+    pub running: bool,
+    /// Events injected so far
+    pub events_replayed: u64,
+    /// Events of the script
+    pub events_total: u64,
+    /// Events which failed to be injected
+    pub errors: u64,
+}
+
+/// Counters shared with the replay thread
+#[derive(Default)]
+struct ReplayProgress {
+    running: AtomicBool,
+    stopped: AtomicBool,
+    replayed: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// A script being replayed.
+///
+/// The replay stops when the script ends, when this is dropped or when the
+/// input manager goes away.
+pub struct InputReplay {
+    kill_evt: EventFd,
+    progress: Arc<ReplayProgress>,
+    events_total: u64,
+}
+
+impl InputReplay {
+    /// Start replaying `script` through `manager`
+    ///
+    /// The events are sent to `backend`, or to the backend active when each
+    /// of them is due. Times are divided by `speed`, which must be positive.
+    pub fn start(
+        manager: &Arc<Mutex<InputManager>>,
+        script: InputScript,
+        speed: f64,
+        backend: Option<BackendType>,
+    ) -> Result<Self> {
+        if !(speed.is_finite() && speed > 0.0) {
+            return Err(InputError::InvalidScript(format!(
+                "Invalid replay speed: {speed}"
+            )));
+        }
+
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        let progress = Arc::new(ReplayProgress {
+            running: AtomicBool::new(true),
+            ..Default::default()
+        });
+        let events_total = script.events.len() as u64;
+        let mut worker = ReplayWorker {
+            manager: Arc::downgrade(manager),
+            script,
+            speed,
+            backend,
+            kill_evt: kill_evt.try_clone()?,
+            timer: TimerFd::new().map_err(io::Error::from)?,
+            progress: progress.clone(),
+        };
+
+        info!(
+            "Replaying {} input events over {:?}",
+            events_total,
+            worker.script.duration().div_f64(speed)
+        );
+        thread::Builder::new()
+            .name("input-replay".to_string())
+            .spawn(move || {
+                if let Err(e) = worker.run() {
+                    error!("Input replay thread failed: {e}");
+                }
+                worker.progress.running.store(false, Ordering::Release);
+            })?;
+
+        Ok(InputReplay {
+            kill_evt,
+            progress,
+            events_total,
+        })
+    }
+
+    /// Progress of the replay
+    pub fn status(&self) -> ReplayStatus {
+        ReplayStatus {
+            running: self.progress.running.load(Ordering::Acquire),
+            events_replayed: self.progress.replayed.load(Ordering::Acquire),
+            events_total: self.events_total,
+            errors: self.progress.errors.load(Ordering::Acquire),
+        }
+    }
+}
+
+impl Drop for InputReplay {
+    fn drop(&mut self) {
+        // The thread isn't joined because this may be dropped with the input
+        // manager locked, which the thread needs for every event. It checks
+        // the flag with the lock held instead, so that no event is sent
+        // after this returns.
+        self.progress.stopped.store(true, Ordering::Release);
+        // Ignore the result because there is nothing we can do about it.
+        let _ = self.kill_evt.write(1);
+    }
+}
+
+struct ReplayWorker {
+    manager: Weak<Mutex<InputManager>>,
+    script: InputScript,
+    speed: f64,
+    backend: Option<BackendType>,
+    kill_evt: EventFd,
+    timer: TimerFd,
+    progress: Arc<ReplayProgress>,
+}
+
+impl ReplayWorker {
+    const KILL_EVENT: u64 = 0;
+    const TIMER_EVENT: u64 = 1;
+
+    fn run(&mut self) -> io::Result<()> {
+        let epoll = Epoll::new()?;
+        epoll.ctl(
+            ControlOperation::Add,
+            self.kill_evt.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, Self::KILL_EVENT),
+        )?;
+        epoll.ctl(
+            ControlOperation::Add,
+            self.timer.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, Self::TIMER_EVENT),
+        )?;
+
+        let events = std::mem::take(&mut self.script.events);
+        let start = Instant::now();
+        for script_event in &events {
+            let time = Duration::from_micros(script_event.time_us);
+            let deadline = start + time.div_f64(self.speed);
+            if !self.wait_until(&epoll, deadline)? {
+                break;
+            }
+
+            let Some(manager) = self.manager.upgrade() else {
+                break;
+            };
+            let mut manager = manager.lock().unwrap();
+            if self.progress.stopped.load(Ordering::Acquire) {
+                break;
+            }
+            let backend = self.backend.unwrap_or(manager.active_backend());
+            if manager.inject_with(backend, &script_event.event).is_err() {
+                self.progress.errors.fetch_add(1, Ordering::AcqRel);
+            }
+            self.progress.replayed.fetch_add(1, Ordering::AcqRel);
+        }
+
+        info!(
+            "Replayed {} input events",
+            self.progress.replayed.load(Ordering::Acquire)
+        );
+        Ok(())
+    }
+
+    /// Wait for `deadline`, false if the replay was stopped meanwhile
+    fn wait_until(&mut self, epoll: &Epoll, deadline: Instant) -> io::Result<bool> {
+        let now = Instant::now();
+        // A zero duration would disarm the timer
+        if deadline <= now {
+            return Ok(!self.progress.stopped.load(Ordering::Acquire));
+        }
+        self.timer.reset(deadline - now, None)?;
+
+        let mut events = [EpollEvent::default(); 2];
+        loop {
+            let count = match epoll.wait(-1, &mut events) {
+                Ok(count) => count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            for event in &events[..count] {
+                match event.data() {
+                    Self::KILL_EVENT => return Ok(false),
+                    Self::TIMER_EVENT => {
+                        self.timer.wait()?;
+                        return Ok(true);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::event::{KeyboardAction, KeyboardEvent};
+
+    #[test]
+    fn test_script_json() {
+        let script: InputScript = serde_json::from_str(
+            r#"{"events": [
+                {"time_us": 0, "device": "keyboard", "action": "press", "code": 30},
+                {"time_us": 5000, "device": "mouse", "action": "move", "x": 5, "y": -3, "z": 0}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(script.events.len(), 2);
+        assert!(matches!(
+            script.events[0].event,
+            InputEvent::Keyboard(KeyboardEvent {
+                action: KeyboardAction::Press,
+                code: 30,
+                ..
+            })
+        ));
+        assert!(matches!(script.events[1].event, InputEvent::Mouse(_)));
+        assert_eq!(script.duration(), Duration::from_millis(5));
+
+        let json = serde_json::to_string(&script).unwrap();
+        let again: InputScript = serde_json::from_str(&json).unwrap();
+        assert_eq!(again.events.len(), 2);
+        assert_eq!(again.events[1].time_us, 5000);
+    }
+
+    #[test]
+    fn test_recorder() {
+        let mut recorder = InputRecorder::new();
+        assert!(recorder.is_empty());
+
+        recorder.record(&InputEvent::keyboard(KeyboardAction::Press, 0x1E));
+        thread::sleep(Duration::from_millis(2));
+        recorder.record(&InputEvent::keyboard(KeyboardAction::Release, 0x1E));
+        assert_eq!(recorder.len(), 2);
+
+        let script = recorder.finish();
+        assert_eq!(script.events[0].time_us, 0);
+        assert!(script.events[1].time_us >= 2000);
+    }
+}
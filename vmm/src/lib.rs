@@ -14,7 +14,7 @@ use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
 #[cfg(not(target_arch = "riscv64"))]
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{io, result, thread};
 
 use anyhow::anyhow;
@@ -45,8 +45,8 @@ use vmm_sys_util::signal::unblock_signal;
 use vmm_sys_util::sock_ctrl_msg::ScmSocket;
 
 use crate::api::{
-    ApiRequest, ApiResponse, RequestHandler, VmInfoResponse, VmReceiveMigrationData,
-    VmSendMigrationData, VmmPingResponse,
+    ApiRequest, ApiResponse, RequestHandler, VmDisplayChangeData, VmInfoResponse,
+    VmReceiveMigrationData, VmSendMigrationData, VmmPingResponse,
 };
 use crate::config::{RestoreConfig, add_to_config};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
@@ -59,8 +59,8 @@ use crate::migration::{recv_vm_config, recv_vm_state};
 use crate::seccomp_filters::{Thread, get_seccomp_filter};
 use crate::vm::{Error as VmError, Vm, VmState};
 use crate::vm_config::{
-    DeviceConfig, DiskConfig, FsConfig, GenericVhostUserConfig, NetConfig, PmemConfig,
-    UserDeviceConfig, VdpaConfig, VmConfig, VsockConfig,
+    DeviceConfig, DiskConfig, FsConfig, GenericVhostUserConfig, GpuConfig, NetConfig, PmemConfig,
+    UserDeviceConfig, VdpaConfig, VirtioInputConfig, VmConfig, VsockConfig,
 };
 
 mod acpi;
@@ -73,6 +73,11 @@ mod coredump;
 pub mod cpu;
 pub mod device_manager;
 pub mod device_tree;
+pub mod disk_backup;
+pub mod disk_commit;
+pub mod frame_export;
+#[cfg(feature = "ivshmem")]
+pub mod frame_recorder;
 #[cfg(feature = "guest_debug")]
 mod gdb;
 #[cfg(feature = "igvm")]
@@ -88,6 +93,8 @@ mod serial_manager;
 mod sigwinch_listener;
 pub mod vm;
 pub mod vm_config;
+#[cfg(feature = "vnc")]
+pub mod vnc;
 
 type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 type GuestRegionMmap = vm_memory::GuestRegionMmap<AtomicBitmap>;
@@ -443,6 +450,8 @@ pub fn feature_list() -> Vec<String> {
         "tracing".to_string(),
         #[cfg(feature = "ivshmem")]
         "ivshmem".to_string(),
+        #[cfg(feature = "vnc")]
+        "vnc".to_string(),
     ]
 }
 
@@ -1978,6 +1987,103 @@ impl RequestHandler for Vmm {
         Err(VmError::ResizeDisk)
     }
 
+    fn vm_disk_internal_snapshot_list(
+        &mut self,
+        id: String,
+    ) -> result::Result<Option<Vec<u8>>, VmError> {
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        let snapshots = vm.disk_internal_snapshots(&id)?;
+        serde_json::to_vec(&snapshots)
+            .map(Some)
+            .map_err(VmError::SerializeJson)
+    }
+
+    fn vm_disk_internal_snapshot_create(
+        &mut self,
+        id: String,
+        name: String,
+    ) -> result::Result<(), VmError> {
+        let vm = self.vm.as_mut().ok_or(VmError::VmNotRunning)?;
+        vm.create_disk_internal_snapshot(&id, &name)
+    }
+
+    fn vm_disk_internal_snapshot_apply(
+        &mut self,
+        id: String,
+        name: String,
+    ) -> result::Result<(), VmError> {
+        let vm = self.vm.as_mut().ok_or(VmError::VmNotRunning)?;
+        vm.apply_disk_internal_snapshot(&id, &name)
+    }
+
+    fn vm_disk_internal_snapshot_delete(
+        &mut self,
+        id: String,
+        name: String,
+    ) -> result::Result<(), VmError> {
+        let vm = self.vm.as_mut().ok_or(VmError::VmNotRunning)?;
+        vm.delete_disk_internal_snapshot(&id, &name)
+    }
+
+    fn vm_disk_snapshot(&mut self, id: String, overlay: PathBuf) -> result::Result<(), VmError> {
+        let vm = self.vm.as_mut().ok_or(VmError::VmNotRunning)?;
+        vm.disk_live_snapshot(&id, &overlay)
+    }
+
+    fn vm_disk_commit(&mut self, id: String) -> result::Result<(), VmError> {
+        let vm = self.vm.as_mut().ok_or(VmError::VmNotRunning)?;
+        vm.disk_commit(&id)
+    }
+
+    fn vm_disk_commit_status(&mut self, id: String) -> result::Result<Option<Vec<u8>>, VmError> {
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        let status = vm.disk_commit_status(&id)?;
+        serde_json::to_vec(&status)
+            .map(Some)
+            .map_err(VmError::SerializeJson)
+    }
+
+    fn vm_disk_backup(
+        &mut self,
+        id: String,
+        target: PathBuf,
+        incremental: bool,
+    ) -> result::Result<(), VmError> {
+        let vm = self.vm.as_mut().ok_or(VmError::VmNotRunning)?;
+        vm.disk_backup(&id, &target, incremental)
+    }
+
+    fn vm_disk_backup_status(&mut self, id: String) -> result::Result<Option<Vec<u8>>, VmError> {
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        let status = vm.disk_backup_status(&id)?;
+        serde_json::to_vec(&status)
+            .map(Some)
+            .map_err(VmError::SerializeJson)
+    }
+
+    fn vm_disk_backup_cancel(&mut self, id: String) -> result::Result<(), VmError> {
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        vm.disk_backup_cancel(&id)
+    }
+
+    fn vm_display_change(
+        &mut self,
+        display_data: VmDisplayChangeData,
+    ) -> result::Result<(), VmError> {
+        let vm = self.vm.as_mut().ok_or(VmError::VmNotRunning)?;
+
+        let mode = if display_data.connected {
+            match (display_data.width, display_data.height) {
+                (Some(width), Some(height)) => Some((width, height)),
+                _ => return Err(VmError::MissingDisplayResolution),
+            }
+        } else {
+            None
+        };
+
+        vm.display_change(display_data.scanout_id, mode)
+    }
+
     fn vm_resize_zone(&mut self, id: String, desired_ram: u64) -> result::Result<(), VmError> {
         self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
 
@@ -2264,6 +2370,69 @@ impl RequestHandler for Vmm {
         }
     }
 
+    fn vm_add_gpu(&mut self, gpu_cfg: GpuConfig) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        {
+            // Validate the configuration change in a cloned configuration
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
+
+            if config.gpu.is_some() {
+                return Err(VmError::TooManyGpuDevices);
+            }
+
+            config.gpu = Some(gpu_cfg.clone());
+            config.validate().map_err(VmError::ConfigValidation)?;
+        }
+
+        if let Some(ref mut vm) = self.vm {
+            let info = vm.add_gpu(gpu_cfg).inspect_err(|e| {
+                error!("Error when adding new gpu device to the VM: {e:?}");
+            })?;
+            serde_json::to_vec(&info)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            // Update VmConfig by adding the new device.
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
+            config.gpu = Some(gpu_cfg);
+            Ok(None)
+        }
+    }
+
+    fn vm_add_input(
+        &mut self,
+        input_cfg: VirtioInputConfig,
+    ) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        {
+            // Validate the configuration change in a cloned configuration
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
+
+            if config.input.is_some() {
+                return Err(VmError::TooManyInputDevices);
+            }
+
+            config.input = Some(input_cfg.clone());
+            config.validate().map_err(VmError::ConfigValidation)?;
+        }
+
+        if let Some(ref mut vm) = self.vm {
+            let info = vm.add_input(input_cfg).inspect_err(|e| {
+                error!("Error when adding new input device to the VM: {e:?}");
+            })?;
+            serde_json::to_vec(&info)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            // Update VmConfig by adding the new device.
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
+            config.input = Some(input_cfg);
+            Ok(None)
+        }
+    }
+
     fn vm_counters(&mut self) -> result::Result<Option<Vec<u8>>, VmError> {
         if let Some(ref mut vm) = self.vm {
             let info = vm.counters().inspect_err(|e| {
@@ -2298,84 +2467,238 @@ impl RequestHandler for Vmm {
         input_request: crate::input::InputRequest,
     ) -> result::Result<crate::api::VmInjectInputResponse, VmError> {
         use crate::api::VmInjectInputResponse;
-        use crate::input::KeyboardAction;
+        use crate::input::BackendType;
 
         let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        // The guest may have changed resolution since the last request
+        let screen_size = vm.screen_size();
+        let input_manager = vm.input_manager();
+        let mut input_manager = input_manager.lock().unwrap();
 
-        let mut keyboard_events = 0u64;
-        let mut mouse_events = 0u64;
-        let mut errors = 0u64;
+        if let Some((width, height)) = screen_size {
+            input_manager.set_screen_size(width, height);
+        }
 
-        // Inject keyboard events
-        for event in &input_request.keyboard {
-            let release = match event.action {
-                KeyboardAction::Press | KeyboardAction::Type => false,
-                KeyboardAction::Release => true,
-            };
-            // Convert code to PS/2 scancode (lower byte for Set 2)
-            let scancode = event.code as u8;
+        let stats = input_manager
+            .process_request(&input_request)
+            .map_err(VmError::InputInjection)?;
+        let backend = input_request
+            .backend
+            .as_deref()
+            .and_then(BackendType::from_name)
+            .unwrap_or(input_manager.active_backend());
 
-            let i8042_event = devices::legacy::KeyboardEvent { scancode, release };
+        info!(
+            "Injected input through {}: {} keyboard events, {} mouse events, {} touch events, {} deferred, {} dropped, {} errors",
+            backend.name(),
+            stats.keyboard_events,
+            stats.mouse_events,
+            stats.touch_events,
+            stats.deferred_events,
+            stats.dropped_events,
+            stats.errors
+        );
 
-            if vm.inject_keyboard(&i8042_event) {
-                keyboard_events += 1;
-            } else {
-                errors += 1;
-                warn!("Failed to inject keyboard event: scancode={:02x}", scancode);
-            }
-        }
+        Ok(VmInjectInputResponse {
+            keyboard_events: stats.keyboard_events,
+            mouse_events: stats.mouse_events,
+            touch_events: stats.touch_events,
+            total_events: stats.total_events,
+            errors: stats.errors,
+            deferred_events: stats.deferred_events,
+            dropped_events: stats.dropped_events,
+            backend: backend.name().to_string(),
+            backends: input_manager.stats().backends.clone(),
+        })
+    }
 
-        // Inject mouse events
-        for event in &input_request.mouse {
-            // Build mouse buttons from event
-            let buttons = devices::legacy::MouseButtons {
-                left: event.button == Some(crate::input::MouseButton::Left),
-                right: event.button == Some(crate::input::MouseButton::Right),
-                middle: event.button == Some(crate::input::MouseButton::Middle),
-            };
+    fn vm_type_text(
+        &mut self,
+        type_text_data: crate::api::VmTypeTextData,
+    ) -> result::Result<crate::api::VmTypeTextResponse, VmError> {
+        use crate::api::VmTypeTextResponse;
+        use crate::input::{BackendType, InputManager};
 
-            // Convert coordinates (clamp to i16 range)
-            let dx = event.x.clamp(-255, 255) as i16;
-            let dy = event.y.clamp(-255, 255) as i16;
-            let dz = event.z.clamp(-8, 7) as i8;
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        let input_manager = vm.input_manager();
+
+        let typed = InputManager::type_text(
+            &input_manager,
+            &type_text_data.text,
+            type_text_data.layout,
+            type_text_data.backend.as_deref(),
+            Duration::from_millis(type_text_data.delay_ms),
+        )
+        .map_err(VmError::InputInjection)?;
+        let backend = type_text_data
+            .backend
+            .as_deref()
+            .and_then(BackendType::from_name)
+            .unwrap_or(input_manager.lock().unwrap().active_backend());
 
-            let i8042_event = devices::legacy::MouseEvent {
-                dx,
-                dy,
-                dz,
-                buttons,
-            };
+        info!(
+            "Typing {} characters through {} with the {} layout: {} untypable characters, {} deferred events, {} queued events, {} errors",
+            typed.characters,
+            backend.name(),
+            type_text_data.layout.name(),
+            typed.untypable.len(),
+            typed.stats.deferred_events,
+            typed.queued_events,
+            typed.stats.errors
+        );
 
-            if vm.inject_mouse(&i8042_event) {
-                mouse_events += 1;
-            } else {
-                errors += 1;
-                warn!("Failed to inject mouse event: dx={}, dy={}", dx, dy);
-            }
+        Ok(VmTypeTextResponse {
+            characters: typed.characters,
+            untypable: typed.untypable,
+            keyboard_events: typed.stats.keyboard_events,
+            errors: typed.stats.errors,
+            deferred_events: typed.stats.deferred_events,
+            dropped_events: typed.stats.dropped_events,
+            queued_events: typed.queued_events,
+            backend: backend.name().to_string(),
+        })
+    }
+
+    fn vm_input_record_start(&mut self) -> result::Result<(), VmError> {
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        vm.input_manager().lock().unwrap().start_recording();
+        info!("Started recording input");
+        Ok(())
+    }
+
+    fn vm_input_record_stop(
+        &mut self,
+        record_stop_data: crate::api::VmInputRecordStopData,
+    ) -> result::Result<crate::input::InputScript, VmError> {
+        use crate::input::InputError;
+
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        let script = vm
+            .input_manager()
+            .lock()
+            .unwrap()
+            .stop_recording()
+            .map_err(VmError::InputInjection)?;
+
+        if let Some(path) = record_stop_data.path {
+            let json = serde_json::to_vec_pretty(&script)
+                .map_err(|e| VmError::InputInjection(InputError::InvalidScript(e.to_string())))?;
+            std::fs::write(&path, json).map_err(|e| VmError::InputInjection(InputError::Io(e)))?;
+            info!(
+                "Saved {} recorded input events to {:?}",
+                script.events.len(),
+                path
+            );
+        } else {
+            info!("Recorded {} input events", script.events.len());
         }
 
+        Ok(script)
+    }
+
+    fn vm_input_replay_start(
+        &mut self,
+        replay_data: crate::api::VmInputReplayData,
+    ) -> result::Result<crate::input::ReplayStatus, VmError> {
+        use crate::input::{BackendType, InputError, InputManager};
+
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        let script = match (replay_data.script, replay_data.path) {
+            (Some(script), _) => script,
+            (None, Some(path)) => {
+                let json =
+                    std::fs::read(&path).map_err(|e| VmError::InputInjection(InputError::Io(e)))?;
+                serde_json::from_slice(&json).map_err(|e| {
+                    VmError::InputInjection(InputError::InvalidScript(format!("{path:?}: {e}")))
+                })?
+            }
+            (None, None) => {
+                return Err(VmError::InputInjection(InputError::InvalidScript(
+                    "No script given".to_string(),
+                )));
+            }
+        };
+        let backend = replay_data
+            .backend
+            .map(|name| {
+                BackendType::from_name(&name).ok_or_else(|| {
+                    VmError::InputInjection(InputError::BackendNotAvailable(format!(
+                        "Unknown backend: {name}"
+                    )))
+                })
+            })
+            .transpose()?;
+
+        InputManager::start_replay(&vm.input_manager(), script, replay_data.speed, backend)
+            .map_err(VmError::InputInjection)
+    }
+
+    fn vm_input_replay_stop(&mut self) -> result::Result<crate::input::ReplayStatus, VmError> {
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        let status = vm.input_manager().lock().unwrap().stop_replay();
         info!(
-            "Injected input: {} keyboard events, {} mouse events, {} errors",
-            keyboard_events, mouse_events, errors
+            "Stopped input replay after {} of {} events",
+            status.events_replayed, status.events_total
         );
+        Ok(status)
+    }
 
-        Ok(VmInjectInputResponse {
-            keyboard_events,
-            mouse_events,
-            total_events: keyboard_events + mouse_events,
-            errors,
+    fn vm_input_replay_status(&self) -> result::Result<crate::input::ReplayStatus, VmError> {
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        Ok(vm.input_manager().lock().unwrap().replay_status())
+    }
+
+    fn vm_input_state(&self) -> result::Result<crate::input::InputState, VmError> {
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        Ok(vm.input_manager().lock().unwrap().input_state())
+    }
+
+    fn vm_input_switch_backend(
+        &mut self,
+        switch_data: crate::api::VmInputSwitchBackendData,
+    ) -> result::Result<crate::api::VmInputBackendResponse, VmError> {
+        use crate::api::VmInputBackendResponse;
+        use crate::input::{BackendType, InputError};
+
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        let input_manager = vm.input_manager();
+        let mut input_manager = input_manager.lock().unwrap();
+
+        let backend = BackendType::from_name(&switch_data.backend).ok_or_else(|| {
+            VmError::InputInjection(InputError::BackendNotAvailable(format!(
+                "Unknown backend: {}",
+                switch_data.backend
+            )))
+        })?;
+        input_manager
+            .switch_backend(backend)
+            .map_err(VmError::InputInjection)?;
+        info!("Switched input backend to {}", backend.name());
+
+        Ok(VmInputBackendResponse {
+            active_backend: backend.name().to_string(),
+            available_backends: input_manager
+                .available_backends()
+                .iter()
+                .map(|backend| backend.name().to_string())
+                .collect(),
+            backends: input_manager.stats().backends.clone(),
         })
     }
 
-    fn vm_frame_info(&self) -> result::Result<crate::api::VmFrameInfoResponse, VmError> {
+    fn vm_frame_info(
+        &self,
+        scanout_id: u32,
+    ) -> result::Result<crate::api::VmFrameInfoResponse, VmError> {
         use crate::api::VmFrameInfoResponse;
 
         if self.vm.is_none() {
             return Err(VmError::VmNotRunning);
         }
 
+        // The ivshmem frame buffer stands for the first display
         #[cfg(feature = "ivshmem")]
-        {
+        if scanout_id == 0 {
             // Get frame buffer info from device manager
             let vm = self.vm.as_ref().unwrap();
             let device_manager = vm.device_manager();
@@ -2394,6 +2717,26 @@ impl RequestHandler for Vmm {
             }
         }
 
+        // Fall back to the scanout flushed through virtio-gpu
+        if let Some((width, height, format, buffer_count, frame_number, active_index)) = self
+            .vm
+            .as_ref()
+            .unwrap()
+            .device_manager()
+            .lock()
+            .unwrap()
+            .gpu_frame_info(scanout_id)
+        {
+            return Ok(VmFrameInfoResponse {
+                width,
+                height,
+                format,
+                buffer_count,
+                frame_number,
+                active_index,
+            });
+        }
+
         // Return default/empty response if frame buffer is not configured
         Ok(VmFrameInfoResponse {
             width: 0,
@@ -2483,6 +2826,8 @@ impl RequestHandler for Vmm {
             width: 0,
             height: 0,
             format: String::new(),
+            recording: false,
+            recorded_frames: 0,
         })
     }
 
@@ -2514,28 +2859,64 @@ impl RequestHandler for Vmm {
         }
     }
 
+    fn vm_frame_capture_record(
+        &mut self,
+        record_data: crate::api::VmFrameCaptureRecordData,
+    ) -> result::Result<(), VmError> {
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+
+        #[cfg(feature = "ivshmem")]
+        {
+            vm.device_manager()
+                .lock()
+                .unwrap()
+                .frame_capture_record(&record_data.path, record_data.fps, record_data.container)
+                .map_err(VmError::DeviceManager)?;
+            info!("Frame capture recording to {:?}", record_data.path);
+            Ok(())
+        }
+
+        #[cfg(not(feature = "ivshmem"))]
+        {
+            let _ = (vm, record_data);
+            Err(VmError::FrameBufferNotConfigured)
+        }
+    }
+
     fn vm_cursor_info(&self) -> result::Result<crate::api::VmCursorInfoResponse, VmError> {
         use crate::api::VmCursorInfoResponse;
 
-        if self.vm.is_none() {
-            return Err(VmError::VmNotRunning);
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        let device_manager = vm.device_manager();
+
+        // The virtio-gpu cursor queue is used when the guest drives it,
+        // otherwise the cursor published by the guest agent is reported.
+        if let Some(cursor_info) = device_manager.lock().unwrap().gpu_cursor_info() {
+            return Ok(cursor_info);
         }
 
         #[cfg(feature = "ivshmem")]
-        {
-            // Get cursor info from device manager
-            let vm = self.vm.as_ref().unwrap();
-            let device_manager = vm.device_manager();
-
-            if let Some(cursor_info) = device_manager.lock().unwrap().cursor_info() {
-                return Ok(cursor_info);
-            }
+        if let Some(cursor_info) = device_manager.lock().unwrap().cursor_info() {
+            return Ok(cursor_info);
         }
 
         // Return default/empty response if cursor is not available
         Ok(VmCursorInfoResponse::default())
     }
 
+    fn vm_frame_snapshot(
+        &self,
+        scanout_id: u32,
+    ) -> result::Result<crate::frame_export::Frame, VmError> {
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        let device_manager = vm.device_manager();
+        device_manager
+            .lock()
+            .unwrap()
+            .frame_snapshot(scanout_id)
+            .ok_or(VmError::NoFrame)
+    }
+
     fn vm_receive_migration(
         &mut self,
         receive_data_migration: VmReceiveMigrationData,
@@ -2758,6 +3139,11 @@ mod unit_tests {
             landlock_rules: None,
             #[cfg(feature = "ivshmem")]
             ivshmem: None,
+            usb: None,
+            gpu: None,
+            input: None,
+            #[cfg(feature = "vnc")]
+            vnc: None,
         })
     }
 
@@ -650,6 +650,11 @@ fn vmm_thread_rules(
         (libc::SYS_readlinkat, vec![]),
         (libc::SYS_recvfrom, vec![]),
         (libc::SYS_recvmsg, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_rename, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_renameat, vec![]),
+        (libc::SYS_renameat2, vec![]),
         (libc::SYS_restart_syscall, vec![]),
         (libc::SYS_rseq, vec![]),
         (libc::SYS_rt_sigaction, vec![]),
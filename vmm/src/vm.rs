@@ -19,6 +19,7 @@ use std::mem::size_of;
 use std::num::Wrapping;
 use std::ops::Deref;
 use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 #[cfg(not(target_arch = "riscv64"))]
 use std::time::Instant;
@@ -34,6 +35,7 @@ use arch::x86_64::MAX_SUPPORTED_CPUS_LEGACY;
 #[cfg(feature = "tdx")]
 use arch::x86_64::tdx::TdvfSection;
 use arch::{EntryPoint, NumaNode, NumaNodes, get_host_cpu_phys_bits};
+use block::async_io::DiskSnapshot;
 use devices::AcpiNotificationFlags;
 #[cfg(target_arch = "aarch64")]
 use devices::interrupt_controller;
@@ -84,10 +86,13 @@ use crate::coredump::{
 };
 use crate::device_manager::{DeviceManager, DeviceManagerError};
 use crate::device_tree::DeviceTree;
+use crate::disk_backup::DiskBackupStatus;
+use crate::disk_commit::DiskCommitStatus;
 #[cfg(feature = "guest_debug")]
 use crate::gdb::{Debuggable, DebuggableError, GdbRequestPayload, GdbResponsePayload};
 #[cfg(feature = "igvm")]
 use crate::igvm::igvm_loader;
+use crate::input::{InputConfig, InputManager};
 use crate::landlock::LandlockError;
 use crate::memory_manager::{
     Error as MemoryManagerError, MemoryManager, MemoryManagerSnapshotData,
@@ -100,8 +105,9 @@ use crate::migration::{SNAPSHOT_CONFIG_FILE, SNAPSHOT_STATE_FILE, url_to_path};
 #[cfg(feature = "fw_cfg")]
 use crate::vm_config::FwCfgConfig;
 use crate::vm_config::{
-    DeviceConfig, DiskConfig, FsConfig, GenericVhostUserConfig, HotplugMethod, NetConfig,
-    NumaConfig, PayloadConfig, PmemConfig, UserDeviceConfig, VdpaConfig, VmConfig, VsockConfig,
+    DeviceConfig, DiskConfig, FsConfig, GenericVhostUserConfig, GpuConfig, HotplugMethod,
+    NetConfig, NumaConfig, PayloadConfig, PmemConfig, UserDeviceConfig, VdpaConfig,
+    VirtioInputConfig, VmConfig, VsockConfig,
 };
 use crate::{
     CPU_MANAGER_SNAPSHOT_ID, DEVICE_MANAGER_SNAPSHOT_ID, GuestMemoryMmap,
@@ -177,6 +183,9 @@ pub enum Error {
     #[error("VM is not running")]
     VmNotRunning,
 
+    #[error("VM is not paused")]
+    VmNotPaused,
+
     #[error("Cannot clone EventFd")]
     EventFdClone(#[source] io::Error),
 
@@ -228,6 +237,12 @@ pub enum Error {
     #[error("Too many virtio-vsock devices")]
     TooManyVsockDevices,
 
+    #[error("Too many virtio-gpu devices")]
+    TooManyGpuDevices,
+
+    #[error("Too many virtio-input devices")]
+    TooManyInputDevices,
+
     #[error("Failed serializing into JSON")]
     SerializeJson(#[source] serde_json::Error),
 
@@ -246,6 +261,9 @@ pub enum Error {
     #[error("Failed resizing a disk image")]
     ResizeDisk,
 
+    #[error("A display resolution is required to connect a display")]
+    MissingDisplayResolution,
+
     #[error("Cannot activate virtio devices")]
     ActivateVirtioDevices(#[source] DeviceManagerError),
 
@@ -379,6 +397,16 @@ pub enum Error {
 
     #[error("Frame buffer not configured")]
     FrameBufferNotConfigured,
+
+    #[error("No frame published by the guest")]
+    NoFrame,
+
+    #[error("Error injecting input")]
+    InputInjection(#[source] crate::input::InputError),
+
+    #[cfg(feature = "vnc")]
+    #[error("Error starting the VNC server")]
+    VncServer(#[source] crate::vnc::VncError),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -510,6 +538,7 @@ pub struct Vm {
     initramfs: Option<File>,
     threads: Vec<thread::JoinHandle<()>>,
     device_manager: Arc<Mutex<DeviceManager>>,
+    input_manager: Arc<Mutex<InputManager>>,
     config: Arc<Mutex<VmConfig>>,
     state: VmState,
     cpu_manager: Arc<Mutex<cpu::CpuManager>>,
@@ -526,6 +555,8 @@ pub struct Vm {
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
     stop_on_boot: bool,
     load_payload_handle: Option<thread::JoinHandle<Result<EntryPoint>>>,
+    #[cfg(feature = "vnc")]
+    vnc_server: Option<crate::vnc::VncServer>,
 }
 
 impl Vm {
@@ -631,6 +662,20 @@ impl Vm {
             snapshot,
         )?;
 
+        let input_manager = Self::create_input_manager(&device_manager);
+
+        #[cfg(feature = "vnc")]
+        let vnc_server = config
+            .lock()
+            .unwrap()
+            .vnc
+            .as_ref()
+            .map(|vnc_config| {
+                crate::vnc::VncServer::new(vnc_config, &device_manager, &input_manager)
+            })
+            .transpose()
+            .map_err(Error::VncServer)?;
+
         // Load kernel and initramfs files
         #[cfg(feature = "tdx")]
         let kernel = config
@@ -672,6 +717,7 @@ impl Vm {
             kernel,
             initramfs,
             device_manager,
+            input_manager,
             config,
             threads: Vec::with_capacity(1),
             state,
@@ -686,6 +732,8 @@ impl Vm {
             hypervisor,
             stop_on_boot,
             load_payload_handle,
+            #[cfg(feature = "vnc")]
+            vnc_server,
         })
     }
 
@@ -1071,6 +1119,43 @@ impl Vm {
         Ok(())
     }
 
+    /// Create the per-VM input manager and hand it the input devices
+    /// created by the device manager.
+    fn create_input_manager(
+        device_manager: &Arc<Mutex<DeviceManager>>,
+    ) -> Arc<Mutex<InputManager>> {
+        let mut input_manager = InputManager::new(InputConfig::new());
+        let device_manager = device_manager.lock().unwrap();
+
+        #[cfg(target_arch = "x86_64")]
+        if let Some(i8042) = device_manager.i8042_device() {
+            input_manager.init_ps2_backend(i8042);
+        }
+
+        if let Some(virtio_input) = device_manager.virtio_input_device() {
+            input_manager.init_virtio_backend(virtio_input);
+        }
+
+        let (keyboard, mouse, tablet) = device_manager.usb_hid_devices();
+        if keyboard.is_some() || mouse.is_some() || tablet.is_some() {
+            input_manager.init_usb_backend(keyboard, mouse, tablet);
+        }
+
+        // Fall back to whatever is there when the VM has no i8042
+        let available = input_manager.available_backends();
+        if !available.contains(&input_manager.active_backend())
+            && let Some(&backend) = available.first()
+        {
+            let _ = input_manager.switch_backend(backend);
+        }
+
+        let input_manager = Arc::new(Mutex::new(input_manager));
+        if let Err(e) = InputManager::start_pacing(&input_manager) {
+            warn!("Deferred input events won't be sent on their own: {e}");
+        }
+        input_manager
+    }
+
     /// Create fw_cfg device if enabled in configuration.
     #[cfg(feature = "fw_cfg")]
     fn create_fw_cfg_if_enabled(
@@ -1880,6 +1965,14 @@ impl Vm {
             .shutdown()
             .map_err(Error::CpuManager)?;
 
+        // Keep the recorded files playable
+        #[cfg(feature = "ivshmem")]
+        self.device_manager.lock().unwrap().stop_frame_recording();
+
+        // Disconnect the VNC clients and free the socket for the next boot
+        #[cfg(feature = "vnc")]
+        self.vnc_server.take();
+
         // Wait for all the threads to finish
         for thread in self.threads.drain(..) {
             thread.join().map_err(Error::ThreadCleanup)?;
@@ -1987,6 +2080,118 @@ impl Vm {
         Ok(())
     }
 
+    pub fn disk_internal_snapshots(&self, id: &str) -> Result<Vec<DiskSnapshot>> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .disk_snapshots(id)
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Internal snapshots can only be taken, applied or deleted while the VM
+    /// is paused, so the guest doesn't see its disks change under it. The
+    /// requests the disks still have in flight complete beforehand.
+    fn check_paused(&self) -> Result<()> {
+        if self.get_state() != VmState::Paused {
+            return Err(Error::VmNotPaused);
+        }
+        Ok(())
+    }
+
+    pub fn create_disk_internal_snapshot(&mut self, id: &str, name: &str) -> Result<()> {
+        self.check_paused()?;
+        self.device_manager
+            .lock()
+            .unwrap()
+            .create_disk_snapshot(id, name)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn apply_disk_internal_snapshot(&mut self, id: &str, name: &str) -> Result<()> {
+        self.check_paused()?;
+        self.device_manager
+            .lock()
+            .unwrap()
+            .apply_disk_snapshot(id, name)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn delete_disk_internal_snapshot(&mut self, id: &str, name: &str) -> Result<()> {
+        self.check_paused()?;
+        self.device_manager
+            .lock()
+            .unwrap()
+            .delete_disk_snapshot(id, name)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn disk_live_snapshot(&mut self, id: &str, overlay: &Path) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .disk_live_snapshot(id, overlay)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn disk_commit(&mut self, id: &str) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .disk_commit(id)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn disk_commit_status(&self, id: &str) -> Result<DiskCommitStatus> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .disk_commit_status(id)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn disk_backup(&mut self, id: &str, target: &Path, incremental: bool) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .disk_backup(id, target, incremental)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn disk_backup_status(&self, id: &str) -> Result<DiskBackupStatus> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .disk_backup_status(id)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn disk_backup_cancel(&self, id: &str) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .disk_backup_cancel(id)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn display_change(&mut self, scanout_id: u32, mode: Option<(u32, u32)>) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .gpu_display_change(scanout_id, mode)
+            .map_err(Error::DeviceManager)?;
+
+        event!(
+            "vm",
+            "display-changed",
+            "scanout_id",
+            scanout_id.to_string(),
+            "connected",
+            mode.is_some().to_string()
+        );
+
+        Ok(())
+    }
+
     pub fn resize_zone(&mut self, id: &str, desired_memory: u64) -> Result<()> {
         let memory_config = &mut self.config.lock().unwrap().memory;
 
@@ -2081,6 +2286,16 @@ impl Vm {
         // ensure the device would not be created in case of a reboot.
         self.config.lock().unwrap().remove_device(id);
 
+        if self
+            .device_manager
+            .lock()
+            .unwrap()
+            .virtio_input_device()
+            .is_none()
+        {
+            self.input_manager.lock().unwrap().remove_virtio_backend();
+        }
+
         self.device_manager
             .lock()
             .unwrap()
@@ -2260,6 +2475,62 @@ impl Vm {
         Ok(pci_device_info)
     }
 
+    pub fn add_gpu(&mut self, mut gpu_cfg: GpuConfig) -> Result<PciDeviceInfo> {
+        let pci_device_info = self
+            .device_manager
+            .lock()
+            .unwrap()
+            .add_gpu(&mut gpu_cfg)
+            .map_err(Error::DeviceManager)?;
+
+        // Update VmConfig by adding the new device. This is important to
+        // ensure the device would be created in case of a reboot.
+        {
+            let mut config = self.config.lock().unwrap();
+            config.gpu = Some(gpu_cfg);
+        }
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
+            .map_err(Error::DeviceManager)?;
+
+        Ok(pci_device_info)
+    }
+
+    pub fn add_input(&mut self, mut input_cfg: VirtioInputConfig) -> Result<PciDeviceInfo> {
+        let pci_device_info = self
+            .device_manager
+            .lock()
+            .unwrap()
+            .add_input(&mut input_cfg)
+            .map_err(Error::DeviceManager)?;
+
+        // Update VmConfig by adding the new device. This is important to
+        // ensure the device would be created in case of a reboot.
+        {
+            let mut config = self.config.lock().unwrap();
+            config.input = Some(input_cfg);
+        }
+
+        // Make the new device available for input injection
+        if let Some(virtio_input) = self.device_manager.lock().unwrap().virtio_input_device() {
+            self.input_manager
+                .lock()
+                .unwrap()
+                .init_virtio_backend(virtio_input);
+        }
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
+            .map_err(Error::DeviceManager)?;
+
+        Ok(pci_device_info)
+    }
+
     pub fn counters(&self) -> Result<HashMap<String, HashMap<&'static str, Wrapping<u64>>>> {
         Ok(self.device_manager.lock().unwrap().counters())
     }
@@ -2952,21 +3223,33 @@ impl Vm {
         unimplemented!()
     }
 
-    /// Inject keyboard event into the VM's i8042 device (x86_64 only)
-    #[cfg(target_arch = "x86_64")]
-    pub fn inject_keyboard(&self, event: &devices::legacy::KeyboardEvent) -> bool {
-        self.device_manager.lock().unwrap().inject_keyboard(event)
+    /// Get reference to device manager for accessing device state
+    pub fn device_manager(&self) -> Arc<Mutex<DeviceManager>> {
+        self.device_manager.clone()
     }
 
-    /// Inject mouse event into the VM's i8042 device (x86_64 only)
-    #[cfg(target_arch = "x86_64")]
-    pub fn inject_mouse(&self, event: &devices::legacy::MouseEvent) -> bool {
-        self.device_manager.lock().unwrap().inject_mouse(event)
+    /// Get reference to the VM's input manager
+    pub fn input_manager(&self) -> Arc<Mutex<InputManager>> {
+        self.input_manager.clone()
     }
 
-    /// Get reference to device manager for accessing device state
-    pub fn device_manager(&self) -> Arc<Mutex<DeviceManager>> {
-        self.device_manager.clone()
+    /// Resolution of the first display, which absolute pointer positions
+    /// are relative to
+    pub fn screen_size(&self) -> Option<(u32, u32)> {
+        let device_manager = self.device_manager.lock().unwrap();
+
+        #[cfg(feature = "ivshmem")]
+        if let Some((width, height, ..)) = device_manager.frame_buffer_info()
+            && width > 0
+            && height > 0
+        {
+            return Some((width, height));
+        }
+
+        device_manager
+            .gpu_frame_info(0)
+            .map(|(width, height, ..)| (width, height))
+            .filter(|&(width, height)| width > 0 && height > 0)
     }
 
     pub fn memory_manager_data(&self) -> MemoryManagerSnapshotData {
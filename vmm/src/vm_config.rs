@@ -3,6 +3,8 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 use std::net::IpAddr;
+#[cfg(feature = "vnc")]
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 #[cfg(feature = "fw_cfg")]
 use std::str::FromStr;
@@ -13,9 +15,11 @@ use log::{debug, warn};
 use net_util::MacAddr;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use virtio_devices::RateLimiterConfig;
+use virtio_devices::{PointerMode, RateLimiterConfig};
 
 use crate::Landlock;
+#[cfg(feature = "vnc")]
+use crate::input::KeyboardLayout;
 use crate::landlock::LandlockError;
 
 pub type LandlockResult<T> = result::Result<T, LandlockError>;
@@ -302,6 +306,14 @@ pub struct DiskConfig {
     pub sparse: bool,
     #[serde(default)]
     pub image_type: ImageType,
+    #[serde(default)]
+    pub dirty_bitmap: bool,
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    // Never serialized, so that it isn't returned by vm.info nor written to
+    // snapshots. Restored or migrated VMs need a key_file instead.
+    #[serde(default, skip_serializing)]
+    pub key_secret: Option<String>,
 }
 
 impl ApplyLandlock for DiskConfig {
@@ -309,6 +321,9 @@ impl ApplyLandlock for DiskConfig {
         if let Some(path) = &self.path {
             landlock.add_rule_with_access(path, "rw")?;
         }
+        if let Some(key_file) = &self.key_file {
+            landlock.add_rule_with_access(key_file, "r")?;
+        }
         Ok(())
     }
 }
@@ -703,6 +718,10 @@ pub struct FrameBufferConfig {
     /// Number of buffers (default: 3 for triple buffering)
     #[serde(default = "default_buffer_count")]
     pub buffer_count: u32,
+    /// UNIX socket streaming a notification for every frame published by
+    /// the guest
+    #[serde(default)]
+    pub notify_socket: Option<PathBuf>,
 }
 
 #[cfg(feature = "ivshmem")]
@@ -733,18 +752,32 @@ impl Default for FrameBufferConfig {
             height: default_frame_height(),
             format: default_frame_format(),
             buffer_count: default_buffer_count(),
+            notify_socket: None,
         }
     }
 }
 
+#[cfg(feature = "ivshmem")]
+pub fn default_ivshmem_vectors() -> u16 {
+    1
+}
+
 #[cfg(feature = "ivshmem")]
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct IvshmemConfig {
+    /// Backend file, unused when the shared memory comes from `server`
+    #[serde(default)]
     pub path: PathBuf,
     pub size: usize,
     /// Optional frame buffer configuration for lg-capture support
     #[serde(default)]
     pub frame_buffer: Option<FrameBufferConfig>,
+    /// ivshmem-server socket, enables the doorbell mode
+    #[serde(default)]
+    pub server: Option<PathBuf>,
+    /// Number of MSI-X vectors in doorbell mode
+    #[serde(default = "default_ivshmem_vectors")]
+    pub vectors: u16,
 }
 
 #[cfg(feature = "ivshmem")]
@@ -754,10 +787,220 @@ impl Default for IvshmemConfig {
             path: PathBuf::new(),
             size: DEFAULT_IVSHMEM_SIZE << 20,
             frame_buffer: None,
+            server: None,
+            vectors: default_ivshmem_vectors(),
         }
     }
 }
 
+/// USB (xHCI) controller configuration
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UsbConfig {
+    /// Attach a USB HID boot keyboard to the controller
+    #[serde(default = "default_usbconfig_true")]
+    pub keyboard: bool,
+    /// Attach a USB HID boot mouse to the controller
+    #[serde(default = "default_usbconfig_true")]
+    pub mouse: bool,
+    /// Attach a USB HID absolute tablet to the controller
+    #[serde(default)]
+    pub tablet: bool,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
+pub fn default_usbconfig_true() -> bool {
+    true
+}
+
+impl Default for UsbConfig {
+    fn default() -> Self {
+        Self {
+            keyboard: default_usbconfig_true(),
+            mouse: default_usbconfig_true(),
+            tablet: false,
+            pci_segment: 0,
+        }
+    }
+}
+
+pub const DEFAULT_GPU_WIDTH: u32 = 1280;
+pub const DEFAULT_GPU_HEIGHT: u32 = 800;
+
+/// virtio-gpu device configuration
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GpuConfig {
+    /// Display width in pixels
+    #[serde(default = "default_gpuconfig_width")]
+    pub width: u32,
+    /// Display height in pixels
+    #[serde(default = "default_gpuconfig_height")]
+    pub height: u32,
+    /// Number of scanouts exposed to the guest
+    #[serde(default = "default_gpuconfig_max_outputs")]
+    pub max_outputs: u32,
+    /// Advertise EDID support to the guest
+    #[serde(default = "default_gpuconfig_edid")]
+    pub edid: bool,
+    /// Displays connected at boot, one per scanout starting from the first
+    /// one. Without it, a single `width`x`height` display is connected.
+    #[serde(default)]
+    pub outputs: Option<Vec<GpuOutputConfig>>,
+    /// Execute 3D commands through virglrenderer
+    #[serde(default)]
+    pub virgl: bool,
+    /// Advertise VIRTIO_GPU_F_RESOURCE_BLOB to the guest
+    #[serde(default)]
+    pub blob: bool,
+    /// Size of the host-visible shared memory region (0 disables it)
+    #[serde(default)]
+    pub hostmem: u64,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
+/// Resolution of a display connected to a virtio-gpu scanout
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GpuOutputConfig {
+    pub width: u32,
+    pub height: u32,
+}
+
+pub fn default_gpuconfig_width() -> u32 {
+    DEFAULT_GPU_WIDTH
+}
+
+pub fn default_gpuconfig_height() -> u32 {
+    DEFAULT_GPU_HEIGHT
+}
+
+pub fn default_gpuconfig_max_outputs() -> u32 {
+    1
+}
+
+pub fn default_gpuconfig_edid() -> bool {
+    true
+}
+
+impl Default for GpuConfig {
+    fn default() -> Self {
+        Self {
+            width: default_gpuconfig_width(),
+            height: default_gpuconfig_height(),
+            max_outputs: default_gpuconfig_max_outputs(),
+            edid: default_gpuconfig_edid(),
+            outputs: None,
+            virgl: false,
+            blob: false,
+            hostmem: 0,
+            id: None,
+            pci_segment: 0,
+        }
+    }
+}
+
+pub const DEFAULT_VIRTIO_INPUT_NAME: &str = "Cloud Hypervisor Virtio Input";
+
+/// virtio-input device configuration
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VirtioInputConfig {
+    /// Device name reported to the guest
+    #[serde(default = "default_virtioinputconfig_name")]
+    pub name: String,
+    /// Device serial reported to the guest
+    #[serde(default)]
+    pub serial: String,
+    /// Pointer exposed next to the keyboard
+    #[serde(default)]
+    pub pointer: PointerMode,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
+pub fn default_virtioinputconfig_name() -> String {
+    DEFAULT_VIRTIO_INPUT_NAME.to_string()
+}
+
+impl Default for VirtioInputConfig {
+    fn default() -> Self {
+        Self {
+            name: default_virtioinputconfig_name(),
+            serial: String::new(),
+            pointer: PointerMode::default(),
+            iommu: false,
+            id: None,
+            pci_segment: 0,
+        }
+    }
+}
+
+#[cfg(feature = "vnc")]
+pub const DEFAULT_VNC_FPS: u32 = 30;
+#[cfg(feature = "vnc")]
+pub const MAX_VNC_FPS: u32 = 60;
+#[cfg(feature = "vnc")]
+pub const DEFAULT_VNC_MAX_CLIENTS: u32 = 4;
+
+/// Built-in VNC server configuration
+#[cfg(feature = "vnc")]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VncConfig {
+    /// UNIX socket to listen on
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+    /// TCP address to listen on
+    #[serde(default)]
+    pub addr: Option<SocketAddr>,
+    /// Display shown to the clients, the ivshmem frame buffer standing for
+    /// the first one
+    #[serde(default)]
+    pub scanout: u32,
+    /// Maximum number of screen updates per second
+    #[serde(default = "default_vncconfig_fps")]
+    pub fps: u32,
+    /// Keyboard layout of the guest, used to find the keys typing the
+    /// characters sent by the clients
+    #[serde(default)]
+    pub layout: KeyboardLayout,
+    /// Maximum number of clients connected at once
+    #[serde(default = "default_vncconfig_max_clients")]
+    pub max_clients: u32,
+    /// Listen on a TCP address other than loopback, the clients are not
+    /// authenticated
+    #[serde(default)]
+    pub allow_remote: bool,
+}
+
+#[cfg(feature = "vnc")]
+pub fn default_vncconfig_fps() -> u32 {
+    DEFAULT_VNC_FPS
+}
+
+#[cfg(feature = "vnc")]
+pub fn default_vncconfig_max_clients() -> u32 {
+    DEFAULT_VNC_MAX_CLIENTS
+}
+
+#[cfg(feature = "vnc")]
+impl ApplyLandlock for VncConfig {
+    fn apply_landlock(&self, landlock: &mut Landlock) -> LandlockResult<()> {
+        if let Some(socket) = &self.socket {
+            if let Some(parent) = socket.parent() {
+                landlock.add_rule_with_access(parent, "w")?;
+            }
+            landlock.add_rule_with_access(socket, "rw")?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct NumaDistance {
     #[serde(default)]
@@ -1052,6 +1295,11 @@ pub struct VmConfig {
     pub landlock_rules: Option<Vec<LandlockConfig>>,
     #[cfg(feature = "ivshmem")]
     pub ivshmem: Option<IvshmemConfig>,
+    pub usb: Option<UsbConfig>,
+    pub gpu: Option<GpuConfig>,
+    pub input: Option<VirtioInputConfig>,
+    #[cfg(feature = "vnc")]
+    pub vnc: Option<VncConfig>,
 }
 
 impl VmConfig {
@@ -1136,6 +1384,11 @@ impl VmConfig {
             tpm_config.apply_landlock(&mut landlock)?;
         }
 
+        #[cfg(feature = "vnc")]
+        if let Some(vnc_config) = &self.vnc {
+            vnc_config.apply_landlock(&mut landlock)?;
+        }
+
         if self.net.is_some() {
             landlock.add_rule_with_access(Path::new("/dev/net/tun"), "rw")?;
         }
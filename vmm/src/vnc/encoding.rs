@@ -0,0 +1,612 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Framebuffer Encodings
+//!
+//! The screen is compared with what the client last received in tiles of
+//! 64x64 pixels, and only the tiles which changed are sent. A region which
+//! moved up or down, like a scrolled window, is sent as a CopyRect first,
+//! so that the client copies it from its own framebuffer.
+//!
+//! The tiles are sent with ZRLE, which compresses every rectangle of the
+//! session in a single zlib stream, or Raw when the client doesn't have it.
+//! The tiles of ZRLE are the same 64x64 pixels, each of them sent as a
+//! solid colour, a palette of up to 16 colours or raw pixels, whichever is
+//! the smallest.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io;
+
+use flate2::{Compress, Compression, FlushCompress};
+
+use super::protocol::{
+    ENCODING_COPY_RECT, ENCODING_CURSOR, ENCODING_DESKTOP_SIZE, ENCODING_QEMU_EXTENDED_KEY_EVENT,
+    ENCODING_RAW, ENCODING_ZRLE, PixelFormat, Rect, Update,
+};
+use crate::frame_export::CursorImage;
+
+/// Side of the tiles compared and sent
+pub const TILE_SIZE: usize = 64;
+
+/// Rows which must have moved together for a CopyRect to be sent
+const MIN_SCROLL_ROWS: usize = 16;
+
+/// Rows of old content a moved row may match before it is ignored, for
+/// uniform rows match everywhere
+const MAX_ROW_MATCHES: usize = 4;
+
+/// Pixels of the screen, as 8-bit RGB triplets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+impl Framebuffer {
+    /// Black screen
+    pub fn new(width: usize, height: usize) -> Self {
+        Framebuffer {
+            width,
+            height,
+            rgb: vec![0; width * height * 3],
+        }
+    }
+
+    /// Copy of `rgb`, of `width` by `height` pixels, cropped or extended
+    /// with black to the size of this framebuffer
+    pub fn fit(&mut self, width: usize, height: usize, rgb: &[u8]) {
+        if (width, height) == (self.width, self.height) {
+            self.rgb.copy_from_slice(rgb);
+            return;
+        }
+
+        self.rgb.fill(0);
+        let row_len = width.min(self.width) * 3;
+        for y in 0..height.min(self.height) {
+            self.rgb[y * self.width * 3..][..row_len]
+                .copy_from_slice(&rgb[y * width * 3..][..row_len]);
+        }
+    }
+
+    /// Bytes of the row `y` between the columns `x` and `x + width`
+    fn row(&self, y: usize, x: usize, width: usize) -> &[u8] {
+        &self.rgb[(y * self.width + x) * 3..][..width * 3]
+    }
+
+    fn full(&self) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Tiles which differ from `old`, merged into rectangles along the rows
+    /// of tiles
+    pub fn changes(&self, old: &Framebuffer) -> Vec<Rect> {
+        let mut rects: Vec<Rect> = Vec::new();
+        for tile_y in (0..self.height).step_by(TILE_SIZE) {
+            let height = TILE_SIZE.min(self.height - tile_y);
+            for tile_x in (0..self.width).step_by(TILE_SIZE) {
+                let width = TILE_SIZE.min(self.width - tile_x);
+                let changed = (tile_y..tile_y + height)
+                    .any(|y| self.row(y, tile_x, width) != old.row(y, tile_x, width));
+                if !changed {
+                    continue;
+                }
+
+                match rects.last_mut() {
+                    Some(last) if last.y == tile_y && last.x + last.width == tile_x => {
+                        last.width += width;
+                    }
+                    _ => rects.push(Rect {
+                        x: tile_x,
+                        y: tile_y,
+                        width,
+                        height,
+                    }),
+                }
+            }
+        }
+        rects
+    }
+
+    /// Rows of `area` found higher or lower in `old`, as the destination
+    /// and the source row of a CopyRect
+    pub fn find_move(&self, old: &Framebuffer, area: Rect) -> Option<(Rect, usize)> {
+        let hash = |framebuffer: &Framebuffer, y: usize| {
+            let mut hasher = DefaultHasher::new();
+            hasher.write(framebuffer.row(y, area.x, area.width));
+            hasher.finish()
+        };
+        let rows = area.y..area.y + area.height;
+        let old_hashes: Vec<u64> = rows.clone().map(|y| hash(old, y)).collect();
+        let new_hashes: Vec<u64> = rows.map(|y| hash(self, y)).collect();
+
+        let mut old_rows: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (row, hash) in old_hashes.iter().enumerate() {
+            old_rows.entry(*hash).or_default().push(row);
+        }
+
+        // Vote for the offsets the changed rows were moved by
+        let mut votes: HashMap<isize, usize> = HashMap::new();
+        for (row, hash) in new_hashes.iter().enumerate() {
+            if old_hashes[row] == *hash {
+                continue;
+            }
+            match old_rows.get(hash) {
+                Some(sources) if sources.len() <= MAX_ROW_MATCHES => {
+                    for source in sources {
+                        *votes.entry(*source as isize - row as isize).or_default() += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let (&offset, &count) = votes
+            .iter()
+            .max_by_key(|(offset, count)| (**count, -offset.abs()))?;
+        if count < MIN_SCROLL_ROWS {
+            return None;
+        }
+
+        // Longest run of rows moved by this offset
+        let rows_moved = |row: usize| {
+            let source = row as isize + offset;
+            source >= 0
+                && (source as usize) < area.height
+                && new_hashes[row] == old_hashes[source as usize]
+                && self.row(area.y + row, area.x, area.width)
+                    == old.row(area.y + source as usize, area.x, area.width)
+        };
+        let mut best = (0, 0);
+        let mut start = 0;
+        for row in 0..=area.height {
+            if row < area.height && rows_moved(row) {
+                continue;
+            }
+            if row - start > best.1 - best.0 {
+                best = (start, row);
+            }
+            start = row + 1;
+        }
+        if best.1 - best.0 < MIN_SCROLL_ROWS {
+            return None;
+        }
+
+        let destination = Rect {
+            x: area.x,
+            y: area.y + best.0,
+            width: area.width,
+            height: best.1 - best.0,
+        };
+        Some((destination, (destination.y as isize + offset) as usize))
+    }
+
+    /// Copy the rows of `destination` from `source_y`, as the client does
+    /// for a CopyRect
+    pub fn copy_rows(&mut self, destination: Rect, source_y: usize) {
+        let rows: Vec<u8> = (source_y..source_y + destination.height)
+            .flat_map(|y| self.row(y, destination.x, destination.width).to_vec())
+            .collect();
+        let row_len = destination.width * 3;
+        for (index, row) in rows.chunks_exact(row_len).enumerate() {
+            let offset = ((destination.y + index) * self.width + destination.x) * 3;
+            self.rgb[offset..offset + row_len].copy_from_slice(row);
+        }
+    }
+
+    /// Pixels of `rect` in `format`, row after row
+    fn pixels(&self, rect: Rect, format: &PixelFormat) -> Vec<u32> {
+        (rect.y..rect.y + rect.height)
+            .flat_map(|y| {
+                self.row(y, rect.x, rect.width)
+                    .chunks_exact(3)
+                    .map(|rgb| format.pixel(rgb))
+            })
+            .collect()
+    }
+}
+
+/// Encodings the client accepts, from its SetEncodings message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Encodings {
+    pub zrle: bool,
+    pub copy_rect: bool,
+    pub cursor: bool,
+    pub desktop_size: bool,
+    pub extended_key_event: bool,
+}
+
+impl Encodings {
+    pub fn new(encodings: &[i32]) -> Self {
+        // Raw is always accepted, ZRLE is only used if preferred to it
+        let raw = encodings.iter().position(|e| *e == ENCODING_RAW);
+        let zrle = encodings.iter().position(|e| *e == ENCODING_ZRLE);
+        Encodings {
+            zrle: match (zrle, raw) {
+                (Some(zrle), Some(raw)) => zrle < raw,
+                (zrle, _) => zrle.is_some(),
+            },
+            copy_rect: encodings.contains(&ENCODING_COPY_RECT),
+            cursor: encodings.contains(&ENCODING_CURSOR),
+            desktop_size: encodings.contains(&ENCODING_DESKTOP_SIZE),
+            extended_key_event: encodings.contains(&ENCODING_QEMU_EXTENDED_KEY_EVENT),
+        }
+    }
+}
+
+/// Encodes the rectangles sent to a client.
+pub struct Encoder {
+    zlib: Compress,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder {
+            zlib: Compress::new(Compression::fast(), true),
+        }
+    }
+
+    /// Add the changes of `framebuffer` since the client got `old`, which
+    /// is updated to it
+    pub fn encode_changes(
+        &mut self,
+        update: &mut Update,
+        framebuffer: &Framebuffer,
+        old: &mut Framebuffer,
+        format: &PixelFormat,
+        encodings: Encodings,
+    ) -> io::Result<()> {
+        let mut rects = framebuffer.changes(old);
+        if encodings.copy_rect
+            && let Some(area) = bounding_rect(&rects)
+            && area.height >= MIN_SCROLL_ROWS
+            && let Some((destination, source_y)) = framebuffer.find_move(old, area)
+        {
+            let data = update.rect(destination, ENCODING_COPY_RECT);
+            data.extend_from_slice(&(destination.x as u16).to_be_bytes());
+            data.extend_from_slice(&(source_y as u16).to_be_bytes());
+            old.copy_rows(destination, source_y);
+            rects = framebuffer.changes(old);
+        }
+
+        for rect in rects {
+            self.encode(update, framebuffer, rect, format, encodings)?;
+        }
+        old.rgb.copy_from_slice(&framebuffer.rgb);
+        Ok(())
+    }
+
+    /// Add the whole `framebuffer`
+    pub fn encode_all(
+        &mut self,
+        update: &mut Update,
+        framebuffer: &Framebuffer,
+        format: &PixelFormat,
+        encodings: Encodings,
+    ) -> io::Result<()> {
+        // Keep the rectangles small enough for the clients to show progress
+        for y in (0..framebuffer.height).step_by(TILE_SIZE) {
+            let rect = Rect {
+                y,
+                height: TILE_SIZE.min(framebuffer.height - y),
+                ..framebuffer.full()
+            };
+            self.encode(update, framebuffer, rect, format, encodings)?;
+        }
+        Ok(())
+    }
+
+    fn encode(
+        &mut self,
+        update: &mut Update,
+        framebuffer: &Framebuffer,
+        rect: Rect,
+        format: &PixelFormat,
+        encodings: Encodings,
+    ) -> io::Result<()> {
+        if !encodings.zrle {
+            let data = update.rect(rect, ENCODING_RAW);
+            data.reserve(rect.width * rect.height * format.bytes_per_pixel());
+            for pixel in framebuffer.pixels(rect, format) {
+                format.put_pixel(data, pixel);
+            }
+            return Ok(());
+        }
+
+        let mut tiles = Vec::new();
+        for y in (rect.y..rect.y + rect.height).step_by(TILE_SIZE) {
+            for x in (rect.x..rect.x + rect.width).step_by(TILE_SIZE) {
+                let tile = Rect {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(rect.x + rect.width - x),
+                    height: TILE_SIZE.min(rect.y + rect.height - y),
+                };
+                zrle_tile(
+                    &mut tiles,
+                    &framebuffer.pixels(tile, format),
+                    tile.width,
+                    format,
+                );
+            }
+        }
+
+        let compressed = self.compress(&tiles)?;
+        let data = update.rect(rect, ENCODING_ZRLE);
+        data.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+        data.extend_from_slice(&compressed);
+        Ok(())
+    }
+
+    /// Compress `input` in the zlib stream of the session, flushed so that
+    /// the client can decompress all of it
+    fn compress(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(input.len() / 4 + 64);
+        let start = self.zlib.total_in();
+        loop {
+            let consumed = (self.zlib.total_in() - start) as usize;
+            self.zlib
+                .compress_vec(&input[consumed..], &mut output, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+            let consumed = (self.zlib.total_in() - start) as usize;
+            // The flush is complete once the output isn't full anymore
+            if consumed == input.len() && output.len() < output.capacity() {
+                return Ok(output);
+            }
+            output.reserve(output.capacity());
+        }
+    }
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Smallest rectangle containing all of `rects`
+fn bounding_rect(rects: &[Rect]) -> Option<Rect> {
+    let x = rects.iter().map(|rect| rect.x).min()?;
+    let y = rects.iter().map(|rect| rect.y).min()?;
+    let right = rects.iter().map(|rect| rect.x + rect.width).max()?;
+    let bottom = rects.iter().map(|rect| rect.y + rect.height).max()?;
+    Some(Rect {
+        x,
+        y,
+        width: right - x,
+        height: bottom - y,
+    })
+}
+
+/// Append the ZRLE tile of `pixels`, `width` pixels per row
+fn zrle_tile(out: &mut Vec<u8>, pixels: &[u32], width: usize, format: &PixelFormat) {
+    const RAW: u8 = 0;
+    const MAX_PALETTE: usize = 16;
+
+    let mut palette: Vec<u32> = Vec::with_capacity(MAX_PALETTE);
+    for pixel in pixels {
+        if !palette.contains(pixel) {
+            if palette.len() == MAX_PALETTE {
+                out.push(RAW);
+                for pixel in pixels {
+                    format.put_compressed_pixel(out, *pixel);
+                }
+                return;
+            }
+            palette.push(*pixel);
+        }
+    }
+
+    // A single colour is a solid tile, otherwise the pixels are indexes
+    // in the palette, packed in bytes from the most significant bit and
+    // starting a new byte for every row
+    out.push(palette.len() as u8);
+    for colour in &palette {
+        format.put_compressed_pixel(out, *colour);
+    }
+    let bits = match palette.len() {
+        1 => return,
+        2 => 1,
+        3..=4 => 2,
+        _ => 4,
+    };
+    for row in pixels.chunks(width) {
+        let mut byte = 0u8;
+        let mut used = 0;
+        for pixel in row {
+            let index = palette.iter().position(|colour| colour == pixel).unwrap() as u8;
+            byte |= index << (8 - bits - used);
+            used += bits;
+            if used == 8 {
+                out.push(byte);
+                byte = 0;
+                used = 0;
+            }
+        }
+        if used > 0 {
+            out.push(byte);
+        }
+    }
+}
+
+/// Add the cursor pseudo-rectangle of `cursor`
+pub fn encode_cursor(update: &mut Update, cursor: &CursorImage, format: &PixelFormat) {
+    let (width, height) = (
+        usize::from(cursor.shape.width),
+        usize::from(cursor.shape.height),
+    );
+    let rect = Rect {
+        x: cursor.shape.hot_x.max(0) as usize,
+        y: cursor.shape.hot_y.max(0) as usize,
+        width,
+        height,
+    };
+    let data = update.rect(rect, ENCODING_CURSOR);
+
+    // BGRA pixels, then a bitmask of the opaque ones
+    let mask_row_len = width.div_ceil(8);
+    let mut mask = vec![0u8; mask_row_len * height];
+    for (index, bgra) in cursor.data.chunks_exact(4).take(width * height).enumerate() {
+        format.put_pixel(data, format.pixel(&[bgra[2], bgra[1], bgra[0]]));
+        if bgra[3] >= 0x80 {
+            let (y, x) = (index / width, index % width);
+            mask[y * mask_row_len + x / 8] |= 0x80 >> (x % 8);
+        }
+    }
+    data.extend_from_slice(&mask);
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{Decompress, FlushDecompress};
+
+    use super::*;
+
+    fn framebuffer(
+        width: usize,
+        height: usize,
+        pixel: impl Fn(usize, usize) -> [u8; 3],
+    ) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            rgb: (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .flat_map(|(x, y)| pixel(x, y))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_changes() {
+        let old = Framebuffer::new(200, 100);
+        assert!(old.changes(&old).is_empty());
+
+        let mut new = old.clone();
+        // Two adjacent tiles on the first row, one on the second
+        new.rgb[(10 * 200 + 100) * 3] = 1;
+        new.rgb[(10 * 200 + 150) * 3] = 1;
+        new.rgb[(70 * 200 + 199) * 3] = 1;
+        assert_eq!(
+            new.changes(&old),
+            [
+                Rect {
+                    x: 64,
+                    y: 0,
+                    width: 128,
+                    height: 64,
+                },
+                Rect {
+                    x: 192,
+                    y: 64,
+                    width: 8,
+                    height: 36,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_move() {
+        // Rows of distinct colours, scrolled up by 20 rows
+        let old = framebuffer(64, 128, |x, y| [y as u8, x as u8, 0]);
+        let new = framebuffer(64, 128, |x, y| [(y + 20) as u8, x as u8, 0]);
+        let area = bounding_rect(&new.changes(&old)).unwrap();
+
+        let (destination, source_y) = new.find_move(&old, area).unwrap();
+        assert_eq!(
+            destination,
+            Rect {
+                x: 0,
+                y: 0,
+                width: 64,
+                height: 108,
+            }
+        );
+        assert_eq!(source_y, 20);
+
+        let mut copy = old.clone();
+        copy.copy_rows(destination, source_y);
+        assert_eq!(copy.rgb[..64 * 108 * 3], new.rgb[..64 * 108 * 3]);
+        assert!(new.find_move(&new, area).is_none());
+    }
+
+    #[test]
+    fn test_zrle_tiles() {
+        let format = PixelFormat::DEFAULT;
+
+        let mut out = Vec::new();
+        zrle_tile(&mut out, &[0x112233; 4], 2, &format);
+        assert_eq!(out, [1, 0x33, 0x22, 0x11]);
+
+        // 2 colours, 1 bit per pixel and rows padded to a byte
+        let mut out = Vec::new();
+        zrle_tile(&mut out, &[0, 1, 1, 1, 0, 0], 3, &format);
+        assert_eq!(out, [2, 0, 0, 0, 1, 0, 0, 0b0110_0000, 0b1000_0000]);
+
+        let pixels: Vec<u32> = (0..17).collect();
+        let mut out = Vec::new();
+        zrle_tile(&mut out, &pixels, 17, &format);
+        assert_eq!(out[0], 0);
+        assert_eq!(out.len(), 1 + 17 * 3);
+    }
+
+    #[test]
+    fn test_zrle_stream() {
+        let framebuffer = framebuffer(100, 64, |x, y| [x as u8, y as u8, 0x80]);
+        let encodings = Encodings::new(&[ENCODING_ZRLE, ENCODING_RAW]);
+        assert!(encodings.zrle);
+        let mut encoder = Encoder::new();
+        let mut decompress = Decompress::new(true);
+
+        // Every rectangle decompresses to its tiles on its own
+        for _ in 0..2 {
+            let mut update = Update::new();
+            encoder
+                .encode_all(&mut update, &framebuffer, &PixelFormat::DEFAULT, encodings)
+                .unwrap();
+            let message = update.finish();
+            assert_eq!(message[2..4], [0, 1]);
+
+            let length = u32::from_be_bytes(message[16..20].try_into().unwrap()) as usize;
+            let mut tiles = Vec::with_capacity(1 << 16);
+            decompress
+                .decompress_vec(&message[20..20 + length], &mut tiles, FlushDecompress::Sync)
+                .unwrap();
+            // Two raw tiles of 64x64 and 36x64 pixels
+            assert_eq!(tiles.len(), 2 + 100 * 64 * 3);
+            assert_eq!(tiles[0], 0);
+            assert_eq!(tiles[1..4], [0x80, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn test_cursor() {
+        let cursor = CursorImage {
+            shape: devices::frame_buffer::CursorShapeInfo {
+                width: 2,
+                height: 1,
+                hot_x: 1,
+                hot_y: 0,
+                data_size: 8,
+                reserved: [0; 20],
+            },
+            data: vec![0x10, 0x20, 0x30, 0xff, 0, 0, 0, 0],
+        };
+        let mut update = Update::new();
+        encode_cursor(&mut update, &cursor, &PixelFormat::DEFAULT);
+        let message = update.finish();
+        assert_eq!(message[4..12], [0, 1, 0, 0, 0, 2, 0, 1]);
+        assert_eq!(message[12..16], ENCODING_CURSOR.to_be_bytes());
+        assert_eq!(
+            message[16..],
+            [0x10, 0x20, 0x30, 0, 0, 0, 0, 0, 0b1000_0000]
+        );
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Key Translation
+//!
+//! RFB clients name keys by their X11 keysym, which is the character a key
+//! produced on the client rather than the key itself. Characters are turned
+//! back into a key with the keyboard layout of the guest, the other keysyms
+//! with a fixed table.
+//!
+//! Clients supporting the QEMU extended key event also send the scancode of
+//! the key, which is used instead whenever it is known.
+
+use crate::input::KeyboardLayout;
+
+/// Set 1 scancode of Pause, which has no break code
+const KEY_PAUSE: u16 = 0xE11D;
+
+/// Keysym of the Unicode characters, plus their code point
+const KEYSYM_UNICODE: u32 = 0x0100_0000;
+
+/// Keysyms which aren't characters, with their Set 1 scancode
+#[rustfmt::skip]
+const SPECIAL_KEYS: &[(u32, u16)] = &[
+    (0xff08, 0x0E),   // BackSpace
+    (0xff09, 0x0F),   // Tab
+    (0xfe20, 0x0F),   // ISO_Left_Tab
+    (0xff0d, 0x1C),   // Return
+    (0xff13, KEY_PAUSE), // Pause
+    (0xff14, 0x46),   // Scroll_Lock
+    (0xff1b, 0x01),   // Escape
+    (0xff50, 0xE047), // Home
+    (0xff51, 0xE04B), // Left
+    (0xff52, 0xE048), // Up
+    (0xff53, 0xE04D), // Right
+    (0xff54, 0xE050), // Down
+    (0xff55, 0xE049), // Page_Up
+    (0xff56, 0xE051), // Page_Down
+    (0xff57, 0xE04F), // End
+    (0xff61, 0xE037), // Print
+    (0xff63, 0xE052), // Insert
+    (0xff67, 0xE05D), // Menu
+    (0xff7f, 0x45),   // Num_Lock
+    (0xff8d, 0xE01C), // KP_Enter
+    (0xff95, 0x47),   // KP_Home
+    (0xff96, 0x4B),   // KP_Left
+    (0xff97, 0x48),   // KP_Up
+    (0xff98, 0x4D),   // KP_Right
+    (0xff99, 0x50),   // KP_Down
+    (0xff9a, 0x49),   // KP_Page_Up
+    (0xff9b, 0x51),   // KP_Page_Down
+    (0xff9c, 0x4F),   // KP_End
+    (0xff9d, 0x4C),   // KP_Begin
+    (0xff9e, 0x52),   // KP_Insert
+    (0xff9f, 0x53),   // KP_Delete
+    (0xffaa, 0x37),   // KP_Multiply
+    (0xffab, 0x4E),   // KP_Add
+    (0xffad, 0x4A),   // KP_Subtract
+    (0xffae, 0x53),   // KP_Decimal
+    (0xffaf, 0xE035), // KP_Divide
+    (0xffb0, 0x52),   // KP_0
+    (0xffb1, 0x4F),   // KP_1
+    (0xffb2, 0x50),   // KP_2
+    (0xffb3, 0x51),   // KP_3
+    (0xffb4, 0x4B),   // KP_4
+    (0xffb5, 0x4C),   // KP_5
+    (0xffb6, 0x4D),   // KP_6
+    (0xffb7, 0x47),   // KP_7
+    (0xffb8, 0x48),   // KP_8
+    (0xffb9, 0x49),   // KP_9
+    (0xffbe, 0x3B),   // F1
+    (0xffbf, 0x3C),   // F2
+    (0xffc0, 0x3D),   // F3
+    (0xffc1, 0x3E),   // F4
+    (0xffc2, 0x3F),   // F5
+    (0xffc3, 0x40),   // F6
+    (0xffc4, 0x41),   // F7
+    (0xffc5, 0x42),   // F8
+    (0xffc6, 0x43),   // F9
+    (0xffc7, 0x44),   // F10
+    (0xffc8, 0x57),   // F11
+    (0xffc9, 0x58),   // F12
+    (0xffe1, 0x2A),   // Shift_L
+    (0xffe2, 0x36),   // Shift_R
+    (0xffe3, 0x1D),   // Control_L
+    (0xffe4, 0xE01D), // Control_R
+    (0xffe5, 0x3A),   // Caps_Lock
+    (0xffe7, 0xE05B), // Meta_L
+    (0xffe8, 0xE05C), // Meta_R
+    (0xffe9, 0x38),   // Alt_L
+    (0xffea, 0xE038), // Alt_R
+    (0xffeb, 0xE05B), // Super_L
+    (0xffec, 0xE05C), // Super_R
+    (0xfe03, 0xE038), // ISO_Level3_Shift (AltGr)
+    (0xffff, 0xE053), // Delete
+];
+
+/// Set 1 scancode of the key producing `keysym` with `layout`
+///
+/// The modifiers the character needs are the client's business, they are
+/// sent as keys of their own.
+pub fn keysym_to_code(keysym: u32, layout: KeyboardLayout) -> Option<u16> {
+    if let Some((_, code)) = SPECIAL_KEYS.iter().find(|(special, _)| *special == keysym) {
+        return Some(*code);
+    }
+
+    let c = match keysym {
+        // Latin-1 keysyms are their code point
+        0x20..=0x7e | 0xa0..=0xff => char::from_u32(keysym)?,
+        _ if keysym > KEYSYM_UNICODE => char::from_u32(keysym - KEYSYM_UNICODE)?,
+        _ => return None,
+    };
+
+    // Characters behind a dead key don't have a key of their own
+    match layout.keystrokes(c)?.as_slice() {
+        [keystroke] => Some(keystroke.code),
+        _ => None,
+    }
+}
+
+/// Set 1 scancode of a key number of the QEMU extended key event, which are
+/// the scancodes with the 0xE0 prefix folded into the most significant bit
+pub fn qnum_to_code(keycode: u32) -> Option<u16> {
+    match keycode {
+        0 => None,
+        // Pause is the only key with the 0xE1 prefix
+        0xc6 => Some(KEY_PAUSE),
+        0x01..=0x7f => Some(keycode as u16),
+        0x80..=0xff => Some(0xE000 | (keycode as u16 & 0x7f)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keysyms() {
+        assert_eq!(keysym_to_code(0xff0d, KeyboardLayout::Us), Some(0x1C));
+        assert_eq!(keysym_to_code(0xff52, KeyboardLayout::Us), Some(0xE048));
+        assert_eq!(keysym_to_code(0xffc9, KeyboardLayout::De), Some(0x58));
+
+        // Both cases are on the same key
+        assert_eq!(
+            keysym_to_code(u32::from('a'), KeyboardLayout::Us),
+            Some(0x1E)
+        );
+        assert_eq!(
+            keysym_to_code(u32::from('A'), KeyboardLayout::Us),
+            Some(0x1E)
+        );
+        assert_eq!(
+            keysym_to_code(u32::from('y'), KeyboardLayout::De),
+            Some(0x2C)
+        );
+        assert_eq!(
+            keysym_to_code(u32::from('q'), KeyboardLayout::Fr),
+            Some(0x1E)
+        );
+        assert_eq!(
+            keysym_to_code(u32::from('ü'), KeyboardLayout::De),
+            Some(0x1A)
+        );
+        assert_eq!(
+            keysym_to_code(KEYSYM_UNICODE + u32::from('€'), KeyboardLayout::De),
+            Some(0x12)
+        );
+
+        // Neither a key nor a character
+        assert_eq!(keysym_to_code(0xfe51, KeyboardLayout::Us), None);
+        assert_eq!(keysym_to_code(u32::from('ü'), KeyboardLayout::Us), None);
+    }
+
+    #[test]
+    fn test_qnums() {
+        assert_eq!(qnum_to_code(0x1e), Some(0x1E));
+        assert_eq!(qnum_to_code(0xc8), Some(0xE048));
+        assert_eq!(qnum_to_code(0xc6), Some(KEY_PAUSE));
+        assert_eq!(qnum_to_code(0), None);
+        assert_eq!(qnum_to_code(0x100), None);
+    }
+}
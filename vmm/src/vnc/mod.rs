@@ -0,0 +1,357 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Built-in VNC Server
+//!
+//! Serves the display of the guest over RFB 3.8, on a UNIX socket or a TCP
+//! address, to up to `max_clients` clients at once. The screen is the
+//! ivshmem frame buffer or a virtio-gpu scanout, the same frames
+//! `/vm.frame-capture.snapshot` returns, and the keys and pointer of the
+//! clients go through the input manager of the VM like any other injected
+//! input.
+//!
+//! The server has no authentication. It is meant to be reached through a
+//! UNIX socket or a loopback address, other TCP addresses are refused by
+//! the configuration unless `allow_remote=on` is given.
+
+mod encoding;
+mod keysym;
+mod protocol;
+mod session;
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+
+use log::{error, info, warn};
+use thiserror::Error;
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::device_manager::DeviceManager;
+use crate::frame_export::{CursorImage, Frame};
+use crate::input::InputManager;
+use crate::vm_config::VncConfig;
+
+use self::session::SessionConfig;
+
+const LISTENER_EVENT: u64 = 0;
+const KILL_EVENT: u64 = 1;
+
+#[derive(Debug, Error)]
+pub enum VncError {
+    #[error("Failed to listen on {0}")]
+    Bind(String, #[source] io::Error),
+    #[error("Failed to create the VNC server kill event")]
+    CreateKillEvent(#[source] io::Error),
+    #[error("Failed to spawn the VNC server thread")]
+    SpawnThread(#[source] io::Error),
+    #[error("Failed to wait for the VNC server events")]
+    Epoll(#[source] io::Error),
+}
+
+/// Display served to the clients.
+pub trait Screen: Send + Sync {
+    /// Number of the last frame, changing whenever the screen does
+    fn frame_number(&self) -> Option<u64>;
+    /// Copy of the last frame
+    fn frame(&self) -> Option<Frame>;
+    /// Image of the cursor, if the guest sets one
+    fn cursor(&self) -> Option<CursorImage>;
+}
+
+/// Scanout of the guest, read through the device manager.
+struct GuestScreen {
+    device_manager: Weak<Mutex<DeviceManager>>,
+    scanout: u32,
+}
+
+impl Screen for GuestScreen {
+    fn frame_number(&self) -> Option<u64> {
+        let device_manager = self.device_manager.upgrade()?;
+        device_manager.lock().unwrap().frame_number(self.scanout)
+    }
+
+    fn frame(&self) -> Option<Frame> {
+        let device_manager = self.device_manager.upgrade()?;
+        device_manager.lock().unwrap().frame_snapshot(self.scanout)
+    }
+
+    fn cursor(&self) -> Option<CursorImage> {
+        let device_manager = self.device_manager.upgrade()?;
+        device_manager.lock().unwrap().cursor_image(self.scanout)
+    }
+}
+
+enum Listener {
+    Unix(UnixListener, PathBuf),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    fn bind(config: &VncConfig) -> Result<Self, VncError> {
+        if let Some(path) = config.socket.as_ref() {
+            // A socket left behind by a previous run would fail the bind
+            if path.exists() {
+                std::fs::remove_file(path)
+                    .map_err(|e| VncError::Bind(path.display().to_string(), e))?;
+            }
+            let listener = UnixListener::bind(path)
+                .map_err(|e| VncError::Bind(path.display().to_string(), e))?;
+            Ok(Listener::Unix(listener, path.clone()))
+        } else {
+            // The configuration guarantees either of them is set
+            let addr = config.addr.unwrap();
+            let listener =
+                TcpListener::bind(addr).map_err(|e| VncError::Bind(addr.to_string(), e))?;
+            Ok(Listener::Tcp(listener))
+        }
+    }
+
+    /// Accept the next client, with a name for the logs
+    fn accept(&self) -> io::Result<(VncStream, String)> {
+        match self {
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept()?;
+                Ok((VncStream::Unix(stream), path.display().to_string()))
+            }
+            Listener::Tcp(listener) => {
+                let (stream, addr): (TcpStream, SocketAddr) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                Ok((VncStream::Tcp(stream), addr.to_string()))
+            }
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Connection of a client.
+enum VncStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl VncStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            VncStream::Unix(stream) => stream.try_clone().map(VncStream::Unix),
+            VncStream::Tcp(stream) => stream.try_clone().map(VncStream::Tcp),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            VncStream::Unix(stream) => stream.shutdown(Shutdown::Both),
+            VncStream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+impl Read for VncStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            VncStream::Unix(stream) => stream.read(buf),
+            VncStream::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for VncStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            VncStream::Unix(stream) => stream.write(buf),
+            VncStream::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            VncStream::Unix(stream) => stream.flush(),
+            VncStream::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+/// VNC server of a VM, accepting clients from a dedicated thread until it
+/// is dropped
+pub struct VncServer {
+    kill_evt: EventFd,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl VncServer {
+    /// Listen as described by `config`, serving the screen of
+    /// `device_manager` and injecting input through `input_manager`
+    pub fn new(
+        config: &VncConfig,
+        device_manager: &Arc<Mutex<DeviceManager>>,
+        input_manager: &Arc<Mutex<InputManager>>,
+    ) -> Result<Self, VncError> {
+        let listener = Listener::bind(config)?;
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(VncError::CreateKillEvent)?;
+
+        let mut worker = VncServerWorker {
+            listener,
+            kill_evt: kill_evt.try_clone().map_err(VncError::CreateKillEvent)?,
+            screen: Arc::new(GuestScreen {
+                device_manager: Arc::downgrade(device_manager),
+                scanout: config.scanout,
+            }),
+            input_manager: Arc::downgrade(input_manager),
+            session_config: SessionConfig {
+                fps: config.fps,
+                layout: config.layout,
+            },
+            max_clients: config.max_clients as usize,
+            clients: Vec::new(),
+        };
+        let endpoint = match (&config.socket, config.addr) {
+            (Some(path), _) => path.display().to_string(),
+            (None, addr) => addr.map(|addr| addr.to_string()).unwrap_or_default(),
+        };
+        let worker = thread::Builder::new()
+            .name("vnc-server".to_string())
+            .spawn(move || {
+                if let Err(e) = worker.run() {
+                    error!("VNC server thread failed: {e}");
+                }
+                worker.disconnect_all();
+            })
+            .map_err(VncError::SpawnThread)?;
+
+        info!("Serving display {} over VNC on {endpoint}", config.scanout);
+
+        Ok(VncServer {
+            kill_evt,
+            worker: Some(worker),
+        })
+    }
+}
+
+impl Drop for VncServer {
+    fn drop(&mut self) {
+        // Ignore the results because there is nothing we can do about it.
+        let _ = self.kill_evt.write(1);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+struct VncClient {
+    stream: VncStream,
+    session: thread::JoinHandle<()>,
+}
+
+struct VncServerWorker {
+    listener: Listener,
+    kill_evt: EventFd,
+    screen: Arc<dyn Screen>,
+    input_manager: Weak<Mutex<InputManager>>,
+    session_config: SessionConfig,
+    max_clients: usize,
+    clients: Vec<VncClient>,
+}
+
+impl VncServerWorker {
+    fn run(&mut self) -> Result<(), VncError> {
+        let epoll = Epoll::new().map_err(VncError::Epoll)?;
+        for (fd, data) in [
+            (self.listener.as_raw_fd(), LISTENER_EVENT),
+            (self.kill_evt.as_raw_fd(), KILL_EVENT),
+        ] {
+            epoll
+                .ctl(
+                    ControlOperation::Add,
+                    fd,
+                    EpollEvent::new(EventSet::IN, data),
+                )
+                .map_err(VncError::Epoll)?;
+        }
+
+        let mut events = [EpollEvent::default(); 2];
+        loop {
+            let count = match epoll.wait(-1, &mut events) {
+                Ok(count) => count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(VncError::Epoll(e)),
+            };
+
+            for event in &events[..count] {
+                match event.data() {
+                    KILL_EVENT => return Ok(()),
+                    LISTENER_EVENT => match self.listener.accept() {
+                        Ok((stream, peer)) => self.connect(stream, peer),
+                        Err(e) => warn!("Failed to accept a VNC client: {e}"),
+                    },
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn connect(&mut self, stream: VncStream, peer: String) {
+        // Forget about the clients which left
+        self.clients.retain(|client| !client.session.is_finished());
+        if self.clients.len() >= self.max_clients {
+            warn!(
+                "Refusing the VNC client {peer}: {} clients are connected already",
+                self.clients.len()
+            );
+            let _ = stream.shutdown();
+            return;
+        }
+
+        let (reader, writer) = match (stream.try_clone(), stream.try_clone()) {
+            (Ok(reader), Ok(writer)) => (reader, writer),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("Failed to set up the VNC client {peer}: {e}");
+                return;
+            }
+        };
+        let screen = self.screen.clone();
+        let input_manager = self.input_manager.clone();
+        let config = self.session_config;
+        let session = thread::Builder::new()
+            .name("vnc-client".to_string())
+            .spawn(move || {
+                info!("VNC client {peer} connected");
+                match session::run(reader, writer, screen, input_manager, config) {
+                    Ok(()) => info!("VNC client {peer} disconnected"),
+                    Err(e) => warn!("VNC client {peer} disconnected: {e}"),
+                }
+            });
+        match session {
+            Ok(session) => self.clients.push(VncClient { stream, session }),
+            Err(e) => warn!("Failed to spawn the VNC client thread: {e}"),
+        }
+    }
+
+    /// Close the connections and wait for the sessions to end
+    fn disconnect_all(&mut self) {
+        for client in self.clients.drain(..) {
+            let _ = client.stream.shutdown();
+            let _ = client.session.join();
+        }
+    }
+}
@@ -0,0 +1,493 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! RFB Messages
+//!
+//! Messages of the Remote Framebuffer protocol (RFC 6143), plus the QEMU
+//! extended key event through which most clients send the scancode of
+//! the keys next to their keysym.
+
+use std::io::{self, Read, Write};
+
+/// Versions of the protocol, as exchanged in the handshake
+pub const VERSION_3_3: &[u8; 12] = b"RFB 003.003\n";
+pub const VERSION_3_7: &[u8; 12] = b"RFB 003.007\n";
+pub const VERSION_3_8: &[u8; 12] = b"RFB 003.008\n";
+
+/// Security type without authentication
+pub const SECURITY_NONE: u8 = 1;
+pub const SECURITY_RESULT_OK: u32 = 0;
+pub const SECURITY_RESULT_FAILED: u32 = 1;
+
+// Client to server message types
+const SET_PIXEL_FORMAT: u8 = 0;
+const SET_ENCODINGS: u8 = 2;
+const FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const KEY_EVENT: u8 = 4;
+const POINTER_EVENT: u8 = 5;
+const CLIENT_CUT_TEXT: u8 = 6;
+const QEMU_CLIENT_MESSAGE: u8 = 255;
+const QEMU_EXTENDED_KEY_EVENT: u8 = 0;
+
+// Server to client message types
+const FRAMEBUFFER_UPDATE: u8 = 0;
+
+/// Encodings of the rectangles
+pub const ENCODING_RAW: i32 = 0;
+pub const ENCODING_COPY_RECT: i32 = 1;
+pub const ENCODING_ZRLE: i32 = 16;
+/// Pseudo-encodings, announcing features rather than pixels
+pub const ENCODING_CURSOR: i32 = -239;
+pub const ENCODING_DESKTOP_SIZE: i32 = -223;
+pub const ENCODING_QEMU_EXTENDED_KEY_EVENT: i32 = -258;
+
+/// Largest cut text read from the clients, it is discarded anyway
+const MAX_CUT_TEXT: u32 = 1 << 20;
+
+/// Layout of the pixels sent to a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub bits_per_pixel: u8,
+    pub depth: u8,
+    pub big_endian: bool,
+    pub true_colour: bool,
+    pub red_max: u16,
+    pub green_max: u16,
+    pub blue_max: u16,
+    pub red_shift: u8,
+    pub green_shift: u8,
+    pub blue_shift: u8,
+}
+
+/// Bytes of a ZRLE compressed pixel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CompressedPixel {
+    /// The whole pixel
+    Full,
+    /// The three least significant bytes
+    Low,
+    /// The three most significant bytes
+    High,
+}
+
+impl PixelFormat {
+    /// Format announced to the clients, 32-bit little-endian xRGB
+    pub const DEFAULT: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_colour: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    fn from_bytes(bytes: &[u8; 16]) -> Self {
+        PixelFormat {
+            bits_per_pixel: bytes[0],
+            depth: bytes[1],
+            big_endian: bytes[2] != 0,
+            true_colour: bytes[3] != 0,
+            red_max: u16::from_be_bytes([bytes[4], bytes[5]]),
+            green_max: u16::from_be_bytes([bytes[6], bytes[7]]),
+            blue_max: u16::from_be_bytes([bytes[8], bytes[9]]),
+            red_shift: bytes[10],
+            green_shift: bytes[11],
+            blue_shift: bytes[12],
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[0] = self.bits_per_pixel;
+        bytes[1] = self.depth;
+        bytes[2] = self.big_endian.into();
+        bytes[3] = self.true_colour.into();
+        bytes[4..6].copy_from_slice(&self.red_max.to_be_bytes());
+        bytes[6..8].copy_from_slice(&self.green_max.to_be_bytes());
+        bytes[8..10].copy_from_slice(&self.blue_max.to_be_bytes());
+        bytes[10] = self.red_shift;
+        bytes[11] = self.green_shift;
+        bytes[12] = self.blue_shift;
+        bytes
+    }
+
+    /// Check if pixels can be sent in this format, colour maps are not
+    /// supported
+    pub fn is_supported(&self) -> bool {
+        let fits = |max: u16, shift: u8| {
+            u32::from(shift) < u32::from(self.bits_per_pixel)
+                && (u64::from(max) << shift) < (1u64 << self.bits_per_pixel)
+        };
+        self.true_colour
+            && matches!(self.bits_per_pixel, 8 | 16 | 32)
+            && fits(self.red_max, self.red_shift)
+            && fits(self.green_max, self.green_shift)
+            && fits(self.blue_max, self.blue_shift)
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        usize::from(self.bits_per_pixel / 8)
+    }
+
+    /// Value of the pixel of an 8-bit RGB colour
+    pub fn pixel(&self, rgb: &[u8]) -> u32 {
+        let scale = |value: u8, max: u16| (u32::from(value) * u32::from(max) + 127) / 255;
+        (scale(rgb[0], self.red_max) << self.red_shift)
+            | (scale(rgb[1], self.green_max) << self.green_shift)
+            | (scale(rgb[2], self.blue_max) << self.blue_shift)
+    }
+
+    /// Append `pixel`, in the size and byte order of the format
+    pub fn put_pixel(&self, out: &mut Vec<u8>, pixel: u32) {
+        match (self.bits_per_pixel, self.big_endian) {
+            (8, _) => out.push(pixel as u8),
+            (16, false) => out.extend_from_slice(&(pixel as u16).to_le_bytes()),
+            (16, true) => out.extend_from_slice(&(pixel as u16).to_be_bytes()),
+            (_, false) => out.extend_from_slice(&pixel.to_le_bytes()),
+            (_, true) => out.extend_from_slice(&pixel.to_be_bytes()),
+        }
+    }
+
+    fn compressed_pixel(&self) -> CompressedPixel {
+        if self.bits_per_pixel != 32 || self.depth > 24 {
+            return CompressedPixel::Full;
+        }
+        let mask = (u32::from(self.red_max) << self.red_shift)
+            | (u32::from(self.green_max) << self.green_shift)
+            | (u32::from(self.blue_max) << self.blue_shift);
+        if mask & 0xff00_0000 == 0 {
+            CompressedPixel::Low
+        } else if mask & 0xff == 0 {
+            CompressedPixel::High
+        } else {
+            CompressedPixel::Full
+        }
+    }
+
+    /// Append `pixel` in the ZRLE format, which drops the unused byte of
+    /// the 32-bit pixels
+    pub fn put_compressed_pixel(&self, out: &mut Vec<u8>, pixel: u32) {
+        match (self.compressed_pixel(), self.big_endian) {
+            (CompressedPixel::Full, _) => self.put_pixel(out, pixel),
+            (CompressedPixel::Low, false) => out.extend_from_slice(&pixel.to_le_bytes()[..3]),
+            (CompressedPixel::Low, true) => out.extend_from_slice(&pixel.to_be_bytes()[1..]),
+            (CompressedPixel::High, false) => out.extend_from_slice(&pixel.to_le_bytes()[1..]),
+            (CompressedPixel::High, true) => out.extend_from_slice(&pixel.to_be_bytes()[..3]),
+        }
+    }
+}
+
+/// Message sent by a client once the session is initialized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientMessage {
+    SetPixelFormat(PixelFormat),
+    SetEncodings(Vec<i32>),
+    FramebufferUpdateRequest {
+        incremental: bool,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    },
+    KeyEvent {
+        down: bool,
+        keysym: u32,
+    },
+    PointerEvent {
+        buttons: u8,
+        x: u16,
+        y: u16,
+    },
+    ClientCutText,
+    QemuExtendedKeyEvent {
+        down: bool,
+        keysym: u32,
+        keycode: u32,
+    },
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    Ok(u16::from_be_bytes(read_array(reader)?))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    Ok(u32::from_be_bytes(read_array(reader)?))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl ClientMessage {
+    /// Read the next message of the client
+    pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let [message_type] = read_array(reader)?;
+        let message = match message_type {
+            SET_PIXEL_FORMAT => {
+                let bytes: [u8; 19] = read_array(reader)?;
+                ClientMessage::SetPixelFormat(PixelFormat::from_bytes(
+                    bytes[3..].try_into().unwrap(),
+                ))
+            }
+            SET_ENCODINGS => {
+                read_array::<1>(reader)?;
+                let count = read_u16(reader)?;
+                let encodings = (0..count)
+                    .map(|_| read_u32(reader).map(|encoding| encoding as i32))
+                    .collect::<io::Result<_>>()?;
+                ClientMessage::SetEncodings(encodings)
+            }
+            FRAMEBUFFER_UPDATE_REQUEST => {
+                let [incremental] = read_array(reader)?;
+                ClientMessage::FramebufferUpdateRequest {
+                    incremental: incremental != 0,
+                    x: read_u16(reader)?,
+                    y: read_u16(reader)?,
+                    width: read_u16(reader)?,
+                    height: read_u16(reader)?,
+                }
+            }
+            KEY_EVENT => {
+                let [down, _, _] = read_array(reader)?;
+                ClientMessage::KeyEvent {
+                    down: down != 0,
+                    keysym: read_u32(reader)?,
+                }
+            }
+            POINTER_EVENT => {
+                let [buttons] = read_array(reader)?;
+                ClientMessage::PointerEvent {
+                    buttons,
+                    x: read_u16(reader)?,
+                    y: read_u16(reader)?,
+                }
+            }
+            CLIENT_CUT_TEXT => {
+                read_array::<3>(reader)?;
+                let length = read_u32(reader)?;
+                if length > MAX_CUT_TEXT {
+                    return Err(invalid_data(format!("Cut text too long: {length}")));
+                }
+                io::copy(&mut reader.take(length.into()), &mut io::sink())?;
+                ClientMessage::ClientCutText
+            }
+            QEMU_CLIENT_MESSAGE => {
+                let [subtype] = read_array(reader)?;
+                if subtype != QEMU_EXTENDED_KEY_EVENT {
+                    return Err(invalid_data(format!(
+                        "Unsupported QEMU client message {subtype}"
+                    )));
+                }
+                ClientMessage::QemuExtendedKeyEvent {
+                    down: read_u16(reader)? != 0,
+                    keysym: read_u32(reader)?,
+                    keycode: read_u32(reader)?,
+                }
+            }
+            _ => {
+                return Err(invalid_data(format!(
+                    "Unsupported client message {message_type}"
+                )));
+            }
+        };
+
+        Ok(message)
+    }
+}
+
+/// Write the ServerInit message, describing the framebuffer
+pub fn write_server_init(
+    writer: &mut impl Write,
+    width: u16,
+    height: u16,
+    format: PixelFormat,
+    name: &str,
+) -> io::Result<()> {
+    let mut message = Vec::with_capacity(24 + name.len());
+    message.extend_from_slice(&width.to_be_bytes());
+    message.extend_from_slice(&height.to_be_bytes());
+    message.extend_from_slice(&format.to_bytes());
+    message.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    message.extend_from_slice(name.as_bytes());
+    writer.write_all(&message)
+}
+
+/// Write the reason of a handshake failure
+pub fn write_reason(writer: &mut impl Write, reason: &str) -> io::Result<()> {
+    writer.write_all(&(reason.len() as u32).to_be_bytes())?;
+    writer.write_all(reason.as_bytes())
+}
+
+/// Rectangle of the framebuffer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// FramebufferUpdate message being built.
+pub struct Update {
+    data: Vec<u8>,
+    rects: u16,
+}
+
+impl Update {
+    pub fn new() -> Self {
+        Update {
+            data: vec![FRAMEBUFFER_UPDATE, 0, 0, 0],
+            rects: 0,
+        }
+    }
+
+    /// Add a rectangle, the caller appending its data to the returned
+    /// buffer
+    pub fn rect(&mut self, rect: Rect, encoding: i32) -> &mut Vec<u8> {
+        for value in [rect.x, rect.y, rect.width, rect.height] {
+            self.data.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        self.data.extend_from_slice(&encoding.to_be_bytes());
+        self.rects += 1;
+        &mut self.data
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rects == 0
+    }
+
+    /// The message, ready to be sent
+    pub fn finish(mut self) -> Vec<u8> {
+        self.data[2..4].copy_from_slice(&self.rects.to_be_bytes());
+        self.data
+    }
+}
+
+impl Default for Update {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_messages() {
+        let mut stream: &[u8] = &[
+            2, 0, 0, 2, 0, 0, 0, 16, 0xff, 0xff, 0xff, 0x11, // SetEncodings
+            3, 1, 0, 0, 0, 0, 0x02, 0x80, 0x01, 0xe0, // FramebufferUpdateRequest
+            4, 1, 0, 0, 0, 0, 0xff, 0x0d, // KeyEvent
+            5, 0x01, 0x00, 0x0a, 0x00, 0x14, // PointerEvent
+            6, 0, 0, 0, 0, 0, 0, 2, b'h', b'i', // ClientCutText
+            255, 0, 0, 0, 0, 0, 0, 0x61, 0, 0, 0, 0x1e, // QEMU extended key
+        ];
+
+        assert_eq!(
+            ClientMessage::read(&mut stream).unwrap(),
+            ClientMessage::SetEncodings(vec![ENCODING_ZRLE, ENCODING_CURSOR])
+        );
+        assert_eq!(
+            ClientMessage::read(&mut stream).unwrap(),
+            ClientMessage::FramebufferUpdateRequest {
+                incremental: true,
+                x: 0,
+                y: 0,
+                width: 640,
+                height: 480,
+            }
+        );
+        assert_eq!(
+            ClientMessage::read(&mut stream).unwrap(),
+            ClientMessage::KeyEvent {
+                down: true,
+                keysym: 0xff0d,
+            }
+        );
+        assert_eq!(
+            ClientMessage::read(&mut stream).unwrap(),
+            ClientMessage::PointerEvent {
+                buttons: 1,
+                x: 10,
+                y: 20,
+            }
+        );
+        assert_eq!(
+            ClientMessage::read(&mut stream).unwrap(),
+            ClientMessage::ClientCutText
+        );
+        assert_eq!(
+            ClientMessage::read(&mut stream).unwrap(),
+            ClientMessage::QemuExtendedKeyEvent {
+                down: false,
+                keysym: 0x61,
+                keycode: 0x1e,
+            }
+        );
+        assert!(stream.is_empty());
+
+        let mut stream: &[u8] = &[7, 0];
+        ClientMessage::read(&mut stream).unwrap_err();
+    }
+
+    #[test]
+    fn test_pixel_format() {
+        let format = PixelFormat::DEFAULT;
+        assert!(format.is_supported());
+        assert_eq!(PixelFormat::from_bytes(&format.to_bytes()), format);
+        assert_eq!(format.pixel(&[0x12, 0x34, 0x56]), 0x123456);
+
+        let mut out = Vec::new();
+        format.put_pixel(&mut out, 0x123456);
+        format.put_compressed_pixel(&mut out, 0x123456);
+        assert_eq!(out, [0x56, 0x34, 0x12, 0, 0x56, 0x34, 0x12]);
+
+        // Big-endian RGBx, the unused byte being the least significant
+        let format = PixelFormat {
+            big_endian: true,
+            red_shift: 24,
+            green_shift: 16,
+            blue_shift: 8,
+            ..PixelFormat::DEFAULT
+        };
+        let mut out = Vec::new();
+        format.put_compressed_pixel(&mut out, format.pixel(&[0x12, 0x34, 0x56]));
+        assert_eq!(out, [0x12, 0x34, 0x56]);
+
+        // RGB565
+        let format = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+            ..PixelFormat::DEFAULT
+        };
+        assert!(format.is_supported());
+        let mut out = Vec::new();
+        format.put_compressed_pixel(&mut out, format.pixel(&[0xff, 0, 0xff]));
+        assert_eq!(out, 0xf81fu16.to_le_bytes());
+
+        let colour_map = PixelFormat {
+            bits_per_pixel: 8,
+            depth: 8,
+            true_colour: false,
+            ..PixelFormat::DEFAULT
+        };
+        assert!(!colour_map.is_supported());
+    }
+}
@@ -0,0 +1,849 @@
+// Copyright 2024 Cloud Hypervisor Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! VNC Client Sessions
+//!
+//! A session reads the messages of its client from the thread it runs on,
+//! injecting the key and pointer events right away, while a thread of its
+//! own sends the framebuffer updates the client requested, at most at the
+//! frame rate of the server.
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use super::Screen;
+use super::encoding::{Encoder, Encodings, Framebuffer, encode_cursor};
+use super::keysym::{keysym_to_code, qnum_to_code};
+use super::protocol::{
+    ClientMessage, ENCODING_DESKTOP_SIZE, ENCODING_QEMU_EXTENDED_KEY_EVENT, PixelFormat, Rect,
+    SECURITY_NONE, SECURITY_RESULT_FAILED, SECURITY_RESULT_OK, Update, VERSION_3_3, VERSION_3_7,
+    VERSION_3_8, write_reason, write_server_init,
+};
+use crate::input::{
+    InputEvent, InputManager, KeyboardAction, KeyboardLayout, MouseAction, MouseButton,
+    MouseButtons, MouseEvent,
+};
+
+/// Size announced while the guest didn't show anything yet
+const DEFAULT_WIDTH: usize = 640;
+const DEFAULT_HEIGHT: usize = 480;
+
+/// Name of the desktop shown by the clients
+const DESKTOP_NAME: &str = "Cloud Hypervisor";
+
+/// Pointer buttons, by bit of the PointerEvent button mask
+const POINTER_BUTTONS: [(u8, MouseButton); 3] = [
+    (1 << 0, MouseButton::Left),
+    (1 << 1, MouseButton::Middle),
+    (1 << 2, MouseButton::Right),
+];
+const POINTER_WHEEL_UP: u8 = 1 << 3;
+const POINTER_WHEEL_DOWN: u8 = 1 << 4;
+
+/// Settings shared by the sessions of a server.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionConfig {
+    /// Highest number of updates sent per second
+    pub fps: u32,
+    /// Layout the keysyms are translated with
+    pub layout: KeyboardLayout,
+}
+
+/// What the client asked for, shared with the update thread
+struct State {
+    format: PixelFormat,
+    encodings: Encodings,
+    /// Pending update request, whether it is incremental
+    request: Option<bool>,
+    /// Acknowledge the QEMU extended key events with the next update
+    ack_extended_key: bool,
+    /// Send the cursor with the next update, even if unchanged
+    resend_cursor: bool,
+    /// Size of the framebuffer of the client
+    size: (usize, usize),
+    stopped: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut State)) {
+        f(&mut self.lock());
+        self.changed.notify_one();
+    }
+}
+
+/// Serve a client connected through `reader` and `writer` until it
+/// disconnects or the stream is shut down
+pub fn run(
+    mut reader: impl Read,
+    mut writer: impl Write + Send + 'static,
+    screen: Arc<dyn Screen>,
+    input_manager: Weak<Mutex<InputManager>>,
+    config: SessionConfig,
+) -> io::Result<()> {
+    handshake(&mut reader, &mut writer)?;
+
+    let frame_number = screen.frame_number();
+    let framebuffer = screen
+        .frame()
+        .and_then(|frame| {
+            let rgb = frame.to_rgb().ok()?;
+            Some(Framebuffer {
+                width: frame.width as usize,
+                height: frame.height as usize,
+                rgb,
+            })
+        })
+        .filter(|framebuffer| {
+            (1..=usize::from(u16::MAX)).contains(&framebuffer.width)
+                && (1..=usize::from(u16::MAX)).contains(&framebuffer.height)
+        })
+        .unwrap_or_else(|| Framebuffer::new(DEFAULT_WIDTH, DEFAULT_HEIGHT));
+    write_server_init(
+        &mut writer,
+        framebuffer.width as u16,
+        framebuffer.height as u16,
+        PixelFormat::DEFAULT,
+        DESKTOP_NAME,
+    )?;
+
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            format: PixelFormat::DEFAULT,
+            encodings: Encodings::default(),
+            request: None,
+            ack_extended_key: false,
+            resend_cursor: false,
+            size: (framebuffer.width, framebuffer.height),
+            stopped: false,
+        }),
+        changed: Condvar::new(),
+    });
+    let mut updater = Updater {
+        writer,
+        screen,
+        shared: shared.clone(),
+        interval: Duration::from_secs(1) / config.fps.max(1),
+        encoder: Encoder::new(),
+        frame_number,
+        framebuffer,
+        client: None,
+        cursor: None,
+    };
+    let updater = thread::Builder::new()
+        .name("vnc-update".to_string())
+        .spawn(move || {
+            let result = updater.run();
+            updater.shared.update(|state| state.stopped = true);
+            result
+        })?;
+
+    let mut input = InputHandler {
+        input_manager,
+        layout: config.layout,
+        shared: shared.clone(),
+        pressed_keys: Vec::new(),
+        buttons: 0,
+        position: None,
+        screen_size: None,
+    };
+    let result = loop {
+        if shared.lock().stopped {
+            break Ok(());
+        }
+        match ClientMessage::read(&mut reader) {
+            Ok(message) => {
+                if let Err(e) = handle_message(message, &shared, &mut input) {
+                    break Err(e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+
+    input.release_all();
+    shared.update(|state| state.stopped = true);
+    let update_result = updater
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("VNC update thread panicked")));
+    result.and(update_result)
+}
+
+/// Agree on the version and the security type with the client
+fn handshake(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(VERSION_3_8)?;
+    let mut version = [0u8; 12];
+    reader.read_exact(&mut version)?;
+
+    // Later versions are handled as 3.8, intermediate ones as the previous
+    // official version
+    let minor = std::str::from_utf8(&version)
+        .ok()
+        .and_then(|version| version.strip_prefix("RFB 003."))
+        .and_then(|minor| minor.strip_suffix('\n'))
+        .and_then(|minor| minor.parse::<u32>().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Unsupported RFB version {:?}",
+                    String::from_utf8_lossy(&version)
+                ),
+            )
+        })?;
+    let version = match minor {
+        0..=6 => VERSION_3_3,
+        7 => VERSION_3_7,
+        _ => VERSION_3_8,
+    };
+
+    if version == VERSION_3_3 {
+        writer.write_all(&u32::from(SECURITY_NONE).to_be_bytes())?;
+    } else {
+        writer.write_all(&[1, SECURITY_NONE])?;
+        let mut security = [0u8];
+        reader.read_exact(&mut security)?;
+        if security[0] != SECURITY_NONE {
+            if version == VERSION_3_8 {
+                writer.write_all(&SECURITY_RESULT_FAILED.to_be_bytes())?;
+                write_reason(writer, "Unsupported security type")?;
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported security type {}", security[0]),
+            ));
+        }
+        if version == VERSION_3_8 {
+            writer.write_all(&SECURITY_RESULT_OK.to_be_bytes())?;
+        }
+    }
+
+    // ClientInit, every client shares the display anyway
+    let mut shared = [0u8];
+    reader.read_exact(&mut shared)
+}
+
+fn handle_message(
+    message: ClientMessage,
+    shared: &Shared,
+    input: &mut InputHandler,
+) -> io::Result<()> {
+    match message {
+        ClientMessage::SetPixelFormat(format) => {
+            if !format.is_supported() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unsupported pixel format {format:?}"),
+                ));
+            }
+            shared.update(|state| {
+                state.format = format;
+                state.resend_cursor = true;
+            });
+        }
+        ClientMessage::SetEncodings(encodings) => {
+            let encodings = Encodings::new(&encodings);
+            shared.update(|state| {
+                state.ack_extended_key |=
+                    encodings.extended_key_event && !state.encodings.extended_key_event;
+                state.resend_cursor |= encodings.cursor;
+                state.encodings = encodings;
+            });
+        }
+        ClientMessage::FramebufferUpdateRequest { incremental, .. } => {
+            // The whole screen is updated whatever the region asked for
+            shared
+                .update(|state| state.request = Some(state.request.unwrap_or(true) && incremental));
+        }
+        ClientMessage::KeyEvent { down, keysym } => {
+            if let Some(code) = keysym_to_code(keysym, input.layout) {
+                input.key(down, code);
+            } else {
+                debug!("Ignoring VNC key event of unknown keysym {keysym:#x}");
+            }
+        }
+        ClientMessage::QemuExtendedKeyEvent {
+            down,
+            keysym,
+            keycode,
+        } => {
+            if let Some(code) =
+                qnum_to_code(keycode).or_else(|| keysym_to_code(keysym, input.layout))
+            {
+                input.key(down, code);
+            } else {
+                debug!("Ignoring VNC key event of unknown key {keycode:#x}");
+            }
+        }
+        ClientMessage::PointerEvent { buttons, x, y } => {
+            input.pointer(buttons, usize::from(x), usize::from(y));
+        }
+        ClientMessage::ClientCutText => {}
+    }
+
+    Ok(())
+}
+
+/// Sends the framebuffer updates of a session
+struct Updater<W> {
+    writer: W,
+    screen: Arc<dyn Screen>,
+    shared: Arc<Shared>,
+    interval: Duration,
+    encoder: Encoder,
+    /// Number of the frame in `framebuffer`
+    frame_number: Option<u64>,
+    /// The screen, at the size of the framebuffer of the client
+    framebuffer: Framebuffer,
+    /// What the client shows, `None` until it got the whole screen
+    client: Option<Framebuffer>,
+    /// Size, hotspot and pixels of the cursor the client shows
+    cursor: Option<(u16, u16, i16, i16, Vec<u8>)>,
+}
+
+/// Update to send, with what the client asked for at the time
+struct UpdateRequest {
+    format: PixelFormat,
+    encodings: Encodings,
+    incremental: Option<bool>,
+    ack_extended_key: bool,
+    resend_cursor: bool,
+}
+
+impl<W: Write> Updater<W> {
+    fn run(&mut self) -> io::Result<()> {
+        let mut next_update = Instant::now();
+        loop {
+            let request = {
+                let mut state = self.shared.lock();
+                loop {
+                    if state.stopped {
+                        return Ok(());
+                    }
+                    let ready = state.request.is_some() || state.ack_extended_key;
+                    let now = Instant::now();
+                    if ready && now >= next_update {
+                        break;
+                    }
+                    state = if ready {
+                        self.shared
+                            .changed
+                            .wait_timeout(state, next_update - now)
+                            .unwrap()
+                            .0
+                    } else {
+                        self.shared.changed.wait(state).unwrap()
+                    };
+                }
+                UpdateRequest {
+                    format: state.format,
+                    encodings: state.encodings,
+                    incremental: state.request.take(),
+                    ack_extended_key: std::mem::take(&mut state.ack_extended_key),
+                    resend_cursor: std::mem::take(&mut state.resend_cursor),
+                }
+            };
+            next_update = Instant::now() + self.interval;
+
+            let update = self.update(&request)?;
+            if update.is_empty() {
+                // Nothing changed yet, the request stays pending
+                if let Some(incremental) = request.incremental {
+                    self.shared.update(|state| {
+                        state.request = Some(state.request.unwrap_or(true) && incremental);
+                        state.resend_cursor |= request.resend_cursor;
+                    });
+                }
+                continue;
+            }
+            self.writer.write_all(&update.finish())?;
+        }
+    }
+
+    fn update(&mut self, request: &UpdateRequest) -> io::Result<Update> {
+        let mut update = Update::new();
+        if request.ack_extended_key {
+            update.rect(Rect::default(), ENCODING_QEMU_EXTENDED_KEY_EVENT);
+        }
+        let Some(incremental) = request.incremental else {
+            return Ok(update);
+        };
+
+        let changed = self.refresh_screen();
+        let (width, height) = (self.framebuffer.width, self.framebuffer.height);
+        if (width, height) != self.shared.lock().size {
+            // The client requests the whole screen once resized
+            update.rect(
+                Rect {
+                    width,
+                    height,
+                    ..Default::default()
+                },
+                ENCODING_DESKTOP_SIZE,
+            );
+            self.shared.lock().size = (width, height);
+            self.client = None;
+            return Ok(update);
+        }
+
+        if request.encodings.cursor {
+            self.update_cursor(&mut update, &request.format, request.resend_cursor);
+        }
+
+        match self.client.as_mut() {
+            Some(client) if incremental => {
+                if changed {
+                    self.encoder.encode_changes(
+                        &mut update,
+                        &self.framebuffer,
+                        client,
+                        &request.format,
+                        request.encodings,
+                    )?;
+                }
+            }
+            _ => {
+                self.encoder.encode_all(
+                    &mut update,
+                    &self.framebuffer,
+                    &request.format,
+                    request.encodings,
+                )?;
+                self.client = Some(self.framebuffer.clone());
+            }
+        }
+
+        Ok(update)
+    }
+
+    /// Copy the screen if the guest showed a new frame, returning whether
+    /// it did
+    fn refresh_screen(&mut self) -> bool {
+        let frame_number = self.screen.frame_number();
+        if frame_number.is_none() || frame_number == self.frame_number {
+            return false;
+        }
+        let Some(frame) = self.screen.frame() else {
+            return false;
+        };
+        self.frame_number = frame_number;
+        let rgb = match frame.to_rgb() {
+            Ok(rgb) => rgb,
+            Err(e) => {
+                warn!("Cannot show frame {} over VNC: {e}", frame.frame_number);
+                return false;
+            }
+        };
+
+        let (width, height) = (frame.width as usize, frame.height as usize);
+        let resizable = self.shared.lock().encodings.desktop_size
+            && (1..=usize::from(u16::MAX)).contains(&width)
+            && (1..=usize::from(u16::MAX)).contains(&height);
+        if resizable {
+            self.framebuffer = Framebuffer { width, height, rgb };
+        } else {
+            // Clients which can't be resized see the top left corner
+            self.framebuffer.fit(width, height, &rgb);
+        }
+        true
+    }
+
+    fn update_cursor(&mut self, update: &mut Update, format: &PixelFormat, resend: bool) {
+        let Some(cursor) = self.screen.cursor() else {
+            return;
+        };
+        let shape = &cursor.shape;
+        if cursor.data.len() < usize::from(shape.width) * usize::from(shape.height) * 4 {
+            return;
+        }
+
+        let key = (
+            shape.width,
+            shape.height,
+            shape.hot_x,
+            shape.hot_y,
+            cursor.data.clone(),
+        );
+        if resend || self.cursor.as_ref() != Some(&key) {
+            encode_cursor(update, &cursor, format);
+            self.cursor = Some(key);
+        }
+    }
+}
+
+/// Injects the key and pointer events of a session
+struct InputHandler {
+    input_manager: Weak<Mutex<InputManager>>,
+    layout: KeyboardLayout,
+    shared: Arc<Shared>,
+    /// Keys pressed by the client, released when it disconnects
+    pressed_keys: Vec<u16>,
+    /// Button mask of the last pointer event
+    buttons: u8,
+    /// Position of the last pointer event
+    position: Option<(usize, usize)>,
+    /// Screen size given to the input manager
+    screen_size: Option<(usize, usize)>,
+}
+
+impl InputHandler {
+    fn inject(&self, events: &[InputEvent]) {
+        let Some(input_manager) = self.input_manager.upgrade() else {
+            return;
+        };
+        let mut input_manager = input_manager.lock().unwrap();
+        for event in events {
+            if let Err(e) = input_manager.inject(event) {
+                debug!("Failed to inject VNC input event: {e}");
+            }
+        }
+    }
+
+    fn key(&mut self, down: bool, code: u16) {
+        if down {
+            if !self.pressed_keys.contains(&code) {
+                self.pressed_keys.push(code);
+            }
+        } else {
+            self.pressed_keys.retain(|pressed| *pressed != code);
+        }
+
+        let action = if down {
+            KeyboardAction::Press
+        } else {
+            KeyboardAction::Release
+        };
+        self.inject(&[InputEvent::keyboard(action, code)]);
+    }
+
+    fn pointer(&mut self, buttons: u8, x: usize, y: usize) {
+        let mut events = Vec::new();
+
+        let absolute = self.input_manager.upgrade().is_some_and(|input_manager| {
+            let mut input_manager = input_manager.lock().unwrap();
+            let size = self.shared.lock().size;
+            if self.screen_size != Some(size) {
+                input_manager.set_screen_size(size.0 as u32, size.1 as u32);
+                self.screen_size = Some(size);
+            }
+            input_manager
+                .capabilities()
+                .is_some_and(|capabilities| capabilities.supports_absolute_mouse)
+        });
+        if absolute {
+            if self.position != Some((x, y)) {
+                events.push(InputEvent::Mouse(MouseEvent {
+                    action: MouseAction::MoveAbsolute,
+                    x: x as i32,
+                    y: y as i32,
+                    z: 0,
+                    button: None,
+                    buttons: MouseButtons::default(),
+                }));
+            }
+        } else if let Some((last_x, last_y)) = self.position
+            && (last_x, last_y) != (x, y)
+        {
+            events.push(InputEvent::mouse_move(
+                x as i32 - last_x as i32,
+                y as i32 - last_y as i32,
+            ));
+        }
+        self.position = Some((x, y));
+
+        for (bit, button) in POINTER_BUTTONS {
+            if (buttons ^ self.buttons) & bit != 0 {
+                events.push(InputEvent::mouse_button(button, buttons & bit != 0));
+            }
+        }
+        // The wheel is a press and release of its buttons per notch
+        let pressed = buttons & !self.buttons;
+        if pressed & POINTER_WHEEL_UP != 0 {
+            events.push(InputEvent::mouse_scroll(1));
+        }
+        if pressed & POINTER_WHEEL_DOWN != 0 {
+            events.push(InputEvent::mouse_scroll(-1));
+        }
+        self.buttons = buttons;
+
+        self.inject(&events);
+    }
+
+    /// Release the keys and buttons the client still holds
+    fn release_all(&mut self) {
+        let mut events: Vec<InputEvent> = self
+            .pressed_keys
+            .drain(..)
+            .rev()
+            .map(|code| InputEvent::keyboard(KeyboardAction::Release, code))
+            .collect();
+        for (bit, button) in POINTER_BUTTONS {
+            if self.buttons & bit != 0 {
+                events.push(InputEvent::mouse_button(button, false));
+            }
+        }
+        self.buttons = 0;
+        self.inject(&events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::frame_export::{CursorImage, Frame};
+    use crate::input::ScriptEvent;
+
+    /// Screen of 4x2 pixels whose frames are filled with one colour
+    struct TestScreen {
+        frame_number: AtomicU64,
+        colour: Mutex<[u8; 4]>,
+    }
+
+    impl TestScreen {
+        fn show(&self, bgra: [u8; 4]) {
+            *self.colour.lock().unwrap() = bgra;
+            self.frame_number.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl Screen for TestScreen {
+        fn frame_number(&self) -> Option<u64> {
+            Some(self.frame_number.load(Ordering::SeqCst))
+        }
+
+        fn frame(&self) -> Option<Frame> {
+            Some(Frame {
+                width: 4,
+                height: 2,
+                format: "BGRA32".to_string(),
+                stride: 16,
+                data: self.colour.lock().unwrap().repeat(8),
+                frame_number: self.frame_number.load(Ordering::SeqCst),
+                ..Default::default()
+            })
+        }
+
+        fn cursor(&self) -> Option<CursorImage> {
+            None
+        }
+    }
+
+    struct Client {
+        stream: UnixStream,
+        session: thread::JoinHandle<io::Result<()>>,
+    }
+
+    impl Client {
+        fn connect(screen: Arc<TestScreen>, input_manager: &Arc<Mutex<InputManager>>) -> Self {
+            let (stream, server) = UnixStream::pair().unwrap();
+            let input_manager = Arc::downgrade(input_manager);
+            let session = thread::spawn(move || {
+                let writer = server.try_clone().unwrap();
+                let config = SessionConfig {
+                    fps: 60,
+                    layout: KeyboardLayout::De,
+                };
+                run(server, writer, screen, input_manager, config)
+            });
+            Client { stream, session }
+        }
+
+        fn read(&mut self, length: usize) -> Vec<u8> {
+            let mut data = vec![0u8; length];
+            self.stream.read_exact(&mut data).unwrap();
+            data
+        }
+
+        fn write(&mut self, data: &[u8]) {
+            self.stream.write_all(data).unwrap();
+        }
+
+        /// Handshake of a 3.8 client, returning the ServerInit message
+        fn init(&mut self) -> Vec<u8> {
+            assert_eq!(self.read(12), VERSION_3_8);
+            self.write(VERSION_3_8);
+            assert_eq!(self.read(2), [1, SECURITY_NONE]);
+            self.write(&[SECURITY_NONE]);
+            assert_eq!(self.read(4), SECURITY_RESULT_OK.to_be_bytes());
+            self.write(&[1]);
+
+            let mut server_init = self.read(24);
+            let name_length = u32::from_be_bytes(server_init[20..24].try_into().unwrap());
+            server_init.extend(self.read(name_length as usize));
+            server_init
+        }
+
+        fn request_update(&mut self, incremental: bool) {
+            self.write(&[3, incremental.into(), 0, 0, 0, 0, 0, 4, 0, 2]);
+        }
+
+        /// Header of the next FramebufferUpdate, with the header of its
+        /// first rectangle
+        fn read_update(&mut self) -> (u16, Vec<u8>) {
+            let header = self.read(4);
+            assert_eq!(header[0], 0);
+            let rects = u16::from_be_bytes([header[2], header[3]]);
+            (rects, self.read(12))
+        }
+
+        fn disconnect(self) -> io::Result<()> {
+            self.stream.shutdown(std::net::Shutdown::Both).unwrap();
+            self.session.join().unwrap()
+        }
+    }
+
+    fn input_manager() -> Arc<Mutex<InputManager>> {
+        use std::sync::atomic::AtomicBool;
+
+        use devices::legacy::I8042Device;
+        use vmm_sys_util::eventfd::EventFd;
+
+        let mut input_manager = InputManager::default_config();
+        input_manager.init_ps2_backend(Arc::new(Mutex::new(I8042Device::new(
+            EventFd::new(0).unwrap(),
+            Arc::new(AtomicBool::new(false)),
+        ))));
+        input_manager.start_recording();
+        Arc::new(Mutex::new(input_manager))
+    }
+
+    fn recorded_events(input_manager: &Arc<Mutex<InputManager>>) -> Vec<InputEvent> {
+        let script = input_manager.lock().unwrap().stop_recording().unwrap();
+        script
+            .events
+            .into_iter()
+            .map(|ScriptEvent { event, .. }| event)
+            .collect()
+    }
+
+    #[test]
+    fn test_framebuffer_updates() {
+        let screen = Arc::new(TestScreen {
+            frame_number: AtomicU64::new(1),
+            colour: Mutex::new([0x30, 0x20, 0x10, 0xff]),
+        });
+        let input_manager = input_manager();
+        let mut client = Client::connect(screen.clone(), &input_manager);
+
+        let server_init = client.init();
+        assert_eq!(server_init[..4], [0, 4, 0, 2]);
+        assert_eq!(&server_init[24..], DESKTOP_NAME.as_bytes());
+
+        // Raw only, the whole screen on the first request
+        client.write(&[2, 0, 0, 1, 0, 0, 0, 0]);
+        client.request_update(true);
+        let (rects, header) = client.read_update();
+        assert_eq!(rects, 1);
+        assert_eq!(header, [0, 0, 0, 0, 0, 4, 0, 2, 0, 0, 0, 0]);
+        assert_eq!(client.read(32), [0x30, 0x20, 0x10, 0].repeat(8));
+
+        // Nothing is sent until the screen changes
+        client.request_update(true);
+        screen.show([0x01, 0x02, 0x03, 0xff]);
+        let (rects, header) = client.read_update();
+        assert_eq!(rects, 1);
+        assert_eq!(header[..8], [0, 0, 0, 0, 0, 4, 0, 2]);
+        assert_eq!(client.read(32), [0x01, 0x02, 0x03, 0].repeat(8));
+
+        client.disconnect().unwrap();
+    }
+
+    #[test]
+    fn test_input_events() {
+        let screen = Arc::new(TestScreen {
+            frame_number: AtomicU64::new(0),
+            colour: Mutex::new([0; 4]),
+        });
+        let input_manager = input_manager();
+        let mut client = Client::connect(screen, &input_manager);
+        client.init();
+
+        // 'y' is on the Z key of German keyboards, Return stays pressed
+        client.write(&[4, 1, 0, 0, 0, 0, 0, b'y', 4, 0, 0, 0, 0, 0, 0, b'y']);
+        client.write(&[4, 1, 0, 0, 0, 0, 0xff, 0x0d]);
+        // Left button pressed while moving right, then wheel up
+        client.write(&[5, 0, 0, 10, 0, 10, 5, 1, 0, 15, 0, 10, 5, 9, 0, 15, 0, 10]);
+        // Extended key event of Up, whatever the keysym
+        client.write(&[255, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0xc8]);
+        client.disconnect().unwrap();
+
+        let events = recorded_events(&input_manager);
+        let keys: Vec<(KeyboardAction, u16)> = events
+            .iter()
+            .filter_map(|event| match event {
+                InputEvent::Keyboard(event) => Some((event.action, event.code)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                (KeyboardAction::Press, 0x2C),
+                (KeyboardAction::Release, 0x2C),
+                (KeyboardAction::Press, 0x1C),
+                (KeyboardAction::Press, 0xE048),
+                (KeyboardAction::Release, 0xE048),
+                (KeyboardAction::Release, 0x1C),
+            ]
+        );
+
+        let mouse: Vec<(MouseAction, i32, i32, Option<MouseButton>)> = events
+            .iter()
+            .filter_map(|event| match event {
+                InputEvent::Mouse(event) => Some((event.action, event.x, event.y, event.button)),
+                _ => None,
+            })
+            .collect();
+        // PS/2 mice are relative, the first position is the origin
+        assert_eq!(
+            mouse,
+            [
+                (MouseAction::Move, 5, 0, None),
+                (MouseAction::ButtonPress, 0, 0, Some(MouseButton::Left)),
+                (MouseAction::Scroll, 0, 0, None),
+                (MouseAction::ButtonRelease, 0, 0, Some(MouseButton::Left)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_handshake_versions() {
+        // 3.3 servers choose the security type
+        let mut output = Vec::new();
+        let mut input: &[u8] = b"RFB 003.003\n\x01";
+        handshake(&mut input, &mut output).unwrap();
+        assert_eq!(&output[..12], VERSION_3_8);
+        assert_eq!(output[12..], [0, 0, 0, SECURITY_NONE]);
+
+        // 3.7 clients don't get a security result
+        let mut output = Vec::new();
+        let mut input: &[u8] = b"RFB 003.007\n\x01\x01";
+        handshake(&mut input, &mut output).unwrap();
+        assert_eq!(output[12..], [1, SECURITY_NONE]);
+
+        // VNC authentication is refused
+        let mut output = Vec::new();
+        let mut input: &[u8] = b"RFB 003.008\n\x02";
+        handshake(&mut input, &mut output).unwrap_err();
+        assert_eq!(output[14..18], SECURITY_RESULT_FAILED.to_be_bytes());
+
+        let mut input: &[u8] = b"HTTP/1.1 200\n";
+        handshake(&mut input, &mut Vec::new()).unwrap_err();
+    }
+}
@@ -8,11 +8,16 @@
 //! - Frame capture (X11, Wayland backends)
 //! - Audio capture (PulseAudio, WASAPI backends)
 
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+use devices::legacy::I8042Device;
 use vmm::input::{
     InputBackend, InputCapabilities, InputEvent, KeyboardAction, KeyboardEvent,
     KeyboardModifiers, MouseAction, MouseButton, MouseEvent, MouseButtons,
     Ps2Backend, StealthLevel, VirtioInputBackend, UsbHidBackend,
 };
+use vmm_sys_util::eventfd::EventFd;
 
 // Note: lg_guest_agent types are tested separately in guest-agent crate
 
@@ -29,11 +34,21 @@ fn integration_tests_available() {
 // PS/2 Backend Tests
 // ============================================================================
 
+fn ps2_backend() -> Ps2Backend {
+    let mut backend = Ps2Backend::new();
+    backend.set_device(Arc::new(Mutex::new(I8042Device::new(
+        EventFd::new(0).unwrap(),
+        Arc::new(AtomicBool::new(false)),
+    ))));
+    backend
+}
+
 #[test]
 fn test_ps2_backend_creation() {
     let backend = Ps2Backend::new();
-    assert!(backend.is_ready());
+    assert!(!backend.is_ready()); // Not ready until the i8042 is set
     assert_eq!(backend.name(), "ps2");
+    assert!(ps2_backend().is_ready());
 }
 
 #[test]
@@ -50,12 +65,12 @@ fn test_ps2_backend_capabilities() {
 
 #[test]
 fn test_ps2_keyboard_injection() {
-    let mut backend = Ps2Backend::new();
+    let mut backend = ps2_backend();
 
     let event = KeyboardEvent {
         action: KeyboardAction::Press,
         code: 0x1E, // A key
-        modifiers: KeyboardModifiers::default(),
+        ..Default::default()
     };
 
     let result = backend.inject_keyboard(&event);
@@ -64,7 +79,7 @@ fn test_ps2_keyboard_injection() {
 
 #[test]
 fn test_ps2_mouse_injection() {
-    let mut backend = Ps2Backend::new();
+    let mut backend = ps2_backend();
 
     let event = MouseEvent {
         action: MouseAction::Move,
@@ -131,7 +146,7 @@ fn test_keyboard_event_creation() {
     let event = KeyboardEvent {
         code: 0x1E, // A key
         action: KeyboardAction::Press,
-        modifiers: KeyboardModifiers::default(),
+        ..Default::default()
     };
 
     assert_eq!(event.code, 0x1E);
@@ -151,6 +166,7 @@ fn test_keyboard_event_with_modifiers() {
         code: 0x1E, // A key
         action: KeyboardAction::Type,
         modifiers,
+        ..Default::default()
     };
 
     assert!(event.modifiers.ctrl);